@@ -1068,6 +1068,9 @@ fi
 if [[ -f /tmp/cryptpilot/global.toml ]]; then
     dracut_common_args+=(--include /tmp/cryptpilot/global.toml /etc/cryptpilot/global.toml)
 fi
+if [[ -f /tmp/cryptpilot/config-signing-key.pem ]]; then
+    dracut_common_args+=(--include /tmp/cryptpilot/config-signing-key.pem /etc/cryptpilot/config-signing-key.pem)
+fi
 
 if [ "${uki:-false}" = true ]; then
     # Remove all existing EFI entries
@@ -1271,6 +1274,12 @@ type = 1
 root_hash = "${roothash}"
 EOF
 
+    # Recording the signature of the config from untrusted source, if any
+    if [ -f "${config_dir}/config-signature" ]; then
+        [ -f "${config_dir}/config-signing-key.pem" ] || proc::fatal "The config signing key must exist with the config signature: ${config_dir}/config-signing-key.pem"
+        echo "config_signature = \"$(tr -d '[:space:]' <"${config_dir}/config-signature")\"" >>"${workdir}/metadata.toml"
+    fi
+
 }
 
 main() {
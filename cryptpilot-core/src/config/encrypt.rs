@@ -3,11 +3,15 @@ use documented::DocumentedFields;
 use serde::{Deserialize, Serialize};
 
 use crate::{
+    config::kdf::{KdfConfig, KdfKeyProvider},
     provider::{
-        exec::ExecKeyProvider, kbs::KbsKeyProvider, kms::KmsKeyProvider, oidc::OidcKeyProvider,
-        otp::OtpKeyProvider, tpm2::Tpm2KeyProvider, IntoProvider, KeyProvider, VolumeType,
+        exec::ExecKeyProvider, http::HttpKeyProvider, kbs::KbsKeyProvider, kms::KmsKeyProvider,
+        oidc::OidcKeyProvider, otp::OtpKeyProvider, pkcs11::Pkcs11KeyProvider,
+        prompt::PromptKeyProvider, registry::CustomKeyProvider,
+        systemd_credential::SystemdCredentialKeyProvider, tpm2::Tpm2KeyProvider, IntoProvider,
+        KeyProvider, VolumeType,
     },
-    types::Passphrase,
+    types::{Luks2Cipher, Passphrase},
 };
 
 /// Encryption configuration for the volume.
@@ -17,6 +21,31 @@ pub struct EncryptConfig {
     /// The key provider specific configs
     #[serde(flatten)]
     pub key_provider: KeyProviderConfig,
+
+    /// Post-process the passphrase from the key provider with a KDF before using it (optional). Disabled by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kdf: Option<KdfConfig>,
+
+    /// The cipher of the data encryption when the LUKS2 volume is formatted, "aes-xts-plain64" or "sm4-xts-plain64" (optional). It is kept in the LUKS2 header, so changing it takes effect only after the volume is formatted again. Default: "aes-xts-plain64".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cipher: Option<Luks2Cipher>,
+}
+
+impl IntoProvider for EncryptConfig {
+    type Provider = BoxedKeyProvider;
+
+    /// The key provider with the KDF post-processing applied, which should be used wherever the
+    /// passphrase formats or unlocks the volume.
+    fn into_provider(self) -> Self::Provider {
+        let key_provider = self.key_provider.into_provider();
+        match self.kdf {
+            Some(kdf) => BoxedKeyProvider(Box::new(KdfKeyProvider {
+                inner: key_provider,
+                kdf,
+            })),
+            None => key_provider,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
@@ -34,9 +63,20 @@ pub enum KeyProviderConfig {
     Oidc(crate::provider::oidc::OidcConfig),
     #[cfg(feature = "provider-exec")]
     Exec(crate::provider::exec::ExecConfig),
+    #[cfg(feature = "provider-systemd-credential")]
+    #[serde(rename = "systemd_credential")]
+    SystemdCredential(crate::provider::systemd_credential::SystemdCredentialConfig),
+    #[cfg(feature = "provider-prompt")]
+    Prompt(crate::provider::prompt::PromptConfig),
+    #[cfg(feature = "provider-pkcs11")]
+    Pkcs11(crate::provider::pkcs11::Pkcs11Config),
+    #[cfg(feature = "provider-http")]
+    Http(crate::provider::http::HttpConfig),
+    /// Key provider registered at runtime with [`crate::provider::registry::register_key_provider`]
+    Custom(crate::provider::registry::CustomConfig),
 }
 
-pub struct BoxedKeyProvider(Box<dyn KeyProvider + Send + Sync + 'static>);
+pub struct BoxedKeyProvider(pub(crate) Box<dyn KeyProvider + Send + Sync + 'static>);
 
 #[async_trait::async_trait]
 impl KeyProvider for BoxedKeyProvider {
@@ -47,11 +87,37 @@ impl KeyProvider for BoxedKeyProvider {
         self.0.get_key().await
     }
 
+    async fn get_keys(&self) -> Result<Vec<Passphrase>> {
+        self.0.get_keys().await
+    }
+
     fn volume_type(&self) -> VolumeType {
         self.0.volume_type()
     }
 }
 
+impl KeyProviderConfig {
+    /// The type of the key provider, which is the name of its config section, e.g. "otp" or "kms".
+    /// A key provider registered at runtime is named by its tag.
+    pub fn type_name(&self) -> &str {
+        match self {
+            KeyProviderConfig::Otp(_) => "otp",
+            KeyProviderConfig::Kms(_) => "kms",
+            KeyProviderConfig::Kbs(_) => "kbs",
+            KeyProviderConfig::Tpm2(_) => "tpm2",
+            KeyProviderConfig::Oidc(_) => "oidc",
+            KeyProviderConfig::Exec(_) => "exec",
+            KeyProviderConfig::SystemdCredential(_) => "systemd_credential",
+            KeyProviderConfig::Prompt(_) => "prompt",
+            #[cfg(feature = "provider-pkcs11")]
+            KeyProviderConfig::Pkcs11(_) => "pkcs11",
+            #[cfg(feature = "provider-http")]
+            KeyProviderConfig::Http(_) => "http",
+            KeyProviderConfig::Custom(custom_config) => &custom_config.tag,
+        }
+    }
+}
+
 impl IntoProvider for KeyProviderConfig {
     type Provider = BoxedKeyProvider;
 
@@ -75,6 +141,25 @@ impl IntoProvider for KeyProviderConfig {
             KeyProviderConfig::Exec(exec_config) => Box::new(ExecKeyProvider {
                 options: exec_config,
             }),
+            KeyProviderConfig::SystemdCredential(systemd_credential_config) => {
+                Box::new(SystemdCredentialKeyProvider {
+                    options: systemd_credential_config,
+                })
+            }
+            KeyProviderConfig::Prompt(prompt_config) => Box::new(PromptKeyProvider {
+                options: prompt_config,
+            }),
+            #[cfg(feature = "provider-pkcs11")]
+            KeyProviderConfig::Pkcs11(pkcs11_config) => Box::new(Pkcs11KeyProvider {
+                options: pkcs11_config,
+            }),
+            #[cfg(feature = "provider-http")]
+            KeyProviderConfig::Http(http_config) => Box::new(HttpKeyProvider {
+                options: http_config,
+            }),
+            KeyProviderConfig::Custom(custom_config) => {
+                Box::new(CustomKeyProvider::new(custom_config))
+            }
         })
     }
 }
@@ -0,0 +1,245 @@
+use aes_gcm::{
+    aead::{Aead as _, KeyInit as _, Payload},
+    Aes256Gcm, Nonce,
+};
+use anyhow::{bail, Context as _, Result};
+use base64::{prelude::BASE64_STANDARD, Engine as _};
+use rand::RngCore as _;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::encrypt::EncryptConfig,
+    provider::{IntoProvider as _, KeyProvider as _, VolumeType},
+};
+
+/// The name of the top-level table which marks a config file as an encrypted envelope.
+pub const ENCRYPTED_CONFIG_TABLE: &str = "encrypted_config";
+
+/// The only supported version of the envelope format.
+const ENVELOPE_VERSION: u32 = 1;
+
+const SALT_SIZE: usize = 16;
+const NONCE_SIZE: usize = 12;
+const KEY_SIZE: usize = 32;
+
+/// The HKDF info and the associated data of AES-256-GCM, which bind the key and the ciphertext
+/// to this envelope format.
+const ENVELOPE_CONTEXT: &[u8] = b"cryptpilot-encrypted-config-v1";
+
+/// A config file encrypted at rest, e.g. a volume config containing the credentials of a key
+/// provider. The content is encrypted with AES-256-GCM, with a key derived with HKDF-SHA256 from
+/// the passphrase of the bootstrap key provider.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct EncryptedConfigFile {
+    pub encrypted_config: EncryptedConfigEnvelope,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct EncryptedConfigEnvelope {
+    /// The version of the envelope format.
+    pub version: u32,
+
+    /// The bootstrap key provider, whose passphrase the key is derived from. It must be a
+    /// persistent one, e.g. kms, kbs, tpm2 or exec, and must not depend on the encrypted config.
+    pub key: EncryptConfig,
+
+    /// The salt of HKDF, base64 encoded.
+    pub salt: String,
+
+    /// The nonce of AES-256-GCM, base64 encoded.
+    pub nonce: String,
+
+    /// The encrypted config followed by the authentication tag, base64 encoded.
+    pub ciphertext: String,
+}
+
+/// Check if the content of a config file is an encrypted envelope, i.e. a TOML document with the
+/// top-level table [`ENCRYPTED_CONFIG_TABLE`]. Plaintext config files are not affected.
+pub fn is_encrypted_config(content: &str) -> bool {
+    toml::from_str::<toml::Table>(content)
+        .map(|table| table.contains_key(ENCRYPTED_CONFIG_TABLE))
+        .unwrap_or(false)
+}
+
+async fn derive_key(
+    key: &EncryptConfig,
+    salt: &[u8],
+) -> Result<zeroize::Zeroizing<[u8; KEY_SIZE]>> {
+    let provider = key.clone().into_provider();
+    if provider.volume_type() == VolumeType::Temporary {
+        bail!(
+            "The bootstrap key provider {} generates a new key every time, which cannot decrypt the config later",
+            provider.debug_name()
+        );
+    }
+    let passphrase = provider.get_key().await.with_context(|| {
+        format!(
+            "Failed to get the bootstrap key from {}",
+            provider.debug_name()
+        )
+    })?;
+
+    let mut derived = zeroize::Zeroizing::new([0u8; KEY_SIZE]);
+    hkdf::Hkdf::<sha2::Sha256>::new(Some(salt), passphrase.as_bytes())
+        .expand(ENVELOPE_CONTEXT, derived.as_mut())
+        .map_err(|error| anyhow::anyhow!("{error}"))
+        .context("Failed to derive the key of the encrypted config")?;
+    Ok(derived)
+}
+
+/// Encrypt the content of a config file into an envelope, with a key from the bootstrap key
+/// provider. Returns the TOML document of the envelope.
+pub async fn encrypt_config(plaintext: &str, key: EncryptConfig) -> Result<String> {
+    let mut salt = [0u8; SALT_SIZE];
+    let mut nonce = [0u8; NONCE_SIZE];
+    rand::thread_rng().fill_bytes(&mut salt);
+    rand::thread_rng().fill_bytes(&mut nonce);
+
+    let derived = derive_key(&key, &salt).await?;
+    let ciphertext = Aes256Gcm::new_from_slice(derived.as_ref())
+        .map_err(|error| anyhow::anyhow!("{error}"))?
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: plaintext.as_bytes(),
+                aad: ENVELOPE_CONTEXT,
+            },
+        )
+        .map_err(|error| anyhow::anyhow!("{error}"))
+        .context("Failed to encrypt the config")?;
+
+    let file = EncryptedConfigFile {
+        encrypted_config: EncryptedConfigEnvelope {
+            version: ENVELOPE_VERSION,
+            key,
+            salt: BASE64_STANDARD.encode(salt),
+            nonce: BASE64_STANDARD.encode(nonce),
+            ciphertext: BASE64_STANDARD.encode(ciphertext),
+        },
+    };
+    Ok(toml::to_string(&file)?)
+}
+
+/// Decrypt the content of an encrypted config file, see [`is_encrypted_config`]. Returns the
+/// plaintext content of the config file.
+pub async fn decrypt_config(content: &str) -> Result<String> {
+    let envelope = toml::from_str::<EncryptedConfigFile>(content)
+        .context("Failed to parse the encrypted config envelope")?
+        .encrypted_config;
+    if envelope.version != ENVELOPE_VERSION {
+        bail!(
+            "Unsupported version {} of the encrypted config envelope, expected {ENVELOPE_VERSION}",
+            envelope.version
+        );
+    }
+
+    let salt = BASE64_STANDARD
+        .decode(&envelope.salt)
+        .context("Invalid base64 in `salt`")?;
+    let nonce = BASE64_STANDARD
+        .decode(&envelope.nonce)
+        .context("Invalid base64 in `nonce`")?;
+    if nonce.len() != NONCE_SIZE {
+        bail!("The `nonce` should be {NONCE_SIZE} bytes long");
+    }
+    let ciphertext = BASE64_STANDARD
+        .decode(&envelope.ciphertext)
+        .context("Invalid base64 in `ciphertext`")?;
+
+    let derived = derive_key(&envelope.key, &salt).await?;
+    let plaintext = Aes256Gcm::new_from_slice(derived.as_ref())
+        .map_err(|error| anyhow::anyhow!("{error}"))?
+        .decrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: &ciphertext,
+                aad: ENVELOPE_CONTEXT,
+            },
+        )
+        .map_err(|_| {
+            anyhow::anyhow!(
+                "Failed to decrypt the config, the bootstrap key is wrong or the envelope is corrupted"
+            )
+        })?;
+
+    String::from_utf8(plaintext).context("The decrypted config is not valid UTF-8")
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    fn exec_key(secret: &str) -> Result<EncryptConfig> {
+        Ok(toml::from_str(&format!(
+            r#"
+            [exec]
+            command = "echo"
+            args = ["-n", "{secret}"]
+            "#
+        ))?)
+    }
+
+    #[tokio::test]
+    async fn test_encrypt_decrypt_config() -> Result<()> {
+        let plaintext = r#"
+            volume = "data0"
+            dev = "/dev/nvme1n1p1"
+
+            [encrypt.kms]
+            client_key = "secret-client-key"
+        "#;
+        assert!(!is_encrypted_config(plaintext));
+
+        let encrypted = encrypt_config(plaintext, exec_key("bootstrap-secret")?).await?;
+        assert!(is_encrypted_config(&encrypted));
+        assert!(!encrypted.contains("secret-client-key"));
+        assert_eq!(decrypt_config(&encrypted).await?, plaintext);
+
+        // A wrong bootstrap key
+        let mut file: EncryptedConfigFile = toml::from_str(&encrypted)?;
+        file.encrypted_config.key = exec_key("another-secret")?;
+        assert!(decrypt_config(&toml::to_string(&file)?).await.is_err());
+
+        // A tampered ciphertext
+        let mut file: EncryptedConfigFile = toml::from_str(&encrypted)?;
+        let mut ciphertext = BASE64_STANDARD.decode(&file.encrypted_config.ciphertext)?;
+        ciphertext[0] ^= 1;
+        file.encrypted_config.ciphertext = BASE64_STANDARD.encode(ciphertext);
+        assert!(decrypt_config(&toml::to_string(&file)?).await.is_err());
+
+        // A temporary key provider cannot be the bootstrap key provider
+        assert!(encrypt_config(plaintext, toml::from_str("[otp]")?)
+            .await
+            .is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_decrypt_config_fixture() -> Result<()> {
+        // An envelope of `volume = "data0"` with the bootstrap key "bootstrap-secret", which must
+        // stay decryptable across releases
+        let encrypted = r#"
+            [encrypted_config]
+            version = 1
+            salt = "AAECAwQFBgcICQoLDA0ODw=="
+            nonce = "ZGVmZ2hpamtsbW5v"
+            ciphertext = "G7rmsSTsZiMOF4W8lUfUXcNOXkiWhQaR/2MlWwyIxSQE"
+
+            [encrypted_config.key.exec]
+            command = "echo"
+            args = ["-n", "bootstrap-secret"]
+        "#;
+        assert!(is_encrypted_config(encrypted));
+        assert_eq!(decrypt_config(encrypted).await?, "volume = \"data0\"\n");
+
+        let unsupported = encrypted.replace("version = 1", "version = 2");
+        assert!(decrypt_config(&unsupported).await.is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,221 @@
+use anyhow::{bail, Context as _, Result};
+use documented::{Documented, DocumentedFields};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    provider::{KeyProvider, VolumeType},
+    types::Passphrase,
+};
+
+/// Size in bytes of the key derived from the passphrase.
+const KDF_OUTPUT_SIZE: usize = 32;
+
+/// The minimum size in bytes of the salt of Argon2id.
+const ARGON2_SALT_SIZE_MIN: usize = 8;
+
+/// The cost parameters of Argon2id. They are fixed rather than taken from the defaults of the
+/// argon2 crate, since any change would derive a different passphrase and lock the volumes out.
+const ARGON2_MEMORY_COST_KIB: u32 = 64 * 1024;
+const ARGON2_TIME_COST: u32 = 3;
+const ARGON2_PARALLELISM: u32 = 1;
+
+/// Post-processing of the passphrase from the key provider with a KDF, e.g. to stretch a
+/// low-entropy secret. The derived key is used as the LUKS2 passphrase, hex encoded.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Documented, DocumentedFields)]
+#[serde(deny_unknown_fields)]
+pub struct KdfConfig {
+    /// The KDF algorithm: "hkdf-sha256" for a secret with enough entropy, or "argon2id" to stretch a low-entropy secret (e.g. a short PIN).
+    pub algorithm: KdfAlgorithm,
+
+    /// The salt of the KDF. It must stay the same for the whole lifetime of the volume, since a different salt derives a different passphrase. At least 8 bytes for "argon2id".
+    pub salt: String,
+
+    /// The context specific info string of "hkdf-sha256" (optional). Not allowed for "argon2id".
+    #[serde(default)]
+    pub info: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum KdfAlgorithm {
+    HkdfSha256,
+    Argon2id,
+}
+
+impl KdfConfig {
+    /// Derive the LUKS2 passphrase from the passphrase of the key provider.
+    pub async fn derive(&self, passphrase: &Passphrase) -> Result<Passphrase> {
+        let kdf = self.clone();
+        let passphrase = passphrase.clone();
+        tokio::task::spawn_blocking(move || kdf.derive_blocking(&passphrase)).await?
+    }
+
+    fn derive_blocking(&self, passphrase: &Passphrase) -> Result<Passphrase> {
+        let mut key = zeroize::Zeroizing::new([0u8; KDF_OUTPUT_SIZE]);
+        match self.algorithm {
+            KdfAlgorithm::HkdfSha256 => {
+                hkdf::Hkdf::<sha2::Sha256>::new(Some(self.salt.as_bytes()), passphrase.as_bytes())
+                    .expand(self.info.as_deref().unwrap_or("").as_bytes(), key.as_mut())
+                    .map_err(|error| anyhow::anyhow!("{error}"))
+                    .context("Failed to derive the passphrase with HKDF-SHA256")?;
+            }
+            KdfAlgorithm::Argon2id => {
+                if self.info.is_some() {
+                    bail!("The `info` of the KDF is not supported by argon2id");
+                }
+                if self.salt.len() < ARGON2_SALT_SIZE_MIN {
+                    bail!(
+                        "The salt of argon2id should be at least {ARGON2_SALT_SIZE_MIN} bytes long"
+                    );
+                }
+                let params = argon2::Params::new(
+                    ARGON2_MEMORY_COST_KIB,
+                    ARGON2_TIME_COST,
+                    ARGON2_PARALLELISM,
+                    Some(KDF_OUTPUT_SIZE),
+                )
+                .map_err(|error| anyhow::anyhow!("{error}"))?;
+                argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+                    .hash_password_into(passphrase.as_bytes(), self.salt.as_bytes(), key.as_mut())
+                    .map_err(|error| anyhow::anyhow!("{error}"))
+                    .context("Failed to derive the passphrase with argon2id")?;
+            }
+        }
+        // Keep the passphrase in 7-bit ASCII, as recommended by cryptsetup(8)
+        Ok(Passphrase::from(hex::encode(key.as_ref()).into_bytes()))
+    }
+}
+
+/// A key provider whose passphrases are post-processed with the KDF.
+pub struct KdfKeyProvider<P> {
+    pub inner: P,
+    pub kdf: KdfConfig,
+}
+
+#[async_trait::async_trait]
+impl<P: KeyProvider + Send + Sync> KeyProvider for KdfKeyProvider<P> {
+    fn debug_name(&self) -> String {
+        format!(
+            "{} (with {})",
+            self.inner.debug_name(),
+            serde_variant::to_variant_name(&self.kdf.algorithm).unwrap_or("KDF")
+        )
+    }
+
+    async fn get_key(&self) -> Result<Passphrase> {
+        let passphrase = self.inner.get_key().await?;
+        self.kdf.derive(&passphrase).await
+    }
+
+    async fn get_keys(&self) -> Result<Vec<Passphrase>> {
+        let mut passphrases = vec![];
+        for passphrase in self.inner.get_keys().await? {
+            passphrases.push(self.kdf.derive(&passphrase).await?);
+        }
+        Ok(passphrases)
+    }
+
+    fn volume_type(&self) -> VolumeType {
+        self.inner.volume_type()
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn test_parse_config() -> Result<()> {
+        let config: KdfConfig = toml::from_str(
+            r#"
+            algorithm = "hkdf-sha256"
+            salt = "volume-data0"
+            info = "cryptpilot"
+            "#,
+        )?;
+        assert_eq!(config.algorithm, KdfAlgorithm::HkdfSha256);
+        assert_eq!(config.info.as_deref(), Some("cryptpilot"));
+
+        let config: KdfConfig = toml::from_str(
+            r#"
+            algorithm = "argon2id"
+            salt = "volume-data0"
+            "#,
+        )?;
+        assert_eq!(config.algorithm, KdfAlgorithm::Argon2id);
+        assert_eq!(config.info, None);
+
+        assert!(toml::from_str::<KdfConfig>(r#"algorithm = "md5""#).is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_derive_is_stable() -> Result<()> {
+        let passphrase = Passphrase::from(b"1234".to_vec());
+        for algorithm in [KdfAlgorithm::HkdfSha256, KdfAlgorithm::Argon2id] {
+            let kdf = KdfConfig {
+                algorithm,
+                salt: "volume-data0".to_owned(),
+                info: None,
+            };
+            let derived = kdf.derive(&passphrase).await?;
+            assert_eq!(derived.as_bytes().len(), KDF_OUTPUT_SIZE * 2);
+            assert!(derived.as_bytes().is_ascii());
+            assert_ne!(derived.as_bytes(), passphrase.as_bytes());
+
+            // The same input and KDF yield the same passphrase
+            assert_eq!(
+                kdf.derive(&passphrase).await?.as_bytes(),
+                derived.as_bytes()
+            );
+
+            // While a different salt does not
+            let other_salt = KdfConfig {
+                salt: "volume-data1".to_owned(),
+                ..kdf.clone()
+            };
+            assert_ne!(
+                other_salt.derive(&passphrase).await?.as_bytes(),
+                derived.as_bytes()
+            );
+        }
+
+        // The info string is part of the HKDF input
+        let hkdf = KdfConfig {
+            algorithm: KdfAlgorithm::HkdfSha256,
+            salt: "volume-data0".to_owned(),
+            info: None,
+        };
+        let with_info = KdfConfig {
+            info: Some("cryptpilot".to_owned()),
+            ..hkdf.clone()
+        };
+        assert_ne!(
+            hkdf.derive(&passphrase).await?.as_bytes(),
+            with_info.derive(&passphrase).await?.as_bytes()
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_derive_argon2id_invalid_config() {
+        let passphrase = Passphrase::from(b"1234".to_vec());
+        let short_salt = KdfConfig {
+            algorithm: KdfAlgorithm::Argon2id,
+            salt: "short".to_owned(),
+            info: None,
+        };
+        assert!(short_salt.derive(&passphrase).await.is_err());
+
+        let with_info = KdfConfig {
+            algorithm: KdfAlgorithm::Argon2id,
+            salt: "volume-data0".to_owned(),
+            info: Some("cryptpilot".to_owned()),
+        };
+        assert!(with_info.derive(&passphrase).await.is_err());
+    }
+}
@@ -1 +1,3 @@
 pub mod encrypt;
+pub mod envelope;
+pub mod kdf;
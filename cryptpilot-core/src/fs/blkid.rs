@@ -18,6 +18,8 @@ pub enum BlkidProbeResult {
         pt_type: Option<String>,
         /// LUKS2 subsystem string from `SUBSYSTEM="..."`, if present.
         subsystem: Option<String>,
+        /// Filesystem label from `LABEL="..."`, if present.
+        label: Option<String>,
     },
 }
 
@@ -27,6 +29,22 @@ impl BlkidProbeResult {
         matches!(self, Self::NoSignatures)
     }
 
+    /// Returns the detected signature, which is the filesystem type if present, or the partition
+    /// table type otherwise.
+    pub fn signature(&self) -> Option<&str> {
+        match self {
+            Self::NoSignatures => None,
+            Self::KnownSignature {
+                fs_type, pt_type, ..
+            } => fs_type.as_deref().or(pt_type.as_deref()),
+        }
+    }
+
+    /// Returns `true` if the device contains a LUKS signature.
+    pub fn is_luks(&self) -> bool {
+        self.signature() == Some("crypto_LUKS")
+    }
+
     /// Returns `true` if the device contains dm-snapshot COW metadata.
     pub fn is_dm_snapshot_cow(&self) -> bool {
         match self {
@@ -66,6 +84,8 @@ pub async fn probe_device(device_path: &Path) -> Result<BlkidProbeResult> {
                     let pt_type = parse_blkid_field(&trimmed, "PTTYPE");
                     // Parse SUBSYSTEM="..." (LUKS2 subsystem field)
                     let subsystem = parse_blkid_field(&trimmed, "SUBSYSTEM");
+                    // Parse LABEL="..."
+                    let label = parse_blkid_field(&trimmed, "LABEL");
 
                     // Treat atari partition table as no signature (known false positive)
                     // See: https://bugs.launchpad.net/ubuntu/+source/util-linux/+bug/2015355
@@ -80,7 +100,7 @@ pub async fn probe_device(device_path: &Path) -> Result<BlkidProbeResult> {
                         "blkid probe on {device_path:?}: fs_type={fs_type:?}, pt_type={pt_type:?}"
                     );
 
-                    Ok(BlkidProbeResult::KnownSignature { fs_type, pt_type, subsystem })
+                    Ok(BlkidProbeResult::KnownSignature { fs_type, pt_type, subsystem, label })
                 }
                 2 => {
                     // blkid exit code 2 = no signatures found
@@ -3,13 +3,22 @@ use std::{
     mem::MaybeUninit,
     os::fd::{AsFd, AsRawFd},
     path::Path,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context as TaskContext, Poll},
+    time::Duration,
 };
 
 use anyhow::{bail, ensure, Context, Result};
+use futures::{Stream, StreamExt as _};
 use nix::{ioctl_none, ioctl_readwrite, mount::MsFlags};
 use tokio::{
     fs::{File, OpenOptions},
     io::{AsyncReadExt, AsyncWriteExt},
+    sync::mpsc,
 };
 
 mod gen {
@@ -31,12 +40,68 @@ ioctl_none!(blktrace_teardown, 0x12, 118);
 // blockdev --flushbufs
 ioctl_none!(blkflsbuf, 0x12, 97);
 
+/// A blktrace recording which collects all the events, returned at [`BlkTrace::shutdown`]. For a
+/// long capture, use [`BlkTrace::monitor_streaming`] to consume the events while recording.
 pub struct BlkTrace {
+    handle: BlkTraceStreamHandle,
+    join_handle: tokio::task::JoinHandle<Vec<BlkTraceEvent>>,
+}
+
+/// Controls a blktrace recording started with [`BlkTrace::monitor_streaming`], whose events are
+/// delivered on the paired [`BlkTraceEventStream`].
+pub struct BlkTraceStreamHandle {
     task: BlkTraceTask,
-    join_handle: tokio::task::JoinHandle<Result<Vec<BlkTraceEvent>>>,
+    readers: Vec<tokio::task::JoinHandle<Result<()>>>,
     cancel_token: CancellationToken,
+    events_received: Arc<AtomicU64>,
+}
+
+/// The events of a blktrace recording, in the order they are read from the relay channels of the
+/// CPUs. The stream ends once the recording is shut down with its [`BlkTraceStreamHandle`].
+///
+/// At most [`BLK_TRACE_EVENT_STREAM_CAPACITY`] events are buffered. When the consumer falls
+/// behind, the readers stop reading the relay channels, and the kernel drops the events which do
+/// not fit in its buffers, which is reported as the dropped count at shutdown.
+pub struct BlkTraceEventStream {
+    rx: mpsc::Receiver<BlkTraceEvent>,
 }
 
+impl Stream for BlkTraceEventStream {
+    type Item = BlkTraceEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+/// The number of events buffered in a [`BlkTraceEventStream`].
+pub const BLK_TRACE_EVENT_STREAM_CAPACITY: usize = 100;
+
+/// Controls how long [`BlkTrace::shutdown_with_drain`] waits for the kernel to put the remaining
+/// events on the relay channels before stopping the readers.
+#[derive(Debug, Clone, Copy)]
+pub struct BlkTraceDrainConfig {
+    /// Always wait at least this long, even if no new event arrives.
+    pub min: Duration,
+    /// Stop waiting once neither new events nor new dropped events are seen for this long.
+    pub quiet_period: Duration,
+    /// Never wait longer than this.
+    pub max: Duration,
+}
+
+impl Default for BlkTraceDrainConfig {
+    fn default() -> Self {
+        Self {
+            min: Duration::ZERO,
+            quiet_period: Duration::from_millis(100),
+            max: Duration::from_millis(1000),
+        }
+    }
+}
+
+// The interval to check whether the relay channels are drained
+const BLK_TRACE_DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
 pub struct BlkTraceTask {
     pub block_device_file: File,
     pub block_name: Option<String>,
@@ -111,7 +176,34 @@ impl BlkTrace {
         Ok(())
     }
 
+    /// Start recording the read and write events of the block device, collecting all of them until
+    /// [`BlkTrace::shutdown`].
     pub async fn monitor(path: impl AsRef<Path>) -> Result<Self> {
+        let (handle, mut events) = Self::monitor_streaming(path).await?;
+
+        let join_handle = tokio::spawn(async move {
+            let mut traces = vec![];
+            while let Some(blk_event) = events.next().await {
+                tracing::trace!(?blk_event, "Received a blktrace event");
+                traces.push(blk_event);
+            }
+            traces
+        });
+
+        Ok(Self {
+            handle,
+            join_handle,
+        })
+    }
+
+    /// Start recording the read and write events of the block device, which are delivered live on
+    /// the returned stream, so that a long capture can be analyzed in real time with bounded memory.
+    ///
+    /// The stream must be consumed concurrently with the recording, otherwise
+    /// [`BlkTraceStreamHandle::shutdown`] cannot finish reading the relay channels.
+    pub async fn monitor_streaming(
+        path: impl AsRef<Path>,
+    ) -> Result<(BlkTraceStreamHandle, BlkTraceEventStream)> {
         Self::check_and_setup_debugfs().await?;
 
         let file = File::open(path).await?;
@@ -145,16 +237,18 @@ impl BlkTrace {
         let _ = unsafe { blktrace_start(fd.as_raw_fd()) }.context("Failed to BLKTRACESTART")?;
 
         // Open the relay channel for each cpu
-        let (tx, mut rx) = tokio::sync::mpsc::channel(100);
+        let (tx, rx) = mpsc::channel(BLK_TRACE_EVENT_STREAM_CAPACITY);
 
         let cancel_token = CancellationToken::new();
+        let events_received = Arc::new(AtomicU64::new(0));
 
         let num_cpus = num_cpus::get();
 
-        let join_handles = (0..num_cpus)
+        let readers = (0..num_cpus)
             .map(|i| {
                 let tx = tx.clone();
                 let cancel_token = cancel_token.clone();
+                let events_received = events_received.clone();
                 let relay_channel = format!("/sys/kernel/debug/block/{}/trace{}", block_name, i);
 
                 tokio::spawn(async move {
@@ -217,6 +311,7 @@ impl BlkTrace {
                         })
                         .await
                         .context("Failed to send trace event to channel")?;
+                        events_received.fetch_add(1, Ordering::Relaxed);
                     }
 
                     anyhow::Result::<_>::Ok(())
@@ -224,38 +319,54 @@ impl BlkTrace {
             })
             .collect::<Vec<_>>();
 
-        let join_handle = tokio::spawn(async move {
-            let mut traces = vec![];
-            while let Some(blk_event) = rx.recv().await {
-                tracing::trace!(?blk_event, "Received a blktrace event");
-                traces.push(blk_event);
-            }
-
-            for task in join_handles {
-                task.await??;
-            }
-
-            Ok(traces)
-        });
-
         // Drop all page cache to force read operations to be sent to block device, so that we can capture all the read events.
         task.drop_page_cache().await?;
 
-        Ok(Self {
-            task,
-            join_handle,
-            cancel_token,
-        })
+        Ok((
+            BlkTraceStreamHandle {
+                task,
+                readers,
+                cancel_token,
+                events_received,
+            },
+            BlkTraceEventStream { rx },
+        ))
     }
 
     pub async fn shutdown(self) -> Result<(Vec<BlkTraceEvent>, u64)> {
-        // Wait a millisecond to make sure all the trace is generated and put on the relay channel by kernel
+        self.shutdown_with_drain(BlkTraceDrainConfig::default())
+            .await
+    }
+
+    pub async fn shutdown_with_drain(
+        self,
+        drain: BlkTraceDrainConfig,
+    ) -> Result<(Vec<BlkTraceEvent>, u64)> {
+        let dropped = self.handle.shutdown_with_drain(drain).await?;
+        let events = self.join_handle.await?;
+        Ok((events, dropped))
+    }
+}
+
+impl BlkTraceStreamHandle {
+    /// Stop the recording and end the event stream, returning the count of the events dropped by
+    /// the kernel.
+    pub async fn shutdown(self) -> Result<u64> {
+        self.shutdown_with_drain(BlkTraceDrainConfig::default())
+            .await
+    }
+
+    pub async fn shutdown_with_drain(self, drain: BlkTraceDrainConfig) -> Result<u64> {
         self.task.flush_blkbuf().await?;
 
-        tokio::time::sleep(std::time::Duration::from_millis(1000)).await;
+        // Wait until all the trace is generated and put on the relay channel by kernel, which is
+        // when the readers have seen no new event and the dropped counter is stable for a while.
+        self.wait_for_drain(drain).await?;
 
         self.cancel_token.cancel();
-        let events = self.join_handle.await??;
+        for reader in self.readers {
+            reader.await??;
+        }
 
         let dropped = self
             .task
@@ -271,7 +382,45 @@ impl BlkTrace {
         }
 
         drop(self.task);
-        Ok((events, dropped))
+        Ok(dropped)
+    }
+
+    async fn wait_for_drain(&self, drain: BlkTraceDrainConfig) -> Result<()> {
+        let start = tokio::time::Instant::now();
+        let mut last_seen = (
+            self.events_received.load(Ordering::Relaxed),
+            self.task.get_dropped().await?,
+        );
+        let mut last_change = start;
+
+        loop {
+            tokio::time::sleep(BLK_TRACE_DRAIN_POLL_INTERVAL).await;
+            let now = tokio::time::Instant::now();
+
+            let seen = (
+                self.events_received.load(Ordering::Relaxed),
+                self.task.get_dropped().await?,
+            );
+            if seen != last_seen {
+                last_seen = seen;
+                last_change = now;
+            }
+
+            let elapsed = now - start;
+            if elapsed >= drain.min && now - last_change >= drain.quiet_period {
+                tracing::trace!(?elapsed, "The blktrace relay channels are drained");
+                break;
+            }
+            if elapsed >= drain.max {
+                tracing::debug!(
+                    ?elapsed,
+                    "Still receiving blktrace events, stop waiting for the relay channels to be drained"
+                );
+                break;
+            }
+        }
+
+        Ok(())
     }
 }
 
@@ -395,4 +544,73 @@ pub mod tests {
 
         Ok(())
     }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+    async fn test_blktrace_short_capture() -> Result<()> {
+        let dm_device = DeviceMapperDevice::new_zero(10 * 1024 * 1024 * 1024).await?;
+        let device_path = dm_device.path();
+
+        for _ in 0..3 {
+            let tracer = BlkTrace::monitor(&device_path).await?;
+            {
+                let mut f = File::open(&device_path).await?;
+                f.read_exact(&mut [0; 4096]).await?;
+            }
+
+            let start = std::time::Instant::now();
+            let (events, dropped) = tracer
+                .shutdown_with_drain(BlkTraceDrainConfig {
+                    min: Duration::ZERO,
+                    quiet_period: Duration::from_millis(50),
+                    max: Duration::from_millis(1000),
+                })
+                .await?;
+            let elapsed = start.elapsed();
+            tracing::info!(?elapsed, "Got {} traces in a short capture", events.len());
+
+            assert!(dropped == 0);
+            assert!(events.iter().any(|event| event.is_read()));
+            assert!(elapsed < Duration::from_millis(1000));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+    async fn test_blktrace_streaming() -> Result<()> {
+        let dm_device = DeviceMapperDevice::new_zero(10 * 1024 * 1024 * 1024).await?;
+        let device_path = dm_device.path();
+
+        let (handle, mut events) = BlkTrace::monitor_streaming(&device_path).await?;
+
+        // Consume the events live, keeping only the counts
+        let consumer = tokio::spawn(async move {
+            let (mut count, mut count_read) = (0usize, 0usize);
+            while let Some(event) = events.next().await {
+                count += 1;
+                if event.is_read() {
+                    count_read += 1;
+                }
+            }
+            (count, count_read)
+        });
+
+        // More reads than the capacity of the stream, so that the readers depend on the consumer
+        {
+            let mut f = File::open(&device_path).await?;
+            for i in 0..(BLK_TRACE_EVENT_STREAM_CAPACITY as u64 * 2) {
+                f.seek(SeekFrom::Start(i * 1024 * 1024)).await?;
+                f.read_exact(&mut [0; 4096]).await?;
+            }
+        }
+
+        let dropped = handle.shutdown().await?;
+        let (count, count_read) = consumer.await?;
+        tracing::info!("Got {count} traces from the stream, {count_read} reads");
+
+        assert!(dropped == 0);
+        assert!(count_read > 0);
+
+        Ok(())
+    }
 }
@@ -0,0 +1,151 @@
+use std::{
+    os::unix::fs::{FileTypeExt as _, MetadataExt as _},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context as _, Result};
+use serde::Serialize;
+
+const SYS_DEV_BLOCK_DIR: &str = "/sys/dev/block";
+
+/// The capabilities of a block device, read from its `queue/` directory in sysfs. An attribute is
+/// `None` if it is not exposed by the device, which is common for virtual devices.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct DeviceCaps {
+    pub device: PathBuf,
+    pub logical_block_size: Option<u32>,
+    pub physical_block_size: Option<u32>,
+    pub rotational: Option<bool>,
+    /// The maximum size in bytes of a discard request, zero if discard is not supported.
+    pub discard_max_bytes: Option<u64>,
+    /// Whether the discarded blocks are guaranteed to read back as zeros. Note that recent kernels
+    /// always report `false`, in which case `write_zeroes_max_bytes` tells whether the device can
+    /// zero blocks efficiently.
+    pub discard_zeroes_data: Option<bool>,
+    /// The maximum size in bytes of a write-zeroes request, zero if it is not supported.
+    pub write_zeroes_max_bytes: Option<u64>,
+}
+
+impl DeviceCaps {
+    /// Probe the capabilities of a block device. A partition reports the capabilities of its disk.
+    pub async fn probe(device: &Path) -> Result<Self> {
+        let metadata = tokio::fs::metadata(device)
+            .await
+            .with_context(|| format!("Failed to access device {device:?}"))?;
+        if !metadata.file_type().is_block_device() {
+            bail!("{device:?} is not a block device");
+        }
+
+        let rdev = metadata.rdev();
+        let (major, minor) = (libc::major(rdev), libc::minor(rdev));
+        let mut sysfs_dir = Path::new(SYS_DEV_BLOCK_DIR).join(format!("{major}:{minor}"));
+        // A partition has no `queue/` directory of its own
+        if !sysfs_dir.join("queue").exists() && sysfs_dir.join("partition").exists() {
+            sysfs_dir = tokio::fs::canonicalize(sysfs_dir.join("..")).await?;
+        }
+
+        Self::read_from_queue_dir(device, &sysfs_dir.join("queue")).await
+    }
+
+    async fn read_from_queue_dir(device: &Path, queue_dir: &Path) -> Result<Self> {
+        if !queue_dir.exists() {
+            tracing::warn!(
+                ?device,
+                "The device has no queue attributes in sysfs, the capabilities are unknown"
+            );
+        }
+
+        let read_number = |name: &'static str| async move {
+            let value = tokio::fs::read_to_string(queue_dir.join(name)).await.ok()?;
+            match value.trim().parse::<u64>() {
+                Ok(value) => Some(value),
+                Err(error) => {
+                    tracing::warn!(
+                        ?device,
+                        name,
+                        value = value.trim(),
+                        ?error,
+                        "Invalid queue attribute"
+                    );
+                    None
+                }
+            }
+        };
+
+        Ok(Self {
+            device: device.to_owned(),
+            logical_block_size: read_number("logical_block_size")
+                .await
+                .and_then(|size| size.try_into().ok()),
+            physical_block_size: read_number("physical_block_size")
+                .await
+                .and_then(|size| size.try_into().ok()),
+            rotational: read_number("rotational").await.map(|value| value != 0),
+            discard_max_bytes: read_number("discard_max_bytes").await,
+            discard_zeroes_data: read_number("discard_zeroes_data")
+                .await
+                .map(|value| value != 0),
+            write_zeroes_max_bytes: read_number("write_zeroes_max_bytes").await,
+        })
+    }
+
+    /// The LUKS2 sector size matching the physical block size of the device, if it is a valid one.
+    pub fn suggested_sector_size(&self) -> Option<u32> {
+        self.physical_block_size
+            .filter(|size| crate::fs::luks2::check_sector_size(*size).is_ok())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_from_queue_dir() -> Result<()> {
+        let queue_dir = tempfile::tempdir()?;
+        for (name, value) in [
+            ("logical_block_size", "512\n"),
+            ("physical_block_size", "4096\n"),
+            ("rotational", "0\n"),
+            ("discard_max_bytes", "2147483648\n"),
+            ("discard_zeroes_data", "0\n"),
+        ] {
+            tokio::fs::write(queue_dir.path().join(name), value).await?;
+        }
+
+        let caps = DeviceCaps::read_from_queue_dir(Path::new("/dev/vda"), queue_dir.path()).await?;
+        assert_eq!(
+            caps,
+            DeviceCaps {
+                device: "/dev/vda".into(),
+                logical_block_size: Some(512),
+                physical_block_size: Some(4096),
+                rotational: Some(false),
+                discard_max_bytes: Some(2147483648),
+                discard_zeroes_data: Some(false),
+                write_zeroes_max_bytes: None,
+            }
+        );
+        assert_eq!(caps.suggested_sector_size(), Some(4096));
+
+        // A virtual device without the queue attributes
+        let caps = DeviceCaps::read_from_queue_dir(
+            Path::new("/dev/vda"),
+            &queue_dir.path().join("not-exist"),
+        )
+        .await?;
+        assert_eq!(caps.logical_block_size, None);
+        assert_eq!(caps.suggested_sector_size(), None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_probe_not_block_device() -> Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        assert!(DeviceCaps::probe(file.path()).await.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,74 @@
+use std::{
+    io::{Read as _, Seek as _, SeekFrom, Write as _},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context as _, Result};
+use rand::Rng as _;
+
+/// Simulate data corruption by overwriting `length` bytes at `offset` of a block device or file
+/// with random bytes, each of which is guaranteed to differ from the original one. The data is
+/// flushed to the device before returning. Returns the original bytes.
+///
+/// This is meant for testing that the integrity protection (e.g. dm-integrity or verity) detects
+/// the corruption, never use it on a device with valuable data.
+pub async fn corrupt_bytes(path: &Path, offset: u64, length: usize) -> Result<Vec<u8>> {
+    if length == 0 {
+        bail!("The length of the bytes to corrupt must not be zero");
+    }
+
+    let path_buf = PathBuf::from(path);
+    tokio::task::spawn_blocking(move || {
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path_buf)?;
+
+        let mut original = vec![0u8; length];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut original)
+            .context("The range to corrupt is out of the end of the device")?;
+
+        let mut rng = rand::thread_rng();
+        let corrupted = original
+            .iter()
+            .map(|byte| byte ^ rng.gen_range(1..=u8::MAX))
+            .collect::<Vec<_>>();
+
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(&corrupted)?;
+        file.sync_all()?;
+
+        Ok::<_, anyhow::Error>(original)
+    })
+    .await?
+    .with_context(|| format!("Failed to corrupt {length} bytes at offset {offset} of {path:?}"))
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[tokio::test]
+    async fn test_corrupt_bytes() -> Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        tokio::fs::write(file.path(), vec![0x5a; 4096]).await?;
+
+        let original = corrupt_bytes(file.path(), 1000, 24).await?;
+        assert_eq!(original, vec![0x5a; 24]);
+
+        let data = tokio::fs::read(file.path()).await?;
+        assert_eq!(data.len(), 4096);
+        assert!(data[..1000].iter().all(|byte| *byte == 0x5a));
+        assert!(data[1000..1024].iter().all(|byte| *byte != 0x5a));
+        assert!(data[1024..].iter().all(|byte| *byte == 0x5a));
+
+        // Out of the end of the file
+        assert!(corrupt_bytes(file.path(), 4090, 24).await.is_err());
+        assert!(corrupt_bytes(file.path(), 0, 0).await.is_err());
+
+        Ok(())
+    }
+}
@@ -1,7 +1,42 @@
-use anyhow::{Context as _, Result};
-use devicemapper::{DevId, DmFlags, DmName, DmOptions, DM};
+use std::{
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+
+use anyhow::{bail, Context as _, Result};
+use devicemapper::{DevId, DmFlags, DmName, DmOptions, DmUuid, DM};
+use lazy_static::lazy_static;
 use rand::{distributions::Alphanumeric, Rng as _};
 
+lazy_static! {
+    static ref DM_NAME_PREFIX: RwLock<String> = RwLock::new(String::new());
+}
+
+/// Set the prefix prepended to the names of all the device-mapper devices set up by this process,
+/// e.g. "test1234-" to isolate the mappings of parallel test runs, since device-mapper names are
+/// global to the system. The default is empty.
+pub fn set_dm_name_prefix(prefix: &str) -> Result<()> {
+    if prefix.contains('/') || prefix.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        bail!("Invalid device-mapper name prefix {prefix:?}");
+    }
+    *DM_NAME_PREFIX.write().unwrap_or_else(|e| e.into_inner()) = prefix.to_owned();
+    Ok(())
+}
+
+/// The actual device-mapper name of a device named `name` by cryptpilot, with the prefix set by
+/// [`set_dm_name_prefix`].
+pub fn dm_name(name: &str) -> String {
+    format!(
+        "{}{name}",
+        DM_NAME_PREFIX.read().unwrap_or_else(|e| e.into_inner())
+    )
+}
+
+/// The path below `/dev/mapper/` of a device named `name` by cryptpilot, see [`dm_name`].
+pub fn dm_path(name: &str) -> PathBuf {
+    Path::new("/dev/mapper").join(dm_name(name))
+}
+
 pub struct DeviceMapperDevice {
     dm: DM,
     device_name: String,
@@ -16,13 +51,16 @@ impl DeviceMapperDevice {
             .take(16) // Fixed length characters
             .map(char::from)
             .collect();
-        let device_name = format!("cryptpilot-{}", random_part);
+        let device_name = dm_name(&format!("cryptpilot-{}", random_part));
+        let device_uuid = format!("CRYPTPILOT-{}", random_part);
 
         let dm = DM::new().context("Failed to communicating with device-mapper driver")?;
         let dm_name = DmName::new(&device_name)
             .with_context(|| format!("{device_name} is not a valid device name"))?;
+        let dm_uuid = DmUuid::new(&device_uuid)
+            .with_context(|| format!("{device_uuid} is not a valid device uuid"))?;
         let _dev = dm
-            .device_create(dm_name, None, DmOptions::default())
+            .device_create(dm_name, Some(dm_uuid), DmOptions::default())
             .context("Failed to create device-mapper device")?;
 
         let dm_id = DevId::Name(dm_name);
@@ -44,6 +82,25 @@ impl DeviceMapperDevice {
     pub fn path(&self) -> String {
         format!("/dev/mapper/{}", self.device_name)
     }
+
+    pub fn name(&self) -> &str {
+        &self.device_name
+    }
+}
+
+/// Get the UUID of a device-mapper device by its name, e.g. "CRYPT-LUKS2-<luks uuid>-<name>" for a
+/// device set up by cryptsetup. Returns `None` if the device has no UUID.
+pub fn get_dm_uuid(device_name: &str) -> Result<Option<String>> {
+    let dm = DM::new().context("Failed to communicating with device-mapper driver")?;
+    let dm_name = DmName::new(device_name)
+        .with_context(|| format!("{device_name} is not a valid device name"))?;
+    let info = dm
+        .device_info(&DevId::Name(dm_name))
+        .with_context(|| format!("Failed to get info of device-mapper device {device_name}"))?;
+    Ok(info
+        .uuid()
+        .map(|uuid| String::from_utf8_lossy(uuid.as_bytes()).into_owned())
+        .filter(|uuid| !uuid.is_empty()))
 }
 
 impl Drop for DeviceMapperDevice {
@@ -68,3 +125,40 @@ impl Drop for DeviceMapperDevice {
         };
     }
 }
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    // The prefix is process-wide, so the test runs in a forked process
+    #[test]
+    #[two_rusty_forks::test_fork]
+    fn test_dm_name_prefix() -> Result<()> {
+        assert!(set_dm_name_prefix("bad/prefix-").is_err());
+        assert!(set_dm_name_prefix("bad prefix-").is_err());
+
+        set_dm_name_prefix("test1234-")?;
+        assert_eq!(dm_name("rootfs"), "test1234-rootfs");
+        assert_eq!(dm_path("rootfs"), Path::new("/dev/mapper/test1234-rootfs"));
+
+        set_dm_name_prefix("")?;
+        assert_eq!(dm_name("rootfs"), "rootfs");
+        assert_eq!(dm_path("rootfs"), Path::new("/dev/mapper/rootfs"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_dm_uuid() -> Result<()> {
+        let dm_device = DeviceMapperDevice::new_zero(1024 * 1024).await?;
+
+        let uuid = get_dm_uuid(dm_device.name())?;
+        assert!(uuid.is_some_and(|uuid| uuid.starts_with("CRYPTPILOT-")));
+
+        assert!(get_dm_uuid("cryptpilot-not-exist").is_err());
+
+        Ok(())
+    }
+}
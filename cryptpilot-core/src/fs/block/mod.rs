@@ -1,3 +1,5 @@
 pub mod blktrace;
+pub mod caps;
+pub mod corrupt;
 pub mod devicemapper;
 pub mod dummy;
@@ -0,0 +1,138 @@
+use std::path::Path;
+
+use anyhow::{bail, Context as _, Result};
+use tokio::process::Command;
+
+use crate::{
+    fs::{blkid::BlkidProbeResult, cmd::CheckCommandOutput as _},
+    types::MakeFsType,
+};
+
+/// The outcome of a successful fsck run, decoded from the exit code documented in fsck(8).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsckOutcome {
+    /// The file system is clean.
+    Clean,
+    /// Errors were found and corrected.
+    Corrected,
+    /// Errors were corrected, and a reboot is needed if the file system is the root file system.
+    RebootNeeded,
+}
+
+/// Decode the exit code of fsck. Exit code 1 (errors corrected) and 2 (reboot needed) are
+/// recoverable, while any other non-zero bit (e.g. 4 for uncorrected errors and 8 for operational
+/// errors) fails the check.
+fn check_fsck_exit_code(code: i32) -> Result<FsckOutcome> {
+    match code {
+        0 => Ok(FsckOutcome::Clean),
+        1 => Ok(FsckOutcome::Corrected),
+        2 | 3 => Ok(FsckOutcome::RebootNeeded),
+        _ if code & 4 != 0 => bail!("File system errors left uncorrected (exit code {code})"),
+        _ if code & 8 != 0 => bail!("Operational error (exit code {code})"),
+        _ if code & 16 != 0 => bail!("Usage or syntax error (exit code {code})"),
+        _ if code & 32 != 0 => bail!("Checking canceled by user request (exit code {code})"),
+        _ => bail!("Unexpected exit code {code}"),
+    }
+}
+
+/// Check and repair the file system on the device automatically (without questions) with fsck.
+///
+/// The file system type is taken from `fs_hint` if set, and is detected with blkid otherwise.
+/// Returns `None` if the check is skipped, which is the case for swap and for a device without
+/// any file system.
+pub async fn fsck(device_path: &Path, fs_hint: Option<MakeFsType>) -> Result<Option<FsckOutcome>> {
+    let fs_type = match fs_hint {
+        Some(MakeFsType::Swap) => None,
+        Some(fs_type) => Some(fs_type.to_string()),
+        None => match crate::fs::blkid::probe_device(device_path).await? {
+            BlkidProbeResult::KnownSignature {
+                fs_type: Some(fs_type),
+                ..
+            } if fs_type != "swap" => Some(fs_type),
+            _ => None,
+        },
+    };
+    let Some(fs_type) = fs_type else {
+        tracing::info!("No file system to check on {device_path:?}, skip fsck");
+        return Ok(None);
+    };
+
+    tracing::info!("Checking {fs_type} fs on {device_path:?} with fsck");
+    let outcome = Command::new("fsck")
+        .arg("-p")
+        .arg("-t")
+        .arg(&fs_type)
+        .arg(device_path)
+        .run_with_status_checker(|code, _, _| check_fsck_exit_code(code))
+        .await
+        .with_context(|| format!("Failed to check {fs_type} fs on {device_path:?}"))?;
+
+    match outcome {
+        FsckOutcome::Clean => tracing::info!("The {fs_type} fs on {device_path:?} is clean"),
+        FsckOutcome::Corrected | FsckOutcome::RebootNeeded => {
+            tracing::warn!("Errors in the {fs_type} fs on {device_path:?} were corrected by fsck")
+        }
+    }
+    Ok(Some(outcome))
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    use crate::fs::block::dummy::DummyDevice;
+
+    #[test]
+    fn test_check_fsck_exit_code() {
+        assert_eq!(check_fsck_exit_code(0).unwrap(), FsckOutcome::Clean);
+        assert_eq!(check_fsck_exit_code(1).unwrap(), FsckOutcome::Corrected);
+        assert_eq!(check_fsck_exit_code(2).unwrap(), FsckOutcome::RebootNeeded);
+        assert_eq!(check_fsck_exit_code(3).unwrap(), FsckOutcome::RebootNeeded);
+        assert!(check_fsck_exit_code(4).is_err());
+        assert!(check_fsck_exit_code(5).is_err());
+        assert!(check_fsck_exit_code(8).is_err());
+        assert!(check_fsck_exit_code(32).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fsck_ext4() -> Result<()> {
+        let dummy_device = DummyDevice::setup_on_tmpfs(64 * 1024 * 1024).await?;
+        let device_path = dummy_device.path()?;
+
+        // Nothing to check on an empty device
+        assert_eq!(fsck(&device_path, None).await?, None);
+
+        Command::new("mkfs.ext4")
+            .arg("-q")
+            .arg("-F")
+            .arg(&device_path)
+            .run()
+            .await?;
+        assert_eq!(
+            fsck(&device_path, Some(MakeFsType::Ext4)).await?,
+            Some(FsckOutcome::Clean)
+        );
+        // The file system type is detected
+        assert_eq!(fsck(&device_path, None).await?, Some(FsckOutcome::Clean));
+        // Swap is never checked
+        assert_eq!(fsck(&device_path, Some(MakeFsType::Swap)).await?, None);
+
+        // Errors recorded in the superblock are corrected
+        Command::new("debugfs")
+            .arg("-w")
+            .arg("-R")
+            .arg("ssv state 2")
+            .arg(&device_path)
+            .run()
+            .await?;
+        assert_ne!(fsck(&device_path, Some(MakeFsType::Ext4)).await?, None);
+        assert_eq!(
+            fsck(&device_path, Some(MakeFsType::Ext4)).await?,
+            Some(FsckOutcome::Clean)
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,173 @@
+use std::{
+    os::fd::AsRawFd as _,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use anyhow::{bail, Context as _, Result};
+
+/// The directory holding the lock files of the devices.
+pub const DEVICE_LOCK_DIR: &str = "/run/cryptpilot/lock";
+
+pub const DEVICE_LOCK_TIMEOUT_DEFAULT: Duration = Duration::from_secs(60);
+
+const DEVICE_LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+static DEVICE_LOCK_DISABLED: AtomicBool = AtomicBool::new(false);
+
+static DEVICE_LOCK_TIMEOUT_MS: AtomicU64 =
+    AtomicU64::new(DEVICE_LOCK_TIMEOUT_DEFAULT.as_millis() as u64);
+
+/// Set how long to wait for the lock of a device held by another cryptpilot process. `None`
+/// disables the locking.
+pub fn set_device_lock_timeout(timeout: Option<Duration>) {
+    DEVICE_LOCK_DISABLED.store(timeout.is_none(), Ordering::Relaxed);
+    if let Some(timeout) = timeout {
+        DEVICE_LOCK_TIMEOUT_MS.store(timeout.as_millis() as u64, Ordering::Relaxed);
+    }
+}
+
+/// An exclusive advisory lock on a device, which serializes the operations (e.g. format, open and
+/// close) of concurrent cryptpilot processes on the same device. The lock is released on drop.
+#[derive(Debug)]
+pub struct DeviceLock {
+    _file: std::fs::File,
+    path: PathBuf,
+}
+
+impl DeviceLock {
+    /// Lock the device with the lock timeout set by [`set_device_lock_timeout`]. Returns `None` if the
+    /// locking is disabled.
+    pub async fn lock(dev: &Path) -> Result<Option<Self>> {
+        if DEVICE_LOCK_DISABLED.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+        let timeout = Duration::from_millis(DEVICE_LOCK_TIMEOUT_MS.load(Ordering::Relaxed));
+        Ok(Some(
+            Self::acquire(Path::new(DEVICE_LOCK_DIR), dev, timeout).await?,
+        ))
+    }
+
+    /// Lock the device with a lock file in the lock dir, waiting up to `timeout` if it is locked by
+    /// another process.
+    pub async fn acquire(lock_dir: &Path, dev: &Path, timeout: Duration) -> Result<Self> {
+        // Different paths of the same device (e.g. /dev/disk/by-uuid/...) share the lock
+        let dev = tokio::fs::canonicalize(dev)
+            .await
+            .unwrap_or_else(|_| dev.to_owned());
+        let path = lock_dir.join(lock_file_name(&dev));
+
+        tokio::fs::create_dir_all(lock_dir)
+            .await
+            .with_context(|| format!("Failed to create lock dir {lock_dir:?}"))?;
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open lock file {path:?}"))?;
+
+        let deadline = tokio::time::Instant::now() + timeout;
+        let mut logged = false;
+        loop {
+            match nix::errno::Errno::result(unsafe {
+                libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB)
+            }) {
+                Ok(_) => break,
+                Err(nix::errno::Errno::EWOULDBLOCK) => {}
+                Err(errno) => {
+                    return Err(errno).with_context(|| format!("Failed to lock {path:?}"));
+                }
+            }
+
+            if tokio::time::Instant::now() >= deadline {
+                bail!(
+                    "Another cryptpilot operation on {dev:?} is in progress, timed out after {timeout:?} waiting for the lock {path:?}"
+                );
+            }
+            if !logged {
+                tracing::info!("Waiting for another cryptpilot operation on {dev:?} to finish");
+                logged = true;
+            }
+            tokio::time::sleep(DEVICE_LOCK_RETRY_INTERVAL).await;
+        }
+
+        tracing::debug!(?dev, ?path, "Device locked");
+        Ok(Self { _file: file, path })
+    }
+}
+
+impl Drop for DeviceLock {
+    fn drop(&mut self) {
+        // The lock file is kept, since removing it would race with a process waiting on it
+        tracing::debug!(path = ?self.path, "Device unlocked");
+    }
+}
+
+/// Escape the device path into a file name in the same way as systemd, e.g. "/dev/vdb" to
+/// "dev-vdb.lock".
+fn lock_file_name(dev: &Path) -> String {
+    let escaped = dev
+        .to_string_lossy()
+        .trim_start_matches('/')
+        .replace('\\', "\\x5c")
+        .replace('-', "\\x2d")
+        .replace('/', "-");
+    format!("{escaped}.lock")
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn test_lock_file_name() {
+        assert_eq!(lock_file_name(Path::new("/dev/vdb")), "dev-vdb.lock");
+        assert_eq!(
+            lock_file_name(Path::new("/dev/mapper/vg-root")),
+            "dev-mapper-vg\\x2droot.lock"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_device_lock_contention() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let lock_dir = temp_dir.path().to_owned();
+        let dev = Path::new("/dev/cryptpilot-test-device");
+
+        let lock = DeviceLock::acquire(&lock_dir, dev, Duration::ZERO).await?;
+
+        // Another task times out while the lock is held
+        let error = tokio::spawn({
+            let lock_dir = lock_dir.clone();
+            async move { DeviceLock::acquire(&lock_dir, dev, Duration::from_millis(300)).await }
+        })
+        .await?
+        .unwrap_err();
+        assert!(format!("{error:#}").contains("is in progress"));
+
+        // And gets the lock once it is released
+        let waiter = tokio::spawn({
+            let lock_dir = lock_dir.clone();
+            async move { DeviceLock::acquire(&lock_dir, dev, Duration::from_secs(10)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(!waiter.is_finished());
+        drop(lock);
+        let lock = waiter.await??;
+
+        // A different device is not affected
+        DeviceLock::acquire(
+            &lock_dir,
+            Path::new("/dev/cryptpilot-other"),
+            Duration::ZERO,
+        )
+        .await?;
+
+        drop(lock);
+        Ok(())
+    }
+}
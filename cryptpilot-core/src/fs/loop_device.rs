@@ -0,0 +1,141 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context as _, Result};
+use tokio::{fs::File, io::AsyncReadExt as _, process::Command};
+
+use crate::{async_defer, fs::cmd::CheckCommandOutput as _};
+
+/// The magic at the beginning of a qcow2 image.
+const QCOW2_MAGIC: &[u8; 4] = b"QFI\xfb";
+
+/// A disk image file attached to a free loop device with `losetup --partscan`, so that its
+/// partitions are exposed as `/dev/loopNpM`, like the ones of [`super::nbd::NbdDevice`]. Unlike NBD,
+/// this needs no kernel module other than loop, but only works with raw images. The loop device
+/// is detached on drop.
+pub struct PartitionedLoopDevice {
+    path: PathBuf,
+}
+
+impl PartitionedLoopDevice {
+    /// Attach the raw disk image to a free loop device and scan its partitions. Fails for a qcow2
+    /// image, whose partitions cannot be exposed by a loop device.
+    pub async fn attach(disk_img: impl AsRef<Path>, read_only: bool) -> Result<Self> {
+        let disk_img = disk_img.as_ref();
+        if !disk_img.exists() {
+            bail!("Disk image {disk_img:?} does not exist");
+        }
+        if is_qcow2_image(disk_img).await? {
+            bail!("Disk image {disk_img:?} is a qcow2 image, which cannot be attached to a loop device. Convert it to a raw image with `qemu-img convert -O raw`, or use NBD instead");
+        }
+
+        let mut cmd = Command::new("losetup");
+        cmd.args(["--find", "--show", "--partscan"]);
+        if read_only {
+            cmd.arg("--read-only");
+        }
+        let stdout = cmd.arg(disk_img).run().await.with_context(|| {
+            format!("Failed to attach disk image {disk_img:?} to a loop device")
+        })?;
+        let path = PathBuf::from(String::from_utf8(stdout)?.trim());
+        tracing::debug!(?path, "Attached disk image {disk_img:?} to loop device");
+
+        let device = Self { path };
+        // The partition devices are created by udev asynchronously
+        if let Err(error) = Command::new("udevadm").arg("settle").run().await {
+            tracing::debug!(?error, "Failed to wait for udev to settle");
+        }
+        Ok(device)
+    }
+
+    pub fn to_path(&self) -> PathBuf {
+        self.path.clone()
+    }
+}
+
+impl Drop for PartitionedLoopDevice {
+    fn drop(&mut self) {
+        let path = self.path.clone();
+        async_defer! {
+            async {
+                if let Err(error) = Command::new("losetup")
+                    .arg("--detach")
+                    .arg(&path)
+                    .run()
+                    .await
+                {
+                    tracing::warn!(?error, "Failed to detach loop device {path:?}")
+                };
+
+                Ok::<_, anyhow::Error>(())
+            }
+        }
+    }
+}
+
+/// Check if the file starts with the magic of a qcow2 image.
+async fn is_qcow2_image(disk_img: &Path) -> Result<bool> {
+    let mut magic = [0u8; 4];
+    let mut file = File::open(disk_img)
+        .await
+        .with_context(|| format!("Failed to open disk image {disk_img:?}"))?;
+    Ok(match file.read_exact(&mut magic).await {
+        Ok(_) => &magic == QCOW2_MAGIC,
+        // Too small to be a qcow2 image
+        Err(error) if error.kind() == std::io::ErrorKind::UnexpectedEof => false,
+        Err(error) => return Err(error.into()),
+    })
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_attach_partitioned_image() -> Result<()> {
+        let disk = tempfile::NamedTempFile::new()?;
+        disk.as_file().set_len(32 * 1024 * 1024)?;
+        Command::new("sfdisk")
+            .arg(disk.path())
+            .run_with_input(Some(
+                "label: gpt\n\
+                 size=8MiB, type=C12A7328-F81F-11D2-BA4B-00A0C93EC93B, name=\"EFI System\"\n\
+                 type=0FC63DAF-8483-4772-8E79-3D69D8477DE4, name=\"boot\"\n"
+                    .as_bytes(),
+            ))
+            .await?;
+
+        let loop_device = PartitionedLoopDevice::attach(disk.path(), true).await?;
+        let path = loop_device.to_path();
+        assert!(path.starts_with("/dev"));
+        for part in 1..=2 {
+            let part_path = PathBuf::from(format!("{}p{part}", path.display()));
+            assert!(part_path.exists(), "{part_path:?} does not exist");
+        }
+
+        // The loop device is detached on drop
+        drop(loop_device);
+        let stdout = Command::new("losetup")
+            .args(["--list", "--noheadings", "--output", "NAME", "--associated"])
+            .arg(disk.path())
+            .run()
+            .await?;
+        assert!(String::from_utf8(stdout)?.trim().is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_attach_qcow2_image() -> Result<()> {
+        let disk = tempfile::NamedTempFile::new()?;
+        tokio::fs::write(disk.path(), b"QFI\xfb\x00\x00\x00\x03").await?;
+        let error = match PartitionedLoopDevice::attach(disk.path(), true).await {
+            Ok(_) => bail!("A qcow2 image should not be attached to a loop device"),
+            Err(error) => error,
+        };
+        assert!(format!("{error:#}").contains("qcow2"));
+
+        Ok(())
+    }
+}
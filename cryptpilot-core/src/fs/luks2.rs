@@ -1,26 +1,56 @@
+use std::os::fd::AsRawFd as _;
 use std::path::{Path, PathBuf};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use libcryptsetup_rs::{
     consts::{
         flags::{CryptActivate, CryptDeactivate, CryptVolumeKey},
-        vals::{CryptDebugLevel, EncryptionFormat},
+        vals::{CryptDebugLevel, EncryptionFormat, KeyslotsSize, MetadataSize},
     },
-    CryptInit, CryptParamsLuks2, CryptParamsLuks2Ref,
+    CryptInit, CryptParamsLuks2, CryptParamsLuks2Ref, LibcryptErr,
 };
 use rand::{distributions::Alphanumeric, Rng as _};
+use serde::Serialize;
 use tokio::fs::OpenOptions;
 use tokio::io::AsyncReadExt;
+use tokio::process::Command;
 
-use crate::types::{IntegrityType, Passphrase};
+use crate::types::{IntegrityTuning, IntegrityType, Luks2Cipher, Passphrase};
 
-use super::get_verbose;
+use super::{
+    block::devicemapper::{dm_name, dm_path},
+    cmd::CheckCommandOutput as _,
+    get_verbose,
+};
 
-const LUKS2_VOLUME_KEY_SIZE_BIT_WITH_INTEGRITY: usize = 768;
-const LUKS2_VOLUME_KEY_SIZE_BIT_WITHOUT_INTEGRITY: usize = 512;
-const LUKS2_SECTOR_SIZE: u32 = 4096;
+/// The integrity algorithm of a volume with integrity, whose key is appended to the volume key.
+const LUKS2_INTEGRITY_ALGORITHM: &str = "hmac(sha256)";
+const LUKS2_INTEGRITY_KEY_SIZE_BIT: usize = 256;
+const LUKS2_SECTOR_SIZE_MIN: u32 = 512;
+const LUKS2_SECTOR_SIZE_MAX: u32 = 4096;
+/// The allowed sizes of the LUKS2 metadata area (the JSON area and the binary header).
+const LUKS2_METADATA_SIZES: [u64; 9] = [
+    16 * 1024,
+    32 * 1024,
+    64 * 1024,
+    128 * 1024,
+    256 * 1024,
+    512 * 1024,
+    1024 * 1024,
+    2048 * 1024,
+    4096 * 1024,
+];
+/// The default size of the LUKS2 metadata area used by libcryptsetup.
+const LUKS2_METADATA_SIZE_DEFAULT: u64 = 16 * 1024;
+const LUKS2_KEYSLOTS_SIZE_ALIGNMENT: u64 = 4096;
+const LUKS2_KEYSLOTS_SIZE_MAX: u64 = 128 * 1024 * 1024;
+const INTEGRITY_JOURNAL_WATERMARK_MAX: u32 = 100;
+const INTEGRITY_JOURNAL_COMMIT_TIME_MAX_MS: u32 = 60 * 60 * 1000;
 const LUKS2_SUBSYSTEM_NAME: &str = "cryptpilot";
 const LUKS2_SUBSYSTEM_INITIALIZING: &str = "cryptpilot-initializing";
+/// The label of an initialized volume whose file system is created by `makefs` but has never been
+/// opened since then.
+const LUKS2_LABEL_FRESH_FS: &str = "cryptpilot-fresh-fs";
 
 /// Represents the initialization state of a LUKS2 volume managed by cryptpilot.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -34,9 +64,71 @@ pub enum VolumeInitState {
     /// LUKS2 header exists with subsystem="cryptpilot".
     /// Volume is fully initialized and ready to open.
     Ready,
+    /// Legacy LUKS1 header, e.g. created by an old cryptsetup. It can be opened, but should be
+    /// converted to LUKS2 with `convert-to-luks2`. Not safe to format.
+    Luks1,
+}
+
+/// Map the verbose flag to the libcryptsetup debug level.
+fn debug_level(verbose: bool) -> CryptDebugLevel {
+    if verbose {
+        CryptDebugLevel::All
+    } else {
+        CryptDebugLevel::None
+    }
+}
+
+/// Set the libcryptsetup debug level according to the verbose flag. This should be called at the
+/// beginning of every `spawn_blocking` closure which calls into libcryptsetup.
+///
+/// The debug level only affects logging, so a failure (e.g. the level is not supported by the
+/// library build) is logged and ignored instead of aborting the crypto operation.
+fn set_debug_level(verbose: bool) {
+    let level = debug_level(verbose);
+    if std::panic::catch_unwind(|| libcryptsetup_rs::set_debug_level(level)).is_err() {
+        tracing::warn!(
+            verbose,
+            "Failed to set libcryptsetup debug level, ignore it"
+        );
+    }
+}
+
+/// Read the version of the LUKS header on the device, e.g. 1 for LUKS1 and 2 for LUKS2. Returns
+/// `None` if there is no LUKS header.
+pub async fn get_luks_version(dev: &Path) -> Result<Option<u16>> {
+    let mut file = tokio::fs::File::open(dev).await?;
+    // magic[6] and version[2] in big endian, which are the same in LUKS1 and LUKS2
+    let mut header = [0u8; 8];
+    file.read_exact(&mut header).await?;
+    if header[..6] != *b"LUKS\xba\xbe" {
+        return Ok(None);
+    }
+    Ok(Some(u16::from_be_bytes([header[6], header[7]])))
+}
+
+/// Check if the device has a legacy LUKS1 header.
+pub async fn is_luks1(dev: &Path) -> bool {
+    matches!(get_luks_version(dev).await, Ok(Some(1)))
+}
+
+/// The format to load the header of the device with, which is LUKS2 unless the device has a
+/// legacy LUKS1 header.
+async fn luks_format_of(dev: &Path) -> EncryptionFormat {
+    if is_luks1(dev).await {
+        EncryptionFormat::Luks1
+    } else {
+        EncryptionFormat::Luks2
+    }
 }
 
 async fn get_luks2_subsystem(dev: &Path) -> Result<Option<String>> {
+    get_luks2_label_and_subsystem(dev)
+        .await
+        .map(|(_, subsystem)| subsystem)
+}
+
+/// Read the label and the subsystem from the binary header of the LUKS2 volume.
+async fn get_luks2_label_and_subsystem(dev: &Path) -> Result<(Option<String>, Option<String>)> {
     /// LUKS2 header structure according to the specification
     /// Reference: https://gitlab.com/cryptsetup/cryptsetup/-/blob/24d10f412e2ca1b0a8ed5addb1381507662a9862/lib/luks2/luks2.h
     #[repr(C, packed)]
@@ -82,31 +174,294 @@ async fn get_luks2_subsystem(dev: &Path) -> Result<Option<String>> {
         ));
     }
 
-    let subsystem_str = match header.subsystem.iter().position(|&x| x == 0) {
-        Some(pos) => String::from_utf8_lossy(&header.subsystem[..pos]).to_string(),
-        None => String::from_utf8_lossy(&header.subsystem).to_string(),
+    let parse_field = |field: &[u8]| {
+        let field_str = match field.iter().position(|&x| x == 0) {
+            Some(pos) => String::from_utf8_lossy(&field[..pos]).to_string(),
+            None => String::from_utf8_lossy(field).to_string(),
+        };
+        (!field_str.is_empty() && field_str != "-").then_some(field_str)
     };
 
-    if !subsystem_str.is_empty() && subsystem_str != "-" {
+    let label = parse_field(&header.label);
+    let subsystem = parse_field(&header.subsystem);
+    if let Some(subsystem_str) = &subsystem {
         tracing::debug!("Found LUKS2 subsystem in binary header: {}", subsystem_str);
-        Ok(Some(subsystem_str))
-    } else {
-        Ok(None)
     }
+
+    Ok((label, subsystem))
+}
+
+/// The size of the binary header of a LUKS2 volume, which is followed by the JSON area.
+const LUKS2_BINARY_HEADER_SIZE: usize = 4096;
+/// The maximum size of the whole LUKS2 header (binary header and JSON area) allowed by the spec.
+const LUKS2_HEADER_SIZE_MAX: usize = 4 * 1024 * 1024;
+
+/// Read the JSON metadata area of the LUKS2 volume.
+async fn get_luks2_json_metadata(dev: &Path) -> Result<serde_json::Value> {
+    let mut file = tokio::fs::File::open(dev).await?;
+
+    let mut binary_header = vec![0u8; LUKS2_BINARY_HEADER_SIZE];
+    file.read_exact(&mut binary_header).await?;
+    // magic[6], version[2] and hdr_size[8], all in big endian
+    if !(binary_header[..6] == *b"LUKS\xba\xbe" || binary_header[..6] == *b"SKUL\xba\xbe")
+        || u16::from_be_bytes([binary_header[6], binary_header[7]]) != 2
+    {
+        bail!("Invalid LUKS2 header on {dev:?}");
+    }
+    let hdr_size = u64::from_be_bytes(binary_header[8..16].try_into()?) as usize;
+    if !(LUKS2_BINARY_HEADER_SIZE..=LUKS2_HEADER_SIZE_MAX).contains(&hdr_size) {
+        bail!("Invalid LUKS2 header size {hdr_size} on {dev:?}");
+    }
+
+    let mut json_area = vec![0u8; hdr_size - LUKS2_BINARY_HEADER_SIZE];
+    file.read_exact(&mut json_area).await?;
+    // The JSON area is padded with zeros
+    let json_len = json_area
+        .iter()
+        .position(|&x| x == 0)
+        .unwrap_or(json_area.len());
+    serde_json::from_slice(&json_area[..json_len])
+        .with_context(|| format!("Failed to parse LUKS2 JSON metadata on {dev:?}"))
+}
+
+/// Check if the data integrity protection is enabled on the LUKS2 volume, according to the
+/// `integrity` field of the data segments in the LUKS2 header.
+pub async fn is_integrity_enabled(dev: &Path) -> Result<bool> {
+    // LUKS1 does not support data integrity protection
+    if is_luks1(dev).await {
+        return Ok(false);
+    }
+    let metadata = get_luks2_json_metadata(dev).await?;
+    let segments = metadata
+        .get("segments")
+        .and_then(|segments| segments.as_object())
+        .with_context(|| format!("No segments found in LUKS2 header on {dev:?}"))?;
+    Ok(segments.values().any(|segment| {
+        segment
+            .get("integrity")
+            .is_some_and(|integrity| !integrity.is_null())
+    }))
+}
+
+/// The data integrity protection of a LUKS2 volume, read from the LUKS2 header.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct IntegrityStatus {
+    /// The integrity algorithm of the data segment, e.g. "hmac(sha256)".
+    pub algorithm: String,
+    /// Size in bits of the volume key, which holds both the encryption key and the integrity key.
+    pub volume_key_size: Option<usize>,
+    /// Size in bits of the integrity key at the tail of the volume key, or `None` if the algorithm
+    /// is not keyed separately (e.g. the AEAD modes).
+    pub integrity_key_size: Option<usize>,
+    /// The digest of the volume key stored in the LUKS2 header. It changes only when the volume key,
+    /// and so the integrity key, is regenerated by reformatting the volume.
+    pub volume_key_digest: Option<String>,
+}
+
+/// Get the size in bits of the key of a keyed integrity algorithm.
+fn integrity_key_size(algorithm: &str) -> Option<usize> {
+    match algorithm {
+        "hmac(sha1)" => Some(160),
+        "hmac(sha256)" => Some(256),
+        "hmac(sha512)" => Some(512),
+        _ => None,
+    }
+}
+
+/// Get the status of the data integrity protection of the LUKS2 volume. Returns `None` if the
+/// integrity is not enabled.
+///
+/// Note that the integrity key is part of the volume key, which libcryptsetup cannot change on a
+/// volume with integrity (LUKS2 reencryption does not support it), so the only way to rotate the
+/// integrity key is to reformat the volume.
+pub async fn get_integrity_status(dev: &Path) -> Result<Option<IntegrityStatus>> {
+    if is_luks1(dev).await {
+        return Ok(None);
+    }
+    let metadata = get_luks2_json_metadata(dev).await?;
+    let segments = metadata
+        .get("segments")
+        .and_then(|segments| segments.as_object())
+        .with_context(|| format!("No segments found in LUKS2 header on {dev:?}"))?;
+    let Some(algorithm) = segments.values().find_map(|segment| {
+        segment
+            .get("integrity")
+            .and_then(|integrity| integrity.get("type"))
+            .and_then(|integrity_type| integrity_type.as_str())
+    }) else {
+        return Ok(None);
+    };
+
+    // All the keyslots and digests refer to the same volume key
+    let first_of = |field: &str| {
+        metadata
+            .get(field)
+            .and_then(|objects| objects.as_object())
+            .and_then(|objects| objects.values().next())
+    };
+    let volume_key_size = first_of("keyslots")
+        .and_then(|keyslot| keyslot.get("key_size"))
+        .and_then(|key_size| key_size.as_u64())
+        .map(|key_size| key_size as usize * 8);
+    let volume_key_digest = first_of("digests")
+        .and_then(|digest| digest.get("digest"))
+        .and_then(|digest| digest.as_str())
+        .map(ToOwned::to_owned);
+
+    Ok(Some(IntegrityStatus {
+        algorithm: algorithm.to_owned(),
+        volume_key_size,
+        integrity_key_size: integrity_key_size(algorithm),
+        volume_key_digest,
+    }))
+}
+
+nix::ioctl_read_bad!(blksszget, nix::request_code_none!(0x12, 104), libc::c_int);
+
+/// Check if the sector size is supported by LUKS2, which must be a power of two between 512 and
+/// 4096 bytes.
+pub fn check_sector_size(sector_size: u32) -> Result<()> {
+    if !sector_size.is_power_of_two()
+        || !(LUKS2_SECTOR_SIZE_MIN..=LUKS2_SECTOR_SIZE_MAX).contains(&sector_size)
+    {
+        bail!(
+            "Invalid sector size {sector_size}, should be a power of two between {LUKS2_SECTOR_SIZE_MIN} and {LUKS2_SECTOR_SIZE_MAX}"
+        );
+    }
+    Ok(())
+}
+
+/// The sizes of the LUKS2 header areas. The default sizes of libcryptsetup are used for the unset
+/// ones.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Luks2AreaSize {
+    /// Size in bytes of the metadata area, which holds the JSON metadata (e.g. keyslots and tokens).
+    pub metadata_size: Option<u64>,
+    /// Size in bytes of the binary keyslots area, which holds the encrypted volume keys.
+    pub keyslots_size: Option<u64>,
+}
+
+/// Check if the size of the LUKS2 metadata area is supported, which must be a power of two
+/// between 16 KiB and 4 MiB.
+pub fn check_metadata_size(metadata_size: u64) -> Result<()> {
+    if !LUKS2_METADATA_SIZES.contains(&metadata_size) {
+        bail!(
+            "Invalid LUKS2 metadata size {metadata_size}, should be one of {}",
+            LUKS2_METADATA_SIZES
+                .iter()
+                .map(|size| size.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Check if the size of the LUKS2 keyslots area is supported, which must be a multiple of 4 KiB
+/// and at most 128 MiB.
+pub fn check_keyslots_size(keyslots_size: u64) -> Result<()> {
+    if keyslots_size == 0
+        || keyslots_size % LUKS2_KEYSLOTS_SIZE_ALIGNMENT != 0
+        || keyslots_size > LUKS2_KEYSLOTS_SIZE_MAX
+    {
+        bail!(
+            "Invalid LUKS2 keyslots size {keyslots_size}, should be a multiple of {LUKS2_KEYSLOTS_SIZE_ALIGNMENT} and at most {LUKS2_KEYSLOTS_SIZE_MAX}"
+        );
+    }
+    Ok(())
 }
 
-pub async fn format(dev: &Path, passphrase: &Passphrase, integrity: IntegrityType) -> Result<()> {
+/// Get the logical block size of the block device.
+pub async fn get_logical_block_size(dev: &Path) -> Result<u32> {
+    let file = tokio::fs::File::open(dev).await?;
+    let mut block_size: libc::c_int = 0;
+    unsafe { blksszget(file.as_raw_fd(), &mut block_size) }
+        .with_context(|| format!("Failed to get logical block size of {dev:?}"))?;
+    Ok(block_size as u32)
+}
+
+/// Check that the tunables of the dm-integrity journal are in range.
+pub fn check_integrity_tuning(tuning: &IntegrityTuning) -> Result<()> {
+    if let Some(watermark) = tuning.journal_watermark {
+        if watermark > INTEGRITY_JOURNAL_WATERMARK_MAX {
+            bail!(
+                "Invalid journal watermark {watermark}, should be a percentage between 0 and {INTEGRITY_JOURNAL_WATERMARK_MAX}"
+            );
+        }
+    }
+    if let Some(commit_time) = tuning.journal_commit_time {
+        if !(1..=INTEGRITY_JOURNAL_COMMIT_TIME_MAX_MS).contains(&commit_time) {
+            bail!(
+                "Invalid journal commit time {commit_time}, should be between 1 and {INTEGRITY_JOURNAL_COMMIT_TIME_MAX_MS} milliseconds"
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Detect the sector size to use for the device from its logical block size, clamped to the range
+/// supported by LUKS2.
+async fn detect_sector_size(dev: &Path) -> Result<u32> {
+    let block_size = get_logical_block_size(dev).await?;
+    let sector_size = block_size.clamp(LUKS2_SECTOR_SIZE_MIN, LUKS2_SECTOR_SIZE_MAX);
+    tracing::debug!(
+        "Detected logical block size {block_size} of {dev:?}, using sector size {sector_size}"
+    );
+    Ok(sector_size)
+}
+
+/// Format the device as a LUKS2 volume encrypted with the cipher. If `sector_size` is `None`, it is
+/// detected from the logical block size of the device.
+pub async fn format(
+    dev: &Path,
+    passphrase: &Passphrase,
+    integrity: IntegrityType,
+    cipher: Luks2Cipher,
+    sector_size: Option<u32>,
+) -> Result<()> {
+    format_with_area_size(
+        dev,
+        passphrase,
+        integrity,
+        cipher,
+        sector_size,
+        Luks2AreaSize::default(),
+    )
+    .await
+}
+
+/// Same as [`format`], with the sizes of the LUKS2 header areas, e.g. to provision room for more
+/// keyslots and tokens than the default header can hold.
+pub async fn format_with_area_size(
+    dev: &Path,
+    passphrase: &Passphrase,
+    integrity: IntegrityType,
+    cipher: Luks2Cipher,
+    sector_size: Option<u32>,
+    area_size: Luks2AreaSize,
+) -> Result<()> {
+    passphrase
+        .validate(false)
+        .with_context(|| format!("Refusing to format {dev:?} with the passphrase"))?;
     let passphrase = passphrase.to_owned();
     let verbose = get_verbose().await;
 
+    let sector_size = match sector_size {
+        Some(sector_size) => sector_size,
+        None => detect_sector_size(dev).await?,
+    };
+    check_sector_size(sector_size)?;
+    if let Some(metadata_size) = area_size.metadata_size {
+        check_metadata_size(metadata_size)?;
+    }
+    if let Some(keyslots_size) = area_size.keyslots_size {
+        check_keyslots_size(keyslots_size)?;
+    }
+
     let device_path = PathBuf::from(&dev);
 
     tokio::task::spawn_blocking(move || {
-        if verbose {
-            libcryptsetup_rs::set_debug_level(CryptDebugLevel::All);
-        } else {
-            libcryptsetup_rs::set_debug_level(CryptDebugLevel::None);
-        }
+        set_debug_level(verbose);
 
         let mut params = CryptParamsLuks2 {
             integrity: None,
@@ -114,28 +469,40 @@ pub async fn format(dev: &Path, passphrase: &Passphrase, integrity: IntegrityTyp
             integrity_params: None,
             data_alignment: 0,
             data_device: None,
-            sector_size: LUKS2_SECTOR_SIZE,
+            sector_size,
             label: None,
             subsystem: Some(LUKS2_SUBSYSTEM_INITIALIZING.to_owned()),
         };
 
-        let volume_key = match integrity {
-            IntegrityType::None => {
-                libcryptsetup_rs::Either::Right(LUKS2_VOLUME_KEY_SIZE_BIT_WITHOUT_INTEGRITY / 8)
-            }
+        // With integrity, the volume key holds the integrity key after the encryption key
+        let volume_key_size_bit = match integrity {
+            IntegrityType::None => cipher.key_size_bit(),
             IntegrityType::Journal | IntegrityType::NoJournal => {
-                params.integrity = Some("hmac(sha256)".to_owned());
-                libcryptsetup_rs::Either::Right(LUKS2_VOLUME_KEY_SIZE_BIT_WITH_INTEGRITY / 8)
+                params.integrity = Some(LUKS2_INTEGRITY_ALGORITHM.to_owned());
+                cipher.key_size_bit() + LUKS2_INTEGRITY_KEY_SIZE_BIT
             }
         };
+        let volume_key = libcryptsetup_rs::Either::Right(volume_key_size_bit / 8);
 
         let mut params_ref = (&params).try_into()?;
 
         let mut device = CryptInit::init(&device_path)?;
 
+        if area_size != Luks2AreaSize::default() {
+            // A zero keyslots size lets libcryptsetup calculate the default one
+            device.settings_handle().set_metadata_size(
+                MetadataSize::try_from(
+                    area_size
+                        .metadata_size
+                        .unwrap_or(LUKS2_METADATA_SIZE_DEFAULT),
+                )?,
+                KeyslotsSize::try_from(area_size.keyslots_size.unwrap_or(0))?,
+            )?;
+        }
+
         device.context_handle().format::<CryptParamsLuks2Ref>(
             EncryptionFormat::Luks2,
-            ("aes", "xts-plain64"),
+            cipher.cipher_and_mode(),
             None,
             volume_key,
             Some(&mut params_ref),
@@ -156,16 +523,36 @@ pub async fn format(dev: &Path, passphrase: &Passphrase, integrity: IntegrityTyp
 }
 
 pub async fn mark_volume_as_initialized(dev: &Path) -> Result<()> {
+    set_initialized_label(dev, None).await
+}
+
+/// Mark the volume as initialized, and record that the file system on it is freshly created by
+/// `makefs`. The record is kept until it is cleared by [`clear_fresh_fs_mark`].
+pub async fn mark_volume_as_initialized_with_fresh_fs(dev: &Path) -> Result<()> {
+    set_initialized_label(dev, Some(LUKS2_LABEL_FRESH_FS)).await
+}
+
+/// Clear the record set by [`mark_volume_as_initialized_with_fresh_fs`], after the volume has been
+/// opened for the first time. The volume is kept as initialized.
+pub async fn clear_fresh_fs_mark(dev: &Path) -> Result<()> {
+    set_initialized_label(dev, None).await
+}
+
+/// Check if the volume is initialized with a freshly created file system, which has never been
+/// opened since then.
+pub async fn is_fs_fresh(dev: &Path) -> Result<bool> {
+    let (label, subsystem) = get_luks2_label_and_subsystem(dev).await?;
+    Ok(subsystem.as_deref() == Some(LUKS2_SUBSYSTEM_NAME)
+        && label.as_deref() == Some(LUKS2_LABEL_FRESH_FS))
+}
+
+async fn set_initialized_label(dev: &Path, label: Option<&'static str>) -> Result<()> {
     let verbose = get_verbose().await;
     let dev_path = dev.to_path_buf();
     let dev_path_for_error = dev_path.clone();
 
     tokio::task::spawn_blocking(move || {
-        if verbose {
-            libcryptsetup_rs::set_debug_level(CryptDebugLevel::All);
-        } else {
-            libcryptsetup_rs::set_debug_level(CryptDebugLevel::None);
-        }
+        set_debug_level(verbose);
 
         let mut device = CryptInit::init(&dev_path)?;
 
@@ -177,7 +564,7 @@ pub async fn mark_volume_as_initialized(dev: &Path) -> Result<()> {
         // Mark the volume as initialized by setting the subsystem to "cryptpilot"
         device
             .context_handle()
-            .set_label(None, Some(LUKS2_SUBSYSTEM_NAME))?;
+            .set_label(label, Some(LUKS2_SUBSYSTEM_NAME))?;
 
         Ok::<_, anyhow::Error>(())
     })
@@ -192,24 +579,50 @@ pub async fn mark_volume_as_initialized(dev: &Path) -> Result<()> {
     Ok(())
 }
 
+/// Find the first passphrase among the candidates which unlocks the device, e.g. the old and the
+/// new passphrases during a key rotation. With a single candidate, it is returned without checking,
+/// and is checked when it is used.
+pub async fn select_passphrase(dev: &Path, candidates: Vec<Passphrase>) -> Result<Passphrase> {
+    if candidates.len() <= 1 {
+        return candidates
+            .into_iter()
+            .next()
+            .with_context(|| format!("No passphrase candidate to unlock {dev:?}"));
+    }
+
+    let count = candidates.len();
+    for (index, candidate) in candidates.into_iter().enumerate() {
+        match verify_passphrase(dev, &candidate).await {
+            Ok(_) => {
+                tracing::info!("Passphrase candidate {}/{count} unlocks {dev:?}", index + 1);
+                return Ok(candidate);
+            }
+            Err(error) => {
+                tracing::debug!(
+                    ?error,
+                    "Passphrase candidate {}/{count} does not unlock {dev:?}",
+                    index + 1
+                );
+            }
+        }
+    }
+    bail!("None of the {count} passphrase candidates unlocks {dev:?}")
+}
+
 pub async fn check_passphrase(dev: &Path, passphrase: &Passphrase) -> Result<(), anyhow::Error> {
+    passphrase.validate(false)?;
     let passphrase = passphrase.to_owned();
     let verbose = get_verbose().await;
+    let format = luks_format_of(dev).await;
 
     let device_path = PathBuf::from(&dev);
 
     tokio::task::spawn_blocking(move || {
-        if verbose {
-            libcryptsetup_rs::set_debug_level(CryptDebugLevel::All);
-        } else {
-            libcryptsetup_rs::set_debug_level(CryptDebugLevel::None);
-        }
+        set_debug_level(verbose);
 
         let mut device = CryptInit::init(&device_path)?;
 
-        device
-            .context_handle()
-            .load::<()>(Some(EncryptionFormat::Luks2), None)?;
+        device.context_handle().load::<()>(Some(format), None)?;
         device.activate_handle().activate_by_passphrase(
             None,
             None,
@@ -225,11 +638,201 @@ pub async fn check_passphrase(dev: &Path, passphrase: &Passphrase) -> Result<(),
     Ok(())
 }
 
+/// Whether the error of libcryptsetup means that no keyslot matches the passphrase.
+fn is_wrong_passphrase_error(error: &LibcryptErr) -> bool {
+    matches!(error, LibcryptErr::IOError(error) if error.raw_os_error() == Some(libc::EPERM))
+}
+
+/// A lighter alternative to [`check_passphrase`] for frequent checks (e.g. health probes), which
+/// only tests the passphrase against the keyslots by retrieving the volume key, without going
+/// through the activation path of libcryptsetup. Returns the keyslot unlocked with the passphrase.
+///
+/// If the volume key cannot be retrieved for a reason other than a wrong passphrase, e.g. the
+/// keyslots are not accessible this way, it falls back to [`get_keyslot_by_passphrase`], which
+/// checks the passphrase with a test activation.
+pub async fn verify_passphrase(dev: &Path, passphrase: &Passphrase) -> Result<u32> {
+    passphrase.validate(false)?;
+    let passphrase_for_test = passphrase.to_owned();
+    let verbose = get_verbose().await;
+    let format = luks_format_of(dev).await;
+
+    let device_path = PathBuf::from(&dev);
+
+    let result = tokio::task::spawn_blocking(move || {
+        set_debug_level(verbose);
+
+        let mut device = CryptInit::init(&device_path)?;
+
+        device.context_handle().load::<()>(Some(format), None)?;
+        let volume_key_size = device.status_handle().get_volume_key_size();
+        let mut volume_key = zeroize::Zeroizing::new(vec![0u8; volume_key_size.max(0) as usize]);
+        let keyslot = device.volume_key_handle().get(
+            None,
+            volume_key.as_mut_slice(),
+            Some(passphrase_for_test.as_bytes()),
+        );
+
+        Ok::<_, anyhow::Error>(keyslot)
+    })
+    .await?
+    .with_context(|| format!("Failed to load LUKS header of device {dev:?}"))?;
+
+    match result {
+        Ok((keyslot, _)) => Ok(keyslot as u32),
+        Err(error) if is_wrong_passphrase_error(&error) => Err(anyhow::Error::from(error))
+            .with_context(|| format!("No keyslot of device {dev:?} matches the passphrase")),
+        Err(error) => {
+            tracing::debug!(
+                ?error,
+                "Cannot test the passphrase against the keyslots of {dev:?}, fall back to a test activation"
+            );
+            get_keyslot_by_passphrase(dev, passphrase).await
+        }
+    }
+}
+
+/// Get the keyslot of the LUKS2 volume which can be unlocked with the passphrase.
+pub async fn get_keyslot_by_passphrase(dev: &Path, passphrase: &Passphrase) -> Result<u32> {
+    let passphrase = passphrase.to_owned();
+    let verbose = get_verbose().await;
+    let format = luks_format_of(dev).await;
+
+    let device_path = PathBuf::from(&dev);
+
+    let keyslot = tokio::task::spawn_blocking(move || {
+        set_debug_level(verbose);
+
+        let mut device = CryptInit::init(&device_path)?;
+
+        device.context_handle().load::<()>(Some(format), None)?;
+        let keyslot = device.activate_handle().activate_by_passphrase(
+            None,
+            None,
+            passphrase.as_bytes(),
+            CryptActivate::empty(),
+        )?;
+
+        Ok::<_, anyhow::Error>(keyslot)
+    })
+    .await?
+    .with_context(|| format!("No keyslot of device {dev:?} matches the passphrase"))?;
+
+    Ok(keyslot)
+}
+
+/// Add a new passphrase to a free keyslot of the LUKS2 volume, which is unlocked with an existing
+/// passphrase. Returns the keyslot of the new passphrase.
+pub async fn add_passphrase(
+    dev: &Path,
+    passphrase: &Passphrase,
+    new_passphrase: &Passphrase,
+) -> Result<u32> {
+    let passphrase = passphrase.to_owned();
+    let new_passphrase = new_passphrase.to_owned();
+    let verbose = get_verbose().await;
+
+    let device_path = PathBuf::from(&dev);
+
+    let keyslot = tokio::task::spawn_blocking(move || {
+        set_debug_level(verbose);
+
+        let mut device = CryptInit::init(&device_path)?;
+
+        device
+            .context_handle()
+            .load::<()>(Some(EncryptionFormat::Luks2), None)?;
+        let keyslot = device.keyslot_handle().add_by_passphrase(
+            None,
+            passphrase.as_bytes(),
+            new_passphrase.as_bytes(),
+        )?;
+
+        Ok::<_, anyhow::Error>(keyslot)
+    })
+    .await?
+    .with_context(|| format!("Failed to add passphrase to device {dev:?}"))?;
+
+    Ok(keyslot)
+}
+
+/// Destroy the keyslot of the LUKS2 volume. The passphrase in this keyslot can no longer be used
+/// to unlock the volume.
+pub async fn destroy_keyslot(dev: &Path, keyslot: u32) -> Result<()> {
+    let verbose = get_verbose().await;
+
+    let device_path = PathBuf::from(&dev);
+
+    tokio::task::spawn_blocking(move || {
+        set_debug_level(verbose);
+
+        let mut device = CryptInit::init(&device_path)?;
+
+        device
+            .context_handle()
+            .load::<()>(Some(EncryptionFormat::Luks2), None)?;
+        device.keyslot_handle().destroy(keyslot)?;
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await?
+    .with_context(|| format!("Failed to destroy keyslot {keyslot} of device {dev:?}"))?;
+
+    Ok(())
+}
+
+/// The flags used to activate a volume. With `allow_discards`, discard (TRIM) requests are passed
+/// through to the underlying device.
+fn activate_flags(integrity: IntegrityType, allow_discards: bool) -> CryptActivate {
+    let mut flags = match integrity {
+        IntegrityType::None | IntegrityType::Journal => CryptActivate::empty(),
+        IntegrityType::NoJournal => CryptActivate::NO_JOURNAL,
+    };
+    if allow_discards {
+        flags |= CryptActivate::ALLOW_DISCARDS;
+    }
+    flags
+}
+
 pub async fn open_with_check_passphrase(
     volume: &str,
     dev: &Path,
     passphrase: &Passphrase,
     integrity: IntegrityType,
+    allow_discards: bool,
+) -> Result<(), anyhow::Error> {
+    open_with_flags(
+        volume,
+        dev,
+        passphrase,
+        integrity,
+        activate_flags(integrity, allow_discards),
+    )
+    .await
+}
+
+/// Open the volume read-only, e.g. to inspect the file system on it without changing the device.
+pub async fn open_read_only_with_check_passphrase(
+    volume: &str,
+    dev: &Path,
+    passphrase: &Passphrase,
+    integrity: IntegrityType,
+) -> Result<(), anyhow::Error> {
+    open_with_flags(
+        volume,
+        dev,
+        passphrase,
+        integrity,
+        activate_flags(integrity, false) | CryptActivate::READONLY,
+    )
+    .await
+}
+
+async fn open_with_flags(
+    volume: &str,
+    dev: &Path,
+    passphrase: &Passphrase,
+    integrity: IntegrityType,
+    flags: CryptActivate,
 ) -> Result<(), anyhow::Error> {
     crate::fs::kernel_module::ensure_module_loaded("dm_crypt", &[]).await;
 
@@ -239,33 +842,25 @@ pub async fn open_with_check_passphrase(
 
     let passphrase = passphrase.to_owned();
     let verbose = get_verbose().await;
+    let format = luks_format_of(dev).await;
 
     crate::fs::luks2::check_passphrase(dev, &passphrase)
         .await
         .with_context(||format!("Passphrase verification failed for volume {}: the passphrase is likely incorrect. Please check your passphrase configuration.", volume))?;
 
     let device_path = PathBuf::from(&dev);
-    let volume_name = volume.to_owned();
+    let volume_name = dm_name(volume);
     tokio::task::spawn_blocking(move || {
-        if verbose {
-            libcryptsetup_rs::set_debug_level(CryptDebugLevel::All);
-        } else {
-            libcryptsetup_rs::set_debug_level(CryptDebugLevel::None);
-        }
+        set_debug_level(verbose);
 
         let mut device = CryptInit::init(&device_path)?;
 
-        device
-            .context_handle()
-            .load::<()>(Some(EncryptionFormat::Luks2), None)?;
+        device.context_handle().load::<()>(Some(format), None)?;
         device.activate_handle().activate_by_passphrase(
             Some(&volume_name),
             None,
             passphrase.as_bytes(),
-            match integrity {
-                IntegrityType::None | IntegrityType::Journal => CryptActivate::empty(),
-                IntegrityType::NoJournal => CryptActivate::empty() | CryptActivate::NO_JOURNAL,
-            },
+            flags,
         )?;
 
         Ok::<_, anyhow::Error>(())
@@ -276,6 +871,96 @@ pub async fn open_with_check_passphrase(
     Ok(())
 }
 
+/// Set the journal watermark and commit time in the dm-integrity table line from `dmsetup table`.
+fn tune_integrity_table(table: &str, tuning: &IntegrityTuning) -> Result<String> {
+    let fields = table.split_whitespace().collect::<Vec<_>>();
+    // <start> <length> integrity <device> <offset> <tag size> <mode> <#opt args> <opt args>...
+    let (Some(&"integrity"), Some(&mode), Some(args_count)) = (
+        fields.get(2),
+        fields.get(6),
+        fields.get(7).and_then(|count| count.parse::<usize>().ok()),
+    ) else {
+        bail!("Not a dm-integrity table: {table:?}");
+    };
+    if mode != "J" {
+        bail!("The dm-integrity journal is not in use (mode {mode})");
+    }
+    let Some(args) = fields.get(8..8 + args_count) else {
+        bail!("Bad number of optional arguments in the dm-integrity table: {table:?}");
+    };
+    // The keys are hidden by `dmsetup table`, so such a table cannot be loaded again
+    if let Some(arg) = args.iter().find(|arg| {
+        ["internal_hash:", "journal_crypt:", "journal_mac:"]
+            .iter()
+            .any(|prefix| arg.starts_with(prefix))
+    }) {
+        bail!(
+            "The keyed dm-integrity argument {:?} cannot be tuned",
+            arg.split(':').next()
+        );
+    }
+
+    let mut args = args
+        .iter()
+        .filter(|arg| {
+            !((tuning.journal_watermark.is_some() && arg.starts_with("journal_watermark:"))
+                || (tuning.journal_commit_time.is_some() && arg.starts_with("commit_time:")))
+        })
+        .map(|arg| arg.to_string())
+        .collect::<Vec<_>>();
+    if let Some(watermark) = tuning.journal_watermark {
+        args.push(format!("journal_watermark:{watermark}"));
+    }
+    if let Some(commit_time) = tuning.journal_commit_time {
+        args.push(format!("commit_time:{commit_time}"));
+    }
+
+    let mut tuned = fields[..7].join(" ");
+    tuned += &format!(" {}", args.len());
+    for arg in args {
+        tuned += &format!(" {arg}");
+    }
+    Ok(tuned)
+}
+
+/// Apply the journal watermark and commit time to the dm-integrity device below the opened volume
+/// by reloading its table, since they are not stored in the LUKS2 header and libcryptsetup always
+/// activates the volume with the kernel defaults. The volume must be opened with
+/// [`IntegrityType::Journal`].
+pub async fn tune_integrity_journal(volume: &str, tuning: &IntegrityTuning) -> Result<()> {
+    if tuning.journal_watermark.is_none() && tuning.journal_commit_time.is_none() {
+        return Ok(());
+    }
+    check_integrity_tuning(tuning)?;
+
+    // The dm-integrity device is set up by libcryptsetup with the "_dif" suffix
+    let integrity_name = format!("{}_dif", dm_name(volume));
+    async {
+        let table = Command::new("dmsetup")
+            .arg("table")
+            .arg(&integrity_name)
+            .run()
+            .await?;
+        let table = tune_integrity_table(String::from_utf8_lossy(&table).trim(), tuning)?;
+        tracing::debug!("Reloading the table of {integrity_name}: {table}");
+        Command::new("dmsetup")
+            .arg("reload")
+            .arg(&integrity_name)
+            .arg("--table")
+            .arg(&table)
+            .run()
+            .await?;
+        Command::new("dmsetup")
+            .arg("resume")
+            .arg(&integrity_name)
+            .run()
+            .await?;
+        Ok::<_, anyhow::Error>(())
+    }
+    .await
+    .with_context(|| format!("Failed to tune the integrity journal of volume {volume}"))
+}
+
 pub async fn is_initialized(dev: &Path) -> Result<bool> {
     is_a_cryptpilot_initialized_luks2_volume(dev).await
 }
@@ -285,7 +970,12 @@ pub async fn is_initialized(dev: &Path) -> Result<bool> {
 /// - `None`: no valid LUKS2 header, or header exists but has no cryptpilot marker
 /// - `Initializing`: subsystem is "cryptpilot-initializing" (partial init)
 /// - `Ready`: subsystem is "cryptpilot" (fully initialized)
+/// - `Luks1`: legacy LUKS1 header, which has no subsystem
 pub async fn get_init_state(dev: &Path) -> Result<VolumeInitState> {
+    if is_luks1(dev).await {
+        return Ok(VolumeInitState::Luks1);
+    }
+
     // Try to read the subsystem from the raw header.
     // If the device is not a valid LUKS2 volume or the header can't be read,
     // return None.
@@ -309,8 +999,53 @@ async fn is_a_cryptpilot_initialized_luks2_volume(dev: &Path) -> Result<bool> {
     Ok(state == VolumeInitState::Ready)
 }
 
+/// Convert the legacy LUKS1 header of the device to LUKS2 in place, with libcryptsetup's
+/// conversion (as `cryptsetup convert --type luks2`). The keyslots and the data are kept, so the
+/// same passphrases unlock the volume afterwards. The volume must not be open during the
+/// conversion, and the result is not marked as initialized by cryptpilot.
+pub async fn convert_to_luks2(dev: &Path) -> Result<()> {
+    if get_luks_version(dev).await? != Some(1) {
+        bail!("{dev:?} is not a LUKS1 volume");
+    }
+    let verbose = get_verbose().await;
+
+    let device_path = PathBuf::from(&dev);
+
+    tokio::task::spawn_blocking(move || {
+        set_debug_level(verbose);
+
+        // The sector size of LUKS1 is always 512 bytes, which must be kept for the existing data
+        let params = CryptParamsLuks2 {
+            integrity: None,
+            pbkdf: None,
+            integrity_params: None,
+            data_alignment: 0,
+            data_device: None,
+            sector_size: LUKS2_SECTOR_SIZE_MIN,
+            label: None,
+            subsystem: None,
+        };
+        let mut params_ref: CryptParamsLuks2Ref = (&params).try_into()?;
+
+        let mut device = CryptInit::init(&device_path)?;
+
+        device
+            .context_handle()
+            .load::<()>(Some(EncryptionFormat::Luks1), None)?;
+        device
+            .context_handle()
+            .convert(EncryptionFormat::Luks2, &mut params_ref)?;
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await?
+    .with_context(|| format!("Failed to convert the LUKS1 header of {dev:?} to LUKS2"))?;
+
+    Ok(())
+}
+
 pub fn is_active(volume: &str) -> bool {
-    PathBuf::from(format!("/dev/mapper/{}", volume)).exists()
+    dm_path(volume).exists()
 }
 
 pub async fn is_dev_in_use(dev: &Path) -> Result<bool> {
@@ -326,14 +1061,10 @@ pub async fn is_dev_in_use(dev: &Path) -> Result<bool> {
 
 pub async fn close(volume: &str) -> Result<()> {
     let verbose = get_verbose().await;
-    let volume_name = volume.to_owned();
+    let volume_name = dm_name(volume);
 
     tokio::task::spawn_blocking(move || {
-        if verbose {
-            libcryptsetup_rs::set_debug_level(CryptDebugLevel::All);
-        } else {
-            libcryptsetup_rs::set_debug_level(CryptDebugLevel::None);
-        }
+        set_debug_level(verbose);
 
         let mut device = CryptInit::init_by_name_and_header(&volume_name, None)?;
         device
@@ -348,6 +1079,90 @@ pub async fn close(volume: &str) -> Result<()> {
     Ok(())
 }
 
+/// Whether the error of [`close`] means that the mapping is still in use, e.g. it is mounted or
+/// opened by a process.
+pub fn is_busy_error(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<LibcryptErr>(),
+            Some(LibcryptErr::IOError(error)) if error.raw_os_error() == Some(libc::EBUSY)
+        )
+    })
+}
+
+/// Close the volume with deferred removal, so that the mapping is removed by the kernel once the
+/// last user releases it. Returns immediately even if the volume is still in use.
+pub async fn close_deferred(volume: &str) -> Result<()> {
+    let verbose = get_verbose().await;
+    let volume_name = dm_name(volume);
+
+    tokio::task::spawn_blocking(move || {
+        set_debug_level(verbose);
+
+        let mut device = CryptInit::init_by_name_and_header(&volume_name, None)?;
+        device
+            .activate_handle()
+            .deactivate(&volume_name, CryptDeactivate::DEFERRED)?;
+
+        Ok::<_, anyhow::Error>(())
+    })
+    .await?
+    .with_context(|| format!("Failed to schedule deferred removal of volume `{volume}`"))?;
+
+    Ok(())
+}
+
+/// Get the names of the block devices stacked on top of the mapping of the volume, e.g. LVM or
+/// another device mapper layer, from `/sys/block/<dm-N>/holders`.
+pub async fn get_holders(volume: &str) -> Result<Vec<String>> {
+    let dev = tokio::fs::canonicalize(dm_path(volume))
+        .await
+        .with_context(|| format!("Failed to resolve the device of volume `{volume}`"))?;
+    let Some(dev_name) = dev.file_name() else {
+        bail!("Invalid device path {dev:?} of volume `{volume}`");
+    };
+
+    let mut holders = vec![];
+    let mut entries = tokio::fs::read_dir(Path::new("/sys/block").join(dev_name).join("holders"))
+        .await
+        .with_context(|| format!("Failed to list the holders of {dev:?}"))?;
+    while let Some(entry) = entries.next_entry().await? {
+        holders.push(entry.file_name().to_string_lossy().to_string());
+    }
+    holders.sort();
+    Ok(holders)
+}
+
+/// Get the names of the block devices underlying the mapping of the volume, including the ones
+/// beneath other device mapper layers (e.g. dm-integrity), from `/sys/block/<dm-N>/slaves`.
+pub async fn get_underlying_devices(volume: &str) -> Result<Vec<String>> {
+    let dev = tokio::fs::canonicalize(dm_path(volume))
+        .await
+        .with_context(|| format!("Failed to resolve the device of volume `{volume}`"))?;
+    let Some(dev_name) = dev.file_name() else {
+        bail!("Invalid device path {dev:?} of volume `{volume}`");
+    };
+
+    let mut underlying_devices = vec![];
+    let mut pending = vec![dev_name.to_string_lossy().to_string()];
+    while let Some(dev_name) = pending.pop() {
+        let Ok(mut entries) =
+            tokio::fs::read_dir(Path::new("/sys/class/block").join(&dev_name).join("slaves")).await
+        else {
+            continue;
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let slave = entry.file_name().to_string_lossy().to_string();
+            if !underlying_devices.contains(&slave) {
+                underlying_devices.push(slave.clone());
+                pending.push(slave);
+            }
+        }
+    }
+    underlying_devices.sort();
+    Ok(underlying_devices)
+}
+
 pub struct TempLuksVolume(String);
 
 impl TempLuksVolume {
@@ -365,12 +1180,17 @@ impl TempLuksVolume {
                 .collect::<String>()
         );
         tracing::info!("Setting up a temporary luks volume {name}",);
-        crate::fs::luks2::open_with_check_passphrase(&name, dev, passphrase, integrity).await?;
+        crate::fs::luks2::open_with_check_passphrase(&name, dev, passphrase, integrity, false)
+            .await?;
         Ok(Self(name))
     }
 
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+
     pub fn volume_path(&self) -> PathBuf {
-        Path::new("/dev/mapper").join(&self.0)
+        dm_path(&self.0)
     }
 }
 
@@ -402,4 +1222,224 @@ mod tests {
         assert_eq!(LUKS2_SUBSYSTEM_INITIALIZING, "cryptpilot-initializing");
         assert_ne!(LUKS2_SUBSYSTEM_NAME, LUKS2_SUBSYSTEM_INITIALIZING);
     }
+
+    #[tokio::test]
+    async fn test_get_luks_version() -> Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        tokio::fs::write(file.path(), vec![0u8; 4096]).await?;
+        assert_eq!(get_luks_version(file.path()).await?, None);
+        assert_eq!(get_init_state(file.path()).await?, VolumeInitState::None);
+
+        let mut header = b"LUKS\xba\xbe\x00\x01".to_vec();
+        header.resize(4096, 0);
+        tokio::fs::write(file.path(), &header).await?;
+        assert_eq!(get_luks_version(file.path()).await?, Some(1));
+        assert_eq!(get_init_state(file.path()).await?, VolumeInitState::Luks1);
+        assert!(!is_integrity_enabled(file.path()).await?);
+        assert!(convert_to_luks2(Path::new("/dev/null")).await.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_debug_level() {
+        assert!(matches!(debug_level(true), CryptDebugLevel::All));
+        assert!(matches!(debug_level(false), CryptDebugLevel::None));
+
+        // Setting the debug level should never panic
+        set_debug_level(true);
+        set_debug_level(false);
+    }
+
+    #[test]
+    fn test_is_busy_error() {
+        let error = |errno| {
+            anyhow::Error::from(LibcryptErr::IOError(std::io::Error::from_raw_os_error(
+                errno,
+            )))
+            .context("Failed to close volume `data0`")
+        };
+        assert!(is_busy_error(&error(libc::EBUSY)));
+        assert!(!is_busy_error(&error(libc::ENODEV)));
+        assert!(!is_busy_error(&anyhow::anyhow!("Device or resource busy")));
+    }
+
+    #[test]
+    fn test_check_sector_size() {
+        for sector_size in [512, 1024, 2048, 4096] {
+            assert!(check_sector_size(sector_size).is_ok());
+        }
+        for sector_size in [0, 256, 1000, 8192] {
+            assert!(check_sector_size(sector_size).is_err());
+        }
+    }
+
+    #[test]
+    fn test_check_integrity_tuning() {
+        let tuning = |journal_watermark, journal_commit_time| IntegrityTuning {
+            journal: Some(true),
+            journal_watermark,
+            journal_commit_time,
+        };
+        assert!(check_integrity_tuning(&IntegrityTuning::default()).is_ok());
+        assert!(check_integrity_tuning(&tuning(Some(0), Some(1))).is_ok());
+        assert!(check_integrity_tuning(&tuning(Some(100), Some(3_600_000))).is_ok());
+        assert!(check_integrity_tuning(&tuning(Some(101), None)).is_err());
+        assert!(check_integrity_tuning(&tuning(None, Some(0))).is_err());
+        assert!(check_integrity_tuning(&tuning(None, Some(3_600_001))).is_err());
+    }
+
+    #[test]
+    fn test_tune_integrity_table() -> Result<()> {
+        let table = "0 1982464 integrity 7:0 32768 32 J 6 journal_sectors:15640 interleave_sectors:32768 buffer_sectors:128 journal_watermark:50 commit_time:10000 fix_padding";
+        let tuning = IntegrityTuning {
+            journal: Some(true),
+            journal_watermark: Some(80),
+            journal_commit_time: Some(1000),
+        };
+        assert_eq!(
+            tune_integrity_table(table, &tuning)?,
+            "0 1982464 integrity 7:0 32768 32 J 6 journal_sectors:15640 interleave_sectors:32768 buffer_sectors:128 fix_padding journal_watermark:80 commit_time:1000"
+        );
+
+        // Only the tunables which are set are changed
+        let tuning = IntegrityTuning {
+            journal_commit_time: None,
+            ..tuning
+        };
+        assert_eq!(
+            tune_integrity_table(table, &tuning)?,
+            "0 1982464 integrity 7:0 32768 32 J 6 journal_sectors:15640 interleave_sectors:32768 buffer_sectors:128 commit_time:10000 fix_padding journal_watermark:80"
+        );
+
+        // The journal is not used in the direct mode
+        assert!(tune_integrity_table(&table.replace(" J ", " D "), &tuning).is_err());
+        // The keys are hidden in the table
+        assert!(tune_integrity_table(
+            "0 1982464 integrity 7:0 32768 32 J 2 internal_hash:hmac(sha256):- fix_padding",
+            &tuning
+        )
+        .is_err());
+        assert!(
+            tune_integrity_table("0 1982464 crypt aes-xts-plain64 - 0 7:0 32768", &tuning).is_err()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_area_size() {
+        for metadata_size in [16 * 1024, 256 * 1024, 4 * 1024 * 1024] {
+            assert!(check_metadata_size(metadata_size).is_ok());
+        }
+        for metadata_size in [0, 4096, 48 * 1024, 8 * 1024 * 1024] {
+            assert!(check_metadata_size(metadata_size).is_err());
+        }
+        for keyslots_size in [4096, 16 * 1024 * 1024, 128 * 1024 * 1024] {
+            assert!(check_keyslots_size(keyslots_size).is_ok());
+        }
+        for keyslots_size in [0, 1000, 129 * 1024 * 1024] {
+            assert!(check_keyslots_size(keyslots_size).is_err());
+        }
+    }
+
+    #[test]
+    fn test_activate_flags() {
+        assert_eq!(
+            activate_flags(IntegrityType::None, false),
+            CryptActivate::empty()
+        );
+        assert_eq!(
+            activate_flags(IntegrityType::NoJournal, false),
+            CryptActivate::NO_JOURNAL
+        );
+        assert_eq!(
+            activate_flags(IntegrityType::None, true),
+            CryptActivate::ALLOW_DISCARDS
+        );
+        assert_eq!(
+            activate_flags(IntegrityType::NoJournal, true),
+            CryptActivate::NO_JOURNAL | CryptActivate::ALLOW_DISCARDS
+        );
+    }
+
+    /// Build a LUKS2 header with the given JSON metadata.
+    fn fake_luks2_header(json: &str) -> Vec<u8> {
+        let hdr_size = 16 * 1024;
+        let mut header = vec![0u8; hdr_size];
+        header[..6].copy_from_slice(b"LUKS\xba\xbe");
+        header[6..8].copy_from_slice(&2u16.to_be_bytes());
+        header[8..16].copy_from_slice(&(hdr_size as u64).to_be_bytes());
+        header[LUKS2_BINARY_HEADER_SIZE..LUKS2_BINARY_HEADER_SIZE + json.len()]
+            .copy_from_slice(json.as_bytes());
+        header
+    }
+
+    #[tokio::test]
+    async fn test_is_integrity_enabled() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("cryptpilot-luks2-{}", rand::random::<u64>()));
+
+        tokio::fs::write(
+            &path,
+            fake_luks2_header(
+                r#"{"segments":{"0":{"type":"crypt","offset":"16777216","size":"dynamic","iv_tweak":"0","encryption":"aes-xts-plain64","sector_size":4096}}}"#,
+            ),
+        )
+        .await?;
+        assert!(!is_integrity_enabled(&path).await?);
+
+        tokio::fs::write(
+            &path,
+            fake_luks2_header(
+                r#"{"segments":{"0":{"type":"crypt","offset":"16777216","size":"dynamic","iv_tweak":"0","encryption":"aes-xts-plain64","sector_size":4096,"integrity":{"type":"hmac(sha256)","journal_encryption":"none","journal_integrity":"none"}}}}"#,
+            ),
+        )
+        .await?;
+        assert!(is_integrity_enabled(&path).await?);
+
+        tokio::fs::write(&path, vec![0u8; 16 * 1024]).await?;
+        assert!(is_integrity_enabled(&path).await.is_err());
+
+        tokio::fs::remove_file(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_integrity_status() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("cryptpilot-luks2-{}", rand::random::<u64>()));
+
+        tokio::fs::write(
+            &path,
+            fake_luks2_header(
+                r#"{"keyslots":{"0":{"type":"luks2","key_size":64}},"segments":{"0":{"type":"crypt","offset":"16777216","size":"dynamic","iv_tweak":"0","encryption":"aes-xts-plain64","sector_size":4096}},"digests":{"0":{"type":"pbkdf2","digest":"AAAA"}}}"#,
+            ),
+        )
+        .await?;
+        assert_eq!(get_integrity_status(&path).await?, None);
+
+        tokio::fs::write(
+            &path,
+            fake_luks2_header(
+                r#"{"keyslots":{"0":{"type":"luks2","key_size":96},"1":{"type":"luks2","key_size":96}},"segments":{"0":{"type":"crypt","offset":"16777216","size":"dynamic","iv_tweak":"0","encryption":"aes-xts-plain64","sector_size":4096,"integrity":{"type":"hmac(sha256)","journal_encryption":"none","journal_integrity":"none"}}},"digests":{"0":{"type":"pbkdf2","digest":"BBBB"}}}"#,
+            ),
+        )
+        .await?;
+        assert_eq!(
+            get_integrity_status(&path).await?,
+            Some(IntegrityStatus {
+                algorithm: "hmac(sha256)".to_owned(),
+                volume_key_size: Some(
+                    Luks2Cipher::Aes256Xts.key_size_bit() + LUKS2_INTEGRITY_KEY_SIZE_BIT
+                ),
+                integrity_key_size: Some(LUKS2_INTEGRITY_KEY_SIZE_BIT),
+                volume_key_digest: Some("BBBB".to_owned()),
+            })
+        );
+
+        tokio::fs::write(&path, vec![0u8; 16 * 1024]).await?;
+        assert!(get_integrity_status(&path).await.is_err());
+
+        tokio::fs::remove_file(&path).await?;
+        Ok(())
+    }
 }
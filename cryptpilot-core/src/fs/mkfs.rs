@@ -23,6 +23,7 @@ pub trait MakeFs {
     async fn force_mkfs(
         device_path: impl AsRef<Path> + Send + Sync,
         fs_type: MakeFsType,
+        label: Option<&str>,
     ) -> Result<()>;
 }
 
@@ -33,6 +34,7 @@ impl MakeFs for NormalMakeFs {
     async fn force_mkfs(
         device_path: impl AsRef<Path> + Send + Sync,
         fs_type: MakeFsType,
+        label: Option<&str>,
     ) -> Result<()> {
         // Use mkfs commands directly instead of systemd-makefs
         let (mkfs_cmd, args): (_, &[&str]) = match fs_type {
@@ -45,11 +47,16 @@ impl MakeFs for NormalMakeFs {
             MakeFsType::Vfat => ("mkfs.vfat", &["-I"]), // mkfs.vfat uses -I to force formatting
         };
 
-        Command::new(mkfs_cmd)
-            .args(args)
-            .arg(device_path.as_ref())
-            .run()
-            .await?;
+        let mut cmd = Command::new(mkfs_cmd);
+        cmd.args(args);
+        if let Some(label) = label {
+            let label_arg = match fs_type {
+                MakeFsType::Vfat => "-n", // mkfs.vfat uses -n for volume name
+                MakeFsType::Swap | MakeFsType::Ext4 | MakeFsType::Xfs => "-L",
+            };
+            cmd.arg(label_arg).arg(label);
+        }
+        cmd.arg(device_path.as_ref()).run().await?;
         Ok(())
     }
 }
@@ -61,6 +68,7 @@ impl MakeFs for IntegrityNoWipeMakeFs {
     async fn force_mkfs(
         device_path: impl AsRef<Path> + Send + Sync,
         fs_type: MakeFsType,
+        label: Option<&str>,
     ) -> Result<()> {
         let (device_size, block_size) = {
             let file = File::open(&device_path).await?.into_std().await;
@@ -88,7 +96,7 @@ impl MakeFs for IntegrityNoWipeMakeFs {
 
         // Do some operations to the dummy device
         {
-            NormalMakeFs::force_mkfs(&dummy_device_path, fs_type).await?;
+            NormalMakeFs::force_mkfs(&dummy_device_path, fs_type, label).await?;
 
             // TODO: refact blkid with libblkid-rs crate
             Command::new("blkid")
@@ -178,14 +186,33 @@ impl MakeFs for IntegrityNoWipeMakeFs {
     }
 }
 
+/// Check if the label is allowed by the file system.
+pub fn check_fs_label(makefs: &MakeFsType, label: &str) -> Result<()> {
+    if label.is_empty() {
+        bail!("The label of {makefs} fs should not be empty");
+    }
+    if label.len() > makefs.max_label_len() {
+        bail!(
+            "The label {label:?} is too long for {makefs} fs, which allows at most {} bytes",
+            makefs.max_label_len()
+        );
+    }
+    Ok(())
+}
+
 pub async fn force_mkfs(
     volume_path: &Path,
     makefs: &MakeFsType,
+    label: Option<&str>,
     integrity: IntegrityType,
 ) -> Result<()> {
     let volume_path = volume_path.to_owned();
     let makefs = makefs.to_owned();
 
+    if let Some(label) = label {
+        check_fs_label(&makefs, label)?;
+    }
+
     tracing::info!(
         "Initializing {} fs on volume {:?}, with volume integrity type {:?}",
         makefs,
@@ -193,15 +220,47 @@ pub async fn force_mkfs(
         integrity
     );
     match integrity {
-        IntegrityType::None => NormalMakeFs::force_mkfs(&volume_path, makefs).await,
+        IntegrityType::None => NormalMakeFs::force_mkfs(&volume_path, makefs, label).await,
         IntegrityType::Journal | IntegrityType::NoJournal => {
-            IntegrityNoWipeMakeFs::force_mkfs(&volume_path, makefs).await
+            IntegrityNoWipeMakeFs::force_mkfs(&volume_path, makefs, label).await
         }
     }
     .with_context(|| format!("Failed to initialize {makefs} fs on volume {volume_path:?}"))?;
     Ok(())
 }
 
+/// Checks whether the existing signature on the device can be overwritten before formatting it.
+///
+/// - A device without signatures or with a LUKS signature can always be overwritten.
+/// - If `allowed_signatures` is `None`, any signature can be overwritten.
+/// - Otherwise, the detected signature must be in `allowed_signatures`, or an error is returned.
+pub async fn check_overwrite_signature(
+    device_path: &Path,
+    allowed_signatures: Option<&[String]>,
+) -> Result<()> {
+    let probe = crate::fs::blkid::probe_device(device_path).await?;
+    if probe.is_clean() || probe.is_luks() {
+        return Ok(());
+    }
+
+    let signature = probe.signature().unwrap_or("unknown");
+    tracing::info!("Detected signature {signature:?} on {device_path:?}, probe result: {probe:?}");
+
+    match allowed_signatures {
+        None => {
+            tracing::warn!("The existing {signature:?} signature on {device_path:?} will be overwritten");
+            Ok(())
+        }
+        Some(allowed_signatures) if allowed_signatures.iter().any(|s| s == signature) => {
+            tracing::info!("The existing {signature:?} signature on {device_path:?} is allowed to be overwritten");
+            Ok(())
+        }
+        Some(allowed_signatures) => bail!(
+            "Refusing to overwrite the existing {signature:?} signature on {device_path:?}, which is not in the allowed signatures {allowed_signatures:?}"
+        ),
+    }
+}
+
 /// Checks whether the device contains valuable data (i.e., a known filesystem).
 ///
 /// - Partition tables (e.g., PTTYPE="atari") are ignored and treated as "no valuable data".
@@ -213,12 +272,7 @@ pub async fn has_valuable_data(device_path: &Path, fs_hint: Option<MakeFsType>)
     match (probe, fs_hint) {
         (BlkidProbeResult::NoSignatures, _) => Ok(false),
         (BlkidProbeResult::KnownSignature { fs_type, .. }, Some(expected_fs)) => {
-            let expected_str = match expected_fs {
-                MakeFsType::Swap => "swap",
-                MakeFsType::Ext4 => "ext4",
-                MakeFsType::Xfs => "xfs",
-                MakeFsType::Vfat => "vfat",
-            };
+            let expected_str = expected_fs.blkid_type();
 
             if fs_type.as_deref() == Some(expected_str) {
                 Ok(true)
@@ -237,3 +291,73 @@ pub async fn has_valuable_data(device_path: &Path, fs_hint: Option<MakeFsType>)
         }
     }
 }
+
+/// Probes the file system on the device, and returns the detected signature if it is not the
+/// expected one, e.g. an xfs file system on a volume configured with `makefs = "ext4"`.
+///
+/// A device without any signature is not regarded as a mismatch, since it is the case of a volume
+/// whose file system is not created yet (see [`has_valuable_data`]).
+pub async fn detect_fs_mismatch(
+    device_path: &Path,
+    expected: MakeFsType,
+) -> Result<Option<String>> {
+    let probe = crate::fs::blkid::probe_device(device_path).await?;
+    match probe.signature() {
+        None => Ok(None),
+        Some(signature) if signature == expected.blkid_type() => Ok(None),
+        Some(signature) => {
+            tracing::info!(
+                "Expected {expected} fs on {device_path:?} but found {signature:?}, probe result: {probe:?}"
+            );
+            Ok(Some(signature.to_owned()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_fs_label() {
+        assert!(check_fs_label(&MakeFsType::Ext4, "data0").is_ok());
+        assert!(check_fs_label(&MakeFsType::Ext4, "0123456789abcdef").is_ok());
+        assert!(check_fs_label(&MakeFsType::Ext4, "0123456789abcdefg").is_err());
+        assert!(check_fs_label(&MakeFsType::Swap, "0123456789abcdef").is_ok());
+        assert!(check_fs_label(&MakeFsType::Xfs, "0123456789ab").is_ok());
+        assert!(check_fs_label(&MakeFsType::Xfs, "0123456789abc").is_err());
+        assert!(check_fs_label(&MakeFsType::Vfat, "DATA0").is_ok());
+        assert!(check_fs_label(&MakeFsType::Vfat, "0123456789ab").is_err());
+        assert!(check_fs_label(&MakeFsType::Ext4, "").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_detect_fs_mismatch() -> Result<()> {
+        let device = tempfile::NamedTempFile::new()?;
+        device.as_file().set_len(512 * 1024 * 1024)?;
+
+        // Nothing on the device yet
+        assert_eq!(
+            detect_fs_mismatch(device.path(), MakeFsType::Ext4).await?,
+            None
+        );
+
+        Command::new("mkfs.xfs")
+            .arg("-f")
+            .arg(device.path())
+            .run()
+            .await?;
+        assert_eq!(
+            detect_fs_mismatch(device.path(), MakeFsType::Xfs).await?,
+            None
+        );
+        assert_eq!(
+            detect_fs_mismatch(device.path(), MakeFsType::Ext4)
+                .await?
+                .as_deref(),
+            Some("xfs")
+        );
+
+        Ok(())
+    }
+}
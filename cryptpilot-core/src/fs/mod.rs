@@ -1,11 +1,15 @@
 pub mod blkid;
 pub mod block;
 pub mod cmd;
+pub mod fsck;
 pub mod kernel_module;
+pub mod lock;
+pub mod loop_device;
 pub mod luks2;
 pub mod mkfs;
 pub mod mount;
 pub mod nbd;
+pub mod perm;
 
 use lazy_static::lazy_static;
 use tokio::sync::RwLock;
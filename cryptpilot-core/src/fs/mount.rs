@@ -1,12 +1,15 @@
-use std::path::{Path, PathBuf};
+use std::{
+    os::unix::{ffi::OsStringExt as _, fs::FileTypeExt as _},
+    path::{Path, PathBuf},
+};
 
-use anyhow::{Context as _, Result};
+use anyhow::{bail, Context as _, Result};
 use tempfile::TempDir;
 use tokio::process::Command;
 
 use crate::async_defer;
 
-use super::cmd::CheckCommandOutput as _;
+use super::{cmd::CheckCommandOutput as _, perm::FileKind};
 
 pub struct TmpMountPoint {
     mount_dir: TempDir,
@@ -21,6 +24,8 @@ impl TmpMountPoint {
             .prefix("cryptpilot-mount-")
             .tempdir()?;
         let mount_point = mount_dir.path();
+        // The temporary directory is created with mode 0700
+        super::perm::set_mode(mount_point, FileKind::Directory).await?;
 
         let mut cmd = Command::new("mount");
         if !writable {
@@ -48,9 +53,7 @@ impl Drop for TmpMountPoint {
         async_defer! {
             async{
                 let mount_point = self.mount_dir.path();
-                Command::new("umount")
-                    .arg(mount_point)
-                    .run()
+                umount(mount_point)
                     .await
                     .with_context(|| format!("Failed to umount device {:?} from {:?}", self.dev, mount_point))?;
                 Ok::<_, anyhow::Error>(())
@@ -58,3 +61,349 @@ impl Drop for TmpMountPoint {
         }
     }
 }
+
+/// Decode the octal escapes (e.g. "\040" for a space) of a path in /proc/self/mounts and
+/// /proc/swaps.
+fn unescape_proc_path(path: &str) -> PathBuf {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 4)
+            .filter(|digits| bytes[i] == b'\\' && digits.iter().all(|d| (b'0'..=b'7').contains(d)))
+            .and_then(|digits| u8::from_str_radix(std::str::from_utf8(digits).ok()?, 8).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 4;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    PathBuf::from(std::ffi::OsString::from_vec(decoded))
+}
+
+/// Check whether the path in a proc file refers to the same device as `dev`, which is canonicalized.
+async fn is_same_device(path: &Path, dev: &Path) -> bool {
+    path.starts_with("/dev/") && tokio::fs::canonicalize(path).await.ok().as_deref() == Some(dev)
+}
+
+/// Get the mount points of the device in the order they were mounted, from /proc/self/mounts.
+pub async fn find_mount_points(dev: &Path) -> Result<Vec<PathBuf>> {
+    let dev = tokio::fs::canonicalize(dev)
+        .await
+        .with_context(|| format!("Failed to resolve the device {dev:?}"))?;
+    let mounts = tokio::fs::read_to_string("/proc/self/mounts")
+        .await
+        .context("Failed to read mounts")?;
+
+    let mut mount_points = vec![];
+    for line in mounts.lines() {
+        let mut fields = line.split_whitespace();
+        let (Some(source), Some(target)) = (fields.next(), fields.next()) else {
+            continue;
+        };
+        if is_same_device(&unescape_proc_path(source), &dev).await {
+            mount_points.push(unescape_proc_path(target));
+        }
+    }
+    Ok(mount_points)
+}
+
+/// Check that the SELinux context is in the form of `user:role:type[:range]`, e.g.
+/// `system_u:object_r:container_file_t:s0:c1,c2`, and return it as the `context=` mount option.
+/// The context is quoted since the categories of the range may contain commas, which separate
+/// the mount options.
+pub fn selinux_context_mount_option(context: &str) -> Result<String> {
+    let invalid = || {
+        anyhow::anyhow!(
+            "Invalid SELinux context {context:?}, should be in the form of \"user:role:type[:range]\", e.g. \"system_u:object_r:container_file_t:s0\""
+        )
+    };
+    let mut fields = context.splitn(4, ':');
+    for _ in 0..3 {
+        let field = fields.next().ok_or_else(invalid)?;
+        if field.is_empty()
+            || !field
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+        {
+            return Err(invalid());
+        }
+    }
+    if let Some(range) = fields.next() {
+        if range.is_empty()
+            || !range
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-' | ':' | ','))
+        {
+            return Err(invalid());
+        }
+    }
+    Ok(format!("context=\"{context}\""))
+}
+
+/// The mount points of the running system, whose backing devices must never be overwritten.
+pub const SYSTEM_MOUNT_POINTS: [&str; 2] = ["/", "/boot"];
+
+/// Find the source device of the file system mounted at the mount point with findmnt. Returns
+/// `None` if nothing is mounted there, or if the file system is not backed by a block device
+/// (e.g. overlay or tmpfs).
+async fn find_mount_source(mount_point: &Path) -> Result<Option<PathBuf>> {
+    let stdout = Command::new("findmnt")
+        .args(["--noheadings", "--list", "--nofsroot", "--output", "SOURCE"])
+        .arg("--mountpoint")
+        .arg(mount_point)
+        .run_with_status_checker(|code, stdout, _| match code {
+            0 => Ok(Some(stdout)),
+            // Nothing is mounted at the mount point
+            1 => Ok(None),
+            _ => bail!("Bad exit code"),
+        })
+        .await
+        .with_context(|| format!("Failed to find the source device of {mount_point:?}"))?;
+
+    // The last one hides the others mounted at the same mount point
+    Ok(stdout.and_then(|stdout| {
+        String::from_utf8_lossy(&stdout)
+            .lines()
+            .map(str::trim)
+            .filter(|source| source.starts_with("/dev/"))
+            .last()
+            .map(PathBuf::from)
+    }))
+}
+
+/// List the device and all the block devices below it as (major:minor, path) pairs with lsblk,
+/// e.g. the partition below a dm-crypt device and the disk holding that partition.
+async fn list_backing_devices(dev: &Path) -> Result<Vec<(String, String)>> {
+    let stdout = Command::new("lsblk")
+        .args([
+            "--inverse",
+            "--noheadings",
+            "--list",
+            "--output",
+            "MAJ:MIN,PATH",
+        ])
+        .arg(dev)
+        .run()
+        .await
+        .with_context(|| format!("Failed to list the devices below {dev:?}"))?;
+
+    let mut devices = vec![];
+    for line in String::from_utf8_lossy(&stdout).lines() {
+        let Some((maj_min, path)) = line.trim().split_once(char::is_whitespace) else {
+            continue;
+        };
+        let device = (maj_min.to_owned(), path.trim().to_owned());
+        // A device may be reached more than once, e.g. the members of a RAID on the same disk
+        if !devices.contains(&device) {
+            devices.push(device);
+        }
+    }
+    Ok(devices)
+}
+
+/// Refuse to go on if the device is one of the devices backing the file systems mounted at the
+/// mount points, or the disk holding one of them, e.g. the root disk of the running system.
+pub async fn ensure_not_backing_mount_points(dev: &Path, mount_points: &[&Path]) -> Result<()> {
+    let metadata = tokio::fs::metadata(dev)
+        .await
+        .with_context(|| format!("Failed to get the metadata of {dev:?}"))?;
+    if !metadata.file_type().is_block_device() {
+        return Ok(());
+    }
+    let Some((dev_number, _)) = list_backing_devices(dev).await?.into_iter().next() else {
+        bail!("Failed to get the device number of {dev:?}");
+    };
+
+    for mount_point in mount_points {
+        let Some(source) = find_mount_source(mount_point).await? else {
+            continue;
+        };
+        for (maj_min, path) in list_backing_devices(&source).await? {
+            if maj_min == dev_number {
+                bail!(
+                    "The device {dev:?} ({path}) backs the file system mounted at {mount_point:?} ({source:?})"
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+pub async fn umount(mount_point: &Path) -> Result<()> {
+    Command::new("umount")
+        .arg(mount_point)
+        .run()
+        .await
+        .with_context(|| format!("Failed to umount {mount_point:?}"))?;
+    Ok(())
+}
+
+/// Unmount all the mount points of the device, the most recently mounted one first, so that the
+/// mount points stacked on top of another are unmounted before it. Returns the unmounted mount
+/// points.
+pub async fn umount_all(dev: &Path) -> Result<Vec<PathBuf>> {
+    let mut mount_points = find_mount_points(dev).await?;
+    mount_points.reverse();
+    for mount_point in &mount_points {
+        tracing::info!("Unmounting {dev:?} from {mount_point:?}");
+        umount(mount_point).await?;
+    }
+    Ok(mount_points)
+}
+
+/// Check whether the device is an active swap area, from /proc/swaps.
+pub async fn is_swap_active(dev: &Path) -> Result<bool> {
+    let dev = tokio::fs::canonicalize(dev)
+        .await
+        .with_context(|| format!("Failed to resolve the device {dev:?}"))?;
+    let swaps = tokio::fs::read_to_string("/proc/swaps")
+        .await
+        .context("Failed to read swaps")?;
+
+    // The first line is the header
+    for line in swaps.lines().skip(1) {
+        let Some(filename) = line.split_whitespace().next() else {
+            continue;
+        };
+        if is_same_device(&unescape_proc_path(filename), &dev).await {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+pub async fn swapon(dev: &Path) -> Result<()> {
+    Command::new("swapon")
+        .arg(dev)
+        .run()
+        .await
+        .with_context(|| format!("Failed to swapon {dev:?}"))?;
+    Ok(())
+}
+
+pub async fn swapoff(dev: &Path) -> Result<()> {
+    Command::new("swapoff")
+        .arg(dev)
+        .run()
+        .await
+        .with_context(|| format!("Failed to swapoff {dev:?}"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    use crate::fs::block::dummy::DummyDevice;
+
+    #[test]
+    fn test_selinux_context_mount_option() -> Result<()> {
+        assert_eq!(
+            selinux_context_mount_option("system_u:object_r:container_file_t:s0")?,
+            "context=\"system_u:object_r:container_file_t:s0\""
+        );
+        assert_eq!(
+            selinux_context_mount_option("system_u:object_r:svirt_sandbox_file_t:s0:c1,c2")?,
+            "context=\"system_u:object_r:svirt_sandbox_file_t:s0:c1,c2\""
+        );
+        assert_eq!(
+            selinux_context_mount_option("unconfined_u:object_r:user_home_t")?,
+            "context=\"unconfined_u:object_r:user_home_t\""
+        );
+
+        for context in [
+            "",
+            "object_r:container_file_t",
+            "system_u::container_file_t:s0",
+            "system_u:object_r:container_file_t:",
+            "system_u:object_r:container_file_t:s0\",nosuid",
+            "system_u:object_r:container file_t:s0",
+        ] {
+            assert!(
+                selinux_context_mount_option(context).is_err(),
+                "{context:?}"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_unescape_proc_path() {
+        assert_eq!(unescape_proc_path("/mnt/data"), Path::new("/mnt/data"));
+        assert_eq!(
+            unescape_proc_path("/mnt/my\\040data\\011x"),
+            Path::new("/mnt/my data\tx")
+        );
+        assert_eq!(unescape_proc_path("/mnt/a\\\\b"), Path::new("/mnt/a\\\\b"));
+        assert_eq!(unescape_proc_path("/mnt/a\\09"), Path::new("/mnt/a\\09"));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_umount_all() -> Result<()> {
+        let dummy_device = DummyDevice::setup_on_tmpfs(64 * 1024 * 1024).await?;
+        let device_path = dummy_device.path()?;
+        Command::new("mkfs.ext4")
+            .arg("-q")
+            .arg("-F")
+            .arg(&device_path)
+            .run()
+            .await?;
+        assert!(find_mount_points(&device_path).await?.is_empty());
+
+        let tmp_mount = TmpMountPoint::mount(&device_path, true).await?;
+        let mount_point = tmp_mount.mount_point().to_owned();
+        assert_eq!(
+            find_mount_points(&device_path).await?,
+            vec![mount_point.clone()]
+        );
+
+        assert_eq!(umount_all(&device_path).await?, vec![mount_point]);
+        assert!(find_mount_points(&device_path).await?.is_empty());
+        // Unmounting again when dropped fails, which is ignored
+        drop(tmp_mount);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_ensure_not_backing_mount_points() -> Result<()> {
+        // A mounted device plays the role of the root device
+        let root_device = DummyDevice::setup_on_tmpfs(64 * 1024 * 1024).await?;
+        let root_device_path = root_device.path()?;
+        Command::new("mkfs.ext4")
+            .arg("-q")
+            .arg("-F")
+            .arg(&root_device_path)
+            .run()
+            .await?;
+        let tmp_mount = TmpMountPoint::mount(&root_device_path, false).await?;
+        let mount_points = [tmp_mount.mount_point()];
+
+        let error = ensure_not_backing_mount_points(&root_device_path, &mount_points)
+            .await
+            .unwrap_err();
+        assert!(format!("{error:#}").contains("backs the file system"));
+
+        let other_device = DummyDevice::setup_on_tmpfs(64 * 1024 * 1024).await?;
+        ensure_not_backing_mount_points(&other_device.path()?, &mount_points).await?;
+
+        // Nothing is mounted at the mount point
+        let empty_dir = tempfile::tempdir()?;
+        ensure_not_backing_mount_points(&root_device_path, &[empty_dir.path()]).await?;
+
+        // Not a block device
+        let file = tempfile::NamedTempFile::new()?;
+        ensure_not_backing_mount_points(file.path(), &mount_points).await?;
+
+        Ok(())
+    }
+}
@@ -1,7 +1,10 @@
-use std::path::{Path, PathBuf};
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
 
 use anyhow::{bail, Context as _, Result};
-use block_devs::BlckExt as _;
 use devicemapper::{DevId, DmName, DmOptions, DM};
 use glob::{glob_with, MatchOptions};
 use tokio::{
@@ -12,6 +15,14 @@ use tokio::{
 
 use crate::{async_defer, fs::cmd::CheckCommandOutput as _};
 
+/// The environment variable to set how many seconds to wait for a free NBD device when all of them
+/// are in use. By default it does not wait.
+pub const CRYPTPILOT_NBD_WAIT_TIMEOUT_ENV: &str = "CRYPTPILOT_NBD_WAIT_TIMEOUT";
+
+const SYS_BLOCK_DIR: &str = "/sys/block";
+
+const NBD_WAIT_INTERVAL: Duration = Duration::from_secs(1);
+
 pub struct NbdDeviceNumber(u16);
 
 impl NbdDeviceNumber {
@@ -20,6 +31,42 @@ impl NbdDeviceNumber {
     }
 }
 
+/// All the NBD devices are in use, which can be checked with `error.downcast_ref()`.
+#[derive(Debug)]
+pub struct NbdDevicesExhausted {
+    /// The number of NBD devices in the system
+    pub total: usize,
+}
+
+impl Display for NbdDevicesExhausted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.total == 0 {
+            write!(
+                f,
+                "No NBD device found, please check if the nbd kernel module is loaded"
+            )
+        } else {
+            write!(
+                f,
+                "All {} NBD devices are in use, please disconnect the unused ones with `qemu-nbd --disconnect /dev/nbdN`, reload the nbd kernel module with a larger `nbds_max`, or set {CRYPTPILOT_NBD_WAIT_TIMEOUT_ENV} to wait for a free one",
+                self.total
+            )
+        }
+    }
+}
+
+impl std::error::Error for NbdDevicesExhausted {}
+
+/// The seconds to wait for a free NBD device, set in [`CRYPTPILOT_NBD_WAIT_TIMEOUT_ENV`].
+fn wait_timeout_from_env() -> Result<Duration> {
+    Ok(match std::env::var(CRYPTPILOT_NBD_WAIT_TIMEOUT_ENV) {
+        Ok(value) => Duration::from_secs(value.trim().parse().with_context(|| {
+            format!("Invalid value of {CRYPTPILOT_NBD_WAIT_TIMEOUT_ENV}: {value:?}")
+        })?),
+        Err(_) => Duration::ZERO,
+    })
+}
+
 pub struct NbdDevice {
     nbd_dev_num: NbdDeviceNumber,
     #[allow(unused)]
@@ -35,56 +82,71 @@ impl NbdDevice {
         super::kernel_module::ensure_module_loaded("nbd", &["max_part=8"]).await;
     }
 
+    /// Get a free NBD device, or fail with [`NbdDevicesExhausted`] if all of them are in use.
     pub async fn get_avaliable() -> Result<NbdDeviceNumber> {
         if !Self::is_module_loaded() {
             Self::modprobe().await;
         }
 
-        for i in 0..=99 {
-            let dev = PathBuf::from(format!("/dev/nbd{i}"));
-            if !dev.exists() {
-                continue;
-            }
-
-            let Ok(file) = File::open(&dev).await else {
-                continue;
-            };
-
-            let Ok(blk_size) = file.into_std().await.get_block_device_size() else {
-                continue;
-            };
+        find_free_nbd_device(Path::new(SYS_BLOCK_DIR)).await
+    }
 
-            if blk_size == 0 {
-                return Ok(NbdDeviceNumber(i));
-            }
+    /// Get a free NBD device, waiting up to `timeout` for one to be released if all of them are in
+    /// use.
+    pub async fn wait_for_avaliable(timeout: Duration) -> Result<NbdDeviceNumber> {
+        if !Self::is_module_loaded() {
+            Self::modprobe().await;
         }
 
-        bail!("No available NBD device")
+        wait_for_free_nbd_device(Path::new(SYS_BLOCK_DIR), timeout).await
     }
 
+    /// Connect the disk image to a free NBD device. If all of them are in use, it waits for the
+    /// seconds set in [`CRYPTPILOT_NBD_WAIT_TIMEOUT_ENV`].
     pub async fn connect(disk_img: impl AsRef<Path>) -> Result<Self> {
-        let disk_img = disk_img.as_ref();
+        Self::connect_with_timeout(disk_img, wait_timeout_from_env()?).await
+    }
+
+    /// Same as [`Self::connect`], but the NBD device is read-only, so that nothing can be written
+    /// to the disk image through it.
+    pub async fn connect_read_only(disk_img: impl AsRef<Path>) -> Result<Self> {
+        Self::connect_with_options(disk_img.as_ref(), wait_timeout_from_env()?, true).await
+    }
+
+    /// Connect the disk image to a free NBD device, waiting up to `timeout` for one if all of them
+    /// are in use.
+    pub async fn connect_with_timeout(
+        disk_img: impl AsRef<Path>,
+        timeout: Duration,
+    ) -> Result<Self> {
+        Self::connect_with_options(disk_img.as_ref(), timeout, false).await
+    }
+
+    async fn connect_with_options(
+        disk_img: &Path,
+        timeout: Duration,
+        read_only: bool,
+    ) -> Result<Self> {
         if !disk_img.exists() {
             bail!("Disk image {disk_img:?} does not exist");
         }
 
-        let nbd_dev_num = Self::get_avaliable().await?;
+        let nbd_dev_num = Self::wait_for_avaliable(timeout).await?;
         let nbd_dev_path = nbd_dev_num.to_path();
 
         // The problem is that the nbd device may be use by the kernel (e.g. as mount point or as a device mapper) due to the annoying udev rules. Here we try to add a udev rule to ingore this device.
         let udev_rule = UdevRule::install_ignore_nbd_rule().await?;
 
-        Command::new("qemu-nbd")
-            .arg("--connect")
-            .arg(&nbd_dev_path)
-            .arg("--discard=on")
-            .arg("--detect-zeroes=unmap")
-            .arg(disk_img)
-            .run()
-            .await
-            .with_context(|| {
-                format!("Failed to connect disk image {disk_img:?} to NBD device {nbd_dev_path:?}")
-            })?;
+        let mut cmd = Command::new("qemu-nbd");
+        cmd.arg("--connect").arg(&nbd_dev_path);
+        if read_only {
+            cmd.arg("--read-only");
+        } else {
+            cmd.arg("--discard=on").arg("--detect-zeroes=unmap");
+        }
+        cmd.arg(disk_img).run().await.with_context(|| {
+            format!("Failed to connect disk image {disk_img:?} to NBD device {nbd_dev_path:?}")
+        })?;
 
         tracing::debug!("Waiting 1 second for the nbd device to be ready");
         tokio::time::sleep(std::time::Duration::from_secs(1)).await;
@@ -187,6 +249,67 @@ impl Drop for NbdDevice {
     }
 }
 
+/// Find a free NBD device by scanning the `nbd*/size` files in the sysfs block directory. A device
+/// of size 0 is not connected.
+async fn find_free_nbd_device(sys_block_dir: &Path) -> Result<NbdDeviceNumber> {
+    let mut entries = tokio::fs::read_dir(sys_block_dir)
+        .await
+        .with_context(|| format!("Failed to list block devices in {sys_block_dir:?}"))?;
+
+    let mut devices = vec![];
+    while let Some(entry) = entries.next_entry().await? {
+        let Some(num) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.strip_prefix("nbd"))
+            .and_then(|num| num.parse::<u16>().ok())
+        else {
+            continue;
+        };
+
+        let size = match tokio::fs::read_to_string(entry.path().join("size")).await {
+            Ok(size) => size.trim().parse::<u64>().ok(),
+            Err(error) => {
+                tracing::debug!(?error, "Failed to read size of nbd{num}");
+                None
+            }
+        };
+        devices.push((num, size));
+    }
+    devices.sort_unstable_by_key(|(num, _)| *num);
+
+    match devices.iter().find(|(_, size)| *size == Some(0)) {
+        Some((num, _)) => Ok(NbdDeviceNumber(*num)),
+        None => Err(NbdDevicesExhausted {
+            total: devices.len(),
+        }
+        .into()),
+    }
+}
+
+async fn wait_for_free_nbd_device(
+    sys_block_dir: &Path,
+    timeout: Duration,
+) -> Result<NbdDeviceNumber> {
+    let start = Instant::now();
+    loop {
+        match find_free_nbd_device(sys_block_dir).await {
+            Err(error)
+                if error.downcast_ref::<NbdDevicesExhausted>().is_some()
+                    && start.elapsed() < timeout =>
+            {
+                tracing::info!(
+                    "{error}, waiting for a free one ({:?} left)",
+                    timeout.saturating_sub(start.elapsed())
+                );
+                tokio::time::sleep(NBD_WAIT_INTERVAL.min(timeout.saturating_sub(start.elapsed())))
+                    .await;
+            }
+            result => return result,
+        }
+    }
+}
+
 struct UdevRule {
     // None if udevadm was not available (no-op UdevRule)
     rule_path: Option<PathBuf>,
@@ -299,3 +422,60 @@ impl Drop for UdevRule {
         }
     }
 }
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    async fn fake_nbd_device(sys_block_dir: &Path, name: &str, size: u64) -> Result<()> {
+        let dir = sys_block_dir.join(name);
+        tokio::fs::create_dir_all(&dir).await?;
+        tokio::fs::write(dir.join("size"), format!("{size}\n")).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_nbd_devices_exhausted() -> Result<()> {
+        let sys_block_dir = tempfile::tempdir()?;
+        let sys_block_dir = sys_block_dir.path();
+
+        let error = find_free_nbd_device(sys_block_dir).await.err().unwrap();
+        assert_eq!(
+            error.downcast_ref::<NbdDevicesExhausted>().unwrap().total,
+            0
+        );
+
+        fake_nbd_device(sys_block_dir, "nbd0", 2048).await?;
+        fake_nbd_device(sys_block_dir, "nbd1", 8).await?;
+        fake_nbd_device(sys_block_dir, "loop0", 0).await?;
+
+        let error = wait_for_free_nbd_device(sys_block_dir, Duration::from_millis(1500))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.downcast_ref::<NbdDevicesExhausted>().unwrap().total,
+            2
+        );
+        assert!(error.to_string().contains("All 2 NBD devices are in use"));
+
+        // A device released while waiting is picked up
+        let released = {
+            let sys_block_dir = sys_block_dir.to_owned();
+            async move {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                fake_nbd_device(&sys_block_dir, "nbd1", 0).await
+            }
+        };
+        let (nbd_dev_num, released) = tokio::join!(
+            wait_for_free_nbd_device(sys_block_dir, Duration::from_secs(10)),
+            released
+        );
+        released?;
+        assert_eq!(nbd_dev_num?.to_path(), PathBuf::from("/dev/nbd1"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,110 @@
+use std::{os::unix::fs::PermissionsExt as _, path::Path};
+
+use anyhow::{Context as _, Result};
+use tokio::io::AsyncWriteExt as _;
+
+/// The kind of a file or directory created by cryptpilot, which decides its mode. The mode is
+/// applied explicitly after creation, so the result does not depend on the umask of the process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    /// A file which may hold secrets, e.g. the initrd state with the key provider config.
+    Secret,
+    /// A file which is meant to be read by others, e.g. the FDE summary.
+    Public,
+    /// A directory, e.g. a temporary mount point.
+    Directory,
+}
+
+impl FileKind {
+    /// The mode of this kind.
+    pub fn mode(self) -> u32 {
+        match self {
+            FileKind::Secret => 0o600,
+            FileKind::Public => 0o644,
+            FileKind::Directory => 0o755,
+        }
+    }
+}
+
+/// Set the mode of an existing file or directory according to its kind.
+pub async fn set_mode(path: &Path, kind: FileKind) -> Result<()> {
+    tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(kind.mode()))
+        .await
+        .with_context(|| format!("Failed to set the permissions of {path:?}"))
+}
+
+/// Create the directory and its missing parents. The directory itself gets the mode of
+/// [`FileKind::Directory`] even if it exists already.
+pub async fn create_dir_all(path: &Path) -> Result<()> {
+    tokio::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(FileKind::Directory.mode())
+        .create(path)
+        .await
+        .with_context(|| format!("Failed to create directory {path:?}"))?;
+    set_mode(path, FileKind::Directory).await
+}
+
+/// Write the contents to the file, replacing it if it exists. The mode is applied before any
+/// content is written, so that a secret is never readable by others, even for a moment.
+pub async fn write_file(path: &Path, contents: impl AsRef<[u8]>, kind: FileKind) -> Result<()> {
+    let mode = kind.mode();
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(path)
+        .await
+        .with_context(|| format!("Failed to open {path:?}"))?;
+    // The mode of `open` is masked by the umask, and is ignored for an existing file
+    file.set_permissions(std::fs::Permissions::from_mode(mode))
+        .await
+        .with_context(|| format!("Failed to set the permissions of {path:?}"))?;
+    file.write_all(contents.as_ref())
+        .await
+        .with_context(|| format!("Failed to write {path:?}"))?;
+    file.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    async fn mode_of(path: &Path) -> Result<u32> {
+        Ok(tokio::fs::metadata(path).await?.permissions().mode() & 0o7777)
+    }
+
+    #[tokio::test]
+    async fn test_file_modes() -> Result<()> {
+        let root = tempfile::tempdir()?;
+
+        let dir = root.path().join("run/cryptpilot");
+        create_dir_all(&dir).await?;
+        assert_eq!(mode_of(&dir).await?, 0o755);
+
+        let secret = dir.join("initrd_state.toml");
+        write_file(&secret, "secret", FileKind::Secret).await?;
+        assert_eq!(mode_of(&secret).await?, 0o600);
+        assert_eq!(tokio::fs::read_to_string(&secret).await?, "secret");
+
+        // An existing file with looser permissions is tightened
+        let public = dir.join("fde-summary.json");
+        write_file(&public, "{}", FileKind::Public).await?;
+        assert_eq!(mode_of(&public).await?, 0o644);
+        tokio::fs::set_permissions(&public, std::fs::Permissions::from_mode(0o666)).await?;
+        write_file(&public, "secret", FileKind::Secret).await?;
+        assert_eq!(mode_of(&public).await?, 0o600);
+        assert_eq!(tokio::fs::read_to_string(&public).await?, "secret");
+
+        // An existing directory is fixed as well
+        tokio::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o777)).await?;
+        create_dir_all(&dir).await?;
+        assert_eq!(mode_of(&dir).await?, 0o755);
+
+        Ok(())
+    }
+}
@@ -2,9 +2,24 @@
 pub mod attestation_agent;
 
 use anyhow::Result;
+use serde::{Deserialize, Serialize};
 use sha2::Digest;
 
 pub const OPERATION_NAME_LOAD_CONFIG_UNTRUSTED: &str = "load_config_untrusted";
+pub const OPERATION_NAME_LOAD_VOLUME_CONFIG: &str = "load_volume_config";
+
+/// The behavior when extending the runtime measurement fails.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MeasurementFailurePolicy {
+    /// Return the error, e.g. to refuse booting unmeasured.
+    Fail,
+    /// Log a warning and continue.
+    #[default]
+    Warn,
+    /// Continue silently, only logging at the debug level.
+    Ignore,
+}
 
 pub trait Measure {
     #[allow(async_fn_in_trait)]
@@ -20,6 +35,36 @@ pub trait Measure {
         self.extend_measurement(operation, hash).await
     }
 
+    /// Same as [`Measure::extend_measurement_hash`], but a failure is handled according to the
+    /// policy instead of always being returned. Returns whether the measurement is extended.
+    #[allow(async_fn_in_trait)]
+    async fn extend_measurement_hash_with_policy(
+        &self,
+        policy: MeasurementFailurePolicy,
+        operation: String,
+        content_to_hash: String,
+    ) -> Result<bool> {
+        let Err(error) = self
+            .extend_measurement_hash(operation.clone(), content_to_hash)
+            .await
+        else {
+            return Ok(true);
+        };
+        match policy {
+            MeasurementFailurePolicy::Fail => {
+                Err(error.context(format!("Failed to extend the measurement of `{operation}`")))
+            }
+            MeasurementFailurePolicy::Warn => {
+                tracing::warn!(?error, operation, "Failed to extend the measurement");
+                Ok(false)
+            }
+            MeasurementFailurePolicy::Ignore => {
+                tracing::debug!(?error, operation, "Failed to extend the measurement");
+                Ok(false)
+            }
+        }
+    }
+
     fn calculate_hashed_measurement_value(content_to_hash: String) -> Result<String> {
         let hash = sha2::Sha384::new()
             .chain_update(content_to_hash)
@@ -74,3 +119,91 @@ impl Measure for NopeMeasure {
         Ok(())
     }
 }
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A measure which fails every extend, e.g. the attestation agent is gone.
+    #[derive(Default)]
+    struct FailingMeasure {
+        attempts: AtomicUsize,
+    }
+
+    impl Measure for FailingMeasure {
+        async fn extend_measurement(&self, _operation: String, _content: String) -> Result<()> {
+            self.attempts.fetch_add(1, Ordering::Relaxed);
+            anyhow::bail!("The attestation agent is not responding")
+        }
+    }
+
+    #[tokio::test]
+    async fn test_extend_measurement_hash_with_policy() -> Result<()> {
+        let measure = FailingMeasure::default();
+
+        let error = measure
+            .extend_measurement_hash_with_policy(
+                MeasurementFailurePolicy::Fail,
+                OPERATION_NAME_LOAD_CONFIG_UNTRUSTED.into(),
+                "content".into(),
+            )
+            .await
+            .unwrap_err();
+        assert!(format!("{error:#}").contains("not responding"));
+
+        for policy in [
+            MeasurementFailurePolicy::Warn,
+            MeasurementFailurePolicy::Ignore,
+        ] {
+            assert!(
+                !measure
+                    .extend_measurement_hash_with_policy(
+                        policy,
+                        OPERATION_NAME_LOAD_CONFIG_UNTRUSTED.into(),
+                        "content".into(),
+                    )
+                    .await?
+            );
+        }
+        assert_eq!(measure.attempts.load(Ordering::Relaxed), 3);
+
+        // A successful extend is reported regardless of the policy
+        assert!(
+            NopeMeasure {}
+                .extend_measurement_hash_with_policy(
+                    MeasurementFailurePolicy::Fail,
+                    OPERATION_NAME_LOAD_CONFIG_UNTRUSTED.into(),
+                    "content".into(),
+                )
+                .await?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_deserialize_measurement_failure_policy() -> Result<()> {
+        #[derive(Deserialize)]
+        struct Config {
+            measurement_failure: MeasurementFailurePolicy,
+        }
+        for (raw, policy) in [
+            ("fail", MeasurementFailurePolicy::Fail),
+            ("warn", MeasurementFailurePolicy::Warn),
+            ("ignore", MeasurementFailurePolicy::Ignore),
+        ] {
+            let config: Config = toml::from_str(&format!("measurement_failure = \"{raw}\""))?;
+            assert_eq!(config.measurement_failure, policy);
+        }
+        assert!(toml::from_str::<Config>("measurement_failure = \"abort\"").is_err());
+        assert_eq!(
+            MeasurementFailurePolicy::default(),
+            MeasurementFailurePolicy::Warn
+        );
+
+        Ok(())
+    }
+}
@@ -2,6 +2,8 @@ pub mod helper;
 
 #[cfg(feature = "provider-exec")]
 pub mod exec;
+#[cfg(feature = "provider-http")]
+pub mod http;
 #[cfg(feature = "provider-kbs")]
 pub mod kbs;
 #[cfg(feature = "provider-kms")]
@@ -10,6 +12,13 @@ pub mod kms;
 pub mod oidc;
 #[cfg(feature = "provider-otp")]
 pub mod otp;
+#[cfg(feature = "provider-pkcs11")]
+pub mod pkcs11;
+#[cfg(feature = "provider-prompt")]
+pub mod prompt;
+pub mod registry;
+#[cfg(feature = "provider-systemd-credential")]
+pub mod systemd_credential;
 #[cfg(feature = "provider-tpm2")]
 pub mod tpm2;
 
@@ -23,6 +32,13 @@ pub trait KeyProvider {
 
     async fn get_key(&self) -> Result<Passphrase>;
 
+    /// Get all the passphrases which may unlock the volume, in the order to try them. A provider
+    /// serving rotated secrets may return both the old and the new one during the rotation window.
+    /// By default, only the passphrase from [`KeyProvider::get_key`] is returned.
+    async fn get_keys(&self) -> Result<Vec<Passphrase>> {
+        Ok(vec![self.get_key().await?])
+    }
+
     fn volume_type(&self) -> VolumeType;
 }
 
@@ -32,6 +48,7 @@ pub trait IntoProvider {
     fn into_provider(self) -> Self::Provider;
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum VolumeType {
     /// Temporary volume, which will drop all the data after closing.
     Temporary,
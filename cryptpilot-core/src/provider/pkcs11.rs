@@ -0,0 +1,379 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context as _, Result};
+use base64::{prelude::BASE64_STANDARD, Engine as _};
+use cryptoki::{
+    context::{CInitializeArgs, Pkcs11},
+    mechanism::{
+        rsa::{PkcsMgfType, PkcsOaepParams, PkcsOaepSource},
+        Mechanism, MechanismType,
+    },
+    object::{Attribute, ObjectClass},
+    session::UserType,
+    types::AuthPin,
+};
+use documented::{Documented, DocumentedFields};
+use serde::{Deserialize, Serialize};
+
+use crate::types::Passphrase;
+
+use super::{
+    prompt::{PromptConfig, PromptKeyProvider, PromptMode},
+    KeyProvider,
+};
+
+/// PKCS#11 Key Provider (decrypts a wrapped passphrase with a key in an HSM)
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Documented, DocumentedFields)]
+#[serde(deny_unknown_fields)]
+pub struct Pkcs11Config {
+    /// Path to the PKCS#11 module of the HSM, e.g. "/usr/lib64/pkcs11/libsofthsm2.so"
+    pub module_path: PathBuf,
+
+    /// ID of the slot holding the token with the key
+    pub slot: u64,
+
+    /// Where to get the user PIN of the token from: { env = "<NAME>" } reads it from an environment variable, { file = "<PATH>" } reads it from a file (a trailing newline is ignored), and "prompt" asks for it interactively.
+    pub pin_source: Pkcs11PinSource,
+
+    /// Label (CKA_LABEL) of the RSA private key which decrypts the wrapped blob
+    pub key_label: String,
+
+    /// The passphrase encrypted with the public key of `key_label`, in base64
+    pub wrapped_blob: String,
+
+    /// The mechanism the blob is encrypted with: "rsa-pkcs-oaep" (the default, with SHA-256 and MGF1-SHA256) or "rsa-pkcs" (PKCS#1 v1.5)
+    #[serde(default)]
+    pub mechanism: Pkcs11Mechanism,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum Pkcs11PinSource {
+    Env(String),
+    File(PathBuf),
+    Prompt,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Pkcs11Mechanism {
+    #[default]
+    RsaPkcsOaep,
+    RsaPkcs,
+}
+
+pub struct Pkcs11KeyProvider {
+    pub options: Pkcs11Config,
+}
+
+impl Pkcs11PinSource {
+    async fn get_pin(&self, slot: u64) -> Result<String> {
+        let pin = match self {
+            Pkcs11PinSource::Env(name) => std::env::var(name).with_context(|| {
+                format!("Failed to read the PIN from the environment variable ${name}")
+            })?,
+            Pkcs11PinSource::File(path) => {
+                let pin = tokio::fs::read_to_string(path)
+                    .await
+                    .with_context(|| format!("Failed to read the PIN from {path:?}"))?;
+                pin.strip_suffix('\n').unwrap_or(&pin).to_owned()
+            }
+            Pkcs11PinSource::Prompt => {
+                let pin = PromptKeyProvider {
+                    options: PromptConfig {
+                        message: Some(format!(
+                            "Please enter the PIN of the PKCS#11 token in slot {slot}:"
+                        )),
+                        mode: PromptMode::Auto,
+                        timeout_secs: None,
+                    },
+                }
+                .get_key()
+                .await?;
+                String::from_utf8(pin.as_bytes().to_vec()).context("The PIN is not valid UTF-8")?
+            }
+        };
+        if pin.is_empty() {
+            bail!("The PIN of the PKCS#11 token is empty");
+        }
+        Ok(pin)
+    }
+}
+
+impl Pkcs11Mechanism {
+    fn to_mechanism(self) -> Mechanism<'static> {
+        match self {
+            Pkcs11Mechanism::RsaPkcsOaep => Mechanism::RsaPkcsOaep(PkcsOaepParams::new(
+                MechanismType::SHA256,
+                PkcsMgfType::MGF1_SHA256,
+                PkcsOaepSource::empty(),
+            )),
+            Pkcs11Mechanism::RsaPkcs => Mechanism::RsaPkcs,
+        }
+    }
+}
+
+/// Log into the slot with the PIN, and decrypt the blob with the private key labeled `key_label`.
+fn decrypt_with_token(
+    module_path: &Path,
+    slot: u64,
+    pin: &AuthPin,
+    key_label: &str,
+    mechanism: Pkcs11Mechanism,
+    blob: &[u8],
+) -> Result<Vec<u8>> {
+    let pkcs11 = Pkcs11::new(module_path)
+        .with_context(|| format!("Failed to load PKCS#11 module {module_path:?}"))?;
+    pkcs11
+        .initialize(CInitializeArgs::OsThreads)
+        .context("Failed to initialize PKCS#11 module")?;
+
+    let Some(slot) = pkcs11
+        .get_slots_with_token()
+        .context("Failed to list the PKCS#11 slots")?
+        .into_iter()
+        .find(|s| s.id() == slot)
+    else {
+        bail!("No token is present in PKCS#11 slot {slot}");
+    };
+
+    let session = pkcs11
+        .open_ro_session(slot)
+        .context("Failed to open a session with the PKCS#11 token")?;
+    session
+        .login(UserType::User, Some(pin))
+        .context("Failed to log into the PKCS#11 token")?;
+
+    let keys = session
+        .find_objects(&[
+            Attribute::Class(ObjectClass::PRIVATE_KEY),
+            Attribute::Label(key_label.as_bytes().to_vec()),
+        ])
+        .context("Failed to look up the key in the PKCS#11 token")?;
+    let key = match keys.as_slice() {
+        [key] => *key,
+        [] => bail!("No private key labeled \"{key_label}\" is found in the PKCS#11 token"),
+        _ => bail!("More than one private key is labeled \"{key_label}\" in the PKCS#11 token"),
+    };
+
+    let plaintext = session
+        .decrypt(&mechanism.to_mechanism(), key, blob)
+        .context("Failed to decrypt the wrapped blob with the PKCS#11 token")?;
+
+    if let Err(error) = session.logout() {
+        tracing::debug!(?error, "Failed to log out of the PKCS#11 token, ignore it");
+    }
+    Ok(plaintext)
+}
+
+#[async_trait::async_trait]
+impl KeyProvider for Pkcs11KeyProvider {
+    fn debug_name(&self) -> String {
+        format!(
+            "PKCS#11 (slot {}, key \"{}\")",
+            self.options.slot, self.options.key_label
+        )
+    }
+
+    async fn get_key(&self) -> Result<Passphrase> {
+        let blob = BASE64_STANDARD
+            .decode(self.options.wrapped_blob.trim())
+            .context("The wrapped blob is not valid base64")?;
+        let pin = AuthPin::new(self.options.pin_source.get_pin(self.options.slot).await?);
+
+        let options = self.options.clone();
+        let passphrase = tokio::task::spawn_blocking(move || {
+            decrypt_with_token(
+                &options.module_path,
+                options.slot,
+                &pin,
+                &options.key_label,
+                options.mechanism,
+                &blob,
+            )
+        })
+        .await??;
+
+        if passphrase.is_empty() {
+            bail!("The passphrase decrypted from the wrapped blob is empty");
+        }
+        Ok(Passphrase::from(passphrase))
+    }
+
+    fn volume_type(&self) -> super::VolumeType {
+        super::VolumeType::Persistent
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    /// The locations of the SoftHSM module on the common distributions.
+    const SOFTHSM_MODULE_PATHS: &[&str] = &[
+        "/usr/lib64/pkcs11/libsofthsm2.so",
+        "/usr/lib/softhsm/libsofthsm2.so",
+        "/usr/lib/x86_64-linux-gnu/softhsm/libsofthsm2.so",
+        "/usr/lib/aarch64-linux-gnu/softhsm/libsofthsm2.so",
+    ];
+
+    #[test]
+    fn test_parse_config() -> Result<()> {
+        let config: Pkcs11Config = toml::from_str(
+            r#"
+            module_path = "/usr/lib64/pkcs11/libsofthsm2.so"
+            slot = 0
+            pin_source = { env = "HSM_PIN" }
+            key_label = "cryptpilot"
+            wrapped_blob = "AAAA"
+            "#,
+        )?;
+        assert_eq!(config.pin_source, Pkcs11PinSource::Env("HSM_PIN".into()));
+        assert_eq!(config.mechanism, Pkcs11Mechanism::RsaPkcsOaep);
+
+        let config: Pkcs11Config = toml::from_str(
+            r#"
+            module_path = "/usr/lib64/pkcs11/libsofthsm2.so"
+            slot = 0
+            pin_source = "prompt"
+            key_label = "cryptpilot"
+            wrapped_blob = "AAAA"
+            mechanism = "rsa-pkcs"
+            "#,
+        )?;
+        assert_eq!(config.pin_source, Pkcs11PinSource::Prompt);
+        assert_eq!(config.mechanism, Pkcs11Mechanism::RsaPkcs);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_pin() -> Result<()> {
+        let pin_file = tempfile::NamedTempFile::new()?;
+        tokio::fs::write(pin_file.path(), "1234\n").await?;
+        let pin = Pkcs11PinSource::File(pin_file.path().to_owned())
+            .get_pin(0)
+            .await?;
+        assert_eq!(pin, "1234");
+
+        tokio::fs::write(pin_file.path(), "").await?;
+        assert!(Pkcs11PinSource::File(pin_file.path().to_owned())
+            .get_pin(0)
+            .await
+            .is_err());
+
+        assert!(
+            Pkcs11PinSource::Env("CRYPTPILOT_TEST_PKCS11_PIN_NOT_EXIST".into())
+                .get_pin(0)
+                .await
+                .is_err()
+        );
+
+        Ok(())
+    }
+
+    /// Set up a token with an RSA key pair in SoftHSM, and check the passphrase wrapped with the
+    /// public key is unwrapped by the provider. Skipped if SoftHSM is not installed.
+    #[tokio::test]
+    async fn test_get_key_from_softhsm() -> Result<()> {
+        let Some(module_path) = SOFTHSM_MODULE_PATHS
+            .iter()
+            .map(Path::new)
+            .find(|path| path.exists())
+        else {
+            eprintln!("SoftHSM is not installed, skip the test");
+            return Ok(());
+        };
+
+        // A SoftHSM config with the tokens in a temporary directory
+        let temp_dir = tempfile::tempdir()?;
+        let tokens_dir = temp_dir.path().join("tokens");
+        tokio::fs::create_dir(&tokens_dir).await?;
+        let softhsm_conf = temp_dir.path().join("softhsm2.conf");
+        tokio::fs::write(
+            &softhsm_conf,
+            format!("directories.tokendir = {}\n", tokens_dir.display()),
+        )
+        .await?;
+        std::env::set_var("SOFTHSM2_CONF", &softhsm_conf);
+
+        let passphrase = b"test-passphrase";
+        let (slot, wrapped_blob) = {
+            let module_path = module_path.to_owned();
+            tokio::task::spawn_blocking(move || {
+                let pkcs11 = Pkcs11::new(&module_path)?;
+                pkcs11.initialize(CInitializeArgs::OsThreads)?;
+                let slot = pkcs11.get_slots_with_token()?[0];
+                let so_pin = AuthPin::new("5678".into());
+                pkcs11.init_token(slot, &so_pin, "cryptpilot-test")?;
+
+                // The token is moved to a new slot once initialized
+                let slot = pkcs11
+                    .get_slots_with_initialized_token()?
+                    .into_iter()
+                    .find(|slot| {
+                        pkcs11
+                            .get_token_info(*slot)
+                            .is_ok_and(|info| info.label() == "cryptpilot-test")
+                    })
+                    .context("The initialized token is not found")?;
+                let session = pkcs11.open_rw_session(slot)?;
+                session.login(UserType::So, Some(&so_pin))?;
+                session.init_pin(&AuthPin::new("1234".into()))?;
+                session.logout()?;
+
+                session.login(UserType::User, Some(&AuthPin::new("1234".into())))?;
+                let (public_key, _private_key) = session.generate_key_pair(
+                    &Mechanism::RsaPkcsKeyPairGen,
+                    &[
+                        Attribute::Token(true),
+                        Attribute::ModulusBits(2048u64.into()),
+                        Attribute::PublicExponent(vec![0x01, 0x00, 0x01]),
+                        Attribute::Encrypt(true),
+                    ],
+                    &[
+                        Attribute::Token(true),
+                        Attribute::Private(true),
+                        Attribute::Sensitive(true),
+                        Attribute::Decrypt(true),
+                        Attribute::Label(b"cryptpilot".to_vec()),
+                    ],
+                )?;
+                let wrapped_blob = session.encrypt(
+                    &Pkcs11Mechanism::RsaPkcsOaep.to_mechanism(),
+                    public_key,
+                    passphrase,
+                )?;
+                Ok::<_, anyhow::Error>((slot.id(), BASE64_STANDARD.encode(wrapped_blob)))
+            })
+            .await??
+        };
+
+        let pin_file = temp_dir.path().join("pin");
+        tokio::fs::write(&pin_file, "1234\n").await?;
+        let mut provider = Pkcs11KeyProvider {
+            options: Pkcs11Config {
+                module_path: module_path.to_owned(),
+                slot,
+                pin_source: Pkcs11PinSource::File(pin_file.clone()),
+                key_label: "cryptpilot".into(),
+                wrapped_blob,
+                mechanism: Pkcs11Mechanism::RsaPkcsOaep,
+            },
+        };
+        assert_eq!(provider.get_key().await?.as_bytes(), passphrase);
+
+        // A wrong PIN is rejected by the token
+        tokio::fs::write(&pin_file, "0000\n").await?;
+        assert!(provider.get_key().await.is_err());
+        tokio::fs::write(&pin_file, "1234\n").await?;
+
+        // No such key in the token
+        provider.options.key_label = "not-exist".into();
+        assert!(provider.get_key().await.is_err());
+
+        Ok(())
+    }
+}
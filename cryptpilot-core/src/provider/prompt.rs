@@ -0,0 +1,280 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use anyhow::{bail, Context as _, Result};
+use documented::{Documented, DocumentedFields};
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+use crate::{fs::cmd::CheckCommandOutput as _, types::Passphrase};
+
+use super::KeyProvider;
+
+const SYSTEMD_ASK_PASSWORD_BINARY: &str = "systemd-ask-password";
+
+const PROMPT_MESSAGE_DEFAULT: &str = "Please enter the passphrase for the volume:";
+
+/// The default time to wait for the passphrase to be entered.
+const PROMPT_TIMEOUT_SECS_DEFAULT: u64 = 300;
+
+static NON_INTERACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Fail instead of asking for the passphrase, so that automation never blocks waiting for an
+/// input which never comes.
+pub fn set_non_interactive(non_interactive: bool) {
+    NON_INTERACTIVE.store(non_interactive, Ordering::Relaxed);
+}
+
+/// Interactive Prompt Key Provider (asks the user for the passphrase)
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Documented, DocumentedFields)]
+#[serde(deny_unknown_fields)]
+pub struct PromptConfig {
+    /// Message shown when asking for the passphrase (optional)
+    #[serde(default)]
+    pub message: Option<String>,
+
+    /// How to ask for the passphrase: "auto" (the default) uses `systemd-ask-password` if it is installed, so that the prompt also shows on the boot splash (e.g. plymouth) and the other password agents, and falls back to reading from the terminal. "systemd-ask-password" and "tty" use only the corresponding one.
+    #[serde(default)]
+    pub mode: PromptMode,
+
+    /// The time in seconds to wait for the passphrase to be entered before failing, or 0 to wait forever (optional). Default: 300.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_secs: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum PromptMode {
+    #[default]
+    Auto,
+    SystemdAskPassword,
+    Tty,
+}
+
+/// The way the passphrase is actually asked for.
+#[derive(Debug, PartialEq, Eq)]
+enum PromptMethod {
+    SystemdAskPassword(PathBuf),
+    Tty,
+}
+
+pub struct PromptKeyProvider {
+    pub options: PromptConfig,
+}
+
+impl PromptKeyProvider {
+    fn message(&self) -> &str {
+        self.options
+            .message
+            .as_deref()
+            .unwrap_or(PROMPT_MESSAGE_DEFAULT)
+    }
+
+    /// The time to wait for the passphrase, `None` to wait forever.
+    fn timeout(&self) -> Option<Duration> {
+        match self
+            .options
+            .timeout_secs
+            .unwrap_or(PROMPT_TIMEOUT_SECS_DEFAULT)
+        {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        }
+    }
+}
+
+/// Choose how to ask for the passphrase, given the path of `systemd-ask-password` if it is
+/// installed.
+fn select_method(mode: PromptMode, ask_password: Option<PathBuf>) -> Result<PromptMethod> {
+    Ok(match (mode, ask_password) {
+        (PromptMode::Auto | PromptMode::SystemdAskPassword, Some(ask_password)) => {
+            PromptMethod::SystemdAskPassword(ask_password)
+        }
+        (PromptMode::Auto, None) => {
+            tracing::debug!(
+                "{SYSTEMD_ASK_PASSWORD_BINARY} is not installed, reading the passphrase from the terminal"
+            );
+            PromptMethod::Tty
+        }
+        (PromptMode::SystemdAskPassword, None) => {
+            bail!("{SYSTEMD_ASK_PASSWORD_BINARY} is not installed")
+        }
+        (PromptMode::Tty, _) => PromptMethod::Tty,
+    })
+}
+
+async fn ask_with_systemd(
+    ask_password: &Path,
+    message: &str,
+    timeout: Option<Duration>,
+) -> Result<Passphrase> {
+    let mut output = Command::new(ask_password)
+        .arg("--id=cryptpilot")
+        .arg(format!(
+            "--timeout={}",
+            timeout.map(|timeout| timeout.as_secs()).unwrap_or(0)
+        ))
+        .arg(message)
+        .run()
+        .await
+        .context("Failed to ask for the passphrase with systemd-ask-password")?;
+    // The passphrase is terminated with a newline
+    if output.last() == Some(&b'\n') {
+        output.pop();
+    }
+    Ok(Passphrase::from(output))
+}
+
+async fn ask_with_tty(message: &str, timeout: Option<Duration>) -> Result<Passphrase> {
+    let message = message.to_owned();
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    // A detached thread rather than `spawn_blocking`, since the runtime waits for the blocking
+    // tasks on shutdown, while the read cannot be cancelled after the timeout
+    std::thread::spawn(move || {
+        let _ = tx.send(
+            dialoguer::Password::new()
+                .with_prompt(message)
+                .allow_empty_password(false)
+                .interact(),
+        );
+    });
+    let passphrase = match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, rx)
+            .await
+            .with_context(|| format!("No passphrase entered on the terminal within {timeout:?}"))?,
+        None => rx.await,
+    }?
+    .context("Failed to read the passphrase from the terminal")?;
+    Ok(Passphrase::from(passphrase.into_bytes()))
+}
+
+#[async_trait::async_trait]
+impl KeyProvider for PromptKeyProvider {
+    fn debug_name(&self) -> String {
+        "Interactive Prompt".into()
+    }
+
+    async fn get_key(&self) -> Result<Passphrase> {
+        if NON_INTERACTIVE.load(Ordering::Relaxed) {
+            bail!("Cannot ask for the passphrase in non-interactive mode");
+        }
+
+        let method = select_method(
+            self.options.mode,
+            which::which(SYSTEMD_ASK_PASSWORD_BINARY).ok(),
+        )?;
+
+        let passphrase = match method {
+            PromptMethod::SystemdAskPassword(ask_password) => {
+                ask_with_systemd(&ask_password, self.message(), self.timeout()).await?
+            }
+            PromptMethod::Tty => ask_with_tty(self.message(), self.timeout()).await?,
+        };
+        if passphrase.as_bytes().is_empty() {
+            bail!("The passphrase entered is empty");
+        }
+        Ok(passphrase)
+    }
+
+    fn volume_type(&self) -> super::VolumeType {
+        super::VolumeType::Persistent
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn test_select_method() -> Result<()> {
+        let ask_password = PathBuf::from("/usr/bin/systemd-ask-password");
+
+        // Fall back to the terminal if systemd-ask-password is absent
+        assert_eq!(select_method(PromptMode::Auto, None)?, PromptMethod::Tty);
+        assert_eq!(
+            select_method(PromptMode::Auto, Some(ask_password.clone()))?,
+            PromptMethod::SystemdAskPassword(ask_password.clone())
+        );
+
+        assert!(select_method(PromptMode::SystemdAskPassword, None).is_err());
+        assert_eq!(
+            select_method(PromptMode::Tty, Some(ask_password))?,
+            PromptMethod::Tty
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ask_with_systemd() -> Result<()> {
+        // A fake systemd-ask-password
+        let temp_dir = tempfile::tempdir()?;
+        let ask_password = temp_dir.path().join(SYSTEMD_ASK_PASSWORD_BINARY);
+        let args_file = temp_dir.path().join("args");
+        tokio::fs::write(
+            &ask_password,
+            format!(
+                "#!/bin/sh\necho \"$@\" >{}\necho 'test key'\n",
+                args_file.display()
+            ),
+        )
+        .await?;
+        tokio::fs::set_permissions(
+            &ask_password,
+            std::os::unix::fs::PermissionsExt::from_mode(0o755),
+        )
+        .await?;
+
+        let passphrase = ask_with_systemd(
+            &ask_password,
+            PROMPT_MESSAGE_DEFAULT,
+            Some(Duration::from_secs(30)),
+        )
+        .await?;
+        assert_eq!(passphrase.as_bytes(), b"test key");
+        assert!(tokio::fs::read_to_string(&args_file)
+            .await?
+            .contains("--timeout=30"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_non_interactive() -> Result<()> {
+        let provider = PromptKeyProvider {
+            options: toml::from_str("mode = \"tty\"")?,
+        };
+        set_non_interactive(true);
+        let result = provider.get_key().await;
+        set_non_interactive(false);
+        assert!(format!("{:#}", result.unwrap_err()).contains("non-interactive"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_config() -> Result<()> {
+        let config: PromptConfig = toml::from_str("")?;
+        assert_eq!(config.mode, PromptMode::Auto);
+        let provider = PromptKeyProvider { options: config };
+        assert_eq!(
+            provider.timeout(),
+            Some(Duration::from_secs(PROMPT_TIMEOUT_SECS_DEFAULT))
+        );
+
+        let config: PromptConfig = toml::from_str("timeout_secs = 0")?;
+        assert_eq!(PromptKeyProvider { options: config }.timeout(), None);
+
+        let config: PromptConfig =
+            toml::from_str("message = \"Passphrase:\"\nmode = \"systemd-ask-password\"")?;
+        assert_eq!(config.mode, PromptMode::SystemdAskPassword);
+        assert_eq!(config.message.as_deref(), Some("Passphrase:"));
+
+        Ok(())
+    }
+}
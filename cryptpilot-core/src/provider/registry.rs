@@ -0,0 +1,262 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use anyhow::{anyhow, bail, Result};
+use documented::{Documented, DocumentedFields};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+use crate::{config::encrypt::KeyProviderConfig, types::Passphrase};
+
+use super::{IntoProvider as _, KeyProvider, VolumeType};
+
+pub type DynKeyProvider = Box<dyn KeyProvider + Send + Sync + 'static>;
+
+/// Constructs a key provider from the provider specific options in the config.
+pub type KeyProviderConstructor = Arc<dyn Fn(toml::Table) -> Result<DynKeyProvider> + Send + Sync>;
+
+/// The tags of the built-in key providers, which are the same as the names of the config sections.
+const BUILTIN_KEY_PROVIDERS: &[&str] = &[
+    #[cfg(feature = "provider-otp")]
+    "otp",
+    #[cfg(feature = "provider-kms")]
+    "kms",
+    #[cfg(feature = "provider-kbs")]
+    "kbs",
+    #[cfg(feature = "provider-tpm2")]
+    "tpm2",
+    #[cfg(feature = "provider-oidc")]
+    "oidc",
+    #[cfg(feature = "provider-exec")]
+    "exec",
+    #[cfg(feature = "provider-systemd-credential")]
+    "systemd_credential",
+    #[cfg(feature = "provider-prompt")]
+    "prompt",
+    #[cfg(feature = "provider-pkcs11")]
+    "pkcs11",
+    #[cfg(feature = "provider-http")]
+    "http",
+];
+
+lazy_static! {
+    static ref KEY_PROVIDER_REGISTRY: RwLock<HashMap<String, KeyProviderConstructor>> = RwLock::new(
+        BUILTIN_KEY_PROVIDERS
+            .iter()
+            .map(|tag| (tag.to_string(), builtin_constructor(tag)))
+            .collect()
+    );
+}
+
+/// The built-in key providers are constructed from their typed configs in [`KeyProviderConfig`].
+fn builtin_constructor(tag: &'static str) -> KeyProviderConstructor {
+    Arc::new(move |options| {
+        let mut config = toml::Table::new();
+        config.insert(tag.to_owned(), toml::Value::Table(options));
+        let config: KeyProviderConfig = toml::Value::Table(config).try_into()?;
+        Ok(config.into_provider().0)
+    })
+}
+
+/// Register a key provider with the tag, so that it can be used in the config with:
+///
+/// ```toml
+/// [encrypt.custom]
+/// tag = "<tag>"
+/// # provider specific options
+/// ```
+///
+/// Registering a tag which is already registered, including the built-in ones, is an error.
+pub fn register_key_provider(
+    tag: &str,
+    constructor: impl Fn(toml::Table) -> Result<DynKeyProvider> + Send + Sync + 'static,
+) -> Result<()> {
+    let mut registry = KEY_PROVIDER_REGISTRY
+        .write()
+        .map_err(|_| anyhow!("The key provider registry is poisoned"))?;
+    if registry.contains_key(tag) {
+        bail!("The key provider \"{tag}\" is already registered");
+    }
+    registry.insert(tag.to_owned(), Arc::new(constructor));
+    Ok(())
+}
+
+/// Get the tags of all the registered key providers, sorted by name.
+pub fn registered_key_providers() -> Vec<String> {
+    let mut tags = KEY_PROVIDER_REGISTRY
+        .read()
+        .map(|registry| registry.keys().cloned().collect::<Vec<_>>())
+        .unwrap_or_default();
+    tags.sort();
+    tags
+}
+
+/// Construct the key provider registered with the tag.
+pub fn build_key_provider(tag: &str, options: toml::Table) -> Result<DynKeyProvider> {
+    // The lock is released before calling the constructor
+    let constructor = KEY_PROVIDER_REGISTRY
+        .read()
+        .map_err(|_| anyhow!("The key provider registry is poisoned"))?
+        .get(tag)
+        .cloned();
+    let Some(constructor) = constructor else {
+        bail!(
+            "Unknown key provider \"{tag}\", the registered ones are: {}",
+            registered_key_providers().join(", ")
+        );
+    };
+    constructor(options)
+}
+
+/// Key provider registered at runtime, which is not built into cryptpilot
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Documented, DocumentedFields)]
+pub struct CustomConfig {
+    /// The tag the key provider is registered with
+    pub tag: String,
+
+    /// The provider specific options
+    #[serde(flatten)]
+    pub options: toml::Table,
+}
+
+pub struct CustomKeyProvider {
+    tag: String,
+    inner: Result<DynKeyProvider>,
+}
+
+impl CustomKeyProvider {
+    pub fn new(options: CustomConfig) -> Self {
+        Self {
+            inner: build_key_provider(&options.tag, options.options),
+            tag: options.tag,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl KeyProvider for CustomKeyProvider {
+    fn debug_name(&self) -> String {
+        match &self.inner {
+            Ok(provider) => provider.debug_name(),
+            Err(_) => format!("Unavailable Custom Key Provider ({})", self.tag),
+        }
+    }
+
+    async fn get_key(&self) -> Result<Passphrase> {
+        match &self.inner {
+            Ok(provider) => provider.get_key().await,
+            Err(error) => bail!(
+                "Failed to construct key provider \"{}\": {error:#}",
+                self.tag
+            ),
+        }
+    }
+
+    async fn get_keys(&self) -> Result<Vec<Passphrase>> {
+        match &self.inner {
+            Ok(provider) => provider.get_keys().await,
+            Err(error) => bail!(
+                "Failed to construct key provider \"{}\": {error:#}",
+                self.tag
+            ),
+        }
+    }
+
+    fn volume_type(&self) -> VolumeType {
+        match &self.inner {
+            Ok(provider) => provider.volume_type(),
+            // Never treat the volume as temporary, which would re-format the device
+            Err(_) => VolumeType::Persistent,
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+    use crate::config::encrypt::EncryptConfig;
+
+    struct StaticKeyProvider {
+        key: String,
+    }
+
+    #[async_trait::async_trait]
+    impl KeyProvider for StaticKeyProvider {
+        fn debug_name(&self) -> String {
+            "Static Key".to_owned()
+        }
+
+        async fn get_key(&self) -> Result<Passphrase> {
+            Ok(Passphrase::from(self.key.as_bytes().to_vec()))
+        }
+
+        fn volume_type(&self) -> VolumeType {
+            VolumeType::Persistent
+        }
+    }
+
+    #[tokio::test]
+    async fn test_register_custom_key_provider() -> Result<()> {
+        register_key_provider("test-static", |options| {
+            let key = options
+                .get("key")
+                .and_then(|key| key.as_str())
+                .ok_or_else(|| anyhow!("The key is not set"))?;
+            Ok(Box::new(StaticKeyProvider {
+                key: key.to_owned(),
+            }) as DynKeyProvider)
+        })?;
+        assert!(registered_key_providers().contains(&"test-static".to_owned()));
+
+        // The tag can be registered only once
+        assert!(register_key_provider("test-static", |_| bail!("unreachable")).is_err());
+        #[cfg(feature = "provider-otp")]
+        assert!(register_key_provider("otp", |_| bail!("unreachable")).is_err());
+
+        let config: EncryptConfig = toml::from_str(
+            r#"
+            [custom]
+            tag = "test-static"
+            key = "test-passphrase"
+            "#,
+        )?;
+        let provider = config.key_provider.into_provider();
+        assert_eq!(provider.debug_name(), "Static Key");
+        assert_eq!(provider.volume_type(), VolumeType::Persistent);
+        assert_eq!(provider.get_key().await?.as_bytes(), b"test-passphrase");
+
+        // The errors in constructing the provider are reported when getting the key
+        let config: EncryptConfig = toml::from_str(
+            r#"
+            [custom]
+            tag = "test-static"
+            "#,
+        )?;
+        assert!(config.key_provider.into_provider().get_key().await.is_err());
+
+        let config: EncryptConfig = toml::from_str(
+            r#"
+            [custom]
+            tag = "test-not-registered"
+            "#,
+        )?;
+        let provider = config.key_provider.into_provider();
+        assert_eq!(provider.volume_type(), VolumeType::Persistent);
+        assert!(provider.get_key().await.is_err());
+
+        Ok(())
+    }
+
+    #[cfg(feature = "provider-otp")]
+    #[tokio::test]
+    async fn test_build_builtin_key_provider() -> Result<()> {
+        let provider = build_key_provider("otp", toml::Table::new())?;
+        assert_eq!(provider.volume_type(), VolumeType::Temporary);
+        assert!(!provider.get_key().await?.as_bytes().is_empty());
+        Ok(())
+    }
+}
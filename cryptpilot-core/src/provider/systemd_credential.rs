@@ -0,0 +1,103 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context as _, Result};
+use documented::{Documented, DocumentedFields};
+use serde::{Deserialize, Serialize};
+
+use crate::types::Passphrase;
+
+use super::KeyProvider;
+
+/// The environment variable set by systemd to the directory containing the credentials passed to
+/// the service.
+pub const CREDENTIALS_DIRECTORY_ENV: &str = "CREDENTIALS_DIRECTORY";
+
+/// Systemd Credential Key Provider (reads key from a credential passed to the service by systemd)
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Documented, DocumentedFields)]
+#[serde(deny_unknown_fields)]
+pub struct SystemdCredentialConfig {
+    /// Name of the credential, as set with `LoadCredential=` or `LoadCredentialEncrypted=` in the systemd unit
+    pub name: String,
+}
+
+pub struct SystemdCredentialKeyProvider {
+    pub options: SystemdCredentialConfig,
+}
+
+impl SystemdCredentialKeyProvider {
+    fn credential_path(&self) -> Result<PathBuf> {
+        let name = &self.options.name;
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            bail!("Invalid systemd credential name \"{name}\"");
+        }
+
+        let Some(credentials_dir) = std::env::var_os(CREDENTIALS_DIRECTORY_ENV) else {
+            bail!(
+                "The environment variable ${CREDENTIALS_DIRECTORY_ENV} is not set, the credential \"{name}\" should be passed to the service with `LoadCredential=` or `LoadCredentialEncrypted=`"
+            );
+        };
+        Ok(Path::new(&credentials_dir).join(name))
+    }
+}
+
+#[async_trait::async_trait]
+impl KeyProvider for SystemdCredentialKeyProvider {
+    fn debug_name(&self) -> String {
+        format!("Systemd Credential ({})", self.options.name)
+    }
+
+    async fn get_key(&self) -> Result<Passphrase> {
+        let path = self.credential_path()?;
+        let key = tokio::fs::read(&path)
+            .await
+            .with_context(|| format!("Failed to read systemd credential from {path:?}"))?;
+
+        Ok(Passphrase::from(key))
+    }
+
+    fn volume_type(&self) -> super::VolumeType {
+        super::VolumeType::Persistent
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[tokio::test]
+    async fn test_get_key_from_systemd_credential() -> Result<()> {
+        let provider = SystemdCredentialKeyProvider {
+            options: SystemdCredentialConfig {
+                name: "cryptpilot.data".into(),
+            },
+        };
+
+        // The checks are done in one test since the environment variable is shared in the process
+        std::env::remove_var(CREDENTIALS_DIRECTORY_ENV);
+        let error = provider.get_key().await.unwrap_err();
+        assert!(format!("{error:#}").contains(CREDENTIALS_DIRECTORY_ENV));
+
+        let credentials_dir = tempfile::tempdir()?;
+        std::env::set_var(CREDENTIALS_DIRECTORY_ENV, credentials_dir.path());
+        assert!(provider.get_key().await.is_err());
+
+        tokio::fs::write(
+            credentials_dir.path().join("cryptpilot.data"),
+            b"test-key\x00\n",
+        )
+        .await?;
+        assert_eq!(provider.get_key().await?.as_bytes(), b"test-key\x00\n");
+
+        let provider = SystemdCredentialKeyProvider {
+            options: SystemdCredentialConfig {
+                name: "../cryptpilot.data".into(),
+            },
+        };
+        assert!(provider.get_key().await.is_err());
+
+        std::env::remove_var(CREDENTIALS_DIRECTORY_ENV);
+        Ok(())
+    }
+}
@@ -1,5 +1,6 @@
 use std::fmt::{Debug, Display};
 
+use anyhow::{bail, Result};
 use rand::RngCore as _;
 use serde::{Deserialize, Serialize};
 use zeroize::{Zeroize, ZeroizeOnDrop};
@@ -24,6 +25,23 @@ impl Passphrase {
 
         Passphrase::from(passphrase.into_bytes())
     }
+
+    /// Check that the passphrase returned by a key provider is usable before it is used to format
+    /// or unlock a volume. An empty passphrase is always rejected. Trailing whitespace (e.g. the
+    /// newline printed by `echo` without `-n`) is kept as part of the passphrase, which is warned
+    /// about, or rejected if `reject_trailing_whitespace` is set.
+    pub fn validate(&self, reject_trailing_whitespace: bool) -> Result<()> {
+        if self.0.is_empty() {
+            bail!("The passphrase is empty, the key provider may be misconfigured");
+        }
+        if self.0.last().is_some_and(|c| c.is_ascii_whitespace()) {
+            if reject_trailing_whitespace {
+                bail!("The passphrase ends with whitespace, which is likely an unexpected trailing newline from the key provider");
+            }
+            tracing::warn!("The passphrase ends with whitespace, which is used as part of the key. Check the key provider if it is unexpected, e.g. a trailing newline from `echo` without `-n`");
+        }
+        Ok(())
+    }
 }
 
 impl From<Vec<u8>> for Passphrase {
@@ -56,6 +74,65 @@ pub enum IntegrityType {
     NoJournal,
 }
 
+/// The cipher and mode of the data encryption of a LUKS2 volume.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum Luks2Cipher {
+    /// AES in XTS mode with two 256-bit keys (default).
+    #[default]
+    #[serde(rename = "aes-xts-plain64")]
+    Aes256Xts,
+    /// SM4 in XTS mode with two 128-bit keys, e.g. for compliance with the Chinese commercial
+    /// cryptography standards.
+    #[serde(rename = "sm4-xts-plain64")]
+    Sm4Xts,
+}
+
+impl Luks2Cipher {
+    /// The cipher and the mode passed to libcryptsetup.
+    pub fn cipher_and_mode(&self) -> (&'static str, &'static str) {
+        match self {
+            Luks2Cipher::Aes256Xts => ("aes", "xts-plain64"),
+            Luks2Cipher::Sm4Xts => ("sm4", "xts-plain64"),
+        }
+    }
+
+    /// The size in bits of the encryption key, which is a part of the volume key.
+    pub fn key_size_bit(&self) -> usize {
+        match self {
+            Luks2Cipher::Aes256Xts => 512,
+            Luks2Cipher::Sm4Xts => 256,
+        }
+    }
+}
+
+impl Display for Luks2Cipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(serde_variant::to_variant_name(self).unwrap_or("<unknown>"))
+    }
+}
+
+/// Tuning of the dm-integrity journal of a volume with data integrity protection. The tunables
+/// which are not set are left to the defaults of the kernel.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(deny_unknown_fields)]
+pub struct IntegrityTuning {
+    /// Whether to open the volume with the dm-integrity journal, which keeps the data and the
+    /// integrity tags consistent across a crash at the cost of writing the data twice. Default:
+    /// false, i.e. the data is written directly.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub journal: Option<bool>,
+
+    /// The journal watermark in percent of the journal size, from 0 to 100. Flushing the journal
+    /// to the data area starts once the journal is filled above the watermark. Kernel default: 50.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub journal_watermark: Option<u32>,
+
+    /// The journal commit time in milliseconds, from 1 to 3600000. A journal which is not full is
+    /// committed at this interval. Kernel default: 10000.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub journal_commit_time: Option<u32>,
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
 #[serde(rename_all = "lowercase")]
 #[serde(deny_unknown_fields)]
@@ -66,8 +143,78 @@ pub enum MakeFsType {
     Vfat,
 }
 
+impl MakeFsType {
+    /// The maximum length (in bytes) of the label of this file system.
+    pub fn max_label_len(&self) -> usize {
+        match self {
+            MakeFsType::Swap => 16,
+            MakeFsType::Ext4 => 16,
+            MakeFsType::Xfs => 12,
+            MakeFsType::Vfat => 11,
+        }
+    }
+
+    /// The file system type of this file system reported by blkid (`TYPE="..."`).
+    pub fn blkid_type(&self) -> &'static str {
+        match self {
+            MakeFsType::Swap => "swap",
+            MakeFsType::Ext4 => "ext4",
+            MakeFsType::Xfs => "xfs",
+            MakeFsType::Vfat => "vfat",
+        }
+    }
+}
+
+/// The behavior when the file system found on an opened volume is not the one configured with
+/// `makefs`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FsMismatchPolicy {
+    /// Close the volume and fail the open operation.
+    Fail,
+    /// Log a warning and continue.
+    #[default]
+    Warn,
+    /// Skip the check.
+    Ignore,
+}
+
 impl Display for MakeFsType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_str(serde_variant::to_variant_name(self).unwrap_or("<unknown>"))
     }
 }
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn test_validate_passphrase() {
+        assert!(Passphrase::random().validate(true).is_ok());
+        assert!(Passphrase::from(b"test-passphrase".to_vec())
+            .validate(true)
+            .is_ok());
+
+        // Empty passphrase is always rejected
+        assert!(Passphrase::from(vec![]).validate(false).is_err());
+        assert!(Passphrase::from(vec![]).validate(true).is_err());
+
+        // Trailing whitespace is only rejected on demand
+        for passphrase in [&b"test-passphrase\n"[..], b"test-passphrase ", b"\t"] {
+            assert!(Passphrase::from(passphrase.to_vec())
+                .validate(false)
+                .is_ok());
+            assert!(Passphrase::from(passphrase.to_vec())
+                .validate(true)
+                .is_err());
+        }
+
+        // Leading whitespace is fine
+        assert!(Passphrase::from(b" test-passphrase".to_vec())
+            .validate(true)
+            .is_ok());
+    }
+}
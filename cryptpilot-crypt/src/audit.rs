@@ -0,0 +1,181 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::RwLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context as _, Result};
+use lazy_static::lazy_static;
+use serde::Serialize;
+use tokio::io::AsyncWriteExt as _;
+
+use crate::config::VolumeConfig;
+
+/// The default path of the audit log.
+pub const AUDIT_LOG_PATH_DEFAULT: &str = "/var/log/cryptpilot/audit.log";
+
+lazy_static! {
+    static ref AUDIT_LOG_PATH: RwLock<Option<PathBuf>> =
+        RwLock::new(Some(PathBuf::from(AUDIT_LOG_PATH_DEFAULT)));
+}
+
+/// Set the path of the audit log, to which a record is appended for every destructive operation.
+/// `None` disables the audit log.
+pub fn set_audit_log_path(path: Option<PathBuf>) {
+    *AUDIT_LOG_PATH.write().unwrap_or_else(|e| e.into_inner()) = path;
+}
+
+fn audit_log_path() -> Option<PathBuf> {
+    AUDIT_LOG_PATH
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+}
+
+/// The destructive operations recorded in the audit log.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuditOperation {
+    /// Format a volume with `init`.
+    Init,
+    /// Format an initialized volume again with `init --force-reinit`.
+    Reinit,
+    /// Rotate the integrity key of a volume by reformatting it with `rotate-integrity-key --reformat`.
+    Rekey,
+    /// Convert the LUKS1 header of a volume to LUKS2 with `convert-to-luks2`.
+    Convert,
+    /// Schedule the deferred removal of a volume still in use with `close --force`.
+    CloseForce,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuditOutcome {
+    Success,
+    Failure,
+}
+
+/// A record of the audit log, written as a line of JSON.
+#[derive(Serialize, Debug)]
+pub struct AuditRecord {
+    /// Seconds since the UNIX epoch.
+    pub timestamp: u64,
+    pub operation: AuditOperation,
+    pub volume: String,
+    pub device: PathBuf,
+    pub outcome: AuditOutcome,
+    /// The error of a failed operation.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub uid: u32,
+    pub command_line: Vec<String>,
+}
+
+impl AuditRecord {
+    pub fn new<T>(
+        operation: AuditOperation,
+        volume_config: &VolumeConfig,
+        result: &Result<T>,
+    ) -> Self {
+        Self {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or_default(),
+            operation,
+            volume: volume_config.volume.clone(),
+            device: volume_config.dev.clone(),
+            outcome: match result {
+                Ok(_) => AuditOutcome::Success,
+                Err(_) => AuditOutcome::Failure,
+            },
+            error: result.as_ref().err().map(|error| format!("{error:#}")),
+            uid: nix::unistd::getuid().as_raw(),
+            command_line: std::env::args().collect(),
+        }
+    }
+}
+
+/// Append a record of the operation on the volume and its outcome to the audit log. A failure to
+/// write the audit log is only logged, and never fails the operation.
+pub async fn record<T>(
+    operation: AuditOperation,
+    volume_config: &VolumeConfig,
+    result: &Result<T>,
+) {
+    let Some(path) = audit_log_path() else {
+        return;
+    };
+    let record = AuditRecord::new(operation, volume_config, result);
+    if let Err(error) = append_record(&path, &record).await {
+        tracing::warn!(?error, "Failed to write the audit log {path:?}, continuing");
+    }
+}
+
+async fn append_record(path: &Path, record: &AuditRecord) -> Result<()> {
+    let mut line = serde_json::to_string(record)?;
+    line.push('\n');
+
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .with_context(|| format!("Failed to create directory {parent:?}"))?;
+    }
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .with_context(|| format!("Failed to open {path:?}"))?;
+    // A single write keeps the line in one piece when appended by concurrent processes
+    file.write_all(line.as_bytes()).await?;
+    file.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[tokio::test]
+    async fn test_append_record() -> Result<()> {
+        let volume_config: VolumeConfig = toml::from_str(
+            r#"
+            volume = "data0"
+            dev = "/dev/nonexist"
+
+            [encrypt.otp]
+            "#,
+        )?;
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("log/audit.log");
+
+        let record = AuditRecord::new(AuditOperation::Init, &volume_config, &Ok(()));
+        append_record(&path, &record).await?;
+        let record = AuditRecord::new(
+            AuditOperation::CloseForce,
+            &volume_config,
+            &Err::<(), _>(anyhow::anyhow!("device busy")),
+        );
+        append_record(&path, &record).await?;
+
+        let content = tokio::fs::read_to_string(&path).await?;
+        let lines = content
+            .lines()
+            .map(serde_json::from_str::<serde_json::Value>)
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["operation"], "init");
+        assert_eq!(lines[0]["volume"], "data0");
+        assert_eq!(lines[0]["device"], "/dev/nonexist");
+        assert_eq!(lines[0]["outcome"], "success");
+        assert!(lines[0].get("error").is_none());
+        assert_eq!(lines[1]["operation"], "close-force");
+        assert_eq!(lines[1]["outcome"], "failure");
+        assert_eq!(lines[1]["error"], "device busy");
+
+        Ok(())
+    }
+}
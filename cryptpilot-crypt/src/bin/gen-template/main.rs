@@ -1,5 +1,15 @@
-use anyhow::{bail, Context, Result};
+use std::{
+    io::Write as _,
+    os::unix::fs::{OpenOptionsExt as _, PermissionsExt as _},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
 use clap::{command, Parser, ValueEnum};
+#[cfg(feature = "provider-http")]
+use cryptpilot::provider::http::HttpConfig;
+#[cfg(feature = "provider-pkcs11")]
+use cryptpilot::provider::pkcs11::{Pkcs11Config, Pkcs11Mechanism, Pkcs11PinSource};
 use cryptpilot::{
     config::encrypt::{EncryptConfig, KeyProviderConfig},
     provider::{
@@ -8,8 +18,10 @@ use cryptpilot::{
         kms::KmsConfig,
         oidc::{AliyunKmsConfig, Kms, OidcConfig},
         otp::OtpConfig,
+        prompt::{PromptConfig, PromptMode},
+        systemd_credential::SystemdCredentialConfig,
     },
-    types::MakeFsType,
+    types::{FsMismatchPolicy, IntegrityTuning, MakeFsType},
 };
 use documented::{Documented, DocumentedFields};
 use serde::{Deserialize, Serialize};
@@ -52,6 +64,70 @@ pub struct ExtraConfig {
     /// Whether or not to enable support for data integrity. The default value is false. Note that integrity cannot prevent a replay (rollback) attack.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub integrity: Option<bool>,
+
+    /// Command to run after the volume is opened, e.g. ["/usr/sbin/fsck", "-a"]. The first element is the program and the rest are its arguments. The environment variables CRYPTPILOT_VOLUME, CRYPTPILOT_VOLUME_PATH and CRYPTPILOT_DEV are set to the volume name, the path of the opened volume and the underlying device.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub post_open: Option<Vec<String>>,
+
+    /// Whether or not to close the volume and fail the open operation if the `post_open` command fails. The default value is false, which means the failure is only logged as a warning.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub post_open_abort_on_failure: Option<bool>,
+
+    /// Command to run before the volume is closed, e.g. ["/usr/bin/sync"]. It is run in the same way as `post_open`. If the command fails, the volume will not be closed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pre_close: Option<Vec<String>>,
+
+    /// The label of the file system created by `makefs`, which can be used to reference the volume with `LABEL=` (e.g. in /etc/fstab). The maximum length is 16 bytes for "swap" and "ext4", 12 bytes for "xfs" and 11 bytes for "vfat".
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fs_label: Option<String>,
+
+    /// The existing signatures (the `TYPE` or `PTTYPE` reported by `blkid -p`, e.g. "ext4", "xfs" or "gpt") on the device which are allowed to be overwritten when the device is formatted as a LUKS2 volume. If any other signature is detected, formatting is aborted. An empty list means no existing signature may be overwritten. A LUKS2 signature can always be overwritten. If not set, any existing signature will be overwritten.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub overwrite_signatures: Option<Vec<String>>,
+
+    /// Whether to allow discard (TRIM) requests to be passed through to the underlying device, which benefits SSD-backed volumes. Note that this can leak information about which blocks of the device are in use (e.g. the file system type and the amount of used space). Default: false.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub discard: Option<bool>,
+
+    /// The sector size in bytes of the LUKS2 volume, which should be a power of two between 512 and 4096, e.g. 512 for devices which only support 512-byte sectors. If not set, it is detected from the logical block size of the device.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sector_size: Option<u32>,
+
+    /// Mount options (e.g. "nodiscard") which are only needed the first time the file system created by `makefs` is mounted, e.g. to skip operations which are redundant on a fresh file system. On the first open after the file system is created, they are passed to the `post_open` command with the CRYPTPILOT_MOUNT_OPTIONS environment variable, which is empty on the subsequent opens. For a volume with a temporary key provider, the file system is re-created on every open, so every open is a first open.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_open_mount_options: Option<String>,
+
+    /// Whether to reject a passphrase from the key provider which ends with whitespace (e.g. a trailing newline printed by `echo` without `-n`), instead of only warning about it. Such whitespace is used as part of the key, so a volume formatted with it cannot be opened if the key provider is fixed later. Default: false.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reject_passphrase_trailing_whitespace: Option<bool>,
+
+    /// The size in bytes of the LUKS2 metadata area, which holds the keyslots and tokens in JSON. Should be a power of two between 16384 (16 KiB) and 4194304 (4 MiB). A larger area is needed for many keyslots or tokens. If not set, the default of libcryptsetup is used.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<u64>,
+
+    /// The size in bytes of the LUKS2 keyslots area, which holds the encrypted volume keys of the keyslots. Should be a multiple of 4096 and at most 134217728 (128 MiB). If not set, the default of libcryptsetup is used.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keyslots_size: Option<u64>,
+
+    /// Whether to check and repair the file system on the volume with fsck (in the automatic repair mode `fsck -p`) after the volume is opened and before the `post_open` command is run, e.g. for a data volume which may be left unclean by a power loss. The file system type is taken from `makefs` if set, or detected otherwise. Swap volumes are skipped. Errors corrected by fsck are only logged, and the open operation fails (and the volume is closed) if errors are left uncorrected. Default: false.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fsck: Option<bool>,
+
+    /// Tuning of the dm-integrity journal for a volume with `integrity` enabled, e.g. for a write-heavy volume, with the fields `journal` (whether to open the volume with the journal, default: false), `journal_watermark` (in percent, 0 to 100, kernel default: 50) and `journal_commit_time` (in milliseconds, 1 to 3600000, kernel default: 10000). The watermark and the commit time take effect whenever the journal is used: when the file system is created by `init`, and on open with `journal = true`. If not set, the volume is opened without the journal.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub integrity_tuning: Option<IntegrityTuning>,
+
+    /// The position of the volume in the order in which the volumes are opened during booting (and listed), with lower values opened first, e.g. to open a volume holding the key of another volume before it. Volumes with the same order are sorted by name. If not set, the order is 0.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub order: Option<i32>,
+
+    /// The SELinux context (e.g. "system_u:object_r:container_file_t:s0") in the form of `user:role:type[:range]` of the file system on the volume, on SELinux-enforcing systems. On every open, it is passed as the `context=` mount option to the `post_open` command with the CRYPTPILOT_MOUNT_OPTIONS environment variable, together with the `first_open_mount_options` if any, so that the files get the context without a separate restorecon pass.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mount_context: Option<String>,
+
+    /// What to do when the file system found on the volume after it is opened is not the one configured with `makefs` (e.g. xfs on a volume with `makefs = "ext4"`, left by an earlier provisioning), which would otherwise fail or misbehave when it is mounted. Allowed values are ["fail", "warn", "ignore"]: "fail" closes the volume and fails the open operation, "warn" only logs a warning and "ignore" skips the check. A volume without any file system is not checked. Only takes effect with `makefs`. Default: "warn".
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub makefs_mismatch: Option<FsMismatchPolicy>,
 }
 
 #[derive(Parser, Debug)]
@@ -62,6 +138,14 @@ pub struct Args {
     #[arg(value_enum)]
     /// The type of volume to generate.
     volume_type: VolumeType,
+
+    /// Write the template to the file instead of stdout. The file is created with mode 0600.
+    #[clap(long, short = 'o')]
+    output: Option<PathBuf>,
+
+    /// Overwrite the file specified by `--output` if it already exists.
+    #[clap(long, requires = "output")]
+    force: bool,
 }
 
 #[derive(ValueEnum, Clone, Debug)]
@@ -71,6 +155,12 @@ pub enum VolumeType {
     Kbs,
     Oidc,
     Exec,
+    SystemdCredential,
+    Prompt,
+    #[cfg(feature = "provider-pkcs11")]
+    Pkcs11,
+    #[cfg(feature = "provider-http")]
+    Http,
 }
 
 impl VolumeType {
@@ -104,8 +194,12 @@ XXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXX
 "#
                         .into(),
                     ),
+                    kbs_root_cert_path: None,
+                    kbs_native_root_store: None,
                 },
                 key_uri: "kbs:///default/mykey/volume_data0".into(),
+                max_retries: None,
+                retry_interval_secs: None,
             }),
             VolumeType::Oidc => KeyProviderConfig::Oidc(OidcConfig {
                 kms: Kms::Aliyun(AliyunKmsConfig {
@@ -121,6 +215,35 @@ XXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXX
                 command: "echo".into(),
                 args: vec!["passphrase".into()],
             }),
+            VolumeType::SystemdCredential => {
+                KeyProviderConfig::SystemdCredential(SystemdCredentialConfig {
+                    name: "cryptpilot.data0".into(),
+                })
+            }
+            VolumeType::Prompt => KeyProviderConfig::Prompt(PromptConfig {
+                message: Some("Please enter the passphrase for data0:".into()),
+                mode: PromptMode::Auto,
+                timeout_secs: None,
+            }),
+            #[cfg(feature = "provider-pkcs11")]
+            VolumeType::Pkcs11 => KeyProviderConfig::Pkcs11(Pkcs11Config {
+                module_path: "/usr/lib64/pkcs11/libsofthsm2.so".into(),
+                slot: 0,
+                pin_source: Pkcs11PinSource::File("/etc/cryptpilot/hsm.pin".into()),
+                key_label: "cryptpilot".into(),
+                wrapped_blob: "XXXXXXXXX".into(),
+                mechanism: Pkcs11Mechanism::RsaPkcsOaep,
+            }),
+            #[cfg(feature = "provider-http")]
+            VolumeType::Http => KeyProviderConfig::Http(HttpConfig {
+                url: "https://secrets.example.com/v1/data0".into(),
+                method: None,
+                headers: None,
+                bearer_token: Some("XXXXXXXXX".into()),
+                body: None,
+                response_path: Some("$.data.passphrase".into()),
+                ca_cert: None,
+            }),
         };
         VolumeConfig {
             dev: "/dev/nvme1n1p1".into(),
@@ -129,8 +252,28 @@ XXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXX
                 auto_open: Some(true),
                 makefs: Some(MakeFsType::Ext4),
                 integrity: Some(true),
+                post_open: None,
+                post_open_abort_on_failure: None,
+                pre_close: None,
+                fs_label: None,
+                overwrite_signatures: None,
+                discard: None,
+                sector_size: None,
+                first_open_mount_options: None,
+                reject_passphrase_trailing_whitespace: None,
+                metadata_size: None,
+                keyslots_size: None,
+                fsck: None,
+                integrity_tuning: None,
+                order: None,
+                mount_context: None,
+                makefs_mismatch: None,
+            },
+            encrypt: EncryptConfig {
+                key_provider,
+                kdf: None,
+                cipher: None,
             },
-            encrypt: EncryptConfig { key_provider },
         }
     }
 }
@@ -222,6 +365,55 @@ impl AsAnnotatedToml for VolumeConfig {
                 annotate_toml_table::<ExecConfig>(provider_config)
                     .context("Failed to annotate `ExecConfig`")?;
             }
+            KeyProviderConfig::SystemdCredential(_) => {
+                let Some(provider_config) = key_provider.get_mut("systemd_credential") else {
+                    return Ok(toml);
+                };
+                let Some(provider_config) = provider_config.as_table_mut() else {
+                    return Ok(toml);
+                };
+                append_docs_as_toml_comments(
+                    provider_config.decor_mut(),
+                    SystemdCredentialConfig::DOCS,
+                );
+                annotate_toml_table::<SystemdCredentialConfig>(provider_config)
+                    .context("Failed to annotate `SystemdCredentialConfig`")?;
+            }
+            KeyProviderConfig::Prompt(_) => {
+                let Some(provider_config) = key_provider.get_mut("prompt") else {
+                    return Ok(toml);
+                };
+                let Some(provider_config) = provider_config.as_table_mut() else {
+                    return Ok(toml);
+                };
+                append_docs_as_toml_comments(provider_config.decor_mut(), PromptConfig::DOCS);
+                annotate_toml_table::<PromptConfig>(provider_config)
+                    .context("Failed to annotate `PromptConfig`")?;
+            }
+            #[cfg(feature = "provider-pkcs11")]
+            KeyProviderConfig::Pkcs11(_) => {
+                let Some(provider_config) = key_provider.get_mut("pkcs11") else {
+                    return Ok(toml);
+                };
+                let Some(provider_config) = provider_config.as_table_mut() else {
+                    return Ok(toml);
+                };
+                append_docs_as_toml_comments(provider_config.decor_mut(), Pkcs11Config::DOCS);
+                annotate_toml_table::<Pkcs11Config>(provider_config)
+                    .context("Failed to annotate `Pkcs11Config`")?;
+            }
+            #[cfg(feature = "provider-http")]
+            KeyProviderConfig::Http(_) => {
+                let Some(provider_config) = key_provider.get_mut("http") else {
+                    return Ok(toml);
+                };
+                let Some(provider_config) = provider_config.as_table_mut() else {
+                    return Ok(toml);
+                };
+                append_docs_as_toml_comments(provider_config.decor_mut(), HttpConfig::DOCS);
+                annotate_toml_table::<HttpConfig>(provider_config)
+                    .context("Failed to annotate `HttpConfig`")?;
+            }
             _ => {}
         }
 
@@ -290,6 +482,75 @@ fn main() -> Result<()> {
 
     let doc = args.volume_type.get_volume_config().as_annotated_toml()?;
 
-    print!("{}", doc);
+    write_template(&doc.to_string(), args.output.as_deref(), args.force)
+}
+
+/// Write the template to the file, or to stdout if no file is specified. The file is created with
+/// mode 0600, since the template may contain secret placeholders (e.g. credentials of the key
+/// provider). An existing file is only overwritten with `force`.
+fn write_template(template: &str, output: Option<&Path>, force: bool) -> Result<()> {
+    let Some(output) = output else {
+        print!("{template}");
+        return Ok(());
+    };
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).mode(0o600);
+    if force {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    let mut file = options.open(output).map_err(|error| match error.kind() {
+        std::io::ErrorKind::AlreadyExists => {
+            anyhow!("The file {output:?} already exists, use `--force` to overwrite it")
+        }
+        _ => anyhow!(error).context(format!("Failed to open {output:?}")),
+    })?;
+    // The mode of an overwritten file is not changed by `open()`
+    file.set_permissions(std::fs::Permissions::from_mode(0o600))
+        .with_context(|| format!("Failed to set permissions of {output:?}"))?;
+    file.write_all(template.as_bytes())
+        .with_context(|| format!("Failed to write template to {output:?}"))?;
+
     Ok(())
 }
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn test_write_template_to_file() -> Result<()> {
+        let output = std::env::temp_dir().join(format!(
+            "cryptpilot-gen-template-{}.toml",
+            rand::random::<u64>()
+        ));
+
+        // The file is created with mode 0600
+        write_template("first", Some(&output), false)?;
+        assert_eq!(std::fs::read_to_string(&output)?, "first");
+        assert_eq!(
+            std::fs::metadata(&output)?.permissions().mode() & 0o777,
+            0o600
+        );
+
+        // The existing file is not overwritten without `force`
+        assert!(write_template("second", Some(&output), false).is_err());
+        assert_eq!(std::fs::read_to_string(&output)?, "first");
+
+        // The existing file is overwritten with `force`, and the mode is fixed
+        std::fs::set_permissions(&output, std::fs::Permissions::from_mode(0o644))?;
+        write_template("second", Some(&output), true)?;
+        assert_eq!(std::fs::read_to_string(&output)?, "second");
+        assert_eq!(
+            std::fs::metadata(&output)?.permissions().mode() & 0o777,
+            0o600
+        );
+
+        std::fs::remove_file(&output)?;
+        Ok(())
+    }
+}
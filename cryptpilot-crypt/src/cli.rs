@@ -1,5 +1,5 @@
 use clap::{Args, Parser, Subcommand, ValueEnum};
-use std::fmt::Display;
+use std::{fmt::Display, num::NonZeroUsize, path::PathBuf};
 
 use crate::build::CLAP_LONG_VERSION;
 
@@ -11,8 +11,48 @@ pub struct Cli {
     pub command: CryptSubcommand,
 
     /// Path to the root directory where to load configuration files. Default value is /etc/cryptpilot.
+    /// Can be specified multiple times, in which case configs in later directories override those in earlier ones.
     #[clap(long, short = 'c', global = true)]
-    pub config_dir: Option<String>,
+    pub config_dir: Vec<String>,
+
+    /// Read the volume configs from stdin instead of the configuration files, as a TOML document with each volume in a `[[volume]]` table.
+    #[clap(long, global = true, conflicts_with = "config_dir")]
+    pub config_stdin: bool,
+
+    /// Never prompt on the terminal, and assume `--yes` for the commands which would otherwise ask for confirmation. Can also be enabled by setting the environment variable `CRYPTPILOT_NONINTERACTIVE=1`.
+    #[clap(long, global = true)]
+    pub non_interactive: bool,
+
+    /// Log the config generated for the one-shot CDH of the KBS key provider, with the secrets redacted, to troubleshoot KBS failures. Can also be enabled by setting the environment variable `CRYPTPILOT_DUMP_CDH_CONFIG=1`, or `CRYPTPILOT_DUMP_CDH_CONFIG=keep` to also keep the config file after use.
+    #[clap(long, global = true)]
+    pub dump_cdh_config: bool,
+
+    /// Seconds to wait for another cryptpilot process to finish its operation (init, open or close) on the same device, before failing.
+    #[clap(long, global = true, default_value = "60")]
+    pub lock_timeout: u64,
+
+    /// Do not lock the devices during init, open and close. Concurrent operations on the same device may then corrupt the LUKS2 header.
+    #[clap(long, global = true)]
+    pub no_lock: bool,
+
+    /// Path of the audit log, to which a JSON line is appended for every destructive operation (init, re-init, integrity key rotation and forced close), with its outcome. A failure to write the audit log never fails the operation.
+    #[clap(long, global = true, default_value = crate::audit::AUDIT_LOG_PATH_DEFAULT)]
+    pub audit_log: PathBuf,
+
+    /// Prefix prepended to the names of the device mapper devices set up below /dev/mapper/, e.g. to isolate the mappings of parallel test runs. Empty by default.
+    #[clap(long, global = true, hide = true, default_value = "")]
+    pub dm_name_prefix: String,
+}
+
+pub const CRYPTPILOT_NONINTERACTIVE_ENV: &str = "CRYPTPILOT_NONINTERACTIVE";
+
+impl Cli {
+    /// Whether the non-interactive mode is enabled, either by `--non-interactive` or by the environment variable.
+    pub fn is_non_interactive(&self) -> bool {
+        self.non_interactive
+            || std::env::var(CRYPTPILOT_NONINTERACTIVE_ENV)
+                .is_ok_and(|value| value == "1" || value.eq_ignore_ascii_case("true"))
+    }
 }
 
 #[derive(Subcommand, Debug)]
@@ -33,13 +73,54 @@ pub enum CryptSubcommand {
     #[command(name = "close")]
     Close(CloseOptions),
 
+    /// Check whether the underlay devices of volumes are initialized and ready to open.
+    #[command(name = "is-initialized")]
+    IsInitialized(IsInitializedOptions),
+
+    /// Print the systemd unit templates for opening and closing volumes on demand.
+    #[command(name = "systemd-unit")]
+    SystemdUnit(SystemdUnitOptions),
+
+    /// Report the capabilities of a block device which matter for volumes on it, e.g. whether discarded blocks read back as zeros and the block sizes.
+    #[command(name = "device-caps")]
+    DeviceCaps(DeviceCapsOptions),
+
+    /// Report the status of the data integrity protection of a volume, and rotate its integrity (HMAC) key by reformatting the volume.
+    #[command(name = "rotate-integrity-key")]
+    RotateIntegrityKey(RotateIntegrityKeyOptions),
+
+    /// Convert the legacy LUKS1 header of a volume to LUKS2 in place, keeping the data and the passphrases.
+    #[command(name = "convert-to-luks2")]
+    ConvertToLuks2(ConvertToLuks2Options),
+
     /// Subcommands related to configuration.
     #[command(name = "config")]
     Config(ConfigOptions),
 
+    /// Subcommands related to key providers.
+    #[command(name = "provider")]
+    Provider(ProviderOptions),
+
     /// Running during system booting for data volumes auto-open.
     #[command(name = "boot-service")]
     BootService(BootServiceOptions),
+
+    /// Debugging utilities, only for testing.
+    #[cfg(feature = "debug")]
+    #[command(name = "debug", hide = true)]
+    Debug(DebugOptions),
+}
+
+impl CryptSubcommand {
+    /// Confirm the operation in advance for the commands which would otherwise prompt for it.
+    pub fn assume_yes(&mut self) {
+        match self {
+            CryptSubcommand::Init(init_options) => init_options.yes = true,
+            CryptSubcommand::RotateIntegrityKey(rotate_options) => rotate_options.yes = true,
+            CryptSubcommand::ConvertToLuks2(convert_options) => convert_options.yes = true,
+            _ => {}
+        }
+    }
 }
 
 #[derive(Parser, Debug)]
@@ -51,9 +132,17 @@ pub struct ShowOptions {
     /// Output as JSON format instead of table
     #[clap(long)]
     pub json: bool,
+
+    /// Re-render the table every `--interval` seconds until interrupted, highlighting the volumes whose status changed, e.g. to watch the volumes come up during boot.
+    #[clap(long, conflicts_with = "json")]
+    pub watch: bool,
+
+    /// Seconds between two renderings of `--watch`.
+    #[clap(long, default_value_t = 2, requires = "watch", value_parser = clap::value_parser!(u64).range(1..))]
+    pub interval: u64,
 }
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 pub struct InitOptions {
     /// Name of the volume to initialize.
     #[arg(required=true, num_args=1..)]
@@ -66,6 +155,22 @@ pub struct InitOptions {
     /// Skip confirmation prompts.
     #[clap(long, short = 'y', default_value = "false")]
     pub yes: bool,
+
+    /// Maximum number of volumes to initialize in parallel (default: number of CPUs). Only takes effect with `--yes`, otherwise volumes are initialized one by one.
+    #[clap(long)]
+    pub parallel_devices: Option<NonZeroUsize>,
+
+    /// Adopt an existing LUKS2 volume (e.g. created by plain cryptsetup) without re-formatting it. The passphrase from the key provider must already unlock the volume.
+    #[clap(long, default_value = "false", conflicts_with = "force_reinit")]
+    pub from_existing: bool,
+
+    /// Allow initializing the device backing the root (`/`) or `/boot` file system of the running system, or the disk holding it. This is refused otherwise, even with `--yes`.
+    #[clap(long = "i-know-this-is-root", default_value = "false")]
+    pub i_know_this_is_root: bool,
+
+    /// Read the passphrase of each volume from stdin instead of its key provider, one `<volume>=<base64 passphrase>` per line, for provisioning many volumes from an orchestrator holding the keys. The key goes through `encrypt.kdf` of the volume, if set, like the key from the key provider. Every volume to initialize must be given a key, and no other ones. Requires `--yes`.
+    #[clap(long, default_value = "false", conflicts_with = "from_existing")]
+    pub key_from_stdin_per_volume: bool,
 }
 
 #[derive(Parser, Debug)]
@@ -77,13 +182,108 @@ pub struct OpenOptions {
     /// Check if the filesystem is initialized after opening the volume.
     #[clap(long, default_value = "false")]
     pub check_fs: bool,
+
+    /// Path to a TOML file with an alternate key provider config (in the same format as the `[encrypt]` section of the volume config), which overrides the configured key provider of the volume. This is useful for recovery when the configured key provider is unavailable.
+    #[clap(long)]
+    pub key_provider_override: Option<PathBuf>,
+
+    /// If the mapping of the volume is already active (e.g. opened in initrd), verify that it is backed by the configured device before reusing it. Without this, an active mapping is reused as is.
+    #[clap(long, default_value = "false")]
+    pub map_existing: bool,
+
+    /// Only check that the key provider returns a passphrase which unlocks a keyslot of the volume, without setting up the mapping. The passphrase is tested against the keyslots without activating the volume, which is cheap enough for frequent health probes.
+    #[clap(long, default_value = "false", conflicts_with = "map_existing")]
+    pub probe_only: bool,
+
+    /// Number of times to retry fetching the passphrase and opening a volume after a failure (e.g. a transient key provider error), before giving up. Useful for manual recovery in the emergency shell.
+    #[clap(long, default_value = "0")]
+    pub retries: u32,
+
+    /// Seconds to wait between the retries of `--retries`.
+    #[clap(long, default_value = "1")]
+    pub retry_delay: u64,
 }
 
 #[derive(Parser, Debug)]
 pub struct CloseOptions {
     /// Name of the volume to close.
-    #[arg(required=true, num_args=1..)]
+    #[arg(num_args=1.., required_unless_present = "all", conflicts_with = "all")]
     pub volume: Vec<String>,
+
+    /// Close all the configured volumes which are active, in the reverse of the order in which
+    /// they are opened. Volumes which are not active are skipped, and a failure to close one
+    /// volume does not stop the others from being closed.
+    #[clap(long)]
+    pub all: bool,
+
+    /// If the volume is still in use, schedule a deferred removal of the mapping instead of failing,
+    /// so that it is removed once the last user releases it.
+    #[clap(long, default_value = "false")]
+    pub force: bool,
+
+    /// Turn off swap on the volume and unmount all its mount points before removing the mapping.
+    #[clap(long, default_value = "false")]
+    pub teardown: bool,
+}
+
+#[derive(Parser, Debug)]
+pub struct IsInitializedOptions {
+    /// Name of the volume(s) to check.
+    #[arg(num_args=1.., required_unless_present = "all", conflicts_with = "all")]
+    pub volume: Vec<String>,
+
+    /// Check all the configured volumes.
+    #[clap(long)]
+    pub all: bool,
+}
+
+#[derive(Parser, Debug)]
+pub struct SystemdUnitOptions {
+    /// Name of the volume(s) to be managed with the systemd units. Each volume is checked to exist in the config, and the names of its unit instances are printed.
+    #[arg(num_args=0..)]
+    pub volume: Vec<String>,
+
+    /// Write the unit files into this directory (e.g. /etc/systemd/system) instead of printing them.
+    #[clap(long)]
+    pub output_dir: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+pub struct DeviceCapsOptions {
+    /// Path to the block device.
+    pub dev: PathBuf,
+
+    /// Output as JSON format instead of text
+    #[clap(long)]
+    pub json: bool,
+}
+
+#[derive(Parser, Debug)]
+pub struct RotateIntegrityKeyOptions {
+    /// Name of the volume.
+    pub volume: String,
+
+    /// Rotate the integrity key by reformatting the volume with a new volume key, which erases all the data on it. Without this, only the current integrity key status is reported.
+    #[clap(long, default_value = "false")]
+    pub reformat: bool,
+
+    /// Skip confirmation prompts.
+    #[clap(long, short = 'y', default_value = "false")]
+    pub yes: bool,
+
+    /// Output the integrity key status as JSON format instead of text
+    #[clap(long, conflicts_with = "reformat")]
+    pub json: bool,
+}
+
+#[derive(Parser, Debug)]
+pub struct ConvertToLuks2Options {
+    /// Name of the volume.
+    pub volume: String,
+
+    /// Skip confirmation prompts.
+    #[clap(long, short = 'y', default_value = "false")]
+    pub yes: bool,
 }
 
 #[derive(Debug, Args)]
@@ -99,6 +299,62 @@ pub enum ConfigSubcommand {
     /// Check if the config is valid.
     #[command(name = "check")]
     Check(ConfigCheckOptions),
+
+    /// Encrypt a volume config file with a key from a bootstrap key provider, so that the secrets in it are not stored in plaintext. The encrypted file is decrypted automatically when the config is loaded.
+    #[command(name = "encrypt")]
+    Encrypt(ConfigEncryptOptions),
+}
+
+#[derive(Debug, Args)]
+#[command(args_conflicts_with_subcommands = true)]
+pub struct ProviderOptions {
+    #[command(subcommand)]
+    pub command: ProviderSubcommand,
+}
+
+#[derive(Subcommand, Debug)]
+#[command(args_conflicts_with_subcommands = true)]
+pub enum ProviderSubcommand {
+    /// Fetch a passphrase from the key provider of a volume for real, and report whether it succeeds, the length of the passphrase (never the passphrase itself) and how long it takes. The device of the volume is not touched, unlike `open --probe-only`, which also tests the passphrase against the LUKS header.
+    #[command(name = "test")]
+    Test(ProviderTestOptions),
+}
+
+#[derive(Parser, Debug)]
+pub struct ProviderTestOptions {
+    /// Name of the volume whose key provider to test.
+    #[clap(long)]
+    pub volume: String,
+}
+
+#[cfg(feature = "debug")]
+#[derive(Debug, Args)]
+pub struct DebugOptions {
+    #[command(subcommand)]
+    pub command: DebugSubcommand,
+}
+
+#[cfg(feature = "debug")]
+#[derive(Subcommand, Debug)]
+pub enum DebugSubcommand {
+    /// Overwrite some bytes of a device with random ones to simulate data corruption. This destroys data on the device.
+    #[command(name = "corrupt")]
+    Corrupt(DebugCorruptOptions),
+}
+
+#[cfg(feature = "debug")]
+#[derive(Parser, Debug)]
+pub struct DebugCorruptOptions {
+    /// Path to the block device or file to corrupt.
+    pub dev: PathBuf,
+
+    /// Offset in bytes of the data to corrupt.
+    #[clap(long)]
+    pub offset: u64,
+
+    /// Number of bytes to corrupt.
+    #[clap(long, default_value = "16")]
+    pub length: usize,
 }
 
 #[derive(Parser, Debug)]
@@ -112,6 +368,20 @@ pub struct ConfigCheckOptions {
     pub skip_check_passphrase: bool,
 }
 
+#[derive(Parser, Debug)]
+pub struct ConfigEncryptOptions {
+    /// Path to the plaintext volume config file to encrypt.
+    pub input: PathBuf,
+
+    /// Path to a TOML file with the config of the bootstrap key provider, in the same format as the `encrypt` section of a volume config, e.g. `[kms]` followed by its fields. It must be a persistent key provider, and must be available whenever the config is loaded.
+    #[clap(long)]
+    pub key_provider: PathBuf,
+
+    /// Path to write the encrypted config file to, e.g. /etc/cryptpilot/volumes/data0.toml. Print to stdout if not specified.
+    #[clap(long, short = 'o')]
+    pub output: Option<PathBuf>,
+}
+
 #[derive(Parser, Debug)]
 pub struct BootServiceOptions {
     /// Indicate the stage of the boot process we are in.
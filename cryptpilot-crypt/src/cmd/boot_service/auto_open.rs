@@ -1,8 +1,42 @@
-use anyhow::Result;
+use std::path::Path;
+
+use anyhow::{Context as _, Result};
+use cryptpilot::measure::{AutoDetectMeasure, Measure, OPERATION_NAME_LOAD_VOLUME_CONFIG};
 
 use crate::cli::BootServiceOptions;
 
-use crate::cmd::show::PrintAsTable;
+use crate::{cmd::show::PrintAsTable, config::VolumeConfig};
+
+/// A marker file which disables the auto-open of all the data volumes, e.g. to service the storage
+/// during a maintenance window, without editing the volume configs.
+pub const DISABLE_AUTO_OPEN_MARKER: &str = "/run/cryptpilot/disable-auto-open";
+
+/// The kernel command line token with the same effect as [`DISABLE_AUTO_OPEN_MARKER`].
+pub const DISABLE_AUTO_OPEN_CMDLINE_TOKEN: &str = "cryptpilot.no_auto_open";
+
+const KERNEL_CMDLINE_PATH: &str = "/proc/cmdline";
+
+/// Check whether the auto-open is globally disabled with the marker file or the token in the kernel
+/// command line, and return the reason if so.
+pub async fn auto_open_disabled_by(marker: &Path, cmdline_path: &Path) -> Option<String> {
+    if tokio::fs::try_exists(marker).await.unwrap_or(false) {
+        return Some(format!("the marker file {marker:?}"));
+    }
+    match tokio::fs::read_to_string(cmdline_path).await {
+        Ok(cmdline) => {
+            if cmdline.split_whitespace().any(|token| {
+                token == DISABLE_AUTO_OPEN_CMDLINE_TOKEN
+                    || token == format!("{DISABLE_AUTO_OPEN_CMDLINE_TOKEN}=1")
+            }) {
+                return Some(format!(
+                    "\"{DISABLE_AUTO_OPEN_CMDLINE_TOKEN}\" in the kernel command line"
+                ));
+            }
+        }
+        Err(error) => tracing::debug!(?error, "Failed to read {cmdline_path:?}"),
+    }
+    None
+}
 
 pub async fn setup_user_provided_volumes(_boot_service_options: &BootServiceOptions) -> Result<()> {
     tracing::info!("Checking status for all volumes now");
@@ -15,6 +49,22 @@ pub async fn setup_user_provided_volumes(_boot_service_options: &BootServiceOpti
         return Ok(());
     }
     volume_configs.print_as_table().await?;
+
+    let measure = AutoDetectMeasure::new().await;
+    measure_volume_configs(&measure, &volume_configs)
+        .await
+        .context("Failed to measure the loaded volume configs, refuse to open any volume")?;
+
+    if let Some(reason) = auto_open_disabled_by(
+        Path::new(DISABLE_AUTO_OPEN_MARKER),
+        Path::new(KERNEL_CMDLINE_PATH),
+    )
+    .await
+    {
+        tracing::warn!("Auto-open of all volumes is disabled by {reason}, skip opening volumes");
+        return Ok(());
+    }
+
     tracing::info!("Opening volumes according to volume configs");
     for volume_config in &volume_configs {
         // We only open volumes with auto_open=true
@@ -31,7 +81,7 @@ pub async fn setup_user_provided_volumes(_boot_service_options: &BootServiceOpti
             volume_config.volume,
             volume_config.dev
         );
-        match crate::cmd::open::open_for_specific_volume(volume_config, false).await {
+        match crate::cmd::open::open_for_specific_volume(volume_config, false, false).await {
             Ok(_) => {
                 tracing::info!(
                     "The mapping for volume {} is active now",
@@ -50,3 +100,119 @@ pub async fn setup_user_provided_volumes(_boot_service_options: &BootServiceOpti
     volume_configs.print_as_table().await?;
     Ok(())
 }
+
+/// Extend the hash of the loaded volume configs to the runtime measurement, so that attestation can
+/// cover the data volume configs. This is a no-op if no attestation agent is present.
+pub async fn measure_volume_configs<M: Measure>(
+    measure: &M,
+    volume_configs: &[VolumeConfig],
+) -> Result<()> {
+    let content_to_hash = crate::config::gen_volume_configs_hash_content(volume_configs)?;
+    tracing::info!(
+        "Measuring the loaded volume configs, hash: {}",
+        M::calculate_hashed_measurement_value(content_to_hash.clone())?
+    );
+    measure
+        .extend_measurement_hash(OPERATION_NAME_LOAD_VOLUME_CONFIG.into(), content_to_hash)
+        .await
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+    use anyhow::Result;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct MockMeasure {
+        events: Mutex<Vec<(String, String)>>,
+    }
+
+    impl Measure for MockMeasure {
+        async fn extend_measurement(&self, operation: String, content: String) -> Result<()> {
+            self.events.lock().unwrap().push((operation, content));
+            Ok(())
+        }
+    }
+
+    fn volume_config(volume: &str, dev: &str) -> Result<VolumeConfig> {
+        Ok(toml::from_str(&format!(
+            r#"
+            volume = "{volume}"
+            dev = "{dev}"
+            auto_open = true
+
+            [encrypt.otp]
+            "#
+        ))?)
+    }
+
+    #[tokio::test]
+    async fn test_measure_volume_configs() -> Result<()> {
+        let data0 = volume_config("data0", "/dev/nvme1n1p1")?;
+        let data1 = volume_config("data1", "/dev/nvme1n1p2")?;
+
+        let measure = MockMeasure::default();
+        measure_volume_configs(&measure, &[data0.clone(), data1.clone()]).await?;
+        // The order of the volume configs does not matter
+        measure_volume_configs(&measure, &[data1.clone(), data0.clone()]).await?;
+        // Any change to the volume configs is reflected in the measurement
+        let mut changed = data1.clone();
+        changed.extra_config.auto_open = Some(false);
+        measure_volume_configs(&measure, &[data0.clone(), changed]).await?;
+
+        let events = measure.events.lock().unwrap();
+        assert_eq!(events.len(), 3);
+        assert!(events
+            .iter()
+            .all(|(operation, _)| operation == OPERATION_NAME_LOAD_VOLUME_CONFIG));
+        assert_eq!(
+            events[0].1,
+            MockMeasure::calculate_hashed_measurement_value(
+                crate::config::gen_volume_configs_hash_content(&[data0, data1])?
+            )?
+        );
+        assert_eq!(events[0].1, events[1].1);
+        assert_ne!(events[0].1, events[2].1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_auto_open_disabled_by() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let marker = temp_dir.path().join("disable-auto-open");
+        let cmdline = temp_dir.path().join("cmdline");
+
+        tokio::fs::write(&cmdline, "BOOT_IMAGE=/vmlinuz root=/dev/vda3 ro\n").await?;
+        assert_eq!(auto_open_disabled_by(&marker, &cmdline).await, None);
+        // A missing cmdline does not disable the auto-open
+        assert_eq!(
+            auto_open_disabled_by(&marker, &temp_dir.path().join("missing")).await,
+            None
+        );
+
+        // The marker disables the auto-open
+        tokio::fs::write(&marker, "").await?;
+        assert!(auto_open_disabled_by(&marker, &cmdline)
+            .await
+            .is_some_and(|reason| reason.contains("marker")));
+        tokio::fs::remove_file(&marker).await?;
+
+        // So does the cmdline token
+        for token in ["cryptpilot.no_auto_open", "cryptpilot.no_auto_open=1"] {
+            tokio::fs::write(&cmdline, format!("root=/dev/vda3 {token} ro\n")).await?;
+            assert!(auto_open_disabled_by(&marker, &cmdline)
+                .await
+                .is_some_and(|reason| reason.contains("kernel command line")));
+        }
+        for token in ["cryptpilot.no_auto_open=0", "xcryptpilot.no_auto_open"] {
+            tokio::fs::write(&cmdline, format!("root=/dev/vda3 {token} ro\n")).await?;
+            assert_eq!(auto_open_disabled_by(&marker, &cmdline).await, None);
+        }
+
+        Ok(())
+    }
+}
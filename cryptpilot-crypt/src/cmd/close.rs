@@ -1,28 +1,328 @@
-use anyhow::Result;
+use std::fmt::Display;
+
+use anyhow::{bail, Context as _, Result};
 use async_trait::async_trait;
+use cryptpilot::fs::cmd::CheckCommandOutput as _;
+use tokio::process::Command;
 
-use crate::cli::CloseOptions;
+use crate::{audit::AuditOperation, cli::CloseOptions, config::VolumeConfig};
 
 pub struct CloseCommand {
     pub close_options: CloseOptions,
 }
 
+/// What happened to a volume requested to be closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CloseOutcome {
+    Closed,
+    /// The mapping is still in use, and is scheduled for deferred removal with `--force`.
+    Deferred,
+    /// The mapping is not active, e.g. it was never opened or is removed by another process.
+    NotActive,
+}
+
 #[async_trait]
 impl crate::cmd::Command for CloseCommand {
     async fn run(&self) -> Result<()> {
-        for volume in &self.close_options.volume {
-            tracing::info!("Close volume {volume} now");
+        let volumes = if self.close_options.all {
+            let volume_configs = crate::config::get_volume_config_source()
+                .await
+                .get_volume_configs()
+                .await?;
+            volumes_in_close_order(&volume_configs)
+        } else {
+            self.close_options.volume.clone()
+        };
+
+        let mut closed = vec![];
+        let mut skipped = vec![];
+        let mut failed = vec![];
+        for volume in &volumes {
+            match self.close_volume(volume).await {
+                Ok(CloseOutcome::Closed) => closed.push(volume.to_owned()),
+                Ok(CloseOutcome::Deferred) => closed.push(format!("{volume} (deferred)")),
+                Ok(CloseOutcome::NotActive) => skipped.push(volume.to_owned()),
+                // With `--all`, a failure does not keep the other volumes open
+                Err(error) if self.close_options.all => {
+                    tracing::error!("Failed to close volume {volume}: {error:#}");
+                    failed.push((volume.to_owned(), error));
+                }
+                Err(error) => return Err(error),
+            }
+        }
+
+        if self.close_options.all {
+            let list = |volumes: &[String]| match volumes {
+                [] => "none".to_owned(),
+                volumes => volumes.join(", "),
+            };
+            println!("Closed volumes: {}", list(&closed));
+            println!("Skipped volumes (not active): {}", list(&skipped));
+            if !failed.is_empty() {
+                let failed_volumes = failed
+                    .iter()
+                    .map(|(volume, _)| volume.to_owned())
+                    .collect::<Vec<_>>();
+                println!("Failed volumes: {}", list(&failed_volumes));
+            }
+        }
+
+        if !failed.is_empty() {
+            bail!(
+                "Failed to close {} volume(s): {}",
+                failed.len(),
+                failed
+                    .iter()
+                    .map(|(volume, error)| format!("{volume}: {error:#}"))
+                    .collect::<Vec<_>>()
+                    .join("; ")
+            );
+        }
+
+        Ok(())
+    }
+}
+
+impl CloseCommand {
+    async fn close_volume(&self, volume: &str) -> Result<CloseOutcome> {
+        tracing::info!("Close volume {volume} now");
+
+        if !cryptpilot::fs::luks2::is_active(volume) {
+            tracing::info!("The mapping for {} is not active, nothing to do", volume);
+            return Ok(CloseOutcome::NotActive);
+        }
 
+        // The mapping can be removed without the config, e.g. when the config file is deleted
+        // while the volume is still opened. Only the steps which need the config are skipped.
+        let volume_config = match crate::config::get_volume_config_source()
+            .await
+            .get_volume_config(volume)
+            .await
+        {
+            Ok(volume_config) => Some(volume_config),
+            Err(error) => {
+                tracing::warn!(
+                    "Failed to get the config of volume {volume}, closing it without the device lock, the pre_close hook and the teardown: {error:#}"
+                );
+                None
+            }
+        };
+
+        let _lock = match &volume_config {
+            Some(volume_config) => {
+                Some(cryptpilot::fs::lock::DeviceLock::lock(&volume_config.dev).await?)
+            }
+            None => None,
+        };
+        if let Some(volume_config) = &volume_config {
             if !cryptpilot::fs::luks2::is_active(volume) {
-                tracing::info!("The mapping for {} is not active, nothing to do", volume);
-                continue;
+                tracing::info!("The mapping for {volume} has been removed by another process");
+                return Ok(CloseOutcome::NotActive);
+            }
+            crate::hooks::run_volume_hook(volume_config, crate::hooks::VolumeHook::PreClose)
+                .await?;
+
+            if self.close_options.teardown {
+                teardown_volume(volume_config)
+                    .await
+                    .with_context(|| format!("Failed to tear down volume {volume}"))?;
+            }
+        }
+
+        tracing::info!("Removing mapping for {volume}");
+        if let Err(error) = cryptpilot::fs::luks2::close(volume).await {
+            if !cryptpilot::fs::luks2::is_busy_error(&error) {
+                return Err(error);
+            }
+            let users = find_device_users(volume).await;
+            if !self.close_options.force {
+                return Err(error.context(format!(
+                    "The volume {volume} is still in use{users}, release it first or use `--force` to remove the mapping once it is released"
+                )));
             }
 
-            tracing::info!("Removing mapping for {volume}");
-            cryptpilot::fs::luks2::close(volume).await?;
-            tracing::info!("The volume {volume} is closed now");
+            tracing::warn!(
+                ?error,
+                "Failed to close volume {volume}, which is still in use{users}"
+            );
+            let result = cryptpilot::fs::luks2::close_deferred(volume).await;
+            if let Some(volume_config) = &volume_config {
+                crate::audit::record(AuditOperation::CloseForce, volume_config, &result).await;
+            }
+            result?;
+            tracing::info!(
+                "The mapping for {volume} is scheduled for deferred removal, and will be removed once the last user releases it"
+            );
+            return Ok(CloseOutcome::Deferred);
+        }
+        tracing::info!("The volume {volume} is closed now");
+        Ok(CloseOutcome::Closed)
+    }
+}
+
+/// The names of the volumes in the reverse of the order in which they are opened (see
+/// `sort_by_open_order`), so that a volume is closed after the volumes depending on it, e.g. the
+/// ones whose key files are stored on it.
+fn volumes_in_close_order(volume_configs: &[VolumeConfig]) -> Vec<String> {
+    let mut volume_configs = volume_configs.to_vec();
+    crate::config::sort_by_open_order(&mut volume_configs);
+    volume_configs
+        .iter()
+        .rev()
+        .map(|volume_config| volume_config.volume.clone())
+        .collect()
+}
+
+/// Release the volume from the system before its mapping is removed, reversing what is usually done
+/// after it is opened: turn it off if it is an active swap area, and unmount all its mount points.
+async fn teardown_volume(volume_config: &VolumeConfig) -> Result<()> {
+    let volume_path = volume_config.volume_path();
+    if cryptpilot::fs::mount::is_swap_active(&volume_path).await? {
+        tracing::info!("Turning off swap on volume {}", volume_config.volume);
+        cryptpilot::fs::mount::swapoff(&volume_path).await?;
+    }
+    let mount_points = cryptpilot::fs::mount::umount_all(&volume_path).await?;
+    if !mount_points.is_empty() {
+        tracing::info!(
+            "Volume {} is unmounted from {mount_points:?}",
+            volume_config.volume
+        );
+    }
+    Ok(())
+}
+
+/// The users which keep the mapping of a volume busy.
+#[derive(Debug, Default)]
+struct DeviceUsers {
+    /// Block devices stacked on top of the mapping.
+    holders: Vec<String>,
+    mount_points: Vec<String>,
+    /// Processes with the device or a file on it opened, as `<pid> (<command>)`.
+    processes: Vec<String>,
+}
+
+impl Display for DeviceUsers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut users = vec![];
+        if !self.holders.is_empty() {
+            users.push(format!("held by {}", self.holders.join(", ")));
+        }
+        if !self.mount_points.is_empty() {
+            users.push(format!("mounted on {}", self.mount_points.join(", ")));
+        }
+        if !self.processes.is_empty() {
+            users.push(format!("opened by {}", self.processes.join(", ")));
+        }
+        if !users.is_empty() {
+            write!(f, " ({})", users.join("; "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Find out what keeps the mapping of the volume busy. This is best-effort, any error is logged and
+/// ignored.
+async fn find_device_users(volume: &str) -> DeviceUsers {
+    let mut users = DeviceUsers::default();
+    let dev_path = cryptpilot::fs::block::devicemapper::dm_path(volume);
+
+    match cryptpilot::fs::luks2::get_holders(volume).await {
+        Ok(holders) => users.holders = holders,
+        Err(error) => tracing::debug!(?error, "Failed to get holders of {dev_path:?}"),
+    }
+
+    match cryptpilot::fs::mount::find_mount_points(&dev_path).await {
+        Ok(mount_points) => {
+            users.mount_points = mount_points
+                .iter()
+                .map(|mount_point| mount_point.to_string_lossy().to_string())
+                .collect()
         }
+        Err(error) => tracing::debug!(?error, "Failed to get mount points of {dev_path:?}"),
+    }
 
+    // `fuser` prints only the pids to stdout, and exits with an error if no process is found
+    let mut pids = vec![];
+    let mut fuser_args = vec![vec![dev_path.to_string_lossy().to_string()]];
+    for mount_point in &users.mount_points {
+        fuser_args.push(vec!["-m".to_owned(), mount_point.to_owned()]);
+    }
+    for args in fuser_args {
+        match Command::new("fuser").args(&args).run().await {
+            Ok(stdout) => pids.extend(
+                String::from_utf8_lossy(&stdout)
+                    .split_whitespace()
+                    .filter_map(|pid| pid.parse::<u32>().ok()),
+            ),
+            Err(error) => tracing::debug!(?error, "No process found with fuser {args:?}"),
+        }
+    }
+    pids.sort_unstable();
+    pids.dedup();
+    for pid in pids {
+        let comm = tokio::fs::read_to_string(format!("/proc/{pid}/comm"))
+            .await
+            .map(|comm| comm.trim().to_owned())
+            .unwrap_or_else(|_| "unknown".to_owned());
+        users.processes.push(format!("{pid} ({comm})"));
+    }
+
+    users
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+    use clap::Parser as _;
+
+    fn volume_config(volume: &str, order: Option<i32>) -> Result<VolumeConfig> {
+        let mut volume_config: VolumeConfig = toml::from_str(&format!(
+            r#"
+            volume = "{volume}"
+            dev = "/dev/nvme1n1p1"
+
+            [encrypt.otp]
+            "#
+        ))?;
+        volume_config.extra_config.order = order;
+        Ok(volume_config)
+    }
+
+    #[test]
+    fn test_volumes_in_close_order() -> Result<()> {
+        let volume_configs = vec![
+            volume_config("data0", None)?,
+            volume_config("data1", Some(10))?,
+            volume_config("keys", Some(-10))?,
+            volume_config("swap", None)?,
+        ];
+        // The volume holding the keys is opened first, and closed last
+        assert_eq!(
+            volumes_in_close_order(&volume_configs),
+            ["data1", "swap", "data0", "keys"]
+        );
         Ok(())
     }
+
+    #[test]
+    fn test_close_all_conflicts_with_volumes() {
+        #[derive(clap::Parser, Debug)]
+        struct Cli {
+            #[command(flatten)]
+            close_options: CloseOptions,
+        }
+
+        let cli = Cli::try_parse_from(["close", "--all"]).unwrap();
+        assert!(cli.close_options.all);
+        assert!(cli.close_options.volume.is_empty());
+
+        let cli = Cli::try_parse_from(["close", "data0", "data1"]).unwrap();
+        assert!(!cli.close_options.all);
+        assert_eq!(cli.close_options.volume, ["data0", "data1"]);
+
+        assert!(Cli::try_parse_from(["close", "--all", "data0"]).is_err());
+        assert!(Cli::try_parse_from(["close"]).is_err());
+    }
 }
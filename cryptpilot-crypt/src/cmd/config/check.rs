@@ -5,7 +5,10 @@ use async_trait::async_trait;
 use tracing::Instrument;
 
 use crate::cli::ConfigCheckOptions;
-use cryptpilot::provider::{IntoProvider, KeyProvider};
+use cryptpilot::{
+    provider::{IntoProvider, KeyProvider},
+    types::MakeFsType,
+};
 
 pub struct ConfigCheckCommand {
     pub config_check_options: ConfigCheckOptions,
@@ -85,11 +88,91 @@ impl super::super::Command for ConfigCheckCommand {
                     }
                 }
 
+                // Check if the file system label is valid
+                if let Some(fs_label) = &volume.extra_config.fs_label {
+                    match &volume.extra_config.makefs {
+                        Some(makefs) => {
+                            if let Err(error) = cryptpilot::fs::mkfs::check_fs_label(makefs, fs_label) {
+                                continue_or_throw!(error);
+                            }
+                        }
+                        None => continue_or_throw!(
+                            "The fs_label of volume \"{}\" is set but makefs is not set",
+                            volume.volume
+                        ),
+                    }
+                }
+
+                // The first open mount options only make sense for a file system created by makefs
+                if volume.extra_config.first_open_mount_options.is_some()
+                    && volume.extra_config.makefs.is_none()
+                {
+                    continue_or_throw!(
+                        "The first_open_mount_options of volume \"{}\" is set but makefs is not set",
+                        volume.volume
+                    );
+                }
+
+                // The file system is only checked against the one of makefs
+                if volume.extra_config.makefs_mismatch.is_some()
+                    && volume.extra_config.makefs.is_none()
+                {
+                    continue_or_throw!(
+                        "The makefs_mismatch of volume \"{}\" is set but makefs is not set",
+                        volume.volume
+                    );
+                }
+
+                // Check if the SELinux context is valid, which is meaningless for a swap volume
+                if let Some(mount_context) = &volume.extra_config.mount_context {
+                    if volume.extra_config.makefs == Some(MakeFsType::Swap) {
+                        continue_or_throw!(
+                            "The mount_context of volume \"{}\" is set but the volume is a swap volume",
+                            volume.volume
+                        );
+                    }
+                    if let Err(error) = cryptpilot::fs::mount::selinux_context_mount_option(mount_context) {
+                        continue_or_throw!(error);
+                    }
+                }
+
+                // Check if the sector size is valid
+                if let Some(sector_size) = volume.extra_config.sector_size {
+                    if let Err(error) = cryptpilot::fs::luks2::check_sector_size(sector_size) {
+                        continue_or_throw!(error);
+                    }
+                }
+
+                // Check if the sizes of the LUKS2 header areas are valid
+                if let Some(metadata_size) = volume.extra_config.metadata_size {
+                    if let Err(error) = cryptpilot::fs::luks2::check_metadata_size(metadata_size) {
+                        continue_or_throw!(error);
+                    }
+                }
+                if let Some(keyslots_size) = volume.extra_config.keyslots_size {
+                    if let Err(error) = cryptpilot::fs::luks2::check_keyslots_size(keyslots_size) {
+                        continue_or_throw!(error);
+                    }
+                }
+
+                // Check if the integrity tuning is valid
+                if let Some(integrity_tuning) = &volume.extra_config.integrity_tuning {
+                    if volume.extra_config.integrity != Some(true) {
+                        continue_or_throw!(
+                            "The integrity_tuning of volume \"{}\" is set but integrity is not enabled",
+                            volume.volume
+                        );
+                    }
+                    if let Err(error) = cryptpilot::fs::luks2::check_integrity_tuning(integrity_tuning) {
+                        continue_or_throw!(error);
+                    }
+                }
+
                 if self.config_check_options.skip_check_passphrase {
                     tracing::warn!("Skipping key check for volume \"{}\" due to \"--skip-check-passphrase\" is set", volume.volume);
                 } else {
                     // Check if the key provider can get the key
-                    let key_provider = volume.encrypt.key_provider.clone().into_provider();
+                    let key_provider = volume.encrypt.clone().into_provider();
                     match key_provider.get_key().await.with_context(|| {
                         format!(
                             "Failed to get key for volume \"{}\" from key provider {}",
@@ -102,14 +185,14 @@ impl super::super::Command for ConfigCheckCommand {
 
                             if dev_is_initialized {
                                 // Check if the passphrase is correct if the device is initialized
-                                match cryptpilot::fs::luks2::check_passphrase(&volume.dev, &passphrase)
+                                match cryptpilot::fs::luks2::verify_passphrase(&volume.dev, &passphrase)
                                     .await
                                     .with_context(|| {
                                         format!("The passphrase for volume \"{}\" is incorrect", volume.volume)
                                     }) {
-                                    Ok(()) => {
+                                    Ok(keyslot) => {
                                         tracing::info!(
-                                            "The passphrase for volume \"{}\" is correct",
+                                            "The passphrase for volume \"{}\" is correct, which unlocks keyslot {keyslot}",
                                             volume.volume
                                         );
                                     }
@@ -0,0 +1,66 @@
+use std::os::unix::fs::OpenOptionsExt as _;
+
+use anyhow::{bail, Context as _, Result};
+use async_trait::async_trait;
+use cryptpilot::config::{encrypt::EncryptConfig, envelope};
+use tokio::io::AsyncWriteExt as _;
+
+use crate::{cli::ConfigEncryptOptions, config::VolumeConfig};
+
+pub struct ConfigEncryptCommand {
+    pub config_encrypt_options: ConfigEncryptOptions,
+}
+
+#[async_trait]
+impl super::super::Command for ConfigEncryptCommand {
+    async fn run(&self) -> Result<()> {
+        let options = &self.config_encrypt_options;
+
+        let plaintext = tokio::fs::read_to_string(&options.input)
+            .await
+            .with_context(|| format!("Failed to read {:?}", options.input))?;
+        if envelope::is_encrypted_config(&plaintext) {
+            bail!("The config file {:?} is already encrypted", options.input);
+        }
+        // Refuse to encrypt a broken config, which would only be noticed after decrypting it
+        toml::from_str::<VolumeConfig>(&plaintext)
+            .with_context(|| format!("Failed to parse {:?} as a volume config", options.input))?;
+
+        let key = tokio::fs::read_to_string(&options.key_provider)
+            .await
+            .with_context(|| format!("Failed to read {:?}", options.key_provider))
+            .and_then(|content| {
+                toml::from_str::<EncryptConfig>(&content).with_context(|| {
+                    format!(
+                        "Failed to parse {:?} as a key provider config",
+                        options.key_provider
+                    )
+                })
+            })?;
+
+        let encrypted = envelope::encrypt_config(&plaintext, key).await?;
+        // Make sure that it can be decrypted with the bootstrap key before the plaintext is dropped
+        envelope::decrypt_config(&encrypted)
+            .await
+            .context("Failed to decrypt the encrypted config again")?;
+
+        match &options.output {
+            Some(output) => {
+                let mut file = tokio::fs::OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .mode(0o600)
+                    .open(output)
+                    .await
+                    .with_context(|| format!("Failed to open {output:?}"))?;
+                file.write_all(encrypted.as_bytes()).await?;
+                file.flush().await?;
+                tracing::info!("The encrypted config is written to {output:?}, remove the plaintext config file {:?} once it is verified", options.input);
+            }
+            None => print!("{encrypted}"),
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,106 @@
+use anyhow::{bail, Context as _, Result};
+use async_trait::async_trait;
+use cryptpilot::{
+    fs::luks2::VolumeInitState,
+    provider::{IntoProvider as _, KeyProvider as _, VolumeType},
+};
+use dialoguer::{console::Term, Confirm};
+
+use crate::{audit::AuditOperation, cli::ConvertToLuks2Options, config::VolumeConfig};
+
+pub struct ConvertToLuks2Command {
+    pub convert_to_luks2_options: ConvertToLuks2Options,
+}
+
+#[async_trait]
+impl super::Command for ConvertToLuks2Command {
+    async fn run(&self) -> Result<()> {
+        let options = &self.convert_to_luks2_options;
+        let volume = options.volume.as_str();
+
+        let volume_config = crate::config::get_volume_config_source()
+            .await
+            .get_volume_config(volume)
+            .await?;
+
+        let key_provider = volume_config.encrypt.clone().into_provider();
+        if key_provider.volume_type() == VolumeType::Temporary {
+            bail!("The volume {volume} is temporary, it is re-formatted as LUKS2 on every open");
+        }
+
+        match cryptpilot::fs::luks2::get_init_state(&volume_config.dev).await? {
+            VolumeInitState::Luks1 => {}
+            VolumeInitState::Ready => {
+                bail!("The volume {volume} is already a LUKS2 volume, nothing to convert")
+            }
+            VolumeInitState::None | VolumeInitState::Initializing => {
+                bail!(
+                    "The device {:?} of volume {volume} is not a LUKS1 volume",
+                    volume_config.dev
+                )
+            }
+        }
+
+        if cryptpilot::fs::luks2::is_active(volume) {
+            bail!("The volume {volume} is opened, close it before converting");
+        }
+        if cryptpilot::fs::luks2::is_dev_in_use(&volume_config.dev).await? {
+            bail!("The device {:?} is currently in use", volume_config.dev);
+        }
+
+        if !options.yes {
+            if !Term::stderr().is_term() {
+                bail!("Standard error is not a terminal. Please use '--yes' or '--non-interactive' to confirm the operation in non-interactive mode.");
+            }
+
+            if !Confirm::new()
+                .with_prompt(format!(
+                    "The LUKS1 header of {:?} will be converted to LUKS2 in place. Back up the header with `cryptsetup luksHeaderBackup` first. Do you want to continue?",
+                    volume_config.dev
+                ))
+                .default(false)
+                .interact()?
+            {
+                bail!("Operation canceled");
+            }
+        }
+
+        let result = convert(&volume_config).await;
+        crate::audit::record(AuditOperation::Convert, &volume_config, &result).await;
+        result?;
+
+        tracing::info!("The volume {volume} is converted to LUKS2");
+        Ok(())
+    }
+}
+
+/// Convert the LUKS1 header to LUKS2 after checking that the key provider unlocks the volume, and
+/// mark the volume as initialized by cryptpilot.
+async fn convert(volume_config: &VolumeConfig) -> Result<()> {
+    let key_provider = volume_config.encrypt.clone().into_provider();
+
+    tracing::info!("Fetching passphrase for volume {}", volume_config.volume);
+    let candidates = key_provider
+        .get_keys()
+        .await
+        .context("Failed to get passphrase")?;
+    for candidate in &candidates {
+        volume_config.validate_passphrase(candidate)?;
+    }
+    let passphrase =
+        cryptpilot::fs::luks2::select_passphrase(&volume_config.dev, candidates).await?;
+    cryptpilot::fs::luks2::check_passphrase(&volume_config.dev, &passphrase)
+        .await
+        .with_context(|| {
+            format!(
+                "The passphrase from the key provider does not unlock the LUKS1 volume on {:?}, refuse to convert it",
+                volume_config.dev
+            )
+        })?;
+
+    tracing::info!("Converting {:?} from LUKS1 to LUKS2", volume_config.dev);
+    cryptpilot::fs::luks2::convert_to_luks2(&volume_config.dev).await?;
+    cryptpilot::fs::luks2::mark_volume_as_initialized(&volume_config.dev).await?;
+
+    Ok(())
+}
@@ -0,0 +1,25 @@
+use anyhow::Result;
+use async_trait::async_trait;
+
+use crate::cli::DebugCorruptOptions;
+
+pub struct DebugCorruptCommand {
+    pub debug_corrupt_options: DebugCorruptOptions,
+}
+
+#[async_trait]
+impl super::Command for DebugCorruptCommand {
+    async fn run(&self) -> Result<()> {
+        let DebugCorruptOptions {
+            dev,
+            offset,
+            length,
+        } = &self.debug_corrupt_options;
+
+        tracing::warn!("Corrupting {length} bytes at offset {offset} of {dev:?}");
+        cryptpilot::fs::block::corrupt::corrupt_bytes(dev, *offset, *length).await?;
+        println!("Corrupted {length} bytes at offset {offset} of {dev:?}");
+
+        Ok(())
+    }
+}
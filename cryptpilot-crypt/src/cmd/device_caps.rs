@@ -0,0 +1,110 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use cryptpilot::fs::block::caps::DeviceCaps;
+use serde::Serialize;
+
+use crate::cli::DeviceCapsOptions;
+
+pub struct DeviceCapsCommand {
+    pub device_caps_options: DeviceCapsOptions,
+}
+
+#[derive(Serialize)]
+struct DeviceCapsReport {
+    #[serde(flatten)]
+    caps: DeviceCaps,
+    suggested_sector_size: Option<u32>,
+}
+
+#[async_trait]
+impl super::Command for DeviceCapsCommand {
+    async fn run(&self) -> Result<()> {
+        let caps = DeviceCaps::probe(&self.device_caps_options.dev).await?;
+
+        if self.device_caps_options.json {
+            let report = DeviceCapsReport {
+                suggested_sector_size: caps.suggested_sector_size(),
+                caps,
+            };
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        } else {
+            print!("{}", format_caps(&caps));
+        }
+
+        Ok(())
+    }
+}
+
+fn format_caps(caps: &DeviceCaps) -> String {
+    fn or_unknown(value: Option<impl ToString>) -> String {
+        value
+            .map(|value| value.to_string())
+            .unwrap_or_else(|| "unknown".to_owned())
+    }
+
+    let yes_no =
+        |value: Option<bool>| or_unknown(value.map(|value| if value { "yes" } else { "no" }));
+
+    let mut text = format!("Device:                 {:?}\n", caps.device);
+    text += &format!(
+        "Logical block size:     {}\n",
+        or_unknown(caps.logical_block_size)
+    );
+    text += &format!(
+        "Physical block size:    {}\n",
+        or_unknown(caps.physical_block_size)
+    );
+    text += &format!("Rotational:             {}\n", yes_no(caps.rotational));
+    text += &format!(
+        "Discard supported:      {}\n",
+        yes_no(caps.discard_max_bytes.map(|bytes| bytes > 0))
+    );
+    text += &format!(
+        "Discard zeroes data:    {}\n",
+        yes_no(caps.discard_zeroes_data)
+    );
+    text += &format!(
+        "Write zeroes supported: {}\n",
+        yes_no(caps.write_zeroes_max_bytes.map(|bytes| bytes > 0))
+    );
+    text += &format!(
+        "Suggested sector size:  {}\n",
+        or_unknown(caps.suggested_sector_size())
+    );
+    text
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn test_format_caps() {
+        let caps = DeviceCaps {
+            device: "/dev/vdb".into(),
+            logical_block_size: Some(512),
+            physical_block_size: Some(4096),
+            rotational: Some(false),
+            discard_max_bytes: Some(0),
+            discard_zeroes_data: None,
+            write_zeroes_max_bytes: Some(1 << 20),
+        };
+        let text = format_caps(&caps);
+        assert!(text.contains("Physical block size:    4096\n"));
+        assert!(text.contains("Discard supported:      no\n"));
+        assert!(text.contains("Discard zeroes data:    unknown\n"));
+        assert!(text.contains("Write zeroes supported: yes\n"));
+        assert!(text.contains("Suggested sector size:  4096\n"));
+
+        let report = serde_json::to_value(DeviceCapsReport {
+            suggested_sector_size: caps.suggested_sector_size(),
+            caps,
+        })
+        .unwrap();
+        assert_eq!(report["device"], "/dev/vdb");
+        assert_eq!(report["discard_zeroes_data"], serde_json::Value::Null);
+        assert_eq!(report["suggested_sector_size"], 4096);
+    }
+}
@@ -1,12 +1,21 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::IsTerminal as _,
+    num::NonZeroUsize,
+    sync::Arc,
+};
+
 use anyhow::{bail, Context, Result};
 use async_trait::async_trait;
+use base64::{prelude::BASE64_STANDARD, Engine as _};
 use dialoguer::{console::Term, Confirm};
+use tokio::{sync::Semaphore, task::JoinSet};
 
-use crate::{cli::InitOptions, cmd::show::VolumeStatusKind};
+use crate::{audit::AuditOperation, cli::InitOptions, cmd::show::VolumeStatusKind};
 use cryptpilot::{
     fs::luks2::TempLuksVolume,
     provider::{IntoProvider, KeyProvider},
-    types::IntegrityType,
+    types::{IntegrityType, Passphrase},
 };
 
 use crate::config::VolumeConfig;
@@ -18,42 +27,207 @@ pub struct InitCommand {
 #[async_trait]
 impl crate::cmd::Command for InitCommand {
     async fn run(&self) -> Result<()> {
+        let parallel_devices = if self.init_options.yes {
+            self.init_options
+                .parallel_devices
+                .or_else(|| std::thread::available_parallelism().ok())
+                .map(NonZeroUsize::get)
+                .unwrap_or(1)
+        } else {
+            // The confirmation prompts should not be interleaved
+            1
+        };
+        tracing::debug!("Initializing at most {parallel_devices} volume(s) in parallel");
+
+        let volume_keys = if self.init_options.key_from_stdin_per_volume {
+            if !self.init_options.yes {
+                bail!("The confirmation prompts cannot be answered when the keys are read from stdin. Please use '--yes' or '--non-interactive' with '--key-from-stdin-per-volume'");
+            }
+            let volume_keys = read_volume_keys_from_stdin().await?;
+            check_volume_keys(&volume_keys, &self.init_options.volume)?;
+            Some(Arc::new(volume_keys))
+        } else {
+            None
+        };
+
+        let semaphore = Arc::new(Semaphore::new(parallel_devices));
+        let mut join_set = JoinSet::new();
         for volume in &self.init_options.volume {
-            tracing::info!("Initialize volume {volume} now");
+            let semaphore = semaphore.clone();
+            let init_options = self.init_options.clone();
+            let volume = volume.clone();
+            let key = volume_keys
+                .as_ref()
+                .and_then(|volume_keys| volume_keys.get(&volume).cloned());
+            join_set.spawn(async move {
+                let _permit = semaphore.acquire_owned().await?;
+                init_for_specific_volume(&init_options, &volume, key)
+                    .await
+                    .with_context(|| format!("Failed to initialize volume {volume}"))
+            });
+        }
 
-            let volume_config = crate::config::get_volume_config_source()
-                .await
-                .get_volume_config(volume)
-                .await?;
+        let mut failed = 0;
+        while let Some(result) = join_set.join_next().await {
+            if let Err(error) = result? {
+                tracing::error!("{error:?}");
+                failed += 1;
+            }
+        }
+        if failed > 0 {
+            bail!(
+                "Failed to initialize {failed} of {} volume(s)",
+                self.init_options.volume.len()
+            );
+        }
+        Ok(())
+    }
+}
 
-            tracing::info!(
-                "The key_provider type is \"{}\"",
-                serde_variant::to_variant_name(&volume_config.encrypt.key_provider)?
+/// Read the passphrases of the volumes from stdin, see [`parse_volume_keys`].
+async fn read_volume_keys_from_stdin() -> Result<BTreeMap<String, Passphrase>> {
+    if std::io::stdin().is_terminal() {
+        bail!("The keys of the volumes are expected to be piped to stdin, but stdin is a terminal");
+    }
+
+    let content = tokio::task::spawn_blocking(|| std::io::read_to_string(std::io::stdin()))
+        .await?
+        .context("Failed to read the keys of the volumes from stdin")?;
+    parse_volume_keys(&content)
+}
+
+/// Parse the passphrases of the volumes, one `<volume>=<base64 passphrase>` per line. Empty lines
+/// are skipped. The passphrases are never included in the errors.
+pub fn parse_volume_keys(content: &str) -> Result<BTreeMap<String, Passphrase>> {
+    let mut volume_keys = BTreeMap::new();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let Some((volume, key)) = line.split_once('=') else {
+            bail!(
+                "Line {} of the keys is not in the `<volume>=<base64 passphrase>` format",
+                index + 1
             );
+        };
+        let volume = volume.trim();
+        if volume.is_empty() {
+            bail!("Line {} of the keys has no volume name", index + 1);
+        }
+        let key = BASE64_STANDARD.decode(key.trim()).map_err(|_| {
+            anyhow::anyhow!(
+                "The key of volume {volume} on line {} is not valid base64",
+                index + 1
+            )
+        })?;
+        if volume_keys
+            .insert(volume.to_owned(), Passphrase::from(key))
+            .is_some()
+        {
+            bail!("The key of volume {volume} is given more than once");
+        }
+    }
+    Ok(volume_keys)
+}
 
-            let key_provider = volume_config.encrypt.key_provider.clone().into_provider();
+/// Check that exactly the volumes to initialize are given a key.
+pub fn check_volume_keys(
+    volume_keys: &BTreeMap<String, Passphrase>,
+    volumes: &[String],
+) -> Result<()> {
+    let volumes = volumes.iter().map(String::as_str).collect::<BTreeSet<_>>();
+    let missing = volumes
+        .iter()
+        .filter(|volume| !volume_keys.contains_key(**volume))
+        .copied()
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        bail!("No key is given for volume(s): {}", missing.join(", "));
+    }
+    let extra = volume_keys
+        .keys()
+        .map(String::as_str)
+        .filter(|volume| !volumes.contains(volume))
+        .collect::<Vec<_>>();
+    if !extra.is_empty() {
+        bail!(
+            "Keys are given for volume(s) which are not to be initialized: {}",
+            extra.join(", ")
+        );
+    }
+    Ok(())
+}
 
-            match key_provider.volume_type() {
-                cryptpilot::provider::VolumeType::Temporary => {
-                    tracing::info!("Not required to initialize");
-                    continue;
-                }
-                cryptpilot::provider::VolumeType::Persistent => {
-                    persistent_disk_init(&self.init_options, &volume_config, &key_provider).await?;
-                }
-            }
+/// Initialize the volume. The passphrase is fetched from the key provider of the volume, unless
+/// `key` is given.
+async fn init_for_specific_volume(
+    init_options: &InitOptions,
+    volume: &str,
+    key: Option<Passphrase>,
+) -> Result<()> {
+    tracing::info!("Initialize volume {volume} now");
+
+    let volume_config = crate::config::get_volume_config_source()
+        .await
+        .get_volume_config(volume)
+        .await?;
 
-            tracing::info!("The volume {volume} is initialized now");
+    tracing::info!(
+        "The key_provider type is \"{}\"",
+        serde_variant::to_variant_name(&volume_config.encrypt.key_provider)?
+    );
+
+    let key_provider = volume_config.encrypt.clone().into_provider();
+
+    let _lock = cryptpilot::fs::lock::DeviceLock::lock(&volume_config.dev).await?;
+    match key_provider.volume_type() {
+        cryptpilot::provider::VolumeType::Temporary => {
+            if key.is_some() {
+                bail!("The volume {volume} is temporary, it is formatted with a new key on every open and cannot be initialized with the given key");
+            }
+            tracing::info!("Not required to initialize");
+            return Ok(());
+        }
+        cryptpilot::provider::VolumeType::Persistent => {
+            let result =
+                persistent_disk_init(init_options, &volume_config, &key_provider, key).await;
+            let operation = if init_options.force_reinit {
+                AuditOperation::Reinit
+            } else {
+                AuditOperation::Init
+            };
+            crate::audit::record(operation, &volume_config, &result).await;
+            result?;
         }
-        Ok(())
     }
+
+    tracing::info!("The volume {volume} is initialized now");
+    Ok(())
 }
 
 async fn persistent_disk_init(
     init_options: &InitOptions,
     volume_config: &VolumeConfig,
     key_provider: &impl KeyProvider,
+    key: Option<Passphrase>,
 ) -> Result<()> {
+    if init_options.i_know_this_is_root {
+        tracing::warn!(
+            "Skipping the check of whether {:?} backs the root file system",
+            volume_config.dev
+        );
+    } else {
+        let mount_points = cryptpilot::fs::mount::SYSTEM_MOUNT_POINTS.map(std::path::Path::new);
+        cryptpilot::fs::mount::ensure_not_backing_mount_points(&volume_config.dev, &mount_points)
+            .await
+            .context("Refusing to initialize a device of the running system. Use '--i-know-this-is-root' if you really want to do this")?;
+    }
+
+    if init_options.from_existing {
+        return adopt_existing_volume(volume_config, key_provider).await;
+    }
+
     let status = volume_config.determine_status().await;
     match status.kind {
         VolumeStatusKind::DeviceNotFound
@@ -75,9 +249,18 @@ async fn persistent_disk_init(
             }
         }
     }
+    if let Some(integrity_tuning) = &volume_config.extra_config.integrity_tuning {
+        cryptpilot::fs::luks2::check_integrity_tuning(integrity_tuning)?;
+    }
+    cryptpilot::fs::mkfs::check_overwrite_signature(
+        &volume_config.dev,
+        volume_config.extra_config.overwrite_signatures.as_deref(),
+    )
+    .await?;
+
     if !init_options.yes {
         if !Term::stderr().is_term() {
-            bail!("Standard error is not a terminal. Please use '--yes' to confirm the operation in non-interactive mode.");
+            bail!("Standard error is not a terminal. Please use '--yes' or '--non-interactive' to confirm the operation in non-interactive mode.");
         }
 
         if !Confirm::new()
@@ -96,32 +279,173 @@ async fn persistent_disk_init(
         bail!("The device {:?} is currently in use", volume_config.dev);
     }
 
-    tracing::info!("Fetching passphrase for volume {}", volume_config.volume);
-    let passphrase = key_provider
-        .get_key()
-        .await
-        .context("Failed to get passphrase")?;
+    let passphrase = match key {
+        Some(key) => {
+            tracing::info!(
+                "Using the passphrase from stdin for volume {}",
+                volume_config.volume
+            );
+            // The key from stdin stands for the one from the key provider, so it goes through the
+            // same KDF as the provider's key does when the volume is opened.
+            match &volume_config.encrypt.kdf {
+                Some(kdf) => kdf
+                    .derive(&key)
+                    .await
+                    .context("Failed to derive the passphrase from the key from stdin")?,
+                None => key,
+            }
+        }
+        None => {
+            tracing::info!("Fetching passphrase for volume {}", volume_config.volume);
+            key_provider
+                .get_key()
+                .await
+                .context("Failed to get passphrase")?
+        }
+    };
+    volume_config.validate_passphrase(&passphrase)?;
 
     tracing::info!("Formatting {:?} as LUKS2 volume now", volume_config.dev);
     let integrity = match volume_config.extra_config.integrity {
         Some(true) => IntegrityType::Journal,
         Some(false) | None => IntegrityType::None,
     };
-    cryptpilot::fs::luks2::format(&volume_config.dev, &passphrase, integrity).await?;
+    cryptpilot::fs::luks2::format_with_area_size(
+        &volume_config.dev,
+        &passphrase,
+        integrity,
+        volume_config.encrypt.cipher.unwrap_or_default(),
+        volume_config.extra_config.sector_size,
+        volume_config.luks2_area_size(),
+    )
+    .await?;
 
     if let Some(makefs) = &volume_config.extra_config.makefs {
         let tmp_volume = TempLuksVolume::open(&volume_config.dev, &passphrase, integrity).await?;
+        volume_config
+            .tune_integrity_journal(tmp_volume.name(), integrity)
+            .await?;
 
         tracing::info!(
             "Initializing {makefs} fs on volume {}",
             volume_config.volume
         );
-        cryptpilot::fs::mkfs::force_mkfs(&tmp_volume.volume_path(), makefs, integrity).await?;
+        cryptpilot::fs::mkfs::force_mkfs(
+            &tmp_volume.volume_path(),
+            makefs,
+            volume_config.extra_config.fs_label.as_deref(),
+            integrity,
+        )
+        .await?;
+    }
+
+    // Mark the volume as fully initialized, and record that the file system has not been opened yet
+    if volume_config.extra_config.makefs.is_some() {
+        cryptpilot::fs::luks2::mark_volume_as_initialized_with_fresh_fs(std::path::Path::new(
+            &volume_config.dev,
+        ))
+        .await?;
+    } else {
+        cryptpilot::fs::luks2::mark_volume_as_initialized(std::path::Path::new(&volume_config.dev))
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Let cryptpilot manage an existing LUKS2 volume which is not created by it, by marking the volume
+/// as initialized. The data on the volume is kept, so the passphrase from the key provider must
+/// already unlock the volume.
+async fn adopt_existing_volume(
+    volume_config: &VolumeConfig,
+    key_provider: &impl KeyProvider,
+) -> Result<()> {
+    let status = volume_config.determine_status().await;
+    match status.kind {
+        VolumeStatusKind::RequiresInit => {}
+        VolumeStatusKind::ReadyToOpen => {
+            bail!(
+                "The device {:?} is already initialized, nothing to adopt",
+                volume_config.dev
+            );
+        }
+        VolumeStatusKind::Initializing => {
+            bail!("The initialization of device {:?} by cryptpilot was interrupted, it can not be adopted. Run `cryptpilot-crypt init` again without '--from-existing'", volume_config.dev);
+        }
+        VolumeStatusKind::DeviceNotFound
+        | VolumeStatusKind::CheckFailed
+        | VolumeStatusKind::Opened => {
+            bail!(
+                "The status of device {:?} is incorrect: {:?}({})",
+                volume_config.dev,
+                status.kind,
+                status.description
+            );
+        }
+    }
+
+    if cryptpilot::fs::luks2::is_dev_in_use(&volume_config.dev).await? {
+        bail!("The device {:?} is currently in use", volume_config.dev);
+    }
+
+    tracing::info!("Fetching passphrase for volume {}", volume_config.volume);
+    let passphrase = key_provider
+        .get_key()
+        .await
+        .context("Failed to get passphrase")?;
+    volume_config.validate_passphrase(&passphrase)?;
+
+    cryptpilot::fs::luks2::check_passphrase(&volume_config.dev, &passphrase)
+        .await
+        .with_context(|| {
+            format!(
+                "The passphrase from the key provider does not unlock the existing LUKS2 volume on {:?}, refuse to adopt it",
+                volume_config.dev
+            )
+        })?;
+
+    if volume_config.extra_config.makefs.is_some() {
+        tracing::warn!(
+            "The file system on volume {} is kept as is, makefs is ignored",
+            volume_config.volume
+        );
     }
 
-    // Mark the volume as fully initialized
+    tracing::info!("Adopting existing LUKS2 volume on {:?}", volume_config.dev);
     cryptpilot::fs::luks2::mark_volume_as_initialized(std::path::Path::new(&volume_config.dev))
         .await?;
 
     Ok(())
 }
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn test_parse_volume_keys() -> Result<()> {
+        let volume_keys = parse_volume_keys("data0=cGFzc3dvcmQw\n\n  data1 = cGFzc3dvcmQxIQ== \n")?;
+        assert_eq!(volume_keys.len(), 2);
+        assert_eq!(volume_keys["data0"].as_bytes(), b"password0");
+        assert_eq!(volume_keys["data1"].as_bytes(), b"password1!");
+
+        check_volume_keys(&volume_keys, &["data1".to_owned(), "data0".to_owned()])?;
+        // A volume without a key
+        assert!(check_volume_keys(
+            &volume_keys,
+            &["data0".to_owned(), "data1".to_owned(), "data2".to_owned()]
+        )
+        .is_err());
+        // A key of a volume which is not initialized
+        assert!(check_volume_keys(&volume_keys, &["data0".to_owned()]).is_err());
+
+        assert!(parse_volume_keys("data0").is_err());
+        assert!(parse_volume_keys("=cGFzc3dvcmQw").is_err());
+        assert!(parse_volume_keys("data0=not base64!").is_err());
+        assert!(parse_volume_keys("data0=cGFzc3dvcmQw\ndata0=cGFzc3dvcmQx").is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,150 @@
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use comfy_table::modifiers::UTF8_ROUND_CORNERS;
+use comfy_table::presets::UTF8_FULL;
+use comfy_table::*;
+use cryptpilot::fs::luks2::VolumeInitState;
+use cryptpilot::provider::{IntoProvider, KeyProvider as _, VolumeType};
+
+use crate::cli::IsInitializedOptions;
+use crate::config::VolumeConfig;
+
+/// The initialization state of the backing device of a volume.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeviceInitReport {
+    /// The device is a LUKS2 volume fully initialized by cryptpilot.
+    Initialized,
+    /// The volume uses a temporary key provider and is formatted on every open, so no
+    /// initialization is required.
+    Temporary,
+    /// The device does not exist.
+    DeviceNotFound,
+    /// The device is a LUKS2 volume whose initialization by cryptpilot was interrupted.
+    Initializing,
+    /// The device is a LUKS2 volume which is not initialized by cryptpilot.
+    ForeignLuks2,
+    /// The device is a legacy LUKS1 volume, which can be opened but should be converted to LUKS2.
+    Luks1,
+    /// The device is not a LUKS2 volume. Contains the detected signature on the device, if any.
+    Unencrypted { signature: Option<String> },
+    /// Failed to check the device.
+    CheckFailed { error: String },
+}
+
+impl DeviceInitReport {
+    /// Returns `true` if the volume can be opened without initialization.
+    pub fn is_ready(&self) -> bool {
+        matches!(self, Self::Initialized | Self::Temporary | Self::Luks1)
+    }
+
+    fn description(&self) -> String {
+        match self {
+            Self::Initialized => "initialized".to_owned(),
+            Self::Temporary => "temporary volume, no initialization required".to_owned(),
+            Self::DeviceNotFound => "device not found".to_owned(),
+            Self::Initializing => "initialization was interrupted".to_owned(),
+            Self::ForeignLuks2 => {
+                "LUKS2 volume not initialized by cryptpilot, use `init --from-existing` to adopt it"
+                    .to_owned()
+            }
+            Self::Luks1 => "legacy LUKS1 volume, use `convert-to-luks2` to convert it".to_owned(),
+            Self::Unencrypted { signature: None } => "not encrypted (no signature)".to_owned(),
+            Self::Unencrypted {
+                signature: Some(signature),
+            } => format!("not encrypted ({signature})"),
+            Self::CheckFailed { error } => format!("check failed: {error}"),
+        }
+    }
+}
+
+/// Check whether the backing device of the volume is a cryptpilot-initialized LUKS2 volume.
+pub async fn check_device_initialized(volume_config: &VolumeConfig) -> DeviceInitReport {
+    let key_provider = volume_config.encrypt.clone().into_provider();
+    if key_provider.volume_type() == VolumeType::Temporary {
+        return DeviceInitReport::Temporary;
+    }
+
+    if !Path::new(&volume_config.dev).exists() {
+        return DeviceInitReport::DeviceNotFound;
+    }
+
+    match cryptpilot::fs::luks2::get_init_state(&volume_config.dev).await {
+        Ok(VolumeInitState::Ready) => DeviceInitReport::Initialized,
+        Ok(VolumeInitState::Initializing) => DeviceInitReport::Initializing,
+        Ok(VolumeInitState::Luks1) => DeviceInitReport::Luks1,
+        Ok(VolumeInitState::None) => {
+            match cryptpilot::fs::blkid::probe_device(&volume_config.dev).await {
+                Ok(probe_result) if probe_result.is_luks() => DeviceInitReport::ForeignLuks2,
+                Ok(probe_result) => DeviceInitReport::Unencrypted {
+                    signature: probe_result.signature().map(ToOwned::to_owned),
+                },
+                Err(error) => DeviceInitReport::CheckFailed {
+                    error: format!("{error:#}"),
+                },
+            }
+        }
+        Err(error) => DeviceInitReport::CheckFailed {
+            error: format!("{error:#}"),
+        },
+    }
+}
+
+pub struct IsInitializedCommand {
+    pub is_initialized_options: IsInitializedOptions,
+}
+
+#[async_trait]
+impl crate::cmd::Command for IsInitializedCommand {
+    async fn run(&self) -> Result<()> {
+        let mut volume_configs = crate::config::get_volume_config_source()
+            .await
+            .get_volume_configs()
+            .await?;
+
+        if !self.is_initialized_options.all {
+            for volume in &self.is_initialized_options.volume {
+                if !volume_configs.iter().any(|c| &c.volume == volume) {
+                    bail!("Volume {volume} is not configured");
+                }
+            }
+            volume_configs.retain(|c| self.is_initialized_options.volume.contains(&c.volume));
+        }
+
+        let mut table = Table::new();
+        table
+            .load_preset(UTF8_FULL)
+            .apply_modifier(UTF8_ROUND_CORNERS)
+            .set_content_arrangement(ContentArrangement::Dynamic)
+            .set_header(vec!["Volume", "Underlay Device", "Initialized"]);
+
+        let mut not_ready = 0;
+        for volume_config in &volume_configs {
+            let report = check_device_initialized(volume_config).await;
+            if !report.is_ready() {
+                not_ready += 1;
+            }
+            table.add_row(vec![
+                Cell::new(&volume_config.volume),
+                Cell::new(volume_config.dev.to_string_lossy().as_ref()),
+                Cell::new(report.description()).fg(if report.is_ready() {
+                    Color::Green
+                } else {
+                    Color::Red
+                }),
+            ]);
+        }
+
+        println!("{table}");
+
+        if not_ready > 0 {
+            bail!(
+                "{not_ready} of {} volume(s) are not ready to open",
+                volume_configs.len()
+            );
+        }
+
+        Ok(())
+    }
+}
@@ -1,22 +1,38 @@
 pub mod boot_service;
 pub mod close;
 pub mod config;
+pub mod convert_to_luks2;
+#[cfg(feature = "debug")]
+pub mod debug;
+pub mod device_caps;
 pub mod init;
+pub mod is_initialized;
 pub mod open;
+pub mod provider;
+pub mod rotate_integrity_key;
 pub mod show;
+pub mod systemd_unit;
 
 use anyhow::Result;
 use async_trait::async_trait;
 
 use crate::{
-    cli::{BootServiceOptions, ConfigOptions, ConfigSubcommand},
+    cli::{
+        BootServiceOptions, ConfigOptions, ConfigSubcommand, ProviderOptions, ProviderSubcommand,
+    },
     cmd::boot_service::BootServiceCommand,
 };
 use close::CloseCommand;
-use config::check::ConfigCheckCommand;
+use config::{check::ConfigCheckCommand, encrypt::ConfigEncryptCommand};
+use convert_to_luks2::ConvertToLuks2Command;
+use device_caps::DeviceCapsCommand;
 use init::InitCommand;
+use is_initialized::IsInitializedCommand;
 use open::OpenCommand;
+use provider::test::ProviderTestCommand;
+use rotate_integrity_key::RotateIntegrityKeyCommand;
 use show::ShowCommand;
+use systemd_unit::SystemdUnitCommand;
 
 #[async_trait]
 pub trait Command {
@@ -43,14 +59,59 @@ impl IntoCommand for crate::cli::CryptSubcommand {
             crate::cli::CryptSubcommand::Close(close_options) => {
                 Box::new(CloseCommand { close_options })
             }
+            crate::cli::CryptSubcommand::IsInitialized(is_initialized_options) => {
+                Box::new(IsInitializedCommand {
+                    is_initialized_options,
+                })
+            }
+            crate::cli::CryptSubcommand::SystemdUnit(systemd_unit_options) => {
+                Box::new(SystemdUnitCommand {
+                    systemd_unit_options,
+                })
+            }
+            crate::cli::CryptSubcommand::DeviceCaps(device_caps_options) => {
+                Box::new(DeviceCapsCommand {
+                    device_caps_options,
+                })
+            }
+            crate::cli::CryptSubcommand::RotateIntegrityKey(rotate_integrity_key_options) => {
+                Box::new(RotateIntegrityKeyCommand {
+                    rotate_integrity_key_options,
+                })
+            }
+            crate::cli::CryptSubcommand::ConvertToLuks2(convert_to_luks2_options) => {
+                Box::new(ConvertToLuks2Command {
+                    convert_to_luks2_options,
+                })
+            }
             crate::cli::CryptSubcommand::Config(ConfigOptions { command }) => match command {
                 ConfigSubcommand::Check(config_check_options) => Box::new(ConfigCheckCommand {
                     config_check_options,
                 }),
+                ConfigSubcommand::Encrypt(config_encrypt_options) => {
+                    Box::new(ConfigEncryptCommand {
+                        config_encrypt_options,
+                    })
+                }
+            },
+            crate::cli::CryptSubcommand::Provider(ProviderOptions { command }) => match command {
+                ProviderSubcommand::Test(provider_test_options) => Box::new(ProviderTestCommand {
+                    provider_test_options,
+                }),
             },
             crate::cli::CryptSubcommand::BootService(BootServiceOptions { stage }) => {
                 Box::new(BootServiceCommand { stage })
             }
+            #[cfg(feature = "debug")]
+            crate::cli::CryptSubcommand::Debug(crate::cli::DebugOptions { command }) => {
+                match command {
+                    crate::cli::DebugSubcommand::Corrupt(debug_corrupt_options) => {
+                        Box::new(debug::DebugCorruptCommand {
+                            debug_corrupt_options,
+                        })
+                    }
+                }
+            }
         }
     }
 }
@@ -1,10 +1,19 @@
+use std::{
+    path::Path,
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+
+use again::RetryPolicy;
 use anyhow::{bail, Context as _, Result};
 use async_trait::async_trait;
 
 use crate::cli::OpenOptions;
 use cryptpilot::{
+    config::encrypt::EncryptConfig,
+    fs::luks2::VolumeInitState,
     provider::{IntoProvider, KeyProvider},
-    types::IntegrityType,
+    types::{FsMismatchPolicy, IntegrityType},
 };
 
 use crate::config::VolumeConfig;
@@ -16,26 +25,201 @@ pub struct OpenCommand {
 #[async_trait]
 impl crate::cmd::Command for OpenCommand {
     async fn run(&self) -> Result<()> {
+        let encrypt_override = match &self.open_options.key_provider_override {
+            Some(path) => {
+                if self.open_options.volume.len() > 1 {
+                    bail!("Only one volume can be opened when `--key-provider-override` is set");
+                }
+                Some(load_key_provider_override(path).await?)
+            }
+            None => None,
+        };
+
         for volume in &self.open_options.volume {
             tracing::info!("Open volume {volume} now");
-            let volume_config = crate::config::get_volume_config_source()
+            let mut volume_config = crate::config::get_volume_config_source()
                 .await
                 .get_volume_config(volume)
                 .await?;
 
-            open_for_specific_volume(&volume_config, self.open_options.check_fs).await?;
-            tracing::info!("The volume {volume} is active now");
+            if let Some(encrypt_override) = &encrypt_override {
+                check_key_provider_override(&volume_config, encrypt_override)?;
+                tracing::warn!(
+                    "The key provider of volume {volume} is overridden with \"{}\"",
+                    serde_variant::to_variant_name(&encrypt_override.key_provider)?
+                );
+                volume_config.encrypt = encrypt_override.clone();
+            }
+
+            let attempts = self.open_options.retries.saturating_add(1);
+            let retry_delay = Duration::from_secs(self.open_options.retry_delay);
+            let attempt = AtomicU32::new(0);
+            RetryPolicy::fixed(retry_delay)
+                .with_max_retries(self.open_options.retries as usize)
+                .retry_if(
+                    || {
+                        let attempt = attempt.fetch_add(1, Ordering::Relaxed) + 1;
+                        if attempts > 1 {
+                            tracing::info!("Opening volume {volume}, attempt {attempt}/{attempts}");
+                        }
+                        self.open_once(&volume_config)
+                    },
+                    |error: &anyhow::Error| {
+                        let attempt = attempt.load(Ordering::Relaxed);
+                        if attempt < attempts {
+                            tracing::warn!(
+                                "Attempt {attempt}/{attempts} to open volume {volume} failed, retrying in {retry_delay:?}: {error:#}"
+                            );
+                        }
+                        true
+                    },
+                )
+                .await
+                .map_err(|error| {
+                    if attempts > 1 {
+                        error.context(format!("Giving up after {attempts} attempts"))
+                    } else {
+                        error
+                    }
+                })?;
+        }
+        Ok(())
+    }
+}
+
+impl OpenCommand {
+    /// Fetch the passphrase and open the volume (or only probe it with `--probe-only`) once.
+    async fn open_once(&self, volume_config: &VolumeConfig) -> Result<()> {
+        if self.open_options.probe_only {
+            return probe_for_specific_volume(volume_config).await;
         }
+
+        open_for_specific_volume(
+            volume_config,
+            self.open_options.check_fs,
+            self.open_options.map_existing,
+        )
+        .await?;
+        tracing::info!("The volume {} is active now", volume_config.volume);
         Ok(())
     }
 }
 
-pub async fn open_for_specific_volume(volume_config: &VolumeConfig, check_fs: bool) -> Result<()> {
+/// Check that the active mapping of the volume is backed by the configured device, so that a
+/// mapping with the same name but for another device is not reused by mistake.
+async fn check_existing_mapping(volume_config: &VolumeConfig) -> Result<()> {
+    let dev = tokio::fs::canonicalize(&volume_config.dev)
+        .await
+        .with_context(|| format!("Failed to resolve the device {:?}", volume_config.dev))?;
+    let underlying_devices = cryptpilot::fs::luks2::get_underlying_devices(&volume_config.volume)
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to get the underlying devices of the mapping for volume {}",
+                volume_config.volume
+            )
+        })?;
+    let backed_by_dev = dev.file_name().is_some_and(|dev_name| {
+        underlying_devices
+            .iter()
+            .any(|underlying| dev_name == underlying.as_str())
+    });
+    if !backed_by_dev {
+        bail!(
+            "The mapping for volume {} already exists, but it is backed by {:?} instead of the configured device {:?}",
+            volume_config.volume,
+            underlying_devices,
+            volume_config.dev
+        );
+    }
+    Ok(())
+}
+
+/// Check that the key provider returns a passphrase which unlocks a keyslot of the volume, without
+/// setting up the mapping.
+pub async fn probe_for_specific_volume(volume_config: &VolumeConfig) -> Result<()> {
+    let key_provider = volume_config.encrypt.clone().into_provider();
+    if key_provider.volume_type() == cryptpilot::provider::VolumeType::Temporary {
+        tracing::info!(
+            "The volume {} is re-formatted on every open with a temporary key, nothing to probe",
+            volume_config.volume
+        );
+        return Ok(());
+    }
+    check_openable(volume_config).await?;
+
+    tracing::info!("Fetching passphrase for volume {}", volume_config.volume);
+    let candidates = key_provider
+        .get_keys()
+        .await
+        .context("Failed to get passphrase")?;
+    let count = candidates.len();
+    for (index, candidate) in candidates.iter().enumerate() {
+        volume_config.validate_passphrase(candidate)?;
+        match cryptpilot::fs::luks2::verify_passphrase(&volume_config.dev, candidate).await {
+            Ok(keyslot) => {
+                tracing::info!(
+                    "The passphrase {}/{count} of volume {} unlocks keyslot {keyslot}",
+                    index + 1,
+                    volume_config.volume
+                );
+                return Ok(());
+            }
+            Err(error) => tracing::debug!(
+                ?error,
+                "The passphrase {}/{count} does not unlock volume {}",
+                index + 1,
+                volume_config.volume
+            ),
+        }
+    }
+    bail!(
+        "None of the passphrases from the key provider unlocks volume {}",
+        volume_config.volume
+    )
+}
+
+async fn load_key_provider_override(path: &Path) -> Result<EncryptConfig> {
+    let content = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("Failed to read key provider override file {path:?}"))?;
+    toml::from_str(&content)
+        .with_context(|| format!("Failed to parse key provider override file {path:?}"))
+}
+
+/// The overriding key provider must be able to unlock the existing data on the volume, so it is not
+/// allowed to replace a persistent key provider with a temporary one, which would re-format the device.
+fn check_key_provider_override(
+    volume_config: &VolumeConfig,
+    encrypt_override: &EncryptConfig,
+) -> Result<()> {
+    let configured = volume_config.encrypt.clone().into_provider().volume_type();
+    let overriding = encrypt_override.clone().into_provider().volume_type();
+    if configured != overriding {
+        bail!(
+            "The overriding key provider is for {overriding:?} volume, which does not match the configured one ({configured:?}) of volume {}",
+            volume_config.volume
+        );
+    }
+    Ok(())
+}
+
+/// Open the volume. If the mapping of the volume is already active, there is nothing to do, and with
+/// `map_existing` it is also checked that the mapping is backed by the configured device.
+pub async fn open_for_specific_volume(
+    volume_config: &VolumeConfig,
+    check_fs: bool,
+    map_existing: bool,
+) -> Result<()> {
     tracing::info!(
         "The key_provider type is \"{}\"",
         serde_variant::to_variant_name(&volume_config.encrypt.key_provider)?
     );
+    let _lock = cryptpilot::fs::lock::DeviceLock::lock(&volume_config.dev).await?;
     if cryptpilot::fs::luks2::is_active(&volume_config.volume) {
+        if map_existing {
+            check_existing_mapping(volume_config).await?;
+        }
         tracing::info!("The mapping for {} already exists", volume_config.volume);
         return Ok(());
     }
@@ -43,18 +227,30 @@ pub async fn open_for_specific_volume(volume_config: &VolumeConfig, check_fs: bo
         bail!("The device {:?} is currently in use", volume_config.dev);
     }
 
-    let key_provider = volume_config.encrypt.key_provider.clone().into_provider();
+    let key_provider = volume_config.encrypt.clone().into_provider();
     let volume_config = volume_config.to_owned();
 
-    match key_provider.volume_type() {
+    // Whether the file system on the volume is freshly created by makefs and never opened before
+    let first_open = match key_provider.volume_type() {
         cryptpilot::provider::VolumeType::Temporary => {
             temporary_disk_open(&volume_config, &key_provider).await?;
+            // The file system is re-created on every open
+            volume_config.extra_config.makefs.is_some()
         }
         cryptpilot::provider::VolumeType::Persistent => {
             persistent_disk_open(&volume_config, &key_provider).await?;
+            cryptpilot::fs::luks2::is_fs_fresh(Path::new(&volume_config.dev))
+                .await
+                .unwrap_or(false)
         }
     };
 
+    if let Err(error) = check_makefs_mismatch(&volume_config).await {
+        tracing::info!("Closing volume {} now", volume_config.volume);
+        let _ = cryptpilot::fs::luks2::close(&volume_config.volume).await;
+        return Err(error);
+    }
+
     // Check if filesystem is ready
     if check_fs
         && volume_config.extra_config.makefs.is_some()
@@ -72,9 +268,100 @@ pub async fn open_for_specific_volume(volume_config: &VolumeConfig, check_fs: bo
         )
     }
 
+    // The file system of a temporary volume is re-created on every open, so it is always clean
+    if volume_config.extra_config.fsck == Some(true)
+        && key_provider.volume_type() == cryptpilot::provider::VolumeType::Persistent
+    {
+        if let Err(error) = cryptpilot::fs::fsck::fsck(
+            &volume_config.volume_path(),
+            volume_config.extra_config.makefs,
+        )
+        .await
+        {
+            tracing::info!("Closing volume {} now", volume_config.volume);
+            let _ = cryptpilot::fs::luks2::close(&volume_config.volume).await;
+            return Err(error);
+        }
+    }
+
+    match crate::hooks::run_post_open_hook(&volume_config, first_open).await {
+        Ok(()) => {
+            if first_open
+                && key_provider.volume_type() == cryptpilot::provider::VolumeType::Persistent
+            {
+                // The first_open_mount_options are applied, so they will not be applied on the subsequent opens
+                cryptpilot::fs::luks2::clear_fresh_fs_mark(Path::new(&volume_config.dev)).await?;
+            }
+        }
+        Err(error) => {
+            if volume_config.extra_config.post_open_abort_on_failure == Some(true) {
+                tracing::info!("Closing volume {} now", volume_config.volume);
+                let _ = cryptpilot::fs::luks2::close(&volume_config.volume).await;
+                return Err(error);
+            }
+            tracing::warn!(?error, "The post_open command failed, ignore it");
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that the file system on the opened volume is the one configured with `makefs`, so that a
+/// volume provisioned with another file system is caught before it is mounted. A mismatch is
+/// handled according to the `makefs_mismatch` policy of the volume.
+async fn check_makefs_mismatch(volume_config: &VolumeConfig) -> Result<()> {
+    let Some(makefs) = volume_config.extra_config.makefs else {
+        return Ok(());
+    };
+    let policy = volume_config
+        .extra_config
+        .makefs_mismatch
+        .unwrap_or_default();
+    if policy == FsMismatchPolicy::Ignore {
+        return Ok(());
+    }
+
+    let volume_path = volume_config.volume_path();
+    let found = match cryptpilot::fs::mkfs::detect_fs_mismatch(&volume_path, makefs).await {
+        Ok(found) => found,
+        Err(error) => {
+            return handle_fs_probe_error(
+                policy,
+                error.context(format!(
+                    "Failed to detect the file system on {volume_path:?}"
+                )),
+            )
+        }
+    };
+    let Some(found) = found else {
+        return Ok(());
+    };
+    match policy {
+        FsMismatchPolicy::Fail => bail!(
+            "The file system on volume {} is {found:?}, which does not match the configured makefs \"{makefs}\"",
+            volume_config.volume
+        ),
+        FsMismatchPolicy::Warn => tracing::warn!(
+            "The file system on volume {} is {found:?}, which does not match the configured makefs \"{makefs}\"",
+            volume_config.volume
+        ),
+        FsMismatchPolicy::Ignore => {}
+    }
     Ok(())
 }
 
+/// Handle a failure to probe the file system on the volume. Only the `fail` policy fails the open,
+/// since the file system cannot be checked, while the others skip the check with a warning.
+fn handle_fs_probe_error(policy: FsMismatchPolicy, error: anyhow::Error) -> Result<()> {
+    match policy {
+        FsMismatchPolicy::Fail => Err(error),
+        FsMismatchPolicy::Warn | FsMismatchPolicy::Ignore => {
+            tracing::warn!("Skip checking the file system against makefs: {error:#}");
+            Ok(())
+        }
+    }
+}
+
 async fn temporary_disk_open(
     volume_config: &VolumeConfig,
     key_provider: &impl KeyProvider,
@@ -83,14 +370,26 @@ async fn temporary_disk_open(
         .get_key()
         .await
         .context("Failed to get passphrase")?;
+    volume_config.validate_passphrase(&passphrase)?;
     tracing::info!("The temporary passphrase generated");
 
+    cryptpilot::fs::mkfs::check_overwrite_signature(
+        &volume_config.dev,
+        volume_config.extra_config.overwrite_signatures.as_deref(),
+    )
+    .await?;
+
     tracing::info!("Formatting {:?} as LUKS2 volume now", volume_config.dev);
-    let integrity = match volume_config.extra_config.integrity {
-        Some(true) => IntegrityType::NoJournal,
-        Some(false) | None => IntegrityType::None,
-    };
-    cryptpilot::fs::luks2::format(&volume_config.dev, &passphrase, integrity).await?;
+    let integrity = volume_config.integrity_type_on_open();
+    cryptpilot::fs::luks2::format_with_area_size(
+        &volume_config.dev,
+        &passphrase,
+        integrity,
+        volume_config.encrypt.cipher.unwrap_or_default(),
+        volume_config.extra_config.sector_size,
+        volume_config.luks2_area_size(),
+    )
+    .await?;
 
     tracing::info!("Setting up mapping for volume {} now", volume_config.volume);
     cryptpilot::fs::luks2::open_with_check_passphrase(
@@ -98,12 +397,19 @@ async fn temporary_disk_open(
         &volume_config.dev,
         &passphrase,
         integrity,
+        volume_config.extra_config.discard == Some(true),
     )
     .await?;
+    tune_integrity_journal_or_close(volume_config, integrity).await?;
 
     if let Some(makefs) = &volume_config.extra_config.makefs {
-        match cryptpilot::fs::mkfs::force_mkfs(&volume_config.volume_path(), makefs, integrity)
-            .await
+        match cryptpilot::fs::mkfs::force_mkfs(
+            &volume_config.volume_path(),
+            makefs,
+            volume_config.extra_config.fs_label.as_deref(),
+            integrity,
+        )
+        .await
         {
             Ok(_) => (),
             Err(e) => {
@@ -121,35 +427,115 @@ async fn temporary_disk_open(
     Ok(())
 }
 
+/// Check that the device is initialized by cryptpilot, or is a legacy LUKS1 volume, which can be
+/// opened until it is converted with `convert-to-luks2`.
+async fn check_openable(volume_config: &VolumeConfig) -> Result<()> {
+    match cryptpilot::fs::luks2::get_init_state(&volume_config.dev).await? {
+        VolumeInitState::Ready => Ok(()),
+        VolumeInitState::Luks1 => {
+            tracing::warn!(
+                "{:?} is a legacy LUKS1 volume, convert it to LUKS2 with `cryptpilot-crypt convert-to-luks2 {}`",
+                volume_config.dev,
+                volume_config.volume
+            );
+            Ok(())
+        }
+        VolumeInitState::None | VolumeInitState::Initializing => bail!(
+            "{:?} is not a valid LUKS2 volume, should be initialized before opening it",
+            volume_config.dev
+        ),
+    }
+}
+
 async fn persistent_disk_open(
     volume_config: &VolumeConfig,
     key_provider: &impl KeyProvider,
 ) -> Result<()> {
-    if !cryptpilot::fs::luks2::is_initialized(&volume_config.dev).await? {
-        bail!(
-            "{:?} is not a valid LUKS2 volume, should be initialized before opening it",
-            volume_config.dev
-        );
-    }
+    check_openable(volume_config).await?;
+
+    let integrity = volume_config.integrity_type_on_open();
+    check_integrity_matches(volume_config, integrity).await?;
 
     tracing::info!("Fetching passphrase for volume {}", volume_config.volume);
-    let passphrase = key_provider
-        .get_key()
+    let candidates = key_provider
+        .get_keys()
         .await
         .context("Failed to get passphrase")?;
+    for candidate in &candidates {
+        volume_config.validate_passphrase(candidate)?;
+    }
+    let passphrase =
+        cryptpilot::fs::luks2::select_passphrase(&volume_config.dev, candidates).await?;
 
     tracing::info!("Setting up mapping for volume {} now", volume_config.volume);
-    let integrity = match volume_config.extra_config.integrity {
-        Some(true) => IntegrityType::NoJournal,
-        Some(false) | None => IntegrityType::None,
-    };
     cryptpilot::fs::luks2::open_with_check_passphrase(
         &volume_config.volume,
         &volume_config.dev,
         &passphrase,
         integrity,
+        volume_config.extra_config.discard == Some(true),
     )
     .await?;
+    tune_integrity_journal_or_close(volume_config, integrity).await?;
 
     Ok(())
 }
+
+/// Apply the `integrity_tuning` to the opened volume, and close the volume if it fails.
+async fn tune_integrity_journal_or_close(
+    volume_config: &VolumeConfig,
+    integrity: IntegrityType,
+) -> Result<()> {
+    if let Err(error) = volume_config
+        .tune_integrity_journal(&volume_config.volume, integrity)
+        .await
+    {
+        tracing::info!("Closing volume {} now", volume_config.volume);
+        cryptpilot::fs::luks2::close(&volume_config.volume).await?;
+        return Err(error);
+    }
+    Ok(())
+}
+
+/// The integrity protection can only be chosen when the volume is formatted, so the volume cannot be
+/// opened if the integrity setting in the config has been changed since then.
+async fn check_integrity_matches(
+    volume_config: &VolumeConfig,
+    integrity: IntegrityType,
+) -> Result<()> {
+    let expected = !matches!(integrity, IntegrityType::None);
+    let actual = cryptpilot::fs::luks2::is_integrity_enabled(&volume_config.dev)
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to check the integrity setting of {:?}",
+                volume_config.dev
+            )
+        })?;
+    if expected != actual {
+        let state = |enabled: bool| if enabled { "enabled" } else { "disabled" };
+        bail!(
+            "The integrity of volume {} is {} in the config, but the device {:?} was formatted with integrity {}. The integrity cannot be toggled without reformatting the device: either restore the integrity setting in the config, or re-initialize the volume with `cryptpilot-crypt init --force-reinit`, which erases all the data on it",
+            volume_config.volume,
+            state(expected),
+            volume_config.dev,
+            state(actual)
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn test_handle_fs_probe_error() {
+        let error = || anyhow::anyhow!("blkid: probe failed");
+        assert!(handle_fs_probe_error(FsMismatchPolicy::Fail, error()).is_err());
+        assert!(handle_fs_probe_error(FsMismatchPolicy::Warn, error()).is_ok());
+        assert!(handle_fs_probe_error(FsMismatchPolicy::Ignore, error()).is_ok());
+    }
+}
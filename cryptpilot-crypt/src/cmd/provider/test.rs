@@ -0,0 +1,133 @@
+use std::time::{Duration, Instant};
+
+use anyhow::{Context as _, Result};
+use async_trait::async_trait;
+use cryptpilot::provider::{IntoProvider as _, KeyProvider, VolumeType};
+
+use crate::cli::ProviderTestOptions;
+
+pub struct ProviderTestCommand {
+    pub provider_test_options: ProviderTestOptions,
+}
+
+/// The result of fetching a passphrase from a key provider. The passphrase itself is never kept.
+#[derive(Debug)]
+pub struct ProviderTestReport {
+    pub provider: String,
+    pub volume_type: VolumeType,
+    pub key_length: usize,
+    pub elapsed: Duration,
+}
+
+#[async_trait]
+impl crate::cmd::Command for ProviderTestCommand {
+    async fn run(&self) -> Result<()> {
+        let volume = &self.provider_test_options.volume;
+        let volume_config = crate::config::get_volume_config_source()
+            .await
+            .get_volume_config(volume)
+            .await?;
+
+        let key_provider = volume_config.encrypt.clone().into_provider();
+        let report = test_key_provider(&key_provider)
+            .await
+            .with_context(|| format!("The key provider of volume {volume} failed"))?;
+
+        println!(
+            "The key provider {} of volume {volume} returned a passphrase of {} bytes in {:.3}s",
+            report.provider,
+            report.key_length,
+            report.elapsed.as_secs_f64()
+        );
+        if report.volume_type == VolumeType::Temporary {
+            println!("The passphrase is generated on every call, so the volume is re-formatted on every open");
+        }
+        Ok(())
+    }
+}
+
+/// Fetch a passphrase from the key provider for real, as when the volume is opened, and report how
+/// long it takes. The device of the volume is not touched.
+pub async fn test_key_provider(key_provider: &impl KeyProvider) -> Result<ProviderTestReport> {
+    tracing::info!("Fetching passphrase from {}", key_provider.debug_name());
+    let start = Instant::now();
+    let passphrase = key_provider
+        .get_key()
+        .await
+        .context("Failed to get passphrase")?;
+    let elapsed = start.elapsed();
+    passphrase.validate(false)?;
+
+    Ok(ProviderTestReport {
+        provider: key_provider.debug_name(),
+        volume_type: key_provider.volume_type(),
+        key_length: passphrase.as_bytes().len(),
+        elapsed,
+    })
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+    use anyhow::Result;
+    use cryptpilot::types::Passphrase;
+
+    struct MockKeyProvider {
+        passphrase: Option<&'static [u8]>,
+        delay: Duration,
+    }
+
+    #[async_trait]
+    impl KeyProvider for MockKeyProvider {
+        fn debug_name(&self) -> String {
+            "mock".to_owned()
+        }
+
+        async fn get_key(&self) -> Result<Passphrase> {
+            tokio::time::sleep(self.delay).await;
+            match self.passphrase {
+                Some(passphrase) => Ok(Passphrase::from(passphrase.to_vec())),
+                None => anyhow::bail!("The mock secret store is unreachable"),
+            }
+        }
+
+        fn volume_type(&self) -> VolumeType {
+            VolumeType::Persistent
+        }
+    }
+
+    #[tokio::test]
+    async fn test_test_key_provider() -> Result<()> {
+        let report = test_key_provider(&MockKeyProvider {
+            passphrase: Some(b"mock-secret-passphrase"),
+            delay: Duration::from_millis(50),
+        })
+        .await?;
+        assert_eq!(report.provider, "mock");
+        assert_eq!(report.volume_type, VolumeType::Persistent);
+        assert_eq!(report.key_length, "mock-secret-passphrase".len());
+        assert!(report.elapsed >= Duration::from_millis(50));
+        // The report never carries the passphrase
+        assert!(!format!("{report:?}").contains("mock-secret-passphrase"));
+
+        let error = test_key_provider(&MockKeyProvider {
+            passphrase: None,
+            delay: Duration::ZERO,
+        })
+        .await
+        .unwrap_err();
+        assert!(format!("{error:#}").contains("unreachable"));
+
+        // An empty passphrase is a misconfiguration
+        assert!(test_key_provider(&MockKeyProvider {
+            passphrase: Some(b""),
+            delay: Duration::ZERO,
+        })
+        .await
+        .is_err());
+
+        Ok(())
+    }
+}
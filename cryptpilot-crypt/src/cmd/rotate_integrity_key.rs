@@ -0,0 +1,178 @@
+use std::path::Path;
+
+use anyhow::{bail, Context as _, Result};
+use async_trait::async_trait;
+use cryptpilot::{
+    fs::luks2::IntegrityStatus,
+    provider::{IntoProvider as _, KeyProvider as _, VolumeType},
+};
+use serde::Serialize;
+
+use crate::{
+    audit::AuditOperation,
+    cli::{InitOptions, RotateIntegrityKeyOptions},
+    cmd::{init::InitCommand, Command as _},
+};
+
+pub struct RotateIntegrityKeyCommand {
+    pub rotate_integrity_key_options: RotateIntegrityKeyOptions,
+}
+
+#[derive(Serialize)]
+struct IntegrityKeyReport<'a> {
+    volume: &'a str,
+    integrity: Option<IntegrityStatus>,
+    /// Always false, since libcryptsetup cannot change the volume key of a volume with integrity.
+    online_rotation_supported: bool,
+}
+
+#[async_trait]
+impl super::Command for RotateIntegrityKeyCommand {
+    async fn run(&self) -> Result<()> {
+        let options = &self.rotate_integrity_key_options;
+        let volume = options.volume.as_str();
+
+        let volume_config = crate::config::get_volume_config_source()
+            .await
+            .get_volume_config(volume)
+            .await?;
+
+        let key_provider = volume_config.encrypt.clone().into_provider();
+        if key_provider.volume_type() == VolumeType::Temporary {
+            if options.reformat {
+                bail!("The volume {volume} is temporary, its volume key and integrity key are already regenerated on every open");
+            }
+            println!("The volume {volume} is temporary, its volume key and integrity key are regenerated on every open");
+            return Ok(());
+        }
+
+        let status = cryptpilot::fs::luks2::get_integrity_status(&volume_config.dev)
+            .await
+            .with_context(|| {
+                format!(
+                    "Failed to read the LUKS2 header of volume {volume} on {:?}, is it initialized?",
+                    volume_config.dev
+                )
+            })?;
+
+        if !options.reformat {
+            if options.json {
+                let report = IntegrityKeyReport {
+                    volume,
+                    integrity: status,
+                    online_rotation_supported: false,
+                };
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                print!("{}", format_status(volume, status.as_ref()));
+            }
+            return Ok(());
+        }
+
+        let Some(old_status) = status else {
+            bail!("The data integrity protection is not enabled on volume {volume}, there is no integrity key to rotate");
+        };
+        if !volume_config.extra_config.integrity.unwrap_or(false) {
+            bail!(
+                "The integrity of volume {volume} is disabled in the config, reformatting would remove the integrity protection instead of rotating its key. Enable `integrity` in the config first"
+            );
+        }
+
+        tracing::warn!(
+            "The integrity key of volume {volume} can only be rotated by reformatting, which erases all the data on it. Back up the data first and restore it after the rotation"
+        );
+        let result = reformat(volume, options.yes, &volume_config.dev, &old_status).await;
+        crate::audit::record(AuditOperation::Rekey, &volume_config, &result).await;
+        let new_status = result?;
+        tracing::info!("The integrity key of volume {volume} is rotated");
+        print!("{}", format_status(volume, Some(&new_status)));
+
+        Ok(())
+    }
+}
+
+/// Reformat the volume, and check that its volume key (and thus the integrity key) is changed.
+async fn reformat(
+    volume: &str,
+    yes: bool,
+    dev: &Path,
+    old_status: &IntegrityStatus,
+) -> Result<IntegrityStatus> {
+    InitCommand {
+        init_options: InitOptions {
+            volume: vec![volume.to_owned()],
+            force_reinit: true,
+            yes,
+            parallel_devices: None,
+            from_existing: false,
+            i_know_this_is_root: false,
+            key_from_stdin_per_volume: false,
+        },
+    }
+    .run()
+    .await
+    .with_context(|| format!("Failed to reformat volume {volume}"))?;
+
+    let new_status = cryptpilot::fs::luks2::get_integrity_status(dev)
+        .await?
+        .context("The data integrity protection is not enabled after reformatting")?;
+    if new_status.volume_key_digest == old_status.volume_key_digest {
+        bail!("The volume key of volume {volume} is unchanged after reformatting");
+    }
+    Ok(new_status)
+}
+
+fn format_status(volume: &str, status: Option<&IntegrityStatus>) -> String {
+    let Some(status) = status else {
+        return format!("Volume:                 {volume}\nIntegrity:              disabled\n");
+    };
+
+    let or_unknown = |size: Option<usize>| {
+        size.map(|size| format!("{size} bits"))
+            .unwrap_or_else(|| "unknown".to_owned())
+    };
+
+    let mut text = format!("Volume:                 {volume}\n");
+    text += &format!("Integrity:              {}\n", status.algorithm);
+    text += &format!(
+        "Integrity key size:     {}\n",
+        or_unknown(status.integrity_key_size)
+    );
+    text += &format!(
+        "Volume key size:        {}\n",
+        or_unknown(status.volume_key_size)
+    );
+    text += &format!(
+        "Volume key digest:      {}\n",
+        status.volume_key_digest.as_deref().unwrap_or("unknown")
+    );
+    text += "Online key rotation:    not supported, the integrity key is part of the volume key and is only changed by reformatting with `--reformat`, which erases all the data\n";
+    text
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn test_format_status() {
+        let text = format_status("data", None);
+        assert!(text.contains("disabled"));
+
+        let text = format_status(
+            "data",
+            Some(&IntegrityStatus {
+                algorithm: "hmac(sha256)".to_owned(),
+                volume_key_size: Some(768),
+                integrity_key_size: Some(256),
+                volume_key_digest: None,
+            }),
+        );
+        assert!(text.contains("hmac(sha256)"));
+        assert!(text.contains("256 bits"));
+        assert!(text.contains("768 bits"));
+        assert!(text.contains("not supported"));
+    }
+}
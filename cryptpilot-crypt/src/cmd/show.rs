@@ -1,4 +1,8 @@
-use std::path::{Path, PathBuf};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use anyhow::Result;
 use async_trait::async_trait;
@@ -13,7 +17,7 @@ use cryptpilot::provider::{IntoProvider, KeyProvider as _, VolumeType};
 use crate::config::VolumeConfig;
 
 /// Unified volume status enumeration
-#[derive(Serialize, Clone, Debug, PartialEq)]
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
 pub enum VolumeStatusKind {
     /// Device does not exist physically
     DeviceNotFound,
@@ -57,7 +61,13 @@ impl crate::cmd::Command for ShowCommand {
             volume_configs.retain(|config| self.show_options.volume.contains(&config.volume));
         }
 
-        if self.show_options.json {
+        if self.show_options.watch {
+            watch(
+                &volume_configs,
+                Duration::from_secs(self.show_options.interval),
+            )
+            .await?;
+        } else if self.show_options.json {
             volume_configs.print_as_json().await?;
         } else {
             volume_configs.print_as_table().await?;
@@ -67,6 +77,113 @@ impl crate::cmd::Command for ShowCommand {
     }
 }
 
+/// Re-render the state of the volumes every `interval` until interrupted, highlighting the
+/// volumes whose status changed since the last rendering.
+async fn watch(volume_configs: &[VolumeConfig], interval: Duration) -> Result<()> {
+    let mut previous: Option<Vec<ShowVolume>> = None;
+    loop {
+        let current = snapshot(volume_configs).await;
+        let changes = previous
+            .as_deref()
+            .map(|previous| status_changes(previous, &current))
+            .unwrap_or_default();
+
+        // Clear the screen and move the cursor to the top left corner before redrawing
+        print!("\x1b[2J\x1b[H");
+        println!(
+            "Every {}s: cryptpilot-crypt show (press Ctrl-C to quit)\n",
+            interval.as_secs()
+        );
+        println!("{}", render_state_table(&current, &changes));
+        previous = Some(current);
+
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+        }
+    }
+}
+
+/// Gather the state of the volumes once, the same data printed by `show --json`.
+pub async fn snapshot(volume_configs: &[VolumeConfig]) -> Vec<ShowVolume> {
+    let mut volumes = Vec::new();
+    for volume_config in volume_configs {
+        volumes.push(ShowVolume::from_config(volume_config).await);
+    }
+    volumes
+}
+
+/// The previous status of each volume whose status differs between the two snapshots. Volumes
+/// missing from the previous snapshot are not reported.
+fn status_changes(
+    previous: &[ShowVolume],
+    current: &[ShowVolume],
+) -> HashMap<String, VolumeStatusKind> {
+    current
+        .iter()
+        .filter_map(|volume| {
+            let old = previous.iter().find(|old| old.volume == volume.volume)?;
+            (old.status.kind != volume.status.kind)
+                .then(|| (volume.volume.clone(), old.status.kind.clone()))
+        })
+        .collect()
+}
+
+fn status_color(kind: &VolumeStatusKind) -> Color {
+    match kind {
+        VolumeStatusKind::Opened => Color::Green,
+        VolumeStatusKind::ReadyToOpen => Color::Green,
+        VolumeStatusKind::RequiresInit => Color::Yellow,
+        VolumeStatusKind::Initializing => Color::Yellow,
+        VolumeStatusKind::CheckFailed => Color::Red,
+        VolumeStatusKind::DeviceNotFound => Color::Red,
+    }
+}
+
+/// Render the state of the volumes for `show --watch`, with the changed status shown as
+/// `<old> -> <new>` in bold.
+fn render_state_table(
+    volumes: &[ShowVolume],
+    changes: &HashMap<String, VolumeStatusKind>,
+) -> Table {
+    let mut table = Table::new();
+    table
+        .load_preset(UTF8_FULL)
+        .apply_modifier(UTF8_ROUND_CORNERS)
+        .set_content_arrangement(ContentArrangement::Dynamic)
+        .set_header(vec![
+            "Volume",
+            "Volume Path",
+            "Underlay Device",
+            "Key Provider",
+            "Status",
+        ]);
+
+    for volume in volumes {
+        let status = match changes.get(&volume.volume) {
+            Some(old) => Cell::new(format!("{old:?} -> {:?}", volume.status.kind))
+                .add_attribute(Attribute::Bold),
+            None => Cell::new(format!("{:?}", volume.status.kind)),
+        }
+        .fg(status_color(&volume.status.kind));
+
+        table.add_row(vec![
+            Cell::new(&volume.volume),
+            match volume.status.kind {
+                VolumeStatusKind::Opened => {
+                    Cell::new(volume.volume_path.to_string_lossy().as_ref()).fg(Color::Green)
+                }
+                _ => Cell::new("<not opened>").fg(Color::Yellow),
+            },
+            Cell::new(volume.underlay_device.to_string_lossy().as_ref()),
+            Cell::new(&volume.key_provider),
+            status,
+        ]);
+    }
+
+    table
+}
+
 #[async_trait]
 pub trait PrintAsTable {
     async fn print_as_table(&self) -> Result<()>;
@@ -77,15 +194,24 @@ pub trait PrintAsJson {
     async fn print_as_json(&self) -> Result<()>;
 }
 
-/// Individual volume status information
+/// Individual volume status information. The options of the key provider are never included, as
+/// they may contain secrets, e.g. the client key of KMS.
 #[derive(Serialize)]
 pub struct ShowVolume {
     volume: String,
     volume_path: PathBuf,
     underlay_device: PathBuf,
     key_provider: String,
-    key_provider_options: serde_json::Value,
     extra_options: serde_json::Value,
+    /// Whether the volume is opened, i.e. its device mapper mapping exists
+    active: bool,
+    /// Whether data integrity protection is enabled for the volume
+    integrity: bool,
+    /// Whether the underlay device is initialized as a LUKS2 volume by cryptpilot
+    initialized: bool,
+    /// The device mapper UUID of the volume, only if it is opened
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dm_uuid: Option<String>,
     /// Unified status representation (flattened)
     #[serde(flatten)]
     status: VolumeStatus,
@@ -96,28 +222,47 @@ impl ShowVolume {
     async fn from_config(volume_config: &VolumeConfig) -> Self {
         let volume_path = volume_config.volume_path();
 
-        let key_provider = serde_variant::to_variant_name(&volume_config.encrypt.key_provider)
-            .unwrap_or("unknown")
-            .to_string();
-
-        let key_provider_options = serde_json::to_value(&volume_config.encrypt.key_provider)
-            .unwrap_or(serde_json::Value::Null);
+        let key_provider = volume_config.encrypt.key_provider.type_name().to_owned();
 
         let extra_options = match serde_json::to_value(&volume_config.extra_config) {
             Ok(v) => v,
             Err(_) => serde_json::Value::Null,
         };
 
+        let active = cryptpilot::fs::luks2::is_active(&volume_config.volume);
+        let integrity = volume_config.extra_config.integrity.unwrap_or(false);
+        let initialized = cryptpilot::fs::luks2::is_initialized(&volume_config.dev)
+            .await
+            .unwrap_or_else(|error| {
+                tracing::debug!(?error, "Failed to check if the volume is initialized");
+                false
+            });
+
         // Determine unified status using VolumeConfig method
         let status = volume_config.determine_status().await;
 
+        let dm_uuid = if status.kind == VolumeStatusKind::Opened {
+            cryptpilot::fs::block::devicemapper::get_dm_uuid(
+                &cryptpilot::fs::block::devicemapper::dm_name(&volume_config.volume),
+            )
+            .unwrap_or_else(|error| {
+                tracing::debug!(?error, "Failed to get device mapper UUID, ignore it");
+                None
+            })
+        } else {
+            None
+        };
+
         Self {
             volume: volume_config.volume.clone(),
             volume_path,
             underlay_device: volume_config.dev.clone(),
             key_provider,
-            key_provider_options,
             extra_options,
+            active,
+            integrity,
+            initialized,
+            dm_uuid,
             status,
         }
     }
@@ -151,14 +296,7 @@ impl PrintAsTable for [VolumeConfig] {
             let show_volume = ShowVolume::from_config(volume_config).await;
 
             // Determine color based on status code
-            let status_color = match show_volume.status.kind {
-                VolumeStatusKind::Opened => Color::Green,
-                VolumeStatusKind::ReadyToOpen => Color::Green,
-                VolumeStatusKind::RequiresInit => Color::Yellow,
-                VolumeStatusKind::Initializing => Color::Yellow,
-                VolumeStatusKind::CheckFailed => Color::Red,
-                VolumeStatusKind::DeviceNotFound => Color::Red,
-            };
+            let status_color = status_color(&show_volume.status.kind);
 
             table.add_row(vec![
                 Cell::new(&show_volume.volume),
@@ -209,11 +347,7 @@ impl PrintAsJson for VolumeConfig {
 #[async_trait]
 impl PrintAsJson for [VolumeConfig] {
     async fn print_as_json(&self) -> Result<()> {
-        let mut volumes = Vec::new();
-
-        for volume_config in self {
-            volumes.push(ShowVolume::from_config(volume_config).await);
-        }
+        let volumes = snapshot(self).await;
 
         let json = serde_json::to_string_pretty(&volumes)?;
         println!("{}", json);
@@ -249,7 +383,7 @@ impl VolumeConfig {
         }
 
         // Check volume type
-        let key_provider = self.encrypt.key_provider.clone().into_provider();
+        let key_provider = self.encrypt.clone().into_provider();
         let is_persistent = matches!(key_provider.volume_type(), VolumeType::Persistent);
 
         // For temporary volumes, they are ready to open without initialization
@@ -259,7 +393,7 @@ impl VolumeConfig {
                 description: format!(
                     "Volume '{}' uses {} key provider (temporary volume) and is ready to open",
                     self.volume,
-                    serde_variant::to_variant_name(&self.encrypt.key_provider).unwrap_or("unknown")
+                    self.encrypt.key_provider.type_name()
                 ),
             };
         }
@@ -280,6 +414,13 @@ impl VolumeConfig {
                     self.dev
                 ),
             },
+            Ok(cryptpilot::fs::luks2::VolumeInitState::Luks1) => VolumeStatus {
+                kind: VolumeStatusKind::ReadyToOpen,
+                description: format!(
+                    "\u{26a0} Device '{:?}' is a legacy LUKS1 volume and ready to open, convert it with `convert-to-luks2`",
+                    self.dev
+                ),
+            },
             Ok(cryptpilot::fs::luks2::VolumeInitState::None) => VolumeStatus {
                 kind: VolumeStatusKind::RequiresInit,
                 description: format!(
@@ -300,3 +441,105 @@ impl VolumeConfig {
         }
     }
 }
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[tokio::test]
+    async fn test_snapshot() -> Result<()> {
+        let dev = tempfile::NamedTempFile::new()?;
+        let volume_configs: Vec<VolumeConfig> = vec![
+            toml::from_str(&format!(
+                r#"
+                volume = "watch-swap"
+                dev = "{}"
+
+                [encrypt.otp]
+                "#,
+                dev.path().display()
+            ))?,
+            toml::from_str(
+                r#"
+                volume = "watch-missing"
+                dev = "/dev/nonexist-cryptpilot-watch"
+
+                [encrypt.otp]
+                "#,
+            )?,
+        ];
+
+        let previous = snapshot(&volume_configs).await;
+        assert_eq!(previous.len(), 2);
+        assert_eq!(previous[0].volume, "watch-swap");
+        assert_eq!(previous[0].status.kind, VolumeStatusKind::ReadyToOpen);
+        assert_eq!(previous[1].status.kind, VolumeStatusKind::DeviceNotFound);
+
+        // Nothing changed
+        let current = snapshot(&volume_configs).await;
+        assert!(status_changes(&previous, &current).is_empty());
+
+        // The volume is opened in between
+        let mut current = current;
+        current[0].status.kind = VolumeStatusKind::Opened;
+        let changes = status_changes(&previous, &current);
+        assert_eq!(
+            changes,
+            HashMap::from([("watch-swap".to_owned(), VolumeStatusKind::ReadyToOpen)])
+        );
+        let rendered = render_state_table(&current, &changes).to_string();
+        assert!(rendered.contains("ReadyToOpen -> Opened"));
+        assert!(rendered.contains("DeviceNotFound"));
+        assert!(!rendered.contains("DeviceNotFound ->"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_json_hides_secrets() -> Result<()> {
+        let dev = tempfile::NamedTempFile::new()?;
+        let volume_configs: Vec<VolumeConfig> = vec![
+            toml::from_str(&format!(
+                r#"
+                volume = "json-exec"
+                dev = "{}"
+                integrity = true
+
+                [encrypt.exec]
+                command = "echo"
+                args = ["-n", "SECRET-PASSPHRASE"]
+                "#,
+                dev.path().display()
+            ))?,
+            toml::from_str(
+                r#"
+                volume = "json-kms"
+                dev = "/dev/nonexist-cryptpilot-json"
+
+                [encrypt.kms]
+                kms_instance_id = "kst-bjj66bdba95w1m0xfm3bt"
+                secret_name = "data_passphrase"
+                client_key = '{"KeyId":"KAAP.SECRET-KEY-ID","PrivateKeyData":"SECRET-KEY-DATA"}'
+                client_key_password = "SECRET-KEY-PASSWORD"
+                kms_cert_pem = "SECRET-KMS-CERT"
+                "#,
+            )?,
+        ];
+
+        let json = serde_json::to_value(snapshot(&volume_configs).await)?;
+        assert!(!json.to_string().contains("SECRET"), "{json}");
+
+        assert_eq!(json[0]["volume"], "json-exec");
+        assert_eq!(json[0]["key_provider"], "exec");
+        assert_eq!(json[0]["active"], false);
+        assert_eq!(json[0]["integrity"], true);
+        assert_eq!(json[0]["initialized"], false);
+        assert_eq!(json[1]["key_provider"], "kms");
+        assert_eq!(json[1]["integrity"], false);
+        assert_eq!(json[1]["status"], "DeviceNotFound");
+
+        Ok(())
+    }
+}
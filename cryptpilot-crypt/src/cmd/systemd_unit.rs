@@ -0,0 +1,167 @@
+use anyhow::{Context as _, Result};
+use async_trait::async_trait;
+use cryptpilot::fs::cmd::CheckCommandOutput as _;
+use tokio::process::Command;
+
+use crate::cli::SystemdUnitOptions;
+
+pub const OPEN_UNIT_TEMPLATE_NAME: &str = "cryptpilot-open@.service";
+pub const CLOSE_UNIT_TEMPLATE_NAME: &str = "cryptpilot-close@.service";
+
+const CRYPTPILOT_CRYPT_BIN: &str = "/usr/bin/cryptpilot-crypt";
+
+pub struct SystemdUnitCommand {
+    pub systemd_unit_options: SystemdUnitOptions,
+}
+
+#[async_trait]
+impl crate::cmd::Command for SystemdUnitCommand {
+    async fn run(&self) -> Result<()> {
+        // Make sure the volumes exist before printing the units for them.
+        let mut volume_configs = vec![];
+        for volume in &self.systemd_unit_options.volume {
+            volume_configs.push(
+                crate::config::get_volume_config_source()
+                    .await
+                    .get_volume_config(volume)
+                    .await?,
+            );
+        }
+
+        let units = [
+            (OPEN_UNIT_TEMPLATE_NAME, open_unit_template()),
+            (CLOSE_UNIT_TEMPLATE_NAME, close_unit_template()),
+        ];
+        match &self.systemd_unit_options.output_dir {
+            Some(output_dir) => {
+                for (name, content) in &units {
+                    let path = output_dir.join(name);
+                    tokio::fs::write(&path, content)
+                        .await
+                        .with_context(|| format!("Failed to write systemd unit to {path:?}"))?;
+                    tracing::info!("The systemd unit {name} is written to {path:?}");
+                }
+                tracing::info!("Run `systemctl daemon-reload` to load the systemd units");
+            }
+            None => {
+                for (name, content) in &units {
+                    println!("# {name}\n{content}");
+                }
+            }
+        }
+
+        for volume_config in &volume_configs {
+            let volume = &volume_config.volume;
+            if volume_config.extra_config.auto_open == Some(true) {
+                tracing::warn!("The volume {volume} is configured with `auto_open = true`, it is also opened by cryptpilot.service during boot");
+            }
+            let open_unit = instance_unit_name(OPEN_UNIT_TEMPLATE_NAME, volume).await?;
+            let close_unit = instance_unit_name(CLOSE_UNIT_TEMPLATE_NAME, volume).await?;
+            tracing::info!(
+                "Run `systemctl start {open_unit}` to open volume {volume}, and `systemctl start {close_unit}` to close it"
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// The template unit which opens the volume named by the instance name, and closes it when the
+/// unit is stopped.
+fn open_unit_template() -> String {
+    format!(
+        r#"[Unit]
+Description=Cryptpilot Open Volume %I
+Requires=network-online.target
+After=network-online.target
+Wants=attestation-agent.service
+After=attestation-agent.service
+After=cryptpilot.service
+Conflicts={close_unit}
+
+[Service]
+Type=oneshot
+RemainAfterExit=true
+ExecStart={bin} open %I
+ExecStop={bin} close %I
+StandardOutput=journal+console
+StandardError=journal+console
+
+[Install]
+WantedBy=multi-user.target
+"#,
+        bin = CRYPTPILOT_CRYPT_BIN,
+        close_unit = CLOSE_UNIT_TEMPLATE_NAME.replace("@.", "@%i."),
+    )
+}
+
+/// The template unit which closes the volume named by the instance name. Starting it stops the
+/// matching open unit.
+fn close_unit_template() -> String {
+    format!(
+        r#"[Unit]
+Description=Cryptpilot Close Volume %I
+Conflicts={open_unit}
+After={open_unit}
+
+[Service]
+Type=oneshot
+ExecStart={bin} close %I
+StandardOutput=journal+console
+StandardError=journal+console
+"#,
+        bin = CRYPTPILOT_CRYPT_BIN,
+        open_unit = OPEN_UNIT_TEMPLATE_NAME.replace("@.", "@%i."),
+    )
+}
+
+/// Get the name of the unit instantiated from the template for the volume, with the volume name
+/// escaped by `systemd-escape`.
+async fn instance_unit_name(template: &str, volume: &str) -> Result<String> {
+    let output = Command::new("systemd-escape")
+        .arg(format!("--template={template}"))
+        .arg("--")
+        .arg(volume)
+        .run()
+        .await
+        .with_context(|| format!("Failed to escape volume name {volume:?} for systemd"))?;
+    Ok(String::from_utf8_lossy(&output).trim().to_owned())
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+    use crate::cmd::Command as _;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn test_write_unit_templates() -> Result<()> {
+        let output_dir =
+            std::env::temp_dir().join(format!("cryptpilot-systemd-{}", rand::random::<u64>()));
+        tokio::fs::create_dir_all(&output_dir).await?;
+
+        SystemdUnitCommand {
+            systemd_unit_options: SystemdUnitOptions {
+                volume: vec![],
+                output_dir: Some(output_dir.clone()),
+            },
+        }
+        .run()
+        .await?;
+
+        let open_unit = tokio::fs::read_to_string(output_dir.join(OPEN_UNIT_TEMPLATE_NAME)).await?;
+        assert!(open_unit.contains("ExecStart=/usr/bin/cryptpilot-crypt open %I\n"));
+        assert!(open_unit.contains("ExecStop=/usr/bin/cryptpilot-crypt close %I\n"));
+        assert!(open_unit.contains("Conflicts=cryptpilot-close@%i.service\n"));
+
+        let close_unit =
+            tokio::fs::read_to_string(output_dir.join(CLOSE_UNIT_TEMPLATE_NAME)).await?;
+        assert!(close_unit.contains("ExecStart=/usr/bin/cryptpilot-crypt close %I\n"));
+        assert!(close_unit.contains("Conflicts=cryptpilot-open@%i.service\n"));
+
+        tokio::fs::remove_dir_all(&output_dir).await?;
+        Ok(())
+    }
+}
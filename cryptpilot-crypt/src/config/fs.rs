@@ -1,22 +1,30 @@
 use anyhow::{bail, Context as _, Result};
 use async_trait::async_trait;
-use std::collections::HashSet;
-use std::path::PathBuf;
+use cryptpilot::config::envelope;
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
 
-use crate::config::VolumeConfig;
+use crate::config::{sort_by_open_order, VolumeConfig};
 
 use super::VolumeConfigSource;
 
 pub const CRYPTPILOT_CONFIG_DIR_DEFAULT: &str = "/etc/cryptpilot";
 
 pub struct FileSystemConfigSource {
-    config_dir: PathBuf,
+    /// The config directories, in increasing order of precedence.
+    config_dirs: Vec<PathBuf>,
 }
 
 impl FileSystemConfigSource {
     pub fn new(config_dir: impl Into<PathBuf>) -> Self {
+        Self::new_with_config_dirs([config_dir])
+    }
+
+    /// Create a config source reading from multiple config directories. A volume defined in a
+    /// later directory overrides the volume with the same name defined in an earlier directory.
+    pub fn new_with_config_dirs(config_dirs: impl IntoIterator<Item = impl Into<PathBuf>>) -> Self {
         Self {
-            config_dir: config_dir.into(),
+            config_dirs: config_dirs.into_iter().map(Into::into).collect(),
         }
     }
 
@@ -25,8 +33,29 @@ impl FileSystemConfigSource {
     }
 
     async fn load_volume_configs(&self) -> Result<Vec<VolumeConfig>> {
+        let mut volume_configs = BTreeMap::<String, VolumeConfig>::new();
+
+        for config_dir in &self.config_dirs {
+            for volume_config in Self::load_volume_configs_from_dir(config_dir).await? {
+                if volume_configs.contains_key(&volume_config.volume) {
+                    tracing::info!(
+                        "Volume `{}` is overridden by config dir: {config_dir:?}",
+                        volume_config.volume
+                    );
+                }
+                volume_configs.insert(volume_config.volume.to_owned(), volume_config);
+            }
+        }
+
+        // The volumes are sorted by name first, which breaks the ties of the configured order
+        let mut volume_configs: Vec<_> = volume_configs.into_values().collect();
+        sort_by_open_order(&mut volume_configs);
+        Ok(volume_configs)
+    }
+
+    async fn load_volume_configs_from_dir(config_dir: &Path) -> Result<Vec<VolumeConfig>> {
         let mut volume_configs = Vec::new();
-        let config_dir = self.config_dir.join("volumes");
+        let config_dir = config_dir.join("volumes");
 
         tracing::debug!("Loading volume configs from: {config_dir:?}");
         if !config_dir.exists() {
@@ -41,9 +70,8 @@ impl FileSystemConfigSource {
             let path = entry.path();
 
             if path.is_file() && path.extension().is_some_and(|ext| ext == "toml") {
-                let volume_config = tokio::fs::read_to_string(&path)
+                let volume_config = Self::read_volume_config_file(&path)
                     .await
-                    .map_err(Into::into)
                     .and_then(|content| {
                         toml::from_str::<VolumeConfig>(&content)
                             .context("Failed to parse content as TOML")
@@ -64,19 +92,244 @@ impl FileSystemConfigSource {
             }
         }
 
-        volume_configs.sort_by(|a, b| a.volume.cmp(&b.volume));
-
         Ok(volume_configs)
     }
+
+    /// Read the content of a volume config file, decrypting it first if it is stored encrypted,
+    /// see [`cryptpilot::config::envelope`].
+    async fn read_volume_config_file(path: &Path) -> Result<String> {
+        let content = tokio::fs::read_to_string(path).await?;
+        if !envelope::is_encrypted_config(&content) {
+            return Ok(content);
+        }
+        tracing::debug!("Decrypting encrypted volume config file: {path:?}");
+        envelope::decrypt_config(&content).await
+    }
 }
 
 #[async_trait]
 impl VolumeConfigSource for FileSystemConfigSource {
     fn source_debug_string(&self) -> String {
-        format!("filesystem(volume): {:?}", self.config_dir)
+        format!("filesystem(volume): {:?}", self.config_dirs)
     }
 
     async fn get_volume_configs(&self) -> Result<Vec<VolumeConfig>> {
         self.load_volume_configs().await
     }
 }
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+    use anyhow::Result;
+
+    async fn write_volume_config(config_dir: &Path, file_name: &str, content: &str) -> Result<()> {
+        let volumes_dir = config_dir.join("volumes");
+        tokio::fs::create_dir_all(&volumes_dir).await?;
+        tokio::fs::write(volumes_dir.join(file_name), content).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_load_volume_configs_with_precedence() -> Result<()> {
+        let root =
+            std::env::temp_dir().join(format!("cryptpilot-config-dirs-{}", rand::random::<u64>()));
+        let vendor_dir = root.join("vendor");
+        let operator_dir = root.join("operator");
+
+        write_volume_config(
+            &vendor_dir,
+            "data0.toml",
+            r#"
+            volume = "data0"
+            dev = "/dev/nvme1n1p1"
+
+            [encrypt.otp]
+            "#,
+        )
+        .await?;
+        write_volume_config(
+            &vendor_dir,
+            "data1.toml",
+            r#"
+            volume = "data1"
+            dev = "/dev/nvme1n1p2"
+
+            [encrypt.otp]
+            "#,
+        )
+        .await?;
+        // The operator config file has a different name but defines the same volume.
+        write_volume_config(
+            &operator_dir,
+            "override.toml",
+            r#"
+            volume = "data1"
+            dev = "/dev/nvme2n1p1"
+
+            [encrypt.otp]
+            "#,
+        )
+        .await?;
+
+        let volume_configs =
+            FileSystemConfigSource::new_with_config_dirs([&vendor_dir, &operator_dir])
+                .get_volume_configs()
+                .await?;
+        assert_eq!(
+            volume_configs
+                .iter()
+                .map(|c| format!("{}:{}", c.volume, c.dev.display()))
+                .collect::<Vec<_>>(),
+            vec!["data0:/dev/nvme1n1p1", "data1:/dev/nvme2n1p1"]
+        );
+
+        // Reversing the order makes the vendor config take precedence.
+        let volume_configs =
+            FileSystemConfigSource::new_with_config_dirs([&operator_dir, &vendor_dir])
+                .get_volume_configs()
+                .await?;
+        assert_eq!(
+            volume_configs
+                .iter()
+                .map(|c| format!("{}:{}", c.volume, c.dev.display()))
+                .collect::<Vec<_>>(),
+            vec!["data0:/dev/nvme1n1p1", "data1:/dev/nvme1n1p2"]
+        );
+
+        // Duplicated volumes within a single config dir are still rejected.
+        write_volume_config(
+            &operator_dir,
+            "duplicated.toml",
+            r#"
+            volume = "data1"
+            dev = "/dev/nvme2n1p2"
+
+            [encrypt.otp]
+            "#,
+        )
+        .await?;
+        assert!(
+            FileSystemConfigSource::new_with_config_dirs([&vendor_dir, &operator_dir])
+                .get_volume_configs()
+                .await
+                .is_err()
+        );
+
+        tokio::fs::remove_dir_all(&root).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_load_volume_configs_with_order() -> Result<()> {
+        let config_dir = tempfile::tempdir()?;
+        for (volume, order) in [
+            ("data0", None),
+            ("data1", Some(-1)),
+            ("data2", Some(10)),
+            ("data3", Some(-1)),
+            ("keys", Some(-100)),
+        ] {
+            let order = order
+                .map(|order: i32| format!("order = {order}"))
+                .unwrap_or_default();
+            write_volume_config(
+                config_dir.path(),
+                &format!("{volume}.toml"),
+                &format!(
+                    r#"
+                    volume = "{volume}"
+                    dev = "/dev/mapper/{volume}-dev"
+                    {order}
+
+                    [encrypt.otp]
+                    "#
+                ),
+            )
+            .await?;
+        }
+
+        // The configured order overrides the alphabetical one, which breaks the ties
+        let volume_configs = FileSystemConfigSource::new(config_dir.path())
+            .get_volume_configs()
+            .await?;
+        assert_eq!(
+            volume_configs
+                .iter()
+                .map(|c| c.volume.as_str())
+                .collect::<Vec<_>>(),
+            vec!["keys", "data1", "data3", "data0", "data2"]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_load_encrypted_volume_config() -> Result<()> {
+        let config_dir = tempfile::tempdir()?;
+        let plaintext = r#"
+            volume = "data0"
+            dev = "/dev/nvme1n1p1"
+
+            [encrypt.exec]
+            command = "echo"
+            args = ["-n", "volume-secret"]
+            "#;
+        let encrypted = envelope::encrypt_config(
+            plaintext,
+            toml::from_str(
+                r#"
+                [exec]
+                command = "echo"
+                args = ["-n", "bootstrap-secret"]
+                "#,
+            )?,
+        )
+        .await?;
+        assert!(!encrypted.contains("volume-secret"));
+        write_volume_config(config_dir.path(), "data0.toml", &encrypted).await?;
+        write_volume_config(
+            config_dir.path(),
+            "data1.toml",
+            r#"
+            volume = "data1"
+            dev = "/dev/nvme1n1p2"
+
+            [encrypt.otp]
+            "#,
+        )
+        .await?;
+
+        // The encrypted and the plaintext config files are loaded side by side
+        let volume_configs = FileSystemConfigSource::new(config_dir.path())
+            .get_volume_configs()
+            .await?;
+        assert_eq!(
+            volume_configs
+                .iter()
+                .map(|c| format!("{}:{}", c.volume, c.dev.display()))
+                .collect::<Vec<_>>(),
+            vec!["data0:/dev/nvme1n1p1", "data1:/dev/nvme1n1p2"]
+        );
+        assert_eq!(
+            volume_configs[0],
+            toml::from_str::<VolumeConfig>(plaintext)?
+        );
+
+        // A corrupted envelope fails the loading instead of being skipped
+        write_volume_config(
+            config_dir.path(),
+            "data0.toml",
+            &encrypted.replace("ciphertext = \"", "ciphertext = \"AAAA"),
+        )
+        .await?;
+        assert!(FileSystemConfigSource::new(config_dir.path())
+            .get_volume_configs()
+            .await
+            .is_err());
+
+        Ok(())
+    }
+}
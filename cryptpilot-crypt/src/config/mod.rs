@@ -1,5 +1,6 @@
 pub mod cached;
 pub mod fs;
+pub mod stdin;
 pub mod volume;
 
 // Alias for backward compatibility with tests
@@ -60,3 +61,24 @@ pub async fn get_volume_config_source(
 ) -> RwLockReadGuard<'static, Box<dyn VolumeConfigSource + Send + Sync>> {
     CRYPTPILOT_VOLUME_CONFIG_SOURCE.read().await
 }
+
+/// Sort the volume configs by the `order` of the volumes, which defaults to 0, so that the volumes
+/// with lower values are opened first. The sort is stable, so the volumes with the same order keep
+/// their relative order, e.g. by name.
+pub fn sort_by_open_order(volume_configs: &mut [VolumeConfig]) {
+    volume_configs.sort_by_key(|volume_config| volume_config.extra_config.order.unwrap_or(0));
+}
+
+/// Generate the content to be hashed for measuring the loaded volume configs. The volume configs are
+/// sorted by volume name, so that the result does not depend on the order in which they are loaded.
+pub fn gen_volume_configs_hash_content(volume_configs: &[VolumeConfig]) -> Result<String> {
+    #[derive(serde::Serialize)]
+    struct VolumeConfigBundle<'a> {
+        volume: Vec<&'a VolumeConfig>,
+    }
+
+    let mut volume: Vec<_> = volume_configs.iter().collect();
+    volume.sort_by(|a, b| a.volume.cmp(&b.volume));
+
+    Ok(toml::to_string(&VolumeConfigBundle { volume })?)
+}
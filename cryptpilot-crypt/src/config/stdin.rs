@@ -0,0 +1,142 @@
+use std::{collections::HashSet, io::IsTerminal as _};
+
+use anyhow::{bail, Context as _, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{sort_by_open_order, VolumeConfig};
+
+use super::VolumeConfigSource;
+
+/// A bundle of volume configs in a single TOML document, with each volume in a `[[volume]]` table.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct VolumeConfigBundle {
+    pub volume: Vec<VolumeConfig>,
+}
+
+impl VolumeConfigBundle {
+    pub fn parse(content: &str) -> Result<Self> {
+        let bundle: VolumeConfigBundle =
+            toml::from_str(content).context("Failed to parse volume config bundle as TOML")?;
+
+        let mut volume_names = HashSet::new();
+        for volume_config in &bundle.volume {
+            if !volume_names.insert(&volume_config.volume) {
+                bail!(
+                    "Volume `{}` is defined more than once in the volume config bundle",
+                    volume_config.volume
+                );
+            }
+        }
+
+        Ok(bundle)
+    }
+}
+
+/// This is a config source that holds the volume config bundle read from stdin, so that the configs
+/// generated on the fly can be piped in without writing them to the file system.
+pub struct StdinConfigSource {
+    volumes: Vec<VolumeConfig>,
+}
+
+impl StdinConfigSource {
+    /// Read the whole volume config bundle from stdin until EOF.
+    pub async fn read_from_stdin() -> Result<Self> {
+        if std::io::stdin().is_terminal() {
+            bail!("The volume config bundle is expected to be piped to stdin, but stdin is a terminal");
+        }
+
+        let content = tokio::task::spawn_blocking(|| std::io::read_to_string(std::io::stdin()))
+            .await?
+            .context("Failed to read volume config bundle from stdin")?;
+
+        let mut bundle = VolumeConfigBundle::parse(&content)?;
+        tracing::debug!("Loaded {} volume config(s) from stdin", bundle.volume.len());
+        // The volumes with the same order keep the order in the bundle
+        sort_by_open_order(&mut bundle.volume);
+        Ok(Self {
+            volumes: bundle.volume,
+        })
+    }
+}
+
+#[async_trait]
+impl VolumeConfigSource for StdinConfigSource {
+    fn source_debug_string(&self) -> String {
+        "volume config bundle from stdin".into()
+    }
+
+    async fn get_volume_configs(&self) -> Result<Vec<VolumeConfig>> {
+        Ok(self.volumes.clone())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+    use anyhow::Result;
+
+    #[test]
+    fn test_parse_volume_config_bundle() -> Result<()> {
+        let bundle = VolumeConfigBundle::parse(
+            r#"
+            [[volume]]
+            volume = "data0"
+            dev = "/dev/nvme1n1p1"
+            makefs = "ext4"
+
+            [volume.encrypt.otp]
+
+            [[volume]]
+            volume = "data1"
+            dev = "/dev/nvme1n1p2"
+
+            [volume.encrypt.exec]
+            command = "echo"
+            args = ["-n", "test-passphrase"]
+            "#,
+        )?;
+        assert_eq!(bundle.volume.len(), 2);
+        assert_eq!(bundle.volume[0].volume, "data0");
+        assert_eq!(bundle.volume[1].volume, "data1");
+
+        // Malformed TOML
+        assert!(VolumeConfigBundle::parse("[[volume]\nvolume = \"data0\"").is_err());
+
+        // Unknown field
+        assert!(VolumeConfigBundle::parse(
+            r#"
+            [[volume]]
+            volume = "data0"
+            dev = "/dev/nvme1n1p1"
+            unknown = true
+
+            [volume.encrypt.otp]
+            "#,
+        )
+        .is_err());
+
+        // Duplicated volume names
+        assert!(VolumeConfigBundle::parse(
+            r#"
+            [[volume]]
+            volume = "data0"
+            dev = "/dev/nvme1n1p1"
+
+            [volume.encrypt.otp]
+
+            [[volume]]
+            volume = "data0"
+            dev = "/dev/nvme1n1p2"
+
+            [volume.encrypt.otp]
+            "#,
+        )
+        .is_err());
+
+        Ok(())
+    }
+}
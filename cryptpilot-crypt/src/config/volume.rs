@@ -1,9 +1,14 @@
+use anyhow::{Context as _, Result};
 use documented::DocumentedFields;
 use serde::{Deserialize, Serialize};
 
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
 
-use cryptpilot::{config::encrypt::EncryptConfig, types::MakeFsType};
+use cryptpilot::{
+    config::encrypt::EncryptConfig,
+    fs::luks2::Luks2AreaSize,
+    types::{FsMismatchPolicy, IntegrityTuning, IntegrityType, MakeFsType, Passphrase},
+};
 
 /// The volume configuration.
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone, DocumentedFields)]
@@ -25,7 +30,52 @@ pub struct VolumeConfig {
 
 impl VolumeConfig {
     pub fn volume_path(&self) -> PathBuf {
-        Path::new("/dev/mapper").join(&self.volume)
+        cryptpilot::fs::block::devicemapper::dm_path(&self.volume)
+    }
+
+    /// Reject the passphrase from the key provider if it ends with whitespace and the volume is
+    /// configured to do so. Other problems are checked when the passphrase is used, see
+    /// [`Passphrase::validate`].
+    pub fn validate_passphrase(&self, passphrase: &Passphrase) -> Result<()> {
+        if self.extra_config.reject_passphrase_trailing_whitespace == Some(true) {
+            passphrase
+                .validate(true)
+                .with_context(|| format!("Invalid passphrase for volume {}", self.volume))?;
+        }
+        Ok(())
+    }
+
+    /// The integrity type to open the volume with. The dm-integrity journal is only used if it is
+    /// enabled in `integrity_tuning`, since it writes the data twice.
+    pub fn integrity_type_on_open(&self) -> IntegrityType {
+        let journal = self
+            .extra_config
+            .integrity_tuning
+            .is_some_and(|tuning| tuning.journal == Some(true));
+        match self.extra_config.integrity {
+            Some(true) if journal => IntegrityType::Journal,
+            Some(true) => IntegrityType::NoJournal,
+            Some(false) | None => IntegrityType::None,
+        }
+    }
+
+    /// Apply the `integrity_tuning` to the volume opened with the mapping `name`, which differs
+    /// from the volume name for a temporary mapping. Nothing is done if the journal is not in use.
+    pub async fn tune_integrity_journal(&self, name: &str, integrity: IntegrityType) -> Result<()> {
+        match (&self.extra_config.integrity_tuning, integrity) {
+            (Some(tuning), IntegrityType::Journal) => {
+                cryptpilot::fs::luks2::tune_integrity_journal(name, tuning).await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The sizes of the LUKS2 header areas to format the device with.
+    pub fn luks2_area_size(&self) -> Luks2AreaSize {
+        Luks2AreaSize {
+            metadata_size: self.extra_config.metadata_size,
+            keyslots_size: self.extra_config.keyslots_size,
+        }
     }
 }
 
@@ -44,6 +94,70 @@ pub struct ExtraConfig {
     /// Whether or not to enable support for data integrity. The default value is false. Note that integrity cannot prevent a replay (rollback) attack.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub integrity: Option<bool>,
+
+    /// Command to run after the volume is opened, e.g. ["/usr/sbin/fsck", "-a"]. The first element is the program and the rest are its arguments. The environment variables CRYPTPILOT_VOLUME, CRYPTPILOT_VOLUME_PATH and CRYPTPILOT_DEV are set to the volume name, the path of the opened volume and the underlying device.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub post_open: Option<Vec<String>>,
+
+    /// Whether or not to close the volume and fail the open operation if the `post_open` command fails. The default value is false, which means the failure is only logged as a warning.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub post_open_abort_on_failure: Option<bool>,
+
+    /// Command to run before the volume is closed, e.g. ["/usr/bin/sync"]. It is run in the same way as `post_open`. If the command fails, the volume will not be closed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pre_close: Option<Vec<String>>,
+
+    /// The label of the file system created by `makefs`, which can be used to reference the volume with `LABEL=` (e.g. in /etc/fstab). The maximum length is 16 bytes for "swap" and "ext4", 12 bytes for "xfs" and 11 bytes for "vfat".
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fs_label: Option<String>,
+
+    /// The existing signatures (the `TYPE` or `PTTYPE` reported by `blkid -p`, e.g. "ext4", "xfs" or "gpt") on the device which are allowed to be overwritten when the device is formatted as a LUKS2 volume. If any other signature is detected, formatting is aborted. An empty list means no existing signature may be overwritten. A LUKS2 signature can always be overwritten. If not set, any existing signature will be overwritten.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub overwrite_signatures: Option<Vec<String>>,
+
+    /// Whether to allow discard (TRIM) requests to be passed through to the underlying device, which benefits SSD-backed volumes. Note that this can leak information about which blocks of the device are in use (e.g. the file system type and the amount of used space). Default: false.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub discard: Option<bool>,
+
+    /// The sector size in bytes of the LUKS2 volume, which should be a power of two between 512 and 4096, e.g. 512 for devices which only support 512-byte sectors. If not set, it is detected from the logical block size of the device.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sector_size: Option<u32>,
+
+    /// Mount options (e.g. "nodiscard") which are only needed the first time the file system created by `makefs` is mounted, e.g. to skip operations which are redundant on a fresh file system. On the first open after the file system is created, they are passed to the `post_open` command with the CRYPTPILOT_MOUNT_OPTIONS environment variable, which is empty on the subsequent opens. For a volume with a temporary key provider, the file system is re-created on every open, so every open is a first open.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_open_mount_options: Option<String>,
+
+    /// Whether to reject a passphrase from the key provider which ends with whitespace (e.g. a trailing newline printed by `echo` without `-n`), instead of only warning about it. Such whitespace is used as part of the key, so a volume formatted with it cannot be opened if the key provider is fixed later. Default: false.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reject_passphrase_trailing_whitespace: Option<bool>,
+
+    /// The size in bytes of the LUKS2 metadata area, which holds the keyslots and tokens in JSON. Should be a power of two between 16384 (16 KiB) and 4194304 (4 MiB). A larger area is needed for many keyslots or tokens. If not set, the default of libcryptsetup is used.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<u64>,
+
+    /// The size in bytes of the LUKS2 keyslots area, which holds the encrypted volume keys of the keyslots. Should be a multiple of 4096 and at most 134217728 (128 MiB). If not set, the default of libcryptsetup is used.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keyslots_size: Option<u64>,
+
+    /// Whether to check and repair the file system on the volume with fsck (in the automatic repair mode `fsck -p`) after the volume is opened and before the `post_open` command is run, e.g. for a data volume which may be left unclean by a power loss. The file system type is taken from `makefs` if set, or detected otherwise. Swap volumes are skipped. Errors corrected by fsck are only logged, and the open operation fails (and the volume is closed) if errors are left uncorrected. Default: false.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fsck: Option<bool>,
+
+    /// Tuning of the dm-integrity journal for a volume with `integrity` enabled, e.g. for a write-heavy volume, with the fields `journal` (whether to open the volume with the journal, default: false), `journal_watermark` (in percent, 0 to 100, kernel default: 50) and `journal_commit_time` (in milliseconds, 1 to 3600000, kernel default: 10000). The watermark and the commit time take effect whenever the journal is used: when the file system is created by `init`, and on open with `journal = true`. If not set, the volume is opened without the journal.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub integrity_tuning: Option<IntegrityTuning>,
+
+    /// The position of the volume in the order in which the volumes are opened during booting (and listed), with lower values opened first, e.g. to open a volume holding the key of another volume before it. Volumes with the same order are sorted by name. If not set, the order is 0.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub order: Option<i32>,
+
+    /// The SELinux context (e.g. "system_u:object_r:container_file_t:s0") in the form of `user:role:type[:range]` of the file system on the volume, on SELinux-enforcing systems. On every open, it is passed as the `context=` mount option to the `post_open` command with the CRYPTPILOT_MOUNT_OPTIONS environment variable, together with the `first_open_mount_options` if any, so that the files get the context without a separate restorecon pass.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mount_context: Option<String>,
+
+    /// What to do when the file system found on the volume after it is opened is not the one configured with `makefs` (e.g. xfs on a volume with `makefs = "ext4"`, left by an earlier provisioning), which would otherwise fail or misbehave when it is mounted. Allowed values are ["fail", "warn", "ignore"]: "fail" closes the volume and fails the open operation, "warn" only logs a warning and "ignore" skips the check. A volume without any file system is not checked. Only takes effect with `makefs`. Default: "warn".
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub makefs_mismatch: Option<FsMismatchPolicy>,
 }
 
 #[cfg(test)]
@@ -78,15 +192,76 @@ pub mod tests {
                     auto_open: None,
                     makefs: None,
                     integrity: None,
+                    post_open: None,
+                    post_open_abort_on_failure: None,
+                    pre_close: None,
+                    fs_label: None,
+                    overwrite_signatures: None,
+                    discard: None,
+                    sector_size: None,
+                    first_open_mount_options: None,
+                    reject_passphrase_trailing_whitespace: None,
+                    metadata_size: None,
+                    keyslots_size: None,
+                    fsck: None,
+                    integrity_tuning: None,
+                    order: None,
+                    mount_context: None,
+                    makefs_mismatch: None,
                 },
                 encrypt: EncryptConfig {
                     key_provider: KeyProviderConfig::Otp(cryptpilot::provider::otp::OtpConfig {}),
+                    kdf: None,
+                    cipher: None,
                 }
             }
         );
         Ok(())
     }
 
+    #[test]
+    fn test_deserialize_kdf() -> Result<()> {
+        let raw = r#"
+        dev = "/dev/nvme1n1p1"
+        volume = "data"
+
+        [encrypt.exec]
+        command = "echo"
+        args = ["-n", "1234"]
+
+        [encrypt.kdf]
+        algorithm = "argon2id"
+        salt = "data-volume-salt"
+        "#;
+
+        let config: VolumeConfig = toml::from_str(raw)?;
+        assert!(matches!(
+            config.encrypt.key_provider,
+            KeyProviderConfig::Exec(_)
+        ));
+        assert_eq!(
+            config.encrypt.kdf,
+            Some(cryptpilot::config::kdf::KdfConfig {
+                algorithm: cryptpilot::config::kdf::KdfAlgorithm::Argon2id,
+                salt: "data-volume-salt".into(),
+                info: None,
+            })
+        );
+
+        // The KDF is omitted when serialized if it is not set
+        let config: VolumeConfig = toml::from_str(
+            r#"
+            dev = "/dev/nvme1n1p1"
+            volume = "data"
+
+            [encrypt.otp]
+            "#,
+        )?;
+        assert!(!toml::to_string(&config)?.contains("kdf"));
+
+        Ok(())
+    }
+
     #[test]
     fn test_deserialize_kms() -> Result<()> {
         let raw = r#"
@@ -159,6 +334,22 @@ nc8BTncWI0KGWIzTQasuSEye50R6gc9wZCGIElmhWcu3NYk=
                 auto_open: None,
                 makefs: None,
                 integrity: None,
+                post_open: None,
+                post_open_abort_on_failure: None,
+                pre_close: None,
+                fs_label: None,
+                overwrite_signatures: None,
+                discard: None,
+                sector_size: None,
+                first_open_mount_options: None,
+                reject_passphrase_trailing_whitespace: None,
+                metadata_size: None,
+                keyslots_size: None,
+                fsck: None,
+                integrity_tuning: None,
+                order: None,
+                mount_context: None,
+                makefs_mismatch: None,
             },
             encrypt: EncryptConfig {
                 key_provider: KeyProviderConfig::Kms(cryptpilot::provider::kms::KmsConfig {
@@ -220,6 +411,8 @@ nc8BTncWI0KGWIzTQasuSEye50R6gc9wZCGIElmhWcu3NYk=
                         .to_owned(),
                     },
                 }),
+                kdf: None,
+                cipher: None,
             },
         };
 
@@ -259,6 +452,22 @@ nc8BTncWI0KGWIzTQasuSEye50R6gc9wZCGIElmhWcu3NYk=
                 auto_open: Some(true),
                 makefs: Some(MakeFsType::Ext4),
                 integrity: Some(true),
+                post_open: None,
+                post_open_abort_on_failure: None,
+                pre_close: None,
+                fs_label: None,
+                overwrite_signatures: None,
+                discard: None,
+                sector_size: None,
+                first_open_mount_options: None,
+                reject_passphrase_trailing_whitespace: None,
+                metadata_size: None,
+                keyslots_size: None,
+                fsck: None,
+                integrity_tuning: None,
+                order: None,
+                mount_context: None,
+                makefs_mismatch: None,
             },
             encrypt: EncryptConfig {
                 key_provider: KeyProviderConfig::Oidc(OidcConfig {
@@ -271,6 +480,8 @@ nc8BTncWI0KGWIzTQasuSEye50R6gc9wZCGIElmhWcu3NYk=
                     args: vec!["-c".into(), "/etc/config.json".into(), "get-token".into()],
                     key_id: "disk-decryption-key".into(),
                 }),
+                kdf: None,
+                cipher: None,
             },
         };
         assert_eq!(expected, config);
@@ -0,0 +1,190 @@
+use std::fmt::Display;
+
+use anyhow::{bail, Context as _, Result};
+use cryptpilot::fs::cmd::CheckCommandOutput as _;
+use tokio::process::Command;
+
+use crate::config::VolumeConfig;
+
+/// The hook points of a volume at which a user provided command can be executed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VolumeHook {
+    /// After the volume is opened.
+    PostOpen,
+    /// Before the volume is closed.
+    PreClose,
+}
+
+impl Display for VolumeHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VolumeHook::PostOpen => write!(f, "post_open"),
+            VolumeHook::PreClose => write!(f, "pre_close"),
+        }
+    }
+}
+
+/// Run the command configured for the hook point of the volume. Do nothing if no command is configured.
+pub async fn run_volume_hook(volume_config: &VolumeConfig, hook: VolumeHook) -> Result<()> {
+    run_volume_hook_with_envs(volume_config, hook, &[]).await
+}
+
+/// Run the `post_open` command of the volume, with the mount options of the volume passed in the
+/// CRYPTPILOT_MOUNT_OPTIONS environment variable: the `context=` option of the `mount_context` on
+/// every open, and the `first_open_mount_options` if the volume is opened for the first time since
+/// its file system was created by `makefs`. The variable is empty if there is no such option.
+pub async fn run_post_open_hook(volume_config: &VolumeConfig, first_open: bool) -> Result<()> {
+    let mut mount_options = vec![];
+    if let Some(context) = &volume_config.extra_config.mount_context {
+        mount_options.push(
+            cryptpilot::fs::mount::selinux_context_mount_option(context).with_context(|| {
+                format!("Invalid mount_context of volume {}", volume_config.volume)
+            })?,
+        );
+    }
+    match &volume_config.extra_config.first_open_mount_options {
+        Some(first_open_mount_options) if first_open => {
+            mount_options.push(first_open_mount_options.clone())
+        }
+        _ => {}
+    }
+    run_volume_hook_with_envs(
+        volume_config,
+        VolumeHook::PostOpen,
+        &[("CRYPTPILOT_MOUNT_OPTIONS", mount_options.join(",").as_str())],
+    )
+    .await
+}
+
+async fn run_volume_hook_with_envs(
+    volume_config: &VolumeConfig,
+    hook: VolumeHook,
+    envs: &[(&str, &str)],
+) -> Result<()> {
+    let command = match hook {
+        VolumeHook::PostOpen => &volume_config.extra_config.post_open,
+        VolumeHook::PreClose => &volume_config.extra_config.pre_close,
+    };
+    let Some(command) = command else {
+        return Ok(());
+    };
+    let Some((program, args)) = command.split_first() else {
+        bail!(
+            "The {hook} command of volume {} is empty",
+            volume_config.volume
+        );
+    };
+
+    tracing::info!(
+        "Running {hook} command for volume {}: {command:?}",
+        volume_config.volume
+    );
+    Command::new(program)
+        .args(args)
+        .env("CRYPTPILOT_VOLUME", &volume_config.volume)
+        .env("CRYPTPILOT_VOLUME_PATH", volume_config.volume_path())
+        .env("CRYPTPILOT_DEV", &volume_config.dev)
+        .envs(envs.iter().copied())
+        .run()
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to run {hook} command for volume {}",
+                volume_config.volume
+            )
+        })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+    use anyhow::Result;
+
+    fn volume_config_with_hooks(
+        log: &std::path::Path,
+        post_open: &str,
+        pre_close: &str,
+    ) -> Result<VolumeConfig> {
+        let log = log.to_string_lossy();
+        Ok(toml::from_str(&format!(
+            r#"
+            volume = "data0"
+            dev = "/dev/nvme1n1p1"
+            post_open = ["sh", "-c", "{post_open} >> {log}"]
+            pre_close = ["sh", "-c", "{pre_close} >> {log}"]
+
+            [encrypt.otp]
+            "#
+        ))?)
+    }
+
+    #[tokio::test]
+    async fn test_run_volume_hook_order() -> Result<()> {
+        let log =
+            std::env::temp_dir().join(format!("cryptpilot-hooks-{}.log", rand::random::<u64>()));
+        let volume_config = volume_config_with_hooks(
+            &log,
+            "echo post_open $CRYPTPILOT_VOLUME $CRYPTPILOT_VOLUME_PATH $CRYPTPILOT_DEV",
+            "echo pre_close $CRYPTPILOT_VOLUME",
+        )?;
+
+        run_volume_hook(&volume_config, VolumeHook::PostOpen).await?;
+        run_volume_hook(&volume_config, VolumeHook::PreClose).await?;
+
+        assert_eq!(
+            tokio::fs::read_to_string(&log).await?,
+            "post_open data0 /dev/mapper/data0 /dev/nvme1n1p1\npre_close data0\n"
+        );
+        tokio::fs::remove_file(&log).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_run_post_open_hook_mount_context() -> Result<()> {
+        let log =
+            std::env::temp_dir().join(format!("cryptpilot-hooks-{}.log", rand::random::<u64>()));
+        let mut volume_config =
+            volume_config_with_hooks(&log, "echo \\\"$CRYPTPILOT_MOUNT_OPTIONS\\\"", "true")?;
+        volume_config.extra_config.mount_context =
+            Some("system_u:object_r:container_file_t:s0:c1,c2".into());
+        volume_config.extra_config.first_open_mount_options = Some("nodiscard".into());
+
+        // The context is passed on every open, and the first open mount options only on the first
+        run_post_open_hook(&volume_config, true).await?;
+        run_post_open_hook(&volume_config, false).await?;
+        assert_eq!(
+            tokio::fs::read_to_string(&log).await?,
+            concat!(
+                "context=\"system_u:object_r:container_file_t:s0:c1,c2\",nodiscard\n",
+                "context=\"system_u:object_r:container_file_t:s0:c1,c2\"\n",
+            )
+        );
+
+        // An invalid context is rejected before the command is run
+        volume_config.extra_config.mount_context = Some("container_file_t\",nosuid".into());
+        assert!(run_post_open_hook(&volume_config, false).await.is_err());
+        assert_eq!(tokio::fs::read_to_string(&log).await?.lines().count(), 2);
+
+        tokio::fs::remove_file(&log).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_run_volume_hook_failure() -> Result<()> {
+        let log =
+            std::env::temp_dir().join(format!("cryptpilot-hooks-{}.log", rand::random::<u64>()));
+        let volume_config = volume_config_with_hooks(&log, "false", "true")?;
+
+        assert!(run_volume_hook(&volume_config, VolumeHook::PostOpen)
+            .await
+            .is_err());
+        run_volume_hook(&volume_config, VolumeHook::PreClose).await?;
+        tokio::fs::remove_file(&log).await?;
+
+        Ok(())
+    }
+}
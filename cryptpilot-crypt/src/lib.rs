@@ -4,9 +4,11 @@ use shadow_rs::shadow;
 
 shadow!(build);
 
+pub mod audit;
 pub mod cli;
 pub mod cmd;
 pub mod config;
+pub mod hooks;
 
 // Re-export async_defer from cryptpilot core
 pub use cryptpilot::async_defer;
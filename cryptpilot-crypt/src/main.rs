@@ -3,9 +3,11 @@ use clap::Parser as _;
 use cryptpilot_crypt::build;
 use tracing_subscriber::{layer::SubscriberExt as _, util::SubscriberInitExt as _};
 
+mod audit;
 mod cli;
 mod cmd;
 mod config;
+mod hooks;
 
 use cmd::IntoCommand;
 use config::{cached::CachedVolumeConfigSource, fs::FileSystemConfigSource};
@@ -25,7 +27,20 @@ async fn main() -> Result<()> {
     )
     .await;
 
-    let args = cli::Cli::parse();
+    let mut args = cli::Cli::parse();
+    if args.dump_cdh_config {
+        cryptpilot::provider::kbs::set_dump_cdh_config(true);
+    }
+    cryptpilot::fs::lock::set_device_lock_timeout(
+        (!args.no_lock).then(|| std::time::Duration::from_secs(args.lock_timeout)),
+    );
+    cryptpilot::fs::block::devicemapper::set_dm_name_prefix(&args.dm_name_prefix)?;
+    audit::set_audit_log_path(Some(args.audit_log.clone()));
+    if args.is_non_interactive() {
+        tracing::debug!("Running in non-interactive mode, all confirmations are assumed");
+        args.command.assume_yes();
+        cryptpilot::provider::prompt::set_non_interactive(true);
+    }
 
     if let cli::CryptSubcommand::BootService(boot_service_options) = &args.command {
         tracing::info!(
@@ -42,13 +57,25 @@ async fn main() -> Result<()> {
     }
 
     // Configure volume config source
-    if let Some(config_dir) = &args.config_dir {
-        let path = std::path::Path::new(config_dir);
-        if !path.exists() || !path.is_dir() {
-            bail!("Config dir {config_dir} does not exist or not a directory");
+    if args.config_stdin
+        && matches!(&args.command, cli::CryptSubcommand::Init(init_options) if init_options.key_from_stdin_per_volume)
+    {
+        bail!("`--config-stdin` cannot be used with `--key-from-stdin-per-volume`, since both read from stdin");
+    }
+    if args.config_stdin {
+        config::set_volume_config_source(
+            config::stdin::StdinConfigSource::read_from_stdin().await?,
+        )
+        .await;
+    } else if !args.config_dir.is_empty() {
+        for config_dir in &args.config_dir {
+            let path = std::path::Path::new(config_dir);
+            if !path.exists() || !path.is_dir() {
+                bail!("Config dir {config_dir} does not exist or not a directory");
+            }
         }
         config::set_volume_config_source(CachedVolumeConfigSource::new(
-            FileSystemConfigSource::new(config_dir.clone()),
+            FileSystemConfigSource::new_with_config_dirs(&args.config_dir),
         ))
         .await;
     }
@@ -0,0 +1,96 @@
+// Audit log tests
+
+mod common;
+
+use common::{init_options, set_volumes, VolumeConfigBuilder};
+
+use cryptpilot_crypt::{
+    audit::set_audit_log_path,
+    cli::InitOptions,
+    cmd::{init::InitCommand, Command as _},
+};
+
+use cryptpilot::fs::block::dummy::DummyDevice;
+
+use anyhow::Result;
+
+async fn init_volume(volume: &str, force_reinit: bool) -> Result<()> {
+    InitCommand {
+        init_options: InitOptions {
+            force_reinit,
+            ..init_options(volume)
+        },
+    }
+    .run()
+    .await
+}
+
+fn read_audit_log(path: &std::path::Path) -> Result<Vec<serde_json::Value>> {
+    Ok(std::fs::read_to_string(path)?
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<_, _>>()?)
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+#[serial_test::serial]
+async fn test_audit_log_of_init() -> Result<()> {
+    // Set test mode environment variable to skip some external binary checks
+    std::env::set_var("CRYPTPILOT_TEST_MODE", "1");
+
+    let audit_dir = tempfile::tempdir()?;
+    let audit_log = audit_dir.path().join("audit.log");
+    set_audit_log_path(Some(audit_log.clone()));
+
+    let dummy_device = DummyDevice::setup_on_tmpfs(256 * 1024 * 1024).await?;
+
+    let volume_config = VolumeConfigBuilder::new(dummy_device.path()?)
+        .config(
+            r#"
+            [encrypt.exec]
+            command = "echo"
+            args = ["-n", "test-passphrase"]
+            "#,
+        )
+        .build()?;
+
+    set_volumes(vec![volume_config.clone()]).await;
+
+    init_volume(&volume_config.volume, false).await?;
+    let records = read_audit_log(&audit_log)?;
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["operation"], "init");
+    assert_eq!(records[0]["volume"], volume_config.volume.as_str());
+    assert_eq!(
+        records[0]["device"],
+        volume_config.dev.to_string_lossy().as_ref()
+    );
+    assert_eq!(records[0]["outcome"], "success");
+    assert!(records[0]["timestamp"].as_u64().is_some());
+    assert!(records[0]["uid"].as_u64().is_some());
+    assert!(records[0]["command_line"].as_array().is_some());
+
+    // Initializing again without `--force-reinit` fails, which is recorded too
+    assert!(init_volume(&volume_config.volume, false).await.is_err());
+    let records = read_audit_log(&audit_log)?;
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[1]["operation"], "init");
+    assert_eq!(records[1]["outcome"], "failure");
+    assert!(records[1]["error"]
+        .as_str()
+        .is_some_and(|error| error.contains("already initialized")));
+
+    init_volume(&volume_config.volume, true).await?;
+    let records = read_audit_log(&audit_log)?;
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[2]["operation"], "reinit");
+    assert_eq!(records[2]["outcome"], "success");
+
+    // A failure to write the audit log does not fail the operation
+    set_audit_log_path(Some(dummy_device.path()?.join("audit.log")));
+    init_volume(&volume_config.volume, true).await?;
+
+    set_audit_log_path(None);
+
+    Ok(())
+}
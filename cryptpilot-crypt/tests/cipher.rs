@@ -0,0 +1,107 @@
+// LUKS2 cipher tests
+
+use std::path::Path;
+
+use cryptpilot::fs::{
+    block::dummy::DummyDevice,
+    cmd::CheckCommandOutput as _,
+    luks2::{check_passphrase, close, format, is_active, open_with_check_passphrase},
+};
+use cryptpilot::types::{IntegrityType, Luks2Cipher, Passphrase};
+
+use anyhow::{Context as _, Result};
+use tokio::process::Command;
+
+/// Get the cipher of the data segment and the size in bits of the volume key from the LUKS2 header
+/// of the device.
+async fn luks2_cipher(dev: &Path) -> Result<(String, usize)> {
+    let output = Command::new("cryptsetup")
+        .arg("luksDump")
+        .arg(dev)
+        .env("LC_ALL", "C")
+        .run()
+        .await?;
+    let output = String::from_utf8_lossy(&output);
+    let field = |name: &str| {
+        output
+            .lines()
+            .find_map(|line| line.trim().strip_prefix(&format!("{name}: ")))
+            .and_then(|value| value.split_whitespace().next())
+            .map(ToOwned::to_owned)
+            .with_context(|| format!("No {name} found in LUKS2 header"))
+    };
+    Ok((
+        field("cipher")?,
+        field("Key")?
+            .parse()
+            .context("Invalid volume key size in LUKS2 header")?,
+    ))
+}
+
+#[rstest::rstest]
+#[case(Luks2Cipher::Aes256Xts, IntegrityType::None, "aes-xts-plain64", 512)]
+#[case(Luks2Cipher::Sm4Xts, IntegrityType::None, "sm4-xts-plain64", 256)]
+// The integrity key is appended to the volume key
+#[case(Luks2Cipher::Sm4Xts, IntegrityType::NoJournal, "sm4-xts-plain64", 512)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_format_with_cipher(
+    #[case] cipher: Luks2Cipher,
+    #[case] integrity: IntegrityType,
+    #[case] expected_cipher: &str,
+    #[case] expected_key_size: usize,
+) -> Result<()> {
+    let dummy = DummyDevice::setup_on_tmpfs(100 * 1024 * 1024).await?;
+    let dev = dummy.path()?;
+
+    let passphrase = Passphrase::from(b"test-passphrase-1234567890123456".to_vec());
+    format(&dev, &passphrase, integrity, cipher, None).await?;
+    assert_eq!(
+        luks2_cipher(&dev).await?,
+        (expected_cipher.to_owned(), expected_key_size)
+    );
+
+    // The volume is opened again with the same passphrase, and not with another one
+    assert!(check_passphrase(
+        &dev,
+        &Passphrase::from(b"wrong-passphrase-123456789012345".to_vec())
+    )
+    .await
+    .is_err());
+    let volume = format!("test-{}", rand::random::<u64>());
+    for _ in 0..2 {
+        open_with_check_passphrase(&volume, &dev, &passphrase, integrity, false).await?;
+        assert!(is_active(&volume));
+        close(&volume).await?;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_parse_cipher() -> Result<()> {
+    let config: cryptpilot::config::encrypt::EncryptConfig = toml::from_str(
+        r#"
+        cipher = "sm4-xts-plain64"
+
+        [otp]
+        "#,
+    )?;
+    assert_eq!(config.cipher, Some(Luks2Cipher::Sm4Xts));
+    assert_eq!(
+        Luks2Cipher::Sm4Xts.cipher_and_mode(),
+        ("sm4", "xts-plain64")
+    );
+
+    // AES is used by default
+    let config: cryptpilot::config::encrypt::EncryptConfig = toml::from_str("[otp]")?;
+    assert_eq!(config.cipher.unwrap_or_default(), Luks2Cipher::Aes256Xts);
+
+    assert!(
+        toml::from_str::<cryptpilot::config::encrypt::EncryptConfig>(
+            "cipher = \"twofish-xts-plain64\"\n[otp]"
+        )
+        .is_err()
+    );
+
+    Ok(())
+}
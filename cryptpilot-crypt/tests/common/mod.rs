@@ -0,0 +1,152 @@
+// Fixtures shared by the volume integration tests
+
+#![allow(dead_code)]
+
+pub mod volume;
+
+use std::path::PathBuf;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use cryptpilot_crypt::{
+    cli::{CloseOptions, InitOptions, OpenOptions},
+    cmd::{close::CloseCommand, init::InitCommand, open::OpenCommand, Command as _},
+    config::{
+        source::{set_volume_config_source, VolumeConfigSource},
+        volume::VolumeConfig,
+    },
+};
+
+#[ctor::ctor]
+fn init() {
+    use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+
+    let filter =
+        tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| "debug".into());
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer())
+        .init();
+}
+
+pub struct InMemoryVolumeConfigSource {
+    pub volumes: Vec<VolumeConfig>,
+}
+
+#[async_trait]
+impl VolumeConfigSource for InMemoryVolumeConfigSource {
+    fn source_debug_string(&self) -> String {
+        "in-memory test volume config".to_owned()
+    }
+
+    async fn get_volume_configs(&self) -> Result<Vec<VolumeConfig>> {
+        Ok(self.volumes.clone())
+    }
+}
+
+/// Make the commands operate on the given volumes.
+pub async fn set_volumes(volumes: Vec<VolumeConfig>) {
+    set_volume_config_source(InMemoryVolumeConfigSource { volumes }).await;
+}
+
+/// Builder of a [`VolumeConfig`] on a test device. The volume gets a random name and an OTP key
+/// provider unless configured otherwise, and every option which is not set in the config is left
+/// to its default, so that the tests keep working when new options are added.
+pub struct VolumeConfigBuilder {
+    volume: String,
+    dev: PathBuf,
+    config: String,
+}
+
+impl VolumeConfigBuilder {
+    pub fn new(dev: impl Into<PathBuf>) -> Self {
+        Self {
+            volume: format!("test-{}", rand::random::<u64>()),
+            dev: dev.into(),
+            config: "[encrypt.otp]".to_owned(),
+        }
+    }
+
+    pub fn volume(mut self, volume: impl Into<String>) -> Self {
+        self.volume = volume.into();
+        self
+    }
+
+    /// The settings of the volume other than `volume` and `dev`, in the TOML format of the volume
+    /// config files, e.g. `makefs = "ext4"` followed by an `[encrypt.otp]` table.
+    pub fn config(mut self, config: impl Into<String>) -> Self {
+        self.config = config.into();
+        self
+    }
+
+    pub fn build(self) -> Result<VolumeConfig> {
+        let mut volume_config: VolumeConfig = toml::from_str(&format!(
+            "volume = \"<placeholder>\"\ndev = \"<placeholder>\"\n{}",
+            self.config
+        ))?;
+        volume_config.volume = self.volume;
+        volume_config.dev = self.dev;
+        Ok(volume_config)
+    }
+}
+
+/// The options of `init` for the volume, without any flag set except `--yes`. Override the fields
+/// under test with `InitOptions { force_reinit: true, ..init_options(volume) }`.
+pub fn init_options(volume: &str) -> InitOptions {
+    InitOptions {
+        volume: vec![volume.to_owned()],
+        force_reinit: false,
+        yes: true,
+        parallel_devices: None,
+        from_existing: false,
+        i_know_this_is_root: false,
+        key_from_stdin_per_volume: false,
+    }
+}
+
+/// The options of `open` for the volume, as with no flag given on the command line.
+pub fn open_options(volume: &str) -> OpenOptions {
+    OpenOptions {
+        volume: vec![volume.to_owned()],
+        check_fs: false,
+        key_provider_override: None,
+        map_existing: false,
+        probe_only: false,
+        retries: 0,
+        retry_delay: 1,
+    }
+}
+
+/// The options of `close` for the volume, as with no flag given on the command line.
+pub fn close_options(volume: &str) -> CloseOptions {
+    CloseOptions {
+        volume: vec![volume.to_owned()],
+        force: false,
+        teardown: false,
+        all: false,
+    }
+}
+
+pub async fn init_volume(volume: &str) -> Result<()> {
+    InitCommand {
+        init_options: init_options(volume),
+    }
+    .run()
+    .await
+}
+
+pub async fn open_volume(volume: &str) -> Result<()> {
+    OpenCommand {
+        open_options: open_options(volume),
+    }
+    .run()
+    .await
+}
+
+pub async fn close_volume(volume: &str) -> Result<()> {
+    CloseCommand {
+        close_options: close_options(volume),
+    }
+    .run()
+    .await
+}
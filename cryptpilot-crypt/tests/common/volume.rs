@@ -0,0 +1,414 @@
+// The volume lifecycle harness shared by the key provider tests: init, open, use, close
+
+#![allow(unused_macros)]
+
+use super::{close_options, init_options, open_options, set_volumes};
+
+use std::{
+    future::Future,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use cryptpilot_crypt::{
+    async_defer,
+    cli::InitOptions,
+    cmd::{close::CloseCommand, init::InitCommand, open::OpenCommand, Command as _},
+    config::volume::VolumeConfig,
+};
+
+use cryptpilot::{
+    fs::{block::dummy::DummyDevice, cmd::CheckCommandOutput as _, mount::TmpMountPoint},
+    provider::{IntoProvider, KeyProvider, VolumeType},
+    types::MakeFsType,
+};
+
+use anyhow::Result;
+use block_devs::BlckExt as _;
+use cgroups_rs::{cgroup_builder::CgroupBuilder, Cgroup, CgroupPid};
+use nix::fcntl::{Flock, FlockArg};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncWriteExt},
+    process::Command,
+};
+use tokio_util::bytes::BytesMut;
+
+use rstest_reuse::template;
+
+/// Maximum number of volumes the harness operates on in parallel, which can be set with the
+/// `CRYPTPILOT_TEST_PARALLEL_DEVICES` environment variable (default: number of CPUs). The limit is
+/// shared by the tests forked into separate processes, see [`acquire_device_slot`].
+pub fn parallel_devices() -> NonZeroUsize {
+    std::env::var("CRYPTPILOT_TEST_PARALLEL_DEVICES")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .or_else(|| std::thread::available_parallelism().ok())
+        .unwrap_or(NonZeroUsize::MIN)
+}
+
+/// Hold one of the [`parallel_devices`] slots, each of which is a file locked with flock(2) in a
+/// directory shared by all the test processes, waiting until one of them is free. The slot is
+/// released when the returned lock is dropped, or when the process exits.
+async fn acquire_device_slot() -> Result<Flock<std::fs::File>> {
+    let slot_dir = std::env::temp_dir().join("cryptpilot-test-device-slots");
+    tokio::fs::create_dir_all(&slot_dir).await?;
+    loop {
+        for slot in 0..parallel_devices().get() {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(slot_dir.join(format!("slot{slot}")))?;
+            if let Ok(lock) = Flock::lock(file, FlockArg::LockExclusiveNonblock) {
+                return Ok(lock);
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+#[template]
+#[rstest]
+#[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+#[two_rusty_forks::test_fork]
+pub async fn test_volume_base(
+    #[values("swap", "ext4", "xfs", "vfat")] makefs: &str,
+    #[values(false, true)] integrity: bool,
+) -> Result<()> {
+}
+
+pub async fn open_then<F, T>(volume_config: &VolumeConfig, task: F) -> Result<()>
+where
+    F: FnOnce(VolumeConfig) -> T,
+    T: Future<Output = Result<()>>,
+{
+    OpenCommand {
+        open_options: open_options(&volume_config.volume),
+    }
+    .run()
+    .await?;
+
+    async_defer! {
+        async{
+            CloseCommand{
+                close_options: close_options(&volume_config.volume)
+            }.run().await?;
+            Ok::<_, anyhow::Error>(())
+        }
+    }
+
+    task(volume_config.clone()).await
+}
+
+pub async fn open_and_mount<F, T>(volume_config: &VolumeConfig, task: F) -> Result<()>
+where
+    F: FnOnce(VolumeConfig, PathBuf) -> T,
+    T: Future<Output = Result<()>>,
+{
+    open_then(volume_config, |volume_config| async move {
+        let tmp_mount = TmpMountPoint::mount(volume_config.volume_path(), true).await?;
+
+        task(volume_config, tmp_mount.mount_point().to_path_buf()).await
+    })
+    .await
+}
+
+pub async fn open_and_swapon<F, T>(volume_config: &VolumeConfig, task: F) -> Result<()>
+where
+    F: FnOnce(VolumeConfig) -> T,
+    T: Future<Output = Result<()>>,
+{
+    open_then(volume_config, |volume_config| async move {
+        cryptpilot::fs::mount::swapon(&volume_config.volume_path()).await?;
+
+        async_defer! {
+            async{
+                cryptpilot::fs::mount::swapoff(&volume_config.volume_path()).await?;
+                Ok::<_, anyhow::Error>(())
+            }
+        }
+
+        task(volume_config.clone()).await
+    })
+    .await
+}
+
+pub async fn run_test_on_volume(config_str: &str, use_external_suite: bool) -> Result<()> {
+    // Set test mode environment variable to skip external binary checks
+    std::env::set_var("CRYPTPILOT_TEST_MODE", "1");
+
+    // Avoid exhausting the host with too many devices (mkfs, device-mapper) at the same time
+    let _slot = acquire_device_slot().await?;
+
+    let mut volume_config: VolumeConfig = toml::from_str(config_str)?;
+
+    // Random volume name
+    volume_config.volume = format!("test-{}", rand::random::<u64>());
+
+    let dummy_device = if volume_config.extra_config.makefs == Some(MakeFsType::Swap) {
+        DummyDevice::setup_on_cache_dir(1024 * 1024 * 1024 /* 1G */).await?
+    } else {
+        DummyDevice::setup_on_tmpfs(100 * 1024 * 1024 * 1024 /* 100G */).await?
+    };
+
+    volume_config.dev = dummy_device.path()?;
+
+    set_volumes(vec![volume_config.clone()]).await;
+
+    InitCommand {
+        init_options: InitOptions {
+            parallel_devices: Some(parallel_devices()),
+            ..init_options(&volume_config.volume)
+        },
+    }
+    .run()
+    .await?;
+
+    match &volume_config.extra_config.makefs {
+        Some(MakeFsType::Swap) => {
+            // Just Open it and checking
+            open_then(&volume_config, |volume_config| async move {
+                if !matches!(volume_config.extra_config.integrity, Some(true)) {
+                    assert!(
+                        cryptpilot::fs::mkfs::has_valuable_data(
+                            &volume_config.volume_path(),
+                            volume_config.extra_config.makefs
+                        )
+                        .await?
+                    );
+                }
+                Ok(())
+            })
+            .await?;
+
+            // Open and swapon
+            open_and_swapon(&volume_config, |volume_config| async move {
+                if use_external_suite {
+                    let swap_device_size = File::open(volume_config.volume_path())
+                        .await?
+                        .into_std()
+                        .await
+                        .get_block_device_size()?;
+
+                    let hier = cgroups_rs::hierarchies::auto();
+                    let cg: Cgroup =
+                        CgroupBuilder::new(&format!("cryptpilot-test-{}", rand::random::<u64>()))
+                            .memory()
+                            .memory_hard_limit(128 * 1024 * 1024 /* 128M */)
+                            .memory_swap_limit(-1 /* infinity */)
+                            .done()
+                            .build(hier)?;
+
+                    let cg_clone = cg.clone();
+                    async_defer! {
+                        async{
+                            cg_clone.delete()
+                        }
+                    }
+
+                    // Run stress-ng to consume swap memory
+                    Command::new("stress-ng")
+                        .arg("--timeout")
+                        .arg("10")
+                        .arg("--vm")
+                        .arg("1")
+                        .arg("--vm-hang")
+                        .arg("0")
+                        .arg("--vm-method")
+                        .arg("zero-one")
+                        .arg("--vm-bytes")
+                        .arg(swap_device_size.to_string())
+                        .run_with_child_callback(move |pid| Ok(cg.add_task(CgroupPid::from(pid))?))
+                        .await?;
+                }
+
+                Ok(())
+            })
+            .await?;
+        }
+        Some(_) => {
+            // Just Open it and checking
+            open_then(&volume_config, |volume_config| async move {
+                if !matches!(volume_config.extra_config.integrity, Some(true)) {
+                    assert!(
+                        cryptpilot::fs::mkfs::has_valuable_data(
+                            &volume_config.volume_path(),
+                            volume_config.extra_config.makefs
+                        )
+                        .await?
+                    );
+                }
+                Ok(())
+            })
+            .await?;
+
+            // Open and write file
+            open_and_mount(&volume_config, |_, mount_dir: PathBuf| async move {
+                let mut file = File::options()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(mount_dir.join("testfile"))
+                    .await?;
+                file.write_all("test".as_bytes()).await?;
+                file.flush().await?;
+                Ok(())
+            })
+            .await?;
+
+            // Open again and read file
+            open_and_mount(
+                &volume_config,
+                |volume_config, mount_dir: PathBuf| async move {
+                    match volume_config.encrypt.into_provider().volume_type() {
+                        VolumeType::Temporary => {
+                            assert!(!mount_dir.join("testfile").exists())
+                        }
+                        VolumeType::Persistent => {
+                            let mut file: File = File::options()
+                                .read(true)
+                                .open(mount_dir.join("testfile"))
+                                .await?;
+                            let mut buf = BytesMut::new();
+                            file.read_buf(&mut buf).await?;
+                            assert_eq!("test".as_bytes(), &buf);
+                        }
+                    }
+                    Ok(())
+                },
+            )
+            .await?;
+
+            // Open and test fs stress
+            open_and_mount(&volume_config, |_, mount_dir: PathBuf| async move {
+                run_fs_stress(&mount_dir, PARALLEL_FS_STRESS).await?;
+                Ok(())
+            })
+            .await?;
+
+            if use_external_suite {
+                // Open again and test with pjdfstest
+                open_and_mount(&volume_config, |_, mount_dir: PathBuf| async move {
+                    Command::new("prove")
+                        .arg("-rv")
+                        .arg("/tmp/pjdfstest/tests")
+                        .current_dir(mount_dir)
+                        .run()
+                        .await?;
+                    Ok(())
+                })
+                .await?;
+            }
+        }
+        None => {
+            // Just Open it and do nothing but checking
+            open_then(&volume_config, |volume_config| async move {
+                // Add assertion to check if disk is empty
+                assert!(
+                    !cryptpilot::fs::mkfs::has_valuable_data(
+                        &volume_config.volume_path(),
+                        volume_config.extra_config.makefs
+                    )
+                    .await?
+                );
+                Ok(())
+            })
+            .await?;
+            // Test again
+            open_then(&volume_config, |volume_config| async move {
+                // Add assertion to check if disk is still empty after re-open
+                assert!(
+                    !cryptpilot::fs::mkfs::has_valuable_data(
+                        &volume_config.volume_path(),
+                        volume_config.extra_config.makefs
+                    )
+                    .await?
+                );
+                Ok(())
+            })
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+const PARALLEL_FS_STRESS: FsStressConfig = FsStressConfig {
+    workers: 16,
+    data_files_per_worker: 128,
+    tree_dirs_per_worker: 256,
+};
+
+struct FsStressConfig {
+    workers: usize,
+    data_files_per_worker: usize,
+    tree_dirs_per_worker: usize,
+}
+
+async fn run_fs_stress(root: &Path, config: FsStressConfig) -> anyhow::Result<()> {
+    let data = vec![0u8; 1024 * 1024];
+    let mut tasks = Vec::with_capacity(config.workers);
+    for worker in 0..config.workers {
+        let worker_dir = root.join(format!("w{worker}"));
+        let data = data.clone();
+        let data_files_per_worker = config.data_files_per_worker;
+        tasks.push(tokio::spawn(async move {
+            tokio::fs::create_dir_all(&worker_dir).await?;
+            for file in 0..data_files_per_worker {
+                tokio::fs::write(worker_dir.join(format!("f{file}")), &data).await?;
+            }
+            std::io::Result::Ok(())
+        }));
+    }
+
+    let mut stress_error = None;
+    for task in tasks {
+        match task.await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => {
+                stress_error.get_or_insert_with(|| anyhow::Error::new(err));
+            }
+            Err(err) => {
+                stress_error.get_or_insert_with(|| anyhow::Error::new(err));
+            }
+        }
+    }
+    if let Some(err) = stress_error {
+        return Err(err);
+    }
+
+    let mut tasks = Vec::with_capacity(config.workers);
+    for worker in 0..config.workers {
+        let worker_tree = root.join("trees").join(format!("w{worker}"));
+        let tree_dirs_per_worker = config.tree_dirs_per_worker;
+        tasks.push(tokio::spawn(async move {
+            tokio::fs::create_dir_all(&worker_tree).await?;
+            for dir in 0..tree_dirs_per_worker {
+                let dir_path = worker_tree.join(format!("d{dir}"));
+                tokio::fs::create_dir_all(&dir_path).await?;
+                tokio::fs::write(dir_path.join("file"), format!("w{worker}-{dir}")).await?;
+            }
+            std::io::Result::Ok(())
+        }));
+    }
+
+    let mut stress_error = None;
+    for task in tasks {
+        match task.await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => {
+                stress_error.get_or_insert_with(|| anyhow::Error::new(err));
+            }
+            Err(err) => {
+                stress_error.get_or_insert_with(|| anyhow::Error::new(err));
+            }
+        }
+    }
+    if let Some(err) = stress_error {
+        return Err(err);
+    }
+
+    Ok(())
+}
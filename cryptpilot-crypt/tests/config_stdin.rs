@@ -0,0 +1,60 @@
+// Volume config bundle from stdin tests
+
+use std::process::Stdio;
+
+use cryptpilot::fs::block::dummy::DummyDevice;
+
+use anyhow::{bail, Result};
+use tokio::{io::AsyncWriteExt as _, process::Command};
+
+/// Run cryptpilot-crypt with the volume config bundle piped to stdin.
+async fn run_with_config_stdin(bundle: &str, args: &[&str]) -> Result<()> {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_cryptpilot-crypt"))
+        .arg("--config-stdin")
+        .args(args)
+        .env("CRYPTPILOT_TEST_MODE", "1")
+        .stdin(Stdio::piped())
+        .spawn()?;
+
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    stdin.write_all(bundle.as_bytes()).await?;
+    drop(stdin);
+
+    let status = child.wait().await?;
+    if !status.success() {
+        bail!("cryptpilot-crypt {args:?} exited with {status}");
+    }
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_open_volume_with_config_stdin() -> Result<()> {
+    let dummy_device = DummyDevice::setup_on_tmpfs(1024 * 1024 * 1024).await?;
+    let volume = format!("test-{}", rand::random::<u64>());
+
+    let bundle = format!(
+        r#"
+        [[volume]]
+        volume = "{volume}"
+        dev = "{}"
+        makefs = "ext4"
+
+        [volume.encrypt.otp]
+        "#,
+        dummy_device.path()?.display()
+    );
+
+    run_with_config_stdin(&bundle, &["open", &volume]).await?;
+    assert!(cryptpilot::fs::luks2::is_active(&volume));
+
+    run_with_config_stdin(&bundle, &["close", &volume]).await?;
+    assert!(!cryptpilot::fs::luks2::is_active(&volume));
+
+    // A malformed bundle is rejected
+    assert!(run_with_config_stdin("[[volume]\n", &["open", &volume])
+        .await
+        .is_err());
+    assert!(!cryptpilot::fs::luks2::is_active(&volume));
+
+    Ok(())
+}
@@ -0,0 +1,125 @@
+// Initialization pre-flight report tests
+
+mod common;
+
+use common::{init_volume, set_volumes, VolumeConfigBuilder};
+
+use std::path::Path;
+
+use cryptpilot_crypt::{
+    cli::IsInitializedOptions,
+    cmd::{
+        is_initialized::{check_device_initialized, DeviceInitReport, IsInitializedCommand},
+        Command as _,
+    },
+    config::volume::VolumeConfig,
+};
+
+use cryptpilot::fs::{block::dummy::DummyDevice, cmd::CheckCommandOutput as _};
+
+use anyhow::Result;
+use tokio::process::Command;
+
+fn volume_config(dev: &Path, encrypt: &str) -> Result<VolumeConfig> {
+    VolumeConfigBuilder::new(dev).config(encrypt).build()
+}
+
+async fn is_initialized(volume: Vec<String>, all: bool) -> Result<()> {
+    IsInitializedCommand {
+        is_initialized_options: IsInitializedOptions { volume, all },
+    }
+    .run()
+    .await
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_is_initialized_with_mixed_devices() -> Result<()> {
+    // Set test mode environment variable to skip some external binary checks
+    std::env::set_var("CRYPTPILOT_TEST_MODE", "1");
+
+    let exec_encrypt = r#"
+        [encrypt.exec]
+        command = "echo"
+        args = ["-n", "test-passphrase"]
+        "#;
+
+    // A device initialized by cryptpilot.
+    let ready_device = DummyDevice::setup_on_tmpfs(1024 * 1024 * 1024).await?;
+    let ready = volume_config(&ready_device.path()?, exec_encrypt)?;
+
+    // A raw device without any signature.
+    let raw_device = DummyDevice::setup_on_tmpfs(1024 * 1024 * 1024).await?;
+    let raw = volume_config(&raw_device.path()?, exec_encrypt)?;
+
+    // A LUKS2 device formatted by someone else.
+    let foreign_device = DummyDevice::setup_on_tmpfs(1024 * 1024 * 1024).await?;
+    let key_file = std::env::temp_dir().join(format!(
+        "cryptpilot-is-initialized-{}.key",
+        rand::random::<u64>()
+    ));
+    tokio::fs::write(&key_file, "test-passphrase").await?;
+    Command::new("cryptsetup")
+        .args(["luksFormat", "--type", "luks2", "--batch-mode"])
+        .arg(foreign_device.path()?)
+        .arg(&key_file)
+        .run()
+        .await?;
+    tokio::fs::remove_file(&key_file).await?;
+    let foreign = volume_config(&foreign_device.path()?, exec_encrypt)?;
+
+    // A device which does not exist.
+    let missing = volume_config(
+        Path::new(&format!(
+            "/dev/cryptpilot-missing-{}",
+            rand::random::<u64>()
+        )),
+        exec_encrypt,
+    )?;
+
+    // A temporary volume, which does not require initialization.
+    let temporary = volume_config(&raw_device.path()?, "[encrypt.otp]")?;
+
+    set_volumes(vec![
+        ready.clone(),
+        raw.clone(),
+        foreign.clone(),
+        missing.clone(),
+        temporary.clone(),
+    ])
+    .await;
+
+    init_volume(&ready.volume).await?;
+
+    assert_eq!(
+        check_device_initialized(&ready).await,
+        DeviceInitReport::Initialized
+    );
+    assert_eq!(
+        check_device_initialized(&raw).await,
+        DeviceInitReport::Unencrypted { signature: None }
+    );
+    assert_eq!(
+        check_device_initialized(&foreign).await,
+        DeviceInitReport::ForeignLuks2
+    );
+    assert_eq!(
+        check_device_initialized(&missing).await,
+        DeviceInitReport::DeviceNotFound
+    );
+    assert_eq!(
+        check_device_initialized(&temporary).await,
+        DeviceInitReport::Temporary
+    );
+
+    // The report over all volumes fails since some of them are not ready.
+    assert!(is_initialized(vec![], true).await.is_err());
+    // Only the ready volumes are checked.
+    is_initialized(vec![ready.volume.clone(), temporary.volume.clone()], false).await?;
+    assert!(
+        is_initialized(vec![ready.volume.clone(), raw.volume.clone()], false)
+            .await
+            .is_err()
+    );
+
+    Ok(())
+}
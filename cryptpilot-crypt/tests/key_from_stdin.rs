@@ -0,0 +1,151 @@
+// Batch provisioning with the keys of the volumes from stdin tests
+
+use std::{path::Path, process::Stdio};
+
+use cryptpilot::{
+    config::kdf::{KdfAlgorithm, KdfConfig},
+    fs::block::dummy::DummyDevice,
+    types::Passphrase,
+};
+
+use anyhow::{bail, Result};
+use base64::{prelude::BASE64_STANDARD, Engine as _};
+use tokio::{io::AsyncWriteExt as _, process::Command};
+
+/// Run `cryptpilot-crypt init` for the volumes with the keys piped to stdin.
+async fn init_with_keys_from_stdin(config_dir: &Path, keys: &str, volumes: &[&str]) -> Result<()> {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_cryptpilot-crypt"))
+        .arg("--config-dir")
+        .arg(config_dir)
+        .args(["init", "--yes", "--key-from-stdin-per-volume"])
+        .args(volumes)
+        .env("CRYPTPILOT_TEST_MODE", "1")
+        .stdin(Stdio::piped())
+        .spawn()?;
+
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    stdin.write_all(keys.as_bytes()).await?;
+    drop(stdin);
+
+    let status = child.wait().await?;
+    if !status.success() {
+        bail!("cryptpilot-crypt init {volumes:?} exited with {status}");
+    }
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_init_two_volumes_with_keys_from_stdin() -> Result<()> {
+    let config_dir = tempfile::tempdir()?;
+    tokio::fs::create_dir_all(config_dir.path().join("volumes")).await?;
+
+    let mut volumes = vec![];
+    let mut devices = vec![];
+    for _ in 0..2 {
+        let dummy_device = DummyDevice::setup_on_tmpfs(1024 * 1024 * 1024).await?;
+        let volume = format!("test-{}", rand::random::<u64>());
+        // The key provider always fails, so the volume can only be initialized with the key from
+        // stdin
+        tokio::fs::write(
+            config_dir.path().join(format!("volumes/{volume}.toml")),
+            format!(
+                r#"
+                volume = "{volume}"
+                dev = "{}"
+
+                [encrypt.exec]
+                command = "false"
+                "#,
+                dummy_device.path()?.display()
+            ),
+        )
+        .await?;
+        volumes.push(volume);
+        devices.push(dummy_device);
+    }
+    let volume_refs = volumes.iter().map(String::as_str).collect::<Vec<_>>();
+    let keys = format!(
+        "{}={}\n{}={}\n",
+        volumes[0],
+        BASE64_STANDARD.encode("passphrase-of-volume-0"),
+        volumes[1],
+        BASE64_STANDARD.encode("passphrase-of-volume-1"),
+    );
+
+    // Every volume must be given a key, and no other ones
+    assert!(
+        init_with_keys_from_stdin(config_dir.path(), &keys, &volume_refs[..1])
+            .await
+            .is_err()
+    );
+    assert!(init_with_keys_from_stdin(
+        config_dir.path(),
+        keys.lines().next().unwrap(),
+        &volume_refs
+    )
+    .await
+    .is_err());
+    for device in &devices {
+        assert!(!cryptpilot::fs::luks2::is_initialized(&device.path()?).await?);
+    }
+
+    init_with_keys_from_stdin(config_dir.path(), &keys, &volume_refs).await?;
+    for (index, device) in devices.iter().enumerate() {
+        let dev = device.path()?;
+        assert!(cryptpilot::fs::luks2::is_initialized(&dev).await?);
+        let passphrase = Passphrase::from(format!("passphrase-of-volume-{index}").into_bytes());
+        cryptpilot::fs::luks2::check_passphrase(&dev, &passphrase).await?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_init_with_key_from_stdin_through_kdf() -> Result<()> {
+    let config_dir = tempfile::tempdir()?;
+    tokio::fs::create_dir_all(config_dir.path().join("volumes")).await?;
+
+    let dummy_device = DummyDevice::setup_on_tmpfs(1024 * 1024 * 1024).await?;
+    let dev = dummy_device.path()?;
+    let volume = format!("test-{}", rand::random::<u64>());
+    tokio::fs::write(
+        config_dir.path().join(format!("volumes/{volume}.toml")),
+        format!(
+            r#"
+            volume = "{volume}"
+            dev = "{}"
+
+            [encrypt.exec]
+            command = "false"
+
+            [encrypt.kdf]
+            algorithm = "hkdf-sha256"
+            salt = "cryptpilot-test-salt"
+            "#,
+            dev.display()
+        ),
+    )
+    .await?;
+
+    let keys = format!(
+        "{volume}={}\n",
+        BASE64_STANDARD.encode("passphrase-of-volume")
+    );
+    init_with_keys_from_stdin(config_dir.path(), &keys, &[&volume]).await?;
+
+    // The volume is unlocked by the derived key, as the key provider's key would be
+    let passphrase = Passphrase::from(b"passphrase-of-volume".to_vec());
+    let derived = KdfConfig {
+        algorithm: KdfAlgorithm::HkdfSha256,
+        salt: "cryptpilot-test-salt".into(),
+        info: None,
+    }
+    .derive(&passphrase)
+    .await?;
+    cryptpilot::fs::luks2::check_passphrase(&dev, &derived).await?;
+    assert!(cryptpilot::fs::luks2::check_passphrase(&dev, &passphrase)
+        .await
+        .is_err());
+
+    Ok(())
+}
@@ -0,0 +1,175 @@
+// LUKS2 keyslot management tests
+
+use std::path::Path;
+
+use cryptpilot::fs::{
+    block::dummy::DummyDevice,
+    cmd::CheckCommandOutput as _,
+    luks2::{
+        add_passphrase, check_passphrase, destroy_keyslot, format, format_with_area_size,
+        get_keyslot_by_passphrase, verify_passphrase, Luks2AreaSize,
+    },
+};
+use cryptpilot::types::{IntegrityType, Luks2Cipher, Passphrase};
+
+use anyhow::{Context as _, Result};
+use tokio::process::Command;
+
+/// Get the size of an area (e.g. "Metadata area" or "Keyslots area") from the LUKS2 header of the
+/// device.
+async fn luks2_area_size(dev: &Path, area: &str) -> Result<u64> {
+    let output = Command::new("cryptsetup")
+        .arg("luksDump")
+        .arg(dev)
+        .env("LC_ALL", "C")
+        .run()
+        .await?;
+    String::from_utf8_lossy(&output)
+        .lines()
+        .find_map(|line| line.trim().strip_prefix(&format!("{area}: ")))
+        .and_then(|value| value.split_whitespace().next())
+        .with_context(|| format!("No {area} found in LUKS2 header"))?
+        .parse()
+        .with_context(|| format!("Invalid {area} in LUKS2 header"))
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_replace_passphrase_in_keyslot() -> Result<()> {
+    let dummy = DummyDevice::setup_on_tmpfs(100 * 1024 * 1024).await?;
+    let dev = dummy.path()?;
+
+    let old_passphrase = Passphrase::from(b"old-passphrase-1234567890123456".to_vec());
+    let new_passphrase = Passphrase::from(b"new-passphrase-1234567890123456".to_vec());
+    format(
+        &dev,
+        &old_passphrase,
+        IntegrityType::None,
+        Luks2Cipher::default(),
+        None,
+    )
+    .await?;
+    let old_keyslot = get_keyslot_by_passphrase(&dev, &old_passphrase).await?;
+
+    // The new passphrase can not be added with a wrong passphrase
+    assert!(add_passphrase(&dev, &new_passphrase, &new_passphrase)
+        .await
+        .is_err());
+
+    let new_keyslot = add_passphrase(&dev, &old_passphrase, &new_passphrase).await?;
+    assert_ne!(new_keyslot, old_keyslot);
+    assert_eq!(
+        get_keyslot_by_passphrase(&dev, &new_passphrase).await?,
+        new_keyslot
+    );
+    assert_eq!(
+        get_keyslot_by_passphrase(&dev, &old_passphrase).await?,
+        old_keyslot
+    );
+
+    destroy_keyslot(&dev, old_keyslot).await?;
+    assert!(get_keyslot_by_passphrase(&dev, &old_passphrase)
+        .await
+        .is_err());
+    assert!(check_passphrase(&dev, &old_passphrase).await.is_err());
+    check_passphrase(&dev, &new_passphrase).await?;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_format_with_larger_area_size() -> Result<()> {
+    let dummy = DummyDevice::setup_on_tmpfs(100 * 1024 * 1024).await?;
+    let dev = dummy.path()?;
+
+    let passphrase = Passphrase::from(b"passphrase-12345678901234567890".to_vec());
+    format_with_area_size(
+        &dev,
+        &passphrase,
+        IntegrityType::None,
+        Luks2Cipher::default(),
+        None,
+        Luks2AreaSize {
+            metadata_size: Some(256 * 1024),
+            keyslots_size: Some(32 * 1024 * 1024),
+        },
+    )
+    .await?;
+    assert_eq!(luks2_area_size(&dev, "Metadata area").await?, 256 * 1024);
+    assert_eq!(
+        luks2_area_size(&dev, "Keyslots area").await?,
+        32 * 1024 * 1024
+    );
+
+    // Room for keyslots of several key providers
+    for i in 0..4 {
+        let other_passphrase =
+            Passphrase::from(format!("other-passphrase-{i}-123456789012345").into_bytes());
+        add_passphrase(&dev, &passphrase, &other_passphrase).await?;
+        check_passphrase(&dev, &other_passphrase).await?;
+    }
+    check_passphrase(&dev, &passphrase).await?;
+
+    // Sizes not supported by LUKS2 are rejected
+    for area_size in [
+        Luks2AreaSize {
+            metadata_size: Some(48 * 1024),
+            keyslots_size: None,
+        },
+        Luks2AreaSize {
+            metadata_size: None,
+            keyslots_size: Some(1000),
+        },
+    ] {
+        assert!(format_with_area_size(
+            &dev,
+            &passphrase,
+            IntegrityType::None,
+            Luks2Cipher::default(),
+            None,
+            area_size
+        )
+        .await
+        .is_err());
+    }
+    check_passphrase(&dev, &passphrase).await?;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_verify_passphrase_matches_check_passphrase() -> Result<()> {
+    let dummy = DummyDevice::setup_on_tmpfs(100 * 1024 * 1024).await?;
+    let dev = dummy.path()?;
+
+    let passphrase = Passphrase::from(b"passphrase-12345678901234567890".to_vec());
+    let other_passphrase = Passphrase::from(b"other-passphrase-123456789012345".to_vec());
+    let wrong_passphrase = Passphrase::from(b"wrong-passphrase-123456789012345".to_vec());
+    format(
+        &dev,
+        &passphrase,
+        IntegrityType::NoJournal,
+        Luks2Cipher::default(),
+        None,
+    )
+    .await?;
+    add_passphrase(&dev, &passphrase, &other_passphrase).await?;
+
+    // The light check agrees with the full check (a test activation) on every passphrase, and finds
+    // the same keyslot
+    for candidate in [&passphrase, &other_passphrase] {
+        check_passphrase(&dev, candidate).await?;
+        assert_eq!(
+            verify_passphrase(&dev, candidate).await?,
+            get_keyslot_by_passphrase(&dev, candidate).await?
+        );
+    }
+    assert!(check_passphrase(&dev, &wrong_passphrase).await.is_err());
+    assert!(verify_passphrase(&dev, &wrong_passphrase).await.is_err());
+
+    let keyslot = verify_passphrase(&dev, &passphrase).await?;
+    destroy_keyslot(&dev, keyslot).await?;
+    assert!(check_passphrase(&dev, &passphrase).await.is_err());
+    assert!(verify_passphrase(&dev, &passphrase).await.is_err());
+
+    Ok(())
+}
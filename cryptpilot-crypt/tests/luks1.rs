@@ -0,0 +1,112 @@
+// Legacy LUKS1 volume tests
+
+mod common;
+
+use common::{close_volume, open_volume, set_volumes, VolumeConfigBuilder};
+
+use cryptpilot_crypt::{
+    cli::ConvertToLuks2Options,
+    cmd::{
+        convert_to_luks2::ConvertToLuks2Command,
+        is_initialized::{check_device_initialized, DeviceInitReport},
+        Command as _,
+    },
+    config::volume::VolumeConfig,
+};
+
+use cryptpilot::fs::{
+    block::dummy::DummyDevice, cmd::CheckCommandOutput as _, luks2::VolumeInitState,
+};
+
+use anyhow::Result;
+use tokio::process::Command;
+
+async fn convert_to_luks2(volume: &str) -> Result<()> {
+    ConvertToLuks2Command {
+        convert_to_luks2_options: ConvertToLuks2Options {
+            volume: volume.to_owned(),
+            yes: true,
+        },
+    }
+    .run()
+    .await
+}
+
+async fn fs_type(volume_config: &VolumeConfig) -> Result<String> {
+    let stdout = Command::new("blkid")
+        .args(["-o", "value", "-s", "TYPE"])
+        .arg(volume_config.volume_path())
+        .run()
+        .await?;
+    Ok(String::from_utf8(stdout)?.trim().to_owned())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_open_and_convert_luks1_volume() -> Result<()> {
+    // Set test mode environment variable to skip some external binary checks
+    std::env::set_var("CRYPTPILOT_TEST_MODE", "1");
+
+    // A LUKS1 device formatted by an old cryptsetup.
+    let dummy_device = DummyDevice::setup_on_tmpfs(1024 * 1024 * 1024).await?;
+    let key_file =
+        std::env::temp_dir().join(format!("cryptpilot-luks1-{}.key", rand::random::<u64>()));
+    tokio::fs::write(&key_file, "test-passphrase").await?;
+    Command::new("cryptsetup")
+        .args(["luksFormat", "--type", "luks1", "--batch-mode"])
+        .arg(dummy_device.path()?)
+        .arg(&key_file)
+        .run()
+        .await?;
+    tokio::fs::remove_file(&key_file).await?;
+
+    let volume_config = VolumeConfigBuilder::new(dummy_device.path()?)
+        .config(
+            r#"
+            [encrypt.exec]
+            command = "echo"
+            args = ["-n", "test-passphrase"]
+            "#,
+        )
+        .build()?;
+    set_volumes(vec![volume_config.clone()]).await;
+
+    // The LUKS1 volume is reported distinctly, and can be opened as is
+    assert_eq!(
+        cryptpilot::fs::luks2::get_init_state(&volume_config.dev).await?,
+        VolumeInitState::Luks1
+    );
+    assert_eq!(
+        check_device_initialized(&volume_config).await,
+        DeviceInitReport::Luks1
+    );
+    open_volume(&volume_config.volume).await?;
+    assert!(cryptpilot::fs::luks2::is_active(&volume_config.volume));
+    Command::new("mkfs.ext4")
+        .arg(volume_config.volume_path())
+        .run()
+        .await?;
+
+    // It is not converted while it is opened
+    assert!(convert_to_luks2(&volume_config.volume).await.is_err());
+    close_volume(&volume_config.volume).await?;
+
+    convert_to_luks2(&volume_config.volume).await?;
+    assert_eq!(
+        cryptpilot::fs::luks2::get_init_state(&volume_config.dev).await?,
+        VolumeInitState::Ready
+    );
+    assert_eq!(
+        check_device_initialized(&volume_config).await,
+        DeviceInitReport::Initialized
+    );
+
+    // Converting it again is refused
+    assert!(convert_to_luks2(&volume_config.volume).await.is_err());
+
+    // The data is kept after the conversion
+    open_volume(&volume_config.volume).await?;
+    assert_eq!(fs_type(&volume_config).await?, "ext4");
+    close_volume(&volume_config.volume).await?;
+
+    Ok(())
+}
@@ -1,56 +1,21 @@
 // Volume mkfs with integrity tests
 
+mod common;
+
+use common::{close_volume, init_volume, open_volume, set_volumes, VolumeConfigBuilder};
+
 use std::path::PathBuf;
 
-use cryptpilot_crypt::{
-    async_defer,
-    cli::{CloseOptions, OpenOptions},
-    cmd::{close::CloseCommand, open::OpenCommand, Command as _},
-    config::{
-        source::{set_volume_config_source, VolumeConfigSource},
-        volume::{ExtraConfig, VolumeConfig},
-    },
-};
+use cryptpilot_crypt::{async_defer, config::volume::VolumeConfig};
 
 use cryptpilot::{
-    config::encrypt::{EncryptConfig, KeyProviderConfig},
-    fs::{block::dummy::DummyDevice, cmd::CheckCommandOutput as _},
-    provider::otp::OtpConfig,
-    types::MakeFsType,
+    fs::{blkid::BlkidProbeResult, block::dummy::DummyDevice, cmd::CheckCommandOutput as _},
+    types::{IntegrityType, MakeFsType},
 };
 
 use anyhow::Result;
-use async_trait::async_trait;
 use tokio::process::Command;
 
-#[cfg(test)]
-#[ctor::ctor]
-fn init() {
-    use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
-
-    let filter =
-        tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| "debug".into());
-    tracing_subscriber::registry()
-        .with(filter)
-        .with(tracing_subscriber::fmt::layer())
-        .init();
-}
-
-struct InMemoryVolumeConfigSource {
-    volumes: Vec<VolumeConfig>,
-}
-
-#[async_trait]
-impl VolumeConfigSource for InMemoryVolumeConfigSource {
-    fn source_debug_string(&self) -> String {
-        "in-memory test volume config".to_owned()
-    }
-
-    async fn get_volume_configs(&self) -> Result<Vec<VolumeConfig>> {
-        Ok(self.volumes.clone())
-    }
-}
-
 #[tokio::test(flavor = "multi_thread", worker_threads = 10)]
 async fn test_mkfs_with_integrity() -> Result<()> {
     // Set test mode environment variable to skip some external binary checks
@@ -58,52 +23,31 @@ async fn test_mkfs_with_integrity() -> Result<()> {
 
     let dummy_device = DummyDevice::setup_on_tmpfs(10 * 1024 * 1024 * 1024).await?;
 
-    let volume_config = VolumeConfig {
-        volume: "mkfs_with_integrity".to_owned(),
-        dev: dummy_device.path().unwrap(),
-        extra_config: ExtraConfig {
-            auto_open: Some(true),
-            makefs: Some(MakeFsType::Ext4),
-            integrity: Some(true),
-        },
-        encrypt: EncryptConfig {
-            key_provider: KeyProviderConfig::Otp(OtpConfig {}),
-        },
-    };
+    let volume_config = VolumeConfigBuilder::new(dummy_device.path()?)
+        .volume("mkfs_with_integrity")
+        .config(
+            r#"
+            auto_open = true
+            makefs = "ext4"
+            integrity = true
+
+            [encrypt.otp]
+            "#,
+        )
+        .build()?;
 
-    set_volume_config_source(InMemoryVolumeConfigSource {
-        volumes: vec![volume_config.clone()],
-    })
-    .await;
+    set_volumes(vec![volume_config.clone()]).await;
 
     // Close the volume if it is already opened
-    CloseCommand {
-        close_options: CloseOptions {
-            volume: vec![volume_config.volume.clone()],
-        },
-    }
-    .run()
-    .await
-    .unwrap();
+    close_volume(&volume_config.volume).await.unwrap();
 
     async_defer! {
         async{
-            CloseCommand {
-                close_options: CloseOptions {
-                    volume: vec![volume_config.volume.clone()],
-                }
-            }.run().await.unwrap();
+            close_volume(&volume_config.volume).await.unwrap();
         }
     }
 
-    OpenCommand {
-        open_options: OpenOptions {
-            volume: vec![volume_config.volume.clone()],
-            check_fs: false,
-        },
-    }
-    .run()
-    .await?;
+    open_volume(&volume_config.volume).await?;
 
     Command::new("blkid")
         .arg("-p")
@@ -113,3 +57,107 @@ async fn test_mkfs_with_integrity() -> Result<()> {
 
     Ok(())
 }
+
+#[rstest::rstest]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_mkfs_with_label(
+    #[values(MakeFsType::Swap, MakeFsType::Ext4, MakeFsType::Xfs, MakeFsType::Vfat)]
+    makefs: MakeFsType,
+    #[values(IntegrityType::None, IntegrityType::NoJournal)] integrity: IntegrityType,
+) -> Result<()> {
+    let dummy_device = DummyDevice::setup_on_tmpfs(1024 * 1024 * 1024).await?;
+    let dev_path = dummy_device.path()?;
+
+    cryptpilot::fs::mkfs::force_mkfs(&dev_path, &makefs, Some("DATA0"), integrity).await?;
+
+    let probe = cryptpilot::fs::blkid::probe_device(&dev_path).await?;
+    let BlkidProbeResult::KnownSignature { label, .. } = probe else {
+        panic!("No signature found on {dev_path:?} after mkfs");
+    };
+    assert_eq!(label.as_deref(), Some("DATA0"));
+
+    Ok(())
+}
+
+async fn setup_device_with_ext4_signature() -> Result<DummyDevice> {
+    let dummy_device = DummyDevice::setup_on_tmpfs(1024 * 1024 * 1024).await?;
+    cryptpilot::fs::mkfs::force_mkfs(
+        &dummy_device.path()?,
+        &MakeFsType::Ext4,
+        None,
+        IntegrityType::None,
+    )
+    .await?;
+    Ok(dummy_device)
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_check_overwrite_signature() -> Result<()> {
+    let dummy_device = setup_device_with_ext4_signature().await?;
+    let dev_path = dummy_device.path()?;
+
+    let probe = cryptpilot::fs::blkid::probe_device(&dev_path).await?;
+    assert_eq!(probe.signature(), Some("ext4"));
+
+    cryptpilot::fs::mkfs::check_overwrite_signature(&dev_path, None).await?;
+    cryptpilot::fs::mkfs::check_overwrite_signature(
+        &dev_path,
+        Some(&["xfs".to_owned(), "ext4".to_owned()]),
+    )
+    .await?;
+    assert!(
+        cryptpilot::fs::mkfs::check_overwrite_signature(&dev_path, Some(&["xfs".to_owned()]))
+            .await
+            .is_err()
+    );
+    assert!(
+        cryptpilot::fs::mkfs::check_overwrite_signature(&dev_path, Some(&[]))
+            .await
+            .is_err()
+    );
+
+    // A clean device can always be overwritten
+    let clean_device = DummyDevice::setup_on_tmpfs(1024 * 1024 * 1024).await?;
+    cryptpilot::fs::mkfs::check_overwrite_signature(&clean_device.path()?, Some(&[])).await?;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_init_with_overwrite_signatures() -> Result<()> {
+    // Set test mode environment variable to skip some external binary checks
+    std::env::set_var("CRYPTPILOT_TEST_MODE", "1");
+
+    let dummy_device = setup_device_with_ext4_signature().await?;
+
+    let mut volume_config = VolumeConfigBuilder::new(dummy_device.path()?)
+        .config(
+            r#"
+            makefs = "ext4"
+            overwrite_signatures = ["xfs"]
+
+            [encrypt.exec]
+            command = "echo"
+            args = ["-n", "test-passphrase"]
+            "#,
+        )
+        .build()?;
+
+    let init = |volume_config: VolumeConfig| async move {
+        set_volumes(vec![volume_config.clone()]).await;
+        init_volume(&volume_config.volume).await
+    };
+
+    // The ext4 signature is not allowed to be overwritten, so the device is left untouched
+    assert!(init(volume_config.clone()).await.is_err());
+    let probe = cryptpilot::fs::blkid::probe_device(&volume_config.dev).await?;
+    assert_eq!(probe.signature(), Some("ext4"));
+
+    // Allow overwriting the ext4 signature
+    volume_config.extra_config.overwrite_signatures = Some(vec!["ext4".to_owned()]);
+    init(volume_config.clone()).await?;
+    let probe = cryptpilot::fs::blkid::probe_device(&volume_config.dev).await?;
+    assert!(probe.is_luks());
+
+    Ok(())
+}
@@ -0,0 +1,83 @@
+// Non-interactive mode tests
+
+use std::process::Stdio;
+
+use cryptpilot::fs::block::dummy::DummyDevice;
+
+use anyhow::{bail, Result};
+use tokio::{io::AsyncWriteExt as _, process::Command};
+
+/// Run cryptpilot-crypt with the volume config bundle piped to stdin. The stderr is not a terminal,
+/// so any confirmation prompt fails instead of blocking.
+async fn run_with_config_stdin(
+    bundle: &str,
+    args: &[&str],
+    envs: &[(&str, &str)],
+) -> Result<String> {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_cryptpilot-crypt"))
+        .arg("--config-stdin")
+        .args(args)
+        .env("CRYPTPILOT_TEST_MODE", "1")
+        .env_remove("CRYPTPILOT_NONINTERACTIVE")
+        .envs(envs.iter().copied())
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    stdin.write_all(bundle.as_bytes()).await?;
+    drop(stdin);
+
+    let output = child.wait_with_output().await?;
+    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+    if !output.status.success() {
+        bail!(
+            "cryptpilot-crypt {args:?} exited with {}: {stderr}",
+            output.status
+        );
+    }
+    Ok(stderr)
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_init_with_non_interactive() -> Result<()> {
+    let dummy_device = DummyDevice::setup_on_tmpfs(1024 * 1024 * 1024).await?;
+    let volume = format!("test-{}", rand::random::<u64>());
+
+    let bundle = format!(
+        r#"
+        [[volume]]
+        volume = "{volume}"
+        dev = "{}"
+
+        [volume.encrypt.exec]
+        command = "echo"
+        args = ["-n", "test-passphrase"]
+        "#,
+        dummy_device.path()?.display()
+    );
+
+    // The confirmation is required without the non-interactive mode
+    let error = run_with_config_stdin(&bundle, &["init", &volume], &[])
+        .await
+        .expect_err("init should fail without confirmation");
+    assert!(
+        format!("{error:#}").contains("--non-interactive"),
+        "unexpected error: {error:#}"
+    );
+    assert!(!cryptpilot::fs::luks2::is_initialized(&dummy_device.path()?).await?);
+
+    run_with_config_stdin(&bundle, &["--non-interactive", "init", &volume], &[]).await?;
+    assert!(cryptpilot::fs::luks2::is_initialized(&dummy_device.path()?).await?);
+
+    // The environment variable has the same effect
+    run_with_config_stdin(
+        &bundle,
+        &["init", "--force-reinit", &volume],
+        &[("CRYPTPILOT_NONINTERACTIVE", "1")],
+    )
+    .await?;
+    assert!(cryptpilot::fs::luks2::is_initialized(&dummy_device.path()?).await?);
+
+    Ok(())
+}
@@ -0,0 +1,89 @@
+// Passphrase validation tests
+
+mod common;
+
+use common::{init_options, set_volumes, VolumeConfigBuilder};
+
+use cryptpilot_crypt::{
+    cli::InitOptions,
+    cmd::{init::InitCommand, Command as _},
+};
+
+use cryptpilot::fs::block::dummy::DummyDevice;
+
+use anyhow::Result;
+
+async fn init_with_exec_provider(
+    dummy_device: &DummyDevice,
+    args: &[&str],
+    reject_passphrase_trailing_whitespace: Option<bool>,
+) -> Result<()> {
+    let mut volume_config = VolumeConfigBuilder::new(dummy_device.path()?)
+        .config(
+            r#"
+            [encrypt.exec]
+            command = "printf"
+            "#,
+        )
+        .build()?;
+    volume_config
+        .extra_config
+        .reject_passphrase_trailing_whitespace = reject_passphrase_trailing_whitespace;
+    if let cryptpilot::config::encrypt::KeyProviderConfig::Exec(exec_config) =
+        &mut volume_config.encrypt.key_provider
+    {
+        exec_config.args = args.iter().map(|arg| arg.to_string()).collect();
+    }
+
+    set_volumes(vec![volume_config.clone()]).await;
+
+    InitCommand {
+        init_options: InitOptions {
+            force_reinit: true,
+            ..init_options(&volume_config.volume)
+        },
+    }
+    .run()
+    .await
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+#[serial_test::serial]
+async fn test_init_with_invalid_passphrase() -> Result<()> {
+    // Set test mode environment variable to skip some external binary checks
+    std::env::set_var("CRYPTPILOT_TEST_MODE", "1");
+
+    let dummy_device = DummyDevice::setup_on_tmpfs(1024 * 1024 * 1024).await?;
+
+    // An empty passphrase is always rejected
+    for reject in [None, Some(true)] {
+        assert!(init_with_exec_provider(&dummy_device, &[""], reject)
+            .await
+            .is_err());
+        assert!(!cryptpilot::fs::luks2::is_initialized(&dummy_device.path()?).await?);
+    }
+
+    // Trailing whitespace is rejected only if configured
+    assert!(
+        init_with_exec_provider(&dummy_device, &["test-passphrase\\n"], Some(true))
+            .await
+            .is_err()
+    );
+    assert!(
+        init_with_exec_provider(&dummy_device, &["test-passphrase "], Some(true))
+            .await
+            .is_err()
+    );
+    assert!(!cryptpilot::fs::luks2::is_initialized(&dummy_device.path()?).await?);
+
+    init_with_exec_provider(&dummy_device, &["test-passphrase\\n"], None).await?;
+    assert!(cryptpilot::fs::luks2::is_initialized(&dummy_device.path()?).await?);
+    assert!(cryptpilot::fs::luks2::check_passphrase(
+        &dummy_device.path()?,
+        &cryptpilot::types::Passphrase::from(b"test-passphrase\n".to_vec())
+    )
+    .await
+    .is_ok());
+
+    Ok(())
+}
@@ -0,0 +1,109 @@
+// Integrity key rotation tests
+
+mod common;
+
+use common::{init_volume, set_volumes, VolumeConfigBuilder};
+
+use cryptpilot_crypt::{
+    cli::RotateIntegrityKeyOptions,
+    cmd::{rotate_integrity_key::RotateIntegrityKeyCommand, Command as _},
+};
+
+use cryptpilot::fs::block::dummy::DummyDevice;
+
+use anyhow::Result;
+
+fn rotate_command(volume: &str, reformat: bool) -> RotateIntegrityKeyCommand {
+    RotateIntegrityKeyCommand {
+        rotate_integrity_key_options: RotateIntegrityKeyOptions {
+            volume: volume.to_owned(),
+            reformat,
+            yes: true,
+            json: false,
+        },
+    }
+}
+
+#[rstest::rstest]
+#[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+#[serial_test::serial]
+async fn test_rotate_integrity_key(#[values(false, true)] integrity: bool) -> Result<()> {
+    // Set test mode environment variable to skip some external binary checks
+    std::env::set_var("CRYPTPILOT_TEST_MODE", "1");
+
+    let dummy_device = DummyDevice::setup_on_tmpfs(1024 * 1024 * 1024).await?;
+
+    let mut volume_config = VolumeConfigBuilder::new(dummy_device.path()?)
+        .config(
+            r#"
+            [encrypt.exec]
+            command = "echo"
+            args = ["-n", "test-passphrase"]
+            "#,
+        )
+        .build()?;
+    volume_config.extra_config.integrity = Some(integrity);
+
+    set_volumes(vec![volume_config.clone()]).await;
+
+    // The volume is not initialized yet
+    assert!(rotate_command(&volume_config.volume, false)
+        .run()
+        .await
+        .is_err());
+
+    init_volume(&volume_config.volume).await?;
+
+    // Reporting the status works with and without integrity
+    rotate_command(&volume_config.volume, false).run().await?;
+
+    let status = cryptpilot::fs::luks2::get_integrity_status(&dummy_device.path()?).await?;
+    if !integrity {
+        assert_eq!(status, None);
+        let error = rotate_command(&volume_config.volume, true)
+            .run()
+            .await
+            .expect_err("there is no integrity key to rotate");
+        assert!(
+            format!("{error:#}").contains("not enabled"),
+            "unexpected error: {error:#}"
+        );
+        return Ok(());
+    }
+
+    let status = status.expect("integrity should be enabled");
+    assert_eq!(status.algorithm, "hmac(sha256)");
+    assert_eq!(status.integrity_key_size, Some(256));
+    assert_eq!(status.volume_key_size, Some(768));
+    assert!(status.volume_key_digest.is_some());
+
+    // The volume key, and so the integrity key, is regenerated by reformatting
+    rotate_command(&volume_config.volume, true).run().await?;
+    let new_status = cryptpilot::fs::luks2::get_integrity_status(&dummy_device.path()?)
+        .await?
+        .expect("integrity should still be enabled");
+    assert_eq!(new_status.algorithm, status.algorithm);
+    assert_ne!(new_status.volume_key_digest, status.volume_key_digest);
+
+    // The passphrase from the key provider still unlocks the reformatted volume
+    cryptpilot::fs::luks2::verify_passphrase(
+        &dummy_device.path()?,
+        &cryptpilot::types::Passphrase::from(b"test-passphrase".to_vec()),
+    )
+    .await?;
+
+    // Rotation is refused when the integrity is disabled in the config
+    let mut disabled_config = volume_config.clone();
+    disabled_config.extra_config.integrity = Some(false);
+    set_volumes(vec![disabled_config]).await;
+    let error = rotate_command(&volume_config.volume, true)
+        .run()
+        .await
+        .expect_err("rotation should be refused");
+    assert!(
+        format!("{error:#}").contains("disabled in the config"),
+        "unexpected error: {error:#}"
+    );
+
+    Ok(())
+}
@@ -0,0 +1,76 @@
+// LUKS2 sector size tests
+
+use std::path::Path;
+
+use cryptpilot::fs::{
+    block::dummy::DummyDevice,
+    cmd::CheckCommandOutput as _,
+    luks2::{format, get_logical_block_size},
+};
+use cryptpilot::types::{IntegrityType, Luks2Cipher, Passphrase};
+
+use anyhow::{Context as _, Result};
+use tokio::process::Command;
+
+/// Get the sector size of the data segment from the LUKS2 header of the device.
+async fn luks2_sector_size(dev: &Path) -> Result<u32> {
+    let output = Command::new("cryptsetup")
+        .arg("luksDump")
+        .arg(dev)
+        .env("LC_ALL", "C")
+        .run()
+        .await?;
+    String::from_utf8_lossy(&output)
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("sector: "))
+        .and_then(|value| value.split_whitespace().next())
+        .context("No sector size found in LUKS2 header")?
+        .parse()
+        .context("Invalid sector size in LUKS2 header")
+}
+
+#[rstest::rstest]
+#[case(512, None, 512)]
+#[case(4096, None, 4096)]
+#[case(512, Some(512), 512)]
+#[case(512, Some(4096), 4096)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_format_with_sector_size(
+    #[case] block_size: u64,
+    #[case] sector_size: Option<u32>,
+    #[case] expected_sector_size: u32,
+) -> Result<()> {
+    let dummy = DummyDevice::setup_on_tmpfs_with_block_size(100 * 1024 * 1024, block_size).await?;
+    let dev = dummy.path()?;
+    assert_eq!(get_logical_block_size(&dev).await?, block_size as u32);
+
+    let passphrase = Passphrase::from(b"test-passphrase-1234567890123456".to_vec());
+    format(
+        &dev,
+        &passphrase,
+        IntegrityType::None,
+        Luks2Cipher::default(),
+        sector_size,
+    )
+    .await?;
+
+    assert_eq!(luks2_sector_size(&dev).await?, expected_sector_size);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_format_with_invalid_sector_size() -> Result<()> {
+    let dummy = DummyDevice::setup_on_tmpfs(100 * 1024 * 1024).await?;
+    let passphrase = Passphrase::from(b"test-passphrase-1234567890123456".to_vec());
+
+    assert!(format(
+        &dummy.path()?,
+        &passphrase,
+        IntegrityType::None,
+        Luks2Cipher::default(),
+        Some(1000)
+    )
+    .await
+    .is_err());
+    Ok(())
+}
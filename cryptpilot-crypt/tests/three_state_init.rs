@@ -8,7 +8,7 @@ use cryptpilot::fs::{
     cmd::CheckCommandOutput as _,
     luks2::{format, get_init_state, is_initialized, mark_volume_as_initialized, VolumeInitState},
 };
-use cryptpilot::types::{IntegrityType, MakeFsType, Passphrase};
+use cryptpilot::types::{IntegrityType, Luks2Cipher, MakeFsType, Passphrase};
 
 use anyhow::Result;
 use tokio::process::Command;
@@ -28,7 +28,14 @@ async fn test_init_state_initializing_after_format() -> Result<()> {
     let dummy = DummyDevice::setup_on_tmpfs(100 * 1024 * 1024).await?;
     let passphrase = Passphrase::from(b"test-passphrase-1234567890123456".to_vec());
 
-    format(Path::new(&dummy.path()?), &passphrase, IntegrityType::None).await?;
+    format(
+        Path::new(&dummy.path()?),
+        &passphrase,
+        IntegrityType::None,
+        Luks2Cipher::default(),
+        None,
+    )
+    .await?;
 
     let state = get_init_state(Path::new(&dummy.path()?)).await?;
     assert_eq!(state, VolumeInitState::Initializing);
@@ -41,7 +48,14 @@ async fn test_init_state_ready_after_mark() -> Result<()> {
     let dummy = DummyDevice::setup_on_tmpfs(100 * 1024 * 1024).await?;
     let passphrase = Passphrase::from(b"test-passphrase-1234567890123456".to_vec());
 
-    format(Path::new(&dummy.path()?), &passphrase, IntegrityType::None).await?;
+    format(
+        Path::new(&dummy.path()?),
+        &passphrase,
+        IntegrityType::None,
+        Luks2Cipher::default(),
+        None,
+    )
+    .await?;
     mark_volume_as_initialized(Path::new(&dummy.path()?)).await?;
 
     let state = get_init_state(Path::new(&dummy.path()?)).await?;
@@ -59,7 +73,14 @@ async fn test_is_initialized_only_true_for_ready() -> Result<()> {
     assert!(!is_initialized(Path::new(&dummy.path()?)).await?);
 
     // After format: is_initialized = false (Initializing state)
-    format(Path::new(&dummy.path()?), &passphrase, IntegrityType::None).await?;
+    format(
+        Path::new(&dummy.path()?),
+        &passphrase,
+        IntegrityType::None,
+        Luks2Cipher::default(),
+        None,
+    )
+    .await?;
     assert!(!is_initialized(Path::new(&dummy.path()?)).await?);
 
     // After mark: is_initialized = true (Ready state)
@@ -89,7 +110,14 @@ async fn test_full_init_lifecycle_with_blkid_probe() -> Result<()> {
     assert_eq!(state, VolumeInitState::None, "Expected None before format");
 
     // Step 2: Format → Initializing
-    format(Path::new(&dev_path), &passphrase, IntegrityType::None).await?;
+    format(
+        Path::new(&dev_path),
+        &passphrase,
+        IntegrityType::None,
+        Luks2Cipher::default(),
+        None,
+    )
+    .await?;
     let state = get_init_state(Path::new(&dev_path)).await?;
     assert_eq!(
         state,
@@ -109,6 +137,7 @@ async fn test_full_init_lifecycle_with_blkid_probe() -> Result<()> {
     cryptpilot::fs::mkfs::force_mkfs(
         &tmp_volume.volume_path(),
         &MakeFsType::Ext4,
+        None,
         IntegrityType::None,
     )
     .await?;
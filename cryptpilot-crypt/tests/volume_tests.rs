@@ -1,7 +1,13 @@
 // Volume integration tests
-// These test the complete volume lifecycle: init, open, use, close
+// These test the options of opening and closing a volume on a dummy device. The key providers are
+// tested through the whole volume lifecycle in the provider_*.rs tests.
 
-#![allow(unused_macros)]
+mod common;
+
+use common::{
+    close_options, close_volume, init_options, init_volume, open_options, open_volume, set_volumes,
+    VolumeConfigBuilder,
+};
 
 use std::{
     future::Future,
@@ -9,406 +15,1115 @@ use std::{
 };
 
 use cryptpilot_crypt::{
-    async_defer,
     cli::{CloseOptions, InitOptions, OpenOptions},
     cmd::{close::CloseCommand, init::InitCommand, open::OpenCommand, Command as _},
-    config::{
-        source::{set_volume_config_source, VolumeConfigSource},
-        volume::VolumeConfig,
-    },
+    config::volume::VolumeConfig,
 };
 
 use cryptpilot::{
-    fs::{block::dummy::DummyDevice, cmd::CheckCommandOutput as _, mount::TmpMountPoint},
-    provider::{IntoProvider, KeyProvider, VolumeType},
-    types::MakeFsType,
+    fs::{
+        block::{devicemapper::set_dm_name_prefix, dummy::DummyDevice},
+        cmd::CheckCommandOutput as _,
+        luks2::{format, mark_volume_as_initialized},
+    },
+    provider::{
+        registry::{register_key_provider, DynKeyProvider},
+        KeyProvider, VolumeType,
+    },
+    types::{FsMismatchPolicy, IntegrityTuning, IntegrityType, Luks2Cipher, Passphrase},
 };
 
-use anyhow::Result;
+use anyhow::{anyhow, Context as _, Result};
 use async_trait::async_trait;
-use block_devs::BlckExt as _;
-use cgroups_rs::{cgroup_builder::CgroupBuilder, Cgroup, CgroupPid};
 use tokio::{
-    fs::File,
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncReadExt as _, AsyncSeekExt as _, AsyncWriteExt as _},
     process::Command,
 };
-use tokio_util::bytes::BytesMut;
 
-use rstest_reuse::template;
+/// The options of `open --check-fs` for the volume.
+fn check_fs_options(volume: &str) -> OpenOptions {
+    OpenOptions {
+        check_fs: true,
+        ..open_options(volume)
+    }
+}
+
+/// Open the volume with the options, run `f` while it is open, and close it.
+async fn open_and_close_with<F, Fut>(open_options: OpenOptions, f: F) -> Result<()>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let volume = open_options.volume[0].clone();
+    OpenCommand { open_options }.run().await?;
+    assert!(cryptpilot::fs::luks2::is_active(&volume));
+
+    f().await?;
 
-struct InMemoryVolumeConfigSource {
-    volumes: Vec<VolumeConfig>,
+    close_volume(&volume).await
 }
 
-#[async_trait]
-impl VolumeConfigSource for InMemoryVolumeConfigSource {
-    fn source_debug_string(&self) -> String {
-        "in-memory test volume config".to_owned()
-    }
+/// The config of a volume with a fixed passphrase, and the given settings.
+fn exec_volume_config(dummy_device: &DummyDevice, config: &str) -> Result<VolumeConfig> {
+    VolumeConfigBuilder::new(dummy_device.path()?)
+        .config(format!(
+            r#"
+            {config}
+
+            [encrypt.exec]
+            command = "echo"
+            args = ["-n", "test-passphrase"]
+            "#
+        ))
+        .build()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn test_open_map_existing() -> Result<()> {
+    // Set test mode environment variable to skip some external binary checks
+    std::env::set_var("CRYPTPILOT_TEST_MODE", "1");
+
+    let dummy_device = DummyDevice::setup_on_tmpfs(1024 * 1024 * 1024).await?;
+    let other_dummy_device = DummyDevice::setup_on_tmpfs(1024 * 1024 * 1024).await?;
+
+    let volume_config = VolumeConfigBuilder::new(dummy_device.path()?).build()?;
+    set_volumes(vec![volume_config.clone()]).await;
 
-    async fn get_volume_configs(&self) -> Result<Vec<VolumeConfig>> {
-        Ok(self.volumes.clone())
+    let open = |map_existing| OpenCommand {
+        open_options: OpenOptions {
+            map_existing,
+            ..open_options(&volume_config.volume)
+        },
+    };
+
+    open(false).run().await?;
+    assert!(cryptpilot::fs::luks2::is_active(&volume_config.volume));
+
+    let result = async {
+        // Opening the active volume again is idempotent, with or without `--map-existing`
+        open(false).run().await?;
+        open(true).run().await?;
+        open(true).run().await?;
+        assert!(cryptpilot::fs::luks2::is_active(&volume_config.volume));
+
+        // With `--map-existing`, the mapping is not reused if it is backed by another device than
+        // the configured one
+        let mut other_volume_config = volume_config.clone();
+        other_volume_config.dev = other_dummy_device.path()?;
+        set_volumes(vec![other_volume_config]).await;
+        open(false).run().await?;
+        let error = open(true)
+            .run()
+            .await
+            .expect_err("open should fail when the mapping is backed by another device");
+        assert!(
+            format!("{error:#}").contains("instead of the configured device"),
+            "unexpected error: {error:#}"
+        );
+
+        set_volumes(vec![volume_config.clone()]).await;
+        Ok::<_, anyhow::Error>(())
     }
+    .await;
+
+    close_volume(&volume_config.volume).await?;
+
+    result
 }
 
-#[template]
-#[rstest]
-#[tokio::test(flavor = "multi_thread", worker_threads = 10)]
-#[two_rusty_forks::test_fork]
-pub async fn test_volume_base(
-    #[values("swap", "ext4", "xfs", "vfat")] makefs: &str,
-    #[values(false, true)] integrity: bool,
+#[rstest::rstest]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn test_open_with_discard(
+    #[values(None, Some(false), Some(true))] discard: Option<bool>,
 ) -> Result<()> {
+    // Set test mode environment variable to skip some external binary checks
+    std::env::set_var("CRYPTPILOT_TEST_MODE", "1");
+
+    let dummy_device = DummyDevice::setup_on_tmpfs(1024 * 1024 * 1024).await?;
+
+    let mut volume_config = VolumeConfigBuilder::new(dummy_device.path()?).build()?;
+    volume_config.extra_config.discard = discard;
+
+    set_volumes(vec![volume_config.clone()]).await;
+
+    open_volume(&volume_config.volume).await?;
+
+    let table = Command::new("dmsetup")
+        .arg("table")
+        .arg(&volume_config.volume)
+        .run()
+        .await
+        .map(|stdout| String::from_utf8_lossy(&stdout).to_string());
+
+    close_volume(&volume_config.volume).await?;
+
+    assert_eq!(
+        table?.contains("allow_discards"),
+        discard == Some(true),
+        "unexpected dm-crypt table of volume {}",
+        volume_config.volume
+    );
+
+    Ok(())
 }
 
-pub async fn open_then<F, T>(volume_config: &VolumeConfig, task: F) -> Result<()>
-where
-    F: FnOnce(VolumeConfig) -> T,
-    T: Future<Output = Result<()>>,
-{
+// The prefix is process-wide, so the test runs in a forked process
+#[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+#[two_rusty_forks::test_fork]
+async fn test_dm_name_prefix_applied() -> Result<()> {
+    // Set test mode environment variable to skip some external binary checks
+    std::env::set_var("CRYPTPILOT_TEST_MODE", "1");
+
+    let prefix = format!("test{}-", rand::random::<u32>());
+    set_dm_name_prefix(&prefix)?;
+
+    let dummy_device = DummyDevice::setup_on_tmpfs(10 * 1024 * 1024 * 1024).await?;
+
+    let volume_config = VolumeConfigBuilder::new(dummy_device.path()?)
+        .volume(format!("data{}", rand::random::<u32>()))
+        .config(
+            r#"
+            makefs = "ext4"
+
+            [encrypt.otp]
+            "#,
+        )
+        .build()?;
+
+    set_volumes(vec![volume_config.clone()]).await;
+
+    let mapper_path = Path::new("/dev/mapper").join(format!("{prefix}{}", volume_config.volume));
+    assert_eq!(volume_config.volume_path(), mapper_path);
+
     OpenCommand {
-        open_options: OpenOptions {
-            volume: vec![volume_config.volume.clone()],
-            check_fs: false,
-        },
+        open_options: check_fs_options(&volume_config.volume),
     }
     .run()
     .await?;
 
-    async_defer! {
-        async{
-            CloseCommand{
-                close_options: CloseOptions{
-                    volume: vec![volume_config.volume.clone()],
-                }
-            }.run().await?;
-            Ok::<_, anyhow::Error>(())
-        }
-    }
+    // The mapping is set up with the prefix only
+    assert!(mapper_path.exists());
+    assert!(!Path::new("/dev/mapper")
+        .join(&volume_config.volume)
+        .exists());
+    assert!(cryptpilot::fs::luks2::is_active(&volume_config.volume));
+
+    close_volume(&volume_config.volume).await?;
+    assert!(!mapper_path.exists());
 
-    task(volume_config.clone()).await
+    Ok(())
 }
 
-pub async fn open_and_mount<F, T>(volume_config: &VolumeConfig, task: F) -> Result<()>
-where
-    F: FnOnce(VolumeConfig, PathBuf) -> T,
-    T: Future<Output = Result<()>>,
-{
-    open_then(volume_config, |volume_config| async move {
-        let tmp_mount = TmpMountPoint::mount(volume_config.volume_path(), true).await?;
+#[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+#[serial_test::serial]
+async fn test_first_open_mount_options_only_on_first_open() -> Result<()> {
+    // Set test mode environment variable to skip some external binary checks
+    std::env::set_var("CRYPTPILOT_TEST_MODE", "1");
+
+    let dummy_device = DummyDevice::setup_on_tmpfs(10 * 1024 * 1024 * 1024).await?;
+    let log = std::env::temp_dir().join(format!(
+        "cryptpilot-first-open-{}.log",
+        rand::random::<u64>()
+    ));
+
+    let volume_config = exec_volume_config(
+        &dummy_device,
+        &format!(
+            r#"
+            makefs = "ext4"
+            first_open_mount_options = "nodiscard"
+            post_open = ["sh", "-c", "echo \"[$CRYPTPILOT_MOUNT_OPTIONS]\" >> {}"]
+            "#,
+            log.to_string_lossy()
+        ),
+    )?;
+
+    set_volumes(vec![volume_config.clone()]).await;
+
+    init_volume(&volume_config.volume).await?;
+    assert!(cryptpilot::fs::luks2::is_fs_fresh(&dummy_device.path()?).await?);
+    assert!(cryptpilot::fs::luks2::is_initialized(&dummy_device.path()?).await?);
+
+    // The options are passed on the first open only
+    open_and_close_with(check_fs_options(&volume_config.volume), || async { Ok(()) }).await?;
+    assert!(!cryptpilot::fs::luks2::is_fs_fresh(&dummy_device.path()?).await?);
+    assert!(cryptpilot::fs::luks2::is_initialized(&dummy_device.path()?).await?);
+    open_and_close_with(check_fs_options(&volume_config.volume), || async { Ok(()) }).await?;
+
+    assert_eq!(tokio::fs::read_to_string(&log).await?, "[nodiscard]\n[]\n");
+
+    tokio::fs::remove_file(&log).await?;
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+#[serial_test::serial]
+async fn test_fsck_ext4_after_open() -> Result<()> {
+    // Set test mode environment variable to skip some external binary checks
+    std::env::set_var("CRYPTPILOT_TEST_MODE", "1");
+
+    let dummy_device = DummyDevice::setup_on_tmpfs(10 * 1024 * 1024 * 1024).await?;
+
+    let volume_config = exec_volume_config(
+        &dummy_device,
+        r#"
+        makefs = "ext4"
+        fsck = true
+        "#,
+    )?;
+
+    set_volumes(vec![volume_config.clone()]).await;
+
+    init_volume(&volume_config.volume).await?;
+
+    // A clean file system
+    open_and_close_with(check_fs_options(&volume_config.volume), || async { Ok(()) }).await?;
 
-        task(volume_config, tmp_mount.mount_point().to_path_buf()).await
+    // Record errors in the superblock, which are corrected by fsck on the next open
+    open_and_close_with(check_fs_options(&volume_config.volume), || async {
+        Command::new("debugfs")
+            .arg("-w")
+            .arg("-R")
+            .arg("ssv state 2")
+            .arg(volume_config.volume_path())
+            .run()
+            .await?;
+        Ok(())
     })
-    .await
+    .await?;
+    open_and_close_with(check_fs_options(&volume_config.volume), || async {
+        let output = Command::new("dumpe2fs")
+            .arg("-h")
+            .arg(volume_config.volume_path())
+            .run()
+            .await?;
+        assert!(String::from_utf8_lossy(&output)
+            .lines()
+            .any(|line| line.starts_with("Filesystem state:") && line.ends_with(" clean")));
+        Ok(())
+    })
+    .await?;
+
+    Ok(())
 }
 
-pub async fn open_and_swapon<F, T>(volume_config: &VolumeConfig, task: F) -> Result<()>
-where
-    F: FnOnce(VolumeConfig) -> T,
-    T: Future<Output = Result<()>>,
-{
-    open_then(volume_config, |volume_config| async move {
-        Command::new("swapon")
+#[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+#[serial_test::serial]
+async fn test_makefs_mismatch_after_open() -> Result<()> {
+    // Set test mode environment variable to skip some external binary checks
+    std::env::set_var("CRYPTPILOT_TEST_MODE", "1");
+
+    let dummy_device = DummyDevice::setup_on_tmpfs(10 * 1024 * 1024 * 1024).await?;
+
+    let mut volume_config = exec_volume_config(
+        &dummy_device,
+        r#"
+        makefs = "ext4"
+        makefs_mismatch = "fail"
+        "#,
+    )?;
+
+    set_volumes(vec![volume_config.clone()]).await;
+
+    init_volume(&volume_config.volume).await?;
+
+    // Replace the ext4 fs created by init with xfs, as if the volume was provisioned differently
+    open_and_close_with(open_options(&volume_config.volume), || async {
+        Command::new("mkfs.xfs")
+            .arg("-f")
             .arg(volume_config.volume_path())
             .run()
             .await?;
+        Ok(())
+    })
+    .await?;
+
+    // The volume is closed on the mismatch
+    let error = open_and_close_with(open_options(&volume_config.volume), || async { Ok(()) })
+        .await
+        .unwrap_err();
+    assert!(
+        format!("{error:#}").contains("does not match the configured makefs \"ext4\""),
+        "{error:#}"
+    );
+    assert!(!cryptpilot::fs::luks2::is_active(&volume_config.volume));
+
+    // The mismatch is only warned about
+    volume_config.extra_config.makefs_mismatch = Some(FsMismatchPolicy::Warn);
+    set_volumes(vec![volume_config.clone()]).await;
+    open_and_close_with(open_options(&volume_config.volume), || async { Ok(()) }).await?;
+
+    Ok(())
+}
+
+#[rstest::rstest]
+#[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+#[serial_test::serial]
+async fn test_open_with_integrity_mismatch(
+    #[values(false, true)] integrity_on_init: bool,
+) -> Result<()> {
+    // Set test mode environment variable to skip some external binary checks
+    std::env::set_var("CRYPTPILOT_TEST_MODE", "1");
+
+    let dummy_device = DummyDevice::setup_on_tmpfs(1024 * 1024 * 1024).await?;
+
+    let mut volume_config = exec_volume_config(&dummy_device, "")?;
+    volume_config.extra_config.integrity = Some(integrity_on_init);
+
+    set_volumes(vec![volume_config.clone()]).await;
+
+    init_volume(&volume_config.volume).await?;
+    assert_eq!(
+        cryptpilot::fs::luks2::is_integrity_enabled(&dummy_device.path()?).await?,
+        integrity_on_init
+    );
 
-        async_defer! {
-            async{
-                Command::new("swapoff")
-                    .arg(volume_config.volume_path())
-                    .run()
-                    .await?;
-                Ok::<_, anyhow::Error>(())
-            }
+    // Toggle the integrity in the config, which does not match the device anymore
+    let mut mismatched_config = volume_config.clone();
+    mismatched_config.extra_config.integrity = Some(!integrity_on_init);
+    set_volumes(vec![mismatched_config]).await;
+
+    let error = open_volume(&volume_config.volume)
+        .await
+        .expect_err("open should fail when the integrity setting does not match");
+    assert!(
+        format!("{error:#}").contains("cannot be toggled without reformatting"),
+        "unexpected error: {error:#}"
+    );
+    assert!(!cryptpilot::fs::luks2::is_active(&volume_config.volume));
+
+    // The volume can still be opened with the original config
+    set_volumes(vec![volume_config.clone()]).await;
+    open_volume(&volume_config.volume).await?;
+    assert!(cryptpilot::fs::luks2::is_active(&volume_config.volume));
+
+    close_volume(&volume_config.volume).await?;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+#[serial_test::serial]
+async fn test_open_with_integrity_tuning() -> Result<()> {
+    // Set test mode environment variable to skip some external binary checks
+    std::env::set_var("CRYPTPILOT_TEST_MODE", "1");
+
+    let dummy_device = DummyDevice::setup_on_tmpfs(1024 * 1024 * 1024).await?;
+
+    let volume_config = VolumeConfigBuilder::new(dummy_device.path()?)
+        .config(
+            r#"
+            integrity = true
+            makefs = "ext4"
+
+            [integrity_tuning]
+            journal = true
+            journal_watermark = 80
+            journal_commit_time = 1000
+
+            [encrypt.exec]
+            command = "echo"
+            args = ["-n", "test-passphrase"]
+            "#,
+        )
+        .build()?;
+
+    set_volumes(vec![volume_config.clone()]).await;
+
+    init_volume(&volume_config.volume).await?;
+
+    open_volume(&volume_config.volume).await?;
+    let table = Command::new("dmsetup")
+        .arg("table")
+        .arg(format!("{}_dif", volume_config.volume))
+        .run()
+        .await
+        .map(|stdout| String::from_utf8_lossy(&stdout).to_string());
+    close_volume(&volume_config.volume).await?;
+
+    let table = table?;
+    assert!(
+        table.contains(" J "),
+        "unexpected dm-integrity table {table}"
+    );
+    assert!(table.contains("journal_watermark:80"));
+    assert!(table.contains("commit_time:1000"));
+
+    // An invalid tuning is rejected before the volume is touched
+    let mut invalid_config = volume_config.clone();
+    invalid_config.extra_config.integrity_tuning = Some(IntegrityTuning {
+        journal_watermark: Some(101),
+        ..Default::default()
+    });
+    set_volumes(vec![invalid_config]).await;
+    assert!(InitCommand {
+        init_options: InitOptions {
+            force_reinit: true,
+            ..init_options(&volume_config.volume)
+        },
+    }
+    .run()
+    .await
+    .is_err());
+    assert!(cryptpilot::fs::luks2::is_initialized(&volume_config.dev).await?);
+
+    Ok(())
+}
+
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Fill the whole device with a known pattern, so that every sector has a valid integrity tag.
+async fn fill_device(dev: &Path) -> Result<()> {
+    let mut file = tokio::fs::OpenOptions::new().write(true).open(dev).await?;
+    let size = file.seek(std::io::SeekFrom::End(0)).await?;
+    file.seek(std::io::SeekFrom::Start(0)).await?;
+
+    let chunk = vec![0x5a; CHUNK_SIZE];
+    let mut written = 0;
+    while written < size {
+        let len = (size - written).min(CHUNK_SIZE as u64) as usize;
+        file.write_all(&chunk[..len]).await?;
+        written += len as u64;
+    }
+    file.sync_all().await?;
+    Ok(())
+}
+
+/// Read the whole device and check the pattern written by [`fill_device`].
+async fn read_device(dev: &Path) -> std::io::Result<()> {
+    let mut file = tokio::fs::File::open(dev).await?;
+    let mut chunk = vec![0; CHUNK_SIZE];
+    loop {
+        let len = file.read(&mut chunk).await?;
+        if len == 0 {
+            return Ok(());
         }
+        assert!(chunk[..len].iter().all(|byte| *byte == 0x5a));
+    }
+}
 
-        task(volume_config.clone()).await
-    })
+#[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+#[serial_test::serial]
+async fn test_read_corrupted_data_with_integrity() -> Result<()> {
+    // Set test mode environment variable to skip some external binary checks
+    std::env::set_var("CRYPTPILOT_TEST_MODE", "1");
+
+    let device_size = 64 * 1024 * 1024;
+    let dummy_device = DummyDevice::setup_on_tmpfs(device_size).await?;
+
+    let mut volume_config = exec_volume_config(&dummy_device, "")?;
+    volume_config.extra_config.integrity = Some(true);
+
+    set_volumes(vec![volume_config.clone()]).await;
+
+    init_volume(&volume_config.volume).await?;
+
+    open_volume(&volume_config.volume).await?;
+    fill_device(&volume_config.volume_path()).await?;
+    read_device(&volume_config.volume_path())
+        .await
+        .context("The data should be readable before corruption")?;
+    close_volume(&volume_config.volume).await?;
+
+    // Corrupt the middle of the device, which is far behind the LUKS2 header and is covered by
+    // either the data or the integrity tags of the written sectors.
+    cryptpilot::fs::block::corrupt::corrupt_bytes(&dummy_device.path()?, device_size / 2, 4096)
+        .await?;
+
+    open_volume(&volume_config.volume).await?;
+    let result = read_device(&volume_config.volume_path()).await;
+    close_volume(&volume_config.volume).await?;
+
+    let error = result.expect_err("reading corrupted data should fail");
+    // EIO
+    assert_eq!(error.raw_os_error(), Some(5), "unexpected error: {error:?}");
+
+    Ok(())
+}
+
+async fn write_override_file(content: &str) -> Result<PathBuf> {
+    let path = std::env::temp_dir().join(format!(
+        "cryptpilot-key-provider-override-{}.toml",
+        rand::random::<u64>()
+    ));
+    tokio::fs::write(&path, content).await?;
+    Ok(path)
+}
+
+async fn open_with_override(volume: &str, key_provider_override: PathBuf) -> Result<()> {
+    OpenCommand {
+        open_options: OpenOptions {
+            key_provider_override: Some(key_provider_override),
+            ..open_options(volume)
+        },
+    }
+    .run()
     .await
 }
 
-pub async fn run_test_on_volume(config_str: &str, use_external_suite: bool) -> Result<()> {
-    // Set test mode environment variable to skip external binary checks
+#[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+#[serial_test::serial]
+async fn test_open_with_key_provider_override() -> Result<()> {
+    // Set test mode environment variable to skip some external binary checks
     std::env::set_var("CRYPTPILOT_TEST_MODE", "1");
 
-    let mut volume_config: VolumeConfig = toml::from_str(config_str)?;
+    let dummy_device = DummyDevice::setup_on_tmpfs(10 * 1024 * 1024 * 1024).await?;
+
+    // Initialize the volume with a local key provider, which plays the role of the escrowed key.
+    let mut volume_config = VolumeConfigBuilder::new(dummy_device.path()?)
+        .config(
+            r#"
+            makefs = "ext4"
+
+            [encrypt.exec]
+            command = "echo"
+            args = ["-n", "escrowed-passphrase"]
+            "#,
+        )
+        .build()?;
+
+    set_volumes(vec![volume_config.clone()]).await;
 
-    // Random volume name
-    volume_config.volume = format!("test-{}", rand::random::<u64>());
+    init_volume(&volume_config.volume).await?;
 
-    let dummy_device = if volume_config.extra_config.makefs == Some(MakeFsType::Swap) {
-        DummyDevice::setup_on_cache_dir(1024 * 1024 * 1024 /* 1G */).await?
-    } else {
-        DummyDevice::setup_on_tmpfs(100 * 1024 * 1024 * 1024 /* 100G */).await?
+    // Then switch the configured key provider to an unreachable KBS.
+    volume_config.encrypt = toml::from_str(
+        r#"
+        [kbs]
+        kbs_url = "https://1.2.3.4:8080"
+        key_uri = "kbs:///default/mykey/volume_data0"
+        "#,
+    )?;
+    set_volumes(vec![volume_config.clone()]).await;
+
+    // An override with a temporary key provider would re-format the volume, so it is rejected.
+    let otp_override = write_override_file("[otp]\n").await?;
+    assert!(
+        open_with_override(&volume_config.volume, otp_override.clone())
+            .await
+            .is_err()
+    );
+    assert!(!cryptpilot::fs::luks2::is_active(&volume_config.volume));
+    tokio::fs::remove_file(&otp_override).await?;
+
+    // An override with a wrong passphrase is rejected by the passphrase check.
+    let wrong_override = write_override_file(
+        r#"
+        [exec]
+        command = "echo"
+        args = ["-n", "wrong-passphrase"]
+        "#,
+    )
+    .await?;
+    assert!(
+        open_with_override(&volume_config.volume, wrong_override.clone())
+            .await
+            .is_err()
+    );
+    assert!(!cryptpilot::fs::luks2::is_active(&volume_config.volume));
+    tokio::fs::remove_file(&wrong_override).await?;
+
+    // The override holding the escrowed key opens the volume.
+    let exec_override = write_override_file(
+        r#"
+        [exec]
+        command = "echo"
+        args = ["-n", "escrowed-passphrase"]
+        "#,
+    )
+    .await?;
+    open_with_override(&volume_config.volume, exec_override.clone()).await?;
+    assert!(cryptpilot::fs::luks2::is_active(&volume_config.volume));
+    tokio::fs::remove_file(&exec_override).await?;
+
+    close_volume(&volume_config.volume).await?;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+#[serial_test::serial]
+async fn test_open_with_retries() -> Result<()> {
+    // Set test mode environment variable to skip some external binary checks
+    std::env::set_var("CRYPTPILOT_TEST_MODE", "1");
+
+    let dummy_device = DummyDevice::setup_on_tmpfs(256 * 1024 * 1024).await?;
+    let temp_dir = tempfile::tempdir()?;
+    let marker = temp_dir.path().join("attempted");
+
+    // A key provider which fails on the first attempt, and succeeds once the marker is created
+    let volume_config = VolumeConfigBuilder::new(dummy_device.path()?)
+        .config(format!(
+            r#"
+            [encrypt.exec]
+            command = "sh"
+            args = ["-c", "if [ -e '{marker}' ]; then echo -n test-passphrase; else touch '{marker}'; exit 1; fi"]
+            "#,
+            marker = marker.display()
+        ))
+        .build()?;
+
+    set_volumes(vec![volume_config.clone()]).await;
+
+    tokio::fs::write(&marker, "").await?;
+    init_volume(&volume_config.volume).await?;
+
+    let open = |retries| OpenCommand {
+        open_options: OpenOptions {
+            retries,
+            retry_delay: 0,
+            ..open_options(&volume_config.volume)
+        },
     };
 
-    volume_config.dev = dummy_device.path()?;
+    // Without retries, the transient failure of the key provider fails the open
+    tokio::fs::remove_file(&marker).await?;
+    assert!(open(0).run().await.is_err());
+    assert!(!cryptpilot::fs::luks2::is_active(&volume_config.volume));
 
-    set_volume_config_source(InMemoryVolumeConfigSource {
-        volumes: vec![volume_config.clone()],
-    })
-    .await;
+    // With a retry, the second attempt succeeds
+    tokio::fs::remove_file(&marker).await?;
+    open(1).run().await?;
+    assert!(cryptpilot::fs::luks2::is_active(&volume_config.volume));
+
+    close_volume(&volume_config.volume).await?;
+
+    Ok(())
+}
+
+#[rstest::rstest]
+#[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+#[serial_test::serial]
+async fn test_open_with_kdf(#[values("hkdf-sha256", "argon2id")] algorithm: &str) -> Result<()> {
+    // Set test mode environment variable to skip some external binary checks
+    std::env::set_var("CRYPTPILOT_TEST_MODE", "1");
+
+    let dummy_device = DummyDevice::setup_on_tmpfs(256 * 1024 * 1024).await?;
+
+    let volume_config = VolumeConfigBuilder::new(dummy_device.path()?)
+        .config(format!(
+            r#"
+            [encrypt.exec]
+            command = "echo"
+            args = ["-n", "1234"]
+
+            [encrypt.kdf]
+            algorithm = "{algorithm}"
+            salt = "test-volume-salt"
+            "#
+        ))
+        .build()?;
+
+    set_volumes(vec![volume_config.clone()]).await;
+
+    init_volume(&volume_config.volume).await?;
+
+    // The volume is formatted with the derived passphrase, not the raw one from the key provider
+    let derived = volume_config
+        .encrypt
+        .kdf
+        .as_ref()
+        .expect("the KDF is configured")
+        .derive(&Passphrase::from(b"1234".to_vec()))
+        .await?;
+    cryptpilot::fs::luks2::verify_passphrase(&dummy_device.path()?, &derived).await?;
+    assert!(cryptpilot::fs::luks2::verify_passphrase(
+        &dummy_device.path()?,
+        &Passphrase::from(b"1234".to_vec())
+    )
+    .await
+    .is_err());
+
+    // The same KDF derives the same passphrase on every open
+    for _ in 0..2 {
+        open_volume(&volume_config.volume).await?;
+        assert!(cryptpilot::fs::luks2::is_active(&volume_config.volume));
+        close_volume(&volume_config.volume).await?;
+    }
+
+    // A volume formatted with the KDF cannot be opened without it
+    let mut without_kdf = volume_config.clone();
+    without_kdf.encrypt.kdf = None;
+    set_volumes(vec![without_kdf]).await;
+    assert!(open_volume(&volume_config.volume).await.is_err());
+    assert!(!cryptpilot::fs::luks2::is_active(&volume_config.volume));
+
+    Ok(())
+}
+
+/// A key provider in a key rotation window, which serves both the old and the new passphrases.
+struct RotatingKeyProvider {
+    keys: Vec<String>,
+}
+
+#[async_trait]
+impl KeyProvider for RotatingKeyProvider {
+    fn debug_name(&self) -> String {
+        "Rotating Key".to_owned()
+    }
+
+    async fn get_key(&self) -> Result<Passphrase> {
+        let key = self.keys.first().ok_or_else(|| anyhow!("No key"))?;
+        Ok(Passphrase::from(key.as_bytes().to_vec()))
+    }
+
+    async fn get_keys(&self) -> Result<Vec<Passphrase>> {
+        Ok(self
+            .keys
+            .iter()
+            .map(|key| Passphrase::from(key.as_bytes().to_vec()))
+            .collect())
+    }
+
+    fn volume_type(&self) -> VolumeType {
+        VolumeType::Persistent
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+#[serial_test::serial]
+async fn test_open_with_passphrase_candidates() -> Result<()> {
+    // Set test mode environment variable to skip some external binary checks
+    std::env::set_var("CRYPTPILOT_TEST_MODE", "1");
+
+    register_key_provider("test-rotating", |options| {
+        let keys = options
+            .get("keys")
+            .and_then(|keys| keys.as_array())
+            .ok_or_else(|| anyhow!("The keys are not set"))?
+            .iter()
+            .map(|key| key.as_str().map(ToOwned::to_owned))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| anyhow!("The keys should be strings"))?;
+        Ok(Box::new(RotatingKeyProvider { keys }) as DynKeyProvider)
+    })?;
+
+    let dummy_device = DummyDevice::setup_on_tmpfs(100 * 1024 * 1024).await?;
+    let dev = dummy_device.path()?;
+    format(
+        &dev,
+        &Passphrase::from(b"new-passphrase-1234567890123456".to_vec()),
+        IntegrityType::None,
+        Luks2Cipher::default(),
+        None,
+    )
+    .await?;
+    mark_volume_as_initialized(&dev).await?;
+
+    // Only the second candidate unlocks the volume
+    let volume_config = VolumeConfigBuilder::new(dev.clone())
+        .config(
+            r#"
+            [encrypt.custom]
+            tag = "test-rotating"
+            keys = ["old-passphrase-1234567890123456", "new-passphrase-1234567890123456"]
+            "#,
+        )
+        .build()?;
+
+    set_volumes(vec![volume_config.clone()]).await;
+
+    open_volume(&volume_config.volume).await?;
+    assert!(cryptpilot::fs::luks2::is_active(&volume_config.volume));
+
+    close_volume(&volume_config.volume).await?;
+    assert!(!cryptpilot::fs::luks2::is_active(&volume_config.volume));
+
+    // None of the candidates unlocks the volume
+    let wrong_config = VolumeConfigBuilder::new(dev)
+        .volume(volume_config.volume.clone())
+        .config(
+            r#"
+            [encrypt.custom]
+            tag = "test-rotating"
+            keys = ["old-passphrase-1234567890123456", "other-passphrase-123456789012345"]
+            "#,
+        )
+        .build()?;
+
+    set_volumes(vec![wrong_config]).await;
 
+    let error = open_volume(&volume_config.volume)
+        .await
+        .expect_err("open should fail when no candidate unlocks the volume");
+    assert!(
+        format!("{error:#}").contains("None of the 2 passphrase candidates"),
+        "unexpected error: {error:#}"
+    );
+    assert!(!cryptpilot::fs::luks2::is_active(&volume_config.volume));
+
+    Ok(())
+}
+
+async fn init_from_existing(volume: &str) -> Result<()> {
     InitCommand {
         init_options: InitOptions {
-            volume: vec![volume_config.volume.clone()],
-            force_reinit: false,
-            yes: true,
+            from_existing: true,
+            ..init_options(volume)
         },
     }
     .run()
-    .await?;
+    .await
+}
 
-    match &volume_config.extra_config.makefs {
-        Some(MakeFsType::Swap) => {
-            // Just Open it and checking
-            open_then(&volume_config, |volume_config| async move {
-                if !matches!(volume_config.extra_config.integrity, Some(true)) {
-                    assert!(
-                        cryptpilot::fs::mkfs::has_valuable_data(
-                            &volume_config.volume_path(),
-                            volume_config.extra_config.makefs
-                        )
-                        .await?
-                    );
-                }
-                Ok(())
-            })
-            .await?;
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn test_init_from_existing_luks2_volume() -> Result<()> {
+    // Set test mode environment variable to skip some external binary checks
+    std::env::set_var("CRYPTPILOT_TEST_MODE", "1");
 
-            // Open and swapon
-            open_and_swapon(&volume_config, |volume_config| async move {
-                if use_external_suite {
-                    let swap_device_size = File::open(volume_config.volume_path())
-                        .await?
-                        .into_std()
-                        .await
-                        .get_block_device_size()?;
-
-                    let hier = cgroups_rs::hierarchies::auto();
-                    let cg: Cgroup =
-                        CgroupBuilder::new(&format!("cryptpilot-test-{}", rand::random::<u64>()))
-                            .memory()
-                            .memory_hard_limit(128 * 1024 * 1024 /* 128M */)
-                            .memory_swap_limit(-1 /* infinity */)
-                            .done()
-                            .build(hier)?;
-
-                    let cg_clone = cg.clone();
-                    async_defer! {
-                        async{
-                            cg_clone.delete()
-                        }
-                    }
-
-                    // Run stress-ng to consume swap memory
-                    Command::new("stress-ng")
-                        .arg("--timeout")
-                        .arg("10")
-                        .arg("--vm")
-                        .arg("1")
-                        .arg("--vm-hang")
-                        .arg("0")
-                        .arg("--vm-method")
-                        .arg("zero-one")
-                        .arg("--vm-bytes")
-                        .arg(swap_device_size.to_string())
-                        .run_with_child_callback(move |pid| Ok(cg.add_task(CgroupPid::from(pid))?))
-                        .await?;
-                }
-
-                Ok(())
-            })
-            .await?;
-        }
-        Some(_) => {
-            // Just Open it and checking
-            open_then(&volume_config, |volume_config| async move {
-                if !matches!(volume_config.extra_config.integrity, Some(true)) {
-                    assert!(
-                        cryptpilot::fs::mkfs::has_valuable_data(
-                            &volume_config.volume_path(),
-                            volume_config.extra_config.makefs
-                        )
-                        .await?
-                    );
-                }
-                Ok(())
-            })
-            .await?;
+    // A LUKS2 device formatted by plain cryptsetup, with a file system on it.
+    let dummy_device = DummyDevice::setup_on_tmpfs(1024 * 1024 * 1024).await?;
+    let key_file = std::env::temp_dir().join(format!(
+        "cryptpilot-adopt-existing-{}.key",
+        rand::random::<u64>()
+    ));
+    tokio::fs::write(&key_file, "test-passphrase").await?;
+    Command::new("cryptsetup")
+        .args(["luksFormat", "--type", "luks2", "--batch-mode"])
+        .arg(dummy_device.path()?)
+        .arg(&key_file)
+        .run()
+        .await?;
+    let foreign_volume = format!("foreign-{}", rand::random::<u64>());
+    Command::new("cryptsetup")
+        .args(["open", "--key-file"])
+        .arg(&key_file)
+        .arg(dummy_device.path()?)
+        .arg(&foreign_volume)
+        .run()
+        .await?;
+    Command::new("mkfs.ext4")
+        .arg(format!("/dev/mapper/{foreign_volume}"))
+        .run()
+        .await?;
+    Command::new("cryptsetup")
+        .args(["close", &foreign_volume])
+        .run()
+        .await?;
+    tokio::fs::remove_file(&key_file).await?;
 
-            // Open and write file
-            open_and_mount(&volume_config, |_, mount_dir: PathBuf| async move {
-                let mut file = File::options()
-                    .write(true)
-                    .create(true)
-                    .truncate(true)
-                    .open(mount_dir.join("testfile"))
-                    .await?;
-                file.write_all("test".as_bytes()).await?;
-                file.flush().await?;
-                Ok(())
-            })
-            .await?;
+    let mut volume_config = VolumeConfigBuilder::new(dummy_device.path()?)
+        .config(
+            r#"
+            makefs = "ext4"
 
-            // Open again and read file
-            open_and_mount(
-                &volume_config,
-                |volume_config, mount_dir: PathBuf| async move {
-                    match volume_config
-                        .encrypt
-                        .key_provider
-                        .into_provider()
-                        .volume_type()
-                    {
-                        VolumeType::Temporary => {
-                            assert!(!mount_dir.join("testfile").exists())
-                        }
-                        VolumeType::Persistent => {
-                            let mut file: File = File::options()
-                                .read(true)
-                                .open(mount_dir.join("testfile"))
-                                .await?;
-                            let mut buf = BytesMut::new();
-                            file.read_buf(&mut buf).await?;
-                            assert_eq!("test".as_bytes(), &buf);
-                        }
-                    }
-                    Ok(())
-                },
-            )
-            .await?;
+            [encrypt.exec]
+            command = "echo"
+            args = ["-n", "wrong-passphrase"]
+            "#,
+        )
+        .build()?;
+    set_volumes(vec![volume_config.clone()]).await;
 
-            // Open and test fs stress
-            open_and_mount(&volume_config, |_, mount_dir: PathBuf| async move {
-                run_fs_stress(&mount_dir, PARALLEL_FS_STRESS).await?;
-                Ok(())
-            })
-            .await?;
+    // The volume is not adopted if the passphrase does not unlock it
+    assert!(init_from_existing(&volume_config.volume).await.is_err());
+    assert!(!cryptpilot::fs::luks2::is_initialized(&dummy_device.path()?).await?);
 
-            if use_external_suite {
-                // Open again and test with pjdfstest
-                open_and_mount(&volume_config, |_, mount_dir: PathBuf| async move {
-                    Command::new("prove")
-                        .arg("-rv")
-                        .arg("/tmp/pjdfstest/tests")
-                        .current_dir(mount_dir)
-                        .run()
-                        .await?;
-                    Ok(())
-                })
-                .await?;
-            }
-        }
-        None => {
-            // Just Open it and do nothing but checking
-            open_then(&volume_config, |volume_config| async move {
-                // Add assertion to check if disk is empty
-                assert!(
-                    !cryptpilot::fs::mkfs::has_valuable_data(
-                        &volume_config.volume_path(),
-                        volume_config.extra_config.makefs
-                    )
-                    .await?
-                );
-                Ok(())
-            })
-            .await?;
-            // Test again
-            open_then(&volume_config, |volume_config| async move {
-                // Add assertion to check if disk is still empty after re-open
-                assert!(
-                    !cryptpilot::fs::mkfs::has_valuable_data(
-                        &volume_config.volume_path(),
-                        volume_config.extra_config.makefs
-                    )
-                    .await?
-                );
-                Ok(())
-            })
-            .await?;
-        }
-    }
+    volume_config.encrypt = toml::from_str(
+        r#"
+        [exec]
+        command = "echo"
+        args = ["-n", "test-passphrase"]
+        "#,
+    )?;
+    set_volumes(vec![volume_config.clone()]).await;
+
+    init_from_existing(&volume_config.volume).await?;
+    assert!(cryptpilot::fs::luks2::is_initialized(&dummy_device.path()?).await?);
+
+    // Adopting it again is refused, since it is already initialized
+    assert!(init_from_existing(&volume_config.volume).await.is_err());
+
+    // The existing file system is kept
+    open_and_close_with(check_fs_options(&volume_config.volume), || async { Ok(()) }).await?;
 
     Ok(())
 }
 
-const PARALLEL_FS_STRESS: FsStressConfig = FsStressConfig {
-    workers: 16,
-    data_files_per_worker: 128,
-    tree_dirs_per_worker: 256,
-};
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn test_close_volume_without_config() -> Result<()> {
+    std::env::set_var("CRYPTPILOT_TEST_MODE", "1");
+
+    let dummy_device = DummyDevice::setup_on_tmpfs(1024 * 1024 * 1024).await?;
+    let volume_config = VolumeConfigBuilder::new(dummy_device.path()?).build()?;
+    set_volumes(vec![volume_config.clone()]).await;
 
-struct FsStressConfig {
-    workers: usize,
-    data_files_per_worker: usize,
-    tree_dirs_per_worker: usize,
+    open_volume(&volume_config.volume).await?;
+    assert!(cryptpilot::fs::luks2::is_active(&volume_config.volume));
+
+    // The config of the opened volume is gone, e.g. its config file is deleted
+    set_volumes(vec![]).await;
+    close_volume(&volume_config.volume).await?;
+    assert!(!cryptpilot::fs::luks2::is_active(&volume_config.volume));
+
+    Ok(())
 }
 
-async fn run_fs_stress(root: &Path, config: FsStressConfig) -> anyhow::Result<()> {
-    let data = vec![0u8; 1024 * 1024];
-    let mut tasks = Vec::with_capacity(config.workers);
-    for worker in 0..config.workers {
-        let worker_dir = root.join(format!("w{worker}"));
-        let data = data.clone();
-        let data_files_per_worker = config.data_files_per_worker;
-        tasks.push(tokio::spawn(async move {
-            tokio::fs::create_dir_all(&worker_dir).await?;
-            for file in 0..data_files_per_worker {
-                tokio::fs::write(worker_dir.join(format!("f{file}")), &data).await?;
-            }
-            std::io::Result::Ok(())
-        }));
-    }
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn test_force_close_busy_volume() -> Result<()> {
+    // Set test mode environment variable to skip some external binary checks
+    std::env::set_var("CRYPTPILOT_TEST_MODE", "1");
+
+    let dummy_device = DummyDevice::setup_on_tmpfs(1024 * 1024 * 1024).await?;
+
+    let volume_config = VolumeConfigBuilder::new(dummy_device.path()?).build()?;
+
+    set_volumes(vec![volume_config.clone()]).await;
+
+    open_volume(&volume_config.volume).await?;
+
+    let close = |force| CloseCommand {
+        close_options: CloseOptions {
+            force,
+            ..close_options(&volume_config.volume)
+        },
+    };
+    let is_mapped = || async {
+        Command::new("dmsetup")
+            .arg("info")
+            .arg(&volume_config.volume)
+            .run()
+            .await
+            .is_ok()
+    };
+
+    // Keep the decrypted device busy
+    let busy = std::fs::File::open(volume_config.volume_path())?;
 
-    let mut stress_error = None;
-    for task in tasks {
-        match task.await {
-            Ok(Ok(())) => {}
-            Ok(Err(err)) => {
-                stress_error.get_or_insert_with(|| anyhow::Error::new(err));
-            }
-            Err(err) => {
-                stress_error.get_or_insert_with(|| anyhow::Error::new(err));
-            }
+    let error = close(false)
+        .run()
+        .await
+        .expect_err("close should fail when the volume is busy");
+    assert!(
+        format!("{error:#}").contains("still in use"),
+        "unexpected error: {error:#}"
+    );
+    assert!(is_mapped().await);
+
+    // The mapping is kept until the device is released
+    close(true).run().await?;
+    assert!(is_mapped().await);
+
+    drop(busy);
+    let mut removed = false;
+    for _ in 0..50 {
+        if !is_mapped().await {
+            removed = true;
+            break;
         }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
     }
-    if let Some(err) = stress_error {
-        return Err(err);
+    assert!(
+        removed,
+        "the mapping of volume {} is not removed after the device is released",
+        volume_config.volume
+    );
+
+    Ok(())
+}
+
+/// Set up a volume with the file system on a dummy device, and open it.
+async fn setup_and_open(makefs: &str, dummy_device: &DummyDevice) -> Result<VolumeConfig> {
+    std::env::set_var("CRYPTPILOT_TEST_MODE", "1");
+
+    let volume_config = exec_volume_config(dummy_device, &format!("makefs = \"{makefs}\""))?;
+
+    set_volumes(vec![volume_config.clone()]).await;
+
+    init_volume(&volume_config.volume).await?;
+
+    OpenCommand {
+        open_options: check_fs_options(&volume_config.volume),
     }
+    .run()
+    .await?;
+    assert!(cryptpilot::fs::luks2::is_active(&volume_config.volume));
+
+    Ok(volume_config)
+}
 
-    let mut tasks = Vec::with_capacity(config.workers);
-    for worker in 0..config.workers {
-        let worker_tree = root.join("trees").join(format!("w{worker}"));
-        let tree_dirs_per_worker = config.tree_dirs_per_worker;
-        tasks.push(tokio::spawn(async move {
-            tokio::fs::create_dir_all(&worker_tree).await?;
-            for dir in 0..tree_dirs_per_worker {
-                let dir_path = worker_tree.join(format!("d{dir}"));
-                tokio::fs::create_dir_all(&dir_path).await?;
-                tokio::fs::write(dir_path.join("file"), format!("w{worker}-{dir}")).await?;
-            }
-            std::io::Result::Ok(())
-        }));
+fn close_with_teardown(volume_config: &VolumeConfig, teardown: bool) -> CloseCommand {
+    CloseCommand {
+        close_options: CloseOptions {
+            teardown,
+            ..close_options(&volume_config.volume)
+        },
     }
+}
 
-    let mut stress_error = None;
-    for task in tasks {
-        match task.await {
-            Ok(Ok(())) => {}
-            Ok(Err(err)) => {
-                stress_error.get_or_insert_with(|| anyhow::Error::new(err));
-            }
-            Err(err) => {
-                stress_error.get_or_insert_with(|| anyhow::Error::new(err));
-            }
-        }
+#[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+#[serial_test::serial]
+async fn test_close_teardown_mounted() -> Result<()> {
+    let dummy_device = DummyDevice::setup_on_tmpfs(1024 * 1024 * 1024).await?;
+    let volume_config = setup_and_open("ext4", &dummy_device).await?;
+
+    // Mount the volume twice, like a bind mount of the file system
+    let mount_dirs = [tempfile::tempdir()?, tempfile::tempdir()?];
+    for mount_dir in &mount_dirs {
+        Command::new("mount")
+            .arg(volume_config.volume_path())
+            .arg(mount_dir.path())
+            .run()
+            .await?;
     }
-    if let Some(err) = stress_error {
-        return Err(err);
+
+    // The mounted volume cannot be closed
+    assert!(close_with_teardown(&volume_config, false)
+        .run()
+        .await
+        .is_err());
+    assert!(cryptpilot::fs::luks2::is_active(&volume_config.volume));
+
+    close_with_teardown(&volume_config, true).run().await?;
+    assert!(!cryptpilot::fs::luks2::is_active(&volume_config.volume));
+    let mounts = tokio::fs::read_to_string("/proc/self/mounts").await?;
+    for mount_dir in &mount_dirs {
+        let mount_point = mount_dir.path().to_string_lossy().to_string();
+        assert!(!mounts.lines().any(|line| line
+            .split_whitespace()
+            .nth(1)
+            .is_some_and(|target| target == mount_point)));
     }
 
     Ok(())
 }
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+#[serial_test::serial]
+async fn test_close_teardown_swap() -> Result<()> {
+    let dummy_device = DummyDevice::setup_on_cache_dir(256 * 1024 * 1024).await?;
+    let volume_config = setup_and_open("swap", &dummy_device).await?;
+
+    cryptpilot::fs::mount::swapon(&volume_config.volume_path()).await?;
+    assert!(cryptpilot::fs::mount::is_swap_active(&volume_config.volume_path()).await?);
+
+    // The volume in use as swap cannot be closed
+    assert!(close_with_teardown(&volume_config, false)
+        .run()
+        .await
+        .is_err());
+    assert!(cryptpilot::fs::luks2::is_active(&volume_config.volume));
+
+    close_with_teardown(&volume_config, true).run().await?;
+    assert!(!cryptpilot::fs::luks2::is_active(&volume_config.volume));
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+#[serial_test::serial]
+async fn test_close_teardown_unused() -> Result<()> {
+    let dummy_device = DummyDevice::setup_on_tmpfs(1024 * 1024 * 1024).await?;
+    let volume_config = setup_and_open("ext4", &dummy_device).await?;
+
+    // Nothing to tear down
+    close_with_teardown(&volume_config, true).run().await?;
+    assert!(!cryptpilot::fs::luks2::is_active(&volume_config.volume));
+
+    Ok(())
+}
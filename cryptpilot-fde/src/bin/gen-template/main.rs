@@ -1,6 +1,13 @@
-use anyhow::{Context, Result};
+use std::{
+    io::Write as _,
+    os::unix::fs::{OpenOptionsExt as _, PermissionsExt as _},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
 use clap::{command, Parser};
 use cryptpilot::config::encrypt::{EncryptConfig, KeyProviderConfig};
+use cryptpilot::measure::MeasurementFailurePolicy;
 use cryptpilot::provider::kbs::{CdhType, KbsConfig};
 use cryptpilot_fde::config::{
     BootServiceConfig, DeltaBackend, DeltaConfig, DeltaLocation, FdeConfig, GlobalConfig,
@@ -20,6 +27,14 @@ use crate::build::CLAP_LONG_VERSION;
 pub struct Args {
     #[command(subcommand)]
     pub template: TemplateType,
+
+    /// Write the template to the file instead of stdout. The file is created with mode 0600.
+    #[clap(long, short = 'o')]
+    pub output: Option<PathBuf>,
+
+    /// Overwrite the file specified by `--output` if it already exists.
+    #[clap(long, requires = "output")]
+    pub force: bool,
 }
 
 #[derive(Parser, Debug)]
@@ -103,7 +118,11 @@ impl AsAnnotatedToml for GlobalConfig {
 
 pub fn get_global_config() -> GlobalConfig {
     GlobalConfig {
-        boot: Some(BootServiceConfig { verbose: false }),
+        boot: Some(BootServiceConfig {
+            verbose: false,
+            wait_for_network_secs: None,
+        }),
+        measurement_failure: Some(MeasurementFailurePolicy::Warn),
     }
 }
 
@@ -140,9 +159,15 @@ XXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXX
 "#
                             .into(),
                         ),
+                        kbs_root_cert_path: None,
+                        kbs_native_root_store: None,
                     },
                     key_uri: "kbs:///default/mykey/rootfs_partition".into(),
+                    max_retries: None,
+                    retry_interval_secs: None,
                 }),
+                kdf: None,
+                cipher: None,
             }),
         },
         delta: DeltaConfig {
@@ -159,9 +184,15 @@ XXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXX
 "#
                             .into(),
                         ),
+                        kbs_root_cert_path: None,
+                        kbs_native_root_store: None,
                     },
                     key_uri: "kbs:///default/mykey/data_partition".into(),
+                    max_retries: None,
+                    retry_interval_secs: None,
                 }),
+                kdf: None,
+                cipher: None,
             },
         },
     }
@@ -175,6 +206,72 @@ fn main() -> Result<()> {
         TemplateType::Fde => get_fde_config().as_annotated_toml()?,
     };
 
-    print!("{}", doc);
+    write_template(&doc.to_string(), args.output.as_deref(), args.force)
+}
+
+/// Write the template to the file, or to stdout if no file is specified. The file is created with
+/// mode 0600, since the template may contain secret placeholders (e.g. credentials of the key
+/// provider). An existing file is only overwritten with `force`.
+fn write_template(template: &str, output: Option<&Path>, force: bool) -> Result<()> {
+    let Some(output) = output else {
+        print!("{template}");
+        return Ok(());
+    };
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).mode(0o600);
+    if force {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    let mut file = options.open(output).map_err(|error| match error.kind() {
+        std::io::ErrorKind::AlreadyExists => {
+            anyhow!("The file {output:?} already exists, use `--force` to overwrite it")
+        }
+        _ => anyhow!(error).context(format!("Failed to open {output:?}")),
+    })?;
+    // The mode of an overwritten file is not changed by `open()`
+    file.set_permissions(std::fs::Permissions::from_mode(0o600))
+        .with_context(|| format!("Failed to set permissions of {output:?}"))?;
+    file.write_all(template.as_bytes())
+        .with_context(|| format!("Failed to write template to {output:?}"))?;
+
     Ok(())
 }
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn test_write_template_to_file() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let output = tmp.path().join("fde.toml");
+
+        // The file is created with mode 0600
+        write_template("first", Some(&output), false)?;
+        assert_eq!(std::fs::read_to_string(&output)?, "first");
+        assert_eq!(
+            std::fs::metadata(&output)?.permissions().mode() & 0o777,
+            0o600
+        );
+
+        // The existing file is not overwritten without `force`
+        assert!(write_template("second", Some(&output), false).is_err());
+        assert_eq!(std::fs::read_to_string(&output)?, "first");
+
+        // The existing file is overwritten with `force`, and the mode is fixed
+        std::fs::set_permissions(&output, std::fs::Permissions::from_mode(0o644))?;
+        write_template("second", Some(&output), true)?;
+        assert_eq!(std::fs::read_to_string(&output)?, "second");
+        assert_eq!(
+            std::fs::metadata(&output)?.permissions().mode() & 0o777,
+            0o600
+        );
+
+        Ok(())
+    }
+}
@@ -1,8 +1,10 @@
 use anyhow::{Context as _, Result};
 use clap::Parser as _;
 use cryptpilot_fde::cli::{GuestCli, GuestSubcommand};
-use cryptpilot_fde::cmd::boot_service::copy_config::copy_config_to_initrd_state_if_not_exist;
-use cryptpilot_fde::cmd::{Command, GuestBootServiceCommand};
+use cryptpilot_fde::cmd::boot_service::{
+    copy_config::copy_config_to_initrd_state_if_not_exist, initrd_state::initrd_state_dir,
+};
+use cryptpilot_fde::cmd::{diagnose::DiagnoseCommand, Command, GuestBootServiceCommand};
 use cryptpilot_fde::config::{
     cached::CachedFdeConfigSource, initrd_state::InitrdStateConfigSource,
 };
@@ -28,25 +30,27 @@ async fn main() -> Result<()> {
     .await;
 
     let args = GuestCli::parse();
+    cryptpilot::fs::block::devicemapper::set_dm_name_prefix(&args.dm_name_prefix)?;
 
-    let GuestSubcommand::BootService(boot_service_options) = &args.command;
-
-    tracing::info!(
-        "cryptpilot-fde version: v{}  commit: {}  buildtime: {}",
-        build::PKG_VERSION,
-        build::COMMIT_HASH,
-        build::BUILD_TIME
-    );
+    if let GuestSubcommand::BootService(boot_service_options) = &args.command {
+        tracing::info!(
+            "cryptpilot-fde version: v{}  commit: {}  buildtime: {}",
+            build::PKG_VERSION,
+            build::COMMIT_HASH,
+            build::BUILD_TIME
+        );
 
-    tracing::info!(
-        "The cryptpilot-fde is running in {} stage",
-        boot_service_options.stage
-    );
+        tracing::info!(
+            "The cryptpilot-fde is running in {} stage",
+            boot_service_options.stage
+        );
+    }
 
     // Load config from unsafe space and save to initrd state for later use.
-    copy_config_to_initrd_state_if_not_exist(true).await?;
+    let initrd_state_dir = initrd_state_dir();
+    copy_config_to_initrd_state_if_not_exist(&initrd_state_dir, true).await?;
     cryptpilot_fde::config::set_fde_config_source(CachedFdeConfigSource::new(
-        InitrdStateConfigSource::new(),
+        InitrdStateConfigSource::new_with_state_dir(initrd_state_dir),
     ))
     .await;
 
@@ -78,10 +82,21 @@ async fn main() -> Result<()> {
             .source_debug_string()
     );
 
-    let cmd = GuestBootServiceCommand {
-        boot_service_options: boot_service_options.clone(),
-    };
-    cmd.run().await?;
+    match &args.command {
+        GuestSubcommand::BootService(boot_service_options) => {
+            let cmd = GuestBootServiceCommand {
+                boot_service_options: boot_service_options.clone(),
+            };
+            cmd.run().await?;
+        }
+        GuestSubcommand::Diagnose(diagnose_options) => {
+            DiagnoseCommand {
+                json: diagnose_options.json,
+            }
+            .run()
+            .await?
+        }
+    }
 
     Ok(())
 }
@@ -30,17 +30,34 @@ async fn main() -> Result<()> {
     .await;
 
     let args = Cli::parse();
-
-    if args.config_dir.is_some() {
-        bail!("Cannot specify `--config-dir` with `show-reference-value` or `config` subcommand");
+    if args.dump_cdh_config {
+        cryptpilot::provider::kbs::set_dump_cdh_config(true);
+    }
+    if let Some(max_read_size) = args.max_read_size {
+        cryptpilot_fde::disk::set_max_read_size(max_read_size);
+    }
+    if args.attach_loop {
+        cryptpilot_fde::disk::external::set_attach_loop(true);
     }
 
-    if Path::new("/etc/initrd-release").exists() {
+    if !args.config_dir.is_empty() {
+        for config_dir in &args.config_dir {
+            let path = Path::new(config_dir);
+            if !path.exists() || !path.is_dir() {
+                bail!("Config dir {config_dir} does not exist or not a directory");
+            }
+        }
+        cryptpilot_fde::config::set_fde_config_source(CachedFdeConfigSource::new(
+            FileSystemConfigSource::new_with_config_dirs(&args.config_dir),
+        ))
+        .await;
+    } else if Path::new("/etc/initrd-release").exists() {
         // If we are in initrd emergency shell, try loading from initrd state first,
         // otherwise fall back to filesystem config.
-        if InitrdStateConfigSource::exist() {
+        let initrd_state_source = InitrdStateConfigSource::new();
+        if initrd_state_source.exist() {
             cryptpilot_fde::config::set_fde_config_source(CachedFdeConfigSource::new(
-                InitrdStateConfigSource::new(),
+                initrd_state_source,
             ))
             .await;
         } else {
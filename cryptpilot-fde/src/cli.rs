@@ -14,8 +14,21 @@ pub struct Cli {
     pub command: FdeSubcommand,
 
     /// Path to the root directory where to load configuration files. Default value is /etc/cryptpilot.
+    /// Can be specified multiple times, in which case `global.toml` or `fde.toml` in a later directory replaces the one in an earlier directory.
     #[clap(long, short = 'c', global = true)]
-    pub config_dir: Option<String>,
+    pub config_dir: Vec<String>,
+
+    /// Log the config generated for the one-shot CDH of the KBS key provider, with the secrets redacted, to troubleshoot KBS failures. Can also be enabled by setting the environment variable `CRYPTPILOT_DUMP_CDH_CONFIG=1`, or `CRYPTPILOT_DUMP_CDH_CONFIG=keep` to also keep the config file after use.
+    #[clap(long, global = true)]
+    pub dump_cdh_config: bool,
+
+    /// The maximum size in bytes of a file read from the disk, e.g. a kernel or an initrd, which guards against a corrupted or hostile image claiming a huge file. Default value is 2 GiB.
+    #[clap(long, global = true)]
+    pub max_read_size: Option<u64>,
+
+    /// Attach a disk image file given with --disk to a loop device (with `losetup --partscan`) instead of an NBD device, e.g. on a host where NBD is unavailable or forbidden. Only raw images are supported. Without it, a loop device is still used if the nbd kernel module cannot be loaded.
+    #[clap(long, global = true)]
+    pub attach_loop: bool,
 }
 
 #[derive(Subcommand, Debug)]
@@ -27,6 +40,22 @@ pub enum FdeSubcommand {
     /// Subcommands related to configuration.
     #[command(name = "config")]
     Config(ConfigOptions),
+
+    /// Migrate the rootfs and delta volumes of a disk to new key providers, e.g. from KMS to KBS.
+    #[command(name = "migrate-provider")]
+    MigrateProvider(MigrateProviderOptions),
+
+    /// Check if the initrd contains the up-to-date cryptpilot boot hooks, and report whether it needs to be regenerated.
+    #[command(name = "check-initrd")]
+    CheckInitrd(CheckInitrdOptions),
+
+    /// Cross-check the boot artifacts, the FDE config bundle, the metadata and the dm-verity hash device of a disk, and print a JSON report of each check.
+    #[command(name = "verify-boot-chain")]
+    VerifyBootChain(VerifyBootChainOptions),
+
+    /// List the GPT partitions of a disk with their type GUID, PARTLABEL, file system and size, and the role cryptpilot assigns to each of them.
+    #[command(name = "partitions")]
+    Partitions(PartitionsOptions),
 }
 
 #[derive(Parser, Debug)]
@@ -38,6 +67,58 @@ pub struct ShowReferenceValueOptions {
     /// Specify one or more hash algorithms to use.
     #[clap(long = "hash-algo", default_value = "sha384")]
     pub hash_algos: Vec<ShowReferenceValueHashAlgo>,
+
+    /// Skip boot entries whose files cannot be read (e.g. a stale secondary kernel) with a warning, instead of failing. At least one boot entry must still succeed.
+    #[clap(long)]
+    pub best_effort: bool,
+
+    /// Fill the reference values into the JSON policy template file, instead of printing them as is. Each string of the form "{{<name>}}" in the template is replaced with the values named <name> (e.g. "{{measurement.kernel.SHA-384}}"), and "{{<name>?}}" is replaced with an empty list if the values cannot be computed.
+    #[clap(long)]
+    pub policy_template: Option<PathBuf>,
+
+    /// Compare the reference values with the ones of this other disk (a file or block device), print only the differing values as JSON, and exit with nonzero status if any difference is found.
+    #[clap(long, conflicts_with = "policy_template")]
+    pub compare: Option<PathBuf>,
+
+    /// Also emit the hashes of the file at this path inside the initrd (e.g. /usr/bin/cryptpilot-fde), as `measurement.initrd_file:<path>.<hash-algo>`, to tie the measurement to specific initrd components. Can be specified multiple times. Symlinks inside the initrd are followed, and it is an error if the file is missing from the initrd of any boot entry.
+    #[clap(long = "initrd-file")]
+    pub initrd_files: Vec<PathBuf>,
+
+    /// Never mount the partitions of the disk (and of the one of --compare), since mounting a file system, even read-only, may replay its journal. The files needed are copied out of the partitions with `debugfs` (ext4) and `mcopy` of mtools (vfat) instead, and a disk image file is connected read-only. Useful for a forensic analysis.
+    #[clap(long, requires = "disk")]
+    pub no_mount: bool,
+
+    /// Also emit the `ID`, `VERSION_ID` and `IMAGE_ID` fields of the os-release file in the rootfs, as `os_release.<field>`, and the hashes of the whole file, as `measurement.os_release.<hash-algo>`. On an external cryptpilot FDE disk, the rootfs is read from its LVM volume group, which must not be active on this system. An encrypted rootfs requires --rootfs-key-file.
+    #[clap(long, conflicts_with = "no_mount")]
+    pub os_release: bool,
+
+    /// Also emit the hashes of the grub.cfg and grubenv files used for booting, as `measurement.grub_cfg.<hash-algo>` and `measurement.grubenv.<hash-algo>`, since they define the boot. The line endings are normalized to LF before hashing. Only applies to disks booted with GRUB.
+    #[clap(long)]
+    pub grub_config: bool,
+
+    /// Instead of the boot entries, emit the hashes of every kernel in /boot whose filename matches this glob pattern (e.g. "vmlinuz-*"), and of the initrd paired with it (e.g. initramfs-<version>.img), as `measurement.kernel:<filename>.<hash-algo>` and `measurement.initrd:<filename>.<hash-algo>` keyed by the filename of the kernel. Useful for images whose boot config cannot be parsed reliably. The pattern matches only the regular files directly in /boot.
+    #[clap(long, conflicts_with_all = ["grub_config", "initrd_files", "best_effort"])]
+    pub kernels: Option<String>,
+
+    /// The file containing the passphrase of the encrypted rootfs of the disk (and of the one of --compare), used to open it read-only for --os-release. For a disk encrypted with a test key provider (e.g. `exec`), this is the passphrase in its config.
+    #[clap(long, requires = "os_release")]
+    pub rootfs_key_file: Option<PathBuf>,
+
+    /// After printing the reference values (or the filled policy template), also publish them by an HTTP POST request with the JSON as body to this endpoint of a reference value store, e.g. `https://rvps.example.com/api/reference-values`. Failed requests are retried, except for a 4xx response.
+    #[clap(long, conflicts_with = "compare")]
+    pub publish: Option<String>,
+
+    /// Print the reference values as a bundle for Trustee instead: the registration message of the RVPS (`{"version": "0.1.0", "type": "sample", "payload": <base64 of the reference values>}`) as `reference_values`, and the filled --policy-template as `policy` if given. The reference values in the bundle also include the expected runtime measurement of the FDE config in the initrd, as `AA.eventlog.cryptpilot.alibabacloud.com.load_config_untrusted.<hash-algo>` with SHA-384, and with SM3 if requested with --hash-algo.
+    #[clap(long, conflicts_with = "compare")]
+    pub trustee_bundle: bool,
+
+    /// The file containing the bearer token sent in the `Authorization` header of the --publish request.
+    #[clap(long, requires = "publish")]
+    pub publish_token_file: Option<PathBuf>,
+
+    /// The version of the output schema, which determines the set of reference value names emitted. Names added in later versions are omitted when an older version is requested, so that policy tooling keeps getting a known layout.
+    #[clap(long, value_enum, default_value_t = ReferenceValueSchemaVersion::LATEST)]
+    pub schema_version: ReferenceValueSchemaVersion,
 }
 
 #[derive(Parser, Debug)]
@@ -45,6 +126,58 @@ pub struct ConfigDumpOptions {
     /// Operate on the specified disk instead of the running system. The path can be a file or block device.
     #[clap(long, global = true)]
     pub disk: Option<PathBuf>,
+
+    /// Compare the fde config bundle with the expected one in the specified TOML file, instead of dumping it. Exit with nonzero status if any difference is found.
+    #[clap(long)]
+    pub diff: Option<PathBuf>,
+
+    /// Replace the secrets of the key providers (e.g. client keys, passwords, certs, the arguments of `exec` and the credentials in URLs) with `***` while keeping the structure, so that the output is safe to share, e.g. in an issue. The printed hash is still the one of the unredacted config.
+    #[clap(long, conflicts_with = "diff")]
+    pub redact: bool,
+}
+
+#[derive(Parser, Debug)]
+pub struct MigrateProviderOptions {
+    /// The disk to migrate. The path can be a file or block device.
+    #[clap(long)]
+    pub disk: PathBuf,
+
+    /// Path to the FDE config file (`fde.toml`) currently embedded in the disk, whose key providers can unlock the volumes.
+    #[clap(long)]
+    pub from: PathBuf,
+
+    /// Path to the new FDE config file (`fde.toml`) with the new key providers. Only the `encrypt` sections may differ from the `--from` config.
+    #[clap(long)]
+    pub to: PathBuf,
+}
+
+#[derive(Parser, Debug)]
+pub struct CheckInitrdOptions {
+    /// Check the specified initrd image (or UKI image) instead of the one of the running kernel.
+    #[clap(long, conflicts_with = "disk")]
+    pub initrd: Option<PathBuf>,
+
+    /// Check the initrd images on the specified disk instead of the running system. The path can be a file or block device.
+    #[clap(long)]
+    pub disk: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+pub struct VerifyBootChainOptions {
+    /// The disk to verify. The path can be a file or block device.
+    #[clap(long)]
+    pub disk: PathBuf,
+}
+
+#[derive(Parser, Debug)]
+pub struct PartitionsOptions {
+    /// The disk to inspect. The path can be a file or block device.
+    #[clap(long)]
+    pub disk: PathBuf,
+
+    /// Print the partitions and the warnings as JSON.
+    #[clap(long)]
+    pub json: bool,
 }
 
 #[derive(ValueEnum, Clone, Debug)]
@@ -62,6 +195,41 @@ pub enum ShowReferenceValueHashAlgo {
     Sm3,
 }
 
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ReferenceValueSchemaVersion {
+    /// `kernel_cmdline`, and `measurement.<component>.<hash-algo>` for the kernel_cmdline, kernel, initrd, grub, shim and uki components.
+    #[clap(name = "1")]
+    V1,
+
+    /// Version 1, and `measurement.sbat.<hash-algo>` for the SBAT data of shim.
+    #[clap(name = "2")]
+    V2,
+
+    /// Version 2, and `kernel_version` and `measurement.kernel_version.<hash-algo>` for the version string of the kernel.
+    #[clap(name = "3")]
+    V3,
+
+    /// Version 3, and `measurement.initrd_file:<path>.<hash-algo>` for the files inside the initrd requested with `--initrd-file`.
+    #[clap(name = "4")]
+    V4,
+
+    /// Version 4, and `os_release.<field>` and `measurement.os_release.<hash-algo>` for the os-release file in the rootfs requested with `--os-release`.
+    #[clap(name = "5")]
+    V5,
+
+    /// Version 5, and `measurement.grub_cfg.<hash-algo>` and `measurement.grubenv.<hash-algo>` for the GRUB config files requested with `--grub-config`.
+    #[clap(name = "6")]
+    V6,
+
+    /// Version 6, and `measurement.kernel:<filename>.<hash-algo>` and `measurement.initrd:<filename>.<hash-algo>` for the kernels requested with `--kernels`.
+    #[clap(name = "7")]
+    V7,
+}
+
+impl ReferenceValueSchemaVersion {
+    pub const LATEST: Self = Self::V7;
+}
+
 #[derive(Debug, Args)]
 #[command(args_conflicts_with_subcommands = true)]
 pub struct ConfigOptions {
@@ -100,6 +268,10 @@ pub struct ConfigCheckOptions {
 pub struct GuestCli {
     #[command(subcommand)]
     pub command: GuestSubcommand,
+
+    /// Prefix prepended to the names of the device mapper devices set up below /dev/mapper/, e.g. to isolate the mappings of parallel test runs. Empty by default.
+    #[clap(long, global = true, hide = true, default_value = "")]
+    pub dm_name_prefix: String,
 }
 
 #[derive(Subcommand, Debug)]
@@ -107,6 +279,17 @@ pub enum GuestSubcommand {
     /// Running during system booting FDE stages.
     #[command(name = "boot-service")]
     BootService(BootServiceOptions),
+
+    /// Inspect the volumes and device mapper layers required by FDE, and report the missing or inconsistent components with suggestions to repair them.
+    #[command(name = "diagnose")]
+    Diagnose(DiagnoseOptions),
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct DiagnoseOptions {
+    /// Output a JSON report listing the status of each component and the repair suggestions, instead of text.
+    #[clap(long)]
+    pub json: bool,
 }
 
 #[derive(Parser, Debug, Clone)]
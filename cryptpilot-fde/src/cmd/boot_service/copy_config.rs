@@ -1,18 +1,29 @@
+use std::path::Path;
+
 use anyhow::{bail, Context, Result};
 
 use crate::{
-    cmd::boot_service::initrd_state::InitrdState,
+    cmd::boot_service::{
+        initrd_state::InitrdState,
+        metadata::{
+            load_metadata_from_file, CONFIG_SIGNING_PUBLIC_KEY_PATH_IN_INITRD,
+            METADATA_PATH_IN_INITRD,
+        },
+    },
     config::{
         cloud_init::CloudInitConfigSource, fs::FileSystemConfigSource,
         initrd_state::InitrdStateConfigSource, FdeConfigBundle, FdeConfigSource,
     },
 };
-use cryptpilot::measure::{AutoDetectMeasure, Measure, OPERATION_NAME_LOAD_CONFIG_UNTRUSTED};
+use cryptpilot::measure::{
+    AutoDetectMeasure, Measure, MeasurementFailurePolicy, OPERATION_NAME_LOAD_CONFIG_UNTRUSTED,
+};
 
 pub async fn copy_config_to_initrd_state_if_not_exist(
+    state_dir: &Path,
     measurement_if_from_unsafe_source: bool,
 ) -> Result<()> {
-    if InitrdStateConfigSource::exist() {
+    if InitrdStateConfigSource::new_with_state_dir(state_dir).exist() {
         return Ok(());
     }
 
@@ -20,7 +31,7 @@ pub async fn copy_config_to_initrd_state_if_not_exist(
 
     // Save to initrd state
     let initrd_state = InitrdState { fde_config_bundle };
-    initrd_state.save().await?;
+    initrd_state.save(state_dir).await?;
 
     Ok(())
 }
@@ -31,20 +42,27 @@ async fn load_fde_config_bundle(
     tracing::info!("Trying to load config from cloud-init");
     match load_config_from_cloud_init().await {
         Ok(config) => {
+            // Refuse to boot if the config is signed in the metadata but the signature is invalid
+            verify_untrusted_config_signature(&config)
+                .await
+                .context("Refusing to use cryptpilot config from untrusted source (cloud-init)")?;
+
             if measurement_if_from_unsafe_source {
                 // Extend config hash to runtime measurement
                 let content_to_hash = config.gen_hash_content()?;
 
                 let measure = AutoDetectMeasure::new().await;
-                match measure
-                    .extend_measurement_hash(OPERATION_NAME_LOAD_CONFIG_UNTRUSTED.into(), content_to_hash)
+                let measured = measure
+                    .extend_measurement_hash_with_policy(
+                        measurement_failure_policy().await,
+                        OPERATION_NAME_LOAD_CONFIG_UNTRUSTED.into(),
+                        content_to_hash,
+                    )
                     .await
-                    .context("Using cryptpilot config from untrusted source (cloud-init), but failed to measure it") {
-                    Ok(()) => {
-                        return Ok(config);
-                    },
-                    // Will not use this config if measurement failed
-                    Err(e) => tracing::warn!("{e:?}"),
+                    .context("Using cryptpilot config from untrusted source (cloud-init), but failed to measure it")?;
+                // Will not use this config if measurement failed
+                if measured {
+                    return Ok(config);
                 }
             } else {
                 return Ok(config);
@@ -66,6 +84,56 @@ async fn load_fde_config_bundle(
     bail!("Failed to load config from any source");
 }
 
+/// Verify the config from an untrusted source with the signature in the metadata in the initrd, if
+/// any. Fails if the signature is present but invalid. A config is not required to be signed, for
+/// backward compatibility.
+async fn verify_untrusted_config_signature(config: &FdeConfigBundle) -> Result<()> {
+    let metadata_path = Path::new(METADATA_PATH_IN_INITRD);
+    if !metadata_path.exists() {
+        tracing::debug!("No metadata found in the initrd, skip verifying the config signature");
+        return Ok(());
+    }
+    let metadata = load_metadata_from_file(metadata_path).await?;
+    if metadata.config_signature.is_none() {
+        tracing::debug!("No config signature in the metadata, skip verifying it");
+        return Ok(());
+    }
+
+    let public_key_path = Path::new(CONFIG_SIGNING_PUBLIC_KEY_PATH_IN_INITRD);
+    let public_key_pem = if public_key_path.exists() {
+        Some(
+            tokio::fs::read_to_string(public_key_path)
+                .await
+                .with_context(|| {
+                    format!("Failed to read config signing key {public_key_path:?}")
+                })?,
+        )
+    } else {
+        None
+    };
+    metadata.verify_config_signature(config, public_key_pem.as_deref())?;
+    tracing::info!("The signature of the config from untrusted source is verified");
+    Ok(())
+}
+
+/// The policy on a failed measurement of the config from an untrusted source, which is read from the
+/// global config in the initrd, since the untrusted config cannot decide how it is checked.
+async fn measurement_failure_policy() -> MeasurementFailurePolicy {
+    match load_config_from_current_initrd_environment().await {
+        Ok(config) => config
+            .global
+            .and_then(|global| global.measurement_failure)
+            .unwrap_or_default(),
+        Err(error) => {
+            tracing::debug!(
+                ?error,
+                "Failed to load config from the initrd, using the default measurement failure policy"
+            );
+            MeasurementFailurePolicy::default()
+        }
+    }
+}
+
 async fn load_config_from_current_initrd_environment() -> Result<FdeConfigBundle> {
     FileSystemConfigSource::new_with_default_config_dir()
         .get_fde_config_bundle()
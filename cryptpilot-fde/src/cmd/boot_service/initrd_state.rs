@@ -1,8 +1,9 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use crate::config::FdeConfigBundle;
 
 use anyhow::{Context, Result};
+use cryptpilot::fs::perm::{self, FileKind};
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
@@ -11,25 +12,43 @@ pub struct InitrdState {
     pub fde_config_bundle: FdeConfigBundle,
 }
 
-pub const CRYPTPILOT_INITRD_STATE_PATH: &str = "/var/run/cryptpilot/initrd_state.toml";
+pub const CRYPTPILOT_INITRD_STATE_DIR_DEFAULT: &str = "/var/run/cryptpilot";
+pub const CRYPTPILOT_INITRD_STATE_FILE_NAME: &str = "initrd_state.toml";
+
+/// The environment variable to override the directory of the initrd state file, for testing and
+/// initrd with unusual layout.
+pub const CRYPTPILOT_INITRD_STATE_DIR_ENV: &str = "CRYPTPILOT_INITRD_STATE_DIR";
+
+/// Get the directory of the initrd state file, which is [`CRYPTPILOT_INITRD_STATE_DIR_DEFAULT`]
+/// unless overridden by the [`CRYPTPILOT_INITRD_STATE_DIR_ENV`] environment variable.
+pub fn initrd_state_dir() -> PathBuf {
+    match std::env::var_os(CRYPTPILOT_INITRD_STATE_DIR_ENV) {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(CRYPTPILOT_INITRD_STATE_DIR_DEFAULT),
+    }
+}
+
+pub fn initrd_state_path(state_dir: &Path) -> PathBuf {
+    state_dir.join(CRYPTPILOT_INITRD_STATE_FILE_NAME)
+}
 
 impl InitrdState {
-    pub async fn save(&self) -> Result<()> {
+    pub async fn save(&self, state_dir: &Path) -> Result<()> {
         let str: String = toml::to_string_pretty(self).unwrap();
-        let path = Path::new(CRYPTPILOT_INITRD_STATE_PATH);
-        tokio::fs::create_dir_all(path.parent().unwrap()).await?;
-        tokio::fs::write(CRYPTPILOT_INITRD_STATE_PATH, str).await?;
-        tracing::info!("Successfully wrote initrd state to {CRYPTPILOT_INITRD_STATE_PATH}");
+        let path = initrd_state_path(state_dir);
+        perm::create_dir_all(state_dir).await?;
+        // The config bundle may contain the credentials of the key providers
+        perm::write_file(&path, str, FileKind::Secret).await?;
+        tracing::info!("Successfully wrote initrd state to {path:?}");
         Ok(())
     }
 
-    pub async fn load() -> Result<InitrdState> {
-        tokio::fs::read_to_string(CRYPTPILOT_INITRD_STATE_PATH)
+    pub async fn load(state_dir: &Path) -> Result<InitrdState> {
+        let path = initrd_state_path(state_dir);
+        tokio::fs::read_to_string(&path)
             .await
             .map_err(anyhow::Error::from)
             .and_then(|str| toml::from_str(&str).map_err(anyhow::Error::from))
-            .with_context(|| {
-                format!("Failed to read initrd state from {CRYPTPILOT_INITRD_STATE_PATH}")
-            })
+            .with_context(|| format!("Failed to read initrd state from {path:?}"))
     }
 }
@@ -1,11 +1,24 @@
 use std::path::Path;
 
-use anyhow::{Context as _, Result};
+use anyhow::{anyhow, bail, Context as _, Result};
+use base64::{prelude::BASE64_STANDARD, Engine as _};
 use documented::DocumentedFields;
 use serde::{Deserialize, Serialize};
 
+use crate::config::FdeConfigBundle;
+
 pub const METADATA_PATH_IN_INITRD: &str = "/etc/cryptpilot/metadata.toml";
 
+/// The path of the Ed25519 public key (in PEM) in the initrd, which verifies the `config_signature`
+/// of the metadata.
+pub const CONFIG_SIGNING_PUBLIC_KEY_PATH_IN_INITRD: &str = "/etc/cryptpilot/config-signing-key.pem";
+
+/// The DER prefix of the SubjectPublicKeyInfo of an Ed25519 public key, followed by the 32 bytes of
+/// the key itself.
+const ED25519_SPKI_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone, DocumentedFields)]
 #[serde(deny_unknown_fields)]
 pub struct Metadata {
@@ -14,6 +27,59 @@ pub struct Metadata {
 
     /// The root hash of the rootfs LV in hex format, which works with the rootfs-verity partition.
     pub root_hash: String,
+
+    /// The Ed25519 signature in base64 over the FDE config bundle from an untrusted source (e.g. cloud-init), made with the private key of the public key at /etc/cryptpilot/config-signing-key.pem in the initrd. The signed message is the sha384 hash in hex of the config bundle, which is also extended to the runtime measurement. If set, the boot is refused unless the signature of the config bundle from the untrusted source is valid.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config_signature: Option<String>,
+}
+
+impl Metadata {
+    /// Verify the `config_signature` over the config bundle with the Ed25519 public key in PEM.
+    /// Returns `false` if the metadata has no signature, for backward compatibility, and fails if
+    /// the signature is present but cannot be verified, including when the key is missing.
+    pub fn verify_config_signature(
+        &self,
+        fde_config_bundle: &FdeConfigBundle,
+        public_key_pem: Option<&str>,
+    ) -> Result<bool> {
+        let Some(signature) = &self.config_signature else {
+            return Ok(false);
+        };
+        let Some(public_key_pem) = public_key_pem else {
+            bail!("The metadata has a config signature, but no public key is found at {CONFIG_SIGNING_PUBLIC_KEY_PATH_IN_INITRD} to verify it");
+        };
+
+        let public_key = parse_ed25519_public_key_pem(public_key_pem)?;
+        let signature = BASE64_STANDARD
+            .decode(signature.trim())
+            .context("The config signature in the metadata is not valid base64")?;
+        let message = fde_config_bundle.gen_hash_hex()?;
+        ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, public_key)
+            .verify(message.as_bytes(), &signature)
+            .map_err(|_| {
+                anyhow!("The config signature in the metadata does not match the config bundle (sha384: {message})")
+            })?;
+        Ok(true)
+    }
+}
+
+/// Get the 32 bytes of the Ed25519 public key in the PEM `PUBLIC KEY` format, e.g. the output of
+/// `openssl pkey -pubout`.
+fn parse_ed25519_public_key_pem(pem: &str) -> Result<Vec<u8>> {
+    let base64 = pem
+        .trim()
+        .strip_prefix("-----BEGIN PUBLIC KEY-----")
+        .and_then(|pem| pem.strip_suffix("-----END PUBLIC KEY-----"))
+        .context("The config signing key is not a PEM public key")?
+        .split_whitespace()
+        .collect::<String>();
+    let der = BASE64_STANDARD
+        .decode(base64)
+        .context("The config signing key is not valid base64")?;
+    match der.strip_prefix(&ED25519_SPKI_PREFIX[..]) {
+        Some(public_key) if public_key.len() == 32 => Ok(public_key.to_vec()),
+        _ => bail!("The config signing key is not an Ed25519 public key"),
+    }
 }
 
 pub async fn load_metadata_from_file(metadata_path: &Path) -> Result<Metadata> {
@@ -30,3 +96,119 @@ pub async fn load_metadata_from_file(metadata_path: &Path) -> Result<Metadata> {
 
     Ok(metadata)
 }
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+    use ring::signature::{Ed25519KeyPair, KeyPair as _};
+
+    fn test_key_pair(seed: u8) -> Result<(Ed25519KeyPair, String)> {
+        let key_pair = Ed25519KeyPair::from_seed_unchecked(&[seed; 32])
+            .map_err(|error| anyhow!("Failed to create key pair: {error}"))?;
+        let der = [&ED25519_SPKI_PREFIX[..], key_pair.public_key().as_ref()].concat();
+        let pem = format!(
+            "-----BEGIN PUBLIC KEY-----\n{}\n-----END PUBLIC KEY-----\n",
+            BASE64_STANDARD.encode(der)
+        );
+        Ok((key_pair, pem))
+    }
+
+    fn test_metadata(config_signature: Option<String>) -> Metadata {
+        Metadata {
+            r#type: 1,
+            root_hash: "00".repeat(32),
+            config_signature,
+        }
+    }
+
+    #[test]
+    fn test_verify_config_signature() -> Result<()> {
+        let fde_config_bundle: FdeConfigBundle = toml::from_str(
+            r#"
+[fde.rootfs]
+delta_location = "disk"
+
+[fde.rootfs.encrypt.exec]
+command = "echo"
+args = ["-n", "test-passphrase"]
+
+[fde.delta.encrypt.otp]
+"#,
+        )?;
+        let (key_pair, public_key_pem) = test_key_pair(1)?;
+        let signature_bytes = key_pair
+            .sign(fde_config_bundle.gen_hash_hex()?.as_bytes())
+            .as_ref()
+            .to_vec();
+        let signature = BASE64_STANDARD.encode(&signature_bytes);
+
+        // Valid
+        assert!(test_metadata(Some(signature.clone()))
+            .verify_config_signature(&fde_config_bundle, Some(&public_key_pem))?);
+
+        // Absent, for backward compatibility
+        assert!(!test_metadata(None).verify_config_signature(&fde_config_bundle, None)?);
+        assert!(!test_metadata(None)
+            .verify_config_signature(&fde_config_bundle, Some(&public_key_pem))?);
+
+        // Invalid: a modified config, another key, a corrupted signature, or no key
+        let mut modified = fde_config_bundle.clone();
+        modified.global = Some(toml::from_str("[boot]\nverbose = true")?);
+        assert!(test_metadata(Some(signature.clone()))
+            .verify_config_signature(&modified, Some(&public_key_pem))
+            .is_err());
+        let (_, other_public_key_pem) = test_key_pair(2)?;
+        assert!(test_metadata(Some(signature.clone()))
+            .verify_config_signature(&fde_config_bundle, Some(&other_public_key_pem))
+            .is_err());
+        let mut corrupted = signature_bytes;
+        corrupted[0] ^= 1;
+        assert!(test_metadata(Some(BASE64_STANDARD.encode(corrupted)))
+            .verify_config_signature(&fde_config_bundle, Some(&public_key_pem))
+            .is_err());
+        assert!(test_metadata(Some("not base64!".into()))
+            .verify_config_signature(&fde_config_bundle, Some(&public_key_pem))
+            .is_err());
+        assert!(test_metadata(Some(signature))
+            .verify_config_signature(&fde_config_bundle, None)
+            .is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_load_metadata_with_config_signature() -> Result<()> {
+        let metadata_file = tempfile::NamedTempFile::new()?;
+        tokio::fs::write(
+            metadata_file.path(),
+            format!("type = 1\nroot_hash = \"{}\"\n", "ab".repeat(32)),
+        )
+        .await?;
+        assert_eq!(
+            load_metadata_from_file(metadata_file.path())
+                .await?
+                .config_signature,
+            None
+        );
+
+        tokio::fs::write(
+            metadata_file.path(),
+            format!(
+                "type = 1\nroot_hash = \"{}\"\nconfig_signature = \"c2lnbmF0dXJl\"\n",
+                "ab".repeat(32)
+            ),
+        )
+        .await?;
+        assert_eq!(
+            load_metadata_from_file(metadata_file.path())
+                .await?
+                .config_signature
+                .as_deref(),
+            Some("c2lnbmF0dXJl")
+        );
+
+        Ok(())
+    }
+}
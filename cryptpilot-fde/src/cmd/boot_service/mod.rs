@@ -1,7 +1,9 @@
 pub mod copy_config;
 pub mod initrd_state;
 pub mod metadata;
+pub mod network;
 pub mod stage;
+pub mod summary;
 pub mod time_sync;
 
 use anyhow::{Context, Result};
@@ -21,6 +23,8 @@ impl crate::cmd::Command for BootServiceCommand {
             BootStage::InitrdFdeBeforeSysroot => {
                 time_sync::sync_time_to_system().await?;
 
+                network::wait_for_network_if_configured().await?;
+
                 stage::before_sysroot::setup_volumes_required_by_fde()
                     .await
                     .context("Failed to setup volumes required by FDE")?;
@@ -32,6 +36,8 @@ impl crate::cmd::Command for BootServiceCommand {
             }
         }
 
+        summary::report_fde_summary(&self.boot_service_options.stage).await;
+
         tracing::info!("Everything have been completed, exit now");
 
         Ok(())
@@ -6,7 +6,7 @@ use tokio::{
     process::Command,
 };
 
-use crate::cmd::boot_service::stage::{DELTA_DEVICE, ROOTFS_DEVICE};
+use crate::cmd::boot_service::stage::{delta_device, rootfs_device};
 use cryptpilot::fs::cmd::CheckCommandOutput;
 
 use crate::config::{DeltaBackend, DeltaLocation};
@@ -49,7 +49,7 @@ async fn setup_overlayfs_mounts(fde_config: crate::config::FdeConfig) -> Result<
         tokio::fs::create_dir_all("/delta_volume").await?;
 
         Command::new("mount")
-            .arg(DELTA_DEVICE)
+            .arg(delta_device())
             .arg("/delta_volume")
             .run()
             .await?;
@@ -105,7 +105,7 @@ async fn setup_overlayfs_mounts(fde_config: crate::config::FdeConfig) -> Result<
 
                 Command::new("mount")
                     .args(["-t", "overlay"])
-                    .arg(ROOTFS_DEVICE)
+                    .arg(rootfs_device())
                     .args([
                         "-o",
                         "lowerdir=/sysroot,upperdir=/ram_overlay/upper,workdir=/ram_overlay/work",
@@ -129,7 +129,7 @@ async fn setup_overlayfs_mounts(fde_config: crate::config::FdeConfig) -> Result<
 
                 Command::new("mount")
                     .args(["-t", "overlay"])
-                    .arg(ROOTFS_DEVICE)
+                    .arg(rootfs_device())
                     .args([
                         "-o",
                         "lowerdir=/sysroot,upperdir=/delta_volume/overlay/upper,workdir=/delta_volume/overlay/work",
@@ -229,14 +229,15 @@ async fn check_sysroot() -> Result<()> {
 
     // The mount of /sysroot is not done by cryptpilot. It is intentional, because we do not want to take over the job of /etc/fstab. So we have to check if sysroot is mounted from ROOTFS_LAYER_DEVICE.
     let mtab_content = fs::read_to_string("/etc/mtab").await?;
+    let rootfs_device = rootfs_device();
     for line in mtab_content.lines() {
         let mut fields = line.split(' ');
         match (fields.next(), fields.next()) {
             (Some(device), Some("/sysroot")) => {
-                if device == ROOTFS_DEVICE {
+                if Path::new(device) == rootfs_device {
                     return Ok(());
                 } else {
-                    bail!("Rootfs mounted at /sysroot is not expected and could be a security risk. Expected: {ROOTFS_DEVICE}, got: {device}");
+                    bail!("Rootfs mounted at /sysroot is not expected and could be a security risk. Expected: {}, got: {device}", rootfs_device.display());
                 }
             }
             _ => continue,
@@ -7,23 +7,27 @@ use crate::{
     cmd::boot_service::{
         metadata::{load_metadata_from_file, Metadata, METADATA_PATH_IN_INITRD},
         stage::{
-            DELTA_DEVICE, DELTA_LOGICAL_VOLUME, DELTA_NAME, ROOTFS_DECRYPTED_LAYER_DEVICE,
-            ROOTFS_DECRYPTED_NAME, ROOTFS_DEVICE, ROOTFS_EXTENDED_DEVICE, ROOTFS_EXTENDED_NAME,
-            ROOTFS_HASH_LOGICAL_VOLUME, ROOTFS_LOGICAL_VOLUME, ROOTFS_NAME, ROOTFS_VERITY_DEVICE,
-            ROOTFS_VERITY_NAME, VOLUME_GROUP_NAME,
+            delta_device, dm_uuid_of, rootfs_decrypted_layer_device, rootfs_device,
+            rootfs_extended_device, rootfs_verity_device, DELTA_LOGICAL_VOLUME, DELTA_NAME,
+            ROOTFS_DECRYPTED_NAME, ROOTFS_EXTENDED_NAME, ROOTFS_HASH_LOGICAL_VOLUME,
+            ROOTFS_LOGICAL_VOLUME, ROOTFS_NAME, ROOTFS_VERITY_NAME, VOLUME_GROUP_NAME,
         },
     },
     config::{DeltaBackend, DeltaLocation},
 };
 use block_devs::BlckExt;
 use cryptpilot::{
-    fs::cmd::CheckCommandOutput,
+    fs::{block::devicemapper::dm_name, cmd::CheckCommandOutput},
     provider::{IntoProvider as _, KeyProvider as _, VolumeType},
     types::{IntegrityType, MakeFsType},
 };
 
 const CRYPTPILOT_LVM_SYSTEM_DIR: &str = "/usr/lib/cryptpilot/lvm/";
 
+/// The sector size of the LUKS2 volume on the delta partition. It is fixed rather than detected
+/// from the device, so that the delta volume is always laid out the same as by earlier versions.
+const DELTA_LUKS2_SECTOR_SIZE: u32 = 4096;
+
 pub async fn setup_volumes_required_by_fde() -> Result<()> {
     let fde_config = crate::config::get_fde_config_source()
         .await
@@ -44,11 +48,7 @@ pub async fn setup_volumes_required_by_fde() -> Result<()> {
         volume_group_name = VOLUME_GROUP_NAME,
         "[ 1/4 ] Checking and activating LVM volume group"
     );
-    Command::new("vgchange")
-        .args(["-a", "y", VOLUME_GROUP_NAME])
-        .run()
-        .await
-        .with_context(|| format!("Failed to activate LVM volume group '{VOLUME_GROUP_NAME}'"))?;
+    crate::cmd::diagnose::check_fde_setup(&fde_config).await?;
 
     // 2. Load the root-hash and add it to the AAEL
     tracing::info!("[ 2/4 ] Loading root-hash");
@@ -67,7 +67,7 @@ pub async fn setup_volumes_required_by_fde() -> Result<()> {
     if let Some(encrypt) = &fde_config.rootfs.encrypt {
         // Setup dm-crypt for rootfs lv if required (optional)
         tracing::info!("Fetching passphrase for rootfs volume");
-        let provider = encrypt.key_provider.clone().into_provider();
+        let provider = encrypt.clone().into_provider();
 
         if matches!(provider.volume_type(), VolumeType::Temporary) {
             bail!(
@@ -76,10 +76,13 @@ pub async fn setup_volumes_required_by_fde() -> Result<()> {
             )
         }
 
-        let passphrase = provider
-            .get_key()
+        let candidates = provider
+            .get_keys()
             .await
             .context("Failed to get passphrase")?;
+        let passphrase =
+            cryptpilot::fs::luks2::select_passphrase(Path::new(ROOTFS_LOGICAL_VOLUME), candidates)
+                .await?;
 
         tracing::info!("Setting up dm-crypt for rootfs volume");
         cryptpilot::fs::luks2::open_with_check_passphrase(
@@ -87,6 +90,7 @@ pub async fn setup_volumes_required_by_fde() -> Result<()> {
             Path::new(ROOTFS_LOGICAL_VOLUME),
             &passphrase,
             IntegrityType::None,
+            false,
         )
         .await?;
     } else {
@@ -98,18 +102,18 @@ pub async fn setup_volumes_required_by_fde() -> Result<()> {
     let backend = fde_config.rootfs.delta_backend.unwrap_or_default();
 
     let (dm_verity_output_name, dm_verity_output_device) = match backend {
-        DeltaBackend::Overlayfs => (ROOTFS_NAME, Path::new(ROOTFS_DEVICE)),
-        DeltaBackend::DmSnapshot => (ROOTFS_VERITY_NAME, Path::new(ROOTFS_VERITY_DEVICE)),
+        DeltaBackend::Overlayfs => (ROOTFS_NAME, rootfs_device()),
+        DeltaBackend::DmSnapshot => (ROOTFS_VERITY_NAME, rootfs_verity_device()),
     };
 
     setup_rootfs_dm_verity(
         dm_verity_output_name,
         &metadata.root_hash,
-        Path::new(if fde_config.rootfs.encrypt.is_some() {
-            ROOTFS_DECRYPTED_LAYER_DEVICE
+        &if fde_config.rootfs.encrypt.is_some() {
+            rootfs_decrypted_layer_device()
         } else {
-            ROOTFS_LOGICAL_VOLUME
-        }),
+            PathBuf::from(ROOTFS_LOGICAL_VOLUME)
+        },
     )
     .await?;
     // Now we have the rootfs ro part
@@ -145,31 +149,32 @@ pub async fn setup_volumes_required_by_fde() -> Result<()> {
             // Setup delta volume based on backend type
             match backend {
                 DeltaBackend::Overlayfs => {
-                    let delta_device = Path::new(DELTA_DEVICE);
+                    let delta_device = delta_device();
                     if recreate {
                         tracing::info!("Creating ext4 fs on delta volume");
                         cryptpilot::fs::mkfs::force_mkfs(
-                            delta_device,
+                            &delta_device,
                             &MakeFsType::Ext4,
+                            None,
                             integrity,
                         )
                         .await?;
                     } else {
                         // Resize existing filesystem to fill the expanded device
-                        resize_ext4_filesystem(delta_device).await?;
+                        resize_ext4_filesystem(&delta_device).await?;
                     }
                 }
                 DeltaBackend::DmSnapshot => {
                     // Build dm-snapshot device chain
                     setup_dm_snapshot_device_chain(
-                        dm_verity_output_device,
-                        Path::new(DELTA_DEVICE),
+                        &dm_verity_output_device,
+                        &delta_device(),
                         matches!(delta_location, DeltaLocation::DiskPersist),
                     )
                     .await?;
 
                     // Resize rootfs filesystem to fill the expanded device after building snapshot chain
-                    resize_ext4_filesystem(Path::new(ROOTFS_DEVICE)).await?;
+                    resize_ext4_filesystem(&rootfs_device()).await?;
                 }
             }
 
@@ -191,10 +196,10 @@ pub async fn setup_volumes_required_by_fde() -> Result<()> {
                     tracing::info!("Creating zram device for COW storage");
                     let cow_device = create_zram_cow_device().await?;
                     // Build dm-snapshot device chain
-                    setup_dm_snapshot_device_chain(dm_verity_output_device, &cow_device, false)
+                    setup_dm_snapshot_device_chain(&dm_verity_output_device, &cow_device, false)
                         .await?;
                     // Resize rootfs filesystem to fill the expanded device after building snapshot chain
-                    resize_ext4_filesystem(Path::new(ROOTFS_DEVICE)).await?;
+                    resize_ext4_filesystem(&rootfs_device()).await?;
                 }
             }
         }
@@ -261,7 +266,7 @@ async fn setup_rootfs_dm_verity(
         Command::new("veritysetup")
             .arg("open")
             .arg(lower_dm_device)
-            .arg(dm_verity_output_name)
+            .arg(dm_name(dm_verity_output_name))
             .arg(ROOTFS_HASH_LOGICAL_VOLUME)
             .arg(root_hash)
             .run()
@@ -359,7 +364,7 @@ async fn setup_delta_volume_luks2(
     delta_location: DeltaLocation,
 ) -> Result<(bool, IntegrityType)> {
     tracing::info!("Fetching passphrase for delta volume");
-    let provider = delta_config.encrypt.key_provider.clone().into_provider();
+    let provider = delta_config.encrypt.clone().into_provider();
     let passphrase = provider
         .get_key()
         .await
@@ -390,7 +395,14 @@ async fn setup_delta_volume_luks2(
     if recreate {
         // Create a LUKS volume on it
         tracing::info!("Creating LUKS2 on delta volume");
-        cryptpilot::fs::luks2::format(delta_logical_volume_dev, &passphrase, integrity).await?;
+        cryptpilot::fs::luks2::format(
+            delta_logical_volume_dev,
+            &passphrase,
+            integrity,
+            delta_config.encrypt.cipher.unwrap_or_default(),
+            Some(DELTA_LUKS2_SECTOR_SIZE),
+        )
+        .await?;
     }
 
     // TODO: support change size of the LUKS2 volume and inner ext4 file system
@@ -400,6 +412,7 @@ async fn setup_delta_volume_luks2(
         delta_logical_volume_dev,
         &passphrase,
         integrity,
+        false,
     )
     .await?;
 
@@ -487,7 +500,9 @@ async fn setup_dm_snapshot_device_chain(
     );
     Command::new("dmsetup")
         .arg("create")
-        .arg(ROOTFS_EXTENDED_NAME)
+        .arg(dm_name(ROOTFS_EXTENDED_NAME))
+        .arg("--uuid")
+        .arg(dm_uuid_of(ROOTFS_EXTENDED_NAME))
         .arg("--table")
         .arg(format!(
             "0 {} linear {} 0\n{} {} zero",
@@ -529,6 +544,7 @@ async fn setup_dm_snapshot_device_chain(
                 fs_type,
                 pt_type,
                 subsystem,
+                ..
             } => {
                 // Some other filesystem/partition signature detected — protect user data
                 bail!(
@@ -543,12 +559,14 @@ async fn setup_dm_snapshot_device_chain(
     tracing::info!("Creating dm-snapshot device");
     Command::new("dmsetup")
         .arg("create")
-        .arg(ROOTFS_NAME)
+        .arg(dm_name(ROOTFS_NAME))
+        .arg("--uuid")
+        .arg(dm_uuid_of(ROOTFS_NAME))
         .arg("--table")
         .arg(format!(
             "0 {} snapshot {} {} {} 16", // chunk size is 16 sectors (8KB)
             linear_size,
-            ROOTFS_EXTENDED_DEVICE,
+            rootfs_extended_device().to_string_lossy(),
             cow_device.to_string_lossy(),
             if persistent { "PO" } else { "N" }
         ))
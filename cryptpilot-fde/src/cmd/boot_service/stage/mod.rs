@@ -1,3 +1,7 @@
+use std::path::PathBuf;
+
+use cryptpilot::fs::block::devicemapper::{dm_name, dm_path};
+
 pub mod after_sysroot;
 pub mod before_sysroot;
 
@@ -11,22 +15,44 @@ pub const ROOTFS_HASH_LOGICAL_VOLUME: &str = "/dev/mapper/cryptpilot-rootfs_hash
 // Delta logical volume in LVM
 pub const DELTA_LOGICAL_VOLUME: &str = "/dev/mapper/cryptpilot-delta";
 
+// The names of the device mapper devices below are the ones before the prefix set by
+// `cryptpilot::fs::block::devicemapper::set_dm_name_prefix` is applied, the device paths include it.
+
 // The final rootfs device - Used for mounting as root filesystem
 pub const ROOTFS_NAME: &str = "rootfs";
-pub const ROOTFS_DEVICE: &str = "/dev/mapper/rootfs";
+pub fn rootfs_device() -> PathBuf {
+    dm_path(ROOTFS_NAME)
+}
 
 // Rootfs decrypted which will be used as backend for dm-verity
 pub const ROOTFS_DECRYPTED_NAME: &str = "rootfs_decrypted";
-pub const ROOTFS_DECRYPTED_LAYER_DEVICE: &str = "/dev/mapper/rootfs_decrypted";
+pub fn rootfs_decrypted_layer_device() -> PathBuf {
+    dm_path(ROOTFS_DECRYPTED_NAME)
+}
 
 // The final delta partition device - LUKS2 encrypted device for delta partition
 pub const DELTA_NAME: &str = "delta";
-pub const DELTA_DEVICE: &str = "/dev/mapper/delta";
+pub fn delta_device() -> PathBuf {
+    dm_path(DELTA_NAME)
+}
 
 // dm-snapshot related constants
 // dm-verity device name for dm-snapshot backend
 pub const ROOTFS_VERITY_NAME: &str = "rootfs_verity";
-pub const ROOTFS_VERITY_DEVICE: &str = "/dev/mapper/rootfs_verity";
+pub fn rootfs_verity_device() -> PathBuf {
+    dm_path(ROOTFS_VERITY_NAME)
+}
 // dm-linear device combining dm-verity and zero target (extended rootfs for snapshot)
 pub const ROOTFS_EXTENDED_NAME: &str = "rootfs_extended";
-pub const ROOTFS_EXTENDED_DEVICE: &str = "/dev/mapper/rootfs_extended";
+pub fn rootfs_extended_device() -> PathBuf {
+    dm_path(ROOTFS_EXTENDED_NAME)
+}
+
+// Prefix of the device mapper UUIDs of the layers created with dmsetup, the layers created with
+// cryptsetup or veritysetup have UUIDs assigned by them (e.g. "CRYPT-LUKS2-...")
+pub const DM_UUID_PREFIX: &str = "CRYPTPILOT-FDE-";
+
+/// The device mapper UUID of a layer created with dmsetup.
+pub fn dm_uuid_of(name: &str) -> String {
+    format!("{DM_UUID_PREFIX}{}", dm_name(name))
+}
@@ -0,0 +1,323 @@
+use std::path::Path;
+
+use anyhow::{Context as _, Result};
+use cryptpilot::fs::{
+    block::devicemapper::{dm_name, dm_path, get_dm_uuid},
+    cmd::CheckCommandOutput as _,
+    perm::{self, FileKind},
+};
+use serde::Serialize;
+use tokio::process::Command;
+
+use crate::cli::BootStage;
+
+use super::stage::{
+    DELTA_NAME, ROOTFS_DECRYPTED_NAME, ROOTFS_EXTENDED_NAME, ROOTFS_NAME, ROOTFS_VERITY_NAME,
+};
+
+pub const CRYPTPILOT_FDE_SUMMARY_PATH: &str = "/run/cryptpilot/fde-summary.json";
+
+/// The mount points which are set up for FDE.
+const FDE_MOUNT_POINTS: [&str; 3] = ["/sysroot", "/delta_volume", "/ram_overlay"];
+
+/// Summary of the device mapper stack and mounts established by the FDE boot stages.
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct FdeSummary {
+    pub stage: String,
+    /// The device mapper layers, from the bottom to the top.
+    pub layers: Vec<DmLayer>,
+    pub mounts: Vec<MountEntry>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct DmLayer {
+    pub name: String,
+    /// The device mapper target type of the layer, e.g. "crypt" or "verity".
+    pub target: String,
+    pub size_bytes: u64,
+    /// The device mapper UUID of the layer, which is stable for scripting with `dmsetup` and udev.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uuid: Option<String>,
+    /// Whether data integrity is enabled, only for "crypt" layers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub integrity: Option<bool>,
+    /// The root hash, only for "verity" layers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub root_hash: Option<String>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct MountEntry {
+    pub source: String,
+    pub target: String,
+    pub fstype: String,
+}
+
+/// Log a summary of what is set up after the stage, and write it to [`CRYPTPILOT_FDE_SUMMARY_PATH`]
+/// as JSON. This is best-effort, any error is logged and ignored.
+pub async fn report_fde_summary(stage: &BootStage) {
+    let summary = collect_fde_summary(stage).await;
+    if summary.layers.is_empty() {
+        tracing::info!("No device mapper layer is set up for FDE, skip the summary");
+        return;
+    }
+    log_fde_summary(&summary);
+
+    if let Err(error) = save_fde_summary(&summary).await {
+        tracing::warn!(?error, "Failed to save FDE summary, ignore it");
+    }
+}
+
+async fn collect_fde_summary(stage: &BootStage) -> FdeSummary {
+    let mut summary = FdeSummary {
+        stage: stage.to_string(),
+        ..Default::default()
+    };
+
+    for name in [
+        ROOTFS_DECRYPTED_NAME,
+        ROOTFS_VERITY_NAME,
+        ROOTFS_EXTENDED_NAME,
+        ROOTFS_NAME,
+        DELTA_NAME,
+    ] {
+        if !dm_path(name).exists() {
+            continue;
+        }
+        match Command::new("dmsetup")
+            .args(["table", &dm_name(name)])
+            .run()
+            .await
+        {
+            Ok(table) => match parse_dm_table(name, &String::from_utf8_lossy(&table)) {
+                Some(mut layer) => {
+                    match get_dm_uuid(&dm_name(name)) {
+                        Ok(uuid) => layer.uuid = uuid,
+                        Err(error) => {
+                            tracing::warn!(
+                                ?error,
+                                "Failed to get device mapper UUID of {name}, ignore it"
+                            )
+                        }
+                    }
+                    summary.layers.push(layer)
+                }
+                None => tracing::warn!("Failed to parse device mapper table of {name}, ignore it"),
+            },
+            Err(error) => {
+                tracing::warn!(
+                    ?error,
+                    "Failed to get device mapper table of {name}, ignore it"
+                )
+            }
+        }
+    }
+
+    match tokio::fs::read_to_string("/proc/self/mounts").await {
+        Ok(mounts) => summary.mounts = parse_fde_mounts(&mounts),
+        Err(error) => tracing::warn!(?error, "Failed to read mounts, ignore it"),
+    }
+
+    summary
+}
+
+/// Parse the output of `dmsetup table`. Only the fields which do not contain secrets are kept.
+fn parse_dm_table(name: &str, table: &str) -> Option<DmLayer> {
+    let mut layer: Option<DmLayer> = None;
+    for line in table.lines().filter(|line| !line.trim().is_empty()) {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        let (Some(length), Some(target)) = (
+            fields.get(1).and_then(|length| length.parse::<u64>().ok()),
+            fields.get(2),
+        ) else {
+            return None;
+        };
+        let args = &fields[3..];
+
+        let layer = layer.get_or_insert_with(|| DmLayer {
+            name: name.to_owned(),
+            target: target.to_string(),
+            size_bytes: 0,
+            uuid: None,
+            integrity: None,
+            root_hash: None,
+        });
+        // The length is in 512-byte sectors
+        layer.size_bytes += length * 512;
+        match *target {
+            "crypt" => {
+                // The integrity is shown either in the optional parameters, or in the cipher spec
+                let integrity = args.iter().any(|arg| arg.starts_with("integrity:"))
+                    || args
+                        .first()
+                        .is_some_and(|cipher| cipher.starts_with("capi:"));
+                *layer.integrity.get_or_insert(false) |= integrity;
+            }
+            "verity" => {
+                // <version> <dev> <hash_dev> <data_block_size> <hash_block_size> <num_data_blocks> <hash_start_block> <algorithm> <digest> ...
+                if let Some(root_hash) = args.get(8) {
+                    layer.root_hash = Some(root_hash.to_string());
+                }
+            }
+            _ => {}
+        }
+    }
+    layer
+}
+
+/// Parse the content of `/proc/self/mounts`, and keep the mounts on the mount points set up for FDE.
+fn parse_fde_mounts(mounts: &str) -> Vec<MountEntry> {
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let (source, target, fstype) = (fields.next()?, fields.next()?, fields.next()?);
+            FDE_MOUNT_POINTS
+                .iter()
+                .any(|mount_point| {
+                    target == *mount_point
+                        || target
+                            .strip_prefix(mount_point)
+                            .is_some_and(|rest| rest.starts_with('/'))
+                })
+                .then(|| MountEntry {
+                    source: source.to_owned(),
+                    target: target.to_owned(),
+                    fstype: fstype.to_owned(),
+                })
+        })
+        .collect()
+}
+
+fn log_fde_summary(summary: &FdeSummary) {
+    tracing::info!(
+        "Summary of stage {}: {} device mapper layer(s), {} mount(s)",
+        summary.stage,
+        summary.layers.len(),
+        summary.mounts.len()
+    );
+    for layer in &summary.layers {
+        let mut details = vec![];
+        if let Some(uuid) = &layer.uuid {
+            details.push(format!("uuid {uuid}"));
+        }
+        if let Some(integrity) = layer.integrity {
+            details.push(format!(
+                "integrity {}",
+                if integrity { "on" } else { "off" }
+            ));
+        }
+        if let Some(root_hash) = &layer.root_hash {
+            details.push(format!("root hash {root_hash}"));
+        }
+        tracing::info!(
+            "  layer {}: {}, {:.2} GiB{}",
+            dm_path(&layer.name).display(),
+            layer.target,
+            layer.size_bytes as f64 / (1024 * 1024 * 1024) as f64,
+            if details.is_empty() {
+                "".to_owned()
+            } else {
+                format!(", {}", details.join(", "))
+            }
+        );
+    }
+    for mount in &summary.mounts {
+        tracing::info!(
+            "  mount {} on {} type {}",
+            mount.source,
+            mount.target,
+            mount.fstype
+        );
+    }
+}
+
+async fn save_fde_summary(summary: &FdeSummary) -> Result<()> {
+    let path = Path::new(CRYPTPILOT_FDE_SUMMARY_PATH);
+    if let Some(parent) = path.parent() {
+        perm::create_dir_all(parent).await?;
+    }
+    perm::write_file(
+        path,
+        serde_json::to_string_pretty(summary)?,
+        FileKind::Public,
+    )
+    .await
+    .with_context(|| format!("Failed to write FDE summary to {path:?}"))?;
+    tracing::info!("Successfully wrote FDE summary to {CRYPTPILOT_FDE_SUMMARY_PATH}");
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn test_parse_dm_table() {
+        let layer = parse_dm_table(
+            "rootfs",
+            "0 2097152 verity 1 252:2 252:3 4096 4096 262144 1 sha256 4392c64d2cbd1b1c52e1a1bbc2a0ba2f2b0a4d1e8b2b33a8f3f1d0b0a3c0e2f1 0000\n",
+        )
+        .unwrap();
+        assert_eq!(layer.target, "verity");
+        assert_eq!(layer.size_bytes, 1024 * 1024 * 1024);
+        assert_eq!(layer.integrity, None);
+        assert_eq!(
+            layer.root_hash.as_deref(),
+            Some("4392c64d2cbd1b1c52e1a1bbc2a0ba2f2b0a4d1e8b2b33a8f3f1d0b0a3c0e2f1")
+        );
+
+        let layer = parse_dm_table(
+            "delta",
+            "0 4194304 crypt capi:authenc(hmac(sha256),xts(aes))-plain64 :96:logon:cryptsetup:0f1e 0 253:1 0 1 integrity:32:aead\n",
+        )
+        .unwrap();
+        assert_eq!(layer.target, "crypt");
+        assert_eq!(layer.size_bytes, 2 * 1024 * 1024 * 1024);
+        assert_eq!(layer.integrity, Some(true));
+
+        let layer = parse_dm_table(
+            "rootfs_decrypted",
+            "0 2097152 crypt aes-xts-plain64 :64:logon:cryptsetup:0f1e 0 253:0 32768\n",
+        )
+        .unwrap();
+        assert_eq!(layer.integrity, Some(false));
+
+        let layer = parse_dm_table(
+            "rootfs_extended",
+            "0 2097152 linear 253:4 0\n2097152 2097152 zero\n",
+        )
+        .unwrap();
+        assert_eq!(layer.target, "linear");
+        assert_eq!(layer.size_bytes, 2 * 1024 * 1024 * 1024);
+
+        assert!(parse_dm_table("rootfs", "").is_none());
+        assert!(parse_dm_table("rootfs", "invalid table\n").is_none());
+    }
+
+    #[test]
+    fn test_parse_fde_mounts() {
+        let mounts = parse_fde_mounts(
+            "proc /proc proc rw,nosuid,nodev,noexec,relatime 0 0\n\
+             /dev/mapper/rootfs /sysroot ext4 ro,relatime 0 0\n\
+             /dev/mapper/delta /delta_volume ext4 rw,relatime 0 0\n\
+             /dev/mapper/rootfs /sysroot overlay rw,relatime,lowerdir=/sysroot 0 0\n\
+             /dev/mapper/delta /sysroot/var/lib/docker ext4 rw,relatime 0 0\n\
+             tmpfs /sysroot_bak tmpfs rw 0 0\n",
+        );
+        assert_eq!(
+            mounts
+                .iter()
+                .map(|mount| (mount.target.as_str(), mount.fstype.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("/sysroot", "ext4"),
+                ("/delta_volume", "ext4"),
+                ("/sysroot", "overlay"),
+                ("/sysroot/var/lib/docker", "ext4"),
+            ]
+        );
+    }
+}
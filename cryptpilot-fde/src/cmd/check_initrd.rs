@@ -0,0 +1,407 @@
+use std::{
+    ffi::OsStr,
+    os::unix::ffi::OsStrExt as _,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context as _, Result};
+use async_trait::async_trait;
+use object::{Object as _, ObjectSection as _};
+use tempfile::TempDir;
+
+use crate::{
+    cli::BootStage,
+    disk::{
+        artifacts::BootArtifacts as _,
+        current::OnCurrentSystemFdeDisk,
+        external::OnExternalFdeDisk,
+        initrd::{read_initrd_entries, InitrdEntry},
+        uki::UKI_FILE_PATH,
+        BootArtifactsType, FdeBootType, FdeDisk,
+    },
+};
+
+/// The file in the initrd recording the version of cryptpilot the initrd is built with, which is
+/// written by the dracut module.
+pub const INITRD_VERSION_MARKER_PATH: &str = "/usr/lib/cryptpilot/initrd-version";
+
+const GUEST_BINARY_PATH: &str = "/usr/bin/cryptpilot-fde-guest";
+
+const SYSTEMD_UNIT_DIR: &str = "/usr/lib/systemd/system";
+
+/// The systemd units running the boot stages, which must be installed and enabled in the initrd.
+const BOOT_STAGE_UNITS: [(&str, BootStage); 2] = [
+    (
+        "cryptpilot-fde-before-sysroot.service",
+        BootStage::InitrdFdeBeforeSysroot,
+    ),
+    (
+        "cryptpilot-fde-after-sysroot.service",
+        BootStage::InitrdFdeAfterSysroot,
+    ),
+];
+
+pub struct CheckInitrdCommand {
+    pub initrd: Option<PathBuf>,
+    pub disk: Option<PathBuf>,
+}
+
+/// An initrd to check, with a description of where it comes from.
+struct InitrdToCheck {
+    source: String,
+    initrd: Vec<u8>,
+}
+
+#[async_trait]
+impl super::Command for CheckInitrdCommand {
+    async fn run(&self) -> Result<()> {
+        let initrds = self.load_initrds().await?;
+        if initrds.is_empty() {
+            bail!("No initrd found to check");
+        }
+
+        let mut stale_count = 0;
+        for InitrdToCheck { source, initrd } in &initrds {
+            let problems = check_initrd(initrd)
+                .await
+                .with_context(|| format!("Failed to check the initrd in {source}"))?;
+            if problems.is_empty() {
+                println!("[OK] {source}: the cryptpilot boot hooks are up to date");
+                continue;
+            }
+
+            stale_count += 1;
+            println!("[STALE] {source}: the initrd needs to be regenerated");
+            for problem in &problems {
+                println!("    - {problem}");
+            }
+        }
+
+        if stale_count > 0 {
+            println!("Regenerate the initrd with: {}", self.regenerate_command());
+            bail!("Found {stale_count} initrd(s) with stale cryptpilot boot hooks");
+        }
+
+        Ok(())
+    }
+}
+
+impl CheckInitrdCommand {
+    async fn load_initrds(&self) -> Result<Vec<InitrdToCheck>> {
+        if let Some(path) = &self.initrd {
+            let data = tokio::fs::read(path)
+                .await
+                .with_context(|| format!("Failed to read initrd {path:?}"))?;
+            return Ok(vec![InitrdToCheck {
+                source: format!("{path:?}"),
+                initrd: extract_initrd_from_uki(&data)?.unwrap_or(data),
+            }]);
+        }
+
+        if let Some(disk) = &self.disk {
+            let fde_disk = OnExternalFdeDisk::new_from_disk(disk).await?;
+            return Ok(fde_disk
+                .load_embedded_initrds()
+                .await?
+                .into_iter()
+                .map(|embedded_initrd| InitrdToCheck {
+                    source: format!("{:?} on {disk:?}", embedded_initrd.path),
+                    initrd: embedded_initrd.initrd,
+                })
+                .collect());
+        }
+
+        // The initrd of the running kernel on the current system
+        let kernel_release = tokio::fs::read_to_string("/proc/sys/kernel/osrelease")
+            .await
+            .context("Failed to get the release of the running kernel")?;
+        let path = PathBuf::from(format!("/boot/initramfs-{}.img", kernel_release.trim()));
+        if path.exists() {
+            return Ok(vec![InitrdToCheck {
+                source: format!("{path:?}"),
+                initrd: tokio::fs::read(&path)
+                    .await
+                    .with_context(|| format!("Failed to read initrd {path:?}"))?,
+            }]);
+        }
+
+        let fde_disk = OnCurrentSystemFdeDisk::new().await?;
+        if !matches!(fde_disk.fde_boot_type(), FdeBootType::Uki) {
+            bail!("No initrd found at {path:?}, please specify one with `--initrd`");
+        }
+        let BootArtifactsType::Uki(uki_boot_artifacts) =
+            fde_disk.extract_boot_artifacts(false).await?
+        else {
+            bail!("No UKI image found on the current system");
+        };
+        Ok(uki_boot_artifacts
+            .extract_kernel_artifacts()
+            .await?
+            .into_iter()
+            .map(|kernel_artifacts| InitrdToCheck {
+                source: format!("the UKI image {UKI_FILE_PATH:?}"),
+                initrd: kernel_artifacts.initrd,
+            })
+            .collect())
+    }
+
+    fn regenerate_command(&self) -> String {
+        let kernel_release = self
+            .initrd
+            .as_ref()
+            .and_then(|path| path.file_name())
+            .and_then(|name| {
+                name.to_str()?
+                    .strip_prefix("initramfs-")?
+                    .strip_suffix(".img")
+                    .map(ToOwned::to_owned)
+            });
+        match (&self.initrd, kernel_release) {
+            (Some(path), Some(kernel_release)) => {
+                format!("dracut --force {} {kernel_release}", path.display())
+            }
+            _ if self.disk.is_some() => {
+                "re-run `cryptpilot-convert` on the original disk image, or run `dracut --force --regenerate-all` in the disk image".to_owned()
+            }
+            _ => "dracut --force --regenerate-all".to_owned(),
+        }
+    }
+}
+
+/// Get the `.initrd` section if the data is a UKI image, or `None` if it is not.
+fn extract_initrd_from_uki(data: &[u8]) -> Result<Option<Vec<u8>>> {
+    let Ok(uki_file) = object::File::parse(data) else {
+        return Ok(None);
+    };
+    let Some(section) = uki_file.section_by_name(".initrd") else {
+        return Ok(None);
+    };
+    Ok(Some(section.data()?.to_owned()))
+}
+
+/// Unpack the cryptpilot related files from the initrd, and check if the boot hooks are up to date.
+/// Returns the problems found.
+async fn check_initrd(initrd: &[u8]) -> Result<Vec<String>> {
+    let temp_dir = TempDir::new()?;
+    let root_dir = temp_dir.path();
+    let entries = read_initrd_entries(initrd)
+        .await
+        .context("Failed to read initrd")?;
+    unpack_cryptpilot_files(&entries, root_dir).await?;
+
+    check_unpacked_initrd(root_dir).await
+}
+
+/// Write the cryptpilot related files in the initrd entries to the root dir.
+async fn unpack_cryptpilot_files(entries: &[InitrdEntry], root_dir: &Path) -> Result<()> {
+    let patterns = [
+        GUEST_BINARY_PATH.to_owned(),
+        INITRD_VERSION_MARKER_PATH.to_owned(),
+        format!("{SYSTEMD_UNIT_DIR}/cryptpilot-fde-*"),
+        "/etc/systemd/system/*/cryptpilot-fde-*".to_owned(),
+    ]
+    .iter()
+    .map(|pattern| glob::Pattern::new(pattern))
+    .collect::<Result<Vec<_>, _>>()?;
+    let match_options = glob::MatchOptions {
+        require_literal_separator: true,
+        ..Default::default()
+    };
+
+    for entry in entries {
+        if !patterns
+            .iter()
+            .any(|pattern| pattern.matches_path_with(&entry.path, match_options))
+        {
+            continue;
+        }
+
+        let path = path_in_root(root_dir, &entry.path);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        // A later entry with the same path takes effect
+        if tokio::fs::symlink_metadata(&path).await.is_ok() {
+            tokio::fs::remove_file(&path).await?;
+        }
+        if entry.is_symlink() {
+            let target = PathBuf::from(OsStr::from_bytes(&entry.data));
+            tokio::fs::symlink(target, &path).await?;
+        } else if entry.is_file() {
+            tokio::fs::write(&path, &entry.data).await?;
+        }
+    }
+    Ok(())
+}
+
+fn path_in_root(root_dir: &Path, path: &Path) -> PathBuf {
+    root_dir.join(path.strip_prefix("/").unwrap_or(path))
+}
+
+async fn check_unpacked_initrd(root_dir: &Path) -> Result<Vec<String>> {
+    let mut problems = vec![];
+
+    if !path_in_root(root_dir, Path::new(GUEST_BINARY_PATH)).exists() {
+        problems.push(format!("{GUEST_BINARY_PATH} is not installed"));
+    }
+
+    for (unit, stage) in BOOT_STAGE_UNITS {
+        let unit_path = Path::new(SYSTEMD_UNIT_DIR).join(unit);
+        let Ok(unit_content) = tokio::fs::read_to_string(path_in_root(root_dir, &unit_path)).await
+        else {
+            problems.push(format!("The systemd unit {unit_path:?} is not installed"));
+            continue;
+        };
+
+        if !unit_content.contains(&format!("--stage {stage}")) {
+            problems.push(format!(
+                "The systemd unit {unit_path:?} does not run the boot stage \"{stage}\""
+            ));
+        }
+
+        let pattern = path_in_root(root_dir, Path::new("/etc/systemd/system/*"))
+            .join(unit)
+            .to_string_lossy()
+            .into_owned();
+        let enabled = glob::glob(&pattern)
+            .context("Failed to find systemd unit links with glob pattern")?
+            .flatten()
+            .next()
+            .is_some();
+        if !enabled {
+            problems.push(format!("The systemd unit {unit} is not enabled"));
+        }
+    }
+
+    let expected_version = env!("CARGO_PKG_VERSION");
+    match tokio::fs::read_to_string(path_in_root(
+        root_dir,
+        Path::new(INITRD_VERSION_MARKER_PATH),
+    ))
+    .await
+    {
+        Ok(version) if version.trim() == expected_version => {}
+        Ok(version) => problems.push(format!(
+            "The initrd is built with cryptpilot {}, but the installed version is {expected_version}",
+            version.trim()
+        )),
+        Err(_) => problems.push(format!(
+            "No version marker {INITRD_VERSION_MARKER_PATH} found, the initrd is built with an older version of cryptpilot"
+        )),
+    }
+
+    Ok(problems)
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    async fn write_file(root_dir: &Path, path: &str, content: &str) -> Result<()> {
+        let path = path_in_root(root_dir, Path::new(path));
+        tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+        tokio::fs::write(path, content).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_check_unpacked_initrd() -> Result<()> {
+        let root_dir = TempDir::new()?;
+        let root_dir = root_dir.path();
+
+        write_file(root_dir, GUEST_BINARY_PATH, "").await?;
+        write_file(
+            root_dir,
+            INITRD_VERSION_MARKER_PATH,
+            &format!("{}\n", env!("CARGO_PKG_VERSION")),
+        )
+        .await?;
+        for (unit, stage) in BOOT_STAGE_UNITS {
+            write_file(
+                root_dir,
+                &format!("{SYSTEMD_UNIT_DIR}/{unit}"),
+                &format!("[Service]\nExecStart=/usr/bin/cryptpilot-fde-guest boot-service --stage {stage}\n"),
+            )
+            .await?;
+            write_file(
+                root_dir,
+                &format!("/etc/systemd/system/initrd-root-fs.target.requires/{unit}"),
+                "",
+            )
+            .await?;
+        }
+        assert!(check_unpacked_initrd(root_dir).await?.is_empty());
+
+        // An initrd built before the boot stages were renamed, and without the version marker
+        write_file(
+            root_dir,
+            "/usr/lib/systemd/system/cryptpilot-fde-after-sysroot.service",
+            "[Service]\nExecStart=/usr/bin/cryptpilot-fde boot-service --stage after-sysroot\n",
+        )
+        .await?;
+        tokio::fs::remove_file(path_in_root(
+            root_dir,
+            Path::new(INITRD_VERSION_MARKER_PATH),
+        ))
+        .await?;
+        tokio::fs::remove_dir_all(path_in_root(
+            root_dir,
+            Path::new("/etc/systemd/system/initrd-root-fs.target.requires"),
+        ))
+        .await?;
+
+        let problems = check_unpacked_initrd(root_dir).await?;
+        assert_eq!(problems.len(), 4, "{problems:?}");
+        assert!(problems[1].contains("does not run the boot stage \"initrd-fde-after-sysroot\""));
+        assert!(problems[3].contains("No version marker"));
+
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_check_initrd() -> Result<()> {
+        use crate::disk::initrd::{tests::pack_initrd, InitrdCompression};
+
+        let temp_dir = TempDir::new()?;
+        let root_dir = temp_dir.path().join("root");
+        write_file(&root_dir, GUEST_BINARY_PATH, "").await?;
+        write_file(
+            &root_dir,
+            INITRD_VERSION_MARKER_PATH,
+            &format!("{}\n", env!("CARGO_PKG_VERSION")),
+        )
+        .await?;
+        let wants_dir = path_in_root(
+            &root_dir,
+            Path::new("/etc/systemd/system/initrd-root-fs.target.requires"),
+        );
+        tokio::fs::create_dir_all(&wants_dir).await?;
+        for (unit, stage) in BOOT_STAGE_UNITS {
+            let unit_path = format!("{SYSTEMD_UNIT_DIR}/{unit}");
+            write_file(
+                &root_dir,
+                &unit_path,
+                &format!("[Service]\nExecStart=/usr/bin/cryptpilot-fde-guest boot-service --stage {stage}\n"),
+            )
+            .await?;
+            tokio::fs::symlink(
+                format!("../../../..{SYSTEMD_UNIT_DIR}/{unit}"),
+                wants_dir.join(unit),
+            )
+            .await?;
+        }
+        write_file(&root_dir, "/etc/os-release", "ID=test\n").await?;
+
+        let initrd = pack_initrd(&root_dir, InitrdCompression::Zstd).await?;
+        assert!(check_initrd(&initrd).await?.is_empty());
+
+        // Missing everything
+        tokio::fs::remove_dir_all(&root_dir).await?;
+        write_file(&root_dir, "/etc/os-release", "ID=test\n").await?;
+        let initrd = pack_initrd(&root_dir, InitrdCompression::Xz).await?;
+        assert_eq!(check_initrd(&initrd).await?.len(), 4);
+
+        Ok(())
+    }
+}
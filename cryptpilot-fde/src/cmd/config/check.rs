@@ -70,7 +70,7 @@ impl super::super::Command for ConfigCheckCommand {
                     if self.config_check_options.skip_check_passphrase {
                         tracing::warn!("Skipping key check for FDE volume \"{}\" due to \"--skip-check-passphrase\" is set", volume_debug_name);
                     } else {
-                        let key_provider = encrypt.key_provider.clone().into_provider();
+                        let key_provider = encrypt.clone().into_provider();
                         match key_provider.get_key().await.with_context(|| {
                             format!(
                                 "Failed to get key for FDE \"{}\" volume from key provider {}",
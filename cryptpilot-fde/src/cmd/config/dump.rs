@@ -1,11 +1,11 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use anyhow::Result;
+use anyhow::{Context as _, Result};
 use async_trait::async_trait;
 use futures::StreamExt;
 
 use crate::{
-    config::cloud_init::CLOUD_INIT_FDE_CONFIG_BUNDLE_HEADER,
+    config::{cloud_init::CLOUD_INIT_FDE_CONFIG_BUNDLE_HEADER, FdeConfigBundle},
     disk::{
         artifacts::BootArtifacts, current::OnCurrentSystemFdeDisk, external::OnExternalFdeDisk,
         BootArtifactsType, FdeDisk,
@@ -14,6 +14,8 @@ use crate::{
 
 pub struct ConfigDumpCommand {
     pub disk: Option<PathBuf>,
+    pub diff: Option<PathBuf>,
+    pub redact: bool,
 }
 
 #[async_trait]
@@ -26,7 +28,7 @@ impl super::super::Command for ConfigDumpCommand {
             None => Box::new(OnCurrentSystemFdeDisk::new().await?),
         };
 
-        let boot_artifacts = fde_disk.extract_boot_artifacts().await?;
+        let boot_artifacts = fde_disk.extract_boot_artifacts(false).await?;
         tracing::debug!("Starting to extract cryptpilot fde config");
 
         let kernel_artifacts = match boot_artifacts {
@@ -64,7 +66,24 @@ impl super::super::Command for ConfigDumpCommand {
             .next()
             .ok_or_else(|| anyhow::anyhow!("No fde config bundle found"))?;
 
+        if let Some(reference) = &self.diff {
+            return Self::diff_with_reference(&fde_config_bundle, reference).await;
+        }
+
         let hash_hex = fde_config_bundle.gen_hash_hex()?;
+        if self.redact {
+            let redacted_content_pretty = fde_config_bundle.gen_redacted_content_pretty()?;
+            println!(
+                r#"# This config is generated by cryptpilot, with the secrets redacted. It cannot be used as the cloud-init user data of your instance.
+#
+# The sha384 hash of the unredacted config is: {hash_hex}
+
+
+{redacted_content_pretty}"#
+            );
+            return Ok(());
+        }
+
         let hash_content_pretty = fde_config_bundle.gen_hash_content_pretty()?;
 
         println!(
@@ -80,3 +99,33 @@ impl super::super::Command for ConfigDumpCommand {
         Ok(())
     }
 }
+
+impl ConfigDumpCommand {
+    async fn diff_with_reference(
+        fde_config_bundle: &FdeConfigBundle,
+        reference: &Path,
+    ) -> Result<()> {
+        let content = tokio::fs::read_to_string(reference)
+            .await
+            .with_context(|| format!("Failed to read reference config from {reference:?}"))?;
+        let expected: FdeConfigBundle = toml::from_str(&content)
+            .with_context(|| format!("Failed to parse reference config from {reference:?}"))?;
+
+        let entries = fde_config_bundle.diff(&expected)?;
+        if entries.is_empty() {
+            println!("The fde config bundle matches the reference config {reference:?}");
+            return Ok(());
+        }
+
+        println!(
+            "The fde config bundle differs from the reference config {reference:?} (-: expected only, +: actual only, ~: changed):"
+        );
+        for entry in &entries {
+            println!("{entry}");
+        }
+        anyhow::bail!(
+            "Found {} difference(s) between the fde config bundle and the reference config",
+            entries.len()
+        );
+    }
+}
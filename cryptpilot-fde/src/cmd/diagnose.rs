@@ -0,0 +1,604 @@
+use std::{fmt::Display, path::Path};
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use cryptpilot::fs::{
+    block::devicemapper::{dm_name, dm_path},
+    cmd::CheckCommandOutput as _,
+    luks2::VolumeInitState,
+};
+use serde::Serialize;
+use tokio::process::Command;
+
+use crate::{
+    cmd::boot_service::{
+        metadata::METADATA_PATH_IN_INITRD,
+        stage::{
+            rootfs_device, DELTA_LOGICAL_VOLUME, DELTA_NAME, ROOTFS_DECRYPTED_NAME,
+            ROOTFS_EXTENDED_NAME, ROOTFS_HASH_LOGICAL_VOLUME, ROOTFS_LOGICAL_VOLUME, ROOTFS_NAME,
+            ROOTFS_VERITY_NAME, VOLUME_GROUP_NAME,
+        },
+    },
+    config::{DeltaLocation, FdeConfig},
+};
+
+const RECONVERT_REPAIR: &str =
+    "The conversion of the disk was interrupted, re-run `cryptpilot-convert` on the original disk image";
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// The FDE setup cannot succeed until the problem is repaired.
+    Error,
+    /// The FDE setup can continue, but may not behave as expected.
+    Warning,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Error => write!(f, "ERROR"),
+            Severity::Warning => write!(f, "WARNING"),
+        }
+    }
+}
+
+/// The status of a component required by FDE.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum ComponentStatus {
+    Present,
+    /// The component exists, but is inconsistent with the FDE config or left in a bad state.
+    Degraded,
+    Missing,
+}
+
+/// A problem found in the layers required by FDE, with a suggestion to repair it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnosis {
+    pub severity: Severity,
+    pub component: &'static str,
+    pub status: ComponentStatus,
+    pub problem: String,
+    pub repair: String,
+}
+
+impl Display for Diagnosis {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "[{}] {}: {}\n    Repair: {}",
+            self.severity, self.component, self.problem, self.repair
+        )
+    }
+}
+
+/// The state of the layers required by FDE, as probed from the system.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FdeSetupState {
+    pub metadata_exists: bool,
+    /// The error of activating the LVM volume group, if it failed.
+    pub volume_group_error: Option<String>,
+    /// The init state of the rootfs logical volume, `None` if it does not exist.
+    pub rootfs_volume: Option<VolumeInitState>,
+    pub rootfs_hash_volume_exists: bool,
+    /// The init state of the delta logical volume, `None` if it does not exist.
+    pub delta_volume: Option<VolumeInitState>,
+    /// The device mapper layers of FDE which exist.
+    pub existing_mappings: Vec<&'static str>,
+    /// Whether the rootfs device is mounted, `None` if it is not checked.
+    pub rootfs_mounted: Option<bool>,
+}
+
+const FDE_MAPPING_NAMES: [&str; 5] = [
+    ROOTFS_DECRYPTED_NAME,
+    ROOTFS_VERITY_NAME,
+    ROOTFS_EXTENDED_NAME,
+    ROOTFS_NAME,
+    DELTA_NAME,
+];
+
+/// Probe the state of the LVM volumes and device mapper layers required by FDE. The LVM volume
+/// group is activated if it is not yet. With `before_setup`, the mounts are not checked.
+pub async fn probe_fde_setup_state(before_setup: bool) -> FdeSetupState {
+    let mut state = FdeSetupState {
+        metadata_exists: Path::new(METADATA_PATH_IN_INITRD).exists(),
+        volume_group_error: None,
+        rootfs_volume: None,
+        rootfs_hash_volume_exists: false,
+        delta_volume: None,
+        existing_mappings: FDE_MAPPING_NAMES
+            .into_iter()
+            .filter(|name| dm_path(name).exists())
+            .collect(),
+        rootfs_mounted: None,
+    };
+
+    if let Err(error) = Command::new("vgchange")
+        .args(["-a", "y", VOLUME_GROUP_NAME])
+        .run()
+        .await
+    {
+        state.volume_group_error = Some(format!("{error:#}"));
+        return state;
+    }
+
+    if Path::new(ROOTFS_LOGICAL_VOLUME).exists() {
+        state.rootfs_volume = Some(
+            cryptpilot::fs::luks2::get_init_state(Path::new(ROOTFS_LOGICAL_VOLUME))
+                .await
+                .unwrap_or(VolumeInitState::None),
+        );
+    }
+    state.rootfs_hash_volume_exists = Path::new(ROOTFS_HASH_LOGICAL_VOLUME).exists();
+    if Path::new(DELTA_LOGICAL_VOLUME).exists() {
+        state.delta_volume = Some(
+            cryptpilot::fs::luks2::get_init_state(Path::new(DELTA_LOGICAL_VOLUME))
+                .await
+                .unwrap_or(VolumeInitState::None),
+        );
+    }
+
+    if !before_setup {
+        state.rootfs_mounted = Some(is_rootfs_mounted().await);
+    }
+
+    state
+}
+
+/// Check if the rootfs device is the source of any mount.
+async fn is_rootfs_mounted() -> bool {
+    let Ok(rootfs_device) = tokio::fs::canonicalize(rootfs_device()).await else {
+        return false;
+    };
+    let Ok(mounts) = tokio::fs::read_to_string("/proc/self/mounts").await else {
+        return false;
+    };
+    for source in mounts.lines().filter_map(|line| line.split(' ').next()) {
+        if tokio::fs::canonicalize(source)
+            .await
+            .is_ok_and(|source| source == rootfs_device)
+        {
+            return true;
+        }
+    }
+    false
+}
+
+/// Inspect the LVM volumes and device mapper layers required by FDE, and report what is missing or
+/// inconsistent with the FDE config. The LVM volume group is activated if it is not yet. With
+/// `before_setup`, the device mapper layers set up during boot are expected to be absent.
+pub async fn diagnose_fde_setup(fde_config: &FdeConfig, before_setup: bool) -> Vec<Diagnosis> {
+    let state = probe_fde_setup_state(before_setup).await;
+    diagnose_state(fde_config, &state, before_setup)
+}
+
+fn diagnose_state(
+    fde_config: &FdeConfig,
+    state: &FdeSetupState,
+    before_setup: bool,
+) -> Vec<Diagnosis> {
+    let mut diagnoses = vec![];
+
+    if !state.metadata_exists {
+        diagnoses.push(Diagnosis {
+            severity: Severity::Error,
+            component: "metadata",
+            status: ComponentStatus::Missing,
+            problem: format!(
+                "The metadata file {METADATA_PATH_IN_INITRD} does not exist in initrd"
+            ),
+            repair: RECONVERT_REPAIR.to_owned(),
+        });
+    }
+
+    if let Some(error) = &state.volume_group_error {
+        diagnoses.push(Diagnosis {
+            severity: Severity::Error,
+            component: "volume group",
+            status: ComponentStatus::Missing,
+            problem: format!(
+                "Failed to activate LVM volume group '{VOLUME_GROUP_NAME}': {error}"
+            ),
+            repair: format!("Make sure the disk is attached, and is converted by `cryptpilot-convert`. {RECONVERT_REPAIR}"),
+        });
+        return diagnoses;
+    }
+
+    // The rootfs layer
+    match (&fde_config.rootfs.encrypt, state.rootfs_volume) {
+        (_, None) => diagnoses.push(Diagnosis {
+            severity: Severity::Error,
+            component: "rootfs volume",
+            status: ComponentStatus::Missing,
+            problem: format!("The logical volume {ROOTFS_LOGICAL_VOLUME} does not exist"),
+            repair: RECONVERT_REPAIR.to_owned(),
+        }),
+        (Some(_), Some(VolumeInitState::Ready)) | (None, Some(VolumeInitState::None)) => {}
+        (Some(_), Some(_)) => diagnoses.push(Diagnosis {
+            severity: Severity::Error,
+            component: "rootfs volume",
+            status: ComponentStatus::Degraded,
+            problem: format!("The FDE config requires an encrypted rootfs, but {ROOTFS_LOGICAL_VOLUME} is not a LUKS2 volume initialized by cryptpilot"),
+            repair: format!("{RECONVERT_REPAIR}, or remove the `[rootfs.encrypt]` section if the disk is converted without rootfs encryption"),
+        }),
+        (None, Some(_)) => diagnoses.push(Diagnosis {
+            severity: Severity::Error,
+            component: "rootfs volume",
+            status: ComponentStatus::Degraded,
+            problem: format!("{ROOTFS_LOGICAL_VOLUME} is encrypted, but the FDE config has no `[rootfs.encrypt]` section"),
+            repair: "Use the FDE config the disk is converted with, which can be checked with `cryptpilot-fde-host config dump --disk <disk>`".to_owned(),
+        }),
+    }
+
+    // The dm-verity hash of the rootfs layer
+    if !state.rootfs_hash_volume_exists {
+        diagnoses.push(Diagnosis {
+            severity: Severity::Error,
+            component: "rootfs hash volume",
+            status: ComponentStatus::Missing,
+            problem: format!(
+                "The logical volume {ROOTFS_HASH_LOGICAL_VOLUME} for dm-verity does not exist"
+            ),
+            repair: RECONVERT_REPAIR.to_owned(),
+        });
+    }
+
+    // The delta layer, which is created on first boot if it does not exist
+    let delta_location = fde_config
+        .rootfs
+        .delta_location
+        .unwrap_or(DeltaLocation::Disk);
+    if matches!(
+        delta_location,
+        DeltaLocation::Disk | DeltaLocation::DiskPersist
+    ) {
+        match state.delta_volume {
+            Some(VolumeInitState::Initializing) => diagnoses.push(Diagnosis {
+                severity: Severity::Warning,
+                component: "delta volume",
+                status: ComponentStatus::Degraded,
+                problem: format!("The initialization of {DELTA_LOGICAL_VOLUME} was interrupted"),
+                repair: "Nothing to do, the delta volume will be re-created on boot and the data on it is dropped".to_owned(),
+            }),
+            None if !before_setup => diagnoses.push(Diagnosis {
+                severity: Severity::Error,
+                component: "delta volume",
+                status: ComponentStatus::Missing,
+                problem: format!("The logical volume {DELTA_LOGICAL_VOLUME} does not exist, which should have been created on boot"),
+                repair: "Check the logs of the boot with `journalctl -b -u cryptpilot-fde-before-sysroot`, and make sure there is free space in the volume group for the delta volume, then reboot".to_owned(),
+            }),
+            _ => {}
+        }
+    }
+
+    // Leftovers of an interrupted setup, which block the device mapper layers from being created
+    if before_setup {
+        for name in &state.existing_mappings {
+            diagnoses.push(Diagnosis {
+                severity: Severity::Error,
+                component: "device mapper",
+                status: ComponentStatus::Degraded,
+                problem: format!(
+                    "The device {} already exists, which is left over by an interrupted setup",
+                    dm_path(name).display()
+                ),
+                repair: format!(
+                    "Remove it with `dmsetup remove {}` and retry",
+                    dm_name(name)
+                ),
+            });
+        }
+    }
+
+    if state.rootfs_mounted == Some(false) {
+        diagnoses.push(Diagnosis {
+            severity: Severity::Error,
+            component: "rootfs mount",
+            status: ComponentStatus::Missing,
+            problem: format!("The rootfs device {} is not mounted", rootfs_device().display()),
+            repair: "Check the logs of the boot with `journalctl -b -u cryptpilot-fde-after-sysroot`, and make sure the root filesystem in /etc/fstab or on the kernel command line is the rootfs device, then reboot".to_owned(),
+        });
+    }
+
+    diagnoses
+}
+
+/// The report of a component required by FDE.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ComponentReport {
+    pub component: &'static str,
+    pub status: ComponentStatus,
+    pub problems: Vec<ComponentProblem>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ComponentProblem {
+    pub severity: Severity,
+    pub problem: String,
+    pub repair: String,
+}
+
+/// The machine readable report of the FDE setup, listing each expected component.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct DiagnoseReport {
+    /// Whether no error is found.
+    pub healthy: bool,
+    pub components: Vec<ComponentReport>,
+}
+
+/// Build the report of each component expected in the state. The components behind the volume group
+/// cannot be checked if it fails to activate, and are reported as missing.
+fn build_report(
+    state: &FdeSetupState,
+    diagnoses: &[Diagnosis],
+    before_setup: bool,
+) -> DiagnoseReport {
+    let mut expected = vec![
+        "metadata",
+        "volume group",
+        "rootfs volume",
+        "rootfs hash volume",
+        "delta volume",
+    ];
+    if before_setup {
+        expected.push("device mapper");
+    } else {
+        expected.push("rootfs mount");
+    }
+
+    let components = expected
+        .into_iter()
+        .map(|component| {
+            let problems = diagnoses
+                .iter()
+                .filter(|diagnosis| diagnosis.component == component)
+                .collect::<Vec<_>>();
+            let unchecked = state.volume_group_error.is_some()
+                && !matches!(component, "metadata" | "volume group");
+            let status = if unchecked {
+                ComponentStatus::Missing
+            } else {
+                problems
+                    .iter()
+                    .map(|diagnosis| diagnosis.status)
+                    .max()
+                    .unwrap_or(ComponentStatus::Present)
+            };
+            ComponentReport {
+                component,
+                status,
+                problems: problems
+                    .into_iter()
+                    .map(|diagnosis| ComponentProblem {
+                        severity: diagnosis.severity,
+                        problem: diagnosis.problem.clone(),
+                        repair: diagnosis.repair.clone(),
+                    })
+                    .collect(),
+            }
+        })
+        .collect();
+
+    DiagnoseReport {
+        healthy: !diagnoses
+            .iter()
+            .any(|diagnosis| diagnosis.severity == Severity::Error),
+        components,
+    }
+}
+
+/// Run [`diagnose_fde_setup`] before setting up the volumes required by FDE. Warnings are logged,
+/// and an error with all the problems and repair suggestions is returned if any problem blocks the
+/// setup.
+pub async fn check_fde_setup(fde_config: &FdeConfig) -> Result<()> {
+    let diagnoses = diagnose_fde_setup(fde_config, true).await;
+    for diagnosis in &diagnoses {
+        if diagnosis.severity == Severity::Warning {
+            tracing::warn!("{diagnosis}");
+        }
+    }
+
+    let errors = diagnoses
+        .iter()
+        .filter(|diagnosis| diagnosis.severity == Severity::Error)
+        .map(|diagnosis| diagnosis.to_string())
+        .collect::<Vec<_>>();
+    if !errors.is_empty() {
+        bail!(
+            "The FDE setup is degraded or partial:\n{}",
+            errors.join("\n")
+        );
+    }
+
+    Ok(())
+}
+
+pub struct DiagnoseCommand {
+    pub json: bool,
+}
+
+#[async_trait]
+impl super::Command for DiagnoseCommand {
+    async fn run(&self) -> Result<()> {
+        let fde_config = crate::config::get_fde_config_source()
+            .await
+            .get_fde_config()
+            .await?;
+        let Some(fde_config) = fde_config else {
+            if self.json {
+                println!("{}", serde_json::json!({ "configured": false }));
+            } else {
+                println!("The system is not configured for FDE, nothing to diagnose");
+            }
+            return Ok(());
+        };
+
+        let state = probe_fde_setup_state(false).await;
+        let diagnoses = diagnose_state(&fde_config, &state, false);
+
+        if self.json {
+            let report = build_report(&state, &diagnoses, false);
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        } else if diagnoses.is_empty() {
+            println!("No problem found in the FDE setup");
+            return Ok(());
+        } else {
+            for diagnosis in &diagnoses {
+                println!("{diagnosis}");
+            }
+        }
+
+        let error_count = diagnoses
+            .iter()
+            .filter(|diagnosis| diagnosis.severity == Severity::Error)
+            .count();
+        if error_count > 0 {
+            bail!("Found {error_count} problem(s) in the FDE setup");
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    fn fde_config() -> Result<FdeConfig> {
+        Ok(toml::from_str(
+            r#"
+[rootfs]
+
+[delta]
+integrity = false
+
+[delta.encrypt.exec]
+command = "echo"
+args = ["-n", "passphrase"]
+"#,
+        )?)
+    }
+
+    /// A healthy state of the system booted with FDE, without rootfs encryption.
+    fn healthy_state() -> FdeSetupState {
+        FdeSetupState {
+            metadata_exists: true,
+            volume_group_error: None,
+            rootfs_volume: Some(VolumeInitState::None),
+            rootfs_hash_volume_exists: true,
+            delta_volume: Some(VolumeInitState::Ready),
+            existing_mappings: FDE_MAPPING_NAMES.to_vec(),
+            rootfs_mounted: Some(true),
+        }
+    }
+
+    fn statuses(report: &DiagnoseReport) -> Vec<(&'static str, ComponentStatus)> {
+        report
+            .components
+            .iter()
+            .map(|component| (component.component, component.status))
+            .collect()
+    }
+
+    #[test]
+    fn test_report_all_present() -> Result<()> {
+        let state = healthy_state();
+        let diagnoses = diagnose_state(&fde_config()?, &state, false);
+        assert!(diagnoses.is_empty(), "{diagnoses:?}");
+
+        let report = build_report(&state, &diagnoses, false);
+        assert!(report.healthy);
+        assert_eq!(report.components.len(), 6);
+        assert!(report
+            .components
+            .iter()
+            .all(|component| component.status == ComponentStatus::Present
+                && component.problems.is_empty()));
+
+        let json = serde_json::to_value(&report)?;
+        assert_eq!(json["components"][0]["component"], "metadata");
+        assert_eq!(json["components"][0]["status"], "present");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_report_verity_missing() -> Result<()> {
+        let state = FdeSetupState {
+            rootfs_hash_volume_exists: false,
+            ..healthy_state()
+        };
+        let diagnoses = diagnose_state(&fde_config()?, &state, false);
+        let report = build_report(&state, &diagnoses, false);
+        assert!(!report.healthy);
+
+        let statuses = statuses(&report);
+        assert!(statuses.contains(&("rootfs hash volume", ComponentStatus::Missing)));
+        assert!(statuses.contains(&("rootfs volume", ComponentStatus::Present)));
+
+        let json = serde_json::to_value(&report)?;
+        let component = &json["components"][3];
+        assert_eq!(component["component"], "rootfs hash volume");
+        assert_eq!(component["problems"][0]["severity"], "error");
+        assert_eq!(component["problems"][0]["repair"], RECONVERT_REPAIR);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_report_data_layer_missing() -> Result<()> {
+        let state = FdeSetupState {
+            delta_volume: None,
+            existing_mappings: vec![ROOTFS_VERITY_NAME, ROOTFS_NAME],
+            ..healthy_state()
+        };
+        let diagnoses = diagnose_state(&fde_config()?, &state, false);
+        let report = build_report(&state, &diagnoses, false);
+        assert!(!report.healthy);
+        assert!(statuses(&report).contains(&("delta volume", ComponentStatus::Missing)));
+
+        // The delta volume is created on boot, so it is not a problem before the setup
+        let state = FdeSetupState {
+            existing_mappings: vec![],
+            rootfs_mounted: None,
+            ..state
+        };
+        let diagnoses = diagnose_state(&fde_config()?, &state, true);
+        assert!(diagnoses.is_empty(), "{diagnoses:?}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_report_volume_group_missing() -> Result<()> {
+        let state = FdeSetupState {
+            volume_group_error: Some("Volume group \"cryptpilot\" not found".into()),
+            rootfs_volume: None,
+            rootfs_hash_volume_exists: false,
+            delta_volume: None,
+            existing_mappings: vec![],
+            rootfs_mounted: None,
+            ..healthy_state()
+        };
+        let diagnoses = diagnose_state(&fde_config()?, &state, true);
+        assert_eq!(diagnoses.len(), 1);
+
+        let report = build_report(&state, &diagnoses, true);
+        assert_eq!(
+            statuses(&report),
+            vec![
+                ("metadata", ComponentStatus::Present),
+                ("volume group", ComponentStatus::Missing),
+                ("rootfs volume", ComponentStatus::Missing),
+                ("rootfs hash volume", ComponentStatus::Missing),
+                ("delta volume", ComponentStatus::Missing),
+                ("device mapper", ComponentStatus::Missing),
+            ]
+        );
+
+        Ok(())
+    }
+}
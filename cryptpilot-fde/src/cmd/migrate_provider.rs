@@ -0,0 +1,410 @@
+use std::path::Path;
+
+use anyhow::{bail, Context as _, Result};
+use async_trait::async_trait;
+use tokio::process::Command;
+
+use crate::{
+    cli::MigrateProviderOptions,
+    cmd::boot_service::{
+        metadata::Metadata,
+        stage::{DELTA_LOGICAL_VOLUME, ROOTFS_LOGICAL_VOLUME, VOLUME_GROUP_NAME},
+    },
+    config::{fs::CRYPTPILOT_CONFIG_DIR_DEFAULT, FdeConfig, FdeConfigBundle},
+    disk::{
+        external::OnExternalFdeDisk,
+        initrd::{replace_files_in_initrd, EmbeddedInitrd},
+    },
+};
+use cryptpilot::{
+    config::encrypt::EncryptConfig,
+    fs::cmd::CheckCommandOutput as _,
+    provider::{IntoProvider as _, KeyProvider as _, VolumeType},
+    types::Passphrase,
+};
+
+pub struct MigrateProviderCommand {
+    pub migrate_provider_options: MigrateProviderOptions,
+}
+
+/// The key of a LUKS2 volume which is being migrated to a new key provider.
+struct KeyMigration {
+    name: &'static str,
+    dev: &'static Path,
+    old_keyslot: u32,
+    old_passphrase: Passphrase,
+    new_passphrase: Passphrase,
+}
+
+#[async_trait]
+impl super::Command for MigrateProviderCommand {
+    async fn run(&self) -> Result<()> {
+        let options = &self.migrate_provider_options;
+
+        tracing::info!("[ 1/6 ] Loading FDE configs");
+        let from_config = load_fde_config_file(&options.from).await?.1;
+        let (to_config_content, to_config) = load_fde_config_file(&options.to).await?;
+        check_fde_config_migration(&from_config, &to_config)?;
+
+        tracing::info!("[ 2/6 ] Checking the FDE config embedded in the disk");
+        let fde_disk = OnExternalFdeDisk::new_from_disk_writable(&options.disk).await?;
+        let mut embedded_initrds = vec![];
+        for embedded_initrd in fde_disk.load_embedded_initrds().await? {
+            match embedded_initrd.extract_cryptpilot_files().await {
+                Ok((fde_config_bundle, metadata)) => {
+                    if fde_config_bundle.fde.as_ref() != Some(&from_config) {
+                        bail!(
+                            "The FDE config embedded in {:?} on the disk does not match the config in {:?}",
+                            embedded_initrd.path,
+                            options.from
+                        );
+                    }
+                    embedded_initrds.push((embedded_initrd, fde_config_bundle, metadata));
+                }
+                Err(error) => {
+                    tracing::warn!(
+                        ?error,
+                        path = ?embedded_initrd.path,
+                        "Failed to load cryptpilot config from initrd, skip it"
+                    );
+                }
+            }
+        }
+        if embedded_initrds.is_empty() {
+            bail!("No initrd with cryptpilot config found on the disk");
+        }
+
+        tracing::info!(
+            volume_group_name = VOLUME_GROUP_NAME,
+            "[ 3/6 ] Activating LVM volume group"
+        );
+        if Path::new(ROOTFS_LOGICAL_VOLUME).exists() {
+            bail!("The LVM volume group '{VOLUME_GROUP_NAME}' is already active on this system, please deactivate it before migrating another disk");
+        }
+        Command::new("vgchange")
+            .args(["-a", "y", VOLUME_GROUP_NAME])
+            .run()
+            .await
+            .with_context(|| {
+                format!("Failed to activate LVM volume group '{VOLUME_GROUP_NAME}'")
+            })?;
+
+        let result = migrate(
+            &fde_disk,
+            &from_config,
+            &to_config,
+            &to_config_content,
+            &embedded_initrds,
+        )
+        .await;
+
+        if let Err(error) = Command::new("vgchange")
+            .args(["-a", "n", VOLUME_GROUP_NAME])
+            .run()
+            .await
+        {
+            tracing::warn!(
+                ?error,
+                "Failed to deactivate LVM volume group '{VOLUME_GROUP_NAME}'"
+            );
+        }
+
+        result?;
+
+        tracing::info!("The key providers of the disk are migrated");
+        tracing::warn!("The initrd on the disk is changed, please update the reference values with `cryptpilot-fde-host show-reference-value`");
+
+        Ok(())
+    }
+}
+
+async fn migrate(
+    fde_disk: &OnExternalFdeDisk,
+    from_config: &FdeConfig,
+    to_config: &FdeConfig,
+    to_config_content: &str,
+    embedded_initrds: &[(EmbeddedInitrd, FdeConfigBundle, Metadata)],
+) -> Result<()> {
+    let mut key_migrations = vec![];
+    if let Some(key_migration) = prepare_key_migration(
+        "rootfs",
+        Path::new(ROOTFS_LOGICAL_VOLUME),
+        from_config.rootfs.encrypt.as_ref(),
+        to_config.rootfs.encrypt.as_ref(),
+    )
+    .await?
+    {
+        key_migrations.push(key_migration);
+    }
+    if !Path::new(DELTA_LOGICAL_VOLUME).exists()
+        || !cryptpilot::fs::luks2::is_initialized(Path::new(DELTA_LOGICAL_VOLUME)).await?
+    {
+        tracing::info!("The delta volume is not initialized, it will be created with the new key provider on boot");
+    } else if let Some(key_migration) = prepare_key_migration(
+        "delta",
+        Path::new(DELTA_LOGICAL_VOLUME),
+        Some(&from_config.delta.encrypt),
+        Some(&to_config.delta.encrypt),
+    )
+    .await?
+    {
+        key_migrations.push(key_migration);
+    }
+
+    tracing::info!("[ 4/6 ] Adding keys from the new key providers");
+    for key_migration in &key_migrations {
+        let new_keyslot = cryptpilot::fs::luks2::add_passphrase(
+            key_migration.dev,
+            &key_migration.old_passphrase,
+            &key_migration.new_passphrase,
+        )
+        .await?;
+        let keyslot = cryptpilot::fs::luks2::get_keyslot_by_passphrase(
+            key_migration.dev,
+            &key_migration.new_passphrase,
+        )
+        .await?;
+        if keyslot != new_keyslot {
+            bail!(
+                "The new key of {} volume unlocks keyslot {keyslot} instead of the added keyslot {new_keyslot}",
+                key_migration.name
+            );
+        }
+        tracing::info!(
+            "Added the new key of {} volume to keyslot {new_keyslot}",
+            key_migration.name
+        );
+    }
+
+    tracing::info!("[ 5/6 ] Updating the FDE config embedded in the disk");
+    let fde_config_path = Path::new(CRYPTPILOT_CONFIG_DIR_DEFAULT).join("fde.toml");
+    for (embedded_initrd, _, _) in embedded_initrds {
+        tracing::info!("Updating the FDE config in {:?}", embedded_initrd.path);
+        let new_initrd = replace_files_in_initrd(
+            &embedded_initrd.initrd,
+            &[(&fde_config_path, to_config_content.as_bytes())],
+        )
+        .await?;
+        fde_disk
+            .write_embedded_initrd(embedded_initrd, &new_initrd)
+            .await?;
+    }
+    for embedded_initrd in fde_disk.load_embedded_initrds().await? {
+        let Some((_, fde_config_bundle, metadata)) = embedded_initrds
+            .iter()
+            .find(|(e, _, _)| e.path == embedded_initrd.path)
+        else {
+            continue;
+        };
+        let (new_fde_config_bundle, new_metadata) = embedded_initrd
+            .extract_cryptpilot_files()
+            .await
+            .with_context(|| {
+                format!(
+                    "Failed to load cryptpilot config from the updated {:?}",
+                    embedded_initrd.path
+                )
+            })?;
+        if new_fde_config_bundle.fde.as_ref() != Some(to_config)
+            || new_fde_config_bundle.global != fde_config_bundle.global
+            || &new_metadata != metadata
+        {
+            bail!(
+                "The cryptpilot config in the updated {:?} is not as expected",
+                embedded_initrd.path
+            );
+        }
+    }
+
+    tracing::info!("[ 6/6 ] Removing keys of the old key providers");
+    for key_migration in &key_migrations {
+        cryptpilot::fs::luks2::destroy_keyslot(key_migration.dev, key_migration.old_keyslot)
+            .await?;
+        if cryptpilot::fs::luks2::get_keyslot_by_passphrase(
+            key_migration.dev,
+            &key_migration.old_passphrase,
+        )
+        .await
+        .is_ok()
+        {
+            bail!(
+                "The old key of {} volume can still unlock the volume",
+                key_migration.name
+            );
+        }
+        cryptpilot::fs::luks2::check_passphrase(key_migration.dev, &key_migration.new_passphrase)
+            .await?;
+        tracing::info!(
+            "Removed the old key of {} volume from keyslot {}",
+            key_migration.name,
+            key_migration.old_keyslot
+        );
+    }
+
+    Ok(())
+}
+
+/// Fetch the keys from both key providers and check the old key against the volume. Returns `None`
+/// if the key of the volume does not need to be migrated.
+async fn prepare_key_migration(
+    name: &'static str,
+    dev: &'static Path,
+    from: Option<&EncryptConfig>,
+    to: Option<&EncryptConfig>,
+) -> Result<Option<KeyMigration>> {
+    let (Some(from), Some(to)) = (from, to) else {
+        tracing::info!("The {name} volume is not encrypted, skip it");
+        return Ok(None);
+    };
+
+    let old_provider = from.clone().into_provider();
+    let new_provider = to.clone().into_provider();
+    if matches!(old_provider.volume_type(), VolumeType::Temporary)
+        || matches!(new_provider.volume_type(), VolumeType::Temporary)
+    {
+        tracing::info!(
+            "The {name} volume uses a temporary key provider and is re-created on boot, skip it"
+        );
+        return Ok(None);
+    }
+
+    tracing::info!("Fetching the old key of {name} volume");
+    let old_passphrase = old_provider
+        .get_key()
+        .await
+        .with_context(|| format!("Failed to get the old key of {name} volume"))?;
+    let old_keyslot = cryptpilot::fs::luks2::get_keyslot_by_passphrase(dev, &old_passphrase)
+        .await
+        .with_context(|| format!("The old key cannot unlock the {name} volume"))?;
+
+    tracing::info!("Fetching the new key of {name} volume");
+    let new_passphrase = new_provider
+        .get_key()
+        .await
+        .with_context(|| format!("Failed to get the new key of {name} volume"))?;
+
+    if old_passphrase.as_bytes() == new_passphrase.as_bytes() {
+        tracing::info!("The old and new keys of {name} volume are the same, skip it");
+        return Ok(None);
+    }
+
+    Ok(Some(KeyMigration {
+        name,
+        dev,
+        old_keyslot,
+        old_passphrase,
+        new_passphrase,
+    }))
+}
+
+async fn load_fde_config_file(path: &Path) -> Result<(String, FdeConfig)> {
+    let content = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("Failed to read FDE config from {path:?}"))?;
+    let fde_config = toml::from_str::<FdeConfig>(&content)
+        .with_context(|| format!("Failed to parse FDE config from {path:?}"))?;
+    Ok((content, fde_config))
+}
+
+/// Check that only the key providers are changed between the two FDE configs, and the rootfs
+/// volume can be migrated.
+fn check_fde_config_migration(from: &FdeConfig, to: &FdeConfig) -> Result<()> {
+    match (&from.rootfs.encrypt, &to.rootfs.encrypt) {
+        (None, None) => {}
+        (Some(_), None) | (None, Some(_)) => {
+            bail!("Cannot enable or disable encryption of the rootfs volume by migrating key providers")
+        }
+        (Some(from_encrypt), Some(to_encrypt)) => {
+            for encrypt in [from_encrypt, to_encrypt] {
+                let provider = encrypt.clone().into_provider();
+                if matches!(provider.volume_type(), VolumeType::Temporary) {
+                    bail!(
+                        "Key provider {:?} is not supported for rootfs volume",
+                        provider.debug_name()
+                    )
+                }
+            }
+        }
+    }
+
+    let mut to_without_encrypt = to.clone();
+    to_without_encrypt.rootfs.encrypt = from.rootfs.encrypt.clone();
+    to_without_encrypt.delta.encrypt = from.delta.encrypt.clone();
+    if &to_without_encrypt != from {
+        bail!("Only the key providers (the `encrypt` sections) can be changed by migrating key providers");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+    use anyhow::Result;
+
+    fn fde_config(rootfs_encrypt: &str, delta_integrity: bool, delta_encrypt: &str) -> FdeConfig {
+        toml::from_str(&format!(
+            r#"
+[rootfs]
+delta_location = "disk-persist"
+
+{rootfs_encrypt}
+
+[delta]
+integrity = {delta_integrity}
+
+{delta_encrypt}
+"#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_check_fde_config_migration() -> Result<()> {
+        let exec = |prefix: &str, key: &str| {
+            format!(
+                r#"
+[{prefix}.encrypt.exec]
+command = "echo"
+args = ["-n", "{key}"]
+"#
+            )
+        };
+
+        let from = fde_config(&exec("rootfs", "old"), false, &exec("delta", "old"));
+
+        // Changing the key providers is allowed
+        check_fde_config_migration(
+            &from,
+            &fde_config(&exec("rootfs", "new"), false, &exec("delta", "new")),
+        )?;
+        check_fde_config_migration(
+            &from,
+            &fde_config(&exec("rootfs", "new"), false, "[delta.encrypt.otp]"),
+        )?;
+
+        // Changing other fields is not allowed
+        assert!(check_fde_config_migration(
+            &from,
+            &fde_config(&exec("rootfs", "new"), true, &exec("delta", "new")),
+        )
+        .is_err());
+
+        // Enabling or disabling encryption of the rootfs volume is not allowed
+        assert!(
+            check_fde_config_migration(&from, &fde_config("", false, &exec("delta", "new")))
+                .is_err()
+        );
+
+        // Temporary key providers are not allowed for the rootfs volume
+        assert!(check_fde_config_migration(
+            &from,
+            &fde_config("[rootfs.encrypt.otp]", false, &exec("delta", "new")),
+        )
+        .is_err());
+
+        Ok(())
+    }
+}
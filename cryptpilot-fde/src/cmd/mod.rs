@@ -1,6 +1,12 @@
 pub mod boot_service;
+pub mod check_initrd;
 pub mod config;
+pub mod diagnose;
+pub mod migrate_provider;
+pub mod partitions;
+pub mod reference_value_store;
 pub mod show_reference_value;
+pub mod verify_boot_chain;
 
 use anyhow::Result;
 use async_trait::async_trait;
@@ -24,6 +30,19 @@ impl IntoCommand for FdeSubcommand {
                 Box::new(show_reference_value::ShowReferenceValueCommand {
                     disk: opts.disk,
                     hash_algos: opts.hash_algos,
+                    best_effort: opts.best_effort,
+                    policy_template: opts.policy_template,
+                    schema_version: opts.schema_version,
+                    initrd_files: opts.initrd_files,
+                    compare: opts.compare,
+                    no_mount: opts.no_mount,
+                    os_release: opts.os_release,
+                    grub_config: opts.grub_config,
+                    kernels: opts.kernels,
+                    trustee_bundle: opts.trustee_bundle,
+                    rootfs_key_file: opts.rootfs_key_file,
+                    publish: opts.publish,
+                    publish_token_file: opts.publish_token_file,
                 })
             }
             FdeSubcommand::Config(config_options) => match config_options.command {
@@ -33,9 +52,29 @@ impl IntoCommand for FdeSubcommand {
                     })
                 }
                 crate::cli::ConfigSubcommand::Dump(opts) => {
-                    Box::new(config::dump::ConfigDumpCommand { disk: opts.disk })
+                    Box::new(config::dump::ConfigDumpCommand {
+                        disk: opts.disk,
+                        diff: opts.diff,
+                        redact: opts.redact,
+                    })
                 }
             },
+            FdeSubcommand::MigrateProvider(opts) => {
+                Box::new(migrate_provider::MigrateProviderCommand {
+                    migrate_provider_options: opts,
+                })
+            }
+            FdeSubcommand::CheckInitrd(opts) => Box::new(check_initrd::CheckInitrdCommand {
+                initrd: opts.initrd,
+                disk: opts.disk,
+            }),
+            FdeSubcommand::VerifyBootChain(opts) => {
+                Box::new(verify_boot_chain::VerifyBootChainCommand { disk: opts.disk })
+            }
+            FdeSubcommand::Partitions(opts) => Box::new(partitions::PartitionsCommand {
+                disk: opts.disk,
+                json: opts.json,
+            }),
         }
     }
 }
@@ -0,0 +1,284 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context as _, Result};
+use async_trait::async_trait;
+use cryptpilot::fs::cmd::CheckCommandOutput as _;
+use serde::Serialize;
+use tokio::process::Command;
+
+use crate::disk::{
+    gpt::{GptPartition, GptTable},
+    partition_role::PartitionRole,
+};
+
+/// The PARTLABEL with which the boot partition is detected if no partition has its type GUID.
+const BOOT_PARTLABEL: &str = "boot";
+
+pub struct PartitionsCommand {
+    pub disk: PathBuf,
+    pub json: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct PartitionsReport {
+    disk: PathBuf,
+    sector_size: u64,
+    disk_guid: String,
+    partitions: Vec<PartitionReport>,
+    /// Problems which may make cryptpilot pick the wrong partition for a role.
+    warnings: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct PartitionReport {
+    number: u32,
+    type_guid: String,
+    partlabel: String,
+    partuuid: String,
+    /// The file system (or other signature, e.g. "crypto_LUKS") detected by blkid.
+    filesystem: Option<String>,
+    /// Offset in bytes on the disk.
+    offset: u64,
+    /// Size in bytes.
+    size: u64,
+    /// The role cryptpilot assigns to the partition, if any.
+    role: Option<PartitionRole>,
+}
+
+#[async_trait]
+impl super::Command for PartitionsCommand {
+    async fn run(&self) -> Result<()> {
+        let Some(gpt) = crate::disk::gpt::read_gpt(&self.disk).await? else {
+            bail!(
+                "No GPT found on {:?}, the partitions of a MBR disk have no type GUIDs",
+                self.disk
+            );
+        };
+
+        let mut filesystems = vec![];
+        for partition in &gpt.partitions {
+            filesystems.push(probe_filesystem(&self.disk, &gpt, partition).await?);
+        }
+        let report = build_report(&self.disk, &gpt, filesystems);
+
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        } else {
+            print!("{}", format_report(&report));
+        }
+        Ok(())
+    }
+}
+
+/// Detect the file system on the partition with blkid, by probing the disk at the offset of the
+/// partition, which also works for a disk image file.
+async fn probe_filesystem(
+    disk: &Path,
+    gpt: &GptTable,
+    partition: &GptPartition,
+) -> Result<Option<String>> {
+    Command::new("blkid")
+        .arg("--probe")
+        .arg("--offset")
+        .arg(partition.offset(gpt.sector_size).to_string())
+        .arg("--size")
+        .arg(partition.size(gpt.sector_size).to_string())
+        .args(["--match-tag", "TYPE", "--output", "value"])
+        .arg(disk)
+        .run_with_status_checker(|code, stdout, _| match code {
+            0 => Ok(Some(String::from_utf8_lossy(&stdout).trim().to_owned())
+                .filter(|fs_type| !fs_type.is_empty())),
+            // Nothing is detected
+            2 => Ok(None),
+            _ => bail!("Bad exit code"),
+        })
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to detect the file system on partition {} of {disk:?}",
+                partition.number
+            )
+        })
+}
+
+/// Assign the roles to the partitions in the same way as the FDE disk detection, and collect the
+/// problems found.
+fn build_report(disk: &Path, gpt: &GptTable, filesystems: Vec<Option<String>>) -> PartitionsReport {
+    let mut partitions = gpt
+        .partitions
+        .iter()
+        .zip(filesystems)
+        .map(|(partition, filesystem)| PartitionReport {
+            number: partition.number,
+            type_guid: partition.type_guid.clone(),
+            partlabel: partition.name.clone(),
+            partuuid: partition.unique_guid.clone(),
+            filesystem,
+            offset: partition.offset(gpt.sector_size),
+            size: partition.size(gpt.sector_size),
+            role: PartitionRole::from_type_guid(&partition.type_guid),
+        })
+        .collect::<Vec<_>>();
+
+    let mut warnings = vec![];
+    for role in [
+        PartitionRole::Esp,
+        PartitionRole::Boot,
+        PartitionRole::Root,
+        PartitionRole::Data,
+    ] {
+        let numbers = partitions
+            .iter()
+            .filter(|partition| partition.role == Some(role))
+            .map(|partition| partition.number)
+            .collect::<Vec<_>>();
+        // Multiple data partitions (e.g. /home and /var) are expected
+        if numbers.len() > 1 && role != PartitionRole::Data {
+            warnings.push(format!(
+                "Partitions {numbers:?} all have the type GUID of the {role} partition, partition {} is used",
+                numbers[0]
+            ));
+        }
+    }
+
+    // The boot partition falls back to the one with the PARTLABEL
+    if !partitions
+        .iter()
+        .any(|partition| partition.role == Some(PartitionRole::Boot))
+    {
+        if let Some(partition) = partitions.iter_mut().find(|partition| {
+            partition.role.is_none()
+                && partition.partlabel == BOOT_PARTLABEL
+                && partition.filesystem.as_deref() == Some("ext4")
+        }) {
+            partition.role = Some(PartitionRole::Boot);
+            warnings.push(format!(
+                "Partition {} is detected as the boot partition by its PARTLABEL only, set its type GUID to the one of XBOOTLDR (bc13c2ff-59e6-4262-a352-b275fd6f7172) for a robust detection",
+                partition.number
+            ));
+        }
+    }
+
+    PartitionsReport {
+        disk: disk.to_path_buf(),
+        sector_size: gpt.sector_size,
+        disk_guid: gpt.disk_guid.clone(),
+        partitions,
+        warnings,
+    }
+}
+
+fn format_report(report: &PartitionsReport) -> String {
+    let mut text = format!(
+        "Disk {:?}: GPT, sector size {}, disk GUID {}\n",
+        report.disk, report.sector_size, report.disk_guid
+    );
+    text += &format!(
+        "{:<6}  {:<36}  {:<16}  {:<12}  {:>14}  {}\n",
+        "NUMBER", "TYPE GUID", "PARTLABEL", "FILESYSTEM", "SIZE", "ROLE"
+    );
+    for partition in &report.partitions {
+        text += &format!(
+            "{:<6}  {:<36}  {:<16}  {:<12}  {:>14}  {}\n",
+            partition.number,
+            partition.type_guid,
+            partition.partlabel,
+            partition.filesystem.as_deref().unwrap_or("-"),
+            partition.size,
+            partition
+                .role
+                .map(|role| role.to_string())
+                .unwrap_or_else(|| "-".to_owned())
+        );
+    }
+    for warning in &report.warnings {
+        text += &format!("[WARN] {warning}\n");
+    }
+    text
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    use crate::disk::gpt::tests::write_synthetic_gpt;
+
+    #[tokio::test]
+    async fn test_partitions_report() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let disk = dir.path().join("disk.img");
+        write_synthetic_gpt(
+            &disk,
+            &[
+                ("c12a7328-f81f-11d2-ba4b-00a0c93ec93b", "EFI System", 2),
+                ("0fc63daf-8483-4772-8e79-3d69d8477de4", "boot", 8),
+                ("4f68bce3-e8cd-4db1-96e7-fbcaf984b709", "root", 4),
+                ("4f68bce3-e8cd-4db1-96e7-fbcaf984b709", "root-b", 4),
+            ],
+        )
+        .await?;
+        let gpt = crate::disk::gpt::read_gpt(&disk)
+            .await?
+            .expect("the disk has a GPT");
+
+        // A file system on the second partition
+        Command::new("mkfs.ext4")
+            .arg("-q")
+            .arg("-E")
+            .arg(format!(
+                "offset={}",
+                gpt.partitions[1].offset(gpt.sector_size)
+            ))
+            .arg(&disk)
+            .arg(format!(
+                "{}k",
+                gpt.partitions[1].size(gpt.sector_size) / 1024
+            ))
+            .run()
+            .await?;
+
+        let mut filesystems = vec![];
+        for partition in &gpt.partitions {
+            filesystems.push(probe_filesystem(&disk, &gpt, partition).await?);
+        }
+        assert_eq!(filesystems, vec![None, Some("ext4".to_owned()), None, None]);
+
+        let report = build_report(&disk, &gpt, filesystems);
+        let roles = report
+            .partitions
+            .iter()
+            .map(|partition| partition.role)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            roles,
+            vec![
+                Some(PartitionRole::Esp),
+                Some(PartitionRole::Boot),
+                PartitionRole::from_type_guid("4f68bce3-e8cd-4db1-96e7-fbcaf984b709"),
+                PartitionRole::from_type_guid("4f68bce3-e8cd-4db1-96e7-fbcaf984b709"),
+            ]
+        );
+        assert!(report
+            .warnings
+            .iter()
+            .any(|warning| warning.contains("by its PARTLABEL only")));
+        #[cfg(target_arch = "x86_64")]
+        assert!(report
+            .warnings
+            .iter()
+            .any(|warning| warning.contains("[3, 4]")));
+
+        let json = serde_json::to_value(&report)?;
+        assert_eq!(json["partitions"][0]["role"], "esp");
+        assert_eq!(json["partitions"][1]["filesystem"], "ext4");
+        assert_eq!(json["partitions"][1]["size"], 8 * 1024 * 1024);
+
+        let text = format_report(&report);
+        assert!(text.contains("EFI System"));
+        assert!(text.contains("[WARN]"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,275 @@
+use std::{path::Path, time::Duration};
+
+use again::RetryPolicy;
+use anyhow::{bail, Context as _, Result};
+use reqwest::{StatusCode, Url};
+
+/// The timeout of each request to the reference value store.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The number of retries after a failed request which may succeed later.
+const MAX_RETRIES: usize = 3;
+
+/// The delay before the first retry, which is doubled for each following retry.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// An endpoint of a reference value store (e.g. the RVPS of Trustee), to which the computed
+/// reference values are published with an HTTP POST request.
+pub struct ReferenceValueStore {
+    endpoint: Url,
+    token: Option<String>,
+    retry_delay: Duration,
+}
+
+enum PublishError {
+    /// The request may succeed if retried, e.g. a connection error or a 5xx response.
+    Retryable(anyhow::Error),
+    /// The request is rejected, e.g. the token is wrong.
+    Fatal(anyhow::Error),
+}
+
+impl ReferenceValueStore {
+    /// Create the store of the endpoint, with the bearer token read from `token_file` if any.
+    pub async fn new(endpoint: &str, token_file: Option<&Path>) -> Result<Self> {
+        let endpoint = Url::parse(endpoint)
+            .with_context(|| format!("Invalid reference value store endpoint {endpoint:?}"))?;
+        if !matches!(endpoint.scheme(), "http" | "https") {
+            bail!("The reference value store endpoint {endpoint} should be an HTTP(S) URL");
+        }
+
+        let token = match token_file {
+            Some(token_file) => {
+                let token = tokio::fs::read_to_string(token_file)
+                    .await
+                    .with_context(|| format!("Failed to read the token file {token_file:?}"))?;
+                let token = token.trim().to_owned();
+                if token.is_empty() {
+                    bail!("The token file {token_file:?} is empty");
+                }
+                Some(token)
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            endpoint,
+            token,
+            retry_delay: RETRY_DELAY,
+        })
+    }
+
+    /// POST the reference values, a JSON document, to the endpoint. Connection errors, timeouts,
+    /// 5xx and 429 responses are retried with an exponential backoff, while other responses than
+    /// 2xx fail immediately.
+    pub async fn publish(&self, json: &str) -> Result<()> {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .context("Failed to create HTTP client for the reference value store")?;
+
+        RetryPolicy::exponential(self.retry_delay)
+            .with_max_retries(MAX_RETRIES)
+            .retry_if(
+                || self.publish_once(&client, json),
+                |error: &PublishError| match error {
+                    PublishError::Retryable(error) => {
+                        tracing::warn!(
+                            "Failed to publish the reference values, retrying: {error:#}"
+                        );
+                        true
+                    }
+                    PublishError::Fatal(_) => false,
+                },
+            )
+            .await
+            .map_err(|(PublishError::Retryable(error) | PublishError::Fatal(error))| error)
+            .with_context(|| {
+                format!(
+                    "Failed to publish the reference values to {}",
+                    self.endpoint
+                )
+            })?;
+
+        tracing::info!("Published the reference values to {}", self.endpoint);
+        Ok(())
+    }
+
+    async fn publish_once(&self, client: &reqwest::Client, json: &str) -> Result<(), PublishError> {
+        let mut request = client
+            .post(self.endpoint.clone())
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(json.to_owned());
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+
+        let response = request.send().await.map_err(|error| {
+            PublishError::Retryable(anyhow::Error::new(error).context("Failed to send the request"))
+        })?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+
+        let body = response.text().await.unwrap_or_default();
+        let error = anyhow::anyhow!("The endpoint responded with {status}: {}", body.trim());
+        if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+            Err(PublishError::Retryable(error))
+        } else {
+            Err(PublishError::Fatal(error))
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::{
+        io::{AsyncReadExt as _, AsyncWriteExt as _},
+        net::TcpListener,
+    };
+
+    /// A request received by the mock server.
+    #[derive(Debug, Default, Clone)]
+    struct ReceivedRequest {
+        head: String,
+        body: String,
+    }
+
+    /// Start a mock HTTP server which responds to the requests with the status codes in order.
+    async fn start_mock_server(
+        statuses: Vec<u16>,
+    ) -> Result<(String, Arc<Mutex<Vec<ReceivedRequest>>>)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let endpoint = format!("http://{}/api/reference-values", listener.local_addr()?);
+        let received = Arc::new(Mutex::new(vec![]));
+
+        let received_clone = received.clone();
+        tokio::spawn(async move {
+            for status in statuses {
+                let (mut stream, _) = listener.accept().await?;
+                let mut buf = vec![];
+                let head_end = loop {
+                    let mut chunk = [0u8; 4096];
+                    let n = stream.read(&mut chunk).await?;
+                    buf.extend_from_slice(&chunk[..n]);
+                    if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                        break pos + 4;
+                    }
+                    if n == 0 {
+                        bail!("Connection closed before the end of the request head");
+                    }
+                };
+                let head = String::from_utf8_lossy(&buf[..head_end]).to_string();
+                let content_length = head
+                    .lines()
+                    .find_map(|line| {
+                        let (name, value) = line.split_once(':')?;
+                        name.eq_ignore_ascii_case("content-length")
+                            .then(|| value.trim().parse::<usize>().ok())?
+                    })
+                    .unwrap_or(0);
+                while buf.len() < head_end + content_length {
+                    let mut chunk = [0u8; 4096];
+                    let n = stream.read(&mut chunk).await?;
+                    if n == 0 {
+                        break;
+                    }
+                    buf.extend_from_slice(&chunk[..n]);
+                }
+                received_clone.lock().unwrap().push(ReceivedRequest {
+                    head,
+                    body: String::from_utf8_lossy(&buf[head_end..]).to_string(),
+                });
+
+                stream
+                    .write_all(
+                        format!(
+                            "HTTP/1.1 {status} Mock\r\nContent-Length: 4\r\nConnection: close\r\n\r\nmock"
+                        )
+                        .as_bytes(),
+                    )
+                    .await?;
+                stream.shutdown().await?;
+            }
+            anyhow::Ok(())
+        });
+
+        Ok((endpoint, received))
+    }
+
+    async fn new_store(endpoint: &str, token_file: Option<&Path>) -> Result<ReferenceValueStore> {
+        let mut store = ReferenceValueStore::new(endpoint, token_file).await?;
+        store.retry_delay = Duration::from_millis(10);
+        Ok(store)
+    }
+
+    #[tokio::test]
+    async fn test_publish_with_retries() -> Result<()> {
+        let json = r#"{"measurement.kernel.SHA-384": ["aaaa"]}"#;
+        let token_file = tempfile::NamedTempFile::new()?;
+        tokio::fs::write(token_file.path(), "secret-token\n").await?;
+
+        // The server fails twice before accepting the values
+        let (endpoint, received) = start_mock_server(vec![503, 500, 201]).await?;
+        new_store(&endpoint, Some(token_file.path()))
+            .await?
+            .publish(json)
+            .await?;
+        let received = received.lock().unwrap().clone();
+        assert_eq!(received.len(), 3);
+        for request in &received {
+            assert!(request
+                .head
+                .starts_with("POST /api/reference-values HTTP/1.1\r\n"));
+            assert!(request
+                .head
+                .to_ascii_lowercase()
+                .contains("authorization: bearer secret-token\r\n"));
+            assert!(request
+                .head
+                .to_ascii_lowercase()
+                .contains("content-type: application/json\r\n"));
+            assert_eq!(request.body, json);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_publish_errors() -> Result<()> {
+        let json = "{}";
+
+        // A client error is not retried
+        let (endpoint, received) = start_mock_server(vec![401, 200]).await?;
+        let error = new_store(&endpoint, None)
+            .await?
+            .publish(json)
+            .await
+            .unwrap_err();
+        assert!(format!("{error:#}").contains("401"));
+        assert_eq!(received.lock().unwrap().len(), 1);
+        assert!(!received.lock().unwrap()[0]
+            .head
+            .to_ascii_lowercase()
+            .contains("authorization:"));
+
+        // Give up after the retries
+        let (endpoint, received) = start_mock_server(vec![503; MAX_RETRIES + 1]).await?;
+        assert!(new_store(&endpoint, None)
+            .await?
+            .publish(json)
+            .await
+            .is_err());
+        assert_eq!(received.lock().unwrap().len(), MAX_RETRIES + 1);
+
+        assert!(ReferenceValueStore::new("ftp://example.com", None)
+            .await
+            .is_err());
+
+        Ok(())
+    }
+}
@@ -1,14 +1,27 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context as _, Result};
 use async_trait::async_trait;
+use base64::{prelude::BASE64_STANDARD, Engine as _};
+use cryptpilot::measure::{attestation_agent::AAEL_DOMAIN, OPERATION_NAME_LOAD_CONFIG_UNTRUSTED};
 use indexmap::IndexMap;
+use serde::Serialize;
 
 use crate::{
-    cli::{ShowReferenceValueHashAlgo, ShowReferenceValueOptions},
-    cmd::{Command, IntoCommand},
+    cli::{ReferenceValueSchemaVersion, ShowReferenceValueHashAlgo, ShowReferenceValueOptions},
+    cmd::{reference_value_store::ReferenceValueStore, Command, IntoCommand},
     disk::{
-        artifacts::BootArtifacts, current::OnCurrentSystemFdeDisk, external::OnExternalFdeDisk,
+        artifacts::BootArtifacts,
+        current::OnCurrentSystemFdeDisk,
+        external::OnExternalFdeDisk,
+        grub::{insert_grub_config_reference_value, GrubBootArtifacts},
+        initrd::{find_initrd_file, read_initrd_entries},
+        kernel::{extract_kernel_version, KernelArtifacts},
+        os_release::{parse_os_release, OS_RELEASE_FIELDS},
+        pinned_kernel::{
+            insert_pinned_kernel_reference_value, parse_kernel_glob, PinnedKernel,
+            PINNED_INITRD_COMPONENT_PREFIX, PINNED_KERNEL_COMPONENT_PREFIX,
+        },
         BootArtifactsType, FdeDisk,
     },
 };
@@ -18,6 +31,19 @@ impl IntoCommand for ShowReferenceValueOptions {
         Box::new(ShowReferenceValueCommand {
             disk: self.disk,
             hash_algos: self.hash_algos,
+            best_effort: self.best_effort,
+            policy_template: self.policy_template,
+            schema_version: self.schema_version,
+            initrd_files: self.initrd_files,
+            compare: self.compare,
+            no_mount: self.no_mount,
+            os_release: self.os_release,
+            grub_config: self.grub_config,
+            kernels: self.kernels,
+            trustee_bundle: self.trustee_bundle,
+            rootfs_key_file: self.rootfs_key_file,
+            publish: self.publish,
+            publish_token_file: self.publish_token_file,
         })
     }
 }
@@ -25,6 +51,19 @@ impl IntoCommand for ShowReferenceValueOptions {
 pub struct ShowReferenceValueCommand {
     pub disk: Option<PathBuf>,
     pub hash_algos: Vec<ShowReferenceValueHashAlgo>,
+    pub best_effort: bool,
+    pub policy_template: Option<PathBuf>,
+    pub schema_version: ReferenceValueSchemaVersion,
+    pub initrd_files: Vec<PathBuf>,
+    pub compare: Option<PathBuf>,
+    pub no_mount: bool,
+    pub os_release: bool,
+    pub grub_config: bool,
+    pub kernels: Option<String>,
+    pub trustee_bundle: bool,
+    pub rootfs_key_file: Option<PathBuf>,
+    pub publish: Option<String>,
+    pub publish_token_file: Option<PathBuf>,
 }
 
 #[async_trait]
@@ -33,63 +72,1248 @@ impl super::Command for ShowReferenceValueCommand {
         if self.hash_algos.is_empty() {
             bail!("No hash algorithm specified");
         }
+        if let Some(path) = self.initrd_files.iter().find(|path| !path.is_absolute()) {
+            bail!("The path {path:?} of --initrd-file should be an absolute path in the initrd");
+        }
+        let kernels = self.kernels.as_deref().map(parse_kernel_glob).transpose()?;
+        // Check the endpoint and the token before the time consuming computation
+        let store = match &self.publish {
+            Some(endpoint) => {
+                Some(ReferenceValueStore::new(endpoint, self.publish_token_file.as_deref()).await?)
+            }
+            None => None,
+        };
+
+        let map = self
+            .compute_reference_values(self.disk.as_deref(), kernels.as_ref())
+            .await?;
+
+        if let Some(compare) = &self.compare {
+            let other_map = self
+                .compute_reference_values(Some(compare), kernels.as_ref())
+                .await?;
+            let diff = diff_reference_values(&map, &other_map);
+            println!("{}", serde_json::to_string_pretty(&diff)?);
+            let disk = match &self.disk {
+                Some(disk) => format!("{disk:?}"),
+                None => "the current system".to_owned(),
+            };
+            if !diff.is_empty() {
+                bail!(
+                    "Found {} differing reference value(s) between {disk} and {compare:?}",
+                    diff.len()
+                );
+            }
+            tracing::info!("The reference values of {disk} and {compare:?} are identical");
+            return Ok(());
+        }
+
+        let policy = match &self.policy_template {
+            Some(policy_template) => {
+                let template = tokio::fs::read_to_string(policy_template)
+                    .await
+                    .with_context(|| {
+                        format!("Failed to read policy template {policy_template:?}")
+                    })?;
+                let template = serde_json::from_str(&template).with_context(|| {
+                    format!("Failed to parse policy template {policy_template:?} as JSON")
+                })?;
+                Some(fill_policy_template(template, &map)?)
+            }
+            None => None,
+        };
+        let json = match (self.trustee_bundle, policy) {
+            (true, policy) => serde_json::to_string_pretty(&TrusteeBundle::new(&map, policy)?)?,
+            (false, Some(policy)) => serde_json::to_string_pretty(&policy)?,
+            (false, None) => serde_json::to_string_pretty(&map)?,
+        };
+
+        println!("{json:#}");
+
+        if let Some(store) = store {
+            store.publish(&json).await?;
+        }
+
+        Ok(())
+    }
+}
 
-        tracing::debug!("Collecting boot related artifacts");
+impl ShowReferenceValueCommand {
+    /// Compute the reference values of the disk, or of the current system if no disk is given. If
+    /// the pattern of the pinned kernels is given, the kernels matching it are hashed instead of the
+    /// boot entries.
+    async fn compute_reference_values(
+        &self,
+        disk: Option<&Path>,
+        kernels: Option<&glob::Pattern>,
+    ) -> Result<IndexMap<String, Vec<String>>> {
+        tracing::debug!(?disk, "Collecting boot related artifacts");
         let mut map = IndexMap::new();
 
-        let fde_disk: Box<dyn FdeDisk + Send + Sync> = match &self.disk {
+        let fde_disk: Box<dyn FdeDisk + Send + Sync> = match disk {
+            Some(disk) if self.no_mount => {
+                Box::new(OnExternalFdeDisk::new_from_disk_no_mount(disk).await?)
+            }
             Some(disk) => Box::new(OnExternalFdeDisk::new_from_disk(disk).await?),
             None => Box::new(OnCurrentSystemFdeDisk::new().await?),
         };
 
-        let boot_artifacts = fde_disk.extract_boot_artifacts().await?;
+        let boot_artifacts = match kernels {
+            Some(_) => None,
+            None => Some(fde_disk.extract_boot_artifacts(self.best_effort).await?),
+        };
+        let pinned_kernels = match kernels {
+            Some(pattern) => fde_disk.extract_pinned_kernels(pattern).await?,
+            None => vec![],
+        };
+        // The config is read from the same initrds as the ones hashed below
+        let config_hash_contents = if self.trustee_bundle {
+            let kernel_artifacts = match &boot_artifacts {
+                Some(BootArtifactsType::Grub(grub_boot_artifacts)) => {
+                    grub_boot_artifacts.extract_kernel_artifacts().await?
+                }
+                Some(BootArtifactsType::Uki(uki_boot_artifacts)) => {
+                    uki_boot_artifacts.extract_kernel_artifacts().await?
+                }
+                None => pinned_kernels
+                    .iter()
+                    .map(|pinned| KernelArtifacts {
+                        kernel_cmdlines: vec![],
+                        kernel: vec![],
+                        initrd: pinned.initrd.clone(),
+                    })
+                    .collect(),
+            };
+            config_hash_contents(&kernel_artifacts).await?
+        } else {
+            vec![]
+        };
+        let os_release = if self.os_release {
+            Some(
+                fde_disk
+                    .read_os_release(self.rootfs_key_file.as_deref())
+                    .await
+                    .context("Failed to read the os-release file from the rootfs")?,
+            )
+        } else {
+            None
+        };
         tracing::debug!("Starting to calculate reference values");
 
         match boot_artifacts {
-            BootArtifactsType::Grub(grub_boot_artifacts) => {
-                common_insert(&grub_boot_artifacts, &mut map, &self.hash_algos).await?;
+            Some(BootArtifactsType::Grub(grub_boot_artifacts)) => {
+                common_insert(
+                    &grub_boot_artifacts,
+                    &mut map,
+                    &self.hash_algos,
+                    self.best_effort,
+                    &self.initrd_files,
+                )
+                .await?;
+                if self.grub_config {
+                    insert_grub_config(&mut map, &grub_boot_artifacts, &self.hash_algos);
+                }
             }
-            BootArtifactsType::Uki(uki_boot_artifacts) => {
-                common_insert(&uki_boot_artifacts, &mut map, &self.hash_algos).await?;
+            Some(BootArtifactsType::Uki(uki_boot_artifacts)) => {
+                if self.grub_config {
+                    tracing::warn!("The disk is booted with UKI, ignoring --grub-config");
+                }
+                common_insert(
+                    &uki_boot_artifacts,
+                    &mut map,
+                    &self.hash_algos,
+                    self.best_effort,
+                    &self.initrd_files,
+                )
+                .await?;
             }
+            None => insert_pinned_kernels(&mut map, &pinned_kernels, &self.hash_algos),
         };
+        if let Some(os_release) = os_release {
+            insert_os_release(&mut map, &os_release, &self.hash_algos)?;
+        }
 
-        let json = serde_json::to_string_pretty(&map)?;
+        let mut map = filter_by_schema_version(map, self.schema_version);
+        // Only emitted in the Trustee bundle, so not subject to the schema version
+        insert_aael_config(&mut map, &config_hash_contents, &self.hash_algos);
+        Ok(map)
+    }
+}
 
-        println!("{json:#}");
+/// The version of the registration message of the RVPS of Trustee.
+const TRUSTEE_RVPS_MESSAGE_VERSION: &str = "0.1.0";
 
-        Ok(())
+/// The type of the registration message of the RVPS of Trustee, whose payload is the reference
+/// values as a JSON object of lists, in base64.
+const TRUSTEE_RVPS_MESSAGE_TYPE: &str = "sample";
+
+/// The reference values (and the policy) packaged for Trustee, which can be registered as is.
+#[derive(Debug, Serialize)]
+struct TrusteeBundle {
+    reference_values: TrusteeRvpsMessage,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    policy: Option<serde_json::Value>,
+}
+
+/// The registration message of the RVPS of Trustee.
+#[derive(Debug, Serialize)]
+struct TrusteeRvpsMessage {
+    version: &'static str,
+    r#type: &'static str,
+    payload: String,
+}
+
+impl TrusteeBundle {
+    fn new(map: &IndexMap<String, Vec<String>>, policy: Option<serde_json::Value>) -> Result<Self> {
+        Ok(Self {
+            reference_values: TrusteeRvpsMessage {
+                version: TRUSTEE_RVPS_MESSAGE_VERSION,
+                r#type: TRUSTEE_RVPS_MESSAGE_TYPE,
+                payload: BASE64_STANDARD.encode(serde_json::to_string(map)?),
+            },
+            policy,
+        })
+    }
+}
+
+/// The contents of the FDE configs in the initrds which are hashed into the runtime measurement
+/// when the same config is loaded from an untrusted source, without duplicates. Fails if an initrd
+/// has no config, e.g. the disk is not a cryptpilot FDE disk.
+async fn config_hash_contents(kernel_artifacts: &[KernelArtifacts]) -> Result<Vec<String>> {
+    let mut contents = vec![];
+    for kernel_artifacts in kernel_artifacts {
+        let (fde_config_bundle, _) = kernel_artifacts
+            .extract_cryptpilot_files()
+            .await
+            .context("Failed to read the FDE config from initrd for --trustee-bundle")?;
+        let content = fde_config_bundle.gen_hash_content()?;
+        if !contents.contains(&content) {
+            contents.push(content);
+        }
+    }
+    Ok(contents)
+}
+
+/// Insert the expected contents of the `load_config_untrusted` events in the eventlog of the
+/// attestation agent (AAEL), as `AA.eventlog.<domain>.<operation>.<hash-algo>`. The SHA-384 one
+/// is the content extended by the boot service, and the SM3 one is emitted if requested.
+fn insert_aael_config(
+    map: &mut IndexMap<String, Vec<String>>,
+    config_hash_contents: &[String],
+    hash_algos: &[ShowReferenceValueHashAlgo],
+) {
+    if config_hash_contents.is_empty() {
+        return;
     }
+    let name = |hash_key: &str| {
+        format!("AA.eventlog.{AAEL_DOMAIN}.{OPERATION_NAME_LOAD_CONFIG_UNTRUSTED}.{hash_key}")
+    };
+    map.insert(
+        name("SHA-384"),
+        config_hash_contents
+            .iter()
+            .map(|content| hex::encode(<sha2::Sha384 as digest::Digest>::digest(content)))
+            .collect(),
+    );
+    if hash_algos
+        .iter()
+        .any(|hash_algo| matches!(hash_algo, ShowReferenceValueHashAlgo::Sm3))
+    {
+        map.insert(
+            name("SM3"),
+            config_hash_contents
+                .iter()
+                .map(|content| hex::encode(<sm3::Sm3 as digest::Digest>::digest(content)))
+                .collect(),
+        );
+    }
+}
+
+/// A reference value which differs between the disk and the one compared with. The values are
+/// `None` if the name is missing on that side.
+#[derive(Debug, PartialEq, Serialize)]
+struct ReferenceValueDiff {
+    disk: Option<Vec<String>>,
+    compare: Option<Vec<String>>,
+}
+
+/// Find the reference values which differ between the two maps, by name. The order of the values
+/// with the same name does not matter, since it follows the order of the boot entries.
+fn diff_reference_values(
+    map: &IndexMap<String, Vec<String>>,
+    other_map: &IndexMap<String, Vec<String>>,
+) -> IndexMap<String, ReferenceValueDiff> {
+    let sorted = |values: &Vec<String>| {
+        let mut values = values.clone();
+        values.sort();
+        values.dedup();
+        values
+    };
+
+    map.keys()
+        .chain(other_map.keys().filter(|name| !map.contains_key(*name)))
+        .filter_map(|name| {
+            let values = map.get(name);
+            let other_values = other_map.get(name);
+            if values.map(sorted) == other_values.map(sorted) {
+                return None;
+            }
+            Some((
+                name.clone(),
+                ReferenceValueDiff {
+                    disk: values.cloned(),
+                    compare: other_values.cloned(),
+                },
+            ))
+        })
+        .collect()
+}
+
+/// The components measured in the `measurement.<component>.<hash-algo>` reference values of
+/// schema version 1.
+const SCHEMA_V1_MEASUREMENT_COMPONENTS: [&str; 6] =
+    ["kernel_cmdline", "kernel", "initrd", "grub", "shim", "uki"];
+
+/// The components added in schema version 2.
+const SCHEMA_V2_MEASUREMENT_COMPONENTS: [&str; 1] = ["sbat"];
+
+/// The components added in schema version 3, besides the plain `kernel_version` values.
+const SCHEMA_V3_MEASUREMENT_COMPONENTS: [&str; 1] = ["kernel_version"];
+
+/// The prefix of the `measurement.initrd_file:<path>.<hash-algo>` components added in schema
+/// version 4.
+const INITRD_FILE_COMPONENT_PREFIX: &str = "initrd_file:";
+
+/// The components added in schema version 5, besides the plain `os_release.<field>` values.
+const SCHEMA_V5_MEASUREMENT_COMPONENTS: [&str; 1] = ["os_release"];
+
+/// The components added in schema version 6.
+const SCHEMA_V6_MEASUREMENT_COMPONENTS: [&str; 2] = ["grub_cfg", "grubenv"];
+
+/// The prefixes of the `measurement.kernel:<filename>.<hash-algo>` and
+/// `measurement.initrd:<filename>.<hash-algo>` components added in schema version 7.
+const SCHEMA_V7_MEASUREMENT_COMPONENT_PREFIXES: [&str; 2] = [
+    PINNED_KERNEL_COMPONENT_PREFIX,
+    PINNED_INITRD_COMPONENT_PREFIX,
+];
+
+/// The prefix of the plain `os_release.<field>` values added in schema version 5.
+const OS_RELEASE_FIELD_PREFIX: &str = "os_release.";
+
+const SCHEMA_HASH_KEYS: [&str; 4] = ["SHA-1", "SHA-256", "SHA-384", "SM3"];
+
+/// Check if the reference value name is defined in the schema version.
+fn is_in_schema(name: &str, schema_version: ReferenceValueSchemaVersion) -> bool {
+    if name == "kernel_cmdline" {
+        return true;
+    }
+    if name == "kernel_version" {
+        return schema_version >= ReferenceValueSchemaVersion::V3;
+    }
+    if let Some(field) = name.strip_prefix(OS_RELEASE_FIELD_PREFIX) {
+        return schema_version >= ReferenceValueSchemaVersion::V5
+            && OS_RELEASE_FIELDS.contains(&field);
+    }
+    let is_component_in_schema = |component: &str| {
+        SCHEMA_V1_MEASUREMENT_COMPONENTS.contains(&component)
+            || (schema_version >= ReferenceValueSchemaVersion::V2
+                && SCHEMA_V2_MEASUREMENT_COMPONENTS.contains(&component))
+            || (schema_version >= ReferenceValueSchemaVersion::V3
+                && SCHEMA_V3_MEASUREMENT_COMPONENTS.contains(&component))
+            || (schema_version >= ReferenceValueSchemaVersion::V4
+                && component
+                    .strip_prefix(INITRD_FILE_COMPONENT_PREFIX)
+                    .is_some_and(|path| path.starts_with('/')))
+            || (schema_version >= ReferenceValueSchemaVersion::V5
+                && SCHEMA_V5_MEASUREMENT_COMPONENTS.contains(&component))
+            || (schema_version >= ReferenceValueSchemaVersion::V6
+                && SCHEMA_V6_MEASUREMENT_COMPONENTS.contains(&component))
+            || (schema_version >= ReferenceValueSchemaVersion::V7
+                && SCHEMA_V7_MEASUREMENT_COMPONENT_PREFIXES
+                    .iter()
+                    .any(|prefix| {
+                        component
+                            .strip_prefix(prefix)
+                            .is_some_and(|name| !name.is_empty())
+                    }))
+    };
+    name.strip_prefix("measurement.")
+        .and_then(|name| name.rsplit_once('.'))
+        .is_some_and(|(component, hash_key)| {
+            is_component_in_schema(component) && SCHEMA_HASH_KEYS.contains(&hash_key)
+        })
+}
+
+/// Drop the reference values which are not defined in the schema version, e.g. the ones added in a
+/// later version.
+fn filter_by_schema_version(
+    map: IndexMap<String, Vec<String>>,
+    schema_version: ReferenceValueSchemaVersion,
+) -> IndexMap<String, Vec<String>> {
+    map.into_iter()
+        .filter(|(name, _)| {
+            let in_schema = is_in_schema(name, schema_version);
+            if !in_schema {
+                tracing::debug!(
+                    name,
+                    ?schema_version,
+                    "Omitting reference value not in the schema"
+                );
+            }
+            in_schema
+        })
+        .collect()
+}
+
+/// Replace each placeholder string "{{<name>}}" in the policy template with the reference values
+/// named `<name>`. A placeholder "{{<name>?}}" is optional, and is replaced with an empty list if the
+/// values are not computed. Fails if a required placeholder cannot be filled.
+fn fill_policy_template(
+    template: serde_json::Value,
+    map: &IndexMap<String, Vec<String>>,
+) -> Result<serde_json::Value> {
+    use serde_json::Value;
+
+    Ok(match template {
+        Value::String(string) => {
+            let Some(name) = string
+                .trim()
+                .strip_prefix("{{")
+                .and_then(|s| s.strip_suffix("}}"))
+                .map(str::trim)
+            else {
+                return Ok(Value::String(string));
+            };
+            let (name, optional) = match name.strip_suffix('?') {
+                Some(name) => (name.trim_end(), true),
+                None => (name, false),
+            };
+            match map.get(name) {
+                Some(values) => Value::from(values.clone()),
+                None if optional => Value::Array(vec![]),
+                None => bail!(
+                    "The reference value \"{name}\" required by the policy template cannot be computed, available ones are: {:?}. Check if the boot mode of the disk and the --hash-algo option match the template",
+                    map.keys().collect::<Vec<_>>()
+                ),
+            }
+        }
+        Value::Array(array) => Value::Array(
+            array
+                .into_iter()
+                .map(|value| fill_policy_template(value, map))
+                .collect::<Result<_>>()?,
+        ),
+        Value::Object(object) => Value::Object(
+            object
+                .into_iter()
+                .map(|(key, value)| Ok((key, fill_policy_template(value, map)?)))
+                .collect::<Result<_>>()?,
+        ),
+        value => value,
+    })
+}
+
+/// The version strings of the kernels booted by the boot artifacts, without duplicates. A kernel
+/// whose version cannot be found is skipped.
+async fn kernel_versions(boot_artifacts: &impl BootArtifacts) -> Vec<String> {
+    let kernel_artifacts = match boot_artifacts.extract_kernel_artifacts().await {
+        Ok(kernel_artifacts) => kernel_artifacts,
+        Err(error) => {
+            tracing::debug!(
+                ?error,
+                "Cannot extract the kernel images, skip the kernel version"
+            );
+            return vec![];
+        }
+    };
+    let mut versions = vec![];
+    for kernel_artifacts in kernel_artifacts {
+        match extract_kernel_version(&kernel_artifacts.kernel) {
+            Some(version) if !versions.contains(&version) => versions.push(version),
+            Some(_) => {}
+            None => tracing::debug!("No version string found in the kernel image"),
+        }
+    }
+    versions
+}
+
+/// The contents of the files at the paths inside the initrds of the boot artifacts, without
+/// duplicates. Fails if a file is missing from any initrd.
+async fn read_initrd_files(
+    boot_artifacts: &impl BootArtifacts,
+    paths: &[PathBuf],
+) -> Result<Vec<(PathBuf, Vec<Vec<u8>>)>> {
+    if paths.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut files: Vec<(PathBuf, Vec<Vec<u8>>)> =
+        paths.iter().map(|path| (path.clone(), vec![])).collect();
+    for kernel_artifacts in boot_artifacts.extract_kernel_artifacts().await? {
+        let entries = read_initrd_entries(&kernel_artifacts.initrd)
+            .await
+            .context("Failed to read initrd")?;
+        for (path, contents) in &mut files {
+            let entry = find_initrd_file(&entries, path)
+                .context("Failed to find the file requested by --initrd-file")?;
+            if !contents.contains(&entry.data) {
+                contents.push(entry.data.clone());
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// The name of the reference values of the file at the path inside the initrd.
+fn initrd_file_reference_value_name(path: &Path, hash_key: &str) -> String {
+    format!(
+        "measurement.{INITRD_FILE_COMPONENT_PREFIX}{}.{hash_key}",
+        path.display()
+    )
+}
+
+/// Insert the fields of the os-release file which identify the OS image, and the hashes of the
+/// whole file. Fails if the file has none of the fields, e.g. it is not an os-release file.
+fn insert_os_release(
+    map: &mut IndexMap<String, Vec<String>>,
+    os_release: &[u8],
+    hash_algos: &[ShowReferenceValueHashAlgo],
+) -> Result<()> {
+    let fields = parse_os_release(&String::from_utf8_lossy(os_release));
+    let mut found = false;
+    for field in OS_RELEASE_FIELDS {
+        if let Some(value) = fields.get(field) {
+            map.insert(
+                format!("{OS_RELEASE_FIELD_PREFIX}{field}"),
+                vec![value.clone()],
+            );
+            found = true;
+        }
+    }
+    if !found {
+        bail!(
+            "None of the fields {} is found in the os-release file",
+            OS_RELEASE_FIELDS.join(", ")
+        );
+    }
+
+    for hash_algo in hash_algos {
+        let (hash_key, hash) = match hash_algo {
+            ShowReferenceValueHashAlgo::Sha1 => (
+                "SHA-1",
+                hex::encode(<sha1::Sha1 as digest::Digest>::digest(os_release)),
+            ),
+            ShowReferenceValueHashAlgo::Sha256 => (
+                "SHA-256",
+                hex::encode(<sha2::Sha256 as digest::Digest>::digest(os_release)),
+            ),
+            ShowReferenceValueHashAlgo::Sha384 => (
+                "SHA-384",
+                hex::encode(<sha2::Sha384 as digest::Digest>::digest(os_release)),
+            ),
+            ShowReferenceValueHashAlgo::Sm3 => (
+                "SM3",
+                hex::encode(<sm3::Sm3 as digest::Digest>::digest(os_release)),
+            ),
+        };
+        map.insert(format!("measurement.os_release.{hash_key}"), vec![hash]);
+    }
+    Ok(())
+}
+
+/// Insert the hashes of the grub.cfg and grubenv contents of the GRUB boot entries.
+fn insert_grub_config(
+    map: &mut IndexMap<String, Vec<String>>,
+    grub_boot_artifacts: &GrubBootArtifacts,
+    hash_algos: &[ShowReferenceValueHashAlgo],
+) {
+    for hash_algo in hash_algos {
+        match hash_algo {
+            ShowReferenceValueHashAlgo::Sha1 => {
+                insert_grub_config_reference_value::<sha1::Sha1>(grub_boot_artifacts, map, "SHA-1")
+            }
+            ShowReferenceValueHashAlgo::Sha256 => {
+                insert_grub_config_reference_value::<sha2::Sha256>(
+                    grub_boot_artifacts,
+                    map,
+                    "SHA-256",
+                )
+            }
+            ShowReferenceValueHashAlgo::Sha384 => {
+                insert_grub_config_reference_value::<sha2::Sha384>(
+                    grub_boot_artifacts,
+                    map,
+                    "SHA-384",
+                )
+            }
+            ShowReferenceValueHashAlgo::Sm3 => {
+                insert_grub_config_reference_value::<sm3::Sm3>(grub_boot_artifacts, map, "SM3")
+            }
+        }
+    }
+}
+
+/// Insert the hashes of the kernels pinned by their filenames and of their initrds.
+fn insert_pinned_kernels(
+    map: &mut IndexMap<String, Vec<String>>,
+    pinned_kernels: &[PinnedKernel],
+    hash_algos: &[ShowReferenceValueHashAlgo],
+) {
+    for hash_algo in hash_algos {
+        match hash_algo {
+            ShowReferenceValueHashAlgo::Sha1 => {
+                insert_pinned_kernel_reference_value::<sha1::Sha1>(pinned_kernels, map, "SHA-1")
+            }
+            ShowReferenceValueHashAlgo::Sha256 => {
+                insert_pinned_kernel_reference_value::<sha2::Sha256>(pinned_kernels, map, "SHA-256")
+            }
+            ShowReferenceValueHashAlgo::Sha384 => {
+                insert_pinned_kernel_reference_value::<sha2::Sha384>(pinned_kernels, map, "SHA-384")
+            }
+            ShowReferenceValueHashAlgo::Sm3 => {
+                insert_pinned_kernel_reference_value::<sm3::Sm3>(pinned_kernels, map, "SM3")
+            }
+        }
+    }
+}
+
+async fn insert_with_hash_algo<T>(
+    boot_artifacts: &impl BootArtifacts,
+    map: &mut IndexMap<String, Vec<String>>,
+    hash_key: &str,
+    best_effort: bool,
+    kernel_versions: &[String],
+    initrd_files: &[(PathBuf, Vec<Vec<u8>>)],
+) -> Result<()>
+where
+    T: digest::Digest + digest::Update,
+{
+    boot_artifacts
+        .inseart_reference_value::<T>(map, hash_key, best_effort)
+        .await?;
+    if !kernel_versions.is_empty() {
+        map.insert(
+            format!("measurement.kernel_version.{hash_key}"),
+            kernel_versions
+                .iter()
+                .map(|version| hex::encode(<T as digest::Digest>::digest(version.as_bytes())))
+                .collect(),
+        );
+    }
+    for (path, contents) in initrd_files {
+        map.insert(
+            initrd_file_reference_value_name(path, hash_key),
+            contents
+                .iter()
+                .map(|content| hex::encode(<T as digest::Digest>::digest(content)))
+                .collect(),
+        );
+    }
+    Ok(())
 }
 
 async fn common_insert(
     boot_artifacts: &impl BootArtifacts,
     map: &mut IndexMap<String, Vec<String>>,
     hash_algos: &[ShowReferenceValueHashAlgo],
+    best_effort: bool,
+    initrd_file_paths: &[PathBuf],
 ) -> Result<()> {
+    let initrd_files = read_initrd_files(boot_artifacts, initrd_file_paths).await?;
+    let kernel_versions = kernel_versions(boot_artifacts).await;
+    if !kernel_versions.is_empty() {
+        map.insert("kernel_version".to_owned(), kernel_versions.clone());
+    }
+
     for hash_algo in hash_algos {
         match hash_algo {
             ShowReferenceValueHashAlgo::Sha1 => {
-                boot_artifacts
-                    .inseart_reference_value::<sha1::Sha1>(map, "SHA-1")
-                    .await?
+                insert_with_hash_algo::<sha1::Sha1>(
+                    boot_artifacts,
+                    map,
+                    "SHA-1",
+                    best_effort,
+                    &kernel_versions,
+                    &initrd_files,
+                )
+                .await?
             }
             ShowReferenceValueHashAlgo::Sha256 => {
-                boot_artifacts
-                    .inseart_reference_value::<sha2::Sha256>(map, "SHA-256")
-                    .await?
+                insert_with_hash_algo::<sha2::Sha256>(
+                    boot_artifacts,
+                    map,
+                    "SHA-256",
+                    best_effort,
+                    &kernel_versions,
+                    &initrd_files,
+                )
+                .await?
             }
             ShowReferenceValueHashAlgo::Sha384 => {
-                boot_artifacts
-                    .inseart_reference_value::<sha2::Sha384>(map, "SHA-384")
-                    .await?
+                insert_with_hash_algo::<sha2::Sha384>(
+                    boot_artifacts,
+                    map,
+                    "SHA-384",
+                    best_effort,
+                    &kernel_versions,
+                    &initrd_files,
+                )
+                .await?
             }
             ShowReferenceValueHashAlgo::Sm3 => {
-                boot_artifacts
-                    .inseart_reference_value::<sm3::Sm3>(map, "SM3")
-                    .await?
+                insert_with_hash_algo::<sm3::Sm3>(
+                    boot_artifacts,
+                    map,
+                    "SM3",
+                    best_effort,
+                    &kernel_versions,
+                    &initrd_files,
+                )
+                .await?
             }
         }
     }
     Ok(())
 }
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+    use crate::disk::kernel::KernelArtifacts;
+    use serde_json::json;
+
+    #[test]
+    fn test_trustee_bundle() -> Result<()> {
+        let mut map = IndexMap::new();
+        map.insert(
+            "measurement.uki.SHA-384".to_owned(),
+            vec!["a46e162a".to_owned()],
+        );
+        insert_aael_config(
+            &mut map,
+            &["[fde.rootfs]\n".to_owned()],
+            &[
+                ShowReferenceValueHashAlgo::Sha384,
+                ShowReferenceValueHashAlgo::Sm3,
+            ],
+        );
+        assert_eq!(
+            map.keys().collect::<Vec<_>>(),
+            [
+                "measurement.uki.SHA-384",
+                "AA.eventlog.cryptpilot.alibabacloud.com.load_config_untrusted.SHA-384",
+                "AA.eventlog.cryptpilot.alibabacloud.com.load_config_untrusted.SM3",
+            ]
+        );
+        assert_eq!(
+            map["AA.eventlog.cryptpilot.alibabacloud.com.load_config_untrusted.SHA-384"],
+            [hex::encode(<sha2::Sha384 as digest::Digest>::digest(
+                "[fde.rootfs]\n"
+            ))]
+        );
+
+        let bundle = serde_json::to_value(TrusteeBundle::new(&map, None)?)?;
+        let object = bundle.as_object().unwrap();
+        assert_eq!(object.keys().collect::<Vec<_>>(), ["reference_values"]);
+        let message = bundle["reference_values"].as_object().unwrap();
+        assert_eq!(
+            message.keys().collect::<Vec<_>>(),
+            ["version", "type", "payload"]
+        );
+        assert_eq!(message["version"], "0.1.0");
+        assert_eq!(message["type"], "sample");
+        let payload: IndexMap<String, Vec<String>> =
+            serde_json::from_slice(&BASE64_STANDARD.decode(message["payload"].as_str().unwrap())?)?;
+        assert_eq!(payload, map);
+
+        // The filled policy is included as is
+        let policy = json!({"reference": {"uki": ["a46e162a"]}});
+        let bundle = serde_json::to_value(TrusteeBundle::new(&map, Some(policy.clone()))?)?;
+        assert_eq!(bundle["policy"], policy);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fill_policy_template() -> Result<()> {
+        let mut map = IndexMap::new();
+        map.insert(
+            "measurement.kernel.SHA-384".to_owned(),
+            vec!["aaaa".to_owned(), "bbbb".to_owned()],
+        );
+        map.insert(
+            "measurement.initrd.SHA-384".to_owned(),
+            vec!["cccc".to_owned()],
+        );
+
+        let template = json!({
+            "version": 1,
+            "boot": {
+                "kernel": "{{measurement.kernel.SHA-384}}",
+                "initrd": "{{ measurement.initrd.SHA-384 }}",
+                "grub": "{{measurement.grub.SHA-384?}}",
+                "comment": "not a {{placeholder}}"
+            },
+            "all": ["{{measurement.initrd.SHA-384}}"]
+        });
+        assert_eq!(
+            fill_policy_template(template, &map)?,
+            json!({
+                "version": 1,
+                "boot": {
+                    "kernel": ["aaaa", "bbbb"],
+                    "initrd": ["cccc"],
+                    "grub": [],
+                    "comment": "not a {{placeholder}}"
+                },
+                "all": [["cccc"]]
+            })
+        );
+
+        // A required value which cannot be computed
+        let template = json!({ "uki": "{{measurement.uki.SHA-384}}" });
+        assert!(fill_policy_template(template, &map).is_err());
+
+        Ok(())
+    }
+    #[test]
+    fn test_diff_reference_values() {
+        let to_map = |entries: &[(&str, &[&str])]| -> IndexMap<String, Vec<String>> {
+            entries
+                .iter()
+                .map(|(name, values)| {
+                    (
+                        name.to_string(),
+                        values.iter().map(|value| value.to_string()).collect(),
+                    )
+                })
+                .collect()
+        };
+
+        let map = to_map(&[
+            ("kernel_cmdline", &["a", "b"]),
+            ("measurement.kernel.SHA-384", &["aaaa"]),
+            ("measurement.grub.SHA-384", &["bbbb"]),
+        ]);
+        // The same values in another order are not a difference
+        let same = to_map(&[
+            ("measurement.kernel.SHA-384", &["aaaa"]),
+            ("kernel_cmdline", &["b", "a"]),
+            ("measurement.grub.SHA-384", &["bbbb"]),
+        ]);
+        assert!(diff_reference_values(&map, &same).is_empty());
+
+        let other_map = to_map(&[
+            ("kernel_cmdline", &["a", "b"]),
+            ("measurement.kernel.SHA-384", &["cccc"]),
+            ("measurement.uki.SHA-384", &["dddd"]),
+        ]);
+        let diff = diff_reference_values(&map, &other_map);
+        assert_eq!(
+            diff.keys().collect::<Vec<_>>(),
+            [
+                "measurement.kernel.SHA-384",
+                "measurement.grub.SHA-384",
+                "measurement.uki.SHA-384"
+            ]
+        );
+        assert_eq!(
+            diff["measurement.kernel.SHA-384"],
+            ReferenceValueDiff {
+                disk: Some(vec!["aaaa".to_owned()]),
+                compare: Some(vec!["cccc".to_owned()]),
+            }
+        );
+        assert_eq!(diff["measurement.grub.SHA-384"].compare, None);
+        assert_eq!(diff["measurement.uki.SHA-384"].disk, None);
+    }
+
+    #[test]
+    fn test_filter_by_schema_version_v1() -> Result<()> {
+        let v1_names = [
+            "kernel_cmdline",
+            "measurement.kernel_cmdline.SHA-384",
+            "measurement.kernel.SHA-384",
+            "measurement.initrd.SHA-256",
+            "measurement.grub.SM3",
+            "measurement.shim.SHA-1",
+            "measurement.uki.SHA-384",
+        ];
+        let mut map = IndexMap::new();
+        for name in v1_names.iter().chain(&[
+            "measurement.dtb.SHA-384",
+            "measurement.kernel.SHA3-512",
+            "os_release",
+        ]) {
+            map.insert(name.to_string(), vec!["aaaa".to_owned()]);
+        }
+
+        let filtered = filter_by_schema_version(map, ReferenceValueSchemaVersion::V1);
+        assert_eq!(filtered.keys().collect::<Vec<_>>(), v1_names);
+        assert!(filtered.values().all(|values| values == &["aaaa"]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_filter_by_schema_version_v2() -> Result<()> {
+        let mut map = IndexMap::new();
+        for name in [
+            "kernel_cmdline",
+            "measurement.shim.SHA-384",
+            "measurement.sbat.SHA-384",
+            "measurement.dtb.SHA-384",
+        ] {
+            map.insert(name.to_string(), vec!["aaaa".to_owned()]);
+        }
+
+        let filtered = filter_by_schema_version(map.clone(), ReferenceValueSchemaVersion::V2);
+        assert_eq!(
+            filtered.keys().collect::<Vec<_>>(),
+            [
+                "kernel_cmdline",
+                "measurement.shim.SHA-384",
+                "measurement.sbat.SHA-384"
+            ]
+        );
+
+        // The SBAT values are added in version 2
+        let filtered = filter_by_schema_version(map, ReferenceValueSchemaVersion::V1);
+        assert_eq!(
+            filtered.keys().collect::<Vec<_>>(),
+            ["kernel_cmdline", "measurement.shim.SHA-384"]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_filter_by_schema_version_v3() -> Result<()> {
+        let mut map = IndexMap::new();
+        for name in [
+            "kernel_cmdline",
+            "kernel_version",
+            "measurement.sbat.SHA-384",
+            "measurement.kernel_version.SHA-384",
+        ] {
+            map.insert(name.to_string(), vec!["aaaa".to_owned()]);
+        }
+
+        let filtered = filter_by_schema_version(map.clone(), ReferenceValueSchemaVersion::V3);
+        assert_eq!(filtered.len(), 4);
+
+        // The kernel version values are added in version 3
+        let filtered = filter_by_schema_version(map, ReferenceValueSchemaVersion::V2);
+        assert_eq!(
+            filtered.keys().collect::<Vec<_>>(),
+            ["kernel_cmdline", "measurement.sbat.SHA-384"]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_filter_by_schema_version_v4() -> Result<()> {
+        let mut map = IndexMap::new();
+        for name in [
+            "kernel_version",
+            "measurement.initrd_file:/usr/bin/cryptpilot-fde.SHA-384",
+            "measurement.initrd_file:/etc/cryptpilot/fde.toml.SM3",
+            "measurement.initrd_file:relative.SHA-384",
+        ] {
+            map.insert(name.to_string(), vec!["aaaa".to_owned()]);
+        }
+
+        let filtered = filter_by_schema_version(map.clone(), ReferenceValueSchemaVersion::V4);
+        assert_eq!(
+            filtered.keys().collect::<Vec<_>>(),
+            [
+                "kernel_version",
+                "measurement.initrd_file:/usr/bin/cryptpilot-fde.SHA-384",
+                "measurement.initrd_file:/etc/cryptpilot/fde.toml.SM3"
+            ]
+        );
+
+        // The initrd file values are added in version 4
+        let filtered = filter_by_schema_version(map, ReferenceValueSchemaVersion::V3);
+        assert_eq!(filtered.keys().collect::<Vec<_>>(), ["kernel_version"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_filter_by_schema_version_v5() -> Result<()> {
+        let mut map = IndexMap::new();
+        for name in [
+            "kernel_version",
+            "os_release.ID",
+            "os_release.IMAGE_ID",
+            "os_release.PRETTY_NAME",
+            "measurement.os_release.SHA-384",
+        ] {
+            map.insert(name.to_string(), vec!["aaaa".to_owned()]);
+        }
+
+        let filtered = filter_by_schema_version(map.clone(), ReferenceValueSchemaVersion::V5);
+        assert_eq!(
+            filtered.keys().collect::<Vec<_>>(),
+            [
+                "kernel_version",
+                "os_release.ID",
+                "os_release.IMAGE_ID",
+                "measurement.os_release.SHA-384"
+            ]
+        );
+
+        // The os-release values are added in version 5
+        let filtered = filter_by_schema_version(map, ReferenceValueSchemaVersion::V4);
+        assert_eq!(filtered.keys().collect::<Vec<_>>(), ["kernel_version"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_filter_by_schema_version_v6() -> Result<()> {
+        let mut map = IndexMap::new();
+        for name in [
+            "measurement.grub.SHA-384",
+            "measurement.grub_cfg.SHA-384",
+            "measurement.grubenv.SM3",
+            "measurement.grub_cfg.MD5",
+        ] {
+            map.insert(name.to_string(), vec!["aaaa".to_owned()]);
+        }
+
+        let filtered = filter_by_schema_version(map.clone(), ReferenceValueSchemaVersion::V6);
+        assert_eq!(
+            filtered.keys().collect::<Vec<_>>(),
+            [
+                "measurement.grub.SHA-384",
+                "measurement.grub_cfg.SHA-384",
+                "measurement.grubenv.SM3"
+            ]
+        );
+
+        // The GRUB config values are added in version 6
+        let filtered = filter_by_schema_version(map, ReferenceValueSchemaVersion::V5);
+        assert_eq!(
+            filtered.keys().collect::<Vec<_>>(),
+            ["measurement.grub.SHA-384"]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_filter_by_schema_version_v7() -> Result<()> {
+        let mut map = IndexMap::new();
+        for name in [
+            "measurement.grub_cfg.SHA-384",
+            "measurement.kernel:vmlinuz-5.10.134-16.al8.x86_64.SHA-384",
+            "measurement.initrd:vmlinuz-5.10.134-16.al8.x86_64.SM3",
+            "measurement.kernel:.SHA-384",
+        ] {
+            map.insert(name.to_string(), vec!["aaaa".to_owned()]);
+        }
+
+        let filtered = filter_by_schema_version(map.clone(), ReferenceValueSchemaVersion::V7);
+        assert_eq!(
+            filtered.keys().collect::<Vec<_>>(),
+            [
+                "measurement.grub_cfg.SHA-384",
+                "measurement.kernel:vmlinuz-5.10.134-16.al8.x86_64.SHA-384",
+                "measurement.initrd:vmlinuz-5.10.134-16.al8.x86_64.SM3"
+            ]
+        );
+
+        // The pinned kernel values are added in version 7
+        let filtered = filter_by_schema_version(map, ReferenceValueSchemaVersion::V6);
+        assert_eq!(
+            filtered.keys().collect::<Vec<_>>(),
+            ["measurement.grub_cfg.SHA-384"]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_os_release() -> Result<()> {
+        let os_release = b"NAME=\"Alibaba Cloud Linux\"\nID=\"alinux\"\nVERSION_ID=\"3\"\n";
+        let mut map = IndexMap::new();
+        insert_os_release(
+            &mut map,
+            os_release,
+            &[
+                ShowReferenceValueHashAlgo::Sha256,
+                ShowReferenceValueHashAlgo::Sm3,
+            ],
+        )?;
+        assert_eq!(
+            map.keys().collect::<Vec<_>>(),
+            [
+                "os_release.ID",
+                "os_release.VERSION_ID",
+                "measurement.os_release.SHA-256",
+                "measurement.os_release.SM3"
+            ]
+        );
+        assert_eq!(map["os_release.ID"], ["alinux"]);
+        assert_eq!(map["os_release.VERSION_ID"], ["3"]);
+        assert_eq!(
+            map["measurement.os_release.SHA-256"],
+            [hex::encode(<sha2::Sha256 as digest::Digest>::digest(
+                os_release
+            ))]
+        );
+
+        // Not an os-release file
+        assert!(insert_os_release(
+            &mut IndexMap::new(),
+            b"# empty\n",
+            &[ShowReferenceValueHashAlgo::Sha256]
+        )
+        .is_err());
+
+        Ok(())
+    }
+
+    /// Boot artifacts booting the kernels, with no reference values of their own.
+    struct TestBootArtifacts {
+        kernels: Vec<Vec<u8>>,
+        initrd: Vec<u8>,
+    }
+
+    #[async_trait]
+    impl BootArtifacts for TestBootArtifacts {
+        async fn inseart_reference_value<T>(
+            &self,
+            _map: &mut IndexMap<String, Vec<String>>,
+            _hash_key: &str,
+            _best_effort: bool,
+        ) -> Result<()>
+        where
+            T: digest::Digest + digest::Update,
+        {
+            Ok(())
+        }
+
+        async fn extract_kernel_artifacts(&self) -> Result<Vec<KernelArtifacts>> {
+            Ok(self
+                .kernels
+                .iter()
+                .map(|kernel| KernelArtifacts {
+                    kernel_cmdlines: vec![],
+                    kernel: kernel.clone(),
+                    initrd: self.initrd.clone(),
+                })
+                .collect())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_insert_kernel_version() -> Result<()> {
+        let version = "5.10.134-16.al8.x86_64 (mockbuild@alinux) #1 SMP";
+        let kernel = crate::disk::kernel::tests::build_bzimage(version);
+        let boot_artifacts = TestBootArtifacts {
+            kernels: vec![kernel.clone(), b"no version".to_vec(), kernel],
+            initrd: vec![],
+        };
+
+        let mut map = IndexMap::new();
+        common_insert(
+            &boot_artifacts,
+            &mut map,
+            &[ShowReferenceValueHashAlgo::Sha256],
+            false,
+            &[],
+        )
+        .await?;
+        assert_eq!(map.get("kernel_version"), Some(&vec![version.to_owned()]));
+        assert_eq!(
+            map.get("measurement.kernel_version.SHA-256"),
+            Some(&vec![hex::encode(
+                <sha2::Sha256 as digest::Digest>::digest(version)
+            )])
+        );
+
+        // Nothing is inserted if no version is found
+        let boot_artifacts = TestBootArtifacts {
+            kernels: vec![b"no version".to_vec()],
+            initrd: vec![],
+        };
+        let mut map = IndexMap::new();
+        common_insert(
+            &boot_artifacts,
+            &mut map,
+            &[ShowReferenceValueHashAlgo::Sha256],
+            false,
+            &[],
+        )
+        .await?;
+        assert!(map.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_insert_initrd_files() -> Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let root_dir = temp_dir.path().join("root");
+        tokio::fs::create_dir_all(root_dir.join("usr/bin")).await?;
+        tokio::fs::create_dir_all(root_dir.join("etc/cryptpilot")).await?;
+        tokio::fs::write(root_dir.join("usr/bin/cryptpilot-fde"), "binary").await?;
+        tokio::fs::write(root_dir.join("etc/cryptpilot/fde.toml"), "config").await?;
+        tokio::fs::symlink("usr/bin", root_dir.join("bin")).await?;
+        let initrd = crate::disk::initrd::tests::pack_initrd(
+            &root_dir,
+            crate::disk::initrd::InitrdCompression::Gzip,
+        )
+        .await?;
+
+        // The same initrd booted by two boot entries
+        let boot_artifacts = TestBootArtifacts {
+            kernels: vec![b"kernel".to_vec(), b"kernel".to_vec()],
+            initrd,
+        };
+        let mut map = IndexMap::new();
+        common_insert(
+            &boot_artifacts,
+            &mut map,
+            &[ShowReferenceValueHashAlgo::Sha256],
+            false,
+            &[
+                PathBuf::from("/bin/cryptpilot-fde"),
+                PathBuf::from("/etc/cryptpilot/fde.toml"),
+            ],
+        )
+        .await?;
+        assert_eq!(
+            map.get("measurement.initrd_file:/bin/cryptpilot-fde.SHA-256"),
+            Some(&vec![hex::encode(
+                <sha2::Sha256 as digest::Digest>::digest("binary")
+            )])
+        );
+        assert_eq!(
+            map.get("measurement.initrd_file:/etc/cryptpilot/fde.toml.SHA-256"),
+            Some(&vec![hex::encode(
+                <sha2::Sha256 as digest::Digest>::digest("config")
+            )])
+        );
+
+        // A file missing from the initrd is an error
+        let error = common_insert(
+            &boot_artifacts,
+            &mut IndexMap::new(),
+            &[ShowReferenceValueHashAlgo::Sha256],
+            false,
+            &[PathBuf::from("/usr/bin/missing")],
+        )
+        .await
+        .unwrap_err();
+        assert!(format!("{error:#}").contains("No file \"/usr/bin/missing\" in initrd"));
+
+        Ok(())
+    }
+}
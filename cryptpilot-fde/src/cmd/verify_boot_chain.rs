@@ -0,0 +1,400 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context as _, Result};
+use async_trait::async_trait;
+use serde::Serialize;
+use tokio::process::Command;
+
+use crate::{
+    cmd::boot_service::{
+        metadata::Metadata,
+        stage::{ROOTFS_HASH_LOGICAL_VOLUME, ROOTFS_LOGICAL_VOLUME},
+    },
+    config::FdeConfigBundle,
+    disk::{
+        artifacts::BootArtifacts as _, external::OnExternalFdeDisk, kernel::KernelArtifacts,
+        volume_group::ActiveVolumeGroup, BootArtifactsType, FdeDisk,
+    },
+};
+use cryptpilot::fs::cmd::CheckCommandOutput as _;
+
+pub struct VerifyBootChainCommand {
+    pub disk: PathBuf,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Passed,
+    Failed,
+    /// The check cannot be done, e.g. it depends on a failed check.
+    Skipped,
+}
+
+/// The result of one link of the FDE boot chain.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct SubCheck {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    pub detail: String,
+}
+
+impl SubCheck {
+    fn new(name: &'static str, status: CheckStatus, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// Summary of `verify-boot-chain`, which passes only if none of the checks fails.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct BootChainReport {
+    pub passed: bool,
+    pub checks: Vec<SubCheck>,
+}
+
+impl BootChainReport {
+    fn new(checks: Vec<SubCheck>) -> Self {
+        Self {
+            passed: checks
+                .iter()
+                .all(|check| check.status != CheckStatus::Failed),
+            checks,
+        }
+    }
+}
+
+#[async_trait]
+impl super::Command for VerifyBootChainCommand {
+    async fn run(&self) -> Result<()> {
+        let report = BootChainReport::new(verify_boot_chain(&self.disk).await?);
+        println!("{}", serde_json::to_string_pretty(&report)?);
+
+        if !report.passed {
+            bail!(
+                "The FDE boot chain of {:?} is inconsistent, see the failed checks above",
+                self.disk
+            );
+        }
+        Ok(())
+    }
+}
+
+async fn verify_boot_chain(disk: &Path) -> Result<Vec<SubCheck>> {
+    let fde_disk = OnExternalFdeDisk::new_from_disk(disk).await?;
+    let mut checks = vec![];
+
+    // The boot artifacts, which carry the FDE config bundle and the metadata in the initrd
+    let kernel_artifacts = match extract_kernel_artifacts(&fde_disk).await {
+        Ok(kernel_artifacts) if kernel_artifacts.is_empty() => {
+            checks.push(SubCheck::new(
+                "boot-artifacts",
+                CheckStatus::Failed,
+                "No kernel found in the boot artifacts",
+            ));
+            vec![]
+        }
+        Ok(kernel_artifacts) => {
+            checks.push(SubCheck::new(
+                "boot-artifacts",
+                CheckStatus::Passed,
+                format!("Found {} kernel(s) to boot", kernel_artifacts.len()),
+            ));
+            kernel_artifacts
+        }
+        Err(error) => {
+            checks.push(SubCheck::new(
+                "boot-artifacts",
+                CheckStatus::Failed,
+                format!("{error:#}"),
+            ));
+            vec![]
+        }
+    };
+
+    let mut cryptpilot_files = vec![];
+    for kernel in &kernel_artifacts {
+        cryptpilot_files.push(kernel.extract_cryptpilot_files().await);
+    }
+    let (config_check, metadata_check, root_hash, fde_config_bundle) =
+        check_cryptpilot_files(cryptpilot_files);
+    checks.push(config_check);
+    checks.push(metadata_check);
+
+    // The dm-verity hash device of the rootfs
+    let verity_check = match (&root_hash, &fde_config_bundle) {
+        (Some(root_hash), Some(fde_config_bundle)) => {
+            let rootfs_encrypted = fde_config_bundle
+                .fde
+                .as_ref()
+                .is_some_and(|fde| fde.rootfs.encrypt.is_some());
+            check_verity_with_volume_group(root_hash, rootfs_encrypted)
+                .await
+                .unwrap_or_else(|error| {
+                    SubCheck::new("verity", CheckStatus::Failed, format!("{error:#}"))
+                })
+        }
+        _ => SubCheck::new(
+            "verity",
+            CheckStatus::Skipped,
+            "No consistent root hash found in the metadata",
+        ),
+    };
+    checks.push(verity_check);
+
+    Ok(checks)
+}
+
+async fn extract_kernel_artifacts(fde_disk: &OnExternalFdeDisk) -> Result<Vec<KernelArtifacts>> {
+    match fde_disk.extract_boot_artifacts(false).await? {
+        BootArtifactsType::Grub(grub_boot_artifacts) => {
+            grub_boot_artifacts.extract_kernel_artifacts().await
+        }
+        BootArtifactsType::Uki(uki_boot_artifacts) => {
+            uki_boot_artifacts.extract_kernel_artifacts().await
+        }
+    }
+}
+
+/// Check that every initrd carries the same FDE config bundle and metadata. Returns the config and
+/// metadata checks, with the root hash and the config bundle if they are consistent.
+fn check_cryptpilot_files(
+    cryptpilot_files: Vec<Result<(FdeConfigBundle, Metadata)>>,
+) -> (SubCheck, SubCheck, Option<String>, Option<FdeConfigBundle>) {
+    if cryptpilot_files.is_empty() {
+        return (
+            SubCheck::new("config", CheckStatus::Skipped, "No initrd to check"),
+            SubCheck::new("metadata", CheckStatus::Skipped, "No initrd to check"),
+            None,
+            None,
+        );
+    }
+
+    let mut errors = vec![];
+    let mut config_hashes = vec![];
+    let mut root_hashes = vec![];
+    let mut first_bundle = None;
+    for (index, result) in cryptpilot_files.into_iter().enumerate() {
+        let (fde_config_bundle, metadata) = match result {
+            Ok(files) => files,
+            Err(error) => {
+                errors.push(format!("initrd #{index}: {error:#}"));
+                continue;
+            }
+        };
+        match fde_config_bundle.gen_hash_hex() {
+            Ok(hash) => config_hashes.push(hash),
+            Err(error) => errors.push(format!("initrd #{index}: {error:#}")),
+        }
+        root_hashes.push(metadata.root_hash);
+        first_bundle.get_or_insert(fde_config_bundle);
+    }
+
+    let config_check = match consistent_value(&config_hashes) {
+        _ if !errors.is_empty() => SubCheck::new(
+            "config",
+            CheckStatus::Failed,
+            format!(
+                "Failed to load the FDE config bundle: {}",
+                errors.join("; ")
+            ),
+        ),
+        Some(hash) => SubCheck::new(
+            "config",
+            CheckStatus::Passed,
+            format!("The sha384 hash of the FDE config bundle in the initrd is {hash}"),
+        ),
+        None => SubCheck::new(
+            "config",
+            CheckStatus::Failed,
+            format!(
+                "The initrds carry different FDE config bundles, with sha384 hashes: {}",
+                config_hashes.join(", ")
+            ),
+        ),
+    };
+
+    let root_hash = consistent_value(&root_hashes).filter(|_| errors.is_empty());
+    let metadata_check = match &root_hash {
+        _ if !errors.is_empty() => SubCheck::new(
+            "metadata",
+            CheckStatus::Failed,
+            format!("Failed to load the metadata: {}", errors.join("; ")),
+        ),
+        Some(root_hash) => SubCheck::new(
+            "metadata",
+            CheckStatus::Passed,
+            format!("The root hash of the rootfs is {root_hash}"),
+        ),
+        None => SubCheck::new(
+            "metadata",
+            CheckStatus::Failed,
+            format!(
+                "The initrds carry different root hashes: {}",
+                root_hashes.join(", ")
+            ),
+        ),
+    };
+
+    (
+        config_check,
+        metadata_check,
+        root_hash.map(ToOwned::to_owned),
+        first_bundle.filter(|_| errors.is_empty()),
+    )
+}
+
+/// The value if all the values are the same, or `None` if they differ or there is no value.
+fn consistent_value(values: &[String]) -> Option<&str> {
+    let first = values.first()?;
+    values
+        .iter()
+        .all(|value| value == first)
+        .then_some(first.as_str())
+}
+
+async fn check_verity_with_volume_group(
+    root_hash: &str,
+    rootfs_encrypted: bool,
+) -> Result<SubCheck> {
+    let volume_group = ActiveVolumeGroup::activate().await?;
+    let result = check_verity(root_hash, rootfs_encrypted).await;
+    volume_group.deactivate().await;
+    result
+}
+
+async fn check_verity(root_hash: &str, rootfs_encrypted: bool) -> Result<SubCheck> {
+    Command::new("veritysetup")
+        .arg("dump")
+        .arg(ROOTFS_HASH_LOGICAL_VOLUME)
+        .run()
+        .await
+        .with_context(|| {
+            format!("No valid dm-verity hash device found at {ROOTFS_HASH_LOGICAL_VOLUME}")
+        })?;
+
+    if rootfs_encrypted {
+        // The hash tree is built on the decrypted rootfs, which cannot be read without the key
+        return Ok(SubCheck::new(
+            "verity",
+            CheckStatus::Skipped,
+            format!("The hash device {ROOTFS_HASH_LOGICAL_VOLUME} is valid, but the root hash is not verified since the rootfs is encrypted"),
+        ));
+    }
+
+    Ok(
+        match Command::new("veritysetup")
+            .arg("verify")
+            .arg(ROOTFS_LOGICAL_VOLUME)
+            .arg(ROOTFS_HASH_LOGICAL_VOLUME)
+            .arg(root_hash)
+            .run()
+            .await
+        {
+            Ok(_) => SubCheck::new(
+                "verity",
+                CheckStatus::Passed,
+                format!("The root hash in the metadata matches the hash device {ROOTFS_HASH_LOGICAL_VOLUME}"),
+            ),
+            Err(error) => SubCheck::new(
+                "verity",
+                CheckStatus::Failed,
+                format!("The root hash in the metadata does not match the hash device {ROOTFS_HASH_LOGICAL_VOLUME}: {error:#}"),
+            ),
+        },
+    )
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    fn cryptpilot_files(config: &str, root_hash: &str) -> Result<(FdeConfigBundle, Metadata)> {
+        Ok((
+            toml::from_str(config)?,
+            Metadata {
+                r#type: 1,
+                root_hash: root_hash.to_owned(),
+                config_signature: None,
+            },
+        ))
+    }
+
+    #[test]
+    fn test_check_cryptpilot_files() -> Result<()> {
+        let config = r#"
+            [fde.rootfs]
+            delta_location = "disk"
+
+            [fde.delta]
+            integrity = true
+
+            [fde.delta.encrypt.exec]
+            command = "echo"
+            args = ["-n", "AAAaaawewe222"]
+            "#;
+
+        let (config_check, metadata_check, root_hash, bundle) = check_cryptpilot_files(vec![
+            cryptpilot_files(config, "aaaa"),
+            cryptpilot_files(config, "aaaa"),
+        ]);
+        assert_eq!(config_check.status, CheckStatus::Passed);
+        assert_eq!(metadata_check.status, CheckStatus::Passed);
+        assert_eq!(root_hash.as_deref(), Some("aaaa"));
+        assert!(bundle.is_some());
+
+        // The initrds are built from different images
+        let (config_check, metadata_check, root_hash, _) = check_cryptpilot_files(vec![
+            cryptpilot_files(config, "aaaa"),
+            cryptpilot_files(config, "bbbb"),
+        ]);
+        assert_eq!(config_check.status, CheckStatus::Passed);
+        assert_eq!(metadata_check.status, CheckStatus::Failed);
+        assert_eq!(root_hash, None);
+
+        let (config_check, metadata_check, root_hash, bundle) = check_cryptpilot_files(vec![
+            cryptpilot_files(config, "aaaa"),
+            Err(anyhow::anyhow!("no metadata in initrd")),
+        ]);
+        assert_eq!(config_check.status, CheckStatus::Failed);
+        assert_eq!(metadata_check.status, CheckStatus::Failed);
+        assert_eq!(root_hash, None);
+        assert!(bundle.is_none());
+
+        let (config_check, _, _, _) = check_cryptpilot_files(vec![]);
+        assert_eq!(config_check.status, CheckStatus::Skipped);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_boot_chain_report() -> Result<()> {
+        let report = BootChainReport::new(vec![
+            SubCheck::new(
+                "boot-artifacts",
+                CheckStatus::Passed,
+                "Found 1 kernel(s) to boot",
+            ),
+            SubCheck::new("verity", CheckStatus::Skipped, "the rootfs is encrypted"),
+        ]);
+        assert!(report.passed);
+        assert_eq!(
+            serde_json::to_value(&report)?["checks"][1]["status"],
+            "skipped"
+        );
+
+        let report = BootChainReport::new(vec![SubCheck::new(
+            "config",
+            CheckStatus::Failed,
+            "No config",
+        )]);
+        assert!(!report.passed);
+
+        Ok(())
+    }
+}
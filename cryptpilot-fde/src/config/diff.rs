@@ -0,0 +1,190 @@
+use std::fmt::Display;
+
+use anyhow::Result;
+
+use super::FdeConfigBundle;
+
+/// A single difference between an expected and an actual [`FdeConfigBundle`].
+#[derive(Debug, PartialEq, Clone)]
+pub enum ConfigDiffEntry {
+    /// The key exists in the expected bundle but not in the actual one.
+    Missing { key: String, expected: toml::Value },
+    /// The key exists in the actual bundle but not in the expected one.
+    Unexpected { key: String, actual: toml::Value },
+    /// The key exists in both bundles but with different values.
+    Changed {
+        key: String,
+        expected: toml::Value,
+        actual: toml::Value,
+    },
+}
+
+impl Display for ConfigDiffEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigDiffEntry::Missing { key, expected } => write!(f, "- {key} = {expected}"),
+            ConfigDiffEntry::Unexpected { key, actual } => write!(f, "+ {key} = {actual}"),
+            ConfigDiffEntry::Changed {
+                key,
+                expected,
+                actual,
+            } => write!(f, "~ {key}: expected {expected}, found {actual}"),
+        }
+    }
+}
+
+impl FdeConfigBundle {
+    /// Compare this bundle (the actual one) with an expected bundle, returning all differences
+    /// keyed by their dotted TOML path. An empty result means the two bundles are equivalent.
+    pub fn diff(&self, expected: &FdeConfigBundle) -> Result<Vec<ConfigDiffEntry>> {
+        let expected = toml::Value::try_from(expected)?;
+        let actual = toml::Value::try_from(self)?;
+
+        let mut entries = vec![];
+        diff_value("", &expected, &actual, &mut entries);
+        Ok(entries)
+    }
+}
+
+fn diff_value(
+    key: &str,
+    expected: &toml::Value,
+    actual: &toml::Value,
+    entries: &mut Vec<ConfigDiffEntry>,
+) {
+    match (expected, actual) {
+        (toml::Value::Table(expected), toml::Value::Table(actual)) => {
+            for (sub_key, expected_value) in expected {
+                let full_key = join_key(key, sub_key);
+                match actual.get(sub_key) {
+                    Some(actual_value) => {
+                        diff_value(&full_key, expected_value, actual_value, entries)
+                    }
+                    None => entries.push(ConfigDiffEntry::Missing {
+                        key: full_key,
+                        expected: expected_value.clone(),
+                    }),
+                }
+            }
+            for (sub_key, actual_value) in actual {
+                if !expected.contains_key(sub_key) {
+                    entries.push(ConfigDiffEntry::Unexpected {
+                        key: join_key(key, sub_key),
+                        actual: actual_value.clone(),
+                    });
+                }
+            }
+        }
+        (expected, actual) => {
+            if expected != actual {
+                entries.push(ConfigDiffEntry::Changed {
+                    key: key.to_owned(),
+                    expected: expected.clone(),
+                    actual: actual.clone(),
+                });
+            }
+        }
+    }
+}
+
+fn join_key(prefix: &str, key: &str) -> String {
+    if prefix.is_empty() {
+        key.to_owned()
+    } else {
+        format!("{prefix}.{key}")
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+    use anyhow::Result;
+
+    fn bundle(content: &str) -> Result<FdeConfigBundle> {
+        Ok(toml::from_str(content)?)
+    }
+
+    #[test]
+    fn test_diff_identical() -> Result<()> {
+        let content = r#"
+[global.boot]
+verbose = true
+
+[fde.rootfs]
+delta_location = "disk"
+
+[fde.rootfs.encrypt.exec]
+command = "echo"
+args = ["-n", "123456"]
+
+[fde.delta]
+integrity = true
+
+[fde.delta.encrypt.exec]
+command = "echo"
+args = ["-n", "123456"]
+"#;
+
+        let expected = bundle(content)?;
+        let actual = bundle(content)?;
+        assert_eq!(actual.diff(&expected)?, vec![]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_divergent() -> Result<()> {
+        let expected = bundle(
+            r#"
+[global.boot]
+verbose = true
+
+[fde.rootfs]
+delta_location = "disk"
+
+[fde.delta]
+integrity = true
+
+[fde.delta.encrypt.exec]
+command = "echo"
+args = ["-n", "123456"]
+"#,
+        )?;
+        let actual = bundle(
+            r#"
+[fde.rootfs]
+delta_location = "ram"
+
+[fde.delta]
+integrity = true
+
+[fde.delta.encrypt.exec]
+command = "echo"
+args = ["-n", "654321"]
+"#,
+        )?;
+
+        assert_eq!(
+            actual.diff(&expected)?,
+            vec![
+                ConfigDiffEntry::Changed {
+                    key: "fde.delta.encrypt.exec.args".into(),
+                    expected: toml::Value::Array(vec!["-n".into(), "123456".into()]),
+                    actual: toml::Value::Array(vec!["-n".into(), "654321".into()]),
+                },
+                ConfigDiffEntry::Changed {
+                    key: "fde.rootfs.delta_location".into(),
+                    expected: "disk".into(),
+                    actual: "ram".into(),
+                },
+                ConfigDiffEntry::Missing {
+                    key: "global".into(),
+                    expected: toml::from_str("boot = { verbose = true }")?,
+                },
+            ]
+        );
+
+        Ok(())
+    }
+}
@@ -134,9 +134,15 @@ XXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXX
 "#
                                     .into()
                                 ),
+                                kbs_root_cert_path: None,
+                                kbs_native_root_store: None,
                             },
                             key_uri: "kbs:///default/test/rootfs_partition".into(),
-                        })
+                            max_retries: None,
+                            retry_interval_secs: None,
+                        }),
+                        kdf: None,
+                        cipher: None,
                     })
                 },
                 delta: DeltaConfig {
@@ -153,9 +159,15 @@ XXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXX
 "#
                                     .into()
                                 ),
+                                kbs_root_cert_path: None,
+                                kbs_native_root_store: None,
                             },
                             key_uri: "kbs:///default/test/data_partition".into(),
-                        })
+                            max_retries: None,
+                            retry_interval_secs: None,
+                        }),
+                        kdf: None,
+                        cipher: None,
                     }
                 }
             }
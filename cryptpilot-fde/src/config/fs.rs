@@ -1,5 +1,6 @@
 use anyhow::{Context as _, Result};
 use async_trait::async_trait;
+use serde::de::DeserializeOwned;
 use std::path::PathBuf;
 
 use crate::config::{FdeConfig, GlobalConfig};
@@ -9,13 +10,20 @@ use super::{FdeConfigBundle, FdeConfigSource};
 pub const CRYPTPILOT_CONFIG_DIR_DEFAULT: &str = "/etc/cryptpilot";
 
 pub struct FileSystemConfigSource {
-    config_dir: PathBuf,
+    /// The config directories, in increasing order of precedence.
+    config_dirs: Vec<PathBuf>,
 }
 
 impl FileSystemConfigSource {
     pub fn new(config_dir: impl Into<PathBuf>) -> Self {
+        Self::new_with_config_dirs([config_dir])
+    }
+
+    /// Create a config source reading from multiple config directories. A `global.toml` or
+    /// `fde.toml` in a later directory replaces the whole file found in an earlier directory.
+    pub fn new_with_config_dirs(config_dirs: impl IntoIterator<Item = impl Into<PathBuf>>) -> Self {
         Self {
-            config_dir: config_dir.into(),
+            config_dirs: config_dirs.into_iter().map(Into::into).collect(),
         }
     }
 
@@ -23,44 +31,45 @@ impl FileSystemConfigSource {
         Self::new(PathBuf::from(CRYPTPILOT_CONFIG_DIR_DEFAULT))
     }
 
-    async fn load_global_config(&self) -> Result<Option<GlobalConfig>> {
-        let config_path = self.config_dir.join("global.toml");
+    /// Find the config file with the highest precedence among all the config directories.
+    fn find_config_file(&self, file_name: &str) -> Option<PathBuf> {
+        self.config_dirs
+            .iter()
+            .rev()
+            .map(|config_dir| config_dir.join(file_name))
+            .find(|config_path| config_path.exists())
+    }
 
-        tracing::debug!("Loading global config from: {config_path:?}");
-        if !config_path.exists() {
-            tracing::debug!("global config not found, skip: {config_path:?}");
+    async fn load_config_file<T: DeserializeOwned>(
+        &self,
+        file_name: &str,
+        config_name: &str,
+    ) -> Result<Option<T>> {
+        let Some(config_path) = self.find_config_file(file_name) else {
+            tracing::debug!("{config_name} config not found, skip: {file_name}");
             return Ok(None);
-        }
+        };
+        tracing::debug!("Loading {config_name} config from: {config_path:?}");
 
-        let global_config = tokio::fs::read_to_string(&config_path)
+        let config = tokio::fs::read_to_string(&config_path)
             .await
             .map_err(anyhow::Error::from)
             .and_then(|content| {
-                toml::from_str::<GlobalConfig>(&content).context("Failed to parse content as TOML")
+                toml::from_str::<T>(&content).context("Failed to parse content as TOML")
             })
-            .with_context(|| format!("Failed to load global config from: {config_path:?}"))?;
+            .with_context(|| {
+                format!("Failed to load {config_name} config from: {config_path:?}")
+            })?;
 
-        Ok(Some(global_config))
+        Ok(Some(config))
     }
 
-    async fn load_fde_config(&self) -> Result<Option<FdeConfig>> {
-        let config_path = self.config_dir.join("fde.toml");
-
-        if !config_path.exists() {
-            tracing::debug!("FDE config not found, skip: {config_path:?}");
-            return Ok(None);
-        }
-        tracing::debug!("Loading FDE config from: {config_path:?}");
-
-        let fde_config = tokio::fs::read_to_string(&config_path)
-            .await
-            .map_err(anyhow::Error::from)
-            .and_then(|content| {
-                toml::from_str::<FdeConfig>(&content).context("Failed to parse content as TOML")
-            })
-            .with_context(|| format!("Failed to load FDE config from: {config_path:?}"))?;
+    async fn load_global_config(&self) -> Result<Option<GlobalConfig>> {
+        self.load_config_file("global.toml", "global").await
+    }
 
-        Ok(Some(fde_config))
+    async fn load_fde_config(&self) -> Result<Option<FdeConfig>> {
+        self.load_config_file("fde.toml", "FDE").await
     }
 }
 
@@ -69,8 +78,8 @@ impl FdeConfigSource for FileSystemConfigSource {
     fn source_debug_string(&self) -> String {
         format!(
             "filesystem: global at {:?}, fde at {:?}",
-            self.config_dir.join("global.toml"),
-            self.config_dir.join("fde.toml")
+            self.find_config_file("global.toml"),
+            self.find_config_file("fde.toml")
         )
     }
 
@@ -80,3 +89,71 @@ impl FdeConfigSource for FileSystemConfigSource {
         Ok(FdeConfigBundle { global, fde })
     }
 }
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn test_load_config_with_precedence() -> Result<()> {
+        let vendor_dir = tempfile::tempdir()?;
+        let operator_dir = tempfile::tempdir()?;
+
+        tokio::fs::write(
+            vendor_dir.path().join("global.toml"),
+            r#"
+[boot]
+verbose = true
+"#,
+        )
+        .await?;
+        tokio::fs::write(
+            vendor_dir.path().join("fde.toml"),
+            r#"
+[rootfs]
+delta_location = "disk"
+
+[rootfs.encrypt.exec]
+command = "echo"
+args = ["-n", "vendor"]
+
+[delta.encrypt.exec]
+command = "echo"
+args = ["-n", "vendor"]
+"#,
+        )
+        .await?;
+        tokio::fs::write(
+            operator_dir.path().join("fde.toml"),
+            r#"
+[rootfs]
+delta_location = "ram"
+
+[rootfs.encrypt.exec]
+command = "echo"
+args = ["-n", "operator"]
+
+[delta.encrypt.exec]
+command = "echo"
+args = ["-n", "operator"]
+"#,
+        )
+        .await?;
+
+        let source =
+            FileSystemConfigSource::new_with_config_dirs([vendor_dir.path(), operator_dir.path()]);
+        let bundle = source.get_fde_config_bundle().await?;
+        // The global config only exists in the vendor dir, so it is inherited.
+        assert!(bundle.global.and_then(|global| global.boot).is_some());
+        // The FDE config in the operator dir replaces the one in the vendor dir.
+        let expected: FdeConfig = toml::from_str(
+            &tokio::fs::read_to_string(operator_dir.path().join("fde.toml")).await?,
+        )?;
+        assert_eq!(bundle.fde, Some(expected));
+
+        Ok(())
+    }
+}
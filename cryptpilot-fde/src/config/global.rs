@@ -1,3 +1,4 @@
+use cryptpilot::measure::MeasurementFailurePolicy;
 use documented::DocumentedFields;
 use serde::{Deserialize, Serialize};
 
@@ -7,6 +8,10 @@ pub struct GlobalConfig {
     /// Configuration related to cryptpilot boot service.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub boot: Option<BootServiceConfig>,
+
+    /// The behavior when extending the runtime measurement of the config loaded from an untrusted source (e.g. cloud-init) fails: "fail" refuses to boot, "warn" (the default) logs a warning, and "ignore" logs nothing. An unmeasured config from an untrusted source is never used, the config in the initrd is used instead. Only the value in the initrd takes effect.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub measurement_failure: Option<MeasurementFailurePolicy>,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone, DocumentedFields)]
@@ -15,6 +20,10 @@ pub struct BootServiceConfig {
     /// Enable this option if you want to see more log when running cryptpilot boot service in initrd stage and in system stage.
     #[serde(default = "Default::default")]
     pub verbose: bool,
+
+    /// Wait up to this many seconds in initrd stage for the network to be ready before fetching the keys of the FDE volumes, i.e. a default route exists and the endpoints of the key providers (the KBS in one-shot mode, or the URL of the http key provider) can be connected over TCP (optional). The boot goes on when it times out, leaving the rest to the retries of the key providers. Default: no wait.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wait_for_network_secs: Option<u64>,
 }
 
 #[cfg(test)]
@@ -29,7 +38,13 @@ mod tests {
         let raw = "";
 
         let config: GlobalConfig = toml::from_str(raw)?;
-        assert_eq!(config, GlobalConfig { boot: None });
+        assert_eq!(
+            config,
+            GlobalConfig {
+                boot: None,
+                measurement_failure: None
+            }
+        );
 
         let raw = r#"
 [boot]
@@ -38,7 +53,11 @@ mod tests {
         assert_eq!(
             config,
             GlobalConfig {
-                boot: Some(BootServiceConfig { verbose: false }),
+                boot: Some(BootServiceConfig {
+                    verbose: false,
+                    wait_for_network_secs: None,
+                }),
+                measurement_failure: None,
             }
         );
 
@@ -50,7 +69,30 @@ verbose = false
         assert_eq!(
             config,
             GlobalConfig {
-                boot: Some(BootServiceConfig { verbose: false }),
+                boot: Some(BootServiceConfig {
+                    verbose: false,
+                    wait_for_network_secs: None,
+                }),
+                measurement_failure: None,
+            }
+        );
+
+        let raw = r#"
+measurement_failure = "fail"
+
+[boot]
+verbose = true
+wait_for_network_secs = 30
+        "#;
+        let config: GlobalConfig = toml::from_str(raw)?;
+        assert_eq!(
+            config,
+            GlobalConfig {
+                boot: Some(BootServiceConfig {
+                    verbose: true,
+                    wait_for_network_secs: Some(30),
+                }),
+                measurement_failure: Some(MeasurementFailurePolicy::Fail),
             }
         );
 
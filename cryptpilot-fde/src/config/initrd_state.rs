@@ -1,13 +1,15 @@
-use std::path::Path;
+use std::path::PathBuf;
 
 use anyhow::Result;
 use async_trait::async_trait;
 
-use crate::cmd::boot_service::initrd_state::{InitrdState, CRYPTPILOT_INITRD_STATE_PATH};
+use crate::cmd::boot_service::initrd_state::{initrd_state_dir, initrd_state_path, InitrdState};
 
 use super::{FdeConfigBundle, FdeConfigSource};
 
-pub struct InitrdStateConfigSource {}
+pub struct InitrdStateConfigSource {
+    state_dir: PathBuf,
+}
 
 impl Default for InitrdStateConfigSource {
     fn default() -> Self {
@@ -16,22 +18,101 @@ impl Default for InitrdStateConfigSource {
 }
 
 impl InitrdStateConfigSource {
+    /// Create a config source reading the initrd state from the default directory, which can be
+    /// overridden by the `CRYPTPILOT_INITRD_STATE_DIR` environment variable.
     pub fn new() -> Self {
-        Self {}
+        Self::new_with_state_dir(initrd_state_dir())
+    }
+
+    pub fn new_with_state_dir(state_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            state_dir: state_dir.into(),
+        }
     }
 
-    pub fn exist() -> bool {
-        Path::new(CRYPTPILOT_INITRD_STATE_PATH).exists()
+    pub fn exist(&self) -> bool {
+        initrd_state_path(&self.state_dir).exists()
     }
 }
 
 #[async_trait]
 impl FdeConfigSource for InitrdStateConfigSource {
     fn source_debug_string(&self) -> String {
-        format!("initrd state: {CRYPTPILOT_INITRD_STATE_PATH}")
+        format!("initrd state: {:?}", initrd_state_path(&self.state_dir))
     }
 
     async fn get_fde_config_bundle(&self) -> Result<FdeConfigBundle> {
-        Ok(InitrdState::load().await?.fde_config_bundle)
+        Ok(InitrdState::load(&self.state_dir).await?.fde_config_bundle)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+    use crate::cmd::boot_service::{
+        copy_config::copy_config_to_initrd_state_if_not_exist,
+        initrd_state::CRYPTPILOT_INITRD_STATE_DIR_ENV,
+    };
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn test_initrd_state_dir_from_env() -> Result<()> {
+        let state_dir = tempfile::tempdir()?;
+        std::env::set_var(CRYPTPILOT_INITRD_STATE_DIR_ENV, state_dir.path());
+        assert_eq!(initrd_state_dir(), state_dir.path());
+
+        let source = InitrdStateConfigSource::new();
+        assert!(!source.exist());
+
+        let fde_config_bundle: FdeConfigBundle = toml::from_str(
+            r#"
+[global.boot]
+verbose = true
+
+[fde.rootfs]
+delta_location = "disk"
+
+[fde.rootfs.encrypt.exec]
+command = "echo"
+args = ["-n", "AAAaaawewe222"]
+
+[fde.delta]
+integrity = true
+
+[fde.delta.encrypt.exec]
+command = "echo"
+args = ["-n", "AAAaaawewe222"]"#,
+        )?;
+        InitrdState {
+            fde_config_bundle: fde_config_bundle.clone(),
+        }
+        .save(&initrd_state_dir())
+        .await?;
+
+        assert!(source.exist());
+        assert!(state_dir.path().join("initrd_state.toml").exists());
+        // The initrd state holds the key provider config, so it is only readable by the owner
+        assert_eq!(
+            std::os::unix::fs::PermissionsExt::mode(
+                &tokio::fs::metadata(state_dir.path().join("initrd_state.toml"))
+                    .await?
+                    .permissions()
+            ) & 0o777,
+            0o600
+        );
+        assert_eq!(source.get_fde_config_bundle().await?, fde_config_bundle);
+
+        // The existing initrd state is kept as is
+        copy_config_to_initrd_state_if_not_exist(state_dir.path(), true).await?;
+        assert_eq!(
+            InitrdStateConfigSource::new_with_state_dir(state_dir.path())
+                .get_fde_config_bundle()
+                .await?,
+            fde_config_bundle
+        );
+
+        std::env::remove_var(CRYPTPILOT_INITRD_STATE_DIR_ENV);
+        Ok(())
     }
 }
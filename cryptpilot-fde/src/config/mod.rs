@@ -1,9 +1,11 @@
 pub mod cached;
 pub mod cloud_init;
+pub mod diff;
 pub mod fde;
 pub mod fs;
 pub mod global;
 pub mod initrd_state;
+pub mod redact;
 
 use anyhow::Result;
 use async_trait::async_trait;
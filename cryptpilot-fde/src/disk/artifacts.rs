@@ -10,6 +10,7 @@ pub trait BootArtifacts {
         &self,
         map: &mut IndexMap<String, Vec<String>>,
         hash_key: &str,
+        best_effort: bool,
     ) -> Result<()>
     where
         T: digest::Digest + digest::Update;
@@ -2,11 +2,12 @@ use std::path::{Path, PathBuf};
 
 use anyhow::{bail, Context as _, Result};
 use async_trait::async_trait;
-use tokio::{fs::File, io::AsyncReadExt as _, process::Command};
+use tokio::process::Command;
 
 use crate::disk::{
-    findmnt_of_dir, grub::FdeDiskGrubExt, uki::UKI_FILE_PATH, Disk, FdeBootType, FdeDisk,
-    FdeDiskUkiExt,
+    findmnt_of_dir, grub::FdeDiskGrubExt, list_files_in_dir, os_release::read_os_release_in_root,
+    pinned_kernel::FdeDiskPinnedKernelExt, read_file_with_limit, uki::UKI_FILE_PATH, Disk,
+    FdeBootType, FdeDisk, FdeDiskUkiExt,
 };
 use cryptpilot::fs::cmd::CheckCommandOutput as _;
 
@@ -21,11 +22,35 @@ pub struct OnCurrentSystemFdeDisk {
 enum ExternalDiskType {
     NoFde { root_dev: PathBuf },
     Grub { boot_dev: PathBuf },
-    Uki,
+    Uki { uki_path: PathBuf },
 }
 
+/// The vendor GUID of the EFI variables set by systemd-boot and systemd-stub.
+const SYSTEMD_LOADER_EFI_VARIABLE_GUID: &str = "4a67b082-0a4c-41cf-b6c7-440b29bb8c4f";
+
+const EFIVARS_DIR: &str = "/sys/firmware/efi/efivars";
+
+/// The mount points where the EFI system partition may be mounted.
+const ESP_MOUNT_POINTS: [&str; 3] = ["/boot/efi", "/efi", "/boot"];
+
 impl OnCurrentSystemFdeDisk {
     pub async fn new() -> Result<Self> {
+        // Find the UKI which is actually booted, which may not be the BOOTX64.EFI
+        match find_booted_uki().await {
+            Ok(uki_path) => {
+                tracing::debug!(?uki_path, "Found the booted UKI on the EFI partition");
+                return Ok(Self {
+                    disk_type: ExternalDiskType::Uki { uki_path },
+                });
+            }
+            Err(error) => {
+                tracing::debug!(
+                    ?error,
+                    "Cannot find the booted UKI, fallback to search BOOTX64.EFI"
+                );
+            }
+        }
+
         // Find the BOOTX64.EFI in the EFI partition
         {
             let file = Path::new(UKI_FILE_PATH);
@@ -38,7 +63,9 @@ impl OnCurrentSystemFdeDisk {
                 {
                     Ok(()) => {
                         return Ok(Self {
-                            disk_type: ExternalDiskType::Uki,
+                            disk_type: ExternalDiskType::Uki {
+                                uki_path: file.to_owned(),
+                            },
                         });
                     }
                     Err(error) => {
@@ -72,6 +99,65 @@ impl OnCurrentSystemFdeDisk {
     }
 }
 
+/// Locate the booted UKI on the EFI partition, with the image path recorded in the EFI variables by
+/// systemd-stub (`StubImageIdentifier`) or systemd-boot (`LoaderImageIdentifier`). The latter is the
+/// path of systemd-boot itself if the UKI is loaded by systemd-boot, which is skipped since it is not
+/// a UKI.
+async fn find_booted_uki() -> Result<PathBuf> {
+    for name in ["StubImageIdentifier", "LoaderImageIdentifier"] {
+        let efi_variable =
+            Path::new(EFIVARS_DIR).join(format!("{name}-{SYSTEMD_LOADER_EFI_VARIABLE_GUID}"));
+        if !efi_variable.exists() {
+            continue;
+        }
+        let image_path = tokio::fs::read(&efi_variable)
+            .await
+            .map_err(Into::into)
+            .and_then(|content| parse_efi_image_identifier(&content))
+            .with_context(|| format!("Failed to read EFI variable {efi_variable:?}"))?;
+        tracing::debug!("The EFI variable {name} is {image_path:?}");
+
+        for esp in ESP_MOUNT_POINTS {
+            let uki_path = Path::new(esp).join(image_path.trim_start_matches('/'));
+            if !uki_path.exists() {
+                continue;
+            }
+            match tokio::fs::read(&uki_path)
+                .await
+                .map_err(Into::into)
+                .and_then(|bytes| crate::disk::uki::assume_uki_image(&bytes))
+            {
+                Ok(()) => return Ok(uki_path),
+                Err(error) => {
+                    tracing::debug!(?error, ?uki_path, "The image is not a valid UKI image")
+                }
+            }
+        }
+    }
+
+    bail!("No booted UKI image is found on the EFI partition")
+}
+
+/// Parse the image path from the content of an EFI variable holding a NUL-terminated UTF-16LE
+/// string, e.g. `\EFI\Linux\linux.efi`. The path is returned with `/` as separator.
+fn parse_efi_image_identifier(content: &[u8]) -> Result<String> {
+    // The first 4 bytes are the attributes of the EFI variable
+    let Some(data) = content.get(4..) else {
+        bail!("The EFI variable is too short");
+    };
+    let utf16 = data
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|&c| c != 0)
+        .collect::<Vec<_>>();
+    let image_path = String::from_utf16(&utf16).context("The image path is not valid UTF-16")?;
+    if image_path.is_empty() {
+        bail!("The image path is empty");
+    }
+
+    Ok(image_path.replace('\\', "/"))
+}
+
 #[async_trait]
 impl FdeDisk for OnCurrentSystemFdeDisk {
     fn fde_boot_type(&self) -> FdeBootType {
@@ -81,6 +167,11 @@ impl FdeDisk for OnCurrentSystemFdeDisk {
             ExternalDiskType::Uki { .. } => FdeBootType::Uki,
         }
     }
+
+    async fn read_os_release(&self, _rootfs_key_file: Option<&Path>) -> Result<Vec<u8>> {
+        // The rootfs of the running system is already decrypted and mounted
+        read_os_release_in_root(Path::new("/")).await
+    }
 }
 
 #[async_trait]
@@ -90,10 +181,11 @@ impl Disk for OnCurrentSystemFdeDisk {
     }
 
     async fn read_file_on_disk(&self, path: &Path) -> Result<Vec<u8>> {
-        let mut file = File::open(path).await?;
-        let mut buf = vec![];
-        file.read_to_end(&mut buf).await?;
-        Ok(buf)
+        read_file_with_limit(path).await
+    }
+
+    async fn list_files_on_disk(&self, dir: &Path) -> Result<Vec<String>> {
+        list_files_in_dir(dir).await
     }
 
     fn get_boot_dir_located_dev(&self) -> Result<&Path> {
@@ -122,4 +214,40 @@ impl FdeDiskGrubExt for OnCurrentSystemFdeDisk {
 }
 
 #[async_trait]
-impl FdeDiskUkiExt for OnCurrentSystemFdeDisk {}
+impl FdeDiskPinnedKernelExt for OnCurrentSystemFdeDisk {}
+
+#[async_trait]
+impl FdeDiskUkiExt for OnCurrentSystemFdeDisk {
+    fn uki_file_path(&self) -> PathBuf {
+        match &self.disk_type {
+            ExternalDiskType::Uki { uki_path } => uki_path.to_owned(),
+            _ => PathBuf::from(UKI_FILE_PATH),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn test_parse_efi_image_identifier() -> Result<()> {
+        let mut content = vec![0x06, 0x00, 0x00, 0x00];
+        content.extend(
+            "\\EFI\\Linux\\linux-6.6.efi\0"
+                .encode_utf16()
+                .flat_map(u16::to_le_bytes),
+        );
+        assert_eq!(
+            parse_efi_image_identifier(&content)?,
+            "/EFI/Linux/linux-6.6.efi"
+        );
+
+        assert!(parse_efi_image_identifier(&[0x06, 0x00]).is_err());
+        assert!(parse_efi_image_identifier(&[0x06, 0x00, 0x00, 0x00, 0x00, 0x00]).is_err());
+
+        Ok(())
+    }
+}
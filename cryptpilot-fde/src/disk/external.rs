@@ -1,24 +1,95 @@
-use std::path::{Path, PathBuf};
+use std::{
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
 
 use anyhow::{bail, Context as _, Result};
 use async_trait::async_trait;
 use block_devs::BlckExt;
 use tokio::{
     fs::{self, File},
-    io::AsyncReadExt as _,
     process::Command,
 };
 
 use crate::disk::{
-    findmnt_of_dir, grub::FdeDiskGrubExt, uki::UKI_FILE_PATH_IN_EFI_PART, Disk, FdeBootType,
-    FdeDisk, FdeDiskUkiExt,
+    findmnt_of_dir,
+    grub::FdeDiskGrubExt,
+    list_files_in_dir,
+    os_release::{read_os_release_in_root, read_os_release_of_fde_rootfs},
+    partition_dir::PartitionDir,
+    partition_role::PartitionRole,
+    pinned_kernel::FdeDiskPinnedKernelExt,
+    read_file_with_limit,
+    uki::UKI_FILE_PATH_IN_EFI_PART,
+    Disk, FdeBootType, FdeDisk, FdeDiskUkiExt,
+};
+use cryptpilot::fs::{
+    cmd::CheckCommandOutput as _, loop_device::PartitionedLoopDevice, mount::TmpMountPoint,
+    nbd::NbdDevice,
 };
-use cryptpilot::fs::{cmd::CheckCommandOutput as _, mount::TmpMountPoint, nbd::NbdDevice};
+
+static ATTACH_LOOP: AtomicBool = AtomicBool::new(false);
+
+/// Attach the disk image files to loop devices instead of NBD devices, e.g. on a host where NBD is
+/// unavailable. Only raw images can be attached to loop devices.
+pub fn set_attach_loop(attach_loop: bool) {
+    ATTACH_LOOP.store(attach_loop, Ordering::Relaxed);
+}
+
+/// The device to which a disk image file is attached, to expose its partitions. It is
+/// disconnected on drop.
+enum ImageDevice {
+    Nbd(NbdDevice),
+    Loop(PartitionedLoopDevice),
+}
+
+impl ImageDevice {
+    /// Attach the disk image to an NBD device, or to a loop device if requested with
+    /// [`set_attach_loop`] or if the nbd kernel module cannot be loaded.
+    async fn attach(disk_img: &Path, read_only: bool) -> Result<Self> {
+        if ATTACH_LOOP.load(Ordering::Relaxed) {
+            return Ok(Self::Loop(
+                PartitionedLoopDevice::attach(disk_img, read_only).await?,
+            ));
+        }
+
+        let result = if read_only {
+            NbdDevice::connect_read_only(disk_img).await
+        } else {
+            NbdDevice::connect(disk_img).await
+        };
+        match result {
+            Ok(nbd_device) => Ok(Self::Nbd(nbd_device)),
+            Err(error) if !NbdDevice::is_module_loaded() => {
+                tracing::warn!(
+                    ?error,
+                    "NBD is unavailable, attaching the disk image {disk_img:?} to a loop device instead"
+                );
+                Ok(Self::Loop(
+                    PartitionedLoopDevice::attach(disk_img, read_only)
+                        .await
+                        .with_context(|| {
+                            format!("Failed to fall back to a loop device since NBD is unavailable: {error:#}")
+                        })?,
+                ))
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    fn to_path(&self) -> PathBuf {
+        match self {
+            ImageDevice::Nbd(nbd_device) => nbd_device.to_path(),
+            ImageDevice::Loop(loop_device) => loop_device.to_path(),
+        }
+    }
+}
 
 /// Load the fde related config bundle from a disk device.
 pub struct OnExternalFdeDisk {
     #[allow(unused)]
-    nbd_device: Option<NbdDevice>,
+    image_device: Option<ImageDevice>,
+    disk_device: PathBuf,
     disk_type: ExternalDiskType,
 }
 
@@ -26,56 +97,95 @@ enum ExternalDiskType {
     NoFde {
         #[allow(unused)]
         efi_dev: PathBuf,
-        efi_dev_tmp_mount: TmpMountPoint,
+        efi_dir: PartitionDir,
         root_dev: PathBuf,
-        root_dev_tmp_mount: TmpMountPoint,
+        root_dir: PartitionDir,
     },
     Grub {
         boot_dev: PathBuf,
-        boot_dev_tmp_mount: TmpMountPoint,
+        boot_dir: PartitionDir,
         #[allow(unused)]
         efi_dev: PathBuf,
-        efi_dev_tmp_mount: TmpMountPoint,
+        efi_dir: PartitionDir,
     },
     Uki {
         #[allow(unused)]
         efi_dev: PathBuf,
-        efi_dev_tmp_mount: TmpMountPoint,
+        efi_dir: PartitionDir,
     },
 }
 
+/// How the partitions of the disk are accessed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AccessMode {
+    /// Mount the partitions read-only.
+    ReadOnly,
+    /// Mount the partitions writable.
+    Writable,
+    /// Never mount the partitions, but copy the files needed out of them.
+    NoMount,
+}
+
+impl AccessMode {
+    /// Get the files in `dir` of the file system on the partition.
+    async fn open_partition(&self, dev: &Path, dir: &Path) -> Result<PartitionDir> {
+        match self {
+            AccessMode::ReadOnly => PartitionDir::mount(dev, false).await,
+            AccessMode::Writable => PartitionDir::mount(dev, true).await,
+            AccessMode::NoMount => PartitionDir::extract(dev, dir).await,
+        }
+    }
+}
+
 impl OnExternalFdeDisk {
     pub async fn new_from_disk(disk: &Path) -> Result<Self> {
+        Self::new_from_disk_with_mode(disk, AccessMode::ReadOnly).await
+    }
+
+    /// Same as [`Self::new_from_disk`], but the partitions are mounted writable so that files in
+    /// `/boot` can be modified with [`Self::write_file_on_disk`].
+    pub async fn new_from_disk_writable(disk: &Path) -> Result<Self> {
+        Self::new_from_disk_with_mode(disk, AccessMode::Writable).await
+    }
+
+    /// Same as [`Self::new_from_disk`], but the partitions are never mounted, since mounting may
+    /// replay the journal of the file system even if read-only. Instead, the files in `/boot` are
+    /// copied out of the partitions to temporary directories, see [`PartitionDir::extract`], and
+    /// a disk image file is connected read-only.
+    pub async fn new_from_disk_no_mount(disk: &Path) -> Result<Self> {
+        Self::new_from_disk_with_mode(disk, AccessMode::NoMount).await
+    }
+
+    async fn new_from_disk_with_mode(disk: &Path, mode: AccessMode) -> Result<Self> {
         if !disk.exists() {
             bail!("File not exist: {disk:?}")
         }
 
         let real_block_device = File::open(&disk).await?.into_std().await.is_block_device();
 
-        let (nbd_device, disk_device) = if real_block_device {
+        let (image_device, disk_device) = if real_block_device {
             (None, disk.to_owned())
         } else {
             // Treat it as a disk image file
             tracing::debug!(
                 "The path {disk:?} is not a block device, treat it as a disk image file."
             );
-            let nbd_device = NbdDevice::connect(disk).await?;
-            let disk_device = nbd_device.to_path();
-            (Some(nbd_device), disk_device)
+            let image_device = ImageDevice::attach(disk, mode == AccessMode::NoMount).await?;
+            let disk_device = image_device.to_path();
+            (Some(image_device), disk_device)
         };
 
         // Find the EFI partition and mount it to a tmp mount point
-        let efi_dev = Self::detect_efi_part(&disk_device)
+        let mount_allowed = mode != AccessMode::NoMount;
+        let efi_dev = Self::detect_efi_part(&disk_device, mount_allowed)
             .await
             .context("Cannot found EFI partition on the disk.")?;
-        let efi_dev_tmp_mount = TmpMountPoint::mount(&efi_dev, false).await?;
+        let efi_dir = mode.open_partition(&efi_dev, Path::new("/")).await?;
 
         let disk_type = 'label: {
             // Find the BOOTX64.EFI in the EFI partition
             {
-                let file = efi_dev_tmp_mount
-                    .mount_point()
-                    .join(UKI_FILE_PATH_IN_EFI_PART);
+                let file = efi_dir.path().join(UKI_FILE_PATH_IN_EFI_PART);
                 if file.exists() {
                     tracing::debug!("Found BOOTX64.EFI in the EFI partition, checking...");
                     match tokio::fs::read(&file)
@@ -84,10 +194,7 @@ impl OnExternalFdeDisk {
                         .and_then(|bytes| crate::disk::uki::assume_uki_image(&bytes))
                     {
                         Ok(()) => {
-                            break 'label ExternalDiskType::Uki {
-                                efi_dev,
-                                efi_dev_tmp_mount,
-                            };
+                            break 'label ExternalDiskType::Uki { efi_dev, efi_dir };
                         }
                         Err(error) => {
                             tracing::debug!(?error, ?file, "This disk is not a UKI booted disk since BOOTX64.EFI is not a valid UKI image.");
@@ -97,15 +204,15 @@ impl OnExternalFdeDisk {
             }
 
             // Find the boot partition and mount it to a tmp mount point
-            match Self::detect_boot_part(&disk_device).await {
+            match Self::detect_boot_part(&disk_device, mount_allowed).await {
                 Ok(boot_dev) => {
-                    let boot_dev_tmp_mount = TmpMountPoint::mount(&boot_dev, false).await?;
+                    let boot_dir = mode.open_partition(&boot_dev, Path::new("/")).await?;
 
                     ExternalDiskType::Grub {
                         boot_dev,
-                        boot_dev_tmp_mount,
+                        boot_dir,
                         efi_dev,
-                        efi_dev_tmp_mount,
+                        efi_dir,
                     }
                 }
                 Err(error) => {
@@ -113,24 +220,56 @@ impl OnExternalFdeDisk {
                     let root_dev = Self::detect_root_part(Some(&disk_device))
                         .await
                         .context("Failed to detect root partition on the disk")?;
-                    let root_dev_tmp_mount = TmpMountPoint::mount(&root_dev, false).await?;
+                    // Only the files in /boot are accessed on the root partition
+                    let root_dir = mode.open_partition(&root_dev, Path::new("/boot")).await?;
 
                     ExternalDiskType::NoFde {
                         efi_dev,
-                        efi_dev_tmp_mount,
+                        efi_dir,
                         root_dev,
-                        root_dev_tmp_mount,
+                        root_dir,
                     }
                 }
             }
         };
 
         Ok(Self {
-            nbd_device,
+            image_device,
+            disk_device,
             disk_type,
         })
     }
 
+    /// The block device of the whole disk, which is the NBD (or loop) device if the disk is an image
+    /// file.
+    pub fn disk_device(&self) -> &Path {
+        &self.disk_device
+    }
+
+    /// Overwrite a file in `/boot` on the disk. The disk must be opened with
+    /// [`Self::new_from_disk_writable`].
+    pub(crate) async fn write_file_on_disk(&self, path: &Path, content: &[u8]) -> Result<()> {
+        let real_path = self.resolve_path_on_real_disk(path)?;
+        tokio::fs::write(&real_path, content)
+            .await
+            .with_context(|| format!("Failed to write {path:?} on disk"))?;
+        Ok(())
+    }
+
+    /// List the files in a directory in `/boot` on the disk.
+    pub(crate) async fn list_dir_on_disk(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        let real_path = self.resolve_path_on_real_disk(path)?;
+        let mut files = vec![];
+        let mut entries = fs::read_dir(&real_path)
+            .await
+            .with_context(|| format!("Failed to read dir {path:?} on disk"))?;
+        while let Some(entry) = entries.next_entry().await? {
+            files.push(path.join(entry.file_name()));
+        }
+        files.sort();
+        Ok(files)
+    }
+
     pub async fn detect_root_part(hint_device: Option<&Path>) -> Result<PathBuf> {
         if hint_device.is_none() && Command::new("mountpoint").arg("/").run().await.is_ok() {
             // 1. Execute 'findmnt -n -o SOURCE /' to return the device path where '/' is mounted
@@ -145,7 +284,15 @@ impl OnExternalFdeDisk {
             }
         }
 
-        // 2. Try GPT-style LABEL match
+        // 2. Try GPT partition type GUID match
+        if let Some(hint_device) = hint_device {
+            if let Some(device) = Self::detect_part_by_role(hint_device, PartitionRole::Root).await
+            {
+                return Ok(device);
+            }
+        }
+
+        // 3. Try GPT-style LABEL match
         let mut gpt_cmd = Command::new("blkid");
         gpt_cmd.args([
             "--match-types",
@@ -178,8 +325,15 @@ impl OnExternalFdeDisk {
         bail!("No boot partition found (GPT and MBR methods both failed)");
     }
 
-    pub async fn detect_boot_part(hint_device: &Path) -> Result<PathBuf> {
-        // 1. Try GPT-style PARTLABEL match
+    /// Detect the boot partition on the disk. The last resort of mounting each ext4 partition to
+    /// look for the kernel is skipped if `mount_allowed` is false.
+    pub async fn detect_boot_part(hint_device: &Path, mount_allowed: bool) -> Result<PathBuf> {
+        // 1. Try GPT partition type GUID match
+        if let Some(device) = Self::detect_part_by_role(hint_device, PartitionRole::Boot).await {
+            return Ok(device);
+        }
+
+        // 2. Try GPT-style PARTLABEL match
         let mut gpt_cmd = Command::new("blkid");
         gpt_cmd.args([
             "--match-types",
@@ -207,7 +361,11 @@ impl OnExternalFdeDisk {
             ),
         }
 
-        // 2. Try MBR-style fallback: search all ext4 partitions and check contents
+        if !mount_allowed {
+            bail!("No boot partition found by GPT partition type GUID or PARTLABEL, and mounting the partitions to search for it is not allowed");
+        }
+
+        // 3. Try MBR-style fallback: search all ext4 partitions and check contents
         let mut lsblk_cmd = Command::new("lsblk");
         lsblk_cmd.args(["-lnpo", "NAME,FSTYPE"]);
 
@@ -258,7 +416,36 @@ impl OnExternalFdeDisk {
         bail!("No boot partition found (GPT and MBR methods both failed)");
     }
 
-    async fn detect_efi_part(hint_device: &Path) -> Result<PathBuf> {
+    /// Find the partition with the role by its GPT partition type GUID, following the
+    /// Discoverable Partitions Specification. Returns `None` if it cannot be found this way, so
+    /// that the caller can fall back to the other methods.
+    async fn detect_part_by_role(hint_device: &Path, role: PartitionRole) -> Option<PathBuf> {
+        match role.find_partition(hint_device).await {
+            Ok(Some(device)) => {
+                tracing::debug!(
+                    "Detected {role:?} partition {device:?} by GPT partition type GUID"
+                );
+                Some(device)
+            }
+            Ok(None) => None,
+            Err(error) => {
+                tracing::debug!(
+                    ?error,
+                    "Failed to detect {role:?} partition by GPT partition type GUID"
+                );
+                None
+            }
+        }
+    }
+
+    async fn detect_efi_part(hint_device: &Path, mount_allowed: bool) -> Result<PathBuf> {
+        if let Some(device) = Self::detect_part_by_role(hint_device, PartitionRole::Esp).await {
+            return Ok(device);
+        }
+        if !mount_allowed {
+            bail!("No EFI partition found by GPT partition type GUID, and mounting the partitions to search for it is not allowed");
+        }
+
         // Obtain all partitions under the device
         let lsblk_stdout = {
             let mut cmd = Command::new("lsblk");
@@ -322,6 +509,21 @@ impl FdeDisk for OnExternalFdeDisk {
             ExternalDiskType::Uki { .. } => FdeBootType::Uki,
         }
     }
+
+    async fn read_os_release(&self, rootfs_key_file: Option<&Path>) -> Result<Vec<u8>> {
+        match &self.disk_type {
+            ExternalDiskType::NoFde { root_dir, .. } => {
+                if let PartitionDir::Extracted(_) = root_dir {
+                    bail!("Reading the os-release file requires mounting the root partition");
+                }
+                read_os_release_in_root(root_dir.path()).await
+            }
+            // The rootfs is a logical volume, which is not mounted to read the boot artifacts
+            ExternalDiskType::Grub { .. } | ExternalDiskType::Uki { .. } => {
+                read_os_release_of_fde_rootfs(rootfs_key_file).await
+            }
+        }
+    }
 }
 
 #[async_trait]
@@ -346,10 +548,11 @@ impl Disk for OnExternalFdeDisk {
             }
         };
 
-        let mut file = File::open(real_path).await?;
-        let mut buf = vec![];
-        file.read_to_end(&mut buf).await?;
-        Ok(buf)
+        read_file_with_limit(&real_path).await
+    }
+
+    async fn list_files_on_disk(&self, dir: &Path) -> Result<Vec<String>> {
+        list_files_in_dir(&self.resolve_path_on_real_disk(dir)?).await
     }
 
     fn get_boot_dir_located_dev(&self) -> Result<&Path> {
@@ -362,15 +565,9 @@ impl Disk for OnExternalFdeDisk {
 
     fn get_efi_part_root_dir(&self) -> &Path {
         match &self.disk_type {
-            ExternalDiskType::NoFde {
-                efi_dev_tmp_mount, ..
-            }
-            | ExternalDiskType::Grub {
-                efi_dev_tmp_mount, ..
-            }
-            | ExternalDiskType::Uki {
-                efi_dev_tmp_mount, ..
-            } => efi_dev_tmp_mount.mount_point(),
+            ExternalDiskType::NoFde { efi_dir, .. }
+            | ExternalDiskType::Grub { efi_dir, .. }
+            | ExternalDiskType::Uki { efi_dir, .. } => efi_dir.path(),
         }
     }
 }
@@ -383,44 +580,28 @@ impl OnExternalFdeDisk {
 
         let real_path = match &self.disk_type {
             ExternalDiskType::NoFde {
-                efi_dev_tmp_mount,
-                root_dev_tmp_mount,
-                ..
+                efi_dir, root_dir, ..
             } => {
                 if path.starts_with("/boot/efi") {
-                    efi_dev_tmp_mount
-                        .mount_point()
-                        .join(path.strip_prefix("/boot/efi")?)
+                    efi_dir.path().join(path.strip_prefix("/boot/efi")?)
                 } else if path.starts_with("/") {
-                    root_dev_tmp_mount
-                        .mount_point()
-                        .join(path.strip_prefix("/")?)
+                    root_dir.path().join(path.strip_prefix("/")?)
                 } else {
                     bail!("The path must be start with /, but got {path:?}")
                 }
             }
             ExternalDiskType::Grub {
-                boot_dev_tmp_mount,
-                efi_dev_tmp_mount,
-                ..
+                boot_dir, efi_dir, ..
             } => {
                 if path.starts_with("/boot/efi") {
-                    efi_dev_tmp_mount
-                        .mount_point()
-                        .join(path.strip_prefix("/boot/efi")?)
+                    efi_dir.path().join(path.strip_prefix("/boot/efi")?)
                 } else {
-                    boot_dev_tmp_mount
-                        .mount_point()
-                        .join(path.strip_prefix("/boot")?)
+                    boot_dir.path().join(path.strip_prefix("/boot")?)
                 }
             }
-            ExternalDiskType::Uki {
-                efi_dev_tmp_mount, ..
-            } => {
+            ExternalDiskType::Uki { efi_dir, .. } => {
                 if path.starts_with("/boot/efi") {
-                    efi_dev_tmp_mount
-                        .mount_point()
-                        .join(path.strip_prefix("/boot/efi")?)
+                    efi_dir.path().join(path.strip_prefix("/boot/efi")?)
                 } else {
                     bail!("Access files other than /boot/efi is not supported")
                 }
@@ -463,3 +644,6 @@ impl FdeDiskGrubExt for OnExternalFdeDisk {
 
 #[async_trait]
 impl FdeDiskUkiExt for OnExternalFdeDisk {}
+
+#[async_trait]
+impl FdeDiskPinnedKernelExt for OnExternalFdeDisk {}
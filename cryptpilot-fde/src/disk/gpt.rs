@@ -0,0 +1,319 @@
+use std::{io::SeekFrom, path::Path};
+
+use anyhow::{bail, Context as _, Result};
+use tokio::io::{AsyncReadExt as _, AsyncSeekExt as _};
+
+/// The signature of the GPT header, at the start of LBA 1.
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+
+/// The logical sector sizes to look for the GPT header with, since the header is at LBA 1.
+const GPT_SECTOR_SIZES: [u64; 2] = [512, 4096];
+
+/// The smallest size of the GPT header, defined by the UEFI specification.
+const GPT_HEADER_SIZE_MIN: usize = 92;
+
+/// The smallest size of a partition entry, which must be a multiple of it.
+const GPT_ENTRY_SIZE_MIN: usize = 128;
+
+/// Upper bound of the size of the partition entry array, to reject a corrupted header.
+const GPT_ENTRIES_SIZE_MAX: usize = 1024 * 1024;
+
+/// The partition name is stored as 36 UTF-16LE code units at offset 56 of the entry.
+const GPT_ENTRY_NAME_RANGE: std::ops::Range<usize> = 56..128;
+
+/// A GUID Partition Table read from a disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GptTable {
+    /// The logical sector size the table is laid out with.
+    pub sector_size: u64,
+    pub disk_guid: String,
+    /// The used partition entries, in the order of the partition entry array.
+    pub partitions: Vec<GptPartition>,
+}
+
+/// A used entry of the partition entry array.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GptPartition {
+    /// The partition number, i.e. the 1-based index of the entry in the partition entry array.
+    pub number: u32,
+    /// The partition type GUID, in lower case.
+    pub type_guid: String,
+    /// The unique partition GUID (PARTUUID), in lower case.
+    pub unique_guid: String,
+    /// The partition name (PARTLABEL).
+    pub name: String,
+    pub first_lba: u64,
+    /// The last LBA of the partition, inclusive.
+    pub last_lba: u64,
+}
+
+impl GptPartition {
+    /// The offset in bytes of the partition on the disk.
+    pub fn offset(&self, sector_size: u64) -> u64 {
+        self.first_lba * sector_size
+    }
+
+    /// The size in bytes of the partition.
+    pub fn size(&self, sector_size: u64) -> u64 {
+        (self.last_lba + 1 - self.first_lba) * sector_size
+    }
+}
+
+/// Format a GUID stored in the mixed-endian layout of GPT, e.g.
+/// "c12a7328-f81f-11d2-ba4b-00a0c93ec93b".
+fn format_guid(bytes: &[u8]) -> String {
+    format!(
+        "{:08x}-{:04x}-{:04x}-{}-{}",
+        u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+        u16::from_le_bytes([bytes[4], bytes[5]]),
+        u16::from_le_bytes([bytes[6], bytes[7]]),
+        hex::encode(&bytes[8..10]),
+        hex::encode(&bytes[10..16]),
+    )
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap_or_default())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap_or_default())
+}
+
+/// The fields of the GPT header which locate the partition entry array.
+#[derive(Debug)]
+struct GptHeader {
+    disk_guid: String,
+    entries_lba: u64,
+    entries_count: usize,
+    entry_size: usize,
+    entries_crc32: u32,
+}
+
+/// Parse and check the GPT header, which is at the start of `sector`. Returns `None` if the
+/// sector does not start with the GPT signature.
+fn parse_gpt_header(sector: &[u8]) -> Result<Option<GptHeader>> {
+    if !sector.starts_with(GPT_SIGNATURE) {
+        return Ok(None);
+    }
+
+    let header_size = read_u32(sector, 12) as usize;
+    if !(GPT_HEADER_SIZE_MIN..=sector.len()).contains(&header_size) {
+        bail!("Invalid GPT header size {header_size}");
+    }
+    // The CRC32 of the header is calculated with the CRC32 field zeroed
+    let mut header = sector[..header_size].to_vec();
+    header[16..20].fill(0);
+    let header_crc32 = read_u32(sector, 16);
+    if crc32fast::hash(&header) != header_crc32 {
+        bail!("The CRC32 of the GPT header does not match");
+    }
+
+    let entries_count = read_u32(sector, 80) as usize;
+    let entry_size = read_u32(sector, 84) as usize;
+    if entry_size < GPT_ENTRY_SIZE_MIN || entry_size % GPT_ENTRY_SIZE_MIN != 0 {
+        bail!("Invalid GPT partition entry size {entry_size}");
+    }
+    if entries_count * entry_size > GPT_ENTRIES_SIZE_MAX {
+        bail!("Too many GPT partition entries ({entries_count})");
+    }
+
+    Ok(Some(GptHeader {
+        disk_guid: format_guid(&sector[56..72]),
+        entries_lba: read_u64(sector, 72),
+        entries_count,
+        entry_size,
+        entries_crc32: read_u32(sector, 88),
+    }))
+}
+
+/// Parse the used entries of the partition entry array, i.e. the ones with a non-zero type GUID.
+fn parse_gpt_entries(entries: &[u8], header: &GptHeader) -> Result<Vec<GptPartition>> {
+    if crc32fast::hash(entries) != header.entries_crc32 {
+        bail!("The CRC32 of the GPT partition entry array does not match");
+    }
+
+    let mut partitions = vec![];
+    for (index, entry) in entries.chunks_exact(header.entry_size).enumerate() {
+        if entry[..16].iter().all(|&byte| byte == 0) {
+            continue;
+        }
+
+        let number = index as u32 + 1;
+        let (first_lba, last_lba) = (read_u64(entry, 32), read_u64(entry, 40));
+        if last_lba < first_lba {
+            bail!("Invalid range of GPT partition {number}: LBA {first_lba} to {last_lba}");
+        }
+        let name = entry[GPT_ENTRY_NAME_RANGE]
+            .chunks_exact(2)
+            .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+            .take_while(|&unit| unit != 0)
+            .collect::<Vec<_>>();
+
+        partitions.push(GptPartition {
+            number,
+            type_guid: format_guid(&entry[..16]),
+            unique_guid: format_guid(&entry[16..32]),
+            name: String::from_utf16_lossy(&name),
+            first_lba,
+            last_lba,
+        });
+    }
+    Ok(partitions)
+}
+
+/// Read the primary GUID Partition Table of the disk, which can be a file or a block device.
+/// Returns `None` if the disk has no GPT, e.g. if it is partitioned with MBR.
+pub async fn read_gpt(disk: &Path) -> Result<Option<GptTable>> {
+    async {
+        let mut file = tokio::fs::File::open(disk).await?;
+
+        for sector_size in GPT_SECTOR_SIZES {
+            let mut sector = vec![0u8; sector_size as usize];
+            file.seek(SeekFrom::Start(sector_size)).await?;
+            if file.read_exact(&mut sector).await.is_err() {
+                // The disk is too small
+                break;
+            }
+            let Some(header) = parse_gpt_header(&sector)? else {
+                continue;
+            };
+
+            let mut entries = vec![0u8; header.entries_count * header.entry_size];
+            file.seek(SeekFrom::Start(header.entries_lba * sector_size))
+                .await?;
+            file.read_exact(&mut entries)
+                .await
+                .context("Failed to read the GPT partition entry array")?;
+
+            return Ok(Some(GptTable {
+                sector_size,
+                partitions: parse_gpt_entries(&entries, &header)?,
+                disk_guid: header.disk_guid,
+            }));
+        }
+        Ok(None)
+    }
+    .await
+    .with_context(|| format!("Failed to read the GPT of {disk:?}"))
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    /// Encode a GUID in the mixed-endian layout of GPT.
+    fn encode_guid(guid: &str) -> Vec<u8> {
+        let bytes = hex::decode(guid.replace('-', "")).unwrap();
+        let mut encoded = bytes.clone();
+        encoded[..4].reverse();
+        encoded[4..6].reverse();
+        encoded[6..8].reverse();
+        encoded
+    }
+
+    /// Write a synthetic GPT with 512-byte sectors to the disk image, with a partition of each
+    /// (type GUID, name, size in MiB), laid out one after another from 1 MiB.
+    pub(crate) async fn write_synthetic_gpt(
+        disk: &Path,
+        partitions: &[(&str, &str, u64)],
+    ) -> Result<()> {
+        const SECTOR_SIZE: usize = 512;
+        const ENTRIES_COUNT: usize = 128;
+
+        let mut entries = vec![0u8; ENTRIES_COUNT * GPT_ENTRY_SIZE_MIN];
+        let mut next_lba = 2048u64;
+        for (index, (type_guid, name, size_mib)) in partitions.iter().enumerate() {
+            let entry = &mut entries[index * GPT_ENTRY_SIZE_MIN..][..GPT_ENTRY_SIZE_MIN];
+            entry[..16].copy_from_slice(&encode_guid(type_guid));
+            entry[16..32].copy_from_slice(&encode_guid(&format!(
+                "00000000-0000-4000-8000-{:012x}",
+                index + 1
+            )));
+            let last_lba = next_lba + size_mib * 2048 - 1;
+            entry[32..40].copy_from_slice(&next_lba.to_le_bytes());
+            entry[40..48].copy_from_slice(&last_lba.to_le_bytes());
+            for (i, unit) in name.encode_utf16().enumerate() {
+                entry[56 + i * 2..][..2].copy_from_slice(&unit.to_le_bytes());
+            }
+            next_lba = last_lba + 1;
+        }
+
+        let mut header = vec![0u8; SECTOR_SIZE];
+        header[..8].copy_from_slice(GPT_SIGNATURE);
+        header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+        header[12..16].copy_from_slice(&(GPT_HEADER_SIZE_MIN as u32).to_le_bytes());
+        header[24..32].copy_from_slice(&1u64.to_le_bytes());
+        header[40..48].copy_from_slice(&34u64.to_le_bytes());
+        header[48..56].copy_from_slice(&(next_lba - 1).to_le_bytes());
+        header[56..72].copy_from_slice(&encode_guid("11111111-2222-4333-8444-555555555555"));
+        header[72..80].copy_from_slice(&2u64.to_le_bytes());
+        header[80..84].copy_from_slice(&(ENTRIES_COUNT as u32).to_le_bytes());
+        header[84..88].copy_from_slice(&(GPT_ENTRY_SIZE_MIN as u32).to_le_bytes());
+        header[88..92].copy_from_slice(&crc32fast::hash(&entries).to_le_bytes());
+        let header_crc32 = crc32fast::hash(&header[..GPT_HEADER_SIZE_MIN]);
+        header[16..20].copy_from_slice(&header_crc32.to_le_bytes());
+
+        let mut image = vec![0u8; SECTOR_SIZE];
+        image.extend_from_slice(&header);
+        image.extend_from_slice(&entries);
+        image.resize(((next_lba + 34) as usize) * SECTOR_SIZE, 0);
+        tokio::fs::write(disk, image).await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_guid() {
+        let guid = "c12a7328-f81f-11d2-ba4b-00a0c93ec93b";
+        assert_eq!(format_guid(&encode_guid(guid)), guid);
+    }
+
+    #[tokio::test]
+    async fn test_read_gpt() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let disk = dir.path().join("disk.img");
+        write_synthetic_gpt(
+            &disk,
+            &[
+                ("c12a7328-f81f-11d2-ba4b-00a0c93ec93b", "EFI System", 2),
+                ("0fc63daf-8483-4772-8e79-3d69d8477de4", "boot", 4),
+            ],
+        )
+        .await?;
+
+        let gpt = read_gpt(&disk).await?.expect("the disk has a GPT");
+        assert_eq!(gpt.sector_size, 512);
+        assert_eq!(gpt.disk_guid, "11111111-2222-4333-8444-555555555555");
+        assert_eq!(gpt.partitions.len(), 2);
+        assert_eq!(
+            gpt.partitions[0],
+            GptPartition {
+                number: 1,
+                type_guid: "c12a7328-f81f-11d2-ba4b-00a0c93ec93b".to_owned(),
+                unique_guid: "00000000-0000-4000-8000-000000000001".to_owned(),
+                name: "EFI System".to_owned(),
+                first_lba: 2048,
+                last_lba: 2048 + 2 * 2048 - 1,
+            }
+        );
+        assert_eq!(gpt.partitions[0].offset(512), 1024 * 1024);
+        assert_eq!(gpt.partitions[0].size(512), 2 * 1024 * 1024);
+        assert_eq!(gpt.partitions[1].number, 2);
+        assert_eq!(gpt.partitions[1].name, "boot");
+        assert_eq!(gpt.partitions[1].size(512), 4 * 1024 * 1024);
+
+        // A corrupted partition entry array is detected
+        let mut image = tokio::fs::read(&disk).await?;
+        image[1024 + 56] ^= 0xff;
+        tokio::fs::write(&disk, &image).await?;
+        assert!(read_gpt(&disk).await.is_err());
+
+        // No GPT on a blank disk
+        tokio::fs::write(&disk, vec![0u8; 1024 * 1024]).await?;
+        assert_eq!(read_gpt(&disk).await?, None);
+
+        Ok(())
+    }
+}
@@ -9,12 +9,22 @@ use async_walkdir::WalkDir;
 use authenticode::PeTrait;
 use futures::StreamExt;
 use indexmap::IndexMap;
-use object::read::pe::{PeFile32, PeFile64};
+use object::{
+    read::pe::{PeFile32, PeFile64},
+    Object as _, ObjectSection as _,
+};
 use tokio::{fs::File, io::AsyncReadExt as _};
 
 use crate::disk::{artifacts::BootArtifacts, kernel::KernelArtifacts, Disk, PartitionTableType};
 
-/// Represents all GRUB-related artifacts found in the same directory as grubx64.efi.
+/// File names of the GRUB EFI binary on the supported architectures (x86_64, aarch64, riscv64).
+const GRUB_EFI_FILE_NAMES: &[&str] = &["grubx64.efi", "grubaa64.efi", "grubriscv64.efi"];
+
+/// File names of the shim EFI binary on the supported architectures (x86_64, aarch64, riscv64).
+const SHIM_EFI_FILE_NAMES: &[&str] =
+    &["shimx64.efi", "shimaa64.efi", "shimriscv64.efi", "shim.efi"];
+
+/// Represents all GRUB-related artifacts found in the same directory as the GRUB EFI binary (e.g. grubx64.efi or grubaa64.efi).
 /// This includes the GRUB binary, configuration files, associated shim binary, and environment data.
 /// These artifacts are typically used during the UEFI boot process to load the operating system kernel.
 #[derive(Debug)]
@@ -22,18 +32,26 @@ pub struct GrubArtifacts {
     /// The directory path containing the GRUB EFI binary (e.g., containing grubx64.efi).
     pub efi_grub_dir: PathBuf,
 
-    /// Raw byte content of the GRUB binary (usually grubx64.efi).
+    /// Path of the GRUB binary.
+    pub grub_path: PathBuf,
+
+    /// Raw byte content of the GRUB binary (usually grubx64.efi, or grubaa64.efi on aarch64).
     pub grub_data: Vec<u8>,
 
-    /// Raw byte content of the Shim binary (usually shimx64.efi), used for secure boot.
+    /// Path of the Shim binary.
+    pub shim_path: PathBuf,
+
+    /// Raw byte content of the Shim binary (usually shimx64.efi, or shimaa64.efi on aarch64), used for secure boot.
     pub shim_data: Vec<u8>,
 
     /// Optional contents of the GRUB environment block file (e.g., grubenv).
     /// This file may store persistent boot variables such as the current boot entry.
+    /// In the extracted boot artifacts, this is the grubenv used for booting, i.e. the one in the BOOT partition if any.
     pub grub_env: Option<String>,
 
     /// Optional contents of the main GRUB configuration file (grub.cfg).
     /// This file defines menu entries and kernel boot parameters.
+    /// In the extracted boot artifacts, this is the grub.cfg used for booting, i.e. the one in the BOOT partition if any.
     pub grub_cfg: Option<String>,
 }
 
@@ -45,12 +63,58 @@ pub struct GrubBootArtifactsItem {
 
 pub type GrubBootArtifacts = Vec<GrubBootArtifactsItem>;
 
+/// Normalize the line endings of the contents of grub.cfg or grubenv before hashing: each CRLF and
+/// each lone CR is converted to LF. GRUB reads the files the same way regardless of the line
+/// endings, so a file which was edited or copied on another platform still gets the same hash.
+pub fn normalize_line_endings(content: &str) -> String {
+    content.replace("\r\n", "\n").replace('\r', "\n")
+}
+
+/// Insert the hashes of the grub.cfg and grubenv contents used by each boot entry, as
+/// `measurement.grub_cfg.<hash-algo>` and `measurement.grubenv.<hash-algo>`. The line endings are
+/// normalized with [`normalize_line_endings`] before hashing.
+pub fn insert_grub_config_reference_value<T>(
+    artifacts: &GrubBootArtifacts,
+    map: &mut IndexMap<String, Vec<String>>,
+    hash_key: &str,
+) where
+    T: digest::Digest,
+{
+    let hash_contents = |contents: Vec<Option<&str>>| {
+        contents
+            .into_iter()
+            .flatten()
+            .map(|content| hex::encode(T::digest(normalize_line_endings(content))))
+            .collect::<Vec<_>>()
+    };
+
+    map.insert(
+        format!("measurement.grub_cfg.{hash_key}"),
+        hash_contents(
+            artifacts
+                .iter()
+                .map(|GrubBootArtifactsItem { grub, kernel: _ }| grub.grub_cfg.as_deref())
+                .collect(),
+        ),
+    );
+    map.insert(
+        format!("measurement.grubenv.{hash_key}"),
+        hash_contents(
+            artifacts
+                .iter()
+                .map(|GrubBootArtifactsItem { grub, kernel: _ }| grub.grub_env.as_deref())
+                .collect(),
+        ),
+    );
+}
+
 #[async_trait]
 impl BootArtifacts for GrubBootArtifacts {
     async fn inseart_reference_value<T>(
         &self,
         map: &mut IndexMap<String, Vec<String>>,
         hash_key: &str,
+        best_effort: bool,
     ) -> Result<()>
     where
         T: digest::Digest + digest::Update,
@@ -106,7 +170,12 @@ impl BootArtifacts for GrubBootArtifacts {
             format!("measurement.grub.{hash_key}"),
             self.iter()
                 .map(|GrubBootArtifactsItem { grub, kernel: _ }| {
-                    calculate_authenticode_hash::<T>(&grub.grub_data)
+                    authenticode_hash_or_placeholder::<T>(
+                        "GRUB",
+                        &grub.grub_path,
+                        &grub.grub_data,
+                        best_effort,
+                    )
                 })
                 .collect::<Result<Vec<_>>>()?,
         );
@@ -115,11 +184,42 @@ impl BootArtifacts for GrubBootArtifacts {
             format!("measurement.shim.{hash_key}"),
             self.iter()
                 .map(|GrubBootArtifactsItem { grub, kernel: _ }| {
-                    calculate_authenticode_hash::<T>(&grub.shim_data)
+                    authenticode_hash_or_placeholder::<T>(
+                        "shim",
+                        &grub.shim_path,
+                        &grub.shim_data,
+                        best_effort,
+                    )
                 })
                 .collect::<Result<Vec<_>>>()?,
         );
 
+        // The SBAT data of shim, which determines whether it is revoked by the SBAT policy
+        let sbat = self
+            .iter()
+            .filter_map(|GrubBootArtifactsItem { grub, kernel: _ }| {
+                match extract_sbat(&grub.shim_data) {
+                    Ok(Some(sbat)) => Some(sbat),
+                    Ok(None) => {
+                        tracing::debug!(file = ?grub.shim_path, "No .sbat section in the shim binary");
+                        None
+                    }
+                    Err(error) => {
+                        tracing::debug!(file = ?grub.shim_path, ?error, "Cannot read the .sbat section of the shim binary");
+                        None
+                    }
+                }
+            })
+            .map(|sbat| {
+                let mut hasher = T::new();
+                digest::Digest::update(&mut hasher, sbat);
+                hex::encode(hasher.finalize())
+            })
+            .collect::<Vec<_>>();
+        if !sbat.is_empty() {
+            map.insert(format!("measurement.sbat.{hash_key}"), sbat);
+        }
+
         Ok(())
     }
 
@@ -140,12 +240,110 @@ fn parse_pe(bytes: &[u8]) -> Result<Box<dyn PeTrait + '_>, object::read::Error>
     }
 }
 
-fn calculate_authenticode_hash<T: digest::Digest + digest::Update>(bytes: &[u8]) -> Result<String> {
-    let pe = parse_pe(bytes)?;
+/// Get the SBAT data (a CSV of the component generations) in the `.sbat` section of the EFI binary,
+/// without the trailing NUL padding. Returns `None` if there is no `.sbat` section.
+fn extract_sbat(bytes: &[u8]) -> Result<Option<&[u8]>> {
+    let pe = object::File::parse(bytes).context("Not a PE image")?;
+    let Some(section) = pe.section_by_name(".sbat") else {
+        return Ok(None);
+    };
+    let data = section.data()?;
+    let end = data
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(data.len());
+    Ok(Some(&data[..end]))
+}
+
+/// The reference value recorded in place of the authenticode hash of an EFI binary which is not
+/// supported, with `--best-effort`.
+const AUTHENTICODE_HASH_PLACEHOLDER: &str = "unsupported-not-a-pe-image";
+
+/// The outcome of calculating the authenticode hash of an EFI binary.
+#[derive(Debug)]
+enum AuthenticodeHash {
+    Hash(String),
+    /// The binary is not a standard PE image (e.g. it is wrapped or compressed), or its authenticode
+    /// hash cannot be calculated.
+    Unsupported(String),
+}
+
+fn calculate_authenticode_hash<T: digest::Digest + digest::Update>(
+    bytes: &[u8],
+) -> AuthenticodeHash {
+    let pe = match parse_pe(bytes) {
+        Ok(pe) => pe,
+        Err(error) => return AuthenticodeHash::Unsupported(format!("not a PE image: {error}")),
+    };
     let mut hasher = T::new();
-    authenticode::authenticode_digest(&*pe, &mut hasher)
-        .context("calculate_authenticode_hash failed")?;
-    Ok(hex::encode(hasher.finalize()))
+    if let Err(error) = authenticode::authenticode_digest(&*pe, &mut hasher) {
+        return AuthenticodeHash::Unsupported(format!(
+            "failed to calculate authenticode hash: {error}"
+        ));
+    }
+    AuthenticodeHash::Hash(hex::encode(hasher.finalize()))
+}
+
+/// Calculate the authenticode hash of the EFI binary. If it is not supported, a placeholder is
+/// returned with `best_effort`, otherwise it is an error.
+fn authenticode_hash_or_placeholder<T: digest::Digest + digest::Update>(
+    name: &str,
+    path: &Path,
+    bytes: &[u8],
+    best_effort: bool,
+) -> Result<String> {
+    match calculate_authenticode_hash::<T>(bytes) {
+        AuthenticodeHash::Hash(hash) => Ok(hash),
+        AuthenticodeHash::Unsupported(reason) if best_effort => {
+            tracing::warn!(
+                file = ?path,
+                "Cannot calculate authenticode hash of the {name} binary ({reason}), record \"{AUTHENTICODE_HASH_PLACEHOLDER}\" instead"
+            );
+            Ok(AUTHENTICODE_HASH_PLACEHOLDER.to_owned())
+        }
+        AuthenticodeHash::Unsupported(reason) => bail!(
+            "Cannot calculate authenticode hash of the {name} binary {path:?}: {reason}. Use `--best-effort` to skip it"
+        ),
+    }
+}
+
+/// The header of the GRUB environment block.
+const GRUB_ENV_BLOCK_HEADER: &str = "# GRUB Environment Block\n";
+
+/// Parse the variables in a GRUB environment block (grubenv), which is a 1024-byte block starting
+/// with the "# GRUB Environment Block" header, followed by `key=value` lines, and padded with `#`.
+/// In the values, backslashes and newlines are escaped with a backslash.
+fn parse_grub_env_block(grub_env: &str) -> Vec<(String, String)> {
+    let content = grub_env
+        .strip_prefix(GRUB_ENV_BLOCK_HEADER)
+        .unwrap_or(grub_env)
+        .trim_end_matches('#');
+
+    let mut vars = vec![];
+    let mut push_var = |line: &str| {
+        if line.starts_with('#') {
+            return;
+        }
+        if let Some((key, value)) = line.split_once('=') {
+            vars.push((key.to_string(), value.to_string()));
+        }
+    };
+
+    let mut line = String::new();
+    let mut chars = content.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => line.extend(chars.next()),
+            '\n' => {
+                push_var(&line);
+                line.clear();
+            }
+            c => line.push(c),
+        }
+    }
+    push_var(&line);
+
+    vars
 }
 
 pub async fn parse_grub_env_vars(
@@ -153,14 +351,9 @@ pub async fn parse_grub_env_vars(
     grub_cfg: &str,
 ) -> Result<HashMap<String, String>> {
     // Parse GRUB environment variables
-    let mut grub_vars = HashMap::new();
-    for line in grub_env.lines() {
-        if let Some(eq_pos) = line.find('=') {
-            let key = &line[..eq_pos];
-            let value = &line[eq_pos + 1..];
-            grub_vars.insert(key.to_string(), value.to_string());
-        }
-    }
+    let mut grub_vars = parse_grub_env_block(grub_env)
+        .into_iter()
+        .collect::<HashMap<_, _>>();
 
     // Set default empty values for tuned_* variables if not present
     if !grub_vars.contains_key("tuned_params") {
@@ -207,7 +400,11 @@ pub async fn parse_grub_env_vars(
 
 #[async_trait]
 pub(super) trait FdeDiskGrubExt: Disk {
-    async fn extract_boot_artifacts_grub(&self) -> Result<GrubBootArtifacts> {
+    /// Extract boot artifacts of all GRUB directories found on the disk.
+    ///
+    /// If `best_effort` is set, a boot entry whose kernel artifacts cannot be loaded is logged and
+    /// skipped instead of failing the whole extraction, as long as at least one entry succeeds.
+    async fn extract_boot_artifacts_grub(&self, best_effort: bool) -> Result<GrubBootArtifacts> {
         let mut artifacts: Vec<_> = vec![];
 
         tracing::debug!("Try to load grub.cfg file from BOOT partition");
@@ -236,11 +433,17 @@ pub(super) trait FdeDiskGrubExt: Disk {
 
         let grub_artifacts = self.load_grub_artifacts().await?;
 
-        for grub_artifact in grub_artifacts {
-            let Some(grub_env) = global_grub_env
-                .as_deref()
-                .or(grub_artifact.grub_env.as_deref())
-            else {
+        for mut grub_artifact in grub_artifacts {
+            // The files in the BOOT partition take precedence over the ones in the EFI partition,
+            // and are kept as the contents used for booting
+            if global_grub_env.is_some() {
+                grub_artifact.grub_env = global_grub_env.clone();
+            }
+            if global_grub_cfg.is_some() {
+                grub_artifact.grub_cfg = global_grub_cfg.clone();
+            }
+
+            let Some(grub_env) = grub_artifact.grub_env.as_deref() else {
                 tracing::warn!(
                     dir = ?grub_artifact.efi_grub_dir,
                     "No grubenv file found, skip this grub directory"
@@ -248,10 +451,7 @@ pub(super) trait FdeDiskGrubExt: Disk {
                 continue;
             };
 
-            let Some(grub_cfg) = global_grub_cfg
-                .as_deref()
-                .or(grub_artifact.grub_cfg.as_deref())
-            else {
+            let Some(grub_cfg) = grub_artifact.grub_cfg.as_deref() else {
                 tracing::warn!(
                     dir = ?grub_artifact.efi_grub_dir,
                     "No grub.cfg file found, skip this grub directory"
@@ -261,7 +461,18 @@ pub(super) trait FdeDiskGrubExt: Disk {
 
             let grub_vars = parse_grub_env_vars(grub_env, grub_cfg).await?;
 
-            let kernel_artifacts = self.load_kernel_artifacts(&grub_vars, grub_cfg).await?;
+            let kernel_artifacts = match self.load_kernel_artifacts(&grub_vars, grub_cfg).await {
+                Ok(v) => v,
+                Err(error) if best_effort => {
+                    tracing::warn!(
+                        dir = ?grub_artifact.efi_grub_dir,
+                        ?error,
+                        "Failed to load kernel artifacts, skip this grub directory"
+                    );
+                    continue;
+                }
+                Err(error) => return Err(error),
+            };
             artifacts.push(GrubBootArtifactsItem {
                 grub: grub_artifact,
                 kernel: kernel_artifacts,
@@ -279,7 +490,7 @@ pub(super) trait FdeDiskGrubExt: Disk {
 
     /// Read grub related artifacts
     ///
-    /// Find directories containing grubx64.efi, then read all required files from that same directory.
+    /// Find directories containing the GRUB EFI binary of any supported architecture (see [GRUB_EFI_FILE_NAMES]), then read all required files from that same directory.
     async fn load_grub_artifacts(&self) -> Result<Vec<GrubArtifacts>> {
         let efi_part_root_dir = self.get_efi_part_root_dir();
         let mut artifacts_list = vec![];
@@ -287,14 +498,14 @@ pub(super) trait FdeDiskGrubExt: Disk {
         let mut entries = WalkDir::new(efi_part_root_dir);
         let mut grub_dirs = HashSet::new();
 
-        // Step 1: Collect all directories containing 'grubx64.efi'
+        // Step 1: Collect all directories containing the GRUB EFI binary (e.g. 'grubx64.efi')
         while let Some(Ok(entry)) = entries.next().await {
             if entry.file_type().await.is_ok_and(|ft| ft.is_file()) {
                 let file_name = entry.file_name().to_string_lossy().to_lowercase();
-                if file_name == "grubx64.efi" {
+                if GRUB_EFI_FILE_NAMES.contains(&file_name.as_str()) {
                     let parent_dir = entry.path().parent().map(|p| p.to_path_buf());
                     if let Some(dir) = parent_dir {
-                        tracing::debug!(dir = ?dir, "Found grubx64.efi, will scan this directory");
+                        tracing::debug!(dir = ?dir, "Found {file_name}, will scan this directory");
                         grub_dirs.insert(dir);
                     }
                 }
@@ -302,7 +513,11 @@ pub(super) trait FdeDiskGrubExt: Disk {
         }
 
         if grub_dirs.is_empty() {
-            bail!("No grubx64.efi found under {}", efi_part_root_dir.display());
+            bail!(
+                "No GRUB EFI binary ({}) found under {}",
+                GRUB_EFI_FILE_NAMES.join(", "),
+                efi_part_root_dir.display()
+            );
         }
 
         // Step 2: For each such directory, try to read all required artifacts
@@ -326,17 +541,17 @@ pub(super) trait FdeDiskGrubExt: Disk {
                 };
 
                 match file_name.as_str() {
-                    "grubx64.efi" => {
-                        tracing::debug!(file = ?file_path, "Reading grubx64.efi");
+                    name if GRUB_EFI_FILE_NAMES.contains(&name) => {
+                        tracing::debug!(file = ?file_path, "Reading grub");
                         let mut buf = Vec::new();
-                        File::open(file_path).await?.read_to_end(&mut buf).await?;
-                        grub_data = Some(buf);
+                        File::open(&file_path).await?.read_to_end(&mut buf).await?;
+                        grub_data = Some((file_path, buf));
                     }
-                    "shimx64.efi" | "shim.efi" => {
+                    name if SHIM_EFI_FILE_NAMES.contains(&name) => {
                         tracing::debug!(file = ?file_path, "Reading grub shim");
                         let mut buf = Vec::new();
-                        File::open(file_path).await?.read_to_end(&mut buf).await?;
-                        shim_data = Some(buf);
+                        File::open(&file_path).await?.read_to_end(&mut buf).await?;
+                        shim_data = Some((file_path, buf));
                     }
                     "grubenv" => {
                         tracing::debug!(file = ?file_path, "Reading grubenv");
@@ -351,19 +566,21 @@ pub(super) trait FdeDiskGrubExt: Disk {
             }
 
             // Validate required binaries are present
-            let Some(grub_data) = grub_data else {
-                tracing::warn!(dir = ?dir, "Missing grubx64.efi in directory, skipping");
+            let Some((grub_path, grub_data)) = grub_data else {
+                tracing::warn!(dir = ?dir, "Missing GRUB EFI binary in directory, skipping");
                 continue;
             };
 
-            let Some(shim_data) = shim_data else {
-                tracing::warn!(dir = ?dir, "Missing shimx64.efi in directory, skipping");
+            let Some((shim_path, shim_data)) = shim_data else {
+                tracing::warn!(dir = ?dir, "Missing shim EFI binary in directory, skipping");
                 continue;
             };
 
             artifacts_list.push(GrubArtifacts {
                 efi_grub_dir: dir,
+                grub_path,
                 grub_data,
+                shim_path,
                 shim_data,
                 grub_env,
                 grub_cfg,
@@ -371,7 +588,9 @@ pub(super) trait FdeDiskGrubExt: Disk {
         }
 
         if artifacts_list.is_empty() {
-            bail!("Found grubx64.efi directories but failed to load complete artifacts from any");
+            bail!(
+                "Found GRUB EFI binary directories but failed to load complete artifacts from any"
+            );
         }
 
         Ok(artifacts_list)
@@ -434,54 +653,64 @@ pub(super) trait FdeDiskGrubExt: Disk {
             format!("{} {}", kernel_path_in_boot_dir, cmdline)
         };
 
-        // Construct a full kernel command line that includes an inferred device identifier prefix (e.g., "(hd0,gpt2)/vmlinuz-... root=...").
+        // Construct full kernel command lines that include an inferred device identifier prefix (e.g., "(hd0,gpt2)/vmlinuz-... root=...").
         // This format is used when GRUB does not rely on `--set=root` and instead embeds the full device path to locate the kernel.
-        let full_kernel_cmdline_with_device_identifier = {
-            // Infer device identifier from current boot partition device path
-            let device_identifier = {
-                // Detect partition table type
-                let partition_type = self.detect_disk_partition_type().await?;
-
-                // Extract partition number from device path
-                // For example, /dev/sda3 -> (hd0,gpt3) or (hd0,msdos3), /dev/nvme0n1p3 -> (hd0,gpt3) or (hd0,msdos3)
-                let boot_dir_dev = self.get_boot_dir_located_dev()?;
-                if let Ok(partition_num) = boot_dir_dev
-                    .to_string_lossy()
-                    .chars()
-                    .rev()
-                    .take_while(|c| c.is_ascii_digit())
-                    .collect::<String>()
-                    .chars()
-                    .rev()
-                    .collect::<String>()
-                    .parse::<u32>()
-                {
-                    match partition_type {
-                        PartitionTableType::Gpt => format!("(hd0,gpt{})", partition_num),
-                        PartitionTableType::Mbr => format!("(hd0,msdos{})", partition_num),
-                    }
-                } else {
-                    bail!(
-                        "Unable to extract partition number from device path: {:?}",
-                        boot_dir_dev
+        // On a system with multiple disks, the GRUB disk index may not be 0, so a command line is listed for each candidate index.
+        let partition_type = self.detect_disk_partition_type().await?;
+        let boot_dev = self.get_boot_dir_located_dev()?;
+        let disk_dev = self.get_disk_root_device(boot_dev)?;
+        let device_map = self.load_grub_device_map().await;
+        let full_kernel_cmdlines_with_device_identifier = |part_dev: &Path| -> Result<Vec<String>> {
+            grub_disk_indices(
+                grub_cfg,
+                device_map.as_deref(),
+                &disk_dev,
+                partition_number(part_dev)?,
+            )
+            .into_iter()
+            .map(|disk_index| {
+                // Combine device identifier with kernel path and command line arguments
+                Ok(format!(
+                    "{}{} {}",
+                    grub_device_identifier(partition_type, part_dev, disk_index)?,
+                    kernel_path.to_string_lossy(),
+                    cmdline
+                ))
+            })
+            .collect()
+        };
+
+        let mut kernel_cmdlines = vec![full_kernel_cmdline_shorter];
+        kernel_cmdlines.extend(full_kernel_cmdlines_with_device_identifier(boot_dev)?);
+
+        // When grub.cfg sets the root device with `search --fs-uuid --set=root <uuid>`, the effective
+        // root is the partition with that file system UUID, which may differ from the inferred one.
+        for fs_uuid in parse_search_fs_uuids(grub_cfg) {
+            let partition = match self.find_partition_by_fs_uuid(&fs_uuid).await {
+                Ok(Some(partition)) => partition,
+                Ok(None) => {
+                    tracing::debug!("No partition found with file system UUID {fs_uuid}, skip it");
+                    continue;
+                }
+                Err(error) => {
+                    tracing::debug!(
+                        ?error,
+                        "Failed to find partition with file system UUID {fs_uuid}, skip it"
                     );
+                    continue;
                 }
             };
-
-            // Combine device identifier with kernel path and command line arguments
-            format!(
-                "{}{} {}",
-                device_identifier,
-                kernel_path.to_string_lossy(),
-                cmdline
-            )
-        };
+            for full_kernel_cmdline_with_uuid_device_identifier in
+                full_kernel_cmdlines_with_device_identifier(&partition)?
+            {
+                if !kernel_cmdlines.contains(&full_kernel_cmdline_with_uuid_device_identifier) {
+                    kernel_cmdlines.push(full_kernel_cmdline_with_uuid_device_identifier);
+                }
+            }
+        }
 
         Ok(KernelArtifacts {
-            kernel_cmdlines: vec![
-                full_kernel_cmdline_shorter,
-                full_kernel_cmdline_with_device_identifier,
-            ],
+            kernel_cmdlines,
             kernel,
             initrd,
         })
@@ -665,4 +894,705 @@ pub(super) trait FdeDiskGrubExt: Disk {
 
         Ok(String::from_utf8(grub_cfg_content)?)
     }
+
+    /// Load the GRUB device map, which maps the GRUB disks to the devices, if it exists.
+    async fn load_grub_device_map(&self) -> Option<String> {
+        for device_map_path in ["/boot/grub2/device.map", "/boot/grub/device.map"] {
+            let device_map_path = Path::new(device_map_path);
+            if !matches!(self.check_file_exist_on_disk(device_map_path), Ok(true)) {
+                continue;
+            }
+            match self.read_file_on_disk_to_string(device_map_path).await {
+                Ok(device_map) => return Some(device_map),
+                Err(error) => {
+                    tracing::debug!(
+                        ?error,
+                        "Failed to read GRUB device map at {device_map_path:?}"
+                    )
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Get the partition number from the device path of a partition.
+/// For example, /dev/sda3 -> 3, /dev/nvme0n1p3 -> 3
+fn partition_number(part_dev: &Path) -> Result<u32> {
+    let Ok(partition_num) = part_dev
+        .to_string_lossy()
+        .chars()
+        .rev()
+        .take_while(|c| c.is_ascii_digit())
+        .collect::<String>()
+        .chars()
+        .rev()
+        .collect::<String>()
+        .parse::<u32>()
+    else {
+        bail!(
+            "Unable to extract partition number from device path: {:?}",
+            part_dev
+        );
+    };
+    Ok(partition_num)
+}
+
+/// Get the GRUB device identifier of a partition from its device path, on the GRUB disk with the index.
+/// For example, /dev/sda3 -> (hd0,gpt3) or (hd0,msdos3), /dev/nvme0n1p3 -> (hd0,gpt3) or (hd0,msdos3)
+fn grub_device_identifier(
+    partition_type: PartitionTableType,
+    part_dev: &Path,
+    disk_index: u32,
+) -> Result<String> {
+    let partition_num = partition_number(part_dev)?;
+
+    Ok(match partition_type {
+        PartitionTableType::Gpt => format!("(hd{disk_index},gpt{partition_num})"),
+        PartitionTableType::Mbr => format!("(hd{disk_index},msdos{partition_num})"),
+    })
+}
+
+/// Parse a GRUB partition reference like `hd1,gpt2` or `hd1,msdos2` into the disk index and the
+/// partition number.
+fn parse_grub_partition(token: &str) -> Option<(u32, u32)> {
+    let (disk, partition) = token.strip_prefix("hd")?.split_once(',')?;
+    let partition = partition
+        .strip_prefix("gpt")
+        .or_else(|| partition.strip_prefix("msdos"))?;
+    Some((disk.parse().ok()?, partition.parse().ok()?))
+}
+
+/// Get the GRUB disk indices (the `N` of `hdN`) which may refer to the disk containing the
+/// partition with the number, since the disk is not always `hd0` on a system with multiple disks.
+///
+/// The index of the disk in the GRUB device map (`(hdN) /dev/sdb`) is used if listed. Otherwise,
+/// the indices are taken from the partitions referenced in grub.cfg by `set root=` and the
+/// `--hint*=` options of `search`, preferring the ones with the same partition number. If none is
+/// found, `hd0` is assumed. Multiple candidates are returned if they cannot be told apart.
+fn grub_disk_indices(
+    grub_cfg: &str,
+    device_map: Option<&str>,
+    disk_dev: &Path,
+    partition_num: u32,
+) -> Vec<u32> {
+    let mapped = device_map
+        .into_iter()
+        .flat_map(str::lines)
+        .find_map(|line| {
+            let mut fields = line.split_whitespace();
+            let disk = fields.next()?.strip_prefix("(hd")?.strip_suffix(')')?;
+            (Path::new(fields.next()?) == disk_dev)
+                .then(|| disk.parse::<u32>().ok())
+                .flatten()
+        });
+    if let Some(disk_index) = mapped {
+        return vec![disk_index];
+    }
+
+    let referenced = grub_cfg
+        .lines()
+        .filter(|line| {
+            let line = line.trim_start();
+            line.starts_with("set root=") || line.contains("--hint")
+        })
+        .flat_map(|line| line.split(|c: char| !c.is_ascii_alphanumeric() && c != ','))
+        .filter_map(parse_grub_partition)
+        .collect::<Vec<_>>();
+
+    let mut disk_indices = referenced
+        .iter()
+        .filter(|(_, num)| *num == partition_num)
+        .map(|(disk_index, _)| *disk_index)
+        .collect::<Vec<_>>();
+    if disk_indices.is_empty() {
+        disk_indices = referenced
+            .iter()
+            .map(|(disk_index, _)| *disk_index)
+            .chain([0])
+            .collect();
+    }
+    disk_indices.sort();
+    disk_indices.dedup();
+    disk_indices
+}
+
+/// Get the file system UUIDs from the `search --fs-uuid --set=root <uuid>` directives in grub.cfg.
+fn parse_search_fs_uuids(grub_cfg: &str) -> Vec<String> {
+    let mut fs_uuids = vec![];
+    for line in grub_cfg.lines() {
+        let args = line.split_whitespace().collect::<Vec<_>>();
+        if args.first() != Some(&"search") {
+            continue;
+        }
+
+        let by_fs_uuid = args.iter().any(|arg| *arg == "--fs-uuid" || *arg == "-u");
+        let set_root = args.windows(2).any(|pair| {
+            pair[0] == "--set=root" || (matches!(pair[0], "--set" | "-s") && pair[1] == "root")
+        });
+        if !by_fs_uuid || !set_root {
+            continue;
+        }
+
+        let Some(fs_uuid) = args
+            .last()
+            .map(|arg| arg.trim_matches(|c| c == '"' || c == '\''))
+            .filter(|arg| !arg.starts_with('-') && *arg != "root")
+        else {
+            continue;
+        };
+        if !fs_uuids.iter().any(|uuid| uuid == fs_uuid) {
+            fs_uuids.push(fs_uuid.to_owned());
+        }
+    }
+    fs_uuids
+}
+
+#[cfg(test)]
+pub mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+    use anyhow::Result;
+
+    const TEST_ROOT_FS_UUID: &str = "2576d86b-4895-4922-b9d9-7c89dec6caa9";
+
+    /// A fake disk rooted at a temporary directory, with the EFI partition mounted at /boot/efi.
+    struct TestDisk {
+        root: tempfile::TempDir,
+        efi_part_root_dir: PathBuf,
+    }
+
+    impl TestDisk {
+        fn path_on_disk(&self, path: &Path) -> PathBuf {
+            self.root
+                .path()
+                .join(path.strip_prefix("/").unwrap_or(path))
+        }
+    }
+
+    #[async_trait]
+    impl Disk for TestDisk {
+        async fn detect_disk_partition_type(&self) -> Result<PartitionTableType> {
+            Ok(PartitionTableType::Gpt)
+        }
+
+        fn check_file_exist_on_disk(&self, path: &Path) -> Result<bool> {
+            Ok(self.path_on_disk(path).exists())
+        }
+
+        async fn read_file_on_disk(&self, path: &Path) -> Result<Vec<u8>> {
+            Ok(tokio::fs::read(self.path_on_disk(path)).await?)
+        }
+
+        async fn list_files_on_disk(&self, dir: &Path) -> Result<Vec<String>> {
+            crate::disk::list_files_in_dir(&self.path_on_disk(dir)).await
+        }
+
+        fn get_boot_dir_located_dev(&self) -> Result<&Path> {
+            Ok(Path::new("/dev/vda2"))
+        }
+
+        async fn find_partition_by_fs_uuid(&self, fs_uuid: &str) -> Result<Option<PathBuf>> {
+            Ok((fs_uuid == TEST_ROOT_FS_UUID).then(|| PathBuf::from("/dev/vda3")))
+        }
+
+        fn get_efi_part_root_dir(&self) -> &Path {
+            &self.efi_part_root_dir
+        }
+    }
+
+    #[async_trait]
+    impl FdeDiskGrubExt for TestDisk {
+        async fn load_global_grub_env_file(&self) -> Result<String> {
+            bail!("Not supported in test")
+        }
+    }
+
+    async fn new_test_disk(files: &[(&str, &[u8])]) -> Result<TestDisk> {
+        let root = tempfile::tempdir()?;
+        let efi_part_root_dir = root.path().join("boot/efi");
+        tokio::fs::create_dir_all(&efi_part_root_dir).await?;
+        for (path, content) in files {
+            let path = root.path().join(path);
+            tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+            tokio::fs::write(path, content).await?;
+        }
+        Ok(TestDisk {
+            root,
+            efi_part_root_dir,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_load_grub_artifacts_aarch64() -> Result<()> {
+        let disk = new_test_disk(&[
+            ("boot/efi/EFI/alinux/grubaa64.efi", b"grub"),
+            ("boot/efi/EFI/alinux/shimaa64.efi", b"shim"),
+            ("boot/efi/EFI/alinux/grubenv", b"saved_entry=test"),
+            ("boot/efi/EFI/alinux/grub.cfg", b"set default=0"),
+            ("boot/efi/EFI/BOOT/BOOTAA64.EFI", b"boot"),
+        ])
+        .await?;
+
+        let artifacts = disk.load_grub_artifacts().await?;
+        assert_eq!(artifacts.len(), 1);
+        assert_eq!(
+            artifacts[0].efi_grub_dir,
+            disk.efi_part_root_dir.join("EFI/alinux")
+        );
+        assert_eq!(artifacts[0].grub_data, b"grub");
+        assert_eq!(artifacts[0].shim_data, b"shim");
+        assert_eq!(artifacts[0].grub_env.as_deref(), Some("saved_entry=test"));
+        assert_eq!(artifacts[0].grub_cfg.as_deref(), Some("set default=0"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_load_grub_artifacts_missing_shim() -> Result<()> {
+        let disk = new_test_disk(&[("boot/efi/EFI/alinux/grubaa64.efi", b"grub")]).await?;
+        assert!(disk.load_grub_artifacts().await.is_err());
+
+        let disk = new_test_disk(&[("boot/efi/EFI/alinux/mmaa64.efi", b"mm")]).await?;
+        assert!(disk.load_grub_artifacts().await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_extract_boot_artifacts_grub_best_effort() -> Result<()> {
+        let disk = new_test_disk(&[
+            ("boot/efi/EFI/good/grubx64.efi", b"grub"),
+            ("boot/efi/EFI/good/shimx64.efi", b"shim"),
+            ("boot/efi/EFI/good/grubenv", b"saved_entry=good"),
+            ("boot/efi/EFI/good/grub.cfg", b""),
+            ("boot/efi/EFI/bad/grubx64.efi", b"grub"),
+            ("boot/efi/EFI/bad/shimx64.efi", b"shim"),
+            ("boot/efi/EFI/bad/grubenv", b"saved_entry=bad"),
+            ("boot/efi/EFI/bad/grub.cfg", b""),
+            (
+                "boot/loader/entries/good.conf",
+                b"linux /boot/vmlinuz-good\ninitrd /boot/initramfs-good.img\noptions root=/dev/vda3 ro\n",
+            ),
+            (
+                "boot/loader/entries/bad.conf",
+                b"linux /boot/vmlinuz-bad\ninitrd /boot/initramfs-bad.img\noptions root=/dev/vda3 ro\n",
+            ),
+            ("boot/vmlinuz-good", b"kernel"),
+            ("boot/initramfs-good.img", b"initrd"),
+        ])
+        .await?;
+
+        // The kernel of the "bad" entry is missing, so the strict mode fails
+        assert!(disk.extract_boot_artifacts_grub(false).await.is_err());
+
+        let artifacts = disk.extract_boot_artifacts_grub(true).await?;
+        assert_eq!(artifacts.len(), 1);
+        assert_eq!(
+            artifacts[0].grub.efi_grub_dir,
+            disk.efi_part_root_dir.join("EFI/good")
+        );
+        assert_eq!(artifacts[0].kernel.kernel, b"kernel");
+        assert_eq!(artifacts[0].kernel.initrd, b"initrd");
+        assert_eq!(
+            artifacts[0].kernel.kernel_cmdlines,
+            vec![
+                "/vmlinuz-good root=/dev/vda3 ro".to_string(),
+                "(hd0,gpt2)/boot/vmlinuz-good root=/dev/vda3 ro".to_string(),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_extract_boot_artifacts_grub_best_effort_all_failed() -> Result<()> {
+        let disk = new_test_disk(&[
+            ("boot/efi/EFI/bad/grubx64.efi", b"grub"),
+            ("boot/efi/EFI/bad/shimx64.efi", b"shim"),
+            ("boot/efi/EFI/bad/grubenv", b"saved_entry=bad"),
+            ("boot/efi/EFI/bad/grub.cfg", b""),
+        ])
+        .await?;
+
+        assert!(disk.extract_boot_artifacts_grub(true).await.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_search_fs_uuids() {
+        let grub_cfg = r#"
+set root='hd0,gpt2'
+if [ x$feature_platform_search_hint = xy ]; then
+  search --no-floppy --fs-uuid --set=root --hint-bios=hd0,gpt3 --hint-efi=hd0,gpt3 2576d86b-4895-4922-b9d9-7c89dec6caa9
+else
+  search --no-floppy --fs-uuid --set=root 2576d86b-4895-4922-b9d9-7c89dec6caa9
+fi
+search --no-floppy --fs-uuid --set boot 'c5b7e0f0-1d1a-4d3e-9a43-8d2f5d1e6a11'
+search --no-floppy --label --set=root root
+search -u -s root "8a0e2b6e-5a3c-4e2b-8f1d-0b9c1c9e7f21"
+"#;
+        assert_eq!(
+            parse_search_fs_uuids(grub_cfg),
+            vec![
+                "2576d86b-4895-4922-b9d9-7c89dec6caa9".to_string(),
+                "8a0e2b6e-5a3c-4e2b-8f1d-0b9c1c9e7f21".to_string(),
+            ]
+        );
+        assert!(parse_search_fs_uuids("set default=0").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_extract_boot_artifacts_grub_search_fs_uuid() -> Result<()> {
+        let grub_cfg = format!(
+            "search --no-floppy --fs-uuid --set=root --hint-efi=hd0,gpt3 {TEST_ROOT_FS_UUID}\n\
+             search --no-floppy --fs-uuid --set=root 00000000-0000-0000-0000-000000000000\n"
+        );
+        let disk = new_test_disk(&[
+            ("boot/efi/EFI/alinux/grubx64.efi", b"grub"),
+            ("boot/efi/EFI/alinux/shimx64.efi", b"shim"),
+            ("boot/efi/EFI/alinux/grubenv", b"saved_entry=test"),
+            ("boot/efi/EFI/alinux/grub.cfg", grub_cfg.as_bytes()),
+            (
+                "boot/loader/entries/test.conf",
+                b"linux /boot/vmlinuz-test\ninitrd /boot/initramfs-test.img\noptions root=/dev/vda3 ro\n",
+            ),
+            ("boot/vmlinuz-test", b"kernel"),
+            ("boot/initramfs-test.img", b"initrd"),
+        ])
+        .await?;
+
+        let artifacts = disk.extract_boot_artifacts_grub(false).await?;
+        assert_eq!(artifacts.len(), 1);
+        assert_eq!(
+            artifacts[0].kernel.kernel_cmdlines,
+            vec![
+                "/vmlinuz-test root=/dev/vda3 ro".to_string(),
+                "(hd0,gpt2)/boot/vmlinuz-test root=/dev/vda3 ro".to_string(),
+                "(hd0,gpt3)/boot/vmlinuz-test root=/dev/vda3 ro".to_string(),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_grub_disk_indices() {
+        let disk_dev = Path::new("/dev/vdb");
+
+        // A single disk
+        let grub_cfg = "set root='hd0,gpt2'\n";
+        assert_eq!(grub_disk_indices(grub_cfg, None, disk_dev, 2), vec![0]);
+        assert_eq!(grub_disk_indices("", None, disk_dev, 2), vec![0]);
+
+        // The boot partition is on the second disk
+        let grub_cfg = r#"
+set root='hd1,gpt2'
+if [ x$feature_platform_search_hint = xy ]; then
+  search --no-floppy --fs-uuid --set=root --hint-bios=hd1,gpt2 --hint-efi=hd1,gpt2 --hint-baremetal=ahci1,gpt2 2576d86b-4895-4922-b9d9-7c89dec6caa9
+fi
+set root=(hd0,gpt1)
+"#;
+        assert_eq!(grub_disk_indices(grub_cfg, None, disk_dev, 2), vec![1]);
+        // The partition is not referenced, so any disk referenced may contain it
+        assert_eq!(grub_disk_indices(grub_cfg, None, disk_dev, 3), vec![0, 1]);
+
+        // The same partition number on two disks cannot be told apart
+        let grub_cfg = "set root='hd0,msdos2'\nsearch --hint=hd2,msdos2 --fs-uuid --set=root xxx\n";
+        assert_eq!(grub_disk_indices(grub_cfg, None, disk_dev, 2), vec![0, 2]);
+
+        // The device map takes precedence
+        let device_map = "# this device map was generated by anaconda\n(hd0)      /dev/vda\n(hd3)      /dev/vdb\n";
+        assert_eq!(
+            grub_disk_indices(grub_cfg, Some(device_map), disk_dev, 2),
+            vec![3]
+        );
+        assert_eq!(
+            grub_disk_indices(grub_cfg, Some(device_map), Path::new("/dev/vdc"), 2),
+            vec![0, 2]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_extract_boot_artifacts_grub_multi_disk() -> Result<()> {
+        let files = |grub_cfg: &'static [u8]| -> Vec<(&'static str, &'static [u8])> {
+            vec![
+                ("boot/efi/EFI/alinux/grubx64.efi", b"grub"),
+                ("boot/efi/EFI/alinux/shimx64.efi", b"shim"),
+                ("boot/efi/EFI/alinux/grubenv", b"saved_entry=test"),
+                ("boot/efi/EFI/alinux/grub.cfg", grub_cfg),
+                (
+                    "boot/loader/entries/test.conf",
+                    b"linux /boot/vmlinuz-test\ninitrd /boot/initramfs-test.img\noptions root=/dev/vda3 ro\n",
+                ),
+                ("boot/vmlinuz-test", b"kernel"),
+                ("boot/initramfs-test.img", b"initrd"),
+            ]
+        };
+
+        // The boot partition /dev/vda2 is on the second GRUB disk
+        let disk = new_test_disk(&files(b"set root='hd1,gpt2'\n")).await?;
+        let artifacts = disk.extract_boot_artifacts_grub(false).await?;
+        assert_eq!(
+            artifacts[0].kernel.kernel_cmdlines,
+            vec![
+                "/vmlinuz-test root=/dev/vda3 ro".to_string(),
+                "(hd1,gpt2)/boot/vmlinuz-test root=/dev/vda3 ro".to_string(),
+            ]
+        );
+
+        // The disk index cannot be determined, so all candidates are listed
+        let disk = new_test_disk(&files(
+            b"search --hint-efi=hd1,gpt1 --fs-uuid --set=root xxx\n",
+        ))
+        .await?;
+        let artifacts = disk.extract_boot_artifacts_grub(false).await?;
+        assert_eq!(
+            artifacts[0].kernel.kernel_cmdlines,
+            vec![
+                "/vmlinuz-test root=/dev/vda3 ro".to_string(),
+                "(hd0,gpt2)/boot/vmlinuz-test root=/dev/vda3 ro".to_string(),
+                "(hd1,gpt2)/boot/vmlinuz-test root=/dev/vda3 ro".to_string(),
+            ]
+        );
+
+        // The device map names the disk of the boot partition
+        let mut with_device_map = files(b"set root='hd1,gpt2'\n");
+        with_device_map.push(("boot/grub2/device.map", b"(hd0) /dev/vdb\n(hd2) /dev/vda\n"));
+        let disk = new_test_disk(&with_device_map).await?;
+        let artifacts = disk.extract_boot_artifacts_grub(false).await?;
+        assert_eq!(
+            artifacts[0].kernel.kernel_cmdlines,
+            vec![
+                "/vmlinuz-test root=/dev/vda3 ro".to_string(),
+                "(hd2,gpt2)/boot/vmlinuz-test root=/dev/vda3 ro".to_string(),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_authenticode_hash_of_non_pe() -> Result<()> {
+        let path = Path::new("/boot/efi/EFI/alinux/grubx64.efi");
+        let data = b"\x1f\x8bnot a PE image";
+
+        assert!(matches!(
+            calculate_authenticode_hash::<sha2::Sha384>(data),
+            AuthenticodeHash::Unsupported(_)
+        ));
+        let error = authenticode_hash_or_placeholder::<sha2::Sha384>("GRUB", path, data, false)
+            .unwrap_err();
+        assert!(error.to_string().contains("grubx64.efi"));
+        assert_eq!(
+            authenticode_hash_or_placeholder::<sha2::Sha384>("GRUB", path, data, true)?,
+            AUTHENTICODE_HASH_PLACEHOLDER
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_parse_grub_env_block() -> Result<()> {
+        let mut grub_env = "# GRUB Environment Block\n\
+            saved_entry=0f8e7c2a3b4d5e6f-5.10.134-19.1.al8.x86_64\n\
+            kernelopts=root=UUID=2576d86b-4895-4922-b9d9-7c89dec6caa9 ro console=ttyS0,115200\n\
+            boot_success=1\n\
+            multiline=first\\\nsecond \\\\ end\n"
+            .to_string();
+        grub_env.push_str(&"#".repeat(1024 - grub_env.len()));
+        assert_eq!(grub_env.len(), 1024);
+
+        assert_eq!(
+            parse_grub_env_block(&grub_env),
+            vec![
+                (
+                    "saved_entry".to_string(),
+                    "0f8e7c2a3b4d5e6f-5.10.134-19.1.al8.x86_64".to_string()
+                ),
+                (
+                    "kernelopts".to_string(),
+                    "root=UUID=2576d86b-4895-4922-b9d9-7c89dec6caa9 ro console=ttyS0,115200"
+                        .to_string()
+                ),
+                ("boot_success".to_string(), "1".to_string()),
+                ("multiline".to_string(), "first\nsecond \\ end".to_string()),
+            ]
+        );
+
+        let grub_vars = parse_grub_env_vars(&grub_env, "").await?;
+        assert_eq!(
+            grub_vars.get("saved_entry").map(String::as_str),
+            Some("0f8e7c2a3b4d5e6f-5.10.134-19.1.al8.x86_64")
+        );
+        assert!(grub_vars.keys().all(|key| !key.starts_with('#')));
+
+        // The plain `key=value` lines are still supported
+        assert_eq!(
+            parse_grub_env_block("saved_entry=test"),
+            vec![("saved_entry".to_string(), "test".to_string())]
+        );
+
+        Ok(())
+    }
+
+    /// Build a minimal PE32+ image with the sections, as a fixture of EFI binaries.
+    pub fn build_pe_image(sections: &[(&str, &[u8])]) -> Vec<u8> {
+        const FILE_ALIGNMENT: usize = 0x200;
+        const SECTION_ALIGNMENT: u32 = 0x1000;
+
+        let mut image = vec![0u8; FILE_ALIGNMENT];
+        // DOS header
+        image[0..2].copy_from_slice(b"MZ");
+        image[0x3c..0x40].copy_from_slice(&0x40u32.to_le_bytes());
+        // PE signature and COFF header
+        image[0x40..0x44].copy_from_slice(b"PE\0\0");
+        image[0x44..0x46].copy_from_slice(&0x8664u16.to_le_bytes());
+        image[0x46..0x48].copy_from_slice(&(sections.len() as u16).to_le_bytes());
+        image[0x54..0x56].copy_from_slice(&240u16.to_le_bytes());
+        image[0x56..0x58].copy_from_slice(&0x22u16.to_le_bytes());
+        // Optional header
+        let optional = 0x58;
+        image[optional..optional + 2].copy_from_slice(&0x20bu16.to_le_bytes());
+        image[optional + 32..optional + 36].copy_from_slice(&SECTION_ALIGNMENT.to_le_bytes());
+        image[optional + 36..optional + 40].copy_from_slice(&(FILE_ALIGNMENT as u32).to_le_bytes());
+        let size_of_image = SECTION_ALIGNMENT * (sections.len() as u32 + 1);
+        image[optional + 56..optional + 60].copy_from_slice(&size_of_image.to_le_bytes());
+        image[optional + 60..optional + 64].copy_from_slice(&(FILE_ALIGNMENT as u32).to_le_bytes());
+        image[optional + 68..optional + 70].copy_from_slice(&10u16.to_le_bytes());
+        image[optional + 108..optional + 112].copy_from_slice(&16u32.to_le_bytes());
+
+        // Section headers and data
+        for (index, (name, data)) in sections.iter().enumerate() {
+            assert!(name.len() <= 8 && data.len() <= FILE_ALIGNMENT);
+            let header = optional + 240 + index * 40;
+            let raw_offset = image.len();
+            image[header..header + name.len()].copy_from_slice(name.as_bytes());
+            image[header + 8..header + 12].copy_from_slice(&(data.len() as u32).to_le_bytes());
+            image[header + 12..header + 16]
+                .copy_from_slice(&(SECTION_ALIGNMENT * (index as u32 + 1)).to_le_bytes());
+            image[header + 16..header + 20].copy_from_slice(&(FILE_ALIGNMENT as u32).to_le_bytes());
+            image[header + 20..header + 24].copy_from_slice(&(raw_offset as u32).to_le_bytes());
+            image[header + 36..header + 40].copy_from_slice(&0x40000040u32.to_le_bytes());
+
+            image.extend_from_slice(data);
+            image.resize(raw_offset + FILE_ALIGNMENT, 0);
+        }
+        image
+    }
+
+    const TEST_SBAT: &[u8] = b"sbat,1,SBAT Version,sbat,1,https://github.com/rhboot/shim/blob/main/SBAT.md\nshim,4,UEFI shim,shim,1,https://github.com/rhboot/shim\n";
+
+    #[test]
+    fn test_extract_sbat() -> Result<()> {
+        let shim = build_pe_image(&[(".text", b"\xc3"), (".sbat", TEST_SBAT)]);
+        assert_eq!(extract_sbat(&shim)?, Some(TEST_SBAT));
+
+        // A shim built without SBAT
+        let shim = build_pe_image(&[(".text", b"\xc3")]);
+        assert_eq!(extract_sbat(&shim)?, None);
+
+        assert!(extract_sbat(b"shim").is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_insert_sbat_reference_value() -> Result<()> {
+        let shim = build_pe_image(&[(".text", b"\xc3"), (".sbat", TEST_SBAT)]);
+        let grub = build_pe_image(&[(".text", b"\xc3")]);
+        let disk = new_test_disk(&[
+            ("boot/efi/EFI/alinux/grubx64.efi", grub.as_slice()),
+            ("boot/efi/EFI/alinux/shimx64.efi", shim.as_slice()),
+            ("boot/efi/EFI/alinux/grubenv", b"saved_entry=test"),
+            ("boot/efi/EFI/alinux/grub.cfg", b""),
+            (
+                "boot/loader/entries/test.conf",
+                b"linux /boot/vmlinuz-test\ninitrd /boot/initramfs-test.img\noptions root=/dev/vda3 ro\n",
+            ),
+            ("boot/vmlinuz-test", b"kernel"),
+            ("boot/initramfs-test.img", b"initrd"),
+        ])
+        .await?;
+        let artifacts = disk.extract_boot_artifacts_grub(false).await?;
+
+        let mut map = IndexMap::new();
+        artifacts
+            .inseart_reference_value::<sha2::Sha384>(&mut map, "SHA-384", false)
+            .await?;
+        let mut hasher = <sha2::Sha384 as digest::Digest>::new();
+        digest::Digest::update(&mut hasher, TEST_SBAT);
+        assert_eq!(
+            map.get("measurement.sbat.SHA-384"),
+            Some(&vec![hex::encode(hasher.finalize())])
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalize_line_endings() {
+        assert_eq!(
+            normalize_line_endings("set timeout=5\r\nblscfg\rboot\n"),
+            "set timeout=5\nblscfg\nboot\n"
+        );
+        assert_eq!(normalize_line_endings("blscfg\n"), "blscfg\n");
+    }
+
+    #[tokio::test]
+    async fn test_insert_grub_config_reference_value() -> Result<()> {
+        const GRUB_CFG: &str = "set timeout=5\n\
+            insmod part_gpt\n\
+            search --no-floppy --fs-uuid --set=root 2576d86b-4895-4922-b9d9-7c89dec6caa9\n\
+            blscfg\n";
+        let mut grub_env = "# GRUB Environment Block\nsaved_entry=test\n".to_string();
+        grub_env.push_str(&"#".repeat(1024 - grub_env.len()));
+
+        // The grub.cfg in the BOOT partition, edited with CRLF line endings, takes precedence over
+        // the stale one in the EFI partition
+        let disk = new_test_disk(&[
+            ("boot/efi/EFI/alinux/grubx64.efi", b"grub"),
+            ("boot/efi/EFI/alinux/shimx64.efi", b"shim"),
+            ("boot/efi/EFI/alinux/grubenv", grub_env.as_bytes()),
+            ("boot/efi/EFI/alinux/grub.cfg", b"stale"),
+            (
+                "boot/grub2/grub.cfg",
+                GRUB_CFG.replace('\n', "\r\n").as_bytes(),
+            ),
+            (
+                "boot/loader/entries/test.conf",
+                b"linux /boot/vmlinuz-test\ninitrd /boot/initramfs-test.img\noptions root=/dev/vda3 ro\n",
+            ),
+            ("boot/vmlinuz-test", b"kernel"),
+            ("boot/initramfs-test.img", b"initrd"),
+        ])
+        .await?;
+        let artifacts = disk.extract_boot_artifacts_grub(false).await?;
+
+        let mut map = IndexMap::new();
+        insert_grub_config_reference_value::<sha2::Sha384>(&artifacts, &mut map, "SHA-384");
+        assert_eq!(
+            map.get("measurement.grub_cfg.SHA-384"),
+            Some(&vec![hex::encode(
+                <sha2::Sha384 as digest::Digest>::digest(GRUB_CFG)
+            )])
+        );
+        assert_eq!(
+            map.get("measurement.grubenv.SHA-384"),
+            Some(&vec![hex::encode(
+                <sha2::Sha384 as digest::Digest>::digest(&grub_env)
+            )])
+        );
+
+        // The values are not emitted by default
+        let mut map = IndexMap::new();
+        artifacts
+            .inseart_reference_value::<sha2::Sha384>(&mut map, "SHA-384", true)
+            .await?;
+        assert!(!map.contains_key("measurement.grub_cfg.SHA-384"));
+        assert!(!map.contains_key("measurement.grubenv.SHA-384"));
+
+        Ok(())
+    }
 }
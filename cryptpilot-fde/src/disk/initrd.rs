@@ -0,0 +1,643 @@
+use std::{
+    ffi::OsStr,
+    os::unix::ffi::OsStrExt as _,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context as _, Result};
+use object::{Object as _, ObjectSection as _};
+use tempfile::TempDir;
+use tokio::process::Command;
+
+use crate::{
+    cmd::boot_service::metadata::Metadata,
+    config::FdeConfigBundle,
+    disk::{
+        external::OnExternalFdeDisk,
+        kernel::KernelArtifacts,
+        uki::{assume_uki_image, UKI_FILE_PATH},
+        Disk as _, FdeBootType, FdeDisk as _,
+    },
+};
+use cryptpilot::fs::cmd::CheckCommandOutput as _;
+
+const CPIO_NEWC_MAGIC: &[u8] = b"07070";
+const CPIO_NEWC_HEADER_SIZE: usize = 110;
+const CPIO_TRAILER_NAME: &[u8] = b"TRAILER!!!";
+/// The file type bits in the mode of a cpio entry.
+const CPIO_MODE_TYPE_MASK: u32 = 0o170000;
+const CPIO_MODE_REGULAR_FILE: u32 = 0o100000;
+const CPIO_MODE_SYMLINK: u32 = 0o120000;
+const DRACUT_SKIPCPIO_PATH: &str = "/usr/lib/dracut/skipcpio";
+
+/// The compression format of the main cpio archive in an initrd.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum InitrdCompression {
+    None,
+    Gzip,
+    Zstd,
+    Xz,
+    Lz4,
+    Bzip2,
+}
+
+impl InitrdCompression {
+    fn detect(data: &[u8]) -> Result<Self> {
+        Ok(if data.starts_with(CPIO_NEWC_MAGIC) {
+            Self::None
+        } else if data.starts_with(&[0x1f, 0x8b]) {
+            Self::Gzip
+        } else if data.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Self::Zstd
+        } else if data.starts_with(&[0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00]) {
+            Self::Xz
+        } else if data.starts_with(&[0x02, 0x21, 0x4c, 0x18]) {
+            Self::Lz4
+        } else if data.starts_with(b"BZh") {
+            Self::Bzip2
+        } else {
+            bail!("Unknown compression format of initrd")
+        })
+    }
+
+    async fn decompress(&self, path: &Path) -> Result<Vec<u8>> {
+        let mut cmd = match self {
+            Self::None => return Ok(tokio::fs::read(path).await?),
+            Self::Gzip => Command::new("gzip"),
+            Self::Zstd => Command::new("zstd"),
+            Self::Xz => Command::new("xz"),
+            Self::Lz4 => Command::new("lz4"),
+            Self::Bzip2 => Command::new("bzip2"),
+        };
+        cmd.arg("-dc")
+            .arg(path)
+            .run()
+            .await
+            .with_context(|| format!("Failed to decompress initrd with {self:?}"))
+    }
+
+    async fn compress(&self, path: &Path) -> Result<Vec<u8>> {
+        let mut cmd = match self {
+            Self::None => return Ok(tokio::fs::read(path).await?),
+            Self::Gzip => Command::new("gzip"),
+            Self::Zstd => {
+                let mut cmd = Command::new("zstd");
+                cmd.arg("-q");
+                cmd
+            }
+            Self::Xz => {
+                // The kernel only supports CRC32 integrity check for xz
+                let mut cmd = Command::new("xz");
+                cmd.arg("--check=crc32");
+                cmd
+            }
+            Self::Lz4 => {
+                // The kernel only supports the legacy lz4 format
+                let mut cmd = Command::new("lz4");
+                cmd.arg("-l");
+                cmd
+            }
+            Self::Bzip2 => Command::new("bzip2"),
+        };
+        cmd.arg("-c")
+            .arg(path)
+            .run()
+            .await
+            .with_context(|| format!("Failed to compress initrd with {self:?}"))
+    }
+}
+
+/// An entry in the cpio archives of an initrd.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InitrdEntry {
+    /// The absolute path of the entry in the initrd.
+    pub path: PathBuf,
+    pub mode: u32,
+    /// The content of a regular file, or the target of a symlink.
+    pub data: Vec<u8>,
+}
+
+impl InitrdEntry {
+    pub fn is_file(&self) -> bool {
+        self.mode & CPIO_MODE_TYPE_MASK == CPIO_MODE_REGULAR_FILE
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.mode & CPIO_MODE_TYPE_MASK == CPIO_MODE_SYMLINK
+    }
+}
+
+/// Read all the entries in an initrd, which may consist of several concatenated cpio archives, e.g.
+/// an uncompressed early archive with CPU microcode followed by the compressed main archive. The
+/// compression format of each archive is detected from its content. An entry may appear more than
+/// once, in which case the later one takes effect when the initrd is unpacked by the kernel.
+pub async fn read_initrd_entries(initrd: &[u8]) -> Result<Vec<InitrdEntry>> {
+    let temp_dir = TempDir::new()?;
+    let mut entries = vec![];
+    let mut data = initrd.to_vec();
+    let mut offset = 0;
+    loop {
+        // The archives may be padded with zeros
+        while data.get(offset) == Some(&0) {
+            offset += 1;
+        }
+        if offset >= data.len() {
+            break;
+        }
+
+        match InitrdCompression::detect(&data[offset..])
+            .with_context(|| format!("Failed to detect initrd archive at offset {offset}"))?
+        {
+            InitrdCompression::None => {
+                let (archive_entries, archive_size) = parse_cpio_archive(&data[offset..])
+                    .with_context(|| format!("Failed to parse cpio archive at offset {offset}"))?;
+                entries.extend(archive_entries);
+                offset += archive_size;
+            }
+            compression => {
+                // The compressed archive extends to the end of the initrd
+                tracing::debug!(offset, ?compression, "Decompressing initrd archive");
+                let archive_path = temp_dir.path().join("archive.img");
+                tokio::fs::write(&archive_path, &data[offset..]).await?;
+                data = compression.decompress(&archive_path).await?;
+                offset = 0;
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// The maximum number of symlinks followed when looking up a path in an initrd, the same as the
+/// limit of the kernel.
+const INITRD_SYMLINK_HOPS_MAX: usize = 40;
+
+/// Find the regular file at the path in the initrd entries, following the symlinks (e.g. /bin to
+/// usr/bin) within the initrd. A later entry with the same path takes effect.
+pub fn find_initrd_file<'a>(entries: &'a [InitrdEntry], path: &Path) -> Result<&'a InitrdEntry> {
+    let lookup = |path: &Path| entries.iter().rev().find(|entry| entry.path == path);
+    let components_of = |path: &Path| {
+        path.components()
+            .filter_map(|component| match component {
+                std::path::Component::Normal(name) => Some(name.to_owned()),
+                std::path::Component::ParentDir => Some("..".into()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let mut resolved = PathBuf::from("/");
+    // The components left to resolve, in reverse order
+    let mut pending = components_of(path);
+    pending.reverse();
+    let mut hops = 0;
+    while let Some(component) = pending.pop() {
+        if component == ".." {
+            resolved.pop();
+            continue;
+        }
+        let candidate = resolved.join(&component);
+        match lookup(&candidate) {
+            Some(entry) if entry.is_symlink() => {
+                hops += 1;
+                if hops > INITRD_SYMLINK_HOPS_MAX {
+                    bail!("Too many levels of symlinks when resolving {path:?} in initrd");
+                }
+                let target = Path::new(OsStr::from_bytes(&entry.data));
+                if target.is_absolute() {
+                    resolved = PathBuf::from("/");
+                }
+                pending.extend(components_of(target).into_iter().rev());
+            }
+            // A directory may have no entry of its own
+            _ => resolved = candidate,
+        }
+    }
+
+    let entry = lookup(&resolved).with_context(|| format!("No file {path:?} in initrd"))?;
+    if !entry.is_file() {
+        bail!("The path {path:?} in initrd is not a regular file");
+    }
+    Ok(entry)
+}
+
+/// Parse an uncompressed cpio archive in the "newc" format at the start of the data, until the
+/// trailer entry. Returns the entries and the size of the archive.
+fn parse_cpio_archive(data: &[u8]) -> Result<(Vec<InitrdEntry>, usize)> {
+    let align = |offset: usize| offset.next_multiple_of(4);
+
+    let mut entries = vec![];
+    let mut offset = 0;
+    loop {
+        let header = data
+            .get(offset..offset + CPIO_NEWC_HEADER_SIZE)
+            .with_context(|| format!("Truncated cpio header at offset {offset}"))?;
+        if !header.starts_with(b"070701") && !header.starts_with(b"070702") {
+            bail!("Invalid cpio header magic at offset {offset}");
+        }
+        let field = |index: usize| -> Result<usize> {
+            let hex = std::str::from_utf8(&header[6 + index * 8..6 + (index + 1) * 8])?;
+            Ok(u32::from_str_radix(hex, 16)
+                .with_context(|| format!("Invalid cpio header field {hex:?}"))?
+                as usize)
+        };
+        let mode = field(1)? as u32;
+        let file_size = field(6)?;
+        let name_size = field(11)?;
+
+        let name_start = offset + CPIO_NEWC_HEADER_SIZE;
+        let name = data
+            .get(name_start..name_start + name_size)
+            .with_context(|| format!("Truncated cpio entry name at offset {name_start}"))?;
+        // The name is terminated with NUL
+        let name = name.strip_suffix(&[0]).unwrap_or(name);
+
+        let data_start = align(name_start + name_size);
+        let content = data
+            .get(data_start..data_start + file_size)
+            .with_context(|| format!("Truncated cpio entry data at offset {data_start}"))?;
+        offset = align(data_start + file_size);
+
+        if name == CPIO_TRAILER_NAME {
+            return Ok((entries, offset));
+        }
+
+        let relative_path = Path::new(OsStr::from_bytes(name));
+        let relative_path = relative_path
+            .strip_prefix("/")
+            .unwrap_or(relative_path)
+            .strip_prefix(".")
+            .unwrap_or(relative_path);
+        if relative_path.as_os_str().is_empty() {
+            continue;
+        }
+        entries.push(InitrdEntry {
+            path: Path::new("/").join(relative_path),
+            mode,
+            data: content.to_vec(),
+        });
+    }
+}
+
+/// Replace (or add) files in an initrd image, keeping the early cpio archive (e.g. CPU microcode)
+/// and the compression format of the main archive. The paths of the files are absolute paths in
+/// the initrd.
+pub async fn replace_files_in_initrd(initrd: &[u8], files: &[(&Path, &[u8])]) -> Result<Vec<u8>> {
+    let temp_dir = TempDir::new()?;
+    let initrd_path = temp_dir.path().join("initrd.img");
+    tokio::fs::write(&initrd_path, initrd)
+        .await
+        .context("Failed to write initrd content to a temporary dir")?;
+
+    // Split the early uncompressed cpio archive from the main archive
+    let main_archive = if initrd.starts_with(CPIO_NEWC_MAGIC) {
+        let rest = Command::new(DRACUT_SKIPCPIO_PATH)
+            .arg(&initrd_path)
+            .run()
+            .await
+            .context("Failed to skip the early cpio archive of initrd")?;
+        if rest.is_empty() {
+            // The whole initrd is a single uncompressed cpio archive
+            initrd.to_vec()
+        } else {
+            rest
+        }
+    } else {
+        initrd.to_vec()
+    };
+    let early_archive = &initrd[..initrd.len() - main_archive.len()];
+
+    let compression = InitrdCompression::detect(&main_archive)?;
+    tracing::debug!(
+        early_archive_size = early_archive.len(),
+        ?compression,
+        "Unpacking main archive of initrd"
+    );
+    let main_archive_path = temp_dir.path().join("main.img");
+    tokio::fs::write(&main_archive_path, &main_archive).await?;
+    let cpio_path = temp_dir.path().join("main.cpio");
+    tokio::fs::write(
+        &cpio_path,
+        compression.decompress(&main_archive_path).await?,
+    )
+    .await?;
+
+    let root_dir = temp_dir.path().join("root");
+    tokio::fs::create_dir(&root_dir).await?;
+    Command::new("cpio")
+        .args([
+            "--extract",
+            "--make-directories",
+            "--preserve-modification-time",
+            "--no-absolute-filenames",
+            "--quiet",
+        ])
+        .arg("-D")
+        .arg(&root_dir)
+        .arg("-F")
+        .arg(&cpio_path)
+        .run()
+        .await
+        .context("Failed to unpack main archive of initrd")?;
+
+    for (path, content) in files {
+        let real_path = root_dir.join(path.strip_prefix("/").unwrap_or(path));
+        if let Some(parent) = real_path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tracing::debug!("Replacing {path:?} in initrd");
+        tokio::fs::write(&real_path, content)
+            .await
+            .with_context(|| format!("Failed to write {path:?} to initrd"))?;
+    }
+
+    let new_cpio = Command::new("sh")
+        .arg("-c")
+        .arg("find . -print0 | LC_ALL=C sort -z | cpio --null --create --format=newc --quiet")
+        .current_dir(&root_dir)
+        .run()
+        .await
+        .context("Failed to pack main archive of initrd")?;
+    tokio::fs::write(&cpio_path, new_cpio).await?;
+    let new_main_archive = compression.compress(&cpio_path).await?;
+
+    let mut new_initrd = early_archive.to_vec();
+    new_initrd.extend_from_slice(&new_main_archive);
+    Ok(new_initrd)
+}
+
+/// An initrd image on a disk, which is either a standalone file booted with GRUB, or the `.initrd`
+/// section of a UKI image.
+pub struct EmbeddedInitrd {
+    /// The path of the file containing the initrd on the disk.
+    pub path: PathBuf,
+    /// Whether the file is a UKI image.
+    uki: bool,
+    /// The content of the initrd.
+    pub initrd: Vec<u8>,
+}
+
+impl EmbeddedInitrd {
+    /// Extract the FDE config bundle and the metadata from the initrd.
+    pub async fn extract_cryptpilot_files(&self) -> Result<(FdeConfigBundle, Metadata)> {
+        KernelArtifacts {
+            kernel_cmdlines: vec![],
+            kernel: vec![],
+            initrd: self.initrd.clone(),
+        }
+        .extract_cryptpilot_files()
+        .await
+    }
+}
+
+impl OnExternalFdeDisk {
+    /// Load all the initrd images on the disk.
+    pub async fn load_embedded_initrds(&self) -> Result<Vec<EmbeddedInitrd>> {
+        match self.fde_boot_type() {
+            FdeBootType::NoFde => bail!("The disk is not a cryptpilot FDE disk"),
+            FdeBootType::Grub => {
+                let mut initrds = vec![];
+                for path in self.list_dir_on_disk(Path::new("/boot")).await? {
+                    let is_initrd = path.file_name().is_some_and(|name| {
+                        let name = name.to_string_lossy();
+                        name.starts_with("initramfs-") && name.ends_with(".img")
+                    });
+                    if !is_initrd {
+                        continue;
+                    }
+                    let initrd = self.read_file_on_disk(&path).await?;
+                    initrds.push(EmbeddedInitrd {
+                        path,
+                        uki: false,
+                        initrd,
+                    });
+                }
+                Ok(initrds)
+            }
+            FdeBootType::Uki => {
+                let path = PathBuf::from(UKI_FILE_PATH);
+                let uki_data = self.read_file_on_disk(&path).await?;
+                let initrd = object::File::parse(&uki_data[..])
+                    .context("Not a valid UKI file")?
+                    .section_by_name(".initrd")
+                    .context("No .initrd section found")?
+                    .data()?
+                    .to_owned();
+                Ok(vec![EmbeddedInitrd {
+                    path,
+                    uki: true,
+                    initrd,
+                }])
+            }
+        }
+    }
+
+    /// Replace the initrd on the disk with a new one.
+    pub async fn write_embedded_initrd(
+        &self,
+        embedded_initrd: &EmbeddedInitrd,
+        new_initrd: &[u8],
+    ) -> Result<()> {
+        if !embedded_initrd.uki {
+            return self
+                .write_file_on_disk(&embedded_initrd.path, new_initrd)
+                .await;
+        }
+
+        let temp_dir = TempDir::new()?;
+        let uki_path = temp_dir.path().join("BOOTX64.EFI");
+        let initrd_path = temp_dir.path().join("initrd.img");
+        tokio::fs::write(
+            &uki_path,
+            self.read_file_on_disk(&embedded_initrd.path).await?,
+        )
+        .await?;
+        tokio::fs::write(&initrd_path, new_initrd).await?;
+
+        Command::new("objcopy")
+            .arg("--update-section")
+            .arg(format!(".initrd={}", initrd_path.display()))
+            .arg(&uki_path)
+            .run()
+            .await
+            .context("Failed to update the .initrd section of UKI image")?;
+
+        let uki_data = tokio::fs::read(&uki_path).await?;
+        assume_uki_image(&uki_data)?;
+        self.write_file_on_disk(&embedded_initrd.path, &uki_data)
+            .await
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+    use anyhow::Result;
+
+    pub(crate) async fn pack_initrd(
+        root_dir: &Path,
+        compression: InitrdCompression,
+    ) -> Result<Vec<u8>> {
+        let cpio = Command::new("sh")
+            .arg("-c")
+            .arg("find . -print0 | cpio --null --create --format=newc --quiet")
+            .current_dir(root_dir)
+            .run()
+            .await?;
+        let cpio_path = root_dir.with_extension("cpio");
+        tokio::fs::write(&cpio_path, cpio).await?;
+        compression.compress(&cpio_path).await
+    }
+
+    async fn unpack_initrd(initrd: &[u8], compression: InitrdCompression) -> Result<TempDir> {
+        let temp_dir = TempDir::new()?;
+        let initrd_path = temp_dir.path().join("initrd.img");
+        tokio::fs::write(&initrd_path, initrd).await?;
+        let cpio_path = temp_dir.path().join("initrd.cpio");
+        tokio::fs::write(&cpio_path, compression.decompress(&initrd_path).await?).await?;
+        Command::new("cpio")
+            .args(["--extract", "--make-directories", "--quiet"])
+            .arg("-D")
+            .arg(temp_dir.path())
+            .arg("-F")
+            .arg(&cpio_path)
+            .run()
+            .await?;
+        Ok(temp_dir)
+    }
+
+    #[tokio::test]
+    async fn test_replace_files_in_initrd() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root_dir = temp_dir.path().join("root");
+        tokio::fs::create_dir_all(root_dir.join("etc/cryptpilot")).await?;
+        tokio::fs::write(root_dir.join("etc/cryptpilot/fde.toml"), "old").await?;
+        tokio::fs::write(root_dir.join("etc/cryptpilot/metadata.toml"), "metadata").await?;
+
+        let initrd = pack_initrd(&root_dir, InitrdCompression::Gzip).await?;
+        assert_eq!(InitrdCompression::detect(&initrd)?, InitrdCompression::Gzip);
+
+        let new_initrd = replace_files_in_initrd(
+            &initrd,
+            &[(Path::new("/etc/cryptpilot/fde.toml"), b"new".as_slice())],
+        )
+        .await?;
+        assert_eq!(
+            InitrdCompression::detect(&new_initrd)?,
+            InitrdCompression::Gzip
+        );
+
+        let unpacked = unpack_initrd(&new_initrd, InitrdCompression::Gzip).await?;
+        assert_eq!(
+            tokio::fs::read_to_string(unpacked.path().join("etc/cryptpilot/fde.toml")).await?,
+            "new"
+        );
+        assert_eq!(
+            tokio::fs::read_to_string(unpacked.path().join("etc/cryptpilot/metadata.toml")).await?,
+            "metadata"
+        );
+
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_read_initrd_entries() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root_dir = temp_dir.path().join("root");
+        tokio::fs::create_dir_all(root_dir.join("etc/cryptpilot")).await?;
+        tokio::fs::write(root_dir.join("etc/cryptpilot/fde.toml"), "fde").await?;
+        tokio::fs::write(root_dir.join("etc/cryptpilot/odd-sized"), "12345").await?;
+        tokio::fs::symlink("fde.toml", root_dir.join("etc/cryptpilot/link.toml")).await?;
+
+        let check_entries = |entries: &[InitrdEntry]| {
+            let find = |path: &str| {
+                entries
+                    .iter()
+                    .rev()
+                    .find(|entry| entry.path == Path::new(path))
+                    .unwrap_or_else(|| panic!("{path} not found in {entries:?}"))
+                    .clone()
+            };
+            let fde_config = find("/etc/cryptpilot/fde.toml");
+            assert!(fde_config.is_file());
+            assert_eq!(fde_config.data, b"fde");
+            assert_eq!(find("/etc/cryptpilot/odd-sized").data, b"12345");
+            let link = find("/etc/cryptpilot/link.toml");
+            assert!(link.is_symlink());
+            assert_eq!(link.data, b"fde.toml");
+            assert!(!find("/etc/cryptpilot").is_file());
+        };
+
+        for compression in [
+            InitrdCompression::None,
+            InitrdCompression::Gzip,
+            InitrdCompression::Zstd,
+            InitrdCompression::Xz,
+        ] {
+            let initrd = pack_initrd(&root_dir, compression).await?;
+            assert_eq!(InitrdCompression::detect(&initrd)?, compression);
+            check_entries(&read_initrd_entries(&initrd).await?);
+        }
+
+        // An uncompressed early archive with microcode, followed by the compressed main archive
+        let early_dir = temp_dir.path().join("early");
+        tokio::fs::create_dir_all(early_dir.join("kernel/x86/microcode")).await?;
+        tokio::fs::write(
+            early_dir.join("kernel/x86/microcode/GenuineIntel.bin"),
+            "microcode",
+        )
+        .await?;
+        let mut initrd = pack_initrd(&early_dir, InitrdCompression::None).await?;
+        initrd.resize(initrd.len().next_multiple_of(512), 0);
+        initrd.extend(pack_initrd(&root_dir, InitrdCompression::Zstd).await?);
+
+        let entries = read_initrd_entries(&initrd).await?;
+        check_entries(&entries);
+        assert!(entries.iter().any(|entry| entry.path
+            == Path::new("/kernel/x86/microcode/GenuineIntel.bin")
+            && entry.data == b"microcode"));
+
+        // A truncated archive
+        let initrd = pack_initrd(&root_dir, InitrdCompression::None).await?;
+        assert!(read_initrd_entries(&initrd[..initrd.len() / 2])
+            .await
+            .is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_find_initrd_file() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root_dir = temp_dir.path().join("root");
+        tokio::fs::create_dir_all(root_dir.join("usr/bin")).await?;
+        tokio::fs::create_dir_all(root_dir.join("etc/cryptpilot")).await?;
+        tokio::fs::write(root_dir.join("usr/bin/cryptpilot-fde"), "binary").await?;
+        tokio::fs::write(root_dir.join("etc/cryptpilot/fde.toml"), "config").await?;
+        tokio::fs::symlink("usr/bin", root_dir.join("bin")).await?;
+        tokio::fs::symlink(
+            "../../etc/cryptpilot/fde.toml",
+            root_dir.join("usr/bin/fde.toml"),
+        )
+        .await?;
+        tokio::fs::symlink("loop", root_dir.join("loop")).await?;
+
+        let initrd = pack_initrd(&root_dir, InitrdCompression::Zstd).await?;
+        let entries = read_initrd_entries(&initrd).await?;
+
+        let find = |path: &str| {
+            find_initrd_file(&entries, Path::new(path)).map(|entry| entry.data.clone())
+        };
+        assert_eq!(find("/usr/bin/cryptpilot-fde")?, b"binary");
+        assert_eq!(find("/etc/cryptpilot/fde.toml")?, b"config");
+        // Symlinks are followed, both for directories and files
+        assert_eq!(find("/bin/cryptpilot-fde")?, b"binary");
+        assert_eq!(find("/bin/fde.toml")?, b"config");
+
+        assert!(format!("{:#}", find("/usr/bin/missing").unwrap_err()).contains("No file"));
+        assert!(find("/usr/bin").is_err());
+        assert!(find("/loop").is_err());
+
+        Ok(())
+    }
+}
@@ -1,5 +1,5 @@
 use anyhow::{Context as _, Result};
-use std::path::Path;
+use std::{io::Read as _, path::Path};
 use tempfile::TempDir;
 use tokio::process::Command;
 
@@ -27,6 +27,84 @@ pub struct KernelArtifacts {
     pub initrd: Vec<u8>,
 }
 
+/// The prefix of the banner (`/proc/version`) compiled into the kernel.
+const LINUX_BANNER_PREFIX: &[u8] = b"Linux version ";
+
+/// The magic of the setup header of the x86 boot protocol, at offset 0x202 of a bzImage.
+const X86_SETUP_HEADER_MAGIC: &[u8] = b"HdrS";
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+/// Get the version string of the kernel image, e.g. "5.10.134-16.al8.x86_64 (mockbuild@...) #1 SMP
+/// ...", which identifies the exact kernel build. Returns `None` if it cannot be found.
+///
+/// For an x86 bzImage, whose banner is compressed, the string is the one the `kernel_version` field
+/// of the setup header points to. Otherwise, it is taken from the "Linux version" banner, which is
+/// searched in the image as is, or in the decompressed image if it is gzip-compressed (e.g. an arm64
+/// `Image.gz`).
+pub fn extract_kernel_version(kernel: &[u8]) -> Option<String> {
+    if let Some(version) = extract_kernel_version_from_setup_header(kernel) {
+        return Some(version);
+    }
+    if let Some(version) = find_linux_banner(kernel) {
+        return Some(version);
+    }
+    if kernel.starts_with(GZIP_MAGIC) {
+        let mut decompressed = vec![];
+        match flate2::read::GzDecoder::new(kernel).read_to_end(&mut decompressed) {
+            Ok(_) => return find_linux_banner(&decompressed),
+            Err(error) => tracing::debug!(?error, "Failed to decompress the kernel image"),
+        }
+    }
+    None
+}
+
+/// Read the version string from the setup header of the x86 boot protocol (2.00+), see
+/// https://www.kernel.org/doc/html/latest/arch/x86/boot.html
+fn extract_kernel_version_from_setup_header(kernel: &[u8]) -> Option<String> {
+    let read_u16 = |offset: usize| {
+        kernel
+            .get(offset..offset + 2)
+            .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+    };
+    if kernel.get(0x202..0x206)? != X86_SETUP_HEADER_MAGIC || read_u16(0x206)? < 0x0200 {
+        return None;
+    }
+    let offset = read_u16(0x20e)?;
+    if offset == 0 {
+        return None;
+    }
+    read_c_string(kernel.get(0x200 + offset as usize..)?)
+}
+
+/// Search for the "Linux version <version>" banner, and return the `<version>` part.
+fn find_linux_banner(kernel: &[u8]) -> Option<String> {
+    let start = kernel
+        .windows(LINUX_BANNER_PREFIX.len())
+        .position(|window| window == LINUX_BANNER_PREFIX)?
+        + LINUX_BANNER_PREFIX.len();
+    let banner = &kernel[start..];
+    let end = banner
+        .iter()
+        .position(|byte| *byte == b'\n')
+        .unwrap_or(banner.len());
+    read_c_string(&banner[..end])
+}
+
+/// Read a NUL-terminated printable string, which is trimmed. Returns `None` if it is empty or not
+/// printable.
+fn read_c_string(bytes: &[u8]) -> Option<String> {
+    let end = bytes
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(bytes.len());
+    let string = std::str::from_utf8(&bytes[..end]).ok()?.trim();
+    if string.is_empty() || string.chars().any(|c| c.is_control()) {
+        return None;
+    }
+    Some(string.to_owned())
+}
+
 impl KernelArtifacts {
     pub async fn extract_cryptpilot_files(&self) -> Result<(FdeConfigBundle, Metadata)> {
         // First, create a tmp dir.
@@ -77,3 +155,78 @@ impl KernelArtifacts {
         Ok((fde_config_bundle, metadata))
     }
 }
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    use std::io::Write as _;
+
+    const TEST_KERNEL_VERSION: &str =
+        "5.10.134-16.al8.x86_64 (mockbuild@alinux) #1 SMP Fri Dec 15 10:10:10 CST 2023";
+
+    /// Build the beginning of an x86 bzImage, whose setup header points to the version string.
+    pub fn build_bzimage(version: &str) -> Vec<u8> {
+        let mut image = vec![0u8; 0x400];
+        image[0x1fe..0x200].copy_from_slice(&[0x55, 0xaa]);
+        image[0x202..0x206].copy_from_slice(X86_SETUP_HEADER_MAGIC);
+        image[0x206..0x208].copy_from_slice(&0x020fu16.to_le_bytes());
+        // The version string is placed after the setup header, like the real kernel does
+        image[0x20e..0x210].copy_from_slice(&0x100u16.to_le_bytes());
+        image[0x300..0x300 + version.len()].copy_from_slice(version.as_bytes());
+        // The compressed payload, in which the banner cannot be found
+        image.extend_from_slice(&[0x1f, 0x8b, 0x08, 0x00, 0xde, 0xad, 0xbe, 0xef]);
+        image
+    }
+
+    /// Build an uncompressed kernel image (e.g. the arm64 `Image`) with the banner.
+    fn build_image_with_banner(version: &str) -> Vec<u8> {
+        let mut image = vec![0u8; 0x200];
+        image.extend_from_slice(b"\x7fsome code");
+        image.extend_from_slice(
+            format!("Linux version {version} (gcc (GCC) 10.2.1)\n\0").as_bytes(),
+        );
+        image.extend_from_slice(&[0u8; 0x40]);
+        image
+    }
+
+    #[test]
+    fn test_extract_kernel_version_from_bzimage() {
+        assert_eq!(
+            extract_kernel_version(&build_bzimage(TEST_KERNEL_VERSION)).as_deref(),
+            Some(TEST_KERNEL_VERSION)
+        );
+    }
+
+    #[test]
+    fn test_extract_kernel_version_from_banner() -> Result<()> {
+        let image = build_image_with_banner("6.6.0-1.al8.aarch64 #1 SMP");
+        assert_eq!(
+            extract_kernel_version(&image).as_deref(),
+            Some("6.6.0-1.al8.aarch64 #1 SMP (gcc (GCC) 10.2.1)")
+        );
+
+        // A gzip-compressed image (e.g. arm64 Image.gz)
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&image)?;
+        let compressed = encoder.finish()?;
+        assert_eq!(
+            extract_kernel_version(&compressed).as_deref(),
+            Some("6.6.0-1.al8.aarch64 #1 SMP (gcc (GCC) 10.2.1)")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_extract_kernel_version_not_found() {
+        assert_eq!(extract_kernel_version(b"kernel"), None);
+        assert_eq!(extract_kernel_version(&[0x1f, 0x8b, 0x00]), None);
+        // The setup header points to nowhere
+        let mut image = build_bzimage(TEST_KERNEL_VERSION);
+        image[0x20e..0x210].copy_from_slice(&0u16.to_le_bytes());
+        assert_eq!(extract_kernel_version(&image), None);
+    }
+}
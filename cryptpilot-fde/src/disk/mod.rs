@@ -1,12 +1,16 @@
-use std::path::{Path, PathBuf};
+use std::{
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context as _, Result};
 use async_trait::async_trait;
-use tokio::process::Command;
+use tokio::{fs::File, io::AsyncReadExt as _, process::Command};
 
 use crate::disk::{
     grub::{FdeDiskGrubExt, GrubBootArtifacts},
     partition_table::PartitionTableType,
+    pinned_kernel::{FdeDiskPinnedKernelExt, PinnedKernel},
     uki::{FdeDiskUkiExt, UkiBootArtifacts},
 };
 use cryptpilot::fs::cmd::CheckCommandOutput as _;
@@ -14,10 +18,28 @@ use cryptpilot::fs::cmd::CheckCommandOutput as _;
 pub mod artifacts;
 pub mod current;
 pub mod external;
-mod grub;
-mod kernel;
+pub mod gpt;
+pub mod grub;
+pub mod initrd;
+pub mod kernel;
+pub mod os_release;
+mod partition_dir;
+pub mod partition_role;
 mod partition_table;
-mod uki;
+pub mod pinned_kernel;
+pub mod uki;
+pub mod volume_group;
+
+/// The default maximum size of a file read from the disk, e.g. a kernel or an initrd.
+pub const DEFAULT_MAX_READ_SIZE: u64 = 2 * 1024 * 1024 * 1024;
+
+static MAX_READ_SIZE: AtomicU64 = AtomicU64::new(DEFAULT_MAX_READ_SIZE);
+
+/// Set the maximum size of a file read from the disk, so that a corrupted or hostile image
+/// claiming a huge file cannot exhaust the memory.
+pub fn set_max_read_size(size: u64) {
+    MAX_READ_SIZE.store(size, Ordering::Relaxed);
+}
 
 #[derive(Debug)]
 pub enum FdeBootType {
@@ -46,19 +68,31 @@ pub enum BootArtifactsType {
 
 #[async_trait]
 #[allow(private_bounds)]
-pub trait FdeDisk: FdeDiskGrubExt + FdeDiskUkiExt {
+pub trait FdeDisk: FdeDiskGrubExt + FdeDiskUkiExt + FdeDiskPinnedKernelExt {
     fn fde_boot_type(&self) -> FdeBootType;
 
-    async fn extract_boot_artifacts(&self) -> Result<BootArtifactsType> {
+    /// Extract boot artifacts from the disk. If `best_effort` is set, boot entries which fail to load are skipped with a warning, as long as at least one entry succeeds.
+    async fn extract_boot_artifacts(&self, best_effort: bool) -> Result<BootArtifactsType> {
         Ok(match self.fde_boot_type() {
             FdeBootType::NoFde => {
                 // The disk is not a FDE disk but we assume it is disk using grub as bootloader and extract all boot artifacts
-                BootArtifactsType::Grub(self.extract_boot_artifacts_grub().await?)
+                BootArtifactsType::Grub(self.extract_boot_artifacts_grub(best_effort).await?)
+            }
+            FdeBootType::Grub => {
+                BootArtifactsType::Grub(self.extract_boot_artifacts_grub(best_effort).await?)
             }
-            FdeBootType::Grub => BootArtifactsType::Grub(self.extract_boot_artifacts_grub().await?),
             FdeBootType::Uki => BootArtifactsType::Uki(self.extract_boot_artifacts_uki().await?),
         })
     }
+
+    /// Read the kernels in /boot whose filenames match the pattern, and the initrds paired with
+    /// them, regardless of the boot entries.
+    async fn extract_pinned_kernels(&self, pattern: &glob::Pattern) -> Result<Vec<PinnedKernel>> {
+        self.extract_pinned_kernel_artifacts(pattern).await
+    }
+
+    /// Read the os-release file from the rootfs of the disk. An encrypted rootfs of a cryptpilot FDE disk is opened with the passphrase in `rootfs_key_file`.
+    async fn read_os_release(&self, rootfs_key_file: Option<&Path>) -> Result<Vec<u8>>;
 }
 
 #[async_trait]
@@ -93,6 +127,23 @@ trait Disk {
     /// Get the path of block device where /boot is located
     fn get_boot_dir_located_dev(&self) -> Result<&Path>;
 
+    /// Find the partition on the disk containing /boot whose file system has the UUID
+    async fn find_partition_by_fs_uuid(&self, fs_uuid: &str) -> Result<Option<PathBuf>> {
+        let disk_device = self.get_disk_root_device(self.get_boot_dir_located_dev()?)?;
+        let stdout = Command::new("lsblk")
+            .args(["-lnpo", "NAME,UUID"])
+            .arg(&disk_device)
+            .run()
+            .await
+            .with_context(|| format!("Failed to list partitions of {disk_device:?}"))?;
+        Ok(String::from_utf8_lossy(&stdout).lines().find_map(|line| {
+            let mut fields = line.split_whitespace();
+            let (name, uuid) = (fields.next()?, fields.next()?);
+            uuid.eq_ignore_ascii_case(fs_uuid)
+                .then(|| PathBuf::from(name))
+        }))
+    }
+
     async fn read_file_on_disk_to_string(&self, path: &Path) -> Result<String> {
         self.read_file_on_disk(path)
             .await
@@ -103,9 +154,61 @@ trait Disk {
 
     async fn read_file_on_disk(&self, path: &Path) -> Result<Vec<u8>>;
 
+    /// List the names of the regular files directly in the directory, in sorted order.
+    async fn list_files_on_disk(&self, dir: &Path) -> Result<Vec<String>>;
+
     fn get_efi_part_root_dir(&self) -> &Path;
 }
 
+/// Read the whole file, failing if it is larger than the maximum read size set with
+/// [`set_max_read_size`].
+async fn read_file_with_limit(path: &Path) -> Result<Vec<u8>> {
+    let limit = MAX_READ_SIZE.load(Ordering::Relaxed);
+    let exceeded = || {
+        anyhow::anyhow!(
+            "The file {path:?} is larger than the maximum read size of {limit} bytes, which can be raised with --max-read-size"
+        )
+    };
+
+    let file = File::open(path)
+        .await
+        .with_context(|| format!("Failed to open {path:?}"))?;
+    let size = file.metadata().await?.len();
+    if size > limit {
+        return Err(exceeded());
+    }
+
+    // The size in the metadata is not trusted, e.g. the file may grow while reading it
+    let mut buf = Vec::with_capacity(size as usize);
+    file.take(limit.saturating_add(1))
+        .read_to_end(&mut buf)
+        .await
+        .with_context(|| format!("Failed to read {path:?}"))?;
+    if buf.len() as u64 > limit {
+        return Err(exceeded());
+    }
+    Ok(buf)
+}
+
+/// List the names of the regular files directly in the directory, in sorted order. Symlinks and
+/// files whose names are not valid UTF-8 are skipped.
+async fn list_files_in_dir(dir: &Path) -> Result<Vec<String>> {
+    let mut names = vec![];
+    let mut entries = tokio::fs::read_dir(dir)
+        .await
+        .with_context(|| format!("Failed to read directory {dir:?}"))?;
+    while let Some(entry) = entries.next_entry().await? {
+        if !entry.file_type().await?.is_file() {
+            continue;
+        }
+        if let Ok(name) = entry.file_name().into_string() {
+            names.push(name);
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
 pub async fn findmnt_of_dir(dir: &Path) -> Result<PathBuf> {
     let mut cmd = Command::new("findmnt");
     cmd.args(["-n", "-o", "SOURCE"]);
@@ -117,3 +220,29 @@ pub async fn findmnt_of_dir(dir: &Path) -> Result<PathBuf> {
     }
     Ok(dev)
 }
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_file_with_limit() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("initramfs.img");
+
+        // A sparse file claiming to be larger than the default limit fails without being read
+        File::create(&path)
+            .await?
+            .set_len(DEFAULT_MAX_READ_SIZE + 1)
+            .await?;
+        let error = read_file_with_limit(&path).await.unwrap_err();
+        assert!(format!("{error:#}").contains("larger than the maximum read size"));
+
+        tokio::fs::write(&path, vec![0x5a; 4096]).await?;
+        assert_eq!(read_file_with_limit(&path).await?, vec![0x5a; 4096]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,300 @@
+use std::{
+    ffi::OsString,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{bail, Context as _, Result};
+use indexmap::IndexMap;
+
+use crate::{
+    cmd::boot_service::stage::ROOTFS_LOGICAL_VOLUME, disk::volume_group::ActiveVolumeGroup,
+};
+use cryptpilot::{
+    fs::{block::devicemapper::dm_path, mount::TmpMountPoint},
+    types::{IntegrityType, Passphrase},
+};
+
+/// The paths of the os-release file, in the order of precedence defined by os-release(5).
+const OS_RELEASE_PATHS: [&str; 2] = ["/etc/os-release", "/usr/lib/os-release"];
+
+/// The fields of the os-release file emitted as reference values, which identify the OS image.
+pub const OS_RELEASE_FIELDS: [&str; 3] = ["ID", "VERSION_ID", "IMAGE_ID"];
+
+/// The maximum number of symlinks followed when resolving the os-release file.
+const MAX_SYMLINKS: usize = 40;
+
+/// Parse the content of an os-release file, which is a list of shell-compatible variable
+/// assignments. Lines which are empty, comments or not an assignment are skipped.
+pub fn parse_os_release(content: &str) -> IndexMap<String, String> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                return None;
+            }
+            let (key, value) = line.split_once('=')?;
+            Some((key.trim().to_owned(), unquote(value.trim())))
+        })
+        .collect()
+}
+
+fn unquote(value: &str) -> String {
+    if let Some(value) = value
+        .strip_prefix('\'')
+        .and_then(|value| value.strip_suffix('\''))
+    {
+        return value.to_owned();
+    }
+    let (value, escapable) = match value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+    {
+        Some(value) => (value, &['\\', '"', '$', '`'][..]),
+        None => (value, &['\\', '"', '\'', '$', '`', ' '][..]),
+    };
+    let mut unquoted = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(next) if escapable.contains(&next) => unquoted.push(next),
+                Some(next) => {
+                    unquoted.push(c);
+                    unquoted.push(next);
+                }
+                None => unquoted.push(c),
+            },
+            _ => unquoted.push(c),
+        }
+    }
+    unquoted
+}
+
+/// Push the components of the path onto the stack of the components to resolve, in reverse order
+/// so that they are popped in order. The root and the `.` components are dropped.
+fn push_components(pending: &mut Vec<OsString>, path: &Path) {
+    for component in path.components().rev() {
+        match component {
+            Component::Normal(name) => pending.push(name.to_owned()),
+            Component::ParentDir => pending.push("..".into()),
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+}
+
+/// Resolve the path inside the root directory component by component, as if the root directory
+/// were chrooted into: the symlinks are followed relative to the root directory instead of the
+/// host, e.g. `/etc/os-release -> /usr/lib/os-release`, and `..` never goes above the root, so
+/// that a symlink in an untrusted image cannot point to a file on the host.
+async fn resolve_in_root(root: &Path, path: &Path) -> Result<PathBuf> {
+    let mut pending = vec![];
+    push_components(&mut pending, path);
+
+    // The path resolved so far, relative to the root directory
+    let mut resolved = PathBuf::new();
+    let mut symlinks = 0;
+    while let Some(name) = pending.pop() {
+        if name == ".." {
+            resolved.pop();
+            continue;
+        }
+        let candidate = resolved.join(&name);
+        let real_path = root.join(&candidate);
+        match tokio::fs::symlink_metadata(&real_path).await {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                symlinks += 1;
+                if symlinks > MAX_SYMLINKS {
+                    bail!("Too many levels of symbolic links in {path:?}");
+                }
+                let link = tokio::fs::read_link(&real_path)
+                    .await
+                    .with_context(|| format!("Failed to read symlink {candidate:?}"))?;
+                if link.is_absolute() {
+                    resolved = PathBuf::new();
+                }
+                push_components(&mut pending, &link);
+            }
+            _ => resolved = candidate,
+        }
+    }
+    Ok(root.join(resolved))
+}
+
+/// Read the os-release file of the root file system mounted or extracted at `root`.
+pub async fn read_os_release_in_root(root: &Path) -> Result<Vec<u8>> {
+    for path in OS_RELEASE_PATHS {
+        let real_path = resolve_in_root(root, Path::new(path)).await?;
+        if real_path.is_file() {
+            tracing::debug!(?real_path, "Reading the os-release file {path}");
+            return tokio::fs::read(&real_path)
+                .await
+                .with_context(|| format!("Failed to read {path} in the root file system"));
+        }
+    }
+    bail!(
+        "No os-release file found in the root file system, tried {}",
+        OS_RELEASE_PATHS.join(", ")
+    )
+}
+
+/// Read the os-release file from the rootfs of a cryptpilot FDE disk connected to this system,
+/// by activating its LVM volume group and mounting the rootfs read-only. An encrypted rootfs is
+/// opened read-only with the passphrase in `rootfs_key_file` first.
+pub async fn read_os_release_of_fde_rootfs(rootfs_key_file: Option<&Path>) -> Result<Vec<u8>> {
+    let volume_group = ActiveVolumeGroup::activate().await?;
+    let result = read_os_release_of_active_rootfs(rootfs_key_file).await;
+    volume_group.deactivate().await;
+    result
+}
+
+async fn read_os_release_of_active_rootfs(rootfs_key_file: Option<&Path>) -> Result<Vec<u8>> {
+    let rootfs = Path::new(ROOTFS_LOGICAL_VOLUME);
+    let encrypted = cryptpilot::fs::luks2::get_luks_version(rootfs)
+        .await?
+        .is_some();
+    if !encrypted {
+        let mount = TmpMountPoint::mount(rootfs, false).await?;
+        return read_os_release_in_root(mount.mount_point()).await;
+    }
+
+    let Some(rootfs_key_file) = rootfs_key_file else {
+        bail!("The rootfs is encrypted, specify the file containing its passphrase with --rootfs-key-file to read the os-release file");
+    };
+    let passphrase = Passphrase::from(
+        tokio::fs::read(rootfs_key_file)
+            .await
+            .with_context(|| format!("Failed to read the rootfs key file {rootfs_key_file:?}"))?,
+    );
+    let name = format!("cryptpilot-inspect-rootfs-{}", std::process::id());
+    cryptpilot::fs::luks2::open_read_only_with_check_passphrase(
+        &name,
+        rootfs,
+        &passphrase,
+        IntegrityType::None,
+    )
+    .await
+    .context("Failed to open the encrypted rootfs with the passphrase in --rootfs-key-file")?;
+
+    let result = async {
+        let mount = TmpMountPoint::mount(dm_path(&name), false).await?;
+        read_os_release_in_root(mount.mount_point()).await
+    }
+    .await;
+
+    if let Err(error) = cryptpilot::fs::luks2::close(&name).await {
+        tracing::warn!(?error, "Failed to close the decrypted rootfs {name}");
+    }
+    result
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    const OS_RELEASE: &str = r#"NAME="Alibaba Cloud Linux"
+VERSION="3 (OpenAnolis Edition)"
+# A comment
+ID=alinux
+ID_LIKE="rhel fedora centos anolis"
+VERSION_ID='3'
+PRETTY_NAME="Alibaba Cloud Linux 3 \"Soaring Falcon\""
+IMAGE_ID=aliyun_3_x64_20G_alibase\ 20240819
+
+"#;
+
+    #[test]
+    fn test_parse_os_release() {
+        let fields = parse_os_release(OS_RELEASE);
+        assert_eq!(fields["NAME"], "Alibaba Cloud Linux");
+        assert_eq!(fields["ID"], "alinux");
+        assert_eq!(fields["ID_LIKE"], "rhel fedora centos anolis");
+        assert_eq!(fields["VERSION_ID"], "3");
+        assert_eq!(
+            fields["PRETTY_NAME"],
+            "Alibaba Cloud Linux 3 \"Soaring Falcon\""
+        );
+        assert_eq!(fields["IMAGE_ID"], "aliyun_3_x64_20G_alibase 20240819");
+        assert_eq!(fields.len(), 7);
+    }
+
+    #[tokio::test]
+    async fn test_read_os_release_in_root() -> Result<()> {
+        let root = tempfile::tempdir()?;
+        assert!(read_os_release_in_root(root.path()).await.is_err());
+
+        // /usr/lib/os-release is the fallback
+        tokio::fs::create_dir_all(root.path().join("usr/lib")).await?;
+        tokio::fs::write(root.path().join("usr/lib/os-release"), OS_RELEASE).await?;
+        assert_eq!(
+            read_os_release_in_root(root.path()).await?,
+            OS_RELEASE.as_bytes()
+        );
+
+        // The absolute symlink is resolved inside the root, not on the host
+        tokio::fs::create_dir_all(root.path().join("etc")).await?;
+        tokio::fs::symlink("/usr/lib/os-release", root.path().join("etc/os-release")).await?;
+        assert_eq!(
+            read_os_release_in_root(root.path()).await?,
+            OS_RELEASE.as_bytes()
+        );
+
+        // /etc/os-release takes precedence
+        tokio::fs::remove_file(root.path().join("etc/os-release")).await?;
+        tokio::fs::write(root.path().join("etc/os-release"), "ID=other\n").await?;
+        assert_eq!(read_os_release_in_root(root.path()).await?, b"ID=other\n");
+
+        // The relative symlink is resolved inside the root as well
+        tokio::fs::remove_file(root.path().join("etc/os-release")).await?;
+        tokio::fs::symlink(
+            "../../usr/./lib/os-release",
+            root.path().join("etc/os-release"),
+        )
+        .await?;
+        assert_eq!(
+            read_os_release_in_root(root.path()).await?,
+            OS_RELEASE.as_bytes()
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_escaping_symlink() -> Result<()> {
+        let host = tempfile::tempdir()?;
+        let host_file = host.path().join("shadow");
+        tokio::fs::write(&host_file, "SECRET").await?;
+
+        let root = tempfile::tempdir()?;
+        tokio::fs::create_dir_all(root.path().join("etc")).await?;
+        let escaping = format!("{}{}", "../".repeat(32), host_file.display());
+        tokio::fs::symlink(&escaping, root.path().join("etc/os-release")).await?;
+
+        // The `..` stops at the root, so the target is looked up inside the root
+        let resolved = resolve_in_root(root.path(), Path::new("/etc/os-release")).await?;
+        assert!(resolved.starts_with(root.path()), "{resolved:?}");
+        assert!(read_os_release_in_root(root.path()).await.is_err());
+
+        // The same for a symlink in the middle of the path
+        tokio::fs::remove_dir_all(root.path().join("etc")).await?;
+        tokio::fs::symlink(
+            format!("{}{}", "../".repeat(32), host.path().display()),
+            root.path().join("etc"),
+        )
+        .await?;
+        tokio::fs::write(host.path().join("os-release"), "ID=host\n").await?;
+        let resolved = resolve_in_root(root.path(), Path::new("/etc/os-release")).await?;
+        assert!(resolved.starts_with(root.path()), "{resolved:?}");
+        assert!(read_os_release_in_root(root.path()).await.is_err());
+
+        // A symlink loop
+        tokio::fs::symlink("/loop", root.path().join("loop")).await?;
+        assert!(resolve_in_root(root.path(), Path::new("/loop"))
+            .await
+            .is_err());
+
+        Ok(())
+    }
+}
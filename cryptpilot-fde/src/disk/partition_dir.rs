@@ -0,0 +1,187 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context as _, Result};
+use tempfile::TempDir;
+use tokio::process::Command;
+
+use cryptpilot::fs::{cmd::CheckCommandOutput as _, mount::TmpMountPoint};
+
+/// A directory holding the files of a partition on the disk.
+pub enum PartitionDir {
+    /// The partition is mounted on a temporary mount point.
+    Mounted(TmpMountPoint),
+    /// The files are copied out of the partition to a temporary directory, without mounting it.
+    /// Mounting a file system, even read-only, may replay its journal and so modify the disk,
+    /// which is undesirable for a forensic analysis.
+    Extracted(TempDir),
+}
+
+impl PartitionDir {
+    pub async fn mount(dev: &Path, writable: bool) -> Result<Self> {
+        Ok(Self::Mounted(TmpMountPoint::mount(dev, writable).await?))
+    }
+
+    /// Copy the directory `dir` (e.g. `/` or `/boot`) of the file system on the partition to a
+    /// temporary directory, under the same path, without mounting it. Only ext2/3/4, which are
+    /// read with `debugfs`, and vfat, which is read with `mcopy` of mtools, are supported. Both
+    /// open the partition read-only and never replay the journal.
+    pub async fn extract(dev: &Path, dir: &Path) -> Result<Self> {
+        let fs_type = probe_fs_type(dev).await?;
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("cryptpilot-extract-")
+            .tempdir()?;
+
+        tracing::debug!(?dev, ?dir, fs_type, "Extracting files without mounting");
+        match fs_type.as_str() {
+            "ext2" | "ext3" | "ext4" => extract_ext4(dev, dir, tmp_dir.path()).await,
+            "vfat" => extract_vfat(dev, dir, tmp_dir.path()).await,
+            _ => bail!(
+                "Reading files without mounting is only supported on ext4 and vfat, but the file system on {dev:?} is {fs_type}"
+            ),
+        }
+        .with_context(|| format!("Failed to extract {dir:?} from {dev:?} without mounting"))?;
+
+        Ok(Self::Extracted(tmp_dir))
+    }
+
+    pub fn path(&self) -> &Path {
+        match self {
+            PartitionDir::Mounted(tmp_mount) => tmp_mount.mount_point(),
+            PartitionDir::Extracted(tmp_dir) => tmp_dir.path(),
+        }
+    }
+}
+
+async fn probe_fs_type(dev: &Path) -> Result<String> {
+    let stdout = Command::new("blkid")
+        .args(["--probe", "--match-tag", "TYPE", "--output", "value"])
+        .arg(dev)
+        .run()
+        .await
+        .with_context(|| format!("Failed to detect the file system on {dev:?}"))?;
+    Ok(String::from_utf8_lossy(&stdout).trim().to_owned())
+}
+
+/// The directory in `target` to which `dir` of the file system is copied.
+fn target_dir_of(dir: &Path, target: &Path) -> Result<PathBuf> {
+    if !dir.is_absolute() {
+        bail!("The path {dir:?} should be an absolute path in the file system");
+    }
+    Ok(target.join(dir.strip_prefix("/")?))
+}
+
+async fn extract_ext4(dev: &Path, dir: &Path, target: &Path) -> Result<()> {
+    let target_dir = target_dir_of(dir, target)?;
+    // `rdump /boot <dir>` creates `<dir>/boot`, while `rdump / <dir>` dumps the content into `<dir>`
+    let dump_to = match target_dir.parent() {
+        Some(parent) if dir != Path::new("/") => parent.to_path_buf(),
+        _ => target_dir.clone(),
+    };
+    tokio::fs::create_dir_all(&dump_to).await?;
+
+    Command::new("debugfs")
+        .arg("-R")
+        .arg(format!(
+            "rdump \"{}\" \"{}\"",
+            dir.to_string_lossy(),
+            dump_to.to_string_lossy()
+        ))
+        .arg(dev)
+        .run_with_status_checker(|code, _, stderr| {
+            if code != 0 {
+                bail!("Bad exit code")
+            }
+            // debugfs exits with 0 even if it fails to dump a file, and only reports it on stderr
+            // after the version banner
+            let stderr = String::from_utf8_lossy(&stderr);
+            let errors = stderr
+                .lines()
+                .filter(|line| !line.starts_with("debugfs ") && !line.trim().is_empty())
+                .collect::<Vec<_>>();
+            if !errors.is_empty() {
+                bail!("{}", errors.join("\n"))
+            }
+            Ok(())
+        })
+        .await?;
+
+    if !target_dir.is_dir() {
+        bail!("The directory {dir:?} is not found");
+    }
+    Ok(())
+}
+
+async fn extract_vfat(dev: &Path, dir: &Path, target: &Path) -> Result<()> {
+    let target_dir = target_dir_of(dir, target)?;
+    tokio::fs::create_dir_all(&target_dir).await?;
+
+    Command::new("mcopy")
+        // Copy recursively, preserving the attributes and modification times
+        .args(["-s", "-p", "-m", "-n", "-i"])
+        .arg(dev)
+        .arg(format!(
+            "::{}/*",
+            dir.to_string_lossy().trim_end_matches('/')
+        ))
+        .arg(&target_dir)
+        // The partition does not have to match the drive geometry of a floppy disk
+        .env("MTOOLS_SKIP_CHECK", "1")
+        .run()
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[tokio::test]
+    async fn test_extract_ext4() -> Result<()> {
+        let src = tempfile::tempdir()?;
+        tokio::fs::create_dir_all(src.path().join("boot/grub2")).await?;
+        tokio::fs::write(src.path().join("boot/grub2/grub.cfg"), "menuentry").await?;
+        tokio::fs::write(src.path().join("boot/vmlinuz-6.6.0"), "kernel").await?;
+        tokio::fs::symlink("vmlinuz-6.6.0", src.path().join("boot/vmlinuz")).await?;
+        tokio::fs::write(src.path().join("etc-secret"), "not extracted").await?;
+
+        let dir = tempfile::tempdir()?;
+        let image = dir.path().join("root.img");
+        tokio::fs::File::create(&image)
+            .await?
+            .set_len(32 * 1024 * 1024)
+            .await?;
+        Command::new("mkfs.ext4")
+            .arg("-q")
+            .arg("-d")
+            .arg(src.path())
+            .arg(&image)
+            .run()
+            .await?;
+
+        // Only the requested directory is extracted, under the same path
+        let partition_dir = PartitionDir::extract(&image, Path::new("/boot")).await?;
+        let path = partition_dir.path();
+        assert_eq!(
+            tokio::fs::read_to_string(path.join("boot/grub2/grub.cfg")).await?,
+            "menuentry"
+        );
+        assert_eq!(
+            tokio::fs::read_link(path.join("boot/vmlinuz")).await?,
+            Path::new("vmlinuz-6.6.0")
+        );
+        assert!(!path.join("etc-secret").exists());
+
+        // The whole file system
+        let partition_dir = PartitionDir::extract(&image, Path::new("/")).await?;
+        assert!(partition_dir.path().join("etc-secret").exists());
+        assert!(partition_dir.path().join("boot/vmlinuz-6.6.0").exists());
+
+        assert!(PartitionDir::extract(&image, Path::new("/nonexist"))
+            .await
+            .is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,224 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context as _, Result};
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+use cryptpilot::fs::cmd::CheckCommandOutput as _;
+
+/// The role of a partition, identified by its GPT partition type GUID as defined in the
+/// Discoverable Partitions Specification:
+/// https://uapi-group.org/specifications/specs/discoverable_partitions_specification/
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PartitionRole {
+    /// EFI System Partition, mounted on `/boot/efi`
+    Esp,
+    /// Extended Boot Loader Partition (XBOOTLDR), mounted on `/boot`
+    Boot,
+    /// Root partition for the architecture of the system
+    Root,
+    /// Data partitions, e.g. `/home`, `/srv` or `/var`
+    Data,
+}
+
+const ESP_TYPE_GUID: &str = "c12a7328-f81f-11d2-ba4b-00a0c93ec93b";
+const XBOOTLDR_TYPE_GUID: &str = "bc13c2ff-59e6-4262-a352-b275fd6f7172";
+#[cfg(target_arch = "x86_64")]
+const ROOT_TYPE_GUID: &str = "4f68bce3-e8cd-4db1-96e7-fbcaf984b709";
+#[cfg(target_arch = "aarch64")]
+const ROOT_TYPE_GUID: &str = "b921b045-1df0-41c3-af44-4c6f280d3fae";
+const DATA_TYPE_GUIDS: &[&str] = &[
+    // Home partition
+    "933ac7e1-2eb4-4f13-b844-0e14e2aef915",
+    // Server data partition
+    "3b8f8425-20e0-4f3b-907f-1a25a76f98e8",
+    // Variable data partition
+    "4d21b016-b534-45c2-a9fb-5c16e091fd2d",
+];
+
+impl std::fmt::Display for PartitionRole {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PartitionRole::Esp => write!(f, "esp"),
+            PartitionRole::Boot => write!(f, "boot"),
+            PartitionRole::Root => write!(f, "root"),
+            PartitionRole::Data => write!(f, "data"),
+        }
+    }
+}
+
+impl PartitionRole {
+    /// Get the role of the partition from its GPT partition type GUID, which is case-insensitive.
+    pub fn from_type_guid(type_guid: &str) -> Option<Self> {
+        let type_guid = type_guid.to_ascii_lowercase();
+        match type_guid.as_str() {
+            ESP_TYPE_GUID => Some(PartitionRole::Esp),
+            XBOOTLDR_TYPE_GUID => Some(PartitionRole::Boot),
+            #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+            ROOT_TYPE_GUID => Some(PartitionRole::Root),
+            guid if DATA_TYPE_GUIDS.contains(&guid) => Some(PartitionRole::Data),
+            _ => None,
+        }
+    }
+
+    /// Find the partition with this role on the disk by its GPT partition type GUID. Returns
+    /// `None` if the disk has no GPT partition table, or no partition has the type GUID of the role.
+    pub async fn find_partition(&self, disk_dev: &Path) -> Result<Option<PathBuf>> {
+        let partitions = list_partition_roles(disk_dev).await?;
+        let mut candidates = partitions
+            .into_iter()
+            .filter(|(_, role)| role == self)
+            .map(|(node, _)| node);
+        let partition = candidates.next();
+        if let Some(partition) = &partition {
+            if candidates.next().is_some() {
+                tracing::warn!(
+                    "Found multiple partitions with the GPT type GUID of {self:?} on {disk_dev:?}, using the first one {partition:?}"
+                );
+            }
+        }
+        Ok(partition)
+    }
+}
+
+#[derive(Deserialize)]
+struct SfdiskDump {
+    partitiontable: SfdiskPartitionTable,
+}
+
+#[derive(Deserialize)]
+struct SfdiskPartitionTable {
+    label: String,
+    #[serde(default)]
+    partitions: Vec<SfdiskPartition>,
+}
+
+#[derive(Deserialize)]
+struct SfdiskPartition {
+    node: PathBuf,
+    #[serde(rename = "type")]
+    type_guid: String,
+}
+
+/// List the partitions on the disk which have a known role, in the order of the partition table.
+pub async fn list_partition_roles(disk_dev: &Path) -> Result<Vec<(PathBuf, PartitionRole)>> {
+    let stdout = Command::new("sfdisk")
+        .arg("--json")
+        .arg(disk_dev)
+        .run()
+        .await
+        .with_context(|| format!("Failed to dump the partition table of {disk_dev:?}"))?;
+    parse_partition_roles(&stdout)
+        .with_context(|| format!("Failed to parse the partition table of {disk_dev:?}"))
+}
+
+fn parse_partition_roles(sfdisk_json: &[u8]) -> Result<Vec<(PathBuf, PartitionRole)>> {
+    let dump: SfdiskDump = serde_json::from_slice(sfdisk_json)?;
+    if dump.partitiontable.label != "gpt" {
+        return Ok(vec![]);
+    }
+
+    Ok(dump
+        .partitiontable
+        .partitions
+        .into_iter()
+        .filter_map(|partition| {
+            PartitionRole::from_type_guid(&partition.type_guid).map(|role| (partition.node, role))
+        })
+        .collect())
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn test_from_type_guid() {
+        assert_eq!(
+            PartitionRole::from_type_guid("C12A7328-F81F-11D2-BA4B-00A0C93EC93B"),
+            Some(PartitionRole::Esp)
+        );
+        assert_eq!(
+            PartitionRole::from_type_guid("bc13c2ff-59e6-4262-a352-b275fd6f7172"),
+            Some(PartitionRole::Boot)
+        );
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!(
+            PartitionRole::from_type_guid("4F68BCE3-E8CD-4DB1-96E7-FBCAF984B709"),
+            Some(PartitionRole::Root)
+        );
+        assert_eq!(
+            PartitionRole::from_type_guid("933AC7E1-2EB4-4F13-B844-0E14E2AEF915"),
+            Some(PartitionRole::Data)
+        );
+        // Linux filesystem data, which has no specific role
+        assert_eq!(
+            PartitionRole::from_type_guid("0FC63DAF-8483-4772-8E79-3D69D8477DE4"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse_partition_roles() -> Result<()> {
+        let roles = parse_partition_roles(
+            br#"{
+                "partitiontable": {
+                    "label": "dos",
+                    "device": "/dev/nbd0",
+                    "unit": "sectors",
+                    "partitions": [
+                        {"node": "/dev/nbd0p1", "start": 2048, "size": 204800, "type": "ef"}
+                    ]
+                }
+            }"#,
+        )?;
+        assert!(roles.is_empty());
+
+        assert!(parse_partition_roles(b"{}").is_err());
+        Ok(())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[tokio::test]
+    async fn test_list_partition_roles_on_gpt_disk() -> Result<()> {
+        let disk = tempfile::NamedTempFile::new()?;
+        disk.as_file().set_len(64 * 1024 * 1024)?;
+
+        // A disk laid out according to the Discoverable Partitions Specification, with a partition
+        // of the generic Linux data type which has no role.
+        Command::new("sfdisk")
+            .arg(disk.path())
+            .run_with_input(Some(
+                "label: gpt\n\
+                 size=8MiB, type=C12A7328-F81F-11D2-BA4B-00A0C93EC93B, name=\"EFI System\"\n\
+                 size=8MiB, type=BC13C2FF-59E6-4262-A352-B275FD6F7172, name=\"misleading\"\n\
+                 size=8MiB, type=0FC63DAF-8483-4772-8E79-3D69D8477DE4, name=\"boot\"\n\
+                 size=16MiB, type=4F68BCE3-E8CD-4DB1-96E7-FBCAF984B709\n\
+                 type=933AC7E1-2EB4-4F13-B844-0E14E2AEF915\n"
+                    .as_bytes(),
+            ))
+            .await?;
+
+        let roles = list_partition_roles(disk.path()).await?;
+        let node = |n: usize| PathBuf::from(format!("{}{n}", disk.path().display()));
+        assert_eq!(
+            roles,
+            vec![
+                (node(1), PartitionRole::Esp),
+                (node(2), PartitionRole::Boot),
+                (node(4), PartitionRole::Root),
+                (node(5), PartitionRole::Data),
+            ]
+        );
+
+        // The type GUID is preferred over the partition name
+        assert_eq!(
+            PartitionRole::Boot.find_partition(disk.path()).await?,
+            Some(node(2))
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,315 @@
+use std::path::Path;
+
+use anyhow::{bail, Context as _, Result};
+use async_trait::async_trait;
+use indexmap::IndexMap;
+
+use crate::disk::Disk;
+
+/// The directory in which the kernels pinned with `--kernels` are searched.
+const BOOT_DIR: &str = "/boot";
+
+/// The prefix of the `measurement.kernel:<filename>.<hash-algo>` reference values of a pinned
+/// kernel.
+pub const PINNED_KERNEL_COMPONENT_PREFIX: &str = "kernel:";
+
+/// The prefix of the `measurement.initrd:<filename>.<hash-algo>` reference values of the initrd
+/// paired with a pinned kernel, keyed by the filename of the kernel.
+pub const PINNED_INITRD_COMPONENT_PREFIX: &str = "initrd:";
+
+/// A kernel found in `/boot` by its filename, and the initrd paired with it.
+#[derive(Debug)]
+pub struct PinnedKernel {
+    /// The filename of the kernel in `/boot`, e.g. `vmlinuz-5.10.134-16.al8.x86_64`.
+    pub name: String,
+    pub kernel: Vec<u8>,
+    pub initrd: Vec<u8>,
+}
+
+/// Parse the glob pattern of the kernel filenames, e.g. `vmlinuz-5.10.*` or `/boot/vmlinuz-*`.
+/// The pattern is matched against the filenames directly in `/boot`, so that it can never select a
+/// file outside of it.
+pub fn parse_kernel_glob(pattern: &str) -> Result<glob::Pattern> {
+    let name_pattern = pattern
+        .strip_prefix(BOOT_DIR)
+        .and_then(|rest| rest.strip_prefix('/'))
+        .unwrap_or(pattern);
+    if name_pattern.is_empty()
+        || name_pattern.contains('/')
+        || name_pattern == "."
+        || name_pattern == ".."
+    {
+        bail!("The pattern {pattern:?} of --kernels should match the filenames of the kernels directly in {BOOT_DIR}, e.g. \"vmlinuz-*\"");
+    }
+    glob::Pattern::new(name_pattern)
+        .with_context(|| format!("Invalid pattern {pattern:?} of --kernels"))
+}
+
+/// The filenames of the initrd paired with the kernel, following the naming conventions of
+/// dracut (`initramfs-<version>.img`), Debian (`initrd.img-<version>`) and SUSE
+/// (`initrd-<version>`).
+fn initrd_candidates(kernel_name: &str) -> Vec<String> {
+    let version = kernel_name
+        .strip_prefix("vmlinuz-")
+        .or_else(|| kernel_name.strip_prefix("vmlinux-"))
+        .or_else(|| kernel_name.strip_prefix("Image-"))
+        .unwrap_or(kernel_name);
+    vec![
+        format!("initramfs-{version}.img"),
+        format!("initrd.img-{version}"),
+        format!("initrd-{version}"),
+    ]
+}
+
+#[async_trait]
+pub(super) trait FdeDiskPinnedKernelExt: Disk {
+    /// Read every kernel in `/boot` whose filename matches the pattern, and the initrd paired with
+    /// it, regardless of the boot entries. Only regular files are matched, so that a symlink (e.g.
+    /// `/boot/vmlinuz`) cannot point outside of `/boot` or duplicate another kernel. Fails if no
+    /// kernel matches, or if a kernel has no initrd.
+    async fn extract_pinned_kernel_artifacts(
+        &self,
+        pattern: &glob::Pattern,
+    ) -> Result<Vec<PinnedKernel>> {
+        let boot_dir = Path::new(BOOT_DIR);
+        let match_options = glob::MatchOptions {
+            require_literal_leading_dot: true,
+            ..Default::default()
+        };
+
+        let file_names = self
+            .list_files_on_disk(boot_dir)
+            .await
+            .with_context(|| format!("Failed to list the files in {boot_dir:?}"))?;
+        let mut pinned_kernels = vec![];
+        for name in file_names
+            .iter()
+            .filter(|name| pattern.matches_with(name, match_options))
+        {
+            let candidates = initrd_candidates(name);
+            let Some(initrd_name) = candidates
+                .iter()
+                .find(|candidate| file_names.contains(candidate))
+            else {
+                bail!(
+                    "No initrd found in {boot_dir:?} for the kernel {name}, tried {}",
+                    candidates.join(", ")
+                );
+            };
+            tracing::debug!(kernel = name, initrd = initrd_name, "Found pinned kernel");
+
+            let kernel_path = boot_dir.join(name);
+            let kernel = self
+                .read_file_on_disk(&kernel_path)
+                .await
+                .with_context(|| format!("Failed to read kernel file at {kernel_path:?}"))?;
+            let initrd_path = boot_dir.join(initrd_name);
+            let initrd = self
+                .read_file_on_disk(&initrd_path)
+                .await
+                .with_context(|| format!("Failed to read initrd file at {initrd_path:?}"))?;
+            pinned_kernels.push(PinnedKernel {
+                name: name.clone(),
+                kernel,
+                initrd,
+            });
+        }
+
+        if pinned_kernels.is_empty() {
+            bail!(
+                "No kernel in {boot_dir:?} matches the pattern \"{}\" of --kernels",
+                pattern.as_str()
+            );
+        }
+        Ok(pinned_kernels)
+    }
+}
+
+/// Insert the hashes of the pinned kernels and their initrds, as
+/// `measurement.kernel:<filename>.<hash-algo>` and `measurement.initrd:<filename>.<hash-algo>`,
+/// where `<filename>` is the filename of the kernel.
+pub fn insert_pinned_kernel_reference_value<T>(
+    pinned_kernels: &[PinnedKernel],
+    map: &mut IndexMap<String, Vec<String>>,
+    hash_key: &str,
+) where
+    T: digest::Digest,
+{
+    for PinnedKernel {
+        name,
+        kernel,
+        initrd,
+    } in pinned_kernels
+    {
+        map.insert(
+            format!("measurement.{PINNED_KERNEL_COMPONENT_PREFIX}{name}.{hash_key}"),
+            vec![hex::encode(T::digest(kernel))],
+        );
+        map.insert(
+            format!("measurement.{PINNED_INITRD_COMPONENT_PREFIX}{name}.{hash_key}"),
+            vec![hex::encode(T::digest(initrd))],
+        );
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+    use std::path::PathBuf;
+
+    /// A disk whose files are the ones in a temporary directory.
+    struct TestDisk {
+        root: tempfile::TempDir,
+    }
+
+    impl TestDisk {
+        fn path_on_disk(&self, path: &Path) -> PathBuf {
+            self.root
+                .path()
+                .join(path.strip_prefix("/").unwrap_or(path))
+        }
+    }
+
+    #[async_trait]
+    impl Disk for TestDisk {
+        fn check_file_exist_on_disk(&self, path: &Path) -> Result<bool> {
+            Ok(self.path_on_disk(path).exists())
+        }
+
+        async fn read_file_on_disk(&self, path: &Path) -> Result<Vec<u8>> {
+            Ok(tokio::fs::read(self.path_on_disk(path)).await?)
+        }
+
+        async fn list_files_on_disk(&self, dir: &Path) -> Result<Vec<String>> {
+            crate::disk::list_files_in_dir(&self.path_on_disk(dir)).await
+        }
+
+        fn get_boot_dir_located_dev(&self) -> Result<&Path> {
+            Ok(Path::new("/dev/vda2"))
+        }
+
+        fn get_efi_part_root_dir(&self) -> &Path {
+            Path::new("/boot/efi")
+        }
+    }
+
+    #[async_trait]
+    impl FdeDiskPinnedKernelExt for TestDisk {}
+
+    async fn new_test_disk(files: &[(&str, &[u8])]) -> Result<TestDisk> {
+        let root = tempfile::tempdir()?;
+        tokio::fs::create_dir_all(root.path().join("boot/grub2")).await?;
+        for (path, content) in files {
+            tokio::fs::write(root.path().join(path), content).await?;
+        }
+        Ok(TestDisk { root })
+    }
+
+    #[test]
+    fn test_parse_kernel_glob() -> Result<()> {
+        assert_eq!(parse_kernel_glob("vmlinuz-*")?.as_str(), "vmlinuz-*");
+        assert_eq!(
+            parse_kernel_glob("/boot/vmlinuz-5.*")?.as_str(),
+            "vmlinuz-5.*"
+        );
+
+        // Patterns which may match a file outside of /boot
+        for pattern in [
+            "../etc/*",
+            "/etc/vmlinuz-*",
+            "/boot/../vmlinuz-*",
+            "grub2/*",
+            "/boot/",
+            "/bootvmlinuz-*",
+            "..",
+            "",
+        ] {
+            assert!(parse_kernel_glob(pattern).is_err(), "{pattern:?}");
+        }
+        assert!(parse_kernel_glob("vmlinuz-[").is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_extract_pinned_kernels() -> Result<()> {
+        let disk = new_test_disk(&[
+            ("boot/vmlinuz-5.10.134-16.al8.x86_64", b"kernel-5.10"),
+            ("boot/initramfs-5.10.134-16.al8.x86_64.img", b"initrd-5.10"),
+            ("boot/vmlinuz-6.6.88-1.al8.x86_64", b"kernel-6.6"),
+            ("boot/initrd.img-6.6.88-1.al8.x86_64", b"initrd-6.6"),
+            ("boot/.vmlinuz-6.6.88-1.al8.x86_64.hmac", b"hmac"),
+            ("boot/vmlinuz-0-rescue-a1b2c3", b"kernel-rescue"),
+            ("boot/grub2/vmlinuz-nested", b"nested"),
+        ])
+        .await?;
+        // A symlink to a kernel is not a kernel on its own
+        tokio::fs::symlink(
+            "vmlinuz-6.6.88-1.al8.x86_64",
+            disk.path_on_disk(Path::new("/boot/vmlinuz")),
+        )
+        .await?;
+
+        let pinned_kernels = disk
+            .extract_pinned_kernel_artifacts(&parse_kernel_glob("vmlinuz-*.al8.*")?)
+            .await?;
+        assert_eq!(
+            pinned_kernels
+                .iter()
+                .map(|pinned| (
+                    pinned.name.as_str(),
+                    pinned.kernel.as_slice(),
+                    pinned.initrd.as_slice()
+                ))
+                .collect::<Vec<_>>(),
+            [
+                (
+                    "vmlinuz-5.10.134-16.al8.x86_64",
+                    &b"kernel-5.10"[..],
+                    &b"initrd-5.10"[..]
+                ),
+                (
+                    "vmlinuz-6.6.88-1.al8.x86_64",
+                    &b"kernel-6.6"[..],
+                    &b"initrd-6.6"[..]
+                ),
+            ]
+        );
+
+        let mut map = IndexMap::new();
+        insert_pinned_kernel_reference_value::<sha2::Sha256>(&pinned_kernels, &mut map, "SHA-256");
+        assert_eq!(
+            map.keys().collect::<Vec<_>>(),
+            [
+                "measurement.kernel:vmlinuz-5.10.134-16.al8.x86_64.SHA-256",
+                "measurement.initrd:vmlinuz-5.10.134-16.al8.x86_64.SHA-256",
+                "measurement.kernel:vmlinuz-6.6.88-1.al8.x86_64.SHA-256",
+                "measurement.initrd:vmlinuz-6.6.88-1.al8.x86_64.SHA-256",
+            ]
+        );
+        assert_eq!(
+            map["measurement.initrd:vmlinuz-6.6.88-1.al8.x86_64.SHA-256"],
+            [hex::encode(<sha2::Sha256 as digest::Digest>::digest(
+                "initrd-6.6"
+            ))]
+        );
+
+        // The rescue kernel has no initrd
+        let error = disk
+            .extract_pinned_kernel_artifacts(&parse_kernel_glob("vmlinuz-*")?)
+            .await
+            .unwrap_err();
+        assert!(format!("{error:#}").contains("No initrd found"));
+
+        // Nothing matches
+        let error = disk
+            .extract_pinned_kernel_artifacts(&parse_kernel_glob("vmlinuz-4.*")?)
+            .await
+            .unwrap_err();
+        assert!(format!("{error:#}").contains("No kernel in"));
+
+        Ok(())
+    }
+}
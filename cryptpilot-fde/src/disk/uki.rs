@@ -1,4 +1,4 @@
-use std::path::Path;
+use std::path::PathBuf;
 
 use anyhow::{bail, Context, Result};
 use async_trait::async_trait;
@@ -20,8 +20,13 @@ pub struct UkiBootArtifacts {
 
 #[async_trait]
 pub(super) trait FdeDiskUkiExt: Disk {
+    /// The path of the UKI image to calculate reference values for.
+    fn uki_file_path(&self) -> PathBuf {
+        PathBuf::from(UKI_FILE_PATH)
+    }
+
     async fn extract_boot_artifacts_uki(&self) -> Result<UkiBootArtifacts> {
-        let uki_data = self.read_file_on_disk(Path::new(UKI_FILE_PATH)).await?;
+        let uki_data = self.read_file_on_disk(&self.uki_file_path()).await?;
 
         assume_uki_image(&uki_data)?;
 
@@ -70,6 +75,7 @@ impl BootArtifacts for UkiBootArtifacts {
         &self,
         map: &mut indexmap::IndexMap<String, Vec<String>>,
         hash_key: &str,
+        _best_effort: bool,
     ) -> Result<()>
     where
         T: digest::Digest + digest::Update,
@@ -0,0 +1,46 @@
+use std::path::Path;
+
+use anyhow::{bail, Context as _, Result};
+use tokio::process::Command;
+
+use crate::cmd::boot_service::stage::{ROOTFS_LOGICAL_VOLUME, VOLUME_GROUP_NAME};
+use cryptpilot::fs::cmd::CheckCommandOutput as _;
+
+/// The LVM volume group of a cryptpilot FDE disk connected to this system (e.g. via nbd), which
+/// is activated to access the logical volumes on it.
+pub struct ActiveVolumeGroup {
+    _private: (),
+}
+
+impl ActiveVolumeGroup {
+    /// Activate the volume group. Fails if it is already active, e.g. on a system booted from a
+    /// cryptpilot FDE disk, since the volume groups of both disks share the same name.
+    pub async fn activate() -> Result<Self> {
+        if Path::new(ROOTFS_LOGICAL_VOLUME).exists() {
+            bail!("The LVM volume group '{VOLUME_GROUP_NAME}' is already active on this system, please deactivate it before inspecting another disk");
+        }
+        Command::new("vgchange")
+            .args(["-a", "y", VOLUME_GROUP_NAME])
+            .run()
+            .await
+            .with_context(|| {
+                format!("Failed to activate LVM volume group '{VOLUME_GROUP_NAME}'")
+            })?;
+        Ok(Self { _private: () })
+    }
+
+    /// Deactivate the volume group. A failure is only logged, since the result of the inspection
+    /// is still valid.
+    pub async fn deactivate(self) {
+        if let Err(error) = Command::new("vgchange")
+            .args(["-a", "n", VOLUME_GROUP_NAME])
+            .run()
+            .await
+        {
+            tracing::warn!(
+                ?error,
+                "Failed to deactivate LVM volume group '{VOLUME_GROUP_NAME}'"
+            );
+        }
+    }
+}
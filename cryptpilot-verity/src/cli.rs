@@ -10,6 +10,31 @@ fn parse_label(s: &str) -> Result<(String, String), String> {
     Ok((key.to_string(), value.to_string()))
 }
 
+fn parse_salt(s: &str) -> Result<Vec<u8>, String> {
+    let salt = hex::decode(s).map_err(|e| format!("invalid salt '{}': {}", s, e))?;
+    if salt.len() > verity_core::config::MAX_SALT_SIZE {
+        return Err(format!(
+            "salt is {} bytes long, which exceeds the maximum of {} bytes",
+            salt.len(),
+            verity_core::config::MAX_SALT_SIZE
+        ));
+    }
+    Ok(salt)
+}
+
+/// Parse the hash algorithm of the fs-verity Merkle tree, rejecting the ones which cannot be opened
+/// or verified yet.
+fn parse_hash_algorithm(s: &str) -> Result<verity_core::config::InnerHashAlgorithm, String> {
+    match s.parse() {
+        Ok(verity_core::config::InnerHashAlgorithm::Sha256) => {
+            Ok(verity_core::config::InnerHashAlgorithm::Sha256)
+        }
+        _ => Err(format!(
+            "unsupported hash algorithm '{s}', only 'sha256' is supported"
+        )),
+    }
+}
+
 use crate::build::CLAP_LONG_VERSION;
 
 #[derive(Parser, Debug)]
@@ -66,6 +91,20 @@ pub struct FormatOptions {
     /// Label in key=value format. Can be specified multiple times.
     #[arg(long = "label", value_parser = parse_label)]
     pub labels: Vec<(String, String)>,
+
+    /// Hash algorithm of the fs-verity Merkle tree. Only "sha256" is supported currently.
+    #[arg(long, default_value = "sha256", value_parser = parse_hash_algorithm)]
+    pub hash_algorithm: verity_core::config::InnerHashAlgorithm,
+
+    /// [optional] Salt in hex (up to 32 bytes) mixed into every hash of the fs-verity Merkle tree.
+    /// If not specified, no salt is used. Either way the root hash is deterministic for the same data.
+    #[arg(long, value_parser = parse_salt)]
+    pub salt: Option<Vec<u8>>,
+
+    /// [optional] Only cover the first N blocks (4096 bytes each) of every file, for testing.
+    /// A file larger than that fails to verify against the resulting metadata.
+    #[arg(long)]
+    pub data_blocks: Option<u64>,
 }
 
 #[derive(Parser, Debug)]
@@ -88,6 +127,12 @@ pub struct VerifyOptions {
     /// without verifying individual file contents against their descriptors
     #[arg(long, default_value = "false")]
     pub metadata_only: bool,
+
+    /// Output a structured result as JSON, with the expected and computed root hash,
+    /// the number of data blocks verified, and the offset of the first corrupt block of each file.
+    /// The whole data directory is scanned instead of aborting on the first error
+    #[arg(long, default_value = "false")]
+    pub json: bool,
 }
 
 #[derive(Parser, Debug)]
@@ -152,3 +197,33 @@ pub struct CloseOptions {
     #[arg()]
     pub mount_point: std::path::PathBuf,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_hash_algorithm() {
+        let parse = |algorithm: &str| {
+            Cli::try_parse_from([
+                "cryptpilot-verity",
+                "format",
+                "/data",
+                "--hash-output",
+                "/data.hash",
+                "--hash-algorithm",
+                algorithm,
+            ])
+        };
+        match parse("sha256").unwrap().command {
+            VeritySubcommand::Format(options) => assert_eq!(
+                options.hash_algorithm,
+                verity_core::config::InnerHashAlgorithm::Sha256
+            ),
+            command => panic!("unexpected command {command:?}"),
+        }
+        // Not supported by open and verify yet
+        assert!(parse("sha512").is_err());
+        assert!(parse("md5").is_err());
+    }
+}
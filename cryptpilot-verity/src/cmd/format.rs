@@ -4,6 +4,7 @@ use async_walkdir::WalkDir;
 use futures::StreamExt;
 use std::path::{Path, PathBuf};
 use tokio::fs;
+use verity_core::config::InnerHashAlgorithm;
 use verity_fuse::file_verifier::file_verity_info::FileVerityInfo;
 
 use crate::cmd::{Command, DEFAULT_METADATA_FILE};
@@ -40,6 +41,23 @@ impl Command for FormatCommand {
             );
         }
 
+        if self.options.hash_algorithm != InnerHashAlgorithm::Sha256 {
+            anyhow::bail!(
+                "Hash algorithm {} is not supported yet, only sha256 is supported by verity-fuse",
+                self.options.hash_algorithm
+            );
+        }
+        let salt = self.options.salt.as_deref().unwrap_or_default();
+        tracing::info!(
+            "Hash algorithm: {}, salt: {}",
+            self.options.hash_algorithm,
+            if salt.is_empty() {
+                "<none>".to_string()
+            } else {
+                hex::encode(salt)
+            }
+        );
+
         // Collect all file paths
         let mut files = Vec::new();
         self.collect_files(&self.options.data_dir, &mut files)
@@ -54,10 +72,13 @@ impl Command for FormatCommand {
         let mut file_infos = Vec::new();
         for file_path in files {
             tracing::debug!("Processing file: {:?}", file_path);
-            let content = fs::read(&file_path).await?;
 
-            // Calculate fs-verity hash
-            let (descriptor, merkle_tree) = crate::metadata::calculate_fsverity_hash(&content);
+            // Calculate fs-verity hash, skipping the holes of sparse files
+            let (descriptor, merkle_tree) = crate::metadata::calculate_fsverity_hash_of_file(
+                &file_path,
+                salt,
+                self.options.data_blocks,
+            )?;
 
             let relative_path = file_path
                 .strip_prefix(&self.options.data_dir)?
@@ -65,6 +86,15 @@ impl Command for FormatCommand {
             let path_str = relative_path.to_string_lossy().to_string();
 
             let descriptor_hash = hex::encode(descriptor.to_descriptor_hash());
+            if self.options.data_blocks.is_some()
+                && fs::metadata(&file_path).await?.len() > descriptor.data_size
+            {
+                tracing::warn!(
+                    "File {:?} is only covered up to {} bytes due to --data-blocks",
+                    file_path,
+                    descriptor.data_size
+                );
+            }
 
             let info = FileVerityInfo {
                 path: path_str,
@@ -134,3 +164,69 @@ impl FormatCommand {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::FormatOptions;
+
+    async fn format_with(
+        data_dir: &Path,
+        hash_algorithm: InnerHashAlgorithm,
+        salt: Option<Vec<u8>>,
+    ) -> Result<String> {
+        let hash_output = data_dir.with_extension("hash");
+        FormatCommand {
+            options: FormatOptions {
+                data_dir: data_dir.to_path_buf(),
+                metadata: None,
+                hash_output: hash_output.clone(),
+                force: true,
+                labels: vec![],
+                hash_algorithm,
+                salt,
+                data_blocks: None,
+            },
+        }
+        .run()
+        .await?;
+        Ok(fs::read_to_string(&hash_output).await?)
+    }
+
+    #[tokio::test]
+    async fn test_format_deterministic_with_salt() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let data_dir = tmp.path().join("data");
+        fs::create_dir_all(data_dir.join("sub")).await?;
+        fs::write(data_dir.join("a.txt"), b"hello").await?;
+        fs::write(data_dir.join("sub/b.bin"), vec![0x5a; 3 * 4096 + 1]).await?;
+
+        let salt = hex::decode("0011223344556677")?;
+        let hash1 = format_with(&data_dir, InnerHashAlgorithm::Sha256, Some(salt.clone())).await?;
+        let hash2 = format_with(&data_dir, InnerHashAlgorithm::Sha256, Some(salt.clone())).await?;
+        assert_eq!(hash1, hash2);
+
+        // The salt is recorded in the metadata, which is still self-consistent
+        let metadata = crate::metadata::deserialize_metadata(
+            &fs::read(data_dir.join(DEFAULT_METADATA_FILE)).await?,
+        )?;
+        for info in &metadata.file_infos {
+            assert_eq!(info.descriptor.salt, salt);
+            info.verify_self()?;
+        }
+
+        // The root hash depends on the salt
+        let unsalted = format_with(&data_dir, InnerHashAlgorithm::Sha256, None).await?;
+        assert_ne!(hash1, unsalted);
+        assert_eq!(
+            unsalted,
+            format_with(&data_dir, InnerHashAlgorithm::Sha256, None).await?
+        );
+
+        assert!(format_with(&data_dir, InnerHashAlgorithm::Sha512, None)
+            .await
+            .is_err());
+
+        Ok(())
+    }
+}
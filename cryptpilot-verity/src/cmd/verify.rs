@@ -1,8 +1,10 @@
 use anyhow::Result;
 use async_trait::async_trait;
 use memmap2::Mmap;
-use std::fs::File;
+use serde::Serialize;
+use std::{fs::File, path::Path};
 use tokio::fs;
+use verity_fuse::file_verifier::file_verity_info::FileVerityInfo;
 
 use crate::cmd::{Command, DEFAULT_METADATA_FILE};
 
@@ -10,6 +12,29 @@ pub struct VerifyCommand {
     pub options: crate::cli::VerifyOptions,
 }
 
+/// Structured result of `verify --json`.
+#[derive(Serialize, Debug)]
+pub struct VerifyReport {
+    pub passed: bool,
+    pub expected_root_hash: String,
+    pub computed_root_hash: String,
+    /// Number of data blocks checked against the metadata, including the corrupt ones.
+    pub blocks_verified: u64,
+    pub corrupt_blocks: u64,
+    /// Files which failed the verification, sorted by path.
+    pub failed_files: Vec<FileVerifyFailure>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct FileVerifyFailure {
+    pub path: String,
+    /// Errors not related to a specific block, e.g. the file is missing or has a wrong size.
+    pub errors: Vec<String>,
+    pub corrupt_blocks: u64,
+    /// Byte offset of the first corrupt block in the file, if any.
+    pub first_corrupt_block_offset: Option<u64>,
+}
+
 #[async_trait]
 impl Command for VerifyCommand {
     async fn run(&self) -> Result<()> {
@@ -30,6 +55,15 @@ impl Command for VerifyCommand {
         // (Avoid TOCTOU attacks - we need immutable snapshot)
         let metadata_bytes = fs::read(&metadata_path).await?;
 
+        if self.options.json {
+            let report = self.scan(&metadata_bytes)?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            if !report.passed {
+                anyhow::bail!("Verification failed");
+            }
+            return Ok(());
+        }
+
         // Calculate metadata hash (only from essential fields)
         let root_hash = crate::metadata::calculate_metadata_hash(&metadata_bytes)?;
 
@@ -73,7 +107,7 @@ impl Command for VerifyCommand {
 
                 // Calculate fs-verity hash from mmap data
                 let (calculated_descriptor, _calculated_merkle_tree) =
-                    crate::metadata::calculate_fsverity_hash(&mmap);
+                    crate::metadata::calculate_fsverity_hash(&mmap, &info.descriptor.salt);
                 let calculated_descriptor_hash =
                     hex::encode(calculated_descriptor.to_descriptor_hash());
 
@@ -96,3 +130,222 @@ impl Command for VerifyCommand {
         Ok(())
     }
 }
+
+impl VerifyCommand {
+    /// Verify the whole data directory block by block without aborting on the first error, and
+    /// collect the result into a report.
+    fn scan(&self, metadata_bytes: &[u8]) -> Result<VerifyReport> {
+        let computed_root_hash = crate::metadata::calculate_metadata_hash(metadata_bytes)?;
+        let metadata_info = crate::metadata::deserialize_metadata(metadata_bytes)?;
+
+        let mut report = VerifyReport {
+            passed: computed_root_hash == self.options.hash,
+            expected_root_hash: self.options.hash.clone(),
+            computed_root_hash,
+            blocks_verified: 0,
+            corrupt_blocks: 0,
+            failed_files: vec![],
+        };
+
+        for info in &metadata_info.file_infos {
+            let mut failure = FileVerifyFailure {
+                path: info.path.clone(),
+                errors: vec![],
+                corrupt_blocks: 0,
+                first_corrupt_block_offset: None,
+            };
+
+            if let Err(error) = info.verify_self() {
+                // The block hashes of the file cannot be trusted
+                failure.errors.push(format!("{error:#}"));
+            } else if !self.options.metadata_only {
+                report.blocks_verified +=
+                    scan_file(&self.options.data_dir.join(&info.path), info, &mut failure);
+            }
+
+            if !failure.errors.is_empty() || failure.corrupt_blocks > 0 {
+                report.corrupt_blocks += failure.corrupt_blocks;
+                report.failed_files.push(failure);
+            }
+        }
+
+        report.passed &= report.failed_files.is_empty();
+        report
+            .failed_files
+            .sort_unstable_by(|a, b| a.path.cmp(&b.path));
+
+        Ok(report)
+    }
+}
+
+/// Verify each data block of the file against the level 1 hashes in the metadata, and record the
+/// problems found in `failure`. Returns the number of blocks checked.
+fn scan_file(file_path: &Path, info: &FileVerityInfo, failure: &mut FileVerifyFailure) -> u64 {
+    let mmap = match File::open(file_path).and_then(|file| {
+        // Safety: Opening regular file in read-only mode
+        unsafe { Mmap::map(&file) }
+    }) {
+        Ok(mmap) => mmap,
+        Err(e) => {
+            failure
+                .errors
+                .push(format!("Failed to read file {file_path:?}: {e}"));
+            return 0;
+        }
+    };
+
+    let data_size = info.descriptor.data_size as usize;
+    if mmap.len() != data_size {
+        failure.errors.push(format!(
+            "File size mismatch. Expected: {}, Actual: {}",
+            data_size,
+            mmap.len()
+        ));
+    }
+
+    let block_size = info.descriptor.block_size();
+    let block_count = data_size.div_ceil(block_size);
+    for block_index in 0..block_count {
+        let start = block_index * block_size;
+        let end = (start + block_size).min(data_size);
+        let data = mmap.get(start..end.min(mmap.len())).unwrap_or_default();
+
+        if data.len() != end - start
+            || !info.merkle_tree.verify_data_block(
+                block_index,
+                block_size,
+                &info.descriptor.salt,
+                data,
+            )
+        {
+            failure.corrupt_blocks += 1;
+            failure
+                .first_corrupt_block_offset
+                .get_or_insert(start as u64);
+        }
+    }
+
+    block_count as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::{FormatOptions, VerifyOptions};
+    use crate::cmd::format::FormatCommand;
+    use verity_core::config::InnerHashAlgorithm;
+
+    #[tokio::test]
+    async fn test_verify_json_reports_corrupt_block() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let data_dir = tmp.path().join("data");
+        fs::create_dir_all(&data_dir).await?;
+        fs::write(data_dir.join("a.txt"), b"hello").await?;
+        fs::write(data_dir.join("b.bin"), vec![0x5a; 4 * 4096 + 1]).await?;
+
+        let hash_output = tmp.path().join("hash");
+        FormatCommand {
+            options: FormatOptions {
+                data_dir: data_dir.clone(),
+                metadata: None,
+                hash_output: hash_output.clone(),
+                force: false,
+                labels: vec![],
+                hash_algorithm: InnerHashAlgorithm::Sha256,
+                salt: None,
+                data_blocks: None,
+            },
+        }
+        .run()
+        .await?;
+        let root_hash = fs::read_to_string(&hash_output).await?.trim().to_owned();
+
+        let verify = VerifyCommand {
+            options: VerifyOptions {
+                data_dir: data_dir.clone(),
+                hash: root_hash.clone(),
+                metadata: None,
+                metadata_only: false,
+                json: true,
+            },
+        };
+        let metadata_bytes = fs::read(data_dir.join(DEFAULT_METADATA_FILE)).await?;
+
+        let report = verify.scan(&metadata_bytes)?;
+        assert!(report.passed);
+        assert_eq!(report.computed_root_hash, root_hash);
+        assert_eq!(report.blocks_verified, 1 + 5);
+        assert!(report.failed_files.is_empty());
+
+        // Corrupt a single byte in the third block of b.bin
+        let mut data = fs::read(data_dir.join("b.bin")).await?;
+        data[2 * 4096 + 100] ^= 0xff;
+        fs::write(data_dir.join("b.bin"), data).await?;
+
+        let report = verify.scan(&metadata_bytes)?;
+        assert!(!report.passed);
+        assert_eq!(report.computed_root_hash, root_hash);
+        assert_eq!(report.blocks_verified, 1 + 5);
+        assert_eq!(report.corrupt_blocks, 1);
+        assert_eq!(report.failed_files.len(), 1);
+        assert_eq!(report.failed_files[0].path, "b.bin");
+        assert!(report.failed_files[0].errors.is_empty());
+        assert_eq!(
+            report.failed_files[0].first_corrupt_block_offset,
+            Some(2 * 4096)
+        );
+        assert!(verify.run().await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_verify_detects_simulated_corruption() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let data_dir = tmp.path().join("data");
+        fs::create_dir_all(&data_dir).await?;
+        fs::write(data_dir.join("c.bin"), vec![0xa5; 8 * 4096]).await?;
+
+        let hash_output = tmp.path().join("hash");
+        FormatCommand {
+            options: FormatOptions {
+                data_dir: data_dir.clone(),
+                metadata: None,
+                hash_output: hash_output.clone(),
+                force: false,
+                labels: vec![],
+                hash_algorithm: InnerHashAlgorithm::Sha256,
+                salt: None,
+                data_blocks: None,
+            },
+        }
+        .run()
+        .await?;
+        let root_hash = fs::read_to_string(&hash_output).await?.trim().to_owned();
+
+        let verify = VerifyCommand {
+            options: VerifyOptions {
+                data_dir: data_dir.clone(),
+                hash: root_hash,
+                metadata: None,
+                metadata_only: false,
+                json: false,
+            },
+        };
+        verify.run().await?;
+
+        cryptpilot::fs::block::corrupt::corrupt_bytes(&data_dir.join("c.bin"), 5 * 4096 + 7, 3)
+            .await?;
+        assert!(verify.run().await.is_err());
+
+        let metadata_bytes = fs::read(data_dir.join(DEFAULT_METADATA_FILE)).await?;
+        let report = verify.scan(&metadata_bytes)?;
+        assert_eq!(report.corrupt_blocks, 1);
+        assert_eq!(
+            report.failed_files[0].first_corrupt_block_offset,
+            Some(5 * 4096)
+        );
+
+        Ok(())
+    }
+}
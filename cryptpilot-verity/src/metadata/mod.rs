@@ -11,12 +11,17 @@ pub use metadata_generated::cryptpilot::verity::{
     Metadata, MetadataArgs,
 };
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context as _, Result};
 use canon_json::CanonJsonSerialize;
 use flatbuffers::FlatBufferBuilder;
 use sha2::digest::typenum::Unsigned;
 use sha2::{digest::OutputSizeUser, Digest, Sha256};
 use std::collections::BTreeMap;
+use std::fs::File;
+use std::os::unix::fs::FileExt as _;
+use std::os::unix::io::AsRawFd as _;
+use std::path::Path;
+use verity_core::config::{InnerHashAlgorithm, DEFAULT_BLOCK_SIZE, MAX_SALT_SIZE};
 use verity_core::digest::FsVeritySha256;
 use verity_core::tree::MerkleTree;
 use verity_fuse::file_verifier::file_verity_info::FileVerityInfo;
@@ -27,21 +32,107 @@ pub struct MetadataInfo {
     pub labels: BTreeMap<String, String>,
 }
 
-/// Calculate fs-verity hash for file data
+/// Calculate fs-verity hash for file data, with the salt mixed into every hash (empty for no salt)
 pub fn calculate_fsverity_hash(
     data: &[u8],
+    salt: &[u8],
 ) -> (
     verity_core::descriptor::FsVerityDescriptor<Sha256>,
     MerkleTree<Sha256>,
 ) {
     // Create FsVerity digest
-    let mut digest = FsVeritySha256::<[u8; 0]>::new();
+    let mut digest = FsVeritySha256::<Vec<u8>>::new_with_salt(salt.to_vec());
     digest.update(data);
 
     // Finalize and get descriptor and merkle tree
     digest.finalize_into_fs_verity_stuffs()
 }
 
+/// The size of the buffer to read the data ranges of a file with.
+const READ_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Calculate fs-verity hash for the file, like [`calculate_fsverity_hash`], but without reading
+/// the holes of a sparse file, which are hashed as zero blocks instead. If `data_blocks` is set,
+/// only the first `data_blocks` blocks of the file are covered, and the descriptor records the
+/// size of the covered range.
+pub fn calculate_fsverity_hash_of_file(
+    path: &Path,
+    salt: &[u8],
+    data_blocks: Option<u64>,
+) -> Result<(
+    verity_core::descriptor::FsVerityDescriptor<Sha256>,
+    MerkleTree<Sha256>,
+)> {
+    let block_size = DEFAULT_BLOCK_SIZE as u64;
+    let file = File::open(path).with_context(|| format!("Failed to open file {path:?}"))?;
+    let file_size = file.metadata()?.len();
+    let size = match data_blocks {
+        Some(data_blocks) => file_size.min(data_blocks.saturating_mul(block_size)),
+        None => file_size,
+    };
+
+    let mut digest = FsVeritySha256::<Vec<u8>>::new_with_salt(salt.to_vec());
+    let mut buf = vec![0u8; READ_BUFFER_SIZE];
+    let mut read_range = |digest: &mut FsVeritySha256<Vec<u8>>, start: u64, end: u64| {
+        let mut offset = start;
+        while offset < end {
+            let len = ((end - offset) as usize).min(buf.len());
+            file.read_exact_at(&mut buf[..len], offset)
+                .with_context(|| format!("Failed to read file {path:?} at offset {offset}"))?;
+            digest.update(&buf[..len]);
+            offset += len as u64;
+        }
+        anyhow::Ok(())
+    };
+
+    let mut offset = 0;
+    while offset < size {
+        let hole_start = seek_sparse(&file, offset, libc::SEEK_HOLE, size)?;
+        if hole_start > offset {
+            read_range(&mut digest, offset, hole_start)?;
+            offset = hole_start;
+            continue;
+        }
+
+        // The offset is in a hole, whose whole blocks are known to be zero
+        let hole_end = seek_sparse(&file, offset, libc::SEEK_DATA, size)?;
+        let zero_end = hole_end / block_size * block_size;
+        if offset % block_size == 0 && zero_end > offset {
+            digest.update_zero_blocks((zero_end - offset) / block_size);
+            offset = zero_end;
+        } else {
+            // A hole not covering a whole block is read as it is
+            let end = ((offset / block_size + 1) * block_size).min(size);
+            read_range(&mut digest, offset, end)?;
+            offset = end;
+        }
+    }
+
+    Ok(digest.finalize_into_fs_verity_stuffs())
+}
+
+/// Find the start of the next hole (`SEEK_HOLE`) or data range (`SEEK_DATA`) at or after the
+/// offset, capped at `size`. If the file system does not support finding holes, the whole file is
+/// treated as data.
+fn seek_sparse(file: &File, offset: u64, whence: libc::c_int, size: u64) -> Result<u64> {
+    // SAFETY: lseek only takes integers, and the fd is kept open by the borrowed file
+    let ret = unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, whence) };
+    if ret >= 0 {
+        return Ok((ret as u64).min(size));
+    }
+
+    let error = std::io::Error::last_os_error();
+    match (error.raw_os_error(), whence) {
+        // No hole support, so no hole until the end of the file
+        (Some(libc::EINVAL), libc::SEEK_HOLE) => Ok(size),
+        // No hole support, so data right at the offset
+        (Some(libc::EINVAL), _) => Ok(offset),
+        // No data after the offset
+        (Some(libc::ENXIO), libc::SEEK_DATA) => Ok(size),
+        _ => Err(error).context("Failed to seek for the holes of the file"),
+    }
+}
+
 /// Serialize file information to FlatBuffers format
 pub fn serialize_metadata(
     file_infos: &[FileVerityInfo],
@@ -163,12 +254,36 @@ pub fn deserialize_metadata(data: &[u8]) -> Result<MetadataInfo> {
                 .ok_or_else(|| anyhow::anyhow!("Missing root_hash in descriptor"))?;
 
             let mut root_hash = sha2::digest::generic_array::GenericArray::default();
+            if root_hash_bytes.len() != root_hash.len() {
+                bail!(
+                    "Broken descriptor for {}: root_hash is {} bytes long, expected {} bytes",
+                    path,
+                    root_hash_bytes.len(),
+                    root_hash.len()
+                );
+            }
             root_hash.copy_from_slice(root_hash_bytes.bytes());
 
             let salt = fb_descriptor
                 .salt()
                 .map(|s| s.bytes().to_vec())
                 .unwrap_or_default();
+            if salt.len() > MAX_SALT_SIZE {
+                bail!(
+                    "Broken descriptor for {}: salt is {} bytes long, which exceeds the maximum of {} bytes",
+                    path,
+                    salt.len(),
+                    MAX_SALT_SIZE
+                );
+            }
+
+            if fb_descriptor.hash_algorithm() != InnerHashAlgorithm::Sha256 as u8 {
+                bail!(
+                    "Unsupported hash algorithm {} for {}. Only sha256 is supported.",
+                    fb_descriptor.hash_algorithm(),
+                    path
+                );
+            }
 
             let descriptor = verity_core::descriptor::FsVerityDescriptor {
                 version: fb_descriptor.version(),
@@ -295,11 +410,50 @@ struct FileHashJsonEntry {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::os::unix::fs::FileExt as _;
+
+    #[test]
+    fn test_calculate_fsverity_hash_of_sparse_file() -> Result<()> {
+        let block_size = DEFAULT_BLOCK_SIZE as u64;
+        let file = tempfile::NamedTempFile::new()?;
+        // Data ranges around holes, one of which does not start on a block boundary, and a
+        // trailing hole ending in a partial block
+        let size = 1024 * block_size + 100;
+        file.as_file().set_len(size)?;
+        file.as_file().write_all_at(&[0x5a; 10], 0)?;
+        file.as_file()
+            .write_all_at(&vec![0xa5; 2 * block_size as usize], 300 * block_size + 17)?;
+        file.as_file().write_all_at(b"tail", 700 * block_size)?;
+        let content = std::fs::read(file.path())?;
+
+        for salt in [&[][..], b"salt"] {
+            let (expected_descriptor, expected_tree) = calculate_fsverity_hash(&content, salt);
+            let (descriptor, tree) = calculate_fsverity_hash_of_file(file.path(), salt, None)?;
+            assert_eq!(descriptor.data_size, size);
+            assert_eq!(descriptor.root_hash, expected_descriptor.root_hash);
+            assert_eq!(tree.level1_as_bytes(), expected_tree.level1_as_bytes());
+
+            // Only the first blocks are covered
+            for data_blocks in [0, 1, 300, 301, 1025] {
+                let covered = (data_blocks * block_size).min(size) as usize;
+                let (expected_descriptor, _) = calculate_fsverity_hash(&content[..covered], salt);
+                let (descriptor, _) =
+                    calculate_fsverity_hash_of_file(file.path(), salt, Some(data_blocks))?;
+                assert_eq!(descriptor.data_size, covered as u64, "{data_blocks}");
+                assert_eq!(
+                    descriptor.root_hash, expected_descriptor.root_hash,
+                    "{data_blocks}"
+                );
+            }
+        }
+
+        Ok(())
+    }
 
     #[test]
     fn test_serialize_deserialize() {
         let test_data = b"test file content";
-        let (descriptor, merkle_tree) = calculate_fsverity_hash(test_data);
+        let (descriptor, merkle_tree) = calculate_fsverity_hash(test_data, &[]);
         let descriptor_hash = hex::encode(descriptor.to_descriptor_hash());
         let info = FileVerityInfo {
             path: "test.txt".to_string(),
@@ -326,10 +480,30 @@ mod tests {
         assert_eq!(deserialized.labels.get("env"), Some(&"prod".to_string()));
     }
 
+    #[test]
+    fn test_deserialize_oversized_salt() -> Result<()> {
+        let (mut descriptor, merkle_tree) = calculate_fsverity_hash(b"test file content", &[]);
+        let descriptor_hash = hex::encode(descriptor.to_descriptor_hash());
+        descriptor.salt = vec![0x5a; MAX_SALT_SIZE + 1];
+        let info = FileVerityInfo {
+            path: "test.txt".to_string(),
+            descriptor,
+            merkle_tree,
+            descriptor_hash,
+        };
+
+        // A malformed metadata file fails to load instead of panicking when used
+        let serialized = serialize_metadata(&[info], &BTreeMap::new())?;
+        let error = deserialize_metadata(&serialized).unwrap_err();
+        assert!(format!("{error:#}").contains("salt"), "{error:#}");
+
+        Ok(())
+    }
+
     #[test]
     fn test_serialize_deserialize_empty_labels() {
         let test_data = b"test file content";
-        let (descriptor, merkle_tree) = calculate_fsverity_hash(test_data);
+        let (descriptor, merkle_tree) = calculate_fsverity_hash(test_data, &[]);
         let descriptor_hash = hex::encode(descriptor.to_descriptor_hash());
         let info = FileVerityInfo {
             path: "test.txt".to_string(),
@@ -351,7 +525,7 @@ mod tests {
     #[test]
     fn test_canonical_json_hash_determinism() {
         let test_data = b"test file content";
-        let (descriptor, merkle_tree) = calculate_fsverity_hash(test_data);
+        let (descriptor, merkle_tree) = calculate_fsverity_hash(test_data, &[]);
         let descriptor_hash = hex::encode(descriptor.to_descriptor_hash());
         let info = FileVerityInfo {
             path: "test.txt".to_string(),
@@ -371,7 +545,7 @@ mod tests {
         assert_eq!(hash1, hash2);
 
         // Different path → different hash
-        let (descriptor2, merkle_tree2) = calculate_fsverity_hash(test_data);
+        let (descriptor2, merkle_tree2) = calculate_fsverity_hash(test_data, &[]);
         let descriptor_hash2 = hex::encode(descriptor2.to_descriptor_hash());
         let info2 = FileVerityInfo {
             path: "other.txt".to_string(),
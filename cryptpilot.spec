@@ -182,17 +182,17 @@ Cryptpilot-crypt provides data volume encryption and automatic volume management
 %build
 # Build cryptpilot-fde-host
 pushd src/cryptpilot-fde/
-cargo install --path . --bin cryptpilot-fde-host --root %{_builddir}/%{name}-%{version}/install/cryptpilot-fde-host/ --locked --offline
+cargo install --path . --bin cryptpilot-fde-host --features provider-http --root %{_builddir}/%{name}-%{version}/install/cryptpilot-fde-host/ --locked --offline
 popd
 
 # Build cryptpilot-fde-guest
 pushd src/cryptpilot-fde/
-cargo install --path . --bin cryptpilot-fde-guest --root %{_builddir}/%{name}-%{version}/install/cryptpilot-fde-guest/ --locked --offline
+cargo install --path . --bin cryptpilot-fde-guest --features provider-http --root %{_builddir}/%{name}-%{version}/install/cryptpilot-fde-guest/ --locked --offline
 popd
 
 # Build cryptpilot-crypt
 pushd src/cryptpilot-crypt/
-cargo install --path . --bin cryptpilot-crypt --root %{_builddir}/%{name}-%{version}/install/cryptpilot-crypt/ --locked --offline
+cargo install --path . --bin cryptpilot-crypt --features provider-pkcs11,provider-http --root %{_builddir}/%{name}-%{version}/install/cryptpilot-crypt/ --locked --offline
 popd
 
 # Build cryptpilot-verity
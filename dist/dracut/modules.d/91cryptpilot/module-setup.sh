@@ -27,6 +27,9 @@ install() {
         # inst_multiple curl nc ip find systemctl journalctl ifconfig lsblk df
         # Install cryptpilot-fde for FDE boot-time decryption
         inst_multiple cryptpilot-fde-guest
+        # Record the version of cryptpilot the initrd is built with, checked by `cryptpilot-fde-host check-initrd`
+        mkdir -p "$initdir"/usr/lib/cryptpilot
+        cryptpilot-fde-guest -V | awk '{print $2}' >"$initdir"/usr/lib/cryptpilot/initrd-version
 
         # TODO: It would be better compatible to use the same network service in initrd as in system. So here we enable NetworkManager in initrd since the Alinux3 OS is using NetworkManager in system. But it would be better to have a more general way to select network service to be enabled.
         # Enable NetworkManager
@@ -341,6 +341,76 @@ where
     D: InnerHash,
     S: AsRef<[u8]> + Clone + Default,
 {
+    /// Update the hash state with `count` blocks of zero bytes, e.g. the holes of a sparse file.
+    ///
+    /// This is equivalent to updating with `count * block_size` zero bytes, but when the data
+    /// written so far is a multiple of the block size, the zero block is hashed only once instead
+    /// of once per block.
+    pub fn update_zero_blocks(&mut self, count: u64) {
+        let block_size = self.config.block_size;
+        // level[0] is either absent or completely full when the input so far is block-aligned
+        let aligned = self.levels.first().is_none_or(|level| level.remaining == 0);
+        if !aligned {
+            let zeroes = vec![0u8; block_size];
+            for _ in 0..count {
+                Update::update(self, &zeroes);
+            }
+            return;
+        }
+
+        let mut zero_block = FixedSizeBlock::new(&self.config);
+        zero_block.fill_to_end();
+        let mut zero_digest: digest::Output<D> = Default::default();
+        zero_block.clone().finalize_into(&mut zero_digest);
+
+        let mut count = count;
+        // whether level[0] holds a zero block, whose digest is already known
+        let mut level0_is_zero = false;
+        if self.levels.is_empty() {
+            if count == 0 {
+                return;
+            }
+            self.levels.push(zero_block.clone());
+            level0_is_zero = true;
+            count -= 1;
+        }
+
+        for _ in 0..count {
+            // level[0] is full, so its digest moves up before it is replaced by the next block
+            let digest = if level0_is_zero {
+                zero_digest.clone()
+            } else {
+                let mut digest: digest::Output<D> = Default::default();
+                mem::replace(&mut self.levels[0], zero_block.clone()).finalize_into(&mut digest);
+                level0_is_zero = true;
+                digest
+            };
+            self.merkle_tree.append_level_1_hash(digest.clone());
+            self.append_digest_to_upper_levels(digest);
+        }
+    }
+
+    /// Append a digest of a level[0] block to level[1], moving up the hierarchy like
+    /// [`Update::update`] does.
+    fn append_digest_to_upper_levels(&mut self, digest: digest::Output<D>) {
+        let mut last_digest = digest;
+        for level in self.levels.iter_mut().skip(1) {
+            // levels 1..n always have room for one more digest
+            level.append(&last_digest);
+            if level.remaining >= D::OutputSize::USIZE {
+                return;
+            }
+            let mut tmp: digest::Output<D> = Default::default();
+            level.finalize_into_and_reset(&mut tmp, &self.config);
+            last_digest = tmp;
+        }
+
+        assert!(self.levels.len() < MAX_LEVELS);
+        let mut level = FixedSizeBlock::new(&self.config);
+        level.append(&last_digest);
+        self.levels.push(level);
+    }
+
     pub fn finalize_into_fs_verity_stuffs(mut self) -> (FsVerityDescriptor<D>, MerkleTree<D>) {
         // a block is block_size bytes; a digest is D::OutputSize::USIZE bytes.
         // dividing them gives the "compression factor", meaning how many times smaller the
@@ -525,6 +595,7 @@ pub fn new_dyn_with_salt_and_block_size<S: AsRef<[u8]> + Clone + Default + 'stat
 #[cfg(test)]
 mod tests {
     use crate::digest::{FsVeritySha256, InnerHashAlgorithm};
+    use sha2::digest::Update as _;
     use std::fs::File;
     use std::io::{BufRead as _, BufReader, Read as _};
     use std::path::PathBuf;
@@ -560,6 +631,51 @@ mod tests {
         testfiles
     }
 
+    #[test]
+    fn test_update_zero_blocks() {
+        let block_size = crate::config::DEFAULT_BLOCK_SIZE;
+        // (leading data bytes, zero blocks, trailing data bytes), crossing a level[1] block
+        for (leading, zero_blocks, trailing) in [
+            (0, 0, 0),
+            (0, 1, 0),
+            (0, 3, 1),
+            (block_size, 200, block_size + 7),
+            (2 * block_size + 1, 5, 0),
+            (3 * block_size, 130, 0),
+        ] {
+            let leading_data = vec![0x5a; leading];
+            let trailing_data = vec![0xa5; trailing];
+            let zeroes = vec![0u8; zero_blocks * block_size];
+
+            let mut expected = FsVeritySha256::new_with_salt(b"salt".to_vec());
+            expected.update(&leading_data);
+            expected.update(&zeroes);
+            expected.update(&trailing_data);
+            let (expected_descriptor, expected_tree) = expected.finalize_into_fs_verity_stuffs();
+
+            let mut digest = FsVeritySha256::new_with_salt(b"salt".to_vec());
+            digest.update(&leading_data);
+            digest.update_zero_blocks(zero_blocks as u64);
+            digest.update(&trailing_data);
+            let (descriptor, tree) = digest.finalize_into_fs_verity_stuffs();
+
+            let case = (leading, zero_blocks, trailing);
+            assert_eq!(
+                descriptor.data_size, expected_descriptor.data_size,
+                "{case:?}"
+            );
+            assert_eq!(
+                descriptor.root_hash, expected_descriptor.root_hash,
+                "{case:?}"
+            );
+            assert_eq!(
+                tree.level1_as_bytes(),
+                expected_tree.level1_as_bytes(),
+                "{case:?}"
+            );
+        }
+    }
+
     #[test]
     fn test_testfiles() {
         let testfiles = get_testfiles();
@@ -620,7 +736,12 @@ mod tests {
                     match reader.read_exact(&mut buffer) {
                         Ok(()) => {
                             assert!(
-                                merkle_tree.verify_data_block(block_index, block_size, &buffer),
+                                merkle_tree.verify_data_block(
+                                    block_index,
+                                    block_size,
+                                    &descriptor.salt,
+                                    &buffer
+                                ),
                                 "Block verification failed: index={}",
                                 block_index
                             );
@@ -630,7 +751,12 @@ mod tests {
                             let rem = reader.fill_buf().unwrap();
                             if !rem.is_empty() {
                                 assert!(
-                                    merkle_tree.verify_data_block(block_index, block_size, rem),
+                                    merkle_tree.verify_data_block(
+                                        block_index,
+                                        block_size,
+                                        &descriptor.salt,
+                                        rem
+                                    ),
                                     "Final block verification failed: index={}, len={}",
                                     block_index,
                                     rem.len()
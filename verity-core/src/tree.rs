@@ -1,4 +1,4 @@
-use crate::digest::{FsVerityDigest, InnerHash};
+use crate::digest::{salt_to_digest, FsVerityDigest, InnerHash};
 
 use digest::typenum::Unsigned;
 use sha2::Digest;
@@ -46,7 +46,14 @@ impl<D: InnerHash> MerkleTree<D> {
         descriptor.root_hash
     }
 
-    pub fn verify_data_block(&self, block_index: usize, block_size: usize, data: &[u8]) -> bool {
+    /// Verify a data block against its level 1 hash. The salt must be the one the tree was built with.
+    pub fn verify_data_block(
+        &self,
+        block_index: usize,
+        block_size: usize,
+        salt: &[u8],
+        data: &[u8],
+    ) -> bool {
         if data.len() > block_size {
             return false;
         }
@@ -55,7 +62,7 @@ impl<D: InnerHash> MerkleTree<D> {
             return false;
         };
 
-        let mut digest = D::new();
+        let mut digest: D = salt_to_digest(&salt);
         digest.update_padded(data, block_size);
         let real = digest.finalize();
         expected == &real
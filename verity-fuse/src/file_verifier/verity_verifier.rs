@@ -52,9 +52,12 @@ impl VerifiableFile for FsEntry {
         match &self.kind {
             EntryKind::VerityEnabled(info) => {
                 let block_size = info.descriptor.block_size();
-                let is_valid = info
-                    .merkle_tree
-                    .verify_data_block(block_index, block_size, data);
+                let is_valid = info.merkle_tree.verify_data_block(
+                    block_index,
+                    block_size,
+                    &info.descriptor.salt,
+                    data,
+                );
 
                 if !is_valid {
                     anyhow::bail!(
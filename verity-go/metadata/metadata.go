@@ -199,6 +199,12 @@ func DeserializeMetadata(data []byte) (result *MetadataInfo, err error) {
 			if salt == nil {
 				salt = []byte{}
 			}
+			if len(salt) > verity.MaxSaltSize {
+				return nil, &ParseError{
+					Message: fmt.Sprintf("broken descriptor for %s: salt is %d bytes long, which exceeds the maximum of %d bytes",
+						path, len(salt), verity.MaxSaltSize),
+				}
+			}
 
 			descriptor := verity.FsVerityDescriptor{
 				Version:       fbDesc.Version(),
@@ -195,6 +195,23 @@ func TestDeserializeError(t *testing.T) {
 	}
 }
 
+func TestDeserializeOversizedSalt(t *testing.T) {
+	info := makeTestFileVerityInfo("test.txt", []byte("test file content"))
+	info.Descriptor.Salt = bytes.Repeat([]byte{0x5a}, verity.MaxSaltSize+1)
+	serialized, err := SerializeMetadata([]FileVerityInfo{info}, map[string]string{})
+	if err != nil {
+		t.Fatalf("SerializeMetadata: %v", err)
+	}
+
+	_, err = DeserializeMetadata(serialized)
+	if err == nil {
+		t.Fatal("expected error from oversized salt")
+	}
+	if _, ok := err.(*ParseError); !ok {
+		t.Errorf("expected ParseError, got %T", err)
+	}
+}
+
 func TestCalculateFsVerityHash(t *testing.T) {
 	desc, tree := CalculateFsVerityHash([]byte("test"))
 
@@ -52,7 +52,8 @@ func (t *MerkleTree) RebuildRootHash(salt []byte, blockSize int) []byte {
 }
 
 // VerifyDataBlock verifies a single data block against the merkle tree.
-func (t *MerkleTree) VerifyDataBlock(blockIndex int, blockSize int, data []byte) bool {
+// The salt must be the one the tree was built with.
+func (t *MerkleTree) VerifyDataBlock(blockIndex int, blockSize int, salt []byte, data []byte) bool {
 	if len(data) > blockSize {
 		return false
 	}
@@ -61,7 +62,7 @@ func (t *MerkleTree) VerifyDataBlock(blockIndex int, blockSize int, data []byte)
 	}
 	expected := t.level1[blockIndex]
 
-	h := t.algo.newHash()
+	h := newSaltedHash(t.algo, salt)
 	h.Write(data)
 	// Pad to block size
 	if len(data) < blockSize {
@@ -53,7 +53,7 @@ func TestOneByte(t *testing.T) {
 	// Verify data block
 	block := make([]byte, desc.BlockSize())
 	block[0] = 'A'
-	if !tree.VerifyDataBlock(0, desc.BlockSize(), block[:1]) {
+	if !tree.VerifyDataBlock(0, desc.BlockSize(), desc.Salt, block[:1]) {
 		t.Errorf("onebyte: data block verification failed")
 	}
 }
@@ -103,6 +103,32 @@ func TestOneBlockPlusOneByte(t *testing.T) {
 	}
 }
 
+func TestSaltedVerifyDataBlock(t *testing.T) {
+	// 4096 bytes of 'A' + 1 byte 'B', hashed with a salt
+	data := make([]byte, DefaultBlockSize+1)
+	for i := 0; i < DefaultBlockSize; i++ {
+		data[i] = 'A'
+	}
+	data[DefaultBlockSize] = 'B'
+	salt := []byte{0x01, 0x02, 0x03, 0x04}
+	d := NewFsVerityWithSalt(HashSHA256, salt)
+	d.Write(data)
+	desc, tree := d.Finalize()
+
+	if !bytes.Equal(desc.Salt, salt) {
+		t.Errorf("salted: salt not recorded in descriptor")
+	}
+	if !tree.VerifyDataBlock(0, desc.BlockSize(), desc.Salt, data[:DefaultBlockSize]) {
+		t.Errorf("salted: data block 0 verification failed")
+	}
+	if !tree.VerifyDataBlock(1, desc.BlockSize(), desc.Salt, data[DefaultBlockSize:]) {
+		t.Errorf("salted: data block 1 verification failed")
+	}
+	if tree.VerifyDataBlock(0, desc.BlockSize(), nil, data[:DefaultBlockSize]) {
+		t.Errorf("salted: data block 0 verified without salt")
+	}
+}
+
 // hashblock test cases: block_size * (hashes_per_block + i) + j bytes of 'A'
 // hashes_per_block = 4096 / 32 = 128
 func TestHashblock(t *testing.T) {